@@ -0,0 +1,259 @@
+use axum::extract::{Query, Request, State};
+use axum::middleware::{self, Next};
+use axum::response::Response;
+use axum::routing::{delete, get, post};
+use axum::{Json, Router};
+use hyper::{header, HeaderMap, Method, StatusCode};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tracing::{error, info, warn};
+
+use crate::app_state::AppState;
+use crate::capture;
+use crate::fault;
+use crate::metrics::MetricsSnapshot;
+use crate::rbac::{self, Principal, Role};
+use crate::request_log::{RequestLogEntry, RequestLogQuery};
+use crate::upstream_errors;
+
+/// Routes under `/admin`, all but the dashboard page behind an admin token
+/// whose role allows the call (see [`required_role`]). The webhook simulator
+/// is only served in NOWPayments test mode.
+pub fn admin_router(state: AppState) -> Router<AppState> {
+    let mut router = Router::new()
+        .route("/cache", delete(invalidate_cache))
+        .route("/request-log", get(query_request_log))
+        .route("/loglevel", get(get_log_level).put(set_log_level))
+        .route("/probes", get(crate::prober::probes_handler))
+        .route("/metrics/snapshot", get(export_metrics))
+        .route("/metrics/import", post(import_metrics))
+        .route("/compare", post(crate::compare::compare_handler))
+        .route(
+            "/extract/{env}/{*path}",
+            get(crate::jsonpath::extract_handler),
+        )
+        .route("/dashboard", get(crate::dashboard::dashboard_handler))
+        .route("/tail", get(crate::tail::tail_handler))
+        .route("/transfers", get(crate::transfers::transfers_handler))
+        .route("/pauses", get(crate::pause::list_handler))
+        .route("/pauses/{env}", delete(crate::pause::resume_handler))
+        .route("/maintenance", get(crate::maintenance::list_handler))
+        .route(
+            "/schedules",
+            get(crate::schedule::list_handler).post(crate::schedule::create_handler),
+        )
+        .route(
+            "/schedules/{id}",
+            get(crate::schedule::get_handler).delete(crate::schedule::cancel_handler),
+        )
+        .route(
+            "/upstream-errors",
+            get(upstream_errors::list_handler).delete(upstream_errors::clear_handler),
+        )
+        .route(
+            "/capture",
+            get(capture::status_handler)
+                .post(capture::arm_handler)
+                .delete(capture::clear_handler),
+        )
+        .route("/capture/download", get(capture::download_handler))
+        .route("/export", get(crate::export::export_handler))
+        .route(
+            "/faults",
+            get(fault::list_handler)
+                .put(fault::set_handler)
+                .delete(fault::clear_handler),
+        );
+    if state.env_var_config.nowpayments_test_mode {
+        router = router.route(
+            "/simulate-webhook",
+            post(crate::nowpayments_ipn_webhook::simulate_webhook_handler),
+        );
+    }
+    router
+        .route_layer(middleware::from_fn_with_state(state, require_admin))
+        // Holds no data, so browsers can load it before asking for the token.
+        .route("/ui", get(crate::dashboard::ui_handler))
+}
+
+/// The least role that may call an `/admin` route. Reads are for viewers,
+/// except those returning captured traffic; writes are for operators, except
+/// those changing how the proxy behaves.
+fn required_role(method: &Method, path: &str) -> Role {
+    match (method, path) {
+        (&Method::GET, "/request-log" | "/capture/download" | "/export" | "/upstream-errors") => {
+            Role::Operator
+        }
+        // Jobs carry the requests' results, and extractions upstream data.
+        (&Method::GET, path) if path.starts_with("/schedules") || path.starts_with("/extract/") => {
+            Role::Operator
+        }
+        (&Method::GET, _) => Role::Viewer,
+        (_, "/faults" | "/metrics/import" | "/simulate-webhook") => Role::Admin,
+        _ => Role::Operator,
+    }
+}
+
+/// Rejects `/admin` requests without `Authorization: Bearer <admin token>`
+/// of a role allowed to make them.
+pub async fn require_admin(
+    State(state): State<AppState>,
+    req: Request,
+    next: Next,
+) -> Result<Response, StatusCode> {
+    let role = required_role(req.method(), req.uri().path());
+    authorize(&state, req, next, role).await
+}
+
+/// Rejects requests without a viewer's (or higher) admin bearer token.
+pub async fn require_viewer(
+    State(state): State<AppState>,
+    req: Request,
+    next: Next,
+) -> Result<Response, StatusCode> {
+    authorize(&state, req, next, Role::Viewer).await
+}
+
+async fn authorize(
+    state: &AppState,
+    req: Request,
+    next: Next,
+    role: Role,
+) -> Result<Response, StatusCode> {
+    if !rbac::enabled(&state.env_var_config) {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    let principal = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .and_then(|token| rbac::authenticate(&state.env_var_config, token.as_bytes()));
+    let (method, path) = (req.method().clone(), req.uri().path().to_string());
+    match principal {
+        Some(principal) if principal.role >= role => {
+            info!(target: "audit", "{} called {} {}", principal, method, path);
+            // Handlers that record who acted read it from here.
+            let mut req = req;
+            req.extensions_mut().insert(principal);
+            Ok(next.run(req).await)
+        }
+        Some(principal) => {
+            warn!(
+                target: "audit",
+                "Refused {} {} to {}: needs the {} role",
+                method, path, principal, role
+            );
+            Err(StatusCode::FORBIDDEN)
+        }
+        None => {
+            warn!("Rejected admin request to {}", path);
+            Err(StatusCode::UNAUTHORIZED)
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct InvalidateCacheParams {
+    path_prefix: String,
+}
+
+/// `DELETE /admin/cache?path_prefix=/test/api` drops matching cached responses.
+async fn invalidate_cache(
+    State(state): State<AppState>,
+    Query(params): Query<InvalidateCacheParams>,
+) -> Json<Value> {
+    let removed = state.cache.invalidate_prefix(&params.path_prefix).await;
+    info!(
+        "Invalidated {} cached responses under {}",
+        removed, params.path_prefix
+    );
+    Json(json!({ "removed": removed }))
+}
+
+/// `GET /admin/request-log?from_ms=&to_ms=&status=&path_prefix=&correlation_id=&fingerprint=&client_tag=&limit=`
+/// returns matching request summaries, newest first.
+async fn query_request_log(
+    State(state): State<AppState>,
+    Query(query): Query<RequestLogQuery>,
+) -> Result<Json<Vec<RequestLogEntry>>, StatusCode> {
+    let Some(log) = &state.request_log else {
+        return Err(StatusCode::NOT_FOUND);
+    };
+    log.sink().query(&query).await.map(Json).map_err(|e| {
+        error!("Request log query failed: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })
+}
+
+/// `GET /admin/metrics/snapshot` returns the lifetime counters as JSON.
+async fn export_metrics(State(state): State<AppState>) -> Json<MetricsSnapshot> {
+    Json(state.metrics.snapshot())
+}
+
+/// `POST /admin/metrics/import` adds an exported snapshot to the counters,
+/// once per run; a second import gets a 409.
+async fn import_metrics(
+    State(state): State<AppState>,
+    Json(snapshot): Json<MetricsSnapshot>,
+) -> Result<Json<Value>, StatusCode> {
+    if !state.metrics.import(&snapshot) {
+        warn!("Rejected a second metrics import");
+        return Err(StatusCode::CONFLICT);
+    }
+    info!(
+        "Imported {} requests from a metrics snapshot",
+        snapshot.requests_total
+    );
+    Ok(Json(
+        json!({ "requests_total": state.metrics.snapshot().requests_total }),
+    ))
+}
+
+#[derive(Deserialize)]
+pub struct LogLevelBody {
+    filter: String,
+}
+
+/// `GET /admin/loglevel` returns the active tracing filter.
+async fn get_log_level(State(state): State<AppState>) -> Result<Json<Value>, StatusCode> {
+    let control = state.log_control.as_ref().ok_or(StatusCode::NOT_FOUND)?;
+    Ok(Json(json!({ "filter": control.current() })))
+}
+
+/// `PUT /admin/loglevel` with `{"filter": "info,axum_example_rev_proxy::proxy=debug"}`
+/// swaps the tracing filter without a restart.
+async fn set_log_level(
+    State(state): State<AppState>,
+    Json(body): Json<LogLevelBody>,
+) -> Result<Json<Value>, (StatusCode, String)> {
+    let control = state
+        .log_control
+        .as_ref()
+        .ok_or((StatusCode::NOT_FOUND, String::new()))?;
+    control.set(&body.filter).map_err(|e| {
+        warn!("Rejected log filter {:?}: {}", body.filter, e);
+        (StatusCode::BAD_REQUEST, e)
+    })?;
+    info!("Log filter changed to {}", body.filter);
+    Ok(Json(json!({ "filter": control.current() })))
+}
+
+/// Carries an admin token on proxied requests, for operator-only features
+/// such as target overrides and dry runs. Never forwarded upstream.
+pub const ADMIN_TOKEN_HEADER: &str = "x-proxy-admin-token";
+
+/// Removes [`ADMIN_TOKEN_HEADER`] from a proxied request, returning who it
+/// belongs to.
+pub fn take_admin_token(state: &AppState, headers: &mut HeaderMap) -> Option<Principal> {
+    let token = headers.remove(ADMIN_TOKEN_HEADER)?;
+    rbac::authenticate(&state.env_var_config, token.as_bytes())
+}
+
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
@@ -0,0 +1,213 @@
+//! Pins the API version each supplier is called with, so a supplier-side
+//! switch of its default version can't change responses under us silently.
+//! Pins come from `API_VERSION_PINS`, a JSON array such as
+//!
+//! ```json
+//! [{"env": "prod", "header": "X-Api-Version", "version": "2024-01",
+//!   "accepted": ["2024-01", "2024-01.2"]}]
+//! ```
+//!
+//! Every request to the env is sent with `header: version`, replacing
+//! whatever the caller sent. A response whose `response_header` (`header`
+//! by default) names a version outside `accepted` (just `version` by
+//! default) is refused with a 502 (`upstream_version_mismatch`) instead of
+//! being passed on or cached. Responses without the header pass unless
+//! `require_response_version` is set.
+
+use hyper::header::{HeaderName, HeaderValue};
+use serde::Deserialize;
+use tracing::error;
+
+use crate::error::ProxyError;
+use crate::interceptor::{OutboundRequest, UpstreamResponse};
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct ApiVersionPin {
+    pub env: String,
+    pub header: String,
+    pub version: String,
+    /// Where the response advertises its version; `header` when unset.
+    #[serde(default)]
+    pub response_header: Option<String>,
+    /// Versions a response may advertise; just `version` when empty.
+    #[serde(default)]
+    pub accepted: Vec<String>,
+    #[serde(default)]
+    pub require_response_version: bool,
+}
+
+/// Parses `API_VERSION_PINS`, a JSON array of [`ApiVersionPin`]s.
+pub fn parse_api_version_pins(value: &str) -> Result<Vec<ApiVersionPin>, String> {
+    if value.trim().is_empty() {
+        return Ok(Vec::new());
+    }
+    let pins: Vec<ApiVersionPin> =
+        serde_json::from_str(value).map_err(|e| format!("invalid API version pins: {e}"))?;
+    for pin in &pins {
+        Pin::new(pin).map_err(|e| format!("API version pin for {}: {e}", pin.env))?;
+    }
+    Ok(pins)
+}
+
+#[derive(Debug)]
+struct Pin {
+    env: String,
+    header: HeaderName,
+    version: HeaderValue,
+    response_header: HeaderName,
+    accepted: Vec<String>,
+    require_response_version: bool,
+}
+
+impl Pin {
+    fn new(pin: &ApiVersionPin) -> Result<Self, String> {
+        let name = |name: &str| {
+            HeaderName::from_bytes(name.as_bytes()).map_err(|_| format!("invalid header {name:?}"))
+        };
+        let header = name(&pin.header)?;
+        Ok(Self {
+            env: pin.env.clone(),
+            response_header: match &pin.response_header {
+                Some(response_header) => name(response_header)?,
+                None => header.clone(),
+            },
+            header,
+            version: HeaderValue::from_str(&pin.version)
+                .map_err(|_| format!("invalid version {:?}", pin.version))?,
+            accepted: match pin.accepted.is_empty() {
+                true => vec![pin.version.clone()],
+                false => pin.accepted.clone(),
+            },
+            require_response_version: pin.require_response_version,
+        })
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ApiVersions {
+    pins: Vec<Pin>,
+}
+
+impl ApiVersions {
+    /// Pins that fail to parse were already rejected with the config.
+    pub fn new(pins: &[ApiVersionPin]) -> Self {
+        Self {
+            pins: pins.iter().filter_map(|pin| Pin::new(pin).ok()).collect(),
+        }
+    }
+
+    /// Sets the pinned version header on a request to a pinned env.
+    pub fn apply(&self, req: &mut OutboundRequest) {
+        if let Some(pin) = self.pin(&req.env) {
+            req.headers.insert(pin.header.clone(), pin.version.clone());
+        }
+    }
+
+    /// Refuses a response advertising a version the env's pin doesn't accept.
+    pub fn check(&self, req: &OutboundRequest, res: &UpstreamResponse) -> Result<(), ProxyError> {
+        let Some(pin) = self.pin(&req.env) else {
+            return Ok(());
+        };
+        let advertised = res
+            .headers
+            .get(&pin.response_header)
+            .map(|value| String::from_utf8_lossy(value.as_bytes()).trim().to_string());
+        let accepted = match &advertised {
+            Some(version) => pin.accepted.iter().any(|accepted| accepted == version),
+            None => !pin.require_response_version,
+        };
+        if accepted {
+            return Ok(());
+        }
+        error!(
+            "{} answered {} with API version {} in {}; accepted: {}",
+            req.env,
+            req.uri,
+            advertised.as_deref().unwrap_or("(none)"),
+            pin.response_header,
+            pin.accepted.join(", ")
+        );
+        Err(ProxyError::ApiVersionMismatch)
+    }
+
+    fn pin(&self, env: &str) -> Option<&Pin> {
+        self.pins.iter().find(|pin| pin.env == env)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::{HeaderMap, Method, StatusCode};
+
+    use super::*;
+
+    fn pinned(accepted: &[&str], required: bool) -> ApiVersions {
+        ApiVersions::new(&[ApiVersionPin {
+            env: "prod".to_string(),
+            header: "X-Api-Version".to_string(),
+            version: "2024-01".to_string(),
+            response_header: None,
+            accepted: accepted.iter().map(|v| v.to_string()).collect(),
+            require_response_version: required,
+        }])
+    }
+
+    fn exchange(env: &str, version: Option<&'static str>) -> (OutboundRequest, UpstreamResponse) {
+        let mut headers = HeaderMap::new();
+        if let Some(version) = version {
+            headers.insert("x-api-version", HeaderValue::from_static(version));
+        }
+        (
+            OutboundRequest {
+                env: env.to_string(),
+                method: Method::GET,
+                uri: "http://supplier/hotels".parse().unwrap(),
+                headers: HeaderMap::new(),
+                body: Default::default(),
+            },
+            UpstreamResponse {
+                status: StatusCode::OK,
+                headers,
+                body: Default::default(),
+                trailers: None,
+            },
+        )
+    }
+
+    #[test]
+    fn pins_requests_and_checks_responses() {
+        let versions = pinned(&[], false);
+        let (mut req, res) = exchange("prod", Some("2024-01"));
+        req.headers
+            .insert("x-api-version", HeaderValue::from_static("2019-06"));
+        versions.apply(&mut req);
+        assert_eq!(req.headers["x-api-version"], "2024-01");
+        assert!(versions.check(&req, &res).is_ok());
+
+        let (req, res) = exchange("prod", Some("2025-02"));
+        assert_eq!(
+            versions.check(&req, &res),
+            Err(ProxyError::ApiVersionMismatch)
+        );
+        // Other envs, and responses without the header, pass.
+        let (req, res) = exchange("test", Some("2025-02"));
+        assert!(versions.check(&req, &res).is_ok());
+        let (req, res) = exchange("prod", None);
+        assert!(versions.check(&req, &res).is_ok());
+        assert!(pinned(&[], true).check(&req, &res).is_err());
+
+        let (req, res) = exchange("prod", Some("2024-01.2"));
+        assert!(pinned(&["2024-01", "2024-01.2"], false)
+            .check(&req, &res)
+            .is_ok());
+    }
+
+    #[test]
+    fn rejects_invalid_pins() {
+        assert!(parse_api_version_pins(
+            r#"[{"env": "prod", "header": "bad header", "version": "1"}]"#
+        )
+        .is_err());
+        assert_eq!(parse_api_version_pins("").unwrap(), []);
+    }
+}
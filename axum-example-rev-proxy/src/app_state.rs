@@ -1,37 +1,897 @@
 use serde::Deserialize;
-use std::env::VarError;
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
 use thiserror::Error;
 
+use crate::api_version::{parse_api_version_pins, ApiVersionPin, ApiVersions};
+use crate::balancer::Balancer;
+use crate::batch::{DEFAULT_BATCH_CONCURRENCY, DEFAULT_BATCH_MAX_REQUESTS};
+use crate::cache::{
+    parse_cache_rules, parse_invalidation_rules, CacheRule, InvalidationRule,
+    DEFAULT_CREDENTIAL_HEADERS,
+};
+use crate::cache::{CacheStore, MemoryCacheStore, ResponseCache};
+use crate::canonical::Canonicalization;
+use crate::capture::Capture;
+use crate::client_tag::{ClientTagConfig, ClientTags, DEFAULT_MAX_CLIENT_TAGS};
+use crate::coalesce::{parse_coalesce_rules, CoalesceRule, Coalescer};
+use crate::config::ProxyConfig;
+use crate::connection_limit::ConnectionLimiter;
+use crate::contract::{parse_openapi_specs, Contract};
+use crate::correlation::{Correlation, DEFAULT_CORRELATION_HEADER};
+use crate::cors::{parse_cors_rules, Cors, CorsRule};
+use crate::dashboard::{SlowRequests, DEFAULT_SLOW_REQUEST_THRESHOLD};
+use crate::debug_log::{DebugLog, DebugLogConfig, DEFAULT_MAX_LOGGED_BYTES, DEFAULT_TRACE_HEADER};
+use crate::dedup::{DedupStore, MemoryDedupStore};
+use crate::dns::{parse_resolvers, DnsResolver, FamilyPolicy, ResolverSpec};
+use crate::egress_ip::{EgressIp, EgressIpConfig, EgressIpSource, DEFAULT_ECHO_URL};
+use crate::egress_policy::EgressPolicy;
+use crate::email::{EmailBridge, EmailConfig};
+use crate::encoding;
+use crate::failover::{Failover, FailoverConfig};
+use crate::fair_queue::FairQueue;
+use crate::fault::Faults;
+use crate::forward_proxy::{parse_destinations, ForwardProxyConfig, DEFAULT_CONNECT_TIMEOUT};
+use crate::header_limits::{
+    HeaderLimits, DEFAULT_MAX_COUNT, DEFAULT_MAX_HEADER_BYTES, DEFAULT_MAX_TOTAL_BYTES,
+};
+use crate::hedge::{HedgeConfig, Hedger};
+use crate::interceptor::Interceptors;
+use crate::latency::{AdaptiveTimeoutConfig, LatencyTracker};
+use crate::log_control::LogControl;
+use crate::maintenance::{parse_maintenance_windows, Maintenance, MaintenanceWindow};
+use crate::masking::{parse_masking_rules, Masking, MaskingConfig};
+use crate::memory_budget::{MemoryBudget, DEFAULT_MAX_INFLIGHT_BYTES};
+use crate::metrics::Metrics;
+use crate::nowpayments_ipn_webhook::IpnSecret;
+use crate::pause::{parse_pause_rules, PauseConfig, TrafficPause};
+use crate::prober::{parse_probes, ProbeConfig, Prober};
+use crate::profile;
+use crate::rate_limit::RateLimiter;
+use crate::rate_limit::{
+    parse_rate_limit, parse_rate_limit_schedules, MemoryRateLimitStore, RateLimit, RateLimitStore,
+    ScheduledRateLimit,
+};
+use crate::rbac::{parse_admin_tokens, AdminToken};
+use crate::request_log::{ArchiveConfig, RequestLog, RequestLogConfig, DEFAULT_REDACT_KEYS};
+use crate::response_headers::{parse_response_header_rules, ResponseHeaderRule, ResponseHeaders};
+use crate::schedule::Scheduler;
+use crate::server::{RuntimeConfig, ServerConfig, DEFAULT_THREAD_NAME};
+use crate::slo::{parse_slo_rules, SloRule, SloTracker};
+use crate::soap::{self, parse_soap_rules, SoapRule};
+use crate::storage::{open_storage, Storage, StorageStores};
+use crate::store_forward::{
+    StoreForward, StoreForwardConfig, DEFAULT_MAX_ATTEMPTS, DEFAULT_RETRY_BASE,
+};
+use crate::tail::Tail;
+use crate::tcp_tunnel::{parse_tunnels, TcpTunnel};
+use crate::tenant::{self, TenantConfig, Tenants};
+use crate::transfers::{Transfers, DEFAULT_LARGE_TRANSFER_BYTES, DEFAULT_PROGRESS_INTERVAL};
+use crate::udp_relay::{parse_relays, UdpRelay, DEFAULT_IDLE_TIMEOUT};
+use crate::upstream_errors::{UpstreamErrors, DEFAULT_UPSTREAM_ERROR_CAPACITY};
+use crate::xml_json::{parse_xml_json_rules, XmlJsonRule};
+
 #[derive(Debug, Clone, Deserialize)]
 #[serde(rename_all = "SCREAMING_SNAKE_CASE")]
 pub struct EnvVarConfig {
     pub ipn_secret: String,
+    /// Further secrets accepted alongside `ipn_secret` while it's rotated.
+    pub ipn_secrets: Vec<IpnSecret>,
+    /// Upstream base URLs keyed by the `{env}` path segment.
+    pub upstreams: BTreeMap<String, String>,
+    /// Further supplier nodes per env, balanced with the env's upstream.
+    pub upstream_nodes: BTreeMap<String, Vec<String>>,
+    /// Header whose value (e.g. a booking session ID) pins requests to one
+    /// node; requests without it are spread round-robin.
+    pub sticky_session_header: Option<String>,
+    /// Secondary upstreams in other regions, and when to fail over to them.
+    pub failover: FailoverConfig,
+    pub nowpayments_allowed_ips: Vec<IpAddr>,
+    /// How long a verified IPN is remembered to reject replays; zero disables.
+    pub webhook_replay_ttl: Duration,
+    /// What IPN signatures are computed over: a canonical form of the body,
+    /// its raw bytes with keys sorted, or the raw body as received.
+    pub nowpayments_canonicalization: Canonicalization,
+    /// Serves `POST /admin/simulate-webhook`, which signs IPNs with the
+    /// primary secret; for staging only.
+    pub nowpayments_test_mode: bool,
+    /// Rhai script applied to every outbound request (`scripting` feature).
+    pub request_script_path: Option<String>,
+    /// Bearer token for `/admin/*`, with the `admin` role; the admin API is
+    /// off when neither this nor `admin_tokens` is set.
+    pub admin_token: Option<String>,
+    /// Named tokens with their roles; see [`crate::rbac`].
+    pub admin_tokens: Vec<AdminToken>,
+    /// Where counters are saved on shutdown and imported from on startup,
+    /// so lifetime totals survive a planned restart.
+    pub metrics_snapshot_path: Option<String>,
+    pub cache_rules: Vec<CacheRule>,
+    pub cache_invalidation_rules: Vec<InvalidationRule>,
+    /// Request headers, besides `Authorization`, holding the caller's own
+    /// credentials. Cached answers are only shared between callers sending
+    /// the same values.
+    pub credential_headers: Vec<String>,
+    /// Prefixes where identical requests share a call; see [`crate::coalesce`].
+    pub coalesce_rules: Vec<CoalesceRule>,
+    /// Routes whose JSON requests go upstream as XML and whose XML responses
+    /// come back as JSON.
+    pub xml_json_rules: Vec<XmlJsonRule>,
+    /// Routes whose requests are wrapped in a SOAP envelope and whose
+    /// responses are unwrapped from one.
+    pub soap_rules: Vec<SoapRule>,
+    /// OpenAPI spec file per environment; traffic is checked against it in
+    /// shadow mode.
+    pub openapi_specs: BTreeMap<String, String>,
+    /// Per-route overrides of the headers returned to callers.
+    pub response_header_rules: Vec<ResponseHeaderRule>,
+    /// Per-route CORS policies; preflights to these routes are answered locally.
+    pub cors_rules: Vec<CorsRule>,
+    /// Per-client request limit; unlimited when unset.
+    pub rate_limit: Option<RateLimit>,
+    /// Limits that replace `rate_limit` during recurring windows.
+    pub rate_limit_schedules: Vec<ScheduledRateLimit>,
+    /// Shares cache, rate-limit and webhook replay state through Redis
+    /// (`redis` feature).
+    pub redis_url: Option<String>,
+    /// One backend for persistent features; see [`crate::storage`].
+    pub storage_url: Option<String>,
+    /// Per-request summaries in SQLite/Postgres (`request_log` feature).
+    pub request_log: Option<RequestLogConfig>,
+    /// Uploads aged request log entries to S3/GCS (`archive` feature).
+    pub archive: Option<ArchiveConfig>,
+    pub debug_log: DebugLogConfig,
+    pub egress_ip: EgressIpConfig,
+    /// Skips dropping private/loopback/link-local DNS answers, for upstreams
+    /// that live on an internal network.
+    pub allow_internal_destinations: bool,
+    /// Resolvers for upstream hostnames, tried in order.
+    pub dns_resolvers: Vec<ResolverSpec>,
+    /// How long a hostname that failed to resolve is cached, doubling while
+    /// it keeps failing. Off when unset.
+    pub dns_negative_ttl: Option<Duration>,
+    /// Re-resolves an upstream's hostname, bypassing cached answers, when
+    /// connecting to it fails, and retries the request once.
+    pub dns_refresh_on_connect_failure: bool,
+    /// Which address families upstream hostnames resolve to, and in which
+    /// order they're tried.
+    pub upstream_ip_family: FamilyPolicy,
+    /// Environments whose upstream gets header names in title case
+    /// (`Api-Key`) over HTTP/1.1, for suppliers that match them exactly.
+    pub title_case_header_envs: Vec<String>,
+    /// Whether the upstream client decodes compressed bodies rather than
+    /// passing them through; see [`crate::encoding`].
+    pub upstream_decompression: bool,
+    /// Inbound keep-alive and timeouts, used by [`crate::server::serve`].
+    pub server: ServerConfig,
+    /// Header carrying the correlation ID shared with suppliers.
+    pub correlation_header: String,
+    /// Inbound requests over these get a 431.
+    pub header_limits: HeaderLimits,
+    /// Hedged GETs for the configured routes; off when unset.
+    pub hedge: Option<HedgeConfig>,
+    /// Per-route upstream timeouts from recent latencies; the client's static
+    /// timeout applies when unset.
+    pub adaptive_timeout: Option<AdaptiveTimeoutConfig>,
+    /// Upstream calls allowed at once; further requests queue fairly per
+    /// client. Unlimited when unset.
+    pub upstream_concurrency: Option<usize>,
+    /// Longest a request waits in that queue, or for a connection slot,
+    /// before a 503.
+    pub queue_timeout: Duration,
+    /// Simultaneous connections allowed to each upstream host; unlimited
+    /// when unset.
+    pub max_connections_per_host: Option<usize>,
+    /// Body bytes all in-flight requests may buffer at once; requests past
+    /// it get a 503. Unlimited when unset.
+    pub max_inflight_body_bytes: Option<usize>,
+    /// Requests taking at least this long are listed on the dashboard.
+    pub slow_request_threshold: Duration,
+    /// Inbound path prefixes whose upstream body, if it fails partway, is
+    /// returned as far as it got rather than as a 502.
+    pub partial_response_paths: Vec<String>,
+    /// Inbound path prefixes whose JSON responses honour `?_fields=`; see
+    /// [`crate::fields`].
+    pub field_filter_paths: Vec<String>,
+    /// Masks PII in responses to chosen API keys; see [`crate::masking`].
+    pub masking: MaskingConfig,
+    /// Fails an upstream body that sends nothing for this long, rather than
+    /// waiting out the whole request timeout. Off when unset.
+    pub upstream_body_idle_timeout: Option<Duration>,
+    /// Upstream bodies at least this big have their progress logged and
+    /// listed at `/admin/transfers`. Off when unset.
+    pub large_transfer_bytes: Option<usize>,
+    /// How often a large transfer's progress is logged.
+    pub transfer_progress_interval: Duration,
+    /// Upstream 5xx responses kept for `/admin/upstream-errors`; none when
+    /// unset.
+    pub upstream_error_capacity: Option<usize>,
+    /// Per-upstream objectives reported at `/slo`.
+    pub slo_rules: Vec<SloRule>,
+    /// Pauses an env whose upstream keeps failing; see [`crate::pause`].
+    pub traffic_pause: PauseConfig,
+    /// Supplier maintenance windows; see [`crate::maintenance`].
+    pub maintenance_windows: Vec<MaintenanceWindow>,
+    /// The API version sent to each supplier; see [`crate::api_version`].
+    pub api_version_pins: Vec<ApiVersionPin>,
+    /// Which `X-Client-Tag`s are counted; see [`crate::client_tag`].
+    pub client_tags: ClientTagConfig,
+    /// Answers writes to these paths with 202 and delivers them later; see
+    /// [`crate::store_forward`]. Off when unset.
+    pub store_forward: Option<StoreForwardConfig>,
+    /// Keeps scheduled requests across restarts; see [`crate::schedule`].
+    pub scheduled_requests_path: Option<String>,
+    /// Sub-requests allowed in one `POST /{env}/_batch`; the endpoint is off
+    /// when unset.
+    pub batch_max_requests: Option<usize>,
+    /// Sub-requests of one batch in flight at once.
+    pub batch_concurrency: usize,
+    /// Synthetic calls made on a timer, reported at `/admin/probes`.
+    pub probes: Vec<ProbeConfig>,
+    /// `CONNECT` listener for tools that can't use `/{env}` paths; off when
+    /// unset.
+    pub forward_proxy: Option<ForwardProxyConfig>,
+    /// SOCKS5 listener (`socks5` feature) for jobs that only speak SOCKS;
+    /// off when unset.
+    pub socks5: Option<ForwardProxyConfig>,
+    /// L4 tunnels from local addresses to supplier `host:port`s.
+    pub tcp_tunnels: Vec<TcpTunnel>,
+    /// Client IPs allowed through the tunnels and UDP relays; any when
+    /// empty.
+    pub tcp_tunnel_allowed_clients: Vec<IpAddr>,
+    /// Experimental UDP relays from local addresses to supplier `host:port`s.
+    pub udp_relays: Vec<UdpRelay>,
+    /// How long a relay session lasts without a reply from the remote.
+    pub udp_relay_idle_timeout: Duration,
+    /// `POST /email/send` bridge to the SMTP provider; off when unset.
+    pub email: Option<EmailConfig>,
+    /// Namespaces served under their own route prefix; see [`crate::tenant`].
+    pub tenants: Vec<TenantConfig>,
 }
 
 impl EnvVarConfig {
     pub fn try_from_env() -> Self {
+        let typed = ProxyConfig::load().unwrap_or_else(|e| panic!("invalid config: {e}"));
+        let ProxyConfig {
+            listeners,
+            upstreams,
+            security,
+            metrics,
+            webhooks,
+        } = typed;
         let value = Self {
-            // todo add secret when available in gh actions
-            ipn_secret: env_w_default("NOWPAYMENTS_IPN_SECRET", "dummy-secret-for-now").unwrap(),
+            ipn_secret: webhooks.ipn_secret,
+            ipn_secrets: webhooks.ipn_secrets,
+            upstreams: BTreeMap::from([
+                ("test".to_string(), upstreams.test_url),
+                ("prod".to_string(), upstreams.prod_url),
+            ]),
+            upstream_nodes: [
+                ("test", upstreams.test_nodes),
+                ("prod", upstreams.prod_nodes),
+            ]
+            .into_iter()
+            .map(|(env, nodes)| (env.to_string(), nodes))
+            .filter(|(_, nodes)| !nodes.is_empty())
+            .collect(),
+            sticky_session_header: upstreams.sticky_session_header,
+            failover: FailoverConfig {
+                secondaries: [
+                    ("test", upstreams.test_secondary_url),
+                    ("prod", upstreams.prod_secondary_url),
+                ]
+                .into_iter()
+                .filter_map(|(env, url)| Some((env.to_string(), url?)))
+                .collect(),
+                failure_threshold: upstreams.failover_failure_threshold.max(1),
+                retry_primary_after: Duration::from_secs(upstreams.failover_retry_primary_secs),
+            },
+            nowpayments_allowed_ips: security.nowpayments_allowed_ips,
+            webhook_replay_ttl: Duration::from_secs(webhooks.replay_ttl_secs),
+            nowpayments_canonicalization: webhooks.canonicalization,
+            nowpayments_test_mode: webhooks.test_mode,
+            request_script_path: env_wo_default("REQUEST_SCRIPT_PATH").unwrap(),
+            admin_token: security.admin_token,
+            admin_tokens: parse_admin_tokens(&security.admin_tokens.join(","))
+                .unwrap_or_else(|e| panic!("security.admin_tokens (ADMIN_TOKENS): {e}")),
+            metrics_snapshot_path: metrics.snapshot_path,
+            xml_json_rules: parse_xml_json_rules(&env_w_default("XML_JSON_RULES", "").unwrap())
+                .unwrap(),
+            soap_rules: parse_soap_rules(&env_w_default("SOAP_RULES", "").unwrap()).unwrap(),
+            openapi_specs: parse_openapi_specs(&env_w_default("OPENAPI_SPECS", "").unwrap())
+                .unwrap(),
+            cache_rules: parse_cache_rules(&env_w_default("RESPONSE_CACHE_RULES", "").unwrap())
+                .unwrap(),
+            coalesce_rules: parse_coalesce_rules(&env_w_default("COALESCE_RULES", "").unwrap())
+                .unwrap(),
+            cache_invalidation_rules: parse_invalidation_rules(
+                &env_w_default("CACHE_INVALIDATION_RULES", "").unwrap(),
+            )
+            .unwrap(),
+            credential_headers: parse_list(
+                &env_w_default("CREDENTIAL_HEADERS", DEFAULT_CREDENTIAL_HEADERS).unwrap(),
+            ),
+            response_header_rules: parse_response_header_rules(
+                &env_w_default("RESPONSE_HEADER_RULES", "").unwrap(),
+            )
+            .unwrap(),
+            cors_rules: parse_cors_rules(&env_w_default("CORS_RULES", "").unwrap()).unwrap(),
+            rate_limit: env_wo_default("RATE_LIMIT")
+                .unwrap()
+                .map(|value| parse_rate_limit(&value).unwrap()),
+            rate_limit_schedules: parse_rate_limit_schedules(
+                &env_w_default("RATE_LIMIT_SCHEDULES", "").unwrap(),
+            )
+            .unwrap(),
+            redis_url: env_wo_default("REDIS_URL").unwrap(),
+            storage_url: env_wo_default("STORAGE_URL").unwrap(),
+            request_log: env_wo_default("REQUEST_LOG_URL")
+                .unwrap()
+                .map(|url| RequestLogConfig {
+                    url,
+                    bodies: env_w_default("REQUEST_LOG_BODIES", "false").unwrap() == "true",
+                    redact_keys: env_wo_default("REQUEST_LOG_REDACT_KEYS")
+                        .unwrap()
+                        .map(|keys| parse_list(&keys))
+                        .unwrap_or_else(|| {
+                            DEFAULT_REDACT_KEYS.iter().map(|k| k.to_string()).collect()
+                        }),
+                }),
+            archive: env_wo_default("ARCHIVE_URL")
+                .unwrap()
+                .map(|url| ArchiveConfig {
+                    url,
+                    interval: Duration::from_secs(env_u64("ARCHIVE_INTERVAL_SECS", "3600")),
+                    archive_after: Duration::from_secs(env_u64("ARCHIVE_AFTER_SECS", "86400")),
+                    retention: match env_u64("ARCHIVE_RETENTION_DAYS", "0") {
+                        0 => None,
+                        days => Some(Duration::from_secs(days * 24 * 60 * 60)),
+                    },
+                    compression_level: env_u64("ARCHIVE_COMPRESSION_LEVEL", "6") as u32,
+                }),
+            debug_log: DebugLogConfig {
+                sample_rate: env_u64("DEBUG_LOG_SAMPLE_RATE", "0"),
+                trace_header: Some(
+                    env_w_default("DEBUG_LOG_TRACE_HEADER", DEFAULT_TRACE_HEADER).unwrap(),
+                ),
+                max_logged_bytes: env_u64(
+                    "DEBUG_LOG_MAX_BYTES",
+                    &DEFAULT_MAX_LOGGED_BYTES.to_string(),
+                ) as usize,
+                pretty_json: env_w_default("DEBUG_LOG_PRETTY_JSON", "true").unwrap() == "true",
+            },
+            egress_ip: EgressIpConfig {
+                source: EgressIpSource::parse(
+                    &env_w_default("EGRESS_IP_SOURCE", DEFAULT_ECHO_URL).unwrap(),
+                ),
+                expected: env_wo_default("EXPECTED_EGRESS_IP").unwrap().map(|ip| {
+                    ip.trim()
+                        .parse()
+                        .unwrap_or_else(|e| panic!("EXPECTED_EGRESS_IP must be an IP: {e}"))
+                }),
+                cache_ttl: Duration::from_secs(env_u64("EGRESS_IP_CACHE_SECS", "300")),
+                check_interval: Duration::from_secs(env_u64("EGRESS_IP_CHECK_INTERVAL_SECS", "60")),
+                alert_webhook_url: env_wo_default("EGRESS_IP_ALERT_WEBHOOK_URL").unwrap(),
+            },
+            allow_internal_destinations: env_w_default("EGRESS_ALLOW_INTERNAL", "false").unwrap()
+                == "true",
+            dns_resolvers: parse_resolvers(&env_w_default("DNS_RESOLVERS", "system").unwrap())
+                .unwrap(),
+            dns_negative_ttl: match env_u64("DNS_NEGATIVE_TTL_SECS", "5") {
+                0 => None,
+                secs => Some(Duration::from_secs(secs)),
+            },
+            dns_refresh_on_connect_failure: env_w_default("DNS_REFRESH_ON_CONNECT_FAILURE", "true")
+                .unwrap()
+                == "true",
+            upstream_ip_family: env_w_default("UPSTREAM_IP_FAMILY", "prefer_v4")
+                .unwrap()
+                .parse()
+                .unwrap(),
+            title_case_header_envs: parse_list(
+                &env_w_default("TITLE_CASE_HEADER_ENVS", "").unwrap(),
+            ),
+            upstream_decompression: env_w_default("UPSTREAM_DECOMPRESS", "false").unwrap()
+                == "true",
+            server: listeners.server_config(),
+            correlation_header: env_w_default("CORRELATION_HEADER", DEFAULT_CORRELATION_HEADER)
+                .unwrap(),
+            header_limits: HeaderLimits {
+                max_total_bytes: env_limit("MAX_REQUEST_HEADER_BYTES", DEFAULT_MAX_TOTAL_BYTES),
+                max_header_bytes: env_limit("MAX_HEADER_BYTES", DEFAULT_MAX_HEADER_BYTES),
+                max_count: env_limit("MAX_REQUEST_HEADERS", DEFAULT_MAX_COUNT),
+            },
+            hedge: env_wo_default("HEDGE_PATHS")
+                .unwrap()
+                .map(|paths| HedgeConfig {
+                    path_prefixes: parse_list(&paths),
+                    percentile: env_u64("HEDGE_PERCENTILE", "95") as f64 / 100.0,
+                    min_delay: Duration::from_millis(env_u64("HEDGE_MIN_DELAY_MS", "50")),
+                    max_in_flight: env_u64("HEDGE_MAX_IN_FLIGHT", "10") as usize,
+                    budget_ratio: env_u64("HEDGE_BUDGET_PERCENT", "10") as f64 / 100.0,
+                }),
+            adaptive_timeout: (env_w_default("ADAPTIVE_TIMEOUT", "false").unwrap() == "true").then(
+                || AdaptiveTimeoutConfig {
+                    percentile: env_u64("ADAPTIVE_TIMEOUT_PERCENTILE", "99") as f64 / 100.0,
+                    factor: env_w_default("ADAPTIVE_TIMEOUT_FACTOR", "3")
+                        .unwrap()
+                        .parse()
+                        .expect("ADAPTIVE_TIMEOUT_FACTOR must be a number"),
+                    min: Duration::from_millis(env_u64("ADAPTIVE_TIMEOUT_MIN_MS", "1000")),
+                    max: Duration::from_millis(env_u64("ADAPTIVE_TIMEOUT_MAX_MS", "30000")),
+                },
+            ),
+            upstream_concurrency: match env_u64("UPSTREAM_CONCURRENCY", "0") {
+                0 => None,
+                limit => Some(limit as usize),
+            },
+            queue_timeout: Duration::from_secs(env_u64("QUEUE_TIMEOUT_SECS", "30")),
+            max_connections_per_host: match env_u64("UPSTREAM_MAX_CONNECTIONS_PER_HOST", "0") {
+                0 => None,
+                limit => Some(limit as usize),
+            },
+            max_inflight_body_bytes: env_limit(
+                "MAX_INFLIGHT_BODY_BYTES",
+                DEFAULT_MAX_INFLIGHT_BYTES,
+            ),
+            slow_request_threshold: Duration::from_millis(env_u64(
+                "SLOW_REQUEST_MS",
+                &DEFAULT_SLOW_REQUEST_THRESHOLD.as_millis().to_string(),
+            )),
+            partial_response_paths: parse_list(
+                &env_w_default("PARTIAL_RESPONSE_PATHS", "").unwrap(),
+            ),
+            field_filter_paths: parse_list(&env_w_default("FIELD_FILTER_PATHS", "").unwrap()),
+            masking: MaskingConfig {
+                rules: parse_masking_rules(&env_w_default("MASKING_RULES", "").unwrap()).unwrap(),
+                hash_key: env_wo_default("MASKING_HASH_KEY").unwrap(),
+            },
+            upstream_body_idle_timeout: match env_u64("UPSTREAM_BODY_IDLE_TIMEOUT_MS", "0") {
+                0 => None,
+                ms => Some(Duration::from_millis(ms)),
+            },
+            large_transfer_bytes: env_limit("LARGE_TRANSFER_BYTES", DEFAULT_LARGE_TRANSFER_BYTES),
+            transfer_progress_interval: Duration::from_secs(env_u64(
+                "TRANSFER_PROGRESS_SECS",
+                &DEFAULT_PROGRESS_INTERVAL.as_secs().to_string(),
+            )),
+            upstream_error_capacity: env_limit(
+                "UPSTREAM_ERROR_CAPTURE",
+                DEFAULT_UPSTREAM_ERROR_CAPACITY,
+            ),
+            slo_rules: parse_slo_rules(&env_w_default("SLO_RULES", "").unwrap()).unwrap(),
+            traffic_pause: PauseConfig {
+                rules: parse_pause_rules(&env_w_default("TRAFFIC_PAUSE_RULES", "").unwrap())
+                    .unwrap(),
+                alert_webhook_url: env_wo_default("TRAFFIC_PAUSE_ALERT_WEBHOOK_URL").unwrap(),
+            },
+            maintenance_windows: parse_maintenance_windows(
+                &env_w_default("MAINTENANCE_WINDOWS", "").unwrap(),
+            )
+            .unwrap(),
+            api_version_pins: parse_api_version_pins(
+                &env_w_default("API_VERSION_PINS", "").unwrap(),
+            )
+            .unwrap(),
+            client_tags: ClientTagConfig {
+                allowed: parse_list(&env_w_default("CLIENT_TAGS", "").unwrap()),
+                max_tags: env_u64("CLIENT_TAG_LIMIT", &DEFAULT_MAX_CLIENT_TAGS.to_string())
+                    as usize,
+            },
+            store_forward: store_forward_from_env(),
+            scheduled_requests_path: env_wo_default("SCHEDULED_REQUESTS_PATH").unwrap(),
+            batch_max_requests: env_limit("BATCH_MAX_REQUESTS", DEFAULT_BATCH_MAX_REQUESTS),
+            batch_concurrency: env_u64("BATCH_CONCURRENCY", &DEFAULT_BATCH_CONCURRENCY.to_string())
+                as usize,
+            probes: parse_probes(&env_w_default("PROBES", "").unwrap()).unwrap(),
+            forward_proxy: forward_proxy_from_env("FORWARD_PROXY_ADDR"),
+            socks5: forward_proxy_from_env("SOCKS5_ADDR"),
+            tcp_tunnels: parse_tunnels(&env_w_default("TCP_TUNNELS", "").unwrap()).unwrap(),
+            tcp_tunnel_allowed_clients: parse_ip_list(
+                &env_w_default("TCP_TUNNEL_ALLOWED_CLIENTS", "").unwrap(),
+            )
+            .unwrap(),
+            udp_relays: parse_relays(&env_w_default("UDP_RELAYS", "").unwrap()).unwrap(),
+            udp_relay_idle_timeout: Duration::from_secs(env_u64(
+                "UDP_RELAY_IDLE_SECS",
+                &DEFAULT_IDLE_TIMEOUT.as_secs().to_string(),
+            )),
+            email: env_wo_default("EMAIL_SMTP_URL")
+                .unwrap()
+                .map(|smtp_url| EmailConfig {
+                    smtp_url,
+                    token: env_wo_default("EMAIL_BRIDGE_TOKEN")
+                        .unwrap()
+                        .expect("EMAIL_BRIDGE_TOKEN must be set with EMAIL_SMTP_URL"),
+                    allowed_senders: parse_list(
+                        &env_w_default("EMAIL_ALLOWED_SENDERS", "").unwrap(),
+                    ),
+                    rate_limit: env_wo_default("EMAIL_RATE_LIMIT")
+                        .unwrap()
+                        .map(|value| parse_rate_limit(&value).unwrap()),
+                }),
+            tenants: tenants_from_env(),
         };
 
         // println!("{value:#?}");
+        for (setting, source, closest) in profile::unread() {
+            match closest {
+                Some(closest) => tracing::warn!(
+                    "{setting} in the config file's {source} is never read; did you mean {closest}?"
+                ),
+                None => tracing::warn!(
+                    "{setting} in the config file's {source} is never read: unknown, or its feature is off"
+                ),
+            }
+        }
         value
     }
+
+    /// Returns the upstream base URL for the `env` path segment, if known.
+    /// Tenant envs are looked up by their `{tenant}.{env}` name.
+    pub fn upstream_for(&self, env: &str) -> Option<&str> {
+        match tenant::split_env(env) {
+            Some((tenant, env)) => self
+                .tenants
+                .iter()
+                .find(|t| t.name == tenant)?
+                .upstreams
+                .get(env)
+                .map(String::as_str),
+            None => self.upstreams.get(env).map(String::as_str),
+        }
+    }
 }
 
 /// Application state shared by handlers.
 #[derive(Clone)]
 pub struct AppState {
     pub client: reqwest::Client,
+    /// Sends header names in title case, for `title_case_header_envs`.
+    pub title_case_client: Option<reqwest::Client>,
     pub env_var_config: EnvVarConfig,
+    pub metrics: Arc<Metrics>,
+    pub interceptors: Arc<Interceptors>,
+    pub cache: Arc<ResponseCache>,
+    pub coalescer: Arc<Coalescer>,
+    pub rate_limiter: Option<Arc<RateLimiter>>,
+    /// Signatures of verified IPNs, for replay protection.
+    pub webhook_dedup: Arc<dyn DedupStore>,
+    pub request_log: Option<Arc<RequestLog>>,
+    /// Set when the process installed its subscriber via
+    /// [`crate::log_control::init_tracing`]; enables `/admin/loglevel`.
+    pub log_control: Option<LogControl>,
+    pub debug_log: Arc<DebugLog>,
+    pub egress_ip: Arc<EgressIp>,
+    /// Destinations outbound requests may reach, derived from `upstreams`.
+    pub egress_policy: Arc<EgressPolicy>,
+    pub response_headers: Arc<ResponseHeaders>,
+    pub cors: Arc<Cors>,
+    pub correlation: Arc<Correlation>,
+    /// Recent upstream latencies per inbound route.
+    pub latency: Arc<LatencyTracker>,
+    pub hedger: Option<Arc<Hedger>>,
+    pub upstream_queue: Option<Arc<FairQueue>>,
+    pub connection_limiter: Option<Arc<ConnectionLimiter>>,
+    pub memory_budget: Option<Arc<MemoryBudget>>,
+    /// Upstream OpenAPI contracts by environment.
+    pub contracts: Arc<BTreeMap<String, Contract>>,
+    pub slow_requests: Arc<SlowRequests>,
+    /// Completed requests for `/admin/tail` subscribers.
+    pub tail: Arc<Tail>,
+    /// Large upstream bodies in flight.
+    pub transfers: Arc<Transfers>,
+    /// Exchanges recorded by an admin-armed capture rule.
+    pub capture: Arc<Capture>,
+    /// Recent upstream 5xx responses.
+    pub upstream_errors: Arc<UpstreamErrors>,
+    /// Admin-set fault injection rules.
+    pub faults: Arc<Faults>,
+    /// Primary upstream health, for envs with a secondary.
+    pub failover: Arc<Failover>,
+    /// Picks the node of multi-node envs.
+    pub balancer: Arc<Balancer>,
+    /// The resolver chain behind the upstream client, for its health.
+    pub dns: Arc<DnsResolver>,
+    pub slo: Arc<SloTracker>,
+    pub traffic_pause: Arc<TrafficPause>,
+    pub maintenance: Arc<Maintenance>,
+    pub api_versions: Arc<ApiVersions>,
+    pub client_tags: Arc<ClientTags>,
+    pub store_forward: Option<Arc<StoreForward>>,
+    pub scheduler: Arc<Scheduler>,
+    pub masking: Arc<Masking>,
+    pub prober: Arc<Prober>,
+    pub email: Option<Arc<EmailBridge>>,
+    pub tenants: Arc<Tenants>,
 }
 
 impl AppState {
     pub async fn build(client: reqwest::Client) -> Self {
+        Self::new(client, EnvVarConfig::try_from_env())
+    }
+
+    /// `client` is used as given; [`crate::encoding::configure_client`]
+    /// matches its decompression to `upstream_decompression`.
+    pub fn new(client: reqwest::Client, env_var_config: EnvVarConfig) -> Self {
+        let stores = SharedStores::for_config(&env_var_config);
+        if let Err(e) = tenant::validate(&env_var_config.tenants, &env_var_config.upstreams) {
+            panic!("Invalid tenant configuration: {e}");
+        }
+        let tenants = Arc::new(Tenants::new(
+            &env_var_config.tenants,
+            stores.rate_limit.clone(),
+        ));
+        let email = env_var_config
+            .email
+            .as_ref()
+            .and_then(|config| email_bridge(config, stores.rate_limit.clone()));
+        let redact_keys: Vec<String> = env_var_config
+            .request_log
+            .as_ref()
+            .map(|config| config.redact_keys.clone())
+            .unwrap_or_else(|| DEFAULT_REDACT_KEYS.iter().map(|k| k.to_string()).collect());
+        let traffic_pause = Arc::new(TrafficPause::new(
+            &env_var_config.traffic_pause,
+            client.clone(),
+        ));
+        let balancer = Arc::new(
+            Balancer::new(
+                &env_var_config.upstreams,
+                &env_var_config.upstream_nodes,
+                env_var_config.sticky_session_header.as_deref(),
+            )
+            .expect("Invalid sticky session header"),
+        );
         Self {
+            title_case_client: (!env_var_config.title_case_header_envs.is_empty()).then(|| {
+                encoding::configure_client(
+                    reqwest::Client::builder(),
+                    env_var_config.upstream_decompression,
+                )
+                .http1_title_case_headers()
+                .build()
+                .expect("Failed to create reqwest client")
+            }),
             client,
-            env_var_config: EnvVarConfig::try_from_env(),
+            metrics: Arc::new(Metrics::default()),
+            interceptors: Arc::new({
+                let mut interceptors = Interceptors::with_defaults();
+                soap::install(
+                    &mut interceptors,
+                    &env_var_config.xml_json_rules,
+                    &env_var_config.soap_rules,
+                );
+                interceptors
+            }),
+            cache: Arc::new(ResponseCache::with_store(
+                env_var_config.cache_rules.clone(),
+                env_var_config.cache_invalidation_rules.clone(),
+                stores.cache,
+            )),
+            coalescer: Arc::new(Coalescer::new(env_var_config.coalesce_rules.clone())),
+            rate_limiter: (env_var_config.rate_limit.is_some()
+                || !env_var_config.rate_limit_schedules.is_empty())
+            .then(|| {
+                Arc::new(RateLimiter::scheduled(
+                    env_var_config.rate_limit,
+                    &env_var_config.rate_limit_schedules,
+                    stores.rate_limit,
+                ))
+            }),
+            webhook_dedup: stores.dedup,
+            request_log: env_var_config.request_log.as_ref().and_then(request_log),
+            log_control: None,
+            debug_log: Arc::new(DebugLog::new(&env_var_config.debug_log)),
+            egress_ip: Arc::new(EgressIp::new(env_var_config.egress_ip.clone())),
+            response_headers: Arc::new(
+                ResponseHeaders::new(&env_var_config.response_header_rules)
+                    .expect("Invalid response header rules"),
+            ),
+            cors: Arc::new(Cors::new(env_var_config.cors_rules.clone())),
+            correlation: Arc::new(
+                Correlation::new(&env_var_config.correlation_header)
+                    .expect("Invalid correlation header"),
+            ),
+            latency: Arc::new(LatencyTracker::default()),
+            hedger: env_var_config
+                .hedge
+                .clone()
+                .map(|config| Arc::new(Hedger::new(config))),
+            slo: Arc::new(SloTracker::new(env_var_config.slo_rules.clone())),
+            traffic_pause,
+            maintenance: Arc::new(Maintenance::new(&env_var_config.maintenance_windows)),
+            api_versions: Arc::new(ApiVersions::new(&env_var_config.api_version_pins)),
+            client_tags: Arc::new(ClientTags::new(&env_var_config.client_tags)),
+            store_forward: env_var_config
+                .store_forward
+                .as_ref()
+                .map(|config| Arc::new(StoreForward::for_config(config, stores.storage.clone()))),
+            masking: Arc::new(Masking::new(&env_var_config.masking)),
+            scheduler: Arc::new(Scheduler::new(
+                env_var_config.scheduled_requests_path.as_deref(),
+                redact_keys.clone(),
+            )),
+            prober: Arc::new(Prober::new(env_var_config.probes.clone())),
+            upstream_queue: env_var_config
+                .upstream_concurrency
+                .map(|limit| Arc::new(FairQueue::new(limit))),
+            connection_limiter: env_var_config
+                .max_connections_per_host
+                .map(|limit| Arc::new(ConnectionLimiter::new(limit))),
+            memory_budget: env_var_config
+                .max_inflight_body_bytes
+                .map(|limit| Arc::new(MemoryBudget::new(limit))),
+            slow_requests: Arc::new(SlowRequests::new(env_var_config.slow_request_threshold)),
+            tail: Arc::new(Tail::default()),
+            transfers: Arc::new(Transfers::new(
+                env_var_config.large_transfer_bytes,
+                env_var_config.transfer_progress_interval,
+            )),
+            faults: Arc::new(Faults::default()),
+            email,
+            tenants,
+            failover: Arc::new(Failover::new(env_var_config.failover.clone())),
+            balancer: balancer.clone(),
+            capture: Arc::new(
+                Capture::new(redact_keys.clone()).with_storage(stores.storage.clone()),
+            ),
+            upstream_errors: Arc::new(UpstreamErrors::new(
+                env_var_config.upstream_error_capacity,
+                redact_keys,
+            )),
+            contracts: Arc::new(
+                env_var_config
+                    .openapi_specs
+                    .iter()
+                    .map(|(env, path)| {
+                        let contract = Contract::load(path).expect("Failed to load OpenAPI spec");
+                        (env.clone(), contract)
+                    })
+                    .collect(),
+            ),
+            dns: Arc::new(
+                DnsResolver::new(&env_var_config.dns_resolvers)
+                    .negative_ttl(env_var_config.dns_negative_ttl)
+                    .family_policy(env_var_config.upstream_ip_family),
+            ),
+            egress_policy: Arc::new(EgressPolicy::for_upstreams(
+                env_var_config
+                    .upstreams
+                    .values()
+                    .chain(env_var_config.failover.secondaries.values())
+                    .chain(
+                        env_var_config
+                            .tenants
+                            .iter()
+                            .flat_map(|tenant| tenant.upstreams.values()),
+                    )
+                    .map(String::as_str)
+                    .chain(balancer.nodes()),
+            )),
+            env_var_config,
+        }
+    }
+
+    /// The client for calls to `env`'s upstream.
+    pub fn client_for(&self, env: &str) -> &reqwest::Client {
+        match &self.title_case_client {
+            Some(client)
+                if self
+                    .env_var_config
+                    .title_case_header_envs
+                    .iter()
+                    .any(|e| e == env) =>
+            {
+                client
+            }
+            _ => &self.client,
+        }
+    }
+}
+
+fn email_bridge(config: &EmailConfig, store: Arc<dyn RateLimitStore>) -> Option<Arc<EmailBridge>> {
+    #[cfg(feature = "smtp")]
+    {
+        let mailer = crate::smtp::SmtpMailer::from_url(&config.smtp_url)
+            .expect("Failed to configure the SMTP transport");
+        Some(Arc::new(EmailBridge::new(
+            config.clone(),
+            Arc::new(mailer),
+            store,
+        )))
+    }
+    #[cfg(not(feature = "smtp"))]
+    {
+        let _ = (config, store);
+        tracing::warn!("EMAIL_SMTP_URL ignored: built without the `smtp` feature");
+        None
+    }
+}
+
+fn request_log(config: &RequestLogConfig) -> Option<Arc<RequestLog>> {
+    #[cfg(feature = "request_log")]
+    {
+        let sink = crate::sql_request_log::SqlRequestLog::connect_lazy(&config.url)
+            .expect("Failed to configure request log");
+        Some(Arc::new(RequestLog::new(
+            Arc::new(sink),
+            config.bodies,
+            config.redact_keys.clone(),
+        )))
+    }
+    #[cfg(not(feature = "request_log"))]
+    {
+        tracing::warn!(
+            "REQUEST_LOG_URL={} ignored: built without the `request_log` feature",
+            config.url
+        );
+        None
+    }
+}
+
+/// Backends for state that replicas may share.
+struct SharedStores {
+    cache: Arc<dyn CacheStore>,
+    rate_limit: Arc<dyn RateLimitStore>,
+    dedup: Arc<dyn DedupStore>,
+    /// The `STORAGE_URL` backend, if one is configured.
+    storage: Option<Arc<dyn Storage>>,
+}
+
+impl SharedStores {
+    /// Redis when `REDIS_URL` is set (and the feature is on), then the
+    /// `STORAGE_URL` backend, process memory otherwise.
+    fn for_config(config: &EnvVarConfig) -> Self {
+        let storage = config
+            .storage_url
+            .as_deref()
+            .map(|url| open_storage(url).expect("Failed to open STORAGE_URL"));
+        if let Some(url) = &config.redis_url {
+            #[cfg(feature = "redis")]
+            {
+                let store = Arc::new(
+                    crate::redis_store::RedisStore::open(url).expect("Failed to configure Redis"),
+                );
+                return Self {
+                    cache: store.clone(),
+                    rate_limit: store.clone(),
+                    dedup: store,
+                    storage,
+                };
+            }
+            #[cfg(not(feature = "redis"))]
+            tracing::warn!(
+                "REDIS_URL={} ignored: built without the `redis` feature",
+                url
+            );
+        }
+        if let Some(storage) = &storage {
+            let stores = Arc::new(StorageStores::new(storage.clone()));
+            return Self {
+                cache: stores.clone(),
+                rate_limit: Arc::new(MemoryRateLimitStore::default()),
+                dedup: stores,
+                storage: Some(storage.clone()),
+            };
+        }
+        Self {
+            cache: Arc::new(MemoryCacheStore::default()),
+            rate_limit: Arc::new(MemoryRateLimitStore::default()),
+            dedup: Arc::new(MemoryDedupStore::default()),
+            storage: None,
+        }
+    }
+}
+
+impl RuntimeConfig {
+    pub fn from_env() -> Self {
+        Self {
+            worker_threads: env_limit("RUNTIME_WORKER_THREADS", 0),
+            max_blocking_threads: env_limit("RUNTIME_MAX_BLOCKING_THREADS", 0),
+            thread_name: env_w_default("RUNTIME_THREAD_NAME", DEFAULT_THREAD_NAME).unwrap(),
+            thread_stack_size: env_limit("RUNTIME_THREAD_STACK_BYTES", 0),
         }
     }
 }
@@ -40,10 +900,16 @@ impl AppState {
 // PRIVATE METHODS
 //
 
+// Settings come from the environment or the config file; see
+// [`crate::profile`].
+
 fn env_w_default(key: &str, default: &str) -> Result<String, EstateEnvConfigError> {
-    match std::env::var(key) {
-        Ok(val) => Ok(val),
-        Err(VarError::NotPresent) => Ok(default.to_string()),
+    match profile::lookup(key) {
+        Ok(Some(val)) => Ok(val),
+        Ok(None) => {
+            profile::record_default(key, default);
+            Ok(default.to_string())
+        }
         Err(e) => Err(EstateEnvConfigError::EnvVarError(format!(
             "missing {key}: {e}"
         ))),
@@ -51,20 +917,125 @@ fn env_w_default(key: &str, default: &str) -> Result<String, EstateEnvConfigErro
 }
 
 fn env_wo_default(key: &str) -> Result<Option<String>, EstateEnvConfigError> {
-    match std::env::var(key) {
-        Ok(val) => Ok(Some(val)),
-        Err(VarError::NotPresent) => Ok(None),
-        Err(e) => Err(EstateEnvConfigError::EnvVarError(format!("{key}: {e}"))),
-    }
+    profile::lookup(key).map_err(|e| EstateEnvConfigError::EnvVarError(format!("{key}: {e}")))
 }
 
+#[allow(dead_code)]
 fn env_or_panic(key: &str) -> String {
-    match std::env::var(key) {
-        Ok(val) => val,
+    match profile::lookup(key) {
+        Ok(Some(val)) => val,
+        Ok(None) => panic!("missing {key}"),
         Err(e) => panic!("missing {key}: {e}"),
     }
 }
 
+/// Reads a non-negative integer setting, panicking on garbage like the other
+/// config parsers.
+fn env_u64(key: &str, default: &str) -> u64 {
+    env_w_default(key, default)
+        .unwrap()
+        .parse()
+        .unwrap_or_else(|e| panic!("{key} must be a non-negative integer: {e}"))
+}
+
+/// A size or count limit, where 0 turns it off.
+fn env_limit(key: &str, default: usize) -> Option<usize> {
+    match env_u64(key, &default.to_string()) {
+        0 => None,
+        limit => Some(limit as usize),
+    }
+}
+
+/// A forward-proxy listener on `addr_var`'s address, with the shared
+/// `FORWARD_PROXY_*` credentials and allowlist; off when it's unset.
+fn forward_proxy_from_env(addr_var: &str) -> Option<ForwardProxyConfig> {
+    let addr = env_wo_default(addr_var).unwrap()?;
+    let config = ForwardProxyConfig {
+        addr: addr
+            .trim()
+            .parse()
+            .unwrap_or_else(|e| panic!("{addr_var} must be ip:port: {e}")),
+        credentials: parse_list(&env_w_default("FORWARD_PROXY_CREDENTIALS", "").unwrap()),
+        allowed_destinations: parse_destinations(
+            &env_w_default("FORWARD_PROXY_ALLOWED_DESTINATIONS", "").unwrap(),
+        )
+        .unwrap(),
+        connect_timeout: Duration::from_secs(env_u64(
+            "FORWARD_PROXY_CONNECT_TIMEOUT_SECS",
+            &DEFAULT_CONNECT_TIMEOUT.as_secs().to_string(),
+        )),
+    };
+    assert!(
+        !config.credentials.is_empty(),
+        "FORWARD_PROXY_CREDENTIALS must list user:password pairs"
+    );
+    Some(config)
+}
+
+fn store_forward_from_env() -> Option<StoreForwardConfig> {
+    let paths = parse_list(&env_w_default("STORE_AND_FORWARD_PATHS", "").unwrap());
+    if paths.is_empty() {
+        return None;
+    }
+    Some(StoreForwardConfig {
+        paths,
+        dir: env_wo_default("STORE_AND_FORWARD_DIR").unwrap(),
+        max_attempts: env_u64(
+            "STORE_AND_FORWARD_MAX_ATTEMPTS",
+            &DEFAULT_MAX_ATTEMPTS.to_string(),
+        )
+        .max(1) as u32,
+        retry_base: Duration::from_secs(env_u64(
+            "STORE_AND_FORWARD_RETRY_SECS",
+            &DEFAULT_RETRY_BASE.as_secs().to_string(),
+        )),
+    })
+}
+
+/// `TENANTS=booking,payments`, then for each tenant `TENANT_BOOKING_UPSTREAMS`
+/// (`env=url` pairs), `TENANT_BOOKING_API_KEYS` and optionally
+/// `TENANT_BOOKING_RATE_LIMIT`. Dashes in a name become underscores.
+fn tenants_from_env() -> Vec<TenantConfig> {
+    parse_list(&env_w_default("TENANTS", "").unwrap())
+        .into_iter()
+        .map(|name| {
+            let prefix = format!("TENANT_{}", name.to_ascii_uppercase().replace('-', "_"));
+            TenantConfig {
+                upstreams: tenant::parse_upstreams(
+                    &env_w_default(&format!("{prefix}_UPSTREAMS"), "").unwrap(),
+                )
+                .unwrap_or_else(|e| panic!("{prefix}_UPSTREAMS: {e}")),
+                api_keys: parse_list(&env_w_default(&format!("{prefix}_API_KEYS"), "").unwrap()),
+                rate_limit: env_wo_default(&format!("{prefix}_RATE_LIMIT"))
+                    .unwrap()
+                    .map(|value| parse_rate_limit(&value).unwrap()),
+                name,
+            }
+        })
+        .collect()
+}
+
+fn parse_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+fn parse_ip_list(value: &str) -> Result<Vec<IpAddr>, EstateEnvConfigError> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(|s| {
+            s.parse::<IpAddr>()
+                .map_err(|e| EstateEnvConfigError::EnvVarError(format!("invalid ip {s}: {e}")))
+        })
+        .collect()
+}
+
 #[derive(Debug, Error, Clone)]
 pub enum EstateEnvConfigError {
     #[error("Failed to get Estate Environment. Did you set environment vairables?")]
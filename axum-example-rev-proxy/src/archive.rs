@@ -0,0 +1,245 @@
+//! Periodic upload of aged request log entries to S3/GCS (behind the
+//! `archive` feature), for audit retention without growing the database.
+//!
+//! Each run uploads entries older than `archive_after` as JSONL (gzipped
+//! unless the level is 0) to `{prefix}/request-log/{first_ms}-{last_ms}.jsonl[.gz]`,
+//! deletes them from the database once the upload succeeded, and removes
+//! archives past `retention`.
+
+use std::io::Write;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use object_store::path::Path;
+use object_store::{ObjectStore, PutPayload};
+use reqwest::Url;
+use tokio::task::JoinHandle;
+use tracing::{error, info};
+
+use crate::app_state::AppState;
+use crate::request_log::{now_ms, ArchiveConfig, RequestLogEntry, RequestLogSink};
+
+const BATCH_SIZE: u32 = 10_000;
+
+/// What one archival run did.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ArchiveRun {
+    pub archived_entries: u64,
+    pub uploaded_objects: usize,
+    pub expired_objects: usize,
+}
+
+pub struct Archiver {
+    config: ArchiveConfig,
+    store: Box<dyn ObjectStore>,
+    prefix: Path,
+    sink: Arc<dyn RequestLogSink>,
+}
+
+impl Archiver {
+    pub fn new(config: ArchiveConfig, sink: Arc<dyn RequestLogSink>) -> Result<Self, String> {
+        let url = Url::parse(&config.url)
+            .map_err(|e| format!("invalid archive url {}: {e}", config.url))?;
+        // Lower-cased env vars line up with object_store's config keys
+        // (`aws_access_key_id`, `google_service_account`, ...).
+        let options = std::env::vars().map(|(key, value)| (key.to_ascii_lowercase(), value));
+        let (store, prefix) = object_store::parse_url_opts(&url, options)
+            .map_err(|e| format!("failed to configure archive store: {e}"))?;
+        Ok(Self {
+            config,
+            store,
+            prefix: prefix.child("request-log"),
+            sink,
+        })
+    }
+
+    /// Archives everything due, then applies retention.
+    pub async fn run_once(&self) -> Result<ArchiveRun, String> {
+        let mut run = ArchiveRun::default();
+        let before_ms = now_ms() - self.config.archive_after.as_millis() as i64;
+
+        loop {
+            let mut batch = self.sink.oldest_before(before_ms, BATCH_SIZE).await?;
+            let Some(last) = batch.last() else {
+                break;
+            };
+            let full = batch.len() == BATCH_SIZE as usize;
+            // Deletion is by timestamp, so a full batch must not end partway
+            // through a millisecond whose remaining entries weren't fetched.
+            if full {
+                let last_ms = last.timestamp_ms;
+                let trimmed = batch.iter().filter(|e| e.timestamp_ms < last_ms).count();
+                if trimmed > 0 {
+                    batch.truncate(trimmed);
+                }
+            }
+
+            let first_ms = batch[0].timestamp_ms;
+            let last_ms = batch[batch.len() - 1].timestamp_ms;
+            self.upload(first_ms, last_ms, &batch).await?;
+            run.uploaded_objects += 1;
+            run.archived_entries += self.sink.delete_through(last_ms).await?;
+
+            if !full {
+                break;
+            }
+        }
+
+        if let Some(retention) = self.config.retention {
+            run.expired_objects = self.expire(retention).await?;
+        }
+        Ok(run)
+    }
+
+    /// Runs [`Self::run_once`] every `interval` until the task is dropped.
+    pub fn spawn(self) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(self.config.interval);
+            loop {
+                ticker.tick().await;
+                match self.run_once().await {
+                    Ok(run) if run.uploaded_objects > 0 || run.expired_objects > 0 => info!(
+                        "Archived {} request log entries in {} objects, expired {} archives",
+                        run.archived_entries, run.uploaded_objects, run.expired_objects
+                    ),
+                    Ok(_) => {}
+                    Err(e) => error!("Request log archival failed: {}", e),
+                }
+            }
+        })
+    }
+
+    async fn upload(
+        &self,
+        first_ms: i64,
+        last_ms: i64,
+        entries: &[RequestLogEntry],
+    ) -> Result<(), String> {
+        let mut jsonl = Vec::new();
+        for entry in entries {
+            serde_json::to_writer(&mut jsonl, entry).map_err(|e| e.to_string())?;
+            jsonl.push(b'\n');
+        }
+
+        let (body, extension) = if self.config.compression_level == 0 {
+            (jsonl, "jsonl")
+        } else {
+            let level = Compression::new(self.config.compression_level.min(9));
+            let mut encoder = GzEncoder::new(Vec::new(), level);
+            encoder.write_all(&jsonl).map_err(|e| e.to_string())?;
+            (encoder.finish().map_err(|e| e.to_string())?, "jsonl.gz")
+        };
+
+        let location = self
+            .prefix
+            .child(format!("{first_ms}-{last_ms}.{extension}"));
+        self.store
+            .put(&location, PutPayload::from(body))
+            .await
+            .map_err(|e| format!("failed to upload {location}: {e}"))?;
+        Ok(())
+    }
+
+    async fn expire(&self, retention: Duration) -> Result<usize, String> {
+        let cutoff = SystemTime::now() - retention;
+        let listing = self
+            .store
+            .list_with_delimiter(Some(&self.prefix))
+            .await
+            .map_err(|e| format!("failed to list archives: {e}"))?;
+        let mut expired = 0;
+        for object in listing.objects {
+            if SystemTime::from(object.last_modified) < cutoff {
+                self.store
+                    .delete(&object.location)
+                    .await
+                    .map_err(|e| format!("failed to delete {}: {e}", object.location))?;
+                expired += 1;
+            }
+        }
+        Ok(expired)
+    }
+}
+
+/// Starts the archiver if both the request log and archival are configured.
+pub fn spawn_archiver(state: &AppState) -> Option<JoinHandle<()>> {
+    let config = state.env_var_config.archive.clone()?;
+    let Some(log) = &state.request_log else {
+        error!("ARCHIVE_URL is set but the request log is not; nothing to archive");
+        return None;
+    };
+    let archiver = Archiver::new(config, log.sink().clone()).expect("Failed to configure archive");
+    Some(archiver.spawn())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request_log::RequestLogQuery;
+    use crate::sql_request_log::SqlRequestLog;
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+
+    fn entry(timestamp_ms: i64) -> RequestLogEntry {
+        RequestLogEntry {
+            timestamp_ms,
+            env: "test".to_string(),
+            method: "GET".to_string(),
+            path: "/test/api".to_string(),
+            query: None,
+            status: 200,
+            duration_ms: 1,
+            request_body: None,
+            response_body: None,
+            correlation_id: None,
+            fingerprint: None,
+            client_tag: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn uploads_aged_entries_and_removes_them_locally() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = dir.path().join("log.db");
+        let sink = Arc::new(
+            SqlRequestLog::connect_lazy(&format!("sqlite://{}?mode=rwc", db.display())).unwrap(),
+        );
+        let now = now_ms();
+        for timestamp_ms in [1_000, 2_000, now] {
+            sink.record(entry(timestamp_ms)).await.unwrap();
+        }
+        let bucket = dir.path().join("bucket");
+        std::fs::create_dir(&bucket).unwrap();
+        let archiver = Archiver::new(
+            ArchiveConfig {
+                url: format!("file://{}/archive", bucket.display()),
+                interval: Duration::from_secs(3600),
+                archive_after: Duration::from_secs(3600),
+                retention: None,
+                compression_level: 6,
+            },
+            sink.clone(),
+        )
+        .unwrap();
+
+        let run = archiver.run_once().await.unwrap();
+
+        assert_eq!(run.archived_entries, 2);
+        assert_eq!(run.uploaded_objects, 1);
+        let remaining = sink.query(&RequestLogQuery::default()).await.unwrap();
+        assert_eq!(remaining, [entry(now)]);
+
+        let file = bucket.join("archive/request-log/1000-2000.jsonl.gz");
+        let mut jsonl = String::new();
+        GzDecoder::new(std::fs::File::open(file).unwrap())
+            .read_to_string(&mut jsonl)
+            .unwrap();
+        let archived: Vec<RequestLogEntry> = jsonl
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(archived, [entry(1_000), entry(2_000)]);
+    }
+}
@@ -0,0 +1,164 @@
+//! Load balancing across an environment's supplier nodes. An env's pool is
+//! its upstream base URL followed by any extra nodes configured for it.
+//!
+//! Requests are spread round-robin, except those carrying the sticky session
+//! header (e.g. a booking session ID): those are pinned to a node by
+//! rendezvous hashing of the header value, so every step of a multi-step
+//! flow reaches the same node, and adding or removing a node only moves the
+//! sessions that hashed to it.
+
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use hyper::header::{HeaderMap, HeaderName};
+use sha2::{Digest, Sha256};
+
+#[derive(Debug)]
+pub struct Balancer {
+    pools: BTreeMap<String, Vec<String>>,
+    sticky_header: Option<HeaderName>,
+    next: AtomicUsize,
+}
+
+impl Balancer {
+    /// `upstreams` are each env's base URL, `nodes` the extra ones.
+    pub fn new(
+        upstreams: &BTreeMap<String, String>,
+        nodes: &BTreeMap<String, Vec<String>>,
+        sticky_header: Option<&str>,
+    ) -> Result<Self, String> {
+        let sticky_header = sticky_header
+            .map(|name| {
+                HeaderName::from_bytes(name.as_bytes())
+                    .map_err(|e| format!("sticky session header {name:?}: {e}"))
+            })
+            .transpose()?;
+        let pools = nodes
+            .iter()
+            .filter_map(|(env, nodes)| {
+                let mut pool = vec![upstreams.get(env)?.clone()];
+                for node in nodes {
+                    if !pool.contains(node) {
+                        pool.push(node.clone());
+                    }
+                }
+                (pool.len() > 1).then(|| (env.clone(), pool))
+            })
+            .collect();
+        Ok(Self {
+            pools,
+            sticky_header,
+            next: AtomicUsize::new(0),
+        })
+    }
+
+    /// `env`'s nodes, empty unless it has several.
+    pub fn pool(&self, env: &str) -> &[String] {
+        self.pools.get(env).map_or(&[], Vec::as_slice)
+    }
+
+    /// Every node of every pool, for the egress policy.
+    pub fn nodes(&self) -> impl Iterator<Item = &str> {
+        self.pools.values().flatten().map(String::as_str)
+    }
+
+    /// The base URL a request to `env` goes to, or `None` when the env has a
+    /// single node.
+    pub fn pick(&self, env: &str, headers: &HeaderMap) -> Option<&str> {
+        let pool = self.pools.get(env)?;
+        let session = self
+            .sticky_header
+            .as_ref()
+            .and_then(|name| headers.get(name))
+            .map(|value| value.as_bytes())
+            .filter(|value| !value.is_empty());
+        let node = match session {
+            Some(session) => pool
+                .iter()
+                .max_by_key(|node| score(session, node))
+                .expect("pools have several nodes"),
+            None => &pool[self.next.fetch_add(1, Ordering::Relaxed) % pool.len()],
+        };
+        Some(node)
+    }
+}
+
+/// Rendezvous weight of `node` for `session`. A stable hash, so every
+/// worker and every restart pins a session to the same node.
+fn score(session: &[u8], node: &str) -> u64 {
+    let digest = Sha256::new()
+        .chain_update(session)
+        .chain_update([0])
+        .chain_update(node.as_bytes())
+        .finalize();
+    u64::from_be_bytes(digest[..8].try_into().expect("8 bytes"))
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::header::HeaderValue;
+
+    use super::*;
+
+    fn balancer(nodes: &[&str]) -> Balancer {
+        Balancer::new(
+            &BTreeMap::from([
+                ("prod".to_string(), "http://a".to_string()),
+                ("test".to_string(), "http://t".to_string()),
+            ]),
+            &BTreeMap::from([(
+                "prod".to_string(),
+                nodes.iter().map(|node| node.to_string()).collect(),
+            )]),
+            Some("x-booking-session"),
+        )
+        .unwrap()
+    }
+
+    fn session(id: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-booking-session", HeaderValue::from_str(id).unwrap());
+        headers
+    }
+
+    #[test]
+    fn spreads_requests_without_a_session() {
+        let balancer = balancer(&["http://b", "http://c"]);
+        let picks: Vec<_> = (0..6)
+            .map(|_| balancer.pick("prod", &HeaderMap::new()).unwrap())
+            .collect();
+        assert_eq!(
+            picks,
+            ["http://a", "http://b", "http://c", "http://a", "http://b", "http://c"]
+        );
+        assert_eq!(balancer.pick("test", &HeaderMap::new()), None);
+    }
+
+    #[test]
+    fn pins_sessions_to_a_node() {
+        let balancer = balancer(&["http://b", "http://c"]);
+        for id in ["s1", "s2", "s3", "s4"] {
+            let first = balancer.pick("prod", &session(id));
+            for _ in 0..5 {
+                assert_eq!(balancer.pick("prod", &session(id)), first);
+            }
+        }
+    }
+
+    #[test]
+    fn moves_only_the_sessions_of_a_removed_node() {
+        let three = balancer(&["http://b", "http://c"]);
+        let two = balancer(&["http://b"]);
+        let ids: Vec<String> = (0..200).map(|i| format!("session-{i}")).collect();
+        let mut spread = BTreeMap::<&str, usize>::new();
+        for id in &ids {
+            let before = three.pick("prod", &session(id)).unwrap();
+            *spread.entry(before).or_default() += 1;
+            if before != "http://c" {
+                assert_eq!(two.pick("prod", &session(id)), Some(before));
+            }
+        }
+        assert_eq!(spread.len(), 3);
+        assert!(spread.values().all(|&count| count > 30));
+    }
+}
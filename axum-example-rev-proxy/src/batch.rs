@@ -0,0 +1,212 @@
+//! `POST /{env}/_batch`: several calls to one env's upstream in a single
+//! round trip, for jobs such as rate shopping that ask a supplier many small
+//! questions at once. The body is a JSON array of sub-requests:
+//!
+//! ```json
+//! [{"method": "GET", "path": "/rates?hotel=1"},
+//!  {"method": "POST", "path": "/quote", "body": {"hotel": 2}}]
+//! ```
+//!
+//! Each one goes through the proxy as if it had been sent on its own, with
+//! the batch's headers plus its own, so rate limits, caching and metrics
+//! all apply per sub-request. At most `BATCH_CONCURRENCY` run at a time and
+//! the answer lists their results in request order. A batch may hold up to
+//! `BATCH_MAX_REQUESTS` sub-requests; 0 turns the endpoint off.
+
+use std::collections::BTreeMap;
+
+use axum::body::{to_bytes, Body, Bytes};
+use axum::extract::{Path, Request, State};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use futures_util::stream::{self, StreamExt};
+use hyper::header::{self, HeaderName, HeaderValue};
+use hyper::{Method, StatusCode, Uri};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::warn;
+
+use crate::app_state::AppState;
+use crate::error::ProxyError;
+use crate::proxy;
+use crate::tenant;
+
+pub const DEFAULT_BATCH_MAX_REQUESTS: usize = 50;
+pub const DEFAULT_BATCH_CONCURRENCY: usize = 4;
+/// Largest batch body, and largest sub-response kept in the answer.
+const MAX_BATCH_BYTES: usize = 8 * 1024 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct SubRequest {
+    #[serde(default = "default_method")]
+    pub method: String,
+    /// Path and query below the env prefix, e.g. `/rates?hotel=1`.
+    pub path: String,
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    /// Sent as is when a string, as JSON otherwise.
+    #[serde(default)]
+    pub body: Option<Value>,
+}
+
+fn default_method() -> String {
+    "GET".to_string()
+}
+
+/// One sub-request's answer. The body is JSON when the upstream sent JSON,
+/// text otherwise.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SubResponse {
+    pub status: u16,
+    pub headers: BTreeMap<String, String>,
+    pub body: Value,
+}
+
+pub async fn batch_handler(
+    State(state): State<AppState>,
+    Path(env): Path<String>,
+    mut req: Request,
+) -> Response {
+    if tenant::split_env(&env).is_some() {
+        let correlation_id = state.correlation.ensure(req.headers_mut());
+        return ProxyError::UnknownEnv.to_response(&correlation_id);
+    }
+    if state.env_var_config.upstream_for(&env).is_none() {
+        let correlation_id = state.correlation.ensure(req.headers_mut());
+        return ProxyError::UnknownEnv.to_response(&correlation_id);
+    }
+    let Some(max_requests) = state.env_var_config.batch_max_requests else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let (parts, body) = req.into_parts();
+    let requests: Vec<SubRequest> = match to_bytes(body, MAX_BATCH_BYTES).await {
+        Ok(body) => match serde_json::from_slice(&body) {
+            Ok(requests) => requests,
+            Err(e) => {
+                return (StatusCode::BAD_REQUEST, format!("invalid batch: {e}")).into_response()
+            }
+        },
+        Err(_) => return StatusCode::PAYLOAD_TOO_LARGE.into_response(),
+    };
+    if requests.len() > max_requests {
+        return (
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format!("a batch holds at most {max_requests} requests"),
+        )
+            .into_response();
+    }
+    let mut subrequests = Vec::with_capacity(requests.len());
+    for (index, request) in requests.into_iter().enumerate() {
+        match build(&parts, &env, request) {
+            Ok(subrequest) => subrequests.push(subrequest),
+            Err(e) => {
+                return (StatusCode::BAD_REQUEST, format!("request {index}: {e}")).into_response()
+            }
+        }
+    }
+
+    let concurrency = state.env_var_config.batch_concurrency.max(1);
+    let results: Vec<SubResponse> = stream::iter(subrequests)
+        .map(|subrequest| {
+            let state = state.clone();
+            let env = env.clone();
+            async move { collect(proxy::serve(state, env, subrequest).await).await }
+        })
+        .buffered(concurrency)
+        .collect()
+        .await;
+    Json(results).into_response()
+}
+
+/// The sub-request as the proxy would have received it.
+fn build(
+    batch: &hyper::http::request::Parts,
+    env: &str,
+    request: SubRequest,
+) -> Result<Request, String> {
+    let method = Method::from_bytes(request.method.to_ascii_uppercase().as_bytes())
+        .map_err(|_| format!("invalid method {:?}", request.method))?;
+    if !request.path.starts_with('/') {
+        return Err("path must start with /".to_string());
+    }
+    let uri: Uri = format!("/{env}{}", request.path)
+        .parse()
+        .map_err(|_| format!("invalid path {:?}", request.path))?;
+
+    // The batch's body headers don't describe the sub-request's, and its
+    // Accept-Encoding is for the combined answer, which we write.
+    let mut headers = batch.headers.clone();
+    for name in [
+        header::ACCEPT_ENCODING,
+        header::CONTENT_LENGTH,
+        header::CONTENT_TYPE,
+        header::CONTENT_ENCODING,
+        header::TRANSFER_ENCODING,
+    ] {
+        headers.remove(name);
+    }
+    let body = match request.body {
+        None => Bytes::new(),
+        Some(Value::String(text)) => Bytes::from(text),
+        Some(json) => {
+            headers.insert(
+                header::CONTENT_TYPE,
+                HeaderValue::from_static("application/json"),
+            );
+            Bytes::from(json.to_string())
+        }
+    };
+    for (name, value) in &request.headers {
+        let name = HeaderName::from_bytes(name.as_bytes())
+            .map_err(|_| format!("invalid header name {name:?}"))?;
+        let value =
+            HeaderValue::from_str(value).map_err(|_| format!("invalid value for {name}"))?;
+        headers.insert(name, value);
+    }
+
+    let mut subrequest = Request::new(Body::from(body));
+    *subrequest.method_mut() = method;
+    *subrequest.uri_mut() = uri;
+    *subrequest.headers_mut() = headers;
+    // Rate limits and client keys follow the batch's caller.
+    *subrequest.extensions_mut() = batch.extensions.clone();
+    Ok(subrequest)
+}
+
+async fn collect(response: Response) -> SubResponse {
+    let (parts, body) = response.into_parts();
+    let headers = parts
+        .headers
+        .iter()
+        .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+        .collect();
+    let body = match to_bytes(body, MAX_BATCH_BYTES).await {
+        Ok(body) => body,
+        Err(e) => {
+            warn!("Failed to read a batch sub-response: {}", e);
+            return SubResponse {
+                status: StatusCode::BAD_GATEWAY.as_u16(),
+                headers,
+                body: Value::String("upstream body could not be read".to_string()),
+            };
+        }
+    };
+    let is_json = parts
+        .headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.contains("json"));
+    let body = match is_json
+        .then(|| serde_json::from_slice(&body).ok())
+        .flatten()
+    {
+        Some(json) => json,
+        None if body.is_empty() => Value::Null,
+        None => Value::String(String::from_utf8_lossy(&body).into_owned()),
+    };
+    SubResponse {
+        status: parts.status.as_u16(),
+        headers,
+        body,
+    }
+}
@@ -0,0 +1,911 @@
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::Router;
+
+use crate::api_version::ApiVersionPin;
+use crate::app_state::{AppState, EnvVarConfig};
+use crate::batch::{DEFAULT_BATCH_CONCURRENCY, DEFAULT_BATCH_MAX_REQUESTS};
+use crate::cache::{CacheRule, InvalidationRule, DEFAULT_CREDENTIAL_HEADERS};
+use crate::canonical::Canonicalization;
+use crate::client_tag::ClientTagConfig;
+use crate::coalesce::CoalesceRule;
+use crate::correlation::DEFAULT_CORRELATION_HEADER;
+use crate::cors::CorsRule;
+use crate::dashboard::DEFAULT_SLOW_REQUEST_THRESHOLD;
+use crate::debug_log::DebugLogConfig;
+use crate::dns::{ChainResolver, DnsResolver, FamilyPolicy, ResolverSpec};
+use crate::egress_ip::EgressIpConfig;
+use crate::egress_policy::GuardedResolver;
+use crate::email::EmailConfig;
+use crate::encoding;
+use crate::failover::FailoverConfig;
+use crate::forward_proxy::ForwardProxyConfig;
+use crate::header_limits::HeaderLimits;
+use crate::hedge::HedgeConfig;
+use crate::interceptor::{Interceptors, RequestInterceptor, ResponseInterceptor};
+use crate::latency::AdaptiveTimeoutConfig;
+use crate::log_control::LogControl;
+use crate::maintenance::MaintenanceWindow;
+use crate::masking::{MaskingConfig, MaskingRule};
+use crate::memory_budget::DEFAULT_MAX_INFLIGHT_BYTES;
+use crate::metrics::Metrics;
+use crate::nowpayments_ipn_webhook::IpnSecret;
+use crate::pause::{PauseConfig, PauseRule};
+use crate::prober::ProbeConfig;
+use crate::rate_limit::{RateLimit, ScheduledRateLimit};
+use crate::rbac::{AdminToken, Role};
+use crate::request_log::RequestLogConfig;
+use crate::response_headers::ResponseHeaderRule;
+use crate::server::ServerConfig;
+use crate::slo::SloRule;
+use crate::soap::SoapRule;
+use crate::store_forward::StoreForwardConfig;
+use crate::tcp_tunnel::TcpTunnel;
+use crate::tenant::TenantConfig;
+use crate::timings::ConnectTimingLayer;
+use crate::transfers::{DEFAULT_LARGE_TRANSFER_BYTES, DEFAULT_PROGRESS_INTERVAL};
+use crate::udp_relay::UdpRelay;
+use crate::upstream_errors::DEFAULT_UPSTREAM_ERROR_CAPACITY;
+use crate::xml_json::XmlJsonRule;
+use crate::RouteOptions;
+
+/// Programmatic construction of the egress proxy [`Router`].
+///
+/// The returned router has its state applied, so it can be served directly or
+/// mounted under a sub-path of another service with [`Router::nest`]:
+///
+/// ```no_run
+/// use axum_example_rev_proxy::builder::ProxyBuilder;
+///
+/// let proxy = ProxyBuilder::new()
+///     .upstream("test", "http://test.services.travelomatix.com")
+///     .webhook(false)
+///     .build();
+/// let app: axum::Router = axum::Router::new().nest("/egress", proxy);
+/// ```
+pub struct ProxyBuilder {
+    config: EnvVarConfig,
+    client: Option<reqwest::Client>,
+    connect_timeout: Option<Duration>,
+    request_timeout: Option<Duration>,
+    metrics: Arc<Metrics>,
+    interceptors: Interceptors,
+    default_interceptors: bool,
+    log_control: Option<LogControl>,
+    options: RouteOptions,
+}
+
+impl Default for ProxyBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ProxyBuilder {
+    /// Starts from an empty upstream set and no webhook secret.
+    pub fn new() -> Self {
+        Self::with_config(EnvVarConfig {
+            ipn_secret: String::new(),
+            ipn_secrets: Vec::new(),
+            upstreams: BTreeMap::new(),
+            upstream_nodes: BTreeMap::new(),
+            sticky_session_header: None,
+            failover: FailoverConfig::default(),
+            nowpayments_allowed_ips: Vec::new(),
+            webhook_replay_ttl: Duration::ZERO,
+            nowpayments_canonicalization: Canonicalization::default(),
+            nowpayments_test_mode: false,
+            request_script_path: None,
+            admin_token: None,
+            admin_tokens: Vec::new(),
+            metrics_snapshot_path: None,
+            xml_json_rules: Vec::new(),
+            soap_rules: Vec::new(),
+            openapi_specs: BTreeMap::new(),
+            cache_rules: Vec::new(),
+            coalesce_rules: Vec::new(),
+            cache_invalidation_rules: Vec::new(),
+            credential_headers: DEFAULT_CREDENTIAL_HEADERS
+                .split(',')
+                .map(str::to_string)
+                .collect(),
+            response_header_rules: Vec::new(),
+            cors_rules: Vec::new(),
+            rate_limit: None,
+            rate_limit_schedules: Vec::new(),
+            redis_url: None,
+            storage_url: None,
+            request_log: None,
+            archive: None,
+            debug_log: DebugLogConfig::default(),
+            egress_ip: EgressIpConfig::default(),
+            allow_internal_destinations: false,
+            dns_resolvers: vec![ResolverSpec::System],
+            dns_negative_ttl: Some(Duration::from_secs(5)),
+            dns_refresh_on_connect_failure: true,
+            upstream_ip_family: FamilyPolicy::PreferV4,
+            title_case_header_envs: Vec::new(),
+            upstream_decompression: false,
+            server: ServerConfig::default(),
+            correlation_header: DEFAULT_CORRELATION_HEADER.to_string(),
+            header_limits: HeaderLimits::default(),
+            hedge: None,
+            adaptive_timeout: None,
+            upstream_concurrency: None,
+            queue_timeout: Duration::from_secs(30),
+            max_connections_per_host: None,
+            max_inflight_body_bytes: Some(DEFAULT_MAX_INFLIGHT_BYTES),
+            slow_request_threshold: DEFAULT_SLOW_REQUEST_THRESHOLD,
+            partial_response_paths: Vec::new(),
+            field_filter_paths: Vec::new(),
+            masking: MaskingConfig::default(),
+            upstream_body_idle_timeout: None,
+            large_transfer_bytes: Some(DEFAULT_LARGE_TRANSFER_BYTES),
+            transfer_progress_interval: DEFAULT_PROGRESS_INTERVAL,
+            upstream_error_capacity: Some(DEFAULT_UPSTREAM_ERROR_CAPACITY),
+            slo_rules: Vec::new(),
+            traffic_pause: PauseConfig::default(),
+            maintenance_windows: Vec::new(),
+            api_version_pins: Vec::new(),
+            client_tags: ClientTagConfig::default(),
+            store_forward: None,
+            scheduled_requests_path: None,
+            batch_max_requests: Some(DEFAULT_BATCH_MAX_REQUESTS),
+            batch_concurrency: DEFAULT_BATCH_CONCURRENCY,
+            probes: Vec::new(),
+            forward_proxy: None,
+            socks5: None,
+            tcp_tunnels: Vec::new(),
+            tcp_tunnel_allowed_clients: Vec::new(),
+            udp_relays: Vec::new(),
+            udp_relay_idle_timeout: crate::udp_relay::DEFAULT_IDLE_TIMEOUT,
+            email: None,
+            tenants: Vec::new(),
+        })
+    }
+
+    /// Starts from the configuration the binary reads from the environment.
+    pub fn from_env() -> Self {
+        Self::with_config(EnvVarConfig::try_from_env())
+    }
+
+    pub fn with_config(config: EnvVarConfig) -> Self {
+        Self {
+            config,
+            client: None,
+            connect_timeout: None,
+            request_timeout: None,
+            metrics: Arc::new(Metrics::default()),
+            interceptors: Interceptors::default(),
+            default_interceptors: true,
+            log_control: None,
+            options: RouteOptions::default(),
+        }
+    }
+
+    /// Adds (or replaces) the upstream served under `/{env}/...`.
+    pub fn upstream(mut self, env: impl Into<String>, base_url: impl Into<String>) -> Self {
+        self.config.upstreams.insert(env.into(), base_url.into());
+        self
+    }
+
+    /// Adds a supplier node for `env`, balanced with its upstream.
+    pub fn upstream_node(mut self, env: impl Into<String>, base_url: impl Into<String>) -> Self {
+        self.config
+            .upstream_nodes
+            .entry(env.into())
+            .or_default()
+            .push(base_url.into());
+        self
+    }
+
+    /// Pins requests carrying this header to one node of a multi-node env,
+    /// by its value.
+    pub fn sticky_session_header(mut self, name: impl Into<String>) -> Self {
+        self.config.sticky_session_header = Some(name.into());
+        self
+    }
+
+    /// Adds (or replaces) a secondary upstream for `env`, in another region,
+    /// that requests fail over to while the primary is failing.
+    pub fn secondary_upstream(
+        mut self,
+        env: impl Into<String>,
+        base_url: impl Into<String>,
+    ) -> Self {
+        self.config
+            .failover
+            .secondaries
+            .insert(env.into(), base_url.into());
+        self
+    }
+
+    /// Consecutive failures that mark a primary down, and how long it stays
+    /// down before being tried again.
+    pub fn failover_policy(
+        mut self,
+        failure_threshold: u32,
+        retry_primary_after: Duration,
+    ) -> Self {
+        self.config.failover.failure_threshold = failure_threshold.max(1);
+        self.config.failover.retry_primary_after = retry_primary_after;
+        self
+    }
+
+    pub fn ipn_secret(mut self, secret: impl Into<String>) -> Self {
+        self.config.ipn_secret = secret.into();
+        self
+    }
+
+    /// Also accepts IPNs signed with `secret`, e.g. the old one during a
+    /// rotation.
+    pub fn ipn_rotation_secret(mut self, secret: IpnSecret) -> Self {
+        self.config.ipn_secrets.push(secret);
+        self
+    }
+
+    pub fn nowpayments_allowed_ips(mut self, ips: impl IntoIterator<Item = IpAddr>) -> Self {
+        self.config.nowpayments_allowed_ips = ips.into_iter().collect();
+        self
+    }
+
+    /// What IPN signatures are computed over: a canonical form of the body,
+    /// its raw bytes with keys sorted, or the raw body as received.
+    pub fn nowpayments_canonicalization(mut self, mode: Canonicalization) -> Self {
+        self.config.nowpayments_canonicalization = mode;
+        self
+    }
+
+    /// Serves `POST /admin/simulate-webhook` (off by default); for staging
+    /// only.
+    pub fn nowpayments_test_mode(mut self, enabled: bool) -> Self {
+        self.config.nowpayments_test_mode = enabled;
+        self
+    }
+
+    /// Rejects a verified IPN seen again within `ttl`; zero disables.
+    pub fn webhook_replay_ttl(mut self, ttl: Duration) -> Self {
+        self.config.webhook_replay_ttl = ttl;
+        self
+    }
+
+    /// Enables the `/admin` API, guarded by `Authorization: Bearer <token>`.
+    pub fn admin_token(mut self, token: impl Into<String>) -> Self {
+        self.config.admin_token = Some(token.into());
+        self
+    }
+
+    /// Adds a named admin token with the given role, enabling the `/admin` API.
+    pub fn admin_role_token(
+        mut self,
+        name: impl Into<String>,
+        role: Role,
+        token: impl Into<String>,
+    ) -> Self {
+        self.config.admin_tokens.push(AdminToken {
+            name: name.into(),
+            role,
+            token: token.into(),
+        });
+        self
+    }
+
+    /// Sends header names to `env`'s upstream in title case (`Api-Key`)
+    /// over HTTP/1.1, for suppliers that match them exactly.
+    pub fn title_case_headers(mut self, env: impl Into<String>) -> Self {
+        self.config.title_case_header_envs.push(env.into());
+        self
+    }
+
+    /// Has the upstream client decode gzip, deflate, brotli and zstd bodies
+    /// rather than passing them through (off by default). A client given to
+    /// [`Self::client`] keeps its own setting.
+    pub fn upstream_decompression(mut self, enabled: bool) -> Self {
+        self.config.upstream_decompression = enabled;
+        self
+    }
+
+    /// Saves counters to `path` on shutdown and imports them from it on the
+    /// next start.
+    pub fn metrics_snapshot_path(mut self, path: impl Into<String>) -> Self {
+        self.config.metrics_snapshot_path = Some(path.into());
+        self
+    }
+
+    /// Converts JSON requests to XML and XML responses to JSON on routes
+    /// matching `rule`.
+    pub fn xml_json_rule(mut self, rule: XmlJsonRule) -> Self {
+        self.config.xml_json_rules.push(rule);
+        self
+    }
+
+    /// Wraps requests in a SOAP envelope and unwraps responses on routes
+    /// matching `rule`.
+    pub fn soap_rule(mut self, rule: SoapRule) -> Self {
+        self.config.soap_rules.push(rule);
+        self
+    }
+
+    /// Checks `env`'s traffic against the OpenAPI spec at `path`, reporting
+    /// violations without blocking anything.
+    pub fn openapi_spec(mut self, env: impl Into<String>, path: impl Into<String>) -> Self {
+        self.config.openapi_specs.insert(env.into(), path.into());
+        self
+    }
+
+    /// Caches GET responses under `path_prefix` (e.g. `/test/api/static`) for `ttl`.
+    pub fn cache_rule(mut self, path_prefix: impl Into<String>, ttl: Duration) -> Self {
+        self.config
+            .cache_rules
+            .push(CacheRule::new(path_prefix, ttl));
+        self
+    }
+
+    /// Adds a fully specified cache rule, e.g. with stale-while-revalidate
+    /// or stale-if-error windows.
+    pub fn add_cache_rule(mut self, rule: CacheRule) -> Self {
+        self.config.cache_rules.push(rule);
+        self
+    }
+
+    /// Makes identical safe-method requests under `path_prefix` arriving
+    /// within `window` of each other share one upstream call.
+    pub fn coalesce_rule(mut self, path_prefix: impl Into<String>, window: Duration) -> Self {
+        self.config.coalesce_rules.push(CoalesceRule {
+            path_prefix: path_prefix.into(),
+            window,
+            any_method: false,
+        });
+        self
+    }
+
+    /// Like [`Self::coalesce_rule`], but for requests of any method, such as
+    /// searches sent as POSTs.
+    pub fn coalesce_rule_any_method(
+        mut self,
+        path_prefix: impl Into<String>,
+        window: Duration,
+    ) -> Self {
+        self.config.coalesce_rules.push(CoalesceRule {
+            path_prefix: path_prefix.into(),
+            window,
+            any_method: true,
+        });
+        self
+    }
+
+    /// Makes successful writes under `write_prefix` also drop cached GETs
+    /// under each of `invalidates`.
+    pub fn cache_invalidation_rule(
+        mut self,
+        write_prefix: impl Into<String>,
+        invalidates: impl IntoIterator<Item = impl Into<String>>,
+    ) -> Self {
+        self.config.cache_invalidation_rules.push(InvalidationRule {
+            write_prefix: write_prefix.into(),
+            invalidates: invalidates.into_iter().map(Into::into).collect(),
+        });
+        self
+    }
+
+    /// Request headers, besides `Authorization`, that hold the caller's own
+    /// credentials, replacing the default `Cookie` and `X-Api-Key`.
+    pub fn credential_headers(
+        mut self,
+        names: impl IntoIterator<Item = impl Into<String>>,
+    ) -> Self {
+        self.config.credential_headers = names.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Sets/removes headers on responses under the rule's path prefix.
+    pub fn response_header_rule(mut self, rule: ResponseHeaderRule) -> Self {
+        self.config.response_header_rules.push(rule);
+        self
+    }
+
+    /// Adds a CORS policy for the rule's path prefix.
+    pub fn cors_rule(mut self, rule: CorsRule) -> Self {
+        self.config.cors_rules.push(rule);
+        self
+    }
+
+    /// Tracks an upstream's compliance with the rule's objectives.
+    pub fn slo_rule(mut self, rule: SloRule) -> Self {
+        self.config.slo_rules.push(rule);
+        self
+    }
+
+    /// Pauses traffic to `rule.env` while its upstream keeps failing.
+    pub fn traffic_pause_rule(mut self, rule: PauseRule) -> Self {
+        self.config.traffic_pause.rules.push(rule);
+        self
+    }
+
+    /// Posts traffic pause alerts to `url`.
+    pub fn traffic_pause_alert_webhook(mut self, url: impl Into<String>) -> Self {
+        self.config.traffic_pause.alert_webhook_url = Some(url.into());
+        self
+    }
+
+    /// Refuses traffic to `window.env` while its maintenance window is open.
+    pub fn maintenance_window(mut self, window: MaintenanceWindow) -> Self {
+        self.config.maintenance_windows.push(window);
+        self
+    }
+
+    /// Sends `pin.env` requests with the pinned API version and refuses
+    /// responses advertising another.
+    pub fn api_version_pin(mut self, pin: ApiVersionPin) -> Self {
+        self.config.api_version_pins.push(pin);
+        self
+    }
+
+    /// Only counts these `X-Client-Tag`s, recording the rest as `other`.
+    pub fn client_tags(mut self, tags: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.config.client_tags.allowed = tags.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Answers writes to `config.paths` with 202 and delivers them later;
+    /// run the deliveries with [`crate::store_forward::spawn_deliverer`].
+    pub fn store_and_forward(mut self, config: StoreForwardConfig) -> Self {
+        self.config.store_forward = Some(config);
+        self
+    }
+
+    /// Saves scheduled requests to `path` so they survive a restart; run them
+    /// with [`crate::schedule::spawn_scheduler`].
+    pub fn scheduled_requests_path(mut self, path: impl Into<String>) -> Self {
+        self.config.scheduled_requests_path = Some(path.into());
+        self
+    }
+
+    /// Limits `POST /{env}/_batch` to `max_requests` sub-requests, run
+    /// `concurrency` at a time; `None` turns it off.
+    pub fn batch(mut self, max_requests: Option<usize>, concurrency: usize) -> Self {
+        self.config.batch_max_requests = max_requests;
+        self.config.batch_concurrency = concurrency;
+        self
+    }
+
+    /// Adds a synthetic probe; run them with [`crate::prober::spawn_probers`].
+    pub fn probe(mut self, probe: ProbeConfig) -> Self {
+        self.config.probes.push(probe);
+        self
+    }
+
+    /// Header used to pass correlation IDs to upstreams (default
+    /// `X-Correlation-Id`).
+    pub fn correlation_header(mut self, name: impl Into<String>) -> Self {
+        self.config.correlation_header = name.into();
+        self
+    }
+
+    /// Serves a `CONNECT` forward proxy, started by
+    /// [`crate::forward_proxy::spawn`].
+    pub fn forward_proxy(mut self, config: ForwardProxyConfig) -> Self {
+        self.config.forward_proxy = Some(config);
+        self
+    }
+
+    /// Serves a SOCKS5 proxy (`socks5` feature), started by
+    /// `crate::socks5::spawn`.
+    pub fn socks5(mut self, config: ForwardProxyConfig) -> Self {
+        self.config.socks5 = Some(config);
+        self
+    }
+
+    /// Adds an L4 tunnel, started by [`crate::tcp_tunnel::spawn_tunnels`].
+    pub fn tcp_tunnel(mut self, tunnel: TcpTunnel) -> Self {
+        self.config.tcp_tunnels.push(tunnel);
+        self
+    }
+
+    /// Adds a tenant namespace, served under `/{name}/{env}/...`.
+    pub fn tenant(mut self, config: TenantConfig) -> Self {
+        self.config.tenants.push(config);
+        self
+    }
+
+    /// Mounts the `POST /email/send` SMTP bridge (`smtp` feature).
+    pub fn email(mut self, config: EmailConfig) -> Self {
+        self.config.email = Some(config);
+        self
+    }
+
+    /// Adds a UDP relay, started by [`crate::udp_relay::spawn_relays`].
+    pub fn udp_relay(mut self, relay: UdpRelay) -> Self {
+        self.config.udp_relays.push(relay);
+        self
+    }
+
+    /// Limits the L4 tunnels and UDP relays to these client IPs.
+    pub fn tcp_tunnel_allowed_clients(mut self, clients: impl IntoIterator<Item = IpAddr>) -> Self {
+        self.config.tcp_tunnel_allowed_clients = clients.into_iter().collect();
+        self
+    }
+
+    /// Hedges slow GETs to the configured routes.
+    pub fn hedge(mut self, config: HedgeConfig) -> Self {
+        self.config.hedge = Some(config);
+        self
+    }
+
+    /// Derives each route's upstream timeout from its recent latencies,
+    /// overriding [`Self::request_timeout`] for proxied calls.
+    pub fn adaptive_timeout(mut self, config: AdaptiveTimeoutConfig) -> Self {
+        self.config.adaptive_timeout = Some(config);
+        self
+    }
+
+    /// Allows `limit` upstream calls at once. Requests beyond that wait up to
+    /// `queue_timeout`, served round-robin across clients.
+    pub fn upstream_concurrency(mut self, limit: usize, queue_timeout: Duration) -> Self {
+        self.config.upstream_concurrency = Some(limit);
+        self.config.queue_timeout = queue_timeout;
+        self
+    }
+
+    /// Limits on inbound request headers; requests over them get a 431.
+    pub fn header_limits(mut self, limits: HeaderLimits) -> Self {
+        self.config.header_limits = limits;
+        self
+    }
+
+    /// Resolves upstream hostnames with `resolvers` in order, failing over
+    /// to the next when one stops answering.
+    pub fn dns_resolvers(mut self, resolvers: impl IntoIterator<Item = ResolverSpec>) -> Self {
+        self.config.dns_resolvers = resolvers.into_iter().collect();
+        self
+    }
+
+    /// Caches failed hostname lookups for `ttl`, doubling while they keep
+    /// failing; `None` disables.
+    pub fn dns_negative_ttl(mut self, ttl: Option<Duration>) -> Self {
+        self.config.dns_negative_ttl = ttl;
+        self
+    }
+
+    /// Re-resolves an upstream and retries once when connecting to it fails
+    /// (on by default).
+    pub fn dns_refresh_on_connect_failure(mut self, enabled: bool) -> Self {
+        self.config.dns_refresh_on_connect_failure = enabled;
+        self
+    }
+
+    /// Prefers IPv4 or IPv6 addresses for upstream hostnames, or uses IPv4
+    /// only (IPv4 first by default).
+    pub fn upstream_ip_family(mut self, policy: FamilyPolicy) -> Self {
+        self.config.upstream_ip_family = policy;
+        self
+    }
+
+    /// Allows at most `limit` simultaneous connections to each upstream
+    /// host; further requests wait up to the queue timeout.
+    pub fn max_connections_per_host(mut self, limit: usize) -> Self {
+        self.config.max_connections_per_host = Some(limit);
+        self
+    }
+
+    /// Lets in-flight requests buffer at most `bytes` of bodies between them;
+    /// `None` removes the budget.
+    pub fn max_inflight_body_bytes(mut self, bytes: Option<usize>) -> Self {
+        self.config.max_inflight_body_bytes = bytes;
+        self
+    }
+
+    /// Lists requests taking at least `threshold` on the admin dashboard.
+    pub fn slow_request_threshold(mut self, threshold: Duration) -> Self {
+        self.config.slow_request_threshold = threshold;
+        self
+    }
+
+    /// Returns what arrived of an upstream body that fails partway, with a
+    /// `Warning` header, for inbound paths under `prefix`.
+    pub fn partial_response_path(mut self, prefix: impl Into<String>) -> Self {
+        self.config.partial_response_paths.push(prefix.into());
+        self
+    }
+
+    /// Masks `rule.fields` in JSON responses to the rule's API keys.
+    pub fn masking_rule(mut self, rule: MaskingRule) -> Self {
+        self.config.masking.rules.push(rule);
+        self
+    }
+
+    /// Keys the HMAC that masking rules hash values with.
+    pub fn masking_hash_key(mut self, key: impl Into<String>) -> Self {
+        self.config.masking.hash_key = Some(key.into());
+        self
+    }
+
+    /// Lets callers trim JSON responses with `?_fields=` on inbound paths
+    /// under `prefix`.
+    pub fn field_filter_path(mut self, prefix: impl Into<String>) -> Self {
+        self.config.field_filter_paths.push(prefix.into());
+        self
+    }
+
+    /// Fails upstream bodies that send nothing for `idle`.
+    pub fn upstream_body_idle_timeout(mut self, idle: Duration) -> Self {
+        self.config.upstream_body_idle_timeout = Some(idle);
+        self
+    }
+
+    /// Logs the progress of upstream bodies of at least `bytes` every
+    /// `interval` and lists them at `/admin/transfers`; `None` turns it off.
+    pub fn large_transfers(mut self, bytes: Option<usize>, interval: Duration) -> Self {
+        self.config.large_transfer_bytes = bytes;
+        self.config.transfer_progress_interval = interval;
+        self
+    }
+
+    /// Keeps the last `count` upstream 5xx responses for
+    /// `/admin/upstream-errors`; `None` keeps none.
+    pub fn upstream_error_capture(mut self, count: Option<usize>) -> Self {
+        self.config.upstream_error_capacity = count;
+        self
+    }
+
+    /// Allows each client (by peer IP) `requests` per `window`.
+    pub fn rate_limit(mut self, requests: u64, window: Duration) -> Self {
+        self.config.rate_limit = Some(RateLimit { requests, window });
+        self
+    }
+
+    /// Uses `schedule.limit` instead while its window is open.
+    pub fn rate_limit_schedule(mut self, schedule: ScheduledRateLimit) -> Self {
+        self.config.rate_limit_schedules.push(schedule);
+        self
+    }
+
+    /// Keeps cache and rate-limit state in Redis so replicas share it.
+    /// Needs the `redis` feature; ignored with a warning otherwise.
+    pub fn redis_url(mut self, url: impl Into<String>) -> Self {
+        self.config.redis_url = Some(url.into());
+        self
+    }
+
+    /// Keeps the cache, webhook replay keys, store-and-forward deliveries
+    /// and captures in one backend, e.g. `file:///var/lib/egress-proxy`;
+    /// see [`crate::storage`].
+    pub fn storage_url(mut self, url: impl Into<String>) -> Self {
+        self.config.storage_url = Some(url.into());
+        self
+    }
+
+    /// Writes per-request summaries to SQLite/Postgres, queryable at
+    /// `/admin/request-log`. Needs the `request_log` feature.
+    pub fn request_log(mut self, config: RequestLogConfig) -> Self {
+        self.config.request_log = Some(config);
+        self
+    }
+
+    /// Logs full headers and bodies for 1 in `sample_rate` requests (0 = off)
+    /// and for requests sending `trace_header: 1`.
+    pub fn debug_log(mut self, config: DebugLogConfig) -> Self {
+        self.config.debug_log = config;
+        self
+    }
+
+    /// Where `/egress-ip` discovers the public IP and what it should be.
+    pub fn egress_ip(mut self, config: EgressIpConfig) -> Self {
+        self.config.egress_ip = config;
+        self
+    }
+
+    /// Lets upstream hostnames resolve to private, loopback and link-local
+    /// addresses (refused by default).
+    pub fn allow_internal_destinations(mut self, allow: bool) -> Self {
+        self.config.allow_internal_destinations = allow;
+        self
+    }
+
+    /// Keep-alive and timeouts for inbound connections. Only used when the
+    /// router is served with [`crate::server::serve`].
+    pub fn server(mut self, config: ServerConfig) -> Self {
+        self.config.server = config;
+        self
+    }
+
+    /// Uses a caller-supplied client. Timeouts set on the builder are ignored
+    /// in that case and DNS answers aren't screened for internal addresses;
+    /// configure the client accordingly.
+    pub fn client(mut self, client: reqwest::Client) -> Self {
+        self.client = Some(client);
+        self
+    }
+
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// Total time allowed for an upstream call, including reading the body.
+    pub fn request_timeout(mut self, timeout: Duration) -> Self {
+        self.request_timeout = Some(timeout);
+        self
+    }
+
+    /// Records into `metrics` instead of a fresh instance, so the host service
+    /// can read or expose the counters itself.
+    pub fn metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Appends a request interceptor that runs for every route.
+    pub fn request_interceptor(mut self, interceptor: impl RequestInterceptor + 'static) -> Self {
+        self.interceptors.global.request.push(Arc::new(interceptor));
+        self
+    }
+
+    /// Appends a response interceptor that runs for every route.
+    pub fn response_interceptor(mut self, interceptor: impl ResponseInterceptor + 'static) -> Self {
+        self.interceptors
+            .global
+            .response
+            .push(Arc::new(interceptor));
+        self
+    }
+
+    /// Appends a request interceptor that only runs for requests under `/{env}/...`.
+    pub fn route_request_interceptor(
+        mut self,
+        env: impl Into<String>,
+        interceptor: impl RequestInterceptor + 'static,
+    ) -> Self {
+        self.interceptors
+            .routes
+            .entry(env.into())
+            .or_default()
+            .request
+            .push(Arc::new(interceptor));
+        self
+    }
+
+    /// Appends a response interceptor that only runs for requests under `/{env}/...`.
+    pub fn route_response_interceptor(
+        mut self,
+        env: impl Into<String>,
+        interceptor: impl ResponseInterceptor + 'static,
+    ) -> Self {
+        self.interceptors
+            .routes
+            .entry(env.into())
+            .or_default()
+            .response
+            .push(Arc::new(interceptor));
+        self
+    }
+
+    /// Toggles the built-in Host rewriting and logging interceptors (on by
+    /// default). They run ahead of any interceptors added on the builder.
+    pub fn default_interceptors(mut self, enabled: bool) -> Self {
+        self.default_interceptors = enabled;
+        self
+    }
+
+    /// Lets `/admin/loglevel` change the tracing filter through `control`.
+    pub fn log_control(mut self, control: LogControl) -> Self {
+        self.log_control = Some(control);
+        self
+    }
+
+    /// Toggles the HTTP trace layer (on by default).
+    pub fn trace(mut self, enabled: bool) -> Self {
+        self.options.trace = enabled;
+        self
+    }
+
+    /// Toggles the `/nowpayments-webhook` route (on by default).
+    pub fn webhook(mut self, enabled: bool) -> Self {
+        self.options.webhook = enabled;
+        self
+    }
+
+    /// Toggles the `/metrics`, `/metrics/paths`, `/slo` and `/info` routes (on by default).
+    pub fn metrics_endpoint(mut self, enabled: bool) -> Self {
+        self.options.metrics_endpoint = enabled;
+        self
+    }
+
+    /// Builds the shared state without wiring routes.
+    pub fn build_state(self) -> (AppState, RouteOptions) {
+        let dns = Arc::new(
+            DnsResolver::new(&self.config.dns_resolvers)
+                .negative_ttl(self.config.dns_negative_ttl)
+                .family_policy(self.config.upstream_ip_family),
+        );
+        let client_builder = || {
+            let mut builder = encoding::configure_client(
+                reqwest::Client::builder(),
+                self.config.upstream_decompression,
+            )
+            .connector_layer(ConnectTimingLayer);
+            if let Some(timeout) = self.connect_timeout {
+                builder = builder.connect_timeout(timeout);
+            }
+            if let Some(timeout) = self.request_timeout {
+                builder = builder.timeout(timeout);
+            }
+            if !self.config.allow_internal_destinations {
+                builder = builder.dns_resolver(Arc::new(GuardedResolver::new(
+                    self.metrics.clone(),
+                    dns.clone(),
+                )));
+            } else {
+                // Even the plain system resolver goes through the chain, so
+                // answers are ordered by the family policy.
+                builder = builder.dns_resolver(Arc::new(ChainResolver(dns.clone())));
+            }
+            builder
+        };
+        // Legacy suppliers that check header name casing get a second client.
+        let title_case = !self.config.title_case_header_envs.is_empty();
+        let (client, title_case_client) = match self.client {
+            Some(client) => {
+                if title_case {
+                    tracing::warn!("TITLE_CASE_HEADER_ENVS ignored: a custom client is in use");
+                }
+                (client, None)
+            }
+            None => (
+                client_builder()
+                    .build()
+                    .expect("Failed to create reqwest client"),
+                title_case.then(|| {
+                    client_builder()
+                        .http1_title_case_headers()
+                        .build()
+                        .expect("Failed to create reqwest client")
+                }),
+            ),
+        };
+
+        let mut interceptors = self.interceptors;
+        crate::soap::install(
+            &mut interceptors,
+            &self.config.xml_json_rules,
+            &self.config.soap_rules,
+        );
+        if self.default_interceptors {
+            let defaults = Interceptors::with_defaults().global;
+            interceptors.global.request.splice(0..0, defaults.request);
+            interceptors.global.response.splice(0..0, defaults.response);
+        }
+        if let Some(path) = &self.config.request_script_path {
+            #[cfg(feature = "scripting")]
+            {
+                let script = crate::scripting::ScriptInterceptor::load(
+                    path,
+                    crate::scripting::ScriptLimits::default(),
+                )
+                .expect("Failed to load request script");
+                interceptors.global.request.push(Arc::new(script));
+            }
+            #[cfg(not(feature = "scripting"))]
+            tracing::warn!(
+                "REQUEST_SCRIPT_PATH={} ignored: built without the `scripting` feature",
+                path
+            );
+        }
+
+        let mut state = AppState::new(client, self.config);
+        state.metrics = self.metrics;
+        state.dns = dns;
+        state.title_case_client = title_case_client;
+        state.interceptors = Arc::new(interceptors);
+        state.log_control = self.log_control;
+        (state, self.options)
+    }
+
+    pub fn build(self) -> Router {
+        let (state, options) = self.build_state();
+        crate::router_with_options(state, options)
+    }
+}
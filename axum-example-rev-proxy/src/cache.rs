@@ -0,0 +1,720 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use axum::body::Bytes;
+use hyper::header::{HeaderMap, HeaderName, HeaderValue};
+use hyper::{header, Method, StatusCode};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::coalesce::VARY_HEADERS;
+use crate::conditional::has_validators;
+use crate::interceptor::UpstreamResponse;
+
+const DEFAULT_MAX_ENTRIES: usize = 10_000;
+
+/// Request headers, besides `Authorization`, that carry the caller's own
+/// credentials unless `CREDENTIAL_HEADERS` says otherwise.
+pub const DEFAULT_CREDENTIAL_HEADERS: &str = "cookie,x-api-key";
+
+/// How long past its last window an entry with an `ETag` or `Last-Modified`
+/// is kept around for conditional revalidation.
+const VALIDATOR_RETENTION: Duration = Duration::from_secs(300);
+
+/// Caches successful GET responses whose inbound path starts with `path_prefix`.
+///
+/// Paths are the ones callers use, including the env segment, e.g. `/test/api/static`.
+/// Once `ttl` has passed an entry may still be served for up to
+/// `stale_while_revalidate` while it is refreshed in the background, and for
+/// up to `stale_if_error` when the refresh attempt fails.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct CacheRule {
+    pub path_prefix: String,
+    pub ttl: Duration,
+    #[serde(default)]
+    pub stale_while_revalidate: Duration,
+    #[serde(default)]
+    pub stale_if_error: Duration,
+}
+
+impl CacheRule {
+    pub fn new(path_prefix: impl Into<String>, ttl: Duration) -> Self {
+        Self {
+            path_prefix: path_prefix.into(),
+            ttl,
+            stale_while_revalidate: Duration::ZERO,
+            stale_if_error: Duration::ZERO,
+        }
+    }
+}
+
+/// Outcome of a cache lookup.
+#[derive(Debug, Clone)]
+pub enum CacheLookup {
+    Fresh(UpstreamResponse),
+    /// Past its TTL but inside the stale-while-revalidate window: serve it
+    /// and refresh in the background.
+    Revalidate(UpstreamResponse),
+    /// Past every serve-without-asking window. It can still be revalidated
+    /// with a conditional request, and served if the upstream fails while
+    /// `serve_on_error` is set.
+    Expired {
+        response: UpstreamResponse,
+        serve_on_error: bool,
+    },
+    Miss,
+}
+
+/// A successful write under `write_prefix` also drops cached GETs under each
+/// of `invalidates`, on top of the default same-prefix invalidation.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct InvalidationRule {
+    pub write_prefix: String,
+    pub invalidates: Vec<String>,
+}
+
+/// A stored response and the instants that bound how it may be served.
+///
+/// Times are wall-clock so entries mean the same thing on every replica
+/// sharing a store.
+#[derive(Debug, Clone)]
+pub struct CacheEntry {
+    pub path: String,
+    pub response: UpstreamResponse,
+    pub fresh_until: SystemTime,
+    pub revalidate_until: SystemTime,
+    pub stale_if_error_until: SystemTime,
+    /// When the entry is dropped; later than the windows above for entries
+    /// carrying validators, so they can be revalidated cheaply.
+    pub retain_until: SystemTime,
+}
+
+/// Where cached responses live. Keys are [`cache_key`]s, so they start with
+/// the inbound path.
+#[async_trait]
+pub trait CacheStore: Send + Sync {
+    async fn get(&self, key: &str) -> Option<CacheEntry>;
+    async fn insert(&self, key: String, entry: CacheEntry);
+    async fn remove(&self, key: &str);
+    /// Drops every entry whose path is [`under_prefix`] `path_prefix`,
+    /// returning how many.
+    async fn remove_prefix(&self, path_prefix: &str) -> usize;
+}
+
+/// Per-process store; the default when no shared backend is configured.
+#[derive(Debug)]
+pub struct MemoryCacheStore {
+    max_entries: usize,
+    entries: Mutex<HashMap<String, CacheEntry>>,
+}
+
+impl Default for MemoryCacheStore {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_ENTRIES)
+    }
+}
+
+impl MemoryCacheStore {
+    pub fn new(max_entries: usize) -> Self {
+        Self {
+            max_entries,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[async_trait]
+impl CacheStore for MemoryCacheStore {
+    async fn get(&self, key: &str) -> Option<CacheEntry> {
+        self.entries.lock().unwrap().get(key).cloned()
+    }
+
+    async fn insert(&self, key: String, entry: CacheEntry) {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= self.max_entries && !entries.contains_key(&key) {
+            let now = SystemTime::now();
+            entries.retain(|_, entry| entry.retain_until > now);
+            if entries.len() >= self.max_entries {
+                return;
+            }
+        }
+        entries.insert(key, entry);
+    }
+
+    async fn remove(&self, key: &str) {
+        self.entries.lock().unwrap().remove(key);
+    }
+
+    async fn remove_prefix(&self, path_prefix: &str) -> usize {
+        let mut entries = self.entries.lock().unwrap();
+        let before = entries.len();
+        entries.retain(|_, entry| !under_prefix(&entry.path, path_prefix));
+        before - entries.len()
+    }
+}
+
+/// A [`CacheEntry`] as shared stores keep it.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct StoredEntry {
+    path: String,
+    status: u16,
+    headers: Vec<(String, String)>,
+    /// Hex, since bodies may be binary (e.g. gzip).
+    body: String,
+    fresh_until_ms: u64,
+    revalidate_until_ms: u64,
+    stale_if_error_until_ms: u64,
+    retain_until_ms: u64,
+}
+
+impl StoredEntry {
+    pub(crate) fn from_entry(entry: &CacheEntry) -> Self {
+        Self {
+            path: entry.path.clone(),
+            status: entry.response.status.as_u16(),
+            headers: entry
+                .response
+                .headers
+                .iter()
+                .filter_map(|(name, value)| {
+                    Some((name.to_string(), value.to_str().ok()?.to_string()))
+                })
+                .collect(),
+            body: hex::encode(&entry.response.body),
+            fresh_until_ms: to_millis(entry.fresh_until),
+            revalidate_until_ms: to_millis(entry.revalidate_until),
+            stale_if_error_until_ms: to_millis(entry.stale_if_error_until),
+            retain_until_ms: to_millis(entry.retain_until),
+        }
+    }
+
+    pub(crate) fn into_entry(self) -> Option<CacheEntry> {
+        let mut headers = HeaderMap::new();
+        for (name, value) in self.headers {
+            headers.append(
+                HeaderName::from_bytes(name.as_bytes()).ok()?,
+                HeaderValue::from_str(&value).ok()?,
+            );
+        }
+        Some(CacheEntry {
+            path: self.path,
+            response: UpstreamResponse {
+                status: StatusCode::from_u16(self.status).ok()?,
+                headers,
+                body: Bytes::from(hex::decode(self.body).ok()?),
+                trailers: None,
+            },
+            fresh_until: from_millis(self.fresh_until_ms),
+            revalidate_until: from_millis(self.revalidate_until_ms),
+            stale_if_error_until: from_millis(self.stale_if_error_until_ms),
+            retain_until: from_millis(self.retain_until_ms),
+        })
+    }
+}
+
+/// TTL cache for upstream GET responses, backed by a [`CacheStore`].
+pub struct ResponseCache {
+    rules: Vec<CacheRule>,
+    invalidation_rules: Vec<InvalidationRule>,
+    store: Arc<dyn CacheStore>,
+    /// Keys this process is refreshing. Replicas sharing a store may each
+    /// refresh the same key once; that's cheaper than a distributed lock.
+    revalidating: Mutex<HashSet<String>>,
+}
+
+impl Default for ResponseCache {
+    fn default() -> Self {
+        Self::new(Vec::new(), Vec::new())
+    }
+}
+
+impl ResponseCache {
+    pub fn new(rules: Vec<CacheRule>, invalidation_rules: Vec<InvalidationRule>) -> Self {
+        Self::with_store(
+            rules,
+            invalidation_rules,
+            Arc::new(MemoryCacheStore::default()),
+        )
+    }
+
+    pub fn with_store(
+        rules: Vec<CacheRule>,
+        invalidation_rules: Vec<InvalidationRule>,
+        store: Arc<dyn CacheStore>,
+    ) -> Self {
+        Self {
+            rules,
+            invalidation_rules,
+            store,
+            revalidating: Mutex::new(HashSet::new()),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.rules.is_empty()
+    }
+
+    /// Returns the rule for `method path`, or `None` if it isn't cacheable.
+    pub fn rule_for(&self, method: &Method, path: &str) -> Option<&CacheRule> {
+        if method != Method::GET {
+            return None;
+        }
+        // The longest matching prefix wins so specific rules can override broad ones.
+        self.rules
+            .iter()
+            .filter(|rule| under_prefix(path, &rule.path_prefix))
+            .max_by_key(|rule| rule.path_prefix.len())
+    }
+
+    pub async fn lookup(&self, key: &str) -> CacheLookup {
+        let now = SystemTime::now();
+        let Some(entry) = self.store.get(key).await else {
+            return CacheLookup::Miss;
+        };
+        if now < entry.fresh_until {
+            CacheLookup::Fresh(entry.response)
+        } else if now < entry.revalidate_until {
+            CacheLookup::Revalidate(entry.response)
+        } else if now < entry.retain_until {
+            CacheLookup::Expired {
+                response: entry.response,
+                serve_on_error: now < entry.stale_if_error_until,
+            }
+        } else {
+            self.store.remove(key).await;
+            CacheLookup::Miss
+        }
+    }
+
+    /// Returns the entry only while it is fresh.
+    pub async fn get(&self, key: &str) -> Option<UpstreamResponse> {
+        match self.lookup(key).await {
+            CacheLookup::Fresh(response) => Some(response),
+            _ => None,
+        }
+    }
+
+    /// Marks `key` as being refreshed. Returns `false` if a refresh is
+    /// already running, so callers start at most one per key.
+    pub fn begin_revalidation(&self, key: &str) -> bool {
+        self.revalidating.lock().unwrap().insert(key.to_string())
+    }
+
+    pub fn end_revalidation(&self, key: &str) {
+        self.revalidating.lock().unwrap().remove(key);
+    }
+
+    /// Stores `response` if it is a 200 the upstream didn't mark
+    /// `no-store`/`private`, and that varies only on headers in the key.
+    pub async fn put(
+        &self,
+        key: String,
+        path: &str,
+        response: &UpstreamResponse,
+        rule: &CacheRule,
+    ) {
+        if response.status != hyper::StatusCode::OK
+            || !upstream_allows_caching(response)
+            || !varies_only_on_key_headers(response)
+        {
+            return;
+        }
+
+        let now = SystemTime::now();
+        let fresh_until = now + rule.ttl;
+        let revalidate_until = fresh_until + rule.stale_while_revalidate;
+        let stale_if_error_until = revalidate_until.max(fresh_until + rule.stale_if_error);
+        let retain_until = if has_validators(response) {
+            stale_if_error_until + VALIDATOR_RETENTION
+        } else {
+            stale_if_error_until
+        };
+        let entry = CacheEntry {
+            path: path.to_string(),
+            response: response.clone(),
+            fresh_until,
+            revalidate_until,
+            stale_if_error_until,
+            retain_until,
+        };
+        self.store.insert(key, entry).await;
+    }
+
+    /// Drops every entry whose path is `path_prefix` or under it, returning
+    /// how many.
+    pub async fn invalidate_prefix(&self, path_prefix: &str) -> usize {
+        self.store.remove_prefix(path_prefix).await
+    }
+
+    /// Applies automatic invalidation after a successful write to `path`.
+    pub async fn invalidate_after_write(&self, path: &str) -> usize {
+        let mut removed = self.invalidate_prefix(path).await;
+        for rule in &self.invalidation_rules {
+            if under_prefix(path, &rule.write_prefix) {
+                for prefix in &rule.invalidates {
+                    removed += self.invalidate_prefix(prefix).await;
+                }
+            }
+        }
+        removed
+    }
+}
+
+/// Cache key for an inbound request: the path callers used plus the query,
+/// then the negotiation headers when the caller sent any, so e.g. a gzip
+/// body is only served to callers that accept gzip, then a digest of the
+/// caller's credentials, so a response fetched with one caller's key is
+/// never served to another (RFC 9111 §3.5).
+pub fn cache_key(
+    path: &str,
+    query: Option<&str>,
+    headers: &HeaderMap,
+    credential_headers: &[String],
+) -> String {
+    let mut key = match query {
+        Some(query) => format!("{path}?{query}"),
+        None => path.to_string(),
+    };
+    if VARY_HEADERS.iter().any(|name| headers.contains_key(name)) {
+        for name in &VARY_HEADERS {
+            key.push('\n');
+            for value in headers.get_all(name) {
+                key.push_str(&String::from_utf8_lossy(value.as_bytes()));
+                key.push(',');
+            }
+        }
+    }
+    if let Some(digest) = credential_digest(headers, credential_headers) {
+        key.push_str("\n#");
+        key.push_str(&digest);
+    }
+    key
+}
+
+/// A hash of the `Authorization` and `credential_headers` values in
+/// `headers`, or `None` when the request carries none of them. Keys hold the
+/// hash rather than the values so shared stores never see a secret.
+pub fn credential_digest(headers: &HeaderMap, credential_headers: &[String]) -> Option<String> {
+    let names = std::iter::once(header::AUTHORIZATION.as_str())
+        .chain(credential_headers.iter().map(String::as_str));
+    let mut hasher = Sha256::new();
+    let mut any = false;
+    for name in names {
+        for value in headers.get_all(name) {
+            any = true;
+            hasher.update(name.to_ascii_lowercase().as_bytes());
+            hasher.update(b":");
+            hasher.update(value.as_bytes());
+            hasher.update(b"\n");
+        }
+    }
+    any.then(|| hex::encode(hasher.finalize()))
+}
+
+/// Whether `path`, or a [`cache_key`] starting with one, is `prefix` or under
+/// it, matching whole segments: `/api/hotel` covers `/api/hotel/1` and
+/// `/api/hotel?id=1` but not `/api/hotels`.
+pub fn under_prefix(path: &str, prefix: &str) -> bool {
+    let Some(rest) = path.strip_prefix(prefix) else {
+        return false;
+    };
+    rest.is_empty() || prefix.ends_with('/') || rest.starts_with(['/', '?', '\n'])
+}
+
+/// Whether the upstream's `Vary` names only headers [`cache_key`] covers.
+fn varies_only_on_key_headers(response: &UpstreamResponse) -> bool {
+    response
+        .headers
+        .get_all(header::VARY)
+        .iter()
+        .flat_map(|value| value.to_str().unwrap_or("*").split(','))
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .all(|name| {
+            VARY_HEADERS
+                .iter()
+                .any(|key| key.as_str().eq_ignore_ascii_case(name))
+        })
+}
+
+fn upstream_allows_caching(response: &UpstreamResponse) -> bool {
+    response
+        .headers
+        .get_all(header::CACHE_CONTROL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|directive| directive.trim().to_ascii_lowercase())
+        .all(|directive| directive != "no-store" && directive != "private")
+}
+
+/// Parses `prefix=ttl[:stale_while_revalidate[:stale_if_error]]` rules
+/// (all in seconds) separated by commas.
+pub fn parse_cache_rules(value: &str) -> Result<Vec<CacheRule>, String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(|pair| {
+            let (prefix, durations) = pair
+                .split_once('=')
+                .ok_or_else(|| format!("expected prefix=ttl_secs, got {pair}"))?;
+            let mut secs = durations.split(':').map(|d| {
+                d.trim()
+                    .parse::<u64>()
+                    .map(Duration::from_secs)
+                    .map_err(|e| format!("invalid duration in {pair}: {e}"))
+            });
+            let mut rule = CacheRule::new(prefix.trim(), secs.next().unwrap()?);
+            if let Some(swr) = secs.next() {
+                rule.stale_while_revalidate = swr?;
+            }
+            if let Some(sie) = secs.next() {
+                rule.stale_if_error = sie?;
+            }
+            Ok(rule)
+        })
+        .collect()
+}
+
+/// Parses `write_prefix=>target|target` rules separated by commas.
+pub fn parse_invalidation_rules(value: &str) -> Result<Vec<InvalidationRule>, String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(|rule| {
+            let (write_prefix, targets) = rule
+                .split_once("=>")
+                .ok_or_else(|| format!("expected write_prefix=>target|target, got {rule}"))?;
+            Ok(InvalidationRule {
+                write_prefix: write_prefix.trim().to_string(),
+                invalidates: targets
+                    .split('|')
+                    .map(str::trim)
+                    .filter(|s| !s.is_empty())
+                    .map(str::to_string)
+                    .collect(),
+            })
+        })
+        .collect()
+}
+
+fn to_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+fn from_millis(millis: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_millis(millis)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Bytes;
+    use hyper::{HeaderMap, StatusCode};
+
+    fn ok(body: &'static str) -> UpstreamResponse {
+        UpstreamResponse {
+            status: StatusCode::OK,
+            headers: HeaderMap::new(),
+            body: Bytes::from_static(body.as_bytes()),
+            trailers: None,
+        }
+    }
+
+    fn cache() -> ResponseCache {
+        ResponseCache::new(
+            parse_cache_rules("/test/api=60,/test/api/live=0").unwrap(),
+            parse_invalidation_rules("/test/api/book=>/test/api/availability").unwrap(),
+        )
+    }
+
+    #[test]
+    fn longest_prefix_rule_wins_and_only_get_is_cacheable() {
+        let cache = cache();
+        let ttl = |method, path| cache.rule_for(&method, path).map(|rule| rule.ttl);
+        assert_eq!(
+            ttl(Method::GET, "/test/api/hotels"),
+            Some(Duration::from_secs(60))
+        );
+        assert_eq!(ttl(Method::GET, "/test/api/live/x"), Some(Duration::ZERO));
+        assert_eq!(ttl(Method::POST, "/test/api/hotels"), None);
+        assert_eq!(ttl(Method::GET, "/prod/api/hotels"), None);
+        // Rules cover whole segments, like invalidation.
+        assert_eq!(ttl(Method::GET, "/test/apis"), None);
+        assert_eq!(ttl(Method::GET, "/test/api"), Some(Duration::from_secs(60)));
+    }
+
+    #[tokio::test]
+    async fn write_invalidates_same_prefix_and_configured_targets() {
+        let store = Arc::new(MemoryCacheStore::default());
+        let cache = ResponseCache::with_store(
+            Vec::new(),
+            parse_invalidation_rules("/test/api/book=>/test/api/availability").unwrap(),
+            store.clone(),
+        );
+        let rule = CacheRule::new("/test", Duration::from_secs(60));
+        for path in [
+            "/test/api/book/1",
+            "/test/api/availability/goa",
+            "/test/api/bookings/2",
+            "/test/api/hotels",
+        ] {
+            cache
+                .put(
+                    cache_key(path, None, &HeaderMap::new(), &[]),
+                    path,
+                    &ok("x"),
+                    &rule,
+                )
+                .await;
+        }
+
+        assert_eq!(cache.invalidate_after_write("/test/api/book").await, 2);
+        assert!(cache.get("/test/api/hotels").await.is_some());
+        // Only whole segments match: `/book` leaves `/bookings` alone.
+        assert!(cache.get("/test/api/bookings/2").await.is_some());
+        assert_eq!(store.len(), 2);
+    }
+
+    #[test]
+    fn prefixes_match_whole_segments() {
+        assert!(under_prefix("/test/api/hotel", "/test/api/hotel"));
+        assert!(under_prefix("/test/api/hotel/1", "/test/api/hotel"));
+        assert!(under_prefix("/test/api/hotel?id=1", "/test/api/hotel"));
+        assert!(under_prefix("/test/api/hotel\n*/*,", "/test/api/hotel"));
+        assert!(under_prefix("/test/api/hotels", "/test/api/"));
+        assert!(!under_prefix("/test/api/hotels", "/test/api/hotel"));
+        assert!(!under_prefix("/test/api", "/test/api/hotel"));
+    }
+
+    #[test]
+    fn keys_on_negotiation_headers() {
+        let key = |headers: &HeaderMap| cache_key("/test/api", Some("a=1"), headers, &[]);
+        let plain = key(&HeaderMap::new());
+        assert_eq!(plain, "/test/api?a=1");
+        let mut gzip = HeaderMap::new();
+        gzip.insert(header::ACCEPT_ENCODING, "gzip".parse().unwrap());
+        let mut br = HeaderMap::new();
+        br.insert(header::ACCEPT_ENCODING, "br".parse().unwrap());
+        let gzip = key(&gzip);
+        assert!(gzip.starts_with("/test/api?a=1\n"));
+        assert_ne!(gzip, plain);
+        assert_ne!(gzip, key(&br));
+    }
+
+    #[test]
+    fn keys_on_a_digest_of_credentials() {
+        let credential_headers = ["x-api-key".to_string()];
+        let key = |name: &str, value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(
+                HeaderName::from_bytes(name.as_bytes()).unwrap(),
+                value.parse().unwrap(),
+            );
+            cache_key("/test/api", None, &headers, &credential_headers)
+        };
+        let alice = key("authorization", "Bearer alice");
+        assert!(alice.starts_with("/test/api\n#"));
+        assert!(!alice.contains("alice"));
+        assert_ne!(alice, key("authorization", "Bearer bob"));
+        assert_ne!(key("x-api-key", "a"), key("x-api-key", "b"));
+        // Headers that aren't credentials leave the key alone.
+        assert_eq!(key("x-trace", "1"), "/test/api");
+    }
+
+    #[tokio::test]
+    async fn skips_responses_varying_on_other_headers() {
+        let cache = cache();
+        let rule = CacheRule::new("/test", Duration::from_secs(60));
+        let mut by_encoding = ok("x");
+        by_encoding
+            .headers
+            .insert(header::VARY, "Accept-Encoding".parse().unwrap());
+        cache
+            .put("a".into(), "/test/api/a", &by_encoding, &rule)
+            .await;
+        let mut by_cookie = ok("x");
+        by_cookie
+            .headers
+            .insert(header::VARY, "accept-encoding, Cookie".parse().unwrap());
+        cache
+            .put("b".into(), "/test/api/b", &by_cookie, &rule)
+            .await;
+        let mut by_anything = ok("x");
+        by_anything
+            .headers
+            .insert(header::VARY, "*".parse().unwrap());
+        cache
+            .put("c".into(), "/test/api/c", &by_anything, &rule)
+            .await;
+
+        assert!(cache.get("a").await.is_some());
+        assert!(cache.get("b").await.is_none());
+        assert!(cache.get("c").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn honours_no_store_and_expiry() {
+        let cache = cache();
+        let mut no_store = ok("x");
+        no_store
+            .headers
+            .insert(header::CACHE_CONTROL, "no-store".parse().unwrap());
+        let rule = CacheRule::new("/test", Duration::from_secs(60));
+        cache.put("a".into(), "/test/api/a", &no_store, &rule).await;
+        let expired = CacheRule::new("/test", Duration::ZERO);
+        cache
+            .put("b".into(), "/test/api/b", &ok("x"), &expired)
+            .await;
+
+        assert!(cache.get("a").await.is_none());
+        assert!(cache.get("b").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn stale_windows_follow_ttl() {
+        let cache = cache();
+        let mut rule = CacheRule::new("/test", Duration::ZERO);
+        rule.stale_while_revalidate = Duration::from_secs(60);
+        cache.put("swr".into(), "/test/swr", &ok("x"), &rule).await;
+        let mut rule = CacheRule::new("/test", Duration::ZERO);
+        rule.stale_if_error = Duration::from_secs(60);
+        cache.put("sie".into(), "/test/sie", &ok("x"), &rule).await;
+
+        assert!(matches!(
+            cache.lookup("swr").await,
+            CacheLookup::Revalidate(_)
+        ));
+        assert!(matches!(
+            cache.lookup("sie").await,
+            CacheLookup::Expired {
+                serve_on_error: true,
+                ..
+            }
+        ));
+        assert!(matches!(cache.lookup("none").await, CacheLookup::Miss));
+    }
+
+    #[test]
+    fn parses_stale_windows() {
+        let rules = parse_cache_rules("/a=60:30:300,/b=5").unwrap();
+        assert_eq!(rules[0].stale_while_revalidate, Duration::from_secs(30));
+        assert_eq!(rules[0].stale_if_error, Duration::from_secs(300));
+        assert_eq!(rules[1].stale_if_error, Duration::ZERO);
+        assert!(parse_cache_rules("/a=x").is_err());
+    }
+}
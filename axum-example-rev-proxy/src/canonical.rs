@@ -0,0 +1,466 @@
+//! Canonical JSON for webhook signatures. Key sorting alone ([`sort_json`])
+//! isn't enough when the signer formats strings and numbers differently from
+//! serde_json, so other encodings can be selected per provider:
+//!
+//! - `sorted`: keys sorted, re-serialized by serde_json (the original behaviour).
+//! - `jcs`: RFC 8785 JSON Canonicalization Scheme.
+//! - `php`: what PHP's `json_encode($data, JSON_UNESCAPED_SLASHES)` produces
+//!   after `json_decode($body, true)` and a recursive `ksort`, with numbers kept
+//!   exactly as they appeared in the raw body.
+//! - `sorted_raw`: keys sorted, but every key and value copied byte for byte
+//!   from the body, so nothing is ever re-encoded.
+//! - `raw`: the body exactly as received.
+
+use std::fmt::Write;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::sort_json::sort_json;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Canonicalization {
+    #[default]
+    Sorted,
+    Jcs,
+    Php,
+    SortedRaw,
+    Raw,
+}
+
+impl FromStr for Canonicalization {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim() {
+            "sorted" => Ok(Self::Sorted),
+            "jcs" => Ok(Self::Jcs),
+            "php" => Ok(Self::Php),
+            "sorted_raw" => Ok(Self::SortedRaw),
+            "raw" => Ok(Self::Raw),
+            other => Err(format!("unknown canonicalization {other:?}")),
+        }
+    }
+}
+
+/// The canonical form of the JSON document in `raw`.
+pub fn canonicalize(raw: &[u8], mode: Canonicalization) -> Result<String, String> {
+    if mode == Canonicalization::Sorted {
+        let value: serde_json::Value = serde_json::from_slice(raw).map_err(|e| e.to_string())?;
+        return serde_json::to_string(&sort_json(&value)).map_err(|e| e.to_string());
+    }
+    let text = std::str::from_utf8(raw).map_err(|e| e.to_string())?;
+    if mode == Canonicalization::Raw {
+        return Ok(text.to_string());
+    }
+    let mut parser = Parser { text, pos: 0 };
+    let value = parser.value()?;
+    parser.skip_whitespace();
+    if parser.pos != text.len() {
+        return Err(format!("trailing characters at {}", parser.pos));
+    }
+    let mut out = String::new();
+    match mode {
+        Canonicalization::Jcs => write_jcs(&value, &mut out)?,
+        Canonicalization::SortedRaw => write_sorted_raw(&value, &mut out),
+        _ => write_php(&value, &mut out),
+    }
+    Ok(out)
+}
+
+/// JSON as written, with number and string lexemes kept verbatim.
+#[derive(Debug, Clone, PartialEq)]
+enum RawJson {
+    Null,
+    Bool(bool),
+    Number(String),
+    String(RawString),
+    Array(Vec<RawJson>),
+    Object(Vec<(RawString, RawJson)>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct RawString {
+    value: String,
+    /// As it appeared in the body, quotes and escapes included.
+    lexeme: String,
+}
+
+struct Parser<'a> {
+    text: &'a str,
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn skip_whitespace(&mut self) {
+        let rest = &self.text[self.pos..];
+        self.pos += rest.len() - rest.trim_start_matches([' ', '\t', '\n', '\r']).len();
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.text.as_bytes().get(self.pos).copied()
+    }
+
+    fn expect(&mut self, byte: u8) -> Result<(), String> {
+        self.skip_whitespace();
+        if self.peek() != Some(byte) {
+            return Err(format!("expected '{}' at {}", byte as char, self.pos));
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn value(&mut self) -> Result<RawJson, String> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some(b'{') => self.object(),
+            Some(b'[') => self.array(),
+            Some(b'"') => self.string().map(RawJson::String),
+            Some(b't') => self.literal("true", RawJson::Bool(true)),
+            Some(b'f') => self.literal("false", RawJson::Bool(false)),
+            Some(b'n') => self.literal("null", RawJson::Null),
+            Some(b'-' | b'0'..=b'9') => self.number(),
+            _ => Err(format!("unexpected input at {}", self.pos)),
+        }
+    }
+
+    fn literal(&mut self, word: &str, value: RawJson) -> Result<RawJson, String> {
+        if !self.text[self.pos..].starts_with(word) {
+            return Err(format!("unexpected input at {}", self.pos));
+        }
+        self.pos += word.len();
+        Ok(value)
+    }
+
+    fn number(&mut self) -> Result<RawJson, String> {
+        let start = self.pos;
+        while let Some(b'-' | b'+' | b'.' | b'e' | b'E' | b'0'..=b'9') = self.peek() {
+            self.pos += 1;
+        }
+        let lexeme = &self.text[start..self.pos];
+        // Validates the grammar; the lexeme itself is what's kept.
+        serde_json::from_str::<serde_json::Number>(lexeme)
+            .map_err(|_| format!("invalid number {lexeme:?} at {start}"))?;
+        Ok(RawJson::Number(lexeme.to_string()))
+    }
+
+    fn string(&mut self) -> Result<RawString, String> {
+        let start = self.pos;
+        self.pos += 1;
+        loop {
+            match self.peek() {
+                None => return Err(format!("unterminated string at {start}")),
+                Some(b'"') => break,
+                Some(b'\\') => self.pos += 2,
+                Some(_) => self.pos += 1,
+            }
+        }
+        self.pos += 1;
+        let lexeme = &self.text[start..self.pos];
+        let value =
+            serde_json::from_str(lexeme).map_err(|e| format!("invalid string at {start}: {e}"))?;
+        Ok(RawString {
+            value,
+            lexeme: lexeme.to_string(),
+        })
+    }
+
+    fn array(&mut self) -> Result<RawJson, String> {
+        self.expect(b'[')?;
+        let mut items = Vec::new();
+        self.skip_whitespace();
+        if self.peek() == Some(b']') {
+            self.pos += 1;
+            return Ok(RawJson::Array(items));
+        }
+        loop {
+            items.push(self.value()?);
+            self.skip_whitespace();
+            match self.peek() {
+                Some(b',') => self.pos += 1,
+                Some(b']') => {
+                    self.pos += 1;
+                    return Ok(RawJson::Array(items));
+                }
+                _ => return Err(format!("expected ',' or ']' at {}", self.pos)),
+            }
+        }
+    }
+
+    fn object(&mut self) -> Result<RawJson, String> {
+        self.expect(b'{')?;
+        let mut members = Vec::new();
+        self.skip_whitespace();
+        if self.peek() == Some(b'}') {
+            self.pos += 1;
+            return Ok(RawJson::Object(members));
+        }
+        loop {
+            self.skip_whitespace();
+            if self.peek() != Some(b'"') {
+                return Err(format!("expected a key at {}", self.pos));
+            }
+            let key = self.string()?;
+            self.expect(b':')?;
+            members.push((key, self.value()?));
+            self.skip_whitespace();
+            match self.peek() {
+                Some(b',') => self.pos += 1,
+                Some(b'}') => {
+                    self.pos += 1;
+                    return Ok(RawJson::Object(members));
+                }
+                _ => return Err(format!("expected ',' or '}}' at {}", self.pos)),
+            }
+        }
+    }
+}
+
+fn write_jcs(value: &RawJson, out: &mut String) -> Result<(), String> {
+    match value {
+        RawJson::Null => out.push_str("null"),
+        RawJson::Bool(b) => out.push_str(if *b { "true" } else { "false" }),
+        RawJson::Number(lexeme) => {
+            let number: f64 = lexeme
+                .parse()
+                .map_err(|_| format!("invalid number {lexeme:?}"))?;
+            if !number.is_finite() {
+                return Err(format!("number {lexeme} is out of range"));
+            }
+            out.push_str(&es_number(number));
+        }
+        RawJson::String(s) => write_string(&s.value, false, out),
+        RawJson::Array(items) => {
+            out.push('[');
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_jcs(item, out)?;
+            }
+            out.push(']');
+        }
+        RawJson::Object(members) => {
+            // RFC 8785 orders keys by their UTF-16 code units.
+            let mut members: Vec<_> = members.iter().collect();
+            members.sort_by(|(a, _), (b, _)| a.value.encode_utf16().cmp(b.value.encode_utf16()));
+            out.push('{');
+            for (i, (key, item)) in members.into_iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_string(&key.value, false, out);
+                out.push(':');
+                write_jcs(item, out)?;
+            }
+            out.push('}');
+        }
+    }
+    Ok(())
+}
+
+fn write_php(value: &RawJson, out: &mut String) {
+    match value {
+        RawJson::Null => out.push_str("null"),
+        RawJson::Bool(b) => out.push_str(if *b { "true" } else { "false" }),
+        RawJson::Number(lexeme) => out.push_str(lexeme),
+        RawJson::String(s) => write_string(&s.value, true, out),
+        RawJson::Array(items) => {
+            out.push('[');
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_php(item, out);
+            }
+            out.push(']');
+        }
+        // `json_decode(.., true)` turns `{}` into an empty array.
+        RawJson::Object(members) if members.is_empty() => out.push_str("[]"),
+        RawJson::Object(members) => {
+            let mut members: Vec<_> = members.iter().collect();
+            members.sort_by(|(a, _), (b, _)| a.value.cmp(&b.value));
+            out.push('{');
+            for (i, (key, item)) in members.into_iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_string(&key.value, true, out);
+                out.push(':');
+                write_php(item, out);
+            }
+            out.push('}');
+        }
+    }
+}
+
+/// Sorted keys and no whitespace, but each scalar exactly as the sender wrote it.
+fn write_sorted_raw(value: &RawJson, out: &mut String) {
+    match value {
+        RawJson::Null => out.push_str("null"),
+        RawJson::Bool(b) => out.push_str(if *b { "true" } else { "false" }),
+        RawJson::Number(lexeme) => out.push_str(lexeme),
+        RawJson::String(s) => out.push_str(&s.lexeme),
+        RawJson::Array(items) => {
+            out.push('[');
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_sorted_raw(item, out);
+            }
+            out.push(']');
+        }
+        RawJson::Object(members) => {
+            let mut members: Vec<_> = members.iter().collect();
+            members.sort_by(|(a, _), (b, _)| a.value.cmp(&b.value));
+            out.push('{');
+            for (i, (key, item)) in members.into_iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                out.push_str(&key.lexeme);
+                out.push(':');
+                write_sorted_raw(item, out);
+            }
+            out.push('}');
+        }
+    }
+}
+
+/// Quotes `s`, escaping only what JSON requires, plus all non-ASCII as
+/// `\uXXXX` when `escape_unicode` is set (as PHP does by default).
+fn write_string(s: &str, escape_unicode: bool, out: &mut String) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\u{8}' => out.push_str("\\b"),
+            '\u{c}' => out.push_str("\\f"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c if escape_unicode && !c.is_ascii() => {
+                let mut units = [0; 2];
+                for unit in c.encode_utf16(&mut units) {
+                    let _ = write!(out, "\\u{:04x}", unit);
+                }
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// Formats `n` the way ECMAScript's `Number.prototype.toString` does, as
+/// RFC 8785 requires.
+fn es_number(n: f64) -> String {
+    if n == 0.0 {
+        return "0".to_string();
+    }
+    // `{:e}` gives the shortest round-tripping digits, e.g. `-1.25e-7`.
+    let formatted = format!("{:e}", n.abs());
+    let (mantissa, exponent) = formatted.split_once('e').expect("exponent is present");
+    let digits: String = mantissa.chars().filter(|c| *c != '.').collect();
+    let exponent: i32 = exponent.parse().expect("exponent is numeric");
+    let k = digits.len() as i32;
+    // The decimal point sits after `n` digits.
+    let point = exponent + 1;
+
+    let mut out = String::new();
+    if n < 0.0 {
+        out.push('-');
+    }
+    if k <= point && point <= 21 {
+        out.push_str(&digits);
+        out.push_str(&"0".repeat((point - k) as usize));
+    } else if 0 < point && point <= 21 {
+        out.push_str(&digits[..point as usize]);
+        out.push('.');
+        out.push_str(&digits[point as usize..]);
+    } else if -6 < point && point <= 0 {
+        out.push_str("0.");
+        out.push_str(&"0".repeat((-point) as usize));
+        out.push_str(&digits);
+    } else {
+        out.push_str(&digits[..1]);
+        if k > 1 {
+            out.push('.');
+            out.push_str(&digits[1..]);
+        }
+        let _ = write!(
+            out,
+            "e{}{}",
+            if point > 0 { "+" } else { "-" },
+            (point - 1).abs()
+        );
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_numbers_like_ecmascript() {
+        let cases = [
+            (1.0, "1"),
+            (-0.0, "0"),
+            (0.000001, "0.000001"),
+            (1e-7, "1e-7"),
+            (123.456, "123.456"),
+            (1e21, "1e+21"),
+            (1e20, "100000000000000000000"),
+            (-1.5e300, "-1.5e+300"),
+            (4.5, "4.5"),
+            (0.1 + 0.2, "0.30000000000000004"),
+        ];
+        for (number, expected) in cases {
+            assert_eq!(es_number(number), expected, "{number}");
+        }
+    }
+
+    #[test]
+    fn canonicalizes_per_mode() {
+        let raw = r#"{"b": 1.50, "a": {"z": "café / €", "y": {}}, "é": 1e2, "c": [true, null]}"#
+            .as_bytes();
+
+        assert_eq!(
+            canonicalize(raw, Canonicalization::Sorted).unwrap(),
+            r#"{"a":{"y":{},"z":"café / €"},"b":1.5,"c":[true,null],"é":100.0}"#
+        );
+        assert_eq!(
+            canonicalize(raw, Canonicalization::Jcs).unwrap(),
+            r#"{"a":{"y":{},"z":"café / €"},"b":1.5,"c":[true,null],"é":100}"#
+        );
+        assert_eq!(
+            canonicalize(raw, Canonicalization::Php).unwrap(),
+            r#"{"a":{"y":[],"z":"caf\u00e9 / \u20ac"},"b":1.50,"c":[true,null],"\u00e9":1e2}"#
+        );
+
+        assert_eq!(
+            canonicalize(raw, Canonicalization::SortedRaw).unwrap(),
+            r#"{"a":{"y":{},"z":"café / €"},"b":1.50,"c":[true,null],"é":1e2}"#
+        );
+        let escaped = br#"{"b": "caf\u00e9 \/ x", "a": 0.10}"#;
+        assert_eq!(
+            canonicalize(escaped, Canonicalization::SortedRaw).unwrap(),
+            r#"{"a":0.10,"b":"caf\u00e9 \/ x"}"#
+        );
+        assert_eq!(
+            canonicalize(escaped, Canonicalization::Raw)
+                .unwrap()
+                .as_bytes(),
+            escaped
+        );
+
+        assert!(canonicalize(b"{\"a\": 1,}", Canonicalization::Jcs).is_err());
+        assert!(canonicalize(b"[1] x", Canonicalization::Php).is_err());
+    }
+}
@@ -0,0 +1,479 @@
+//! Temporary full captures for deep debugging: an admin arms a rule such as
+//! "the next 20 requests under `/test/api/book`", the matching exchanges are
+//! kept in memory with secrets redacted, and the rule disarms itself once
+//! it has enough or its time is up.
+//!
+//! `POST /admin/capture` arms (replacing any earlier capture),
+//! `GET /admin/capture` reports progress, `GET /admin/capture/download`
+//! returns what was captured and `DELETE /admin/capture` disarms and clears.
+//!
+//! With a [`crate::storage`] backend, captured exchanges are also written
+//! there (kept for a day) and downloads read them back, so they survive a
+//! restart and can be fetched from any replica sharing the backend.
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use axum::extract::State;
+use axum::response::IntoResponse;
+use axum::Json;
+use hyper::header::{self, HeaderMap, HeaderValue};
+use hyper::{StatusCode, Uri};
+use serde::{Deserialize, Serialize};
+use tracing::{error, info};
+
+use crate::app_state::AppState;
+use crate::interceptor::{OutboundRequest, UpstreamResponse};
+use crate::request_log::{now_ms, render_body};
+use crate::storage::Storage;
+
+/// Most exchanges one capture may ask for; bodies are capped too, so this
+/// bounds the memory a capture can hold.
+pub const MAX_CAPTURE_COUNT: usize = 100;
+const DEFAULT_TTL: Duration = Duration::from_secs(10 * 60);
+const STORAGE_PREFIX: &str = "capture:";
+/// How long captured exchanges are kept in storage.
+const STORAGE_RETENTION: Duration = Duration::from_secs(24 * 60 * 60);
+/// Headers whose values never appear in a capture.
+const SECRET_HEADERS: &[&str] = &[
+    "authorization",
+    "proxy-authorization",
+    "cookie",
+    "set-cookie",
+    "x-api-key",
+];
+const REDACTED: &str = "[REDACTED]";
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct CaptureRule {
+    /// Inbound path prefix, e.g. `/test/api/book`.
+    pub path_prefix: String,
+    pub count: usize,
+    /// Disarms after this long even if fewer exchanges matched.
+    #[serde(default)]
+    pub ttl_secs: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CapturedRequest {
+    pub method: String,
+    pub uri: String,
+    pub headers: BTreeMap<String, String>,
+    pub body: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CapturedResponse {
+    pub status: u16,
+    pub headers: BTreeMap<String, String>,
+    pub body: String,
+}
+
+/// One exchange as sent upstream and as returned to the caller.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CapturedExchange {
+    pub at_ms: i64,
+    pub route: String,
+    pub request: CapturedRequest,
+    pub response: CapturedResponse,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CaptureStatus {
+    pub rule: Option<CaptureRule>,
+    pub armed: bool,
+    pub captured: usize,
+    /// Seconds until the rule disarms by itself, while armed.
+    pub expires_in_secs: Option<u64>,
+}
+
+#[derive(Debug)]
+struct Armed {
+    rule: CaptureRule,
+    expires: Instant,
+}
+
+#[derive(Debug, Default)]
+struct CaptureState {
+    armed: Option<Armed>,
+    /// The last rule armed, kept for the status once it has disarmed.
+    rule: Option<CaptureRule>,
+    exchanges: Vec<CapturedExchange>,
+}
+
+pub struct Capture {
+    redact_keys: Vec<String>,
+    state: Mutex<CaptureState>,
+    storage: Option<Arc<dyn Storage>>,
+}
+
+impl Capture {
+    /// `redact_keys` are JSON keys and query parameters whose values are
+    /// replaced, as in the request log.
+    pub fn new(redact_keys: Vec<String>) -> Self {
+        Self {
+            redact_keys,
+            state: Mutex::default(),
+            storage: None,
+        }
+    }
+
+    /// Also keeps captured exchanges in `storage`, when there is one.
+    pub fn with_storage(mut self, storage: Option<Arc<dyn Storage>>) -> Self {
+        self.storage = storage;
+        self
+    }
+
+    /// Arms `rule`, dropping whatever an earlier capture collected.
+    pub async fn arm(&self, rule: CaptureRule) -> Result<(), String> {
+        if rule.count == 0 || rule.count > MAX_CAPTURE_COUNT {
+            return Err(format!("count must be between 1 and {MAX_CAPTURE_COUNT}"));
+        }
+        self.clear_storage().await;
+        let ttl = rule.ttl_secs.map_or(DEFAULT_TTL, Duration::from_secs);
+        let mut state = self.state.lock().unwrap();
+        *state = CaptureState {
+            armed: Some(Armed {
+                rule: rule.clone(),
+                expires: Instant::now() + ttl,
+            }),
+            rule: Some(rule),
+            exchanges: Vec::new(),
+        };
+        Ok(())
+    }
+
+    pub async fn clear(&self) {
+        *self.state.lock().unwrap() = CaptureState::default();
+        self.clear_storage().await;
+    }
+
+    async fn clear_storage(&self) {
+        let Some(storage) = &self.storage else {
+            return;
+        };
+        let result = async {
+            for key in storage.list(STORAGE_PREFIX).await? {
+                storage.delete(&key).await?;
+            }
+            Ok::<_, String>(())
+        }
+        .await;
+        if let Err(e) = result {
+            error!("Failed to clear stored captures: {}", e);
+        }
+    }
+
+    /// Records the exchange if an armed rule matches `route`.
+    pub fn offer(&self, route: &str, req: &OutboundRequest, res: &UpstreamResponse) {
+        let mut state = self.state.lock().unwrap();
+        let Some(armed) = &state.armed else {
+            return;
+        };
+        if armed.expires <= Instant::now() {
+            state.armed = None;
+            return;
+        }
+        if !route.starts_with(&armed.rule.path_prefix) {
+            return;
+        }
+        let count = armed.rule.count;
+        let exchange = CapturedExchange {
+            at_ms: now_ms(),
+            route: route.to_string(),
+            request: CapturedRequest {
+                method: req.method.to_string(),
+                uri: redact_uri(&req.uri, &self.redact_keys),
+                headers: redact_headers(&req.headers, &self.redact_keys),
+                body: render_body(&req.headers, &req.body, &self.redact_keys),
+            },
+            response: CapturedResponse {
+                status: res.status.as_u16(),
+                headers: redact_headers(&res.headers, &self.redact_keys),
+                body: render_body(&res.headers, &res.body, &self.redact_keys),
+            },
+        };
+        if let Some(storage) = &self.storage {
+            // Ordered by time, then by place in the capture.
+            let key = format!(
+                "{STORAGE_PREFIX}{:013}:{:03}",
+                exchange.at_ms,
+                state.exchanges.len()
+            );
+            let (storage, json) = (storage.clone(), serde_json::to_vec(&exchange));
+            tokio::spawn(async move {
+                let result = match json {
+                    Ok(json) => storage.put(&key, json, Some(STORAGE_RETENTION)).await,
+                    Err(e) => Err(e.to_string()),
+                };
+                if let Err(e) = result {
+                    error!("Failed to store a captured exchange: {}", e);
+                }
+            });
+        }
+        state.exchanges.push(exchange);
+        if state.exchanges.len() >= count {
+            info!("Capture under {} complete", route);
+            state.armed = None;
+        }
+    }
+
+    pub fn status(&self) -> CaptureStatus {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        if state
+            .armed
+            .as_ref()
+            .is_some_and(|armed| armed.expires <= now)
+        {
+            state.armed = None;
+        }
+        CaptureStatus {
+            rule: state.rule.clone(),
+            armed: state.armed.is_some(),
+            captured: state.exchanges.len(),
+            expires_in_secs: state
+                .armed
+                .as_ref()
+                .map(|armed| armed.expires.duration_since(now).as_secs()),
+        }
+    }
+
+    pub async fn exchanges(&self) -> Vec<CapturedExchange> {
+        let Some(storage) = &self.storage else {
+            return self.state.lock().unwrap().exchanges.clone();
+        };
+        let result = async {
+            let mut exchanges = Vec::new();
+            for key in storage.list(STORAGE_PREFIX).await? {
+                if let Some(json) = storage.get(&key).await? {
+                    exchanges.push(serde_json::from_slice(&json).map_err(|e| e.to_string())?);
+                }
+            }
+            Ok::<_, String>(exchanges)
+        }
+        .await;
+        result.unwrap_or_else(|e| {
+            error!("Failed to read stored captures: {}", e);
+            self.state.lock().unwrap().exchanges.clone()
+        })
+    }
+}
+
+fn redacts(name: &str, redact_keys: &[String]) -> bool {
+    SECRET_HEADERS.contains(&name) || redact_keys.iter().any(|key| key.eq_ignore_ascii_case(name))
+}
+
+/// Joins repeated headers with `, `, replacing secret values and those
+/// named in `redact_keys`.
+pub(crate) fn redact_headers(
+    headers: &HeaderMap,
+    redact_keys: &[String],
+) -> BTreeMap<String, String> {
+    let mut redacted: BTreeMap<String, String> = BTreeMap::new();
+    for (name, value) in headers {
+        let value = if redacts(name.as_str(), redact_keys) {
+            REDACTED.to_string()
+        } else {
+            String::from_utf8_lossy(value.as_bytes()).into_owned()
+        };
+        redacted
+            .entry(name.to_string())
+            .and_modify(|existing| {
+                existing.push_str(", ");
+                existing.push_str(&value);
+            })
+            .or_insert(value);
+    }
+    redacted
+}
+
+/// `uri` with the values of query parameters in `redact_keys` replaced.
+pub(crate) fn redact_uri(uri: &Uri, redact_keys: &[String]) -> String {
+    let Some(query) = uri.query() else {
+        return uri.to_string();
+    };
+    let base = uri.to_string();
+    let base = base.split('?').next().unwrap_or_default();
+    format!("{base}?{}", redact_query(query, redact_keys))
+}
+
+/// `query` with the values of parameters in `redact_keys` replaced.
+pub(crate) fn redact_query(query: &str, redact_keys: &[String]) -> String {
+    let query: Vec<String> = query
+        .split('&')
+        .map(|pair| match pair.split_once('=') {
+            Some((key, _)) if redact_keys.iter().any(|k| k.eq_ignore_ascii_case(key)) => {
+                format!("{key}={REDACTED}")
+            }
+            _ => pair.to_string(),
+        })
+        .collect();
+    query.join("&")
+}
+
+/// `POST /admin/capture` with `{"path_prefix": "/test/api/book", "count": 20}`.
+pub async fn arm_handler(
+    State(state): State<AppState>,
+    Json(rule): Json<CaptureRule>,
+) -> Result<Json<CaptureStatus>, (StatusCode, String)> {
+    state
+        .capture
+        .arm(rule.clone())
+        .await
+        .map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    info!(
+        "Armed capture of {} requests under {}",
+        rule.count, rule.path_prefix
+    );
+    Ok(Json(state.capture.status()))
+}
+
+/// `GET /admin/capture`: the current rule and how far it got.
+pub async fn status_handler(State(state): State<AppState>) -> Json<CaptureStatus> {
+    Json(state.capture.status())
+}
+
+/// `GET /admin/capture/download`: the captured exchanges as a JSON file.
+pub async fn download_handler(State(state): State<AppState>) -> impl IntoResponse {
+    (
+        [(
+            header::CONTENT_DISPOSITION,
+            HeaderValue::from_static("attachment; filename=\"capture.json\""),
+        )],
+        Json(state.capture.exchanges().await),
+    )
+}
+
+/// `DELETE /admin/capture`: disarms and drops what was captured.
+pub async fn clear_handler(State(state): State<AppState>) -> StatusCode {
+    state.capture.clear().await;
+    StatusCode::NO_CONTENT
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::Method;
+
+    use super::*;
+
+    fn exchange(path: &str) -> (OutboundRequest, UpstreamResponse) {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::AUTHORIZATION,
+            HeaderValue::from_static("Bearer abc"),
+        );
+        headers.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("application/json"),
+        );
+        (
+            OutboundRequest {
+                env: "test".to_string(),
+                method: Method::POST,
+                uri: format!("http://supplier{path}?token=abc&city=goa")
+                    .parse()
+                    .unwrap(),
+                headers: headers.clone(),
+                body: r#"{"card_number":"4111","nights":2}"#.into(),
+            },
+            UpstreamResponse {
+                status: StatusCode::OK,
+                headers,
+                body: r#"{"ok":true}"#.into(),
+                trailers: None,
+            },
+        )
+    }
+
+    fn capture() -> Capture {
+        Capture::new(vec!["token".to_string(), "card_number".to_string()])
+    }
+
+    #[tokio::test]
+    async fn captures_matching_requests_until_full() {
+        let capture = capture();
+        capture
+            .arm(CaptureRule {
+                path_prefix: "/test/api/book".to_string(),
+                count: 2,
+                ttl_secs: None,
+            })
+            .await
+            .unwrap();
+        for route in [
+            "/test/api/book/1",
+            "/test/api/search",
+            "/test/api/book/2",
+            "/test/api/book/3",
+        ] {
+            let (req, res) = exchange(route.strip_prefix("/test").unwrap());
+            capture.offer(route, &req, &res);
+        }
+        let exchanges = capture.exchanges().await;
+        assert_eq!(exchanges.len(), 2);
+        assert_eq!(exchanges[1].route, "/test/api/book/2");
+        assert!(!capture.status().armed);
+
+        let request = &exchanges[0].request;
+        assert_eq!(request.headers["authorization"], REDACTED);
+        assert_eq!(
+            request.uri,
+            "http://supplier/api/book/1?token=[REDACTED]&city=goa"
+        );
+        assert_eq!(request.body, r#"{"card_number":"[REDACTED]","nights":2}"#);
+        assert_eq!(exchanges[0].response.body, r#"{"ok":true}"#);
+    }
+
+    #[tokio::test]
+    async fn keeps_captures_in_storage() {
+        let storage: Arc<dyn Storage> = Arc::new(crate::storage::MemoryStorage::default());
+        let rule = CaptureRule {
+            path_prefix: "/test".to_string(),
+            count: 5,
+            ttl_secs: None,
+        };
+        let first = capture().with_storage(Some(storage.clone()));
+        first.arm(rule.clone()).await.unwrap();
+        let (req, res) = exchange("/api");
+        first.offer("/test/api", &req, &res);
+        tokio::time::sleep(Duration::from_millis(20)).await;
+
+        // Another replica sharing the backend sees it.
+        let other = capture().with_storage(Some(storage));
+        let exchanges = other.exchanges().await;
+        assert_eq!(exchanges.len(), 1);
+        assert_eq!(exchanges[0].route, "/test/api");
+        other.arm(rule).await.unwrap();
+        assert!(first.exchanges().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn disarms_when_the_time_is_up() {
+        let capture = capture();
+        capture
+            .arm(CaptureRule {
+                path_prefix: "/test".to_string(),
+                count: 5,
+                ttl_secs: Some(0),
+            })
+            .await
+            .unwrap();
+        let (req, res) = exchange("/api");
+        capture.offer("/test/api", &req, &res);
+        assert!(capture.exchanges().await.is_empty());
+        assert!(!capture.status().armed);
+    }
+
+    #[tokio::test]
+    async fn rejects_oversized_captures() {
+        let rule = |count| CaptureRule {
+            path_prefix: "/test".to_string(),
+            count,
+            ttl_secs: None,
+        };
+        assert!(capture().arm(rule(0)).await.is_err());
+        assert!(capture().arm(rule(MAX_CAPTURE_COUNT + 1)).await.is_err());
+    }
+}
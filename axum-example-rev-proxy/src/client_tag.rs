@@ -0,0 +1,131 @@
+//! Attributes shared-proxy traffic to the calling service. A caller sends
+//! `X-Client-Tag: checkout-service`; the tag is counted per tag at
+//! `/metrics` (`proxy_client_tag_requests_total{tag="checkout-service"}`),
+//! written to the access log and request log, and echoed back in the
+//! response's `X-Client-Tag`. It isn't forwarded to the supplier.
+//!
+//! To keep metric cardinality bounded, tags are lowercased and must be 1-64
+//! characters of `[a-z0-9._-]`, and only `CLIENT_TAGS` (comma-separated)
+//! are kept when that's set, or else the first `CLIENT_TAG_LIMIT` (100 by
+//! default) distinct tags seen. Anything else is recorded, and echoed, as
+//! `other`.
+
+use std::collections::BTreeSet;
+use std::sync::Mutex;
+
+use hyper::header::{HeaderMap, HeaderName};
+use serde::Deserialize;
+
+pub const CLIENT_TAG_HEADER: HeaderName = HeaderName::from_static("x-client-tag");
+
+/// What tags outside the allowed set are recorded as.
+pub const OTHER_TAG: &str = "other";
+
+pub const DEFAULT_MAX_CLIENT_TAGS: usize = 100;
+
+const MAX_TAG_LEN: usize = 64;
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct ClientTagConfig {
+    /// The only tags kept; any well-formed tag when empty.
+    pub allowed: Vec<String>,
+    /// Distinct tags kept when `allowed` is empty.
+    pub max_tags: usize,
+}
+
+impl Default for ClientTagConfig {
+    fn default() -> Self {
+        Self {
+            allowed: Vec::new(),
+            max_tags: DEFAULT_MAX_CLIENT_TAGS,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ClientTags {
+    max_tags: usize,
+    /// The allowed tags, or the ones seen so far.
+    known: Mutex<BTreeSet<String>>,
+    fixed: bool,
+}
+
+impl ClientTags {
+    pub fn new(config: &ClientTagConfig) -> Self {
+        Self {
+            max_tags: config.max_tags,
+            known: Mutex::new(
+                config
+                    .allowed
+                    .iter()
+                    .map(|tag| tag.trim().to_ascii_lowercase())
+                    .collect(),
+            ),
+            fixed: !config.allowed.is_empty(),
+        }
+    }
+
+    /// The tag `headers` are attributed to, if they carry one.
+    pub fn resolve(&self, headers: &HeaderMap) -> Option<String> {
+        let value = headers.get(CLIENT_TAG_HEADER)?;
+        let tag = String::from_utf8_lossy(value.as_bytes())
+            .trim()
+            .to_ascii_lowercase();
+        if !is_valid(&tag) {
+            return Some(OTHER_TAG.to_string());
+        }
+        let mut known = self.known.lock().unwrap();
+        if known.contains(&tag) {
+            return Some(tag);
+        }
+        if self.fixed || known.len() >= self.max_tags {
+            return Some(OTHER_TAG.to_string());
+        }
+        known.insert(tag.clone());
+        Some(tag)
+    }
+}
+
+fn is_valid(tag: &str) -> bool {
+    (1..=MAX_TAG_LEN).contains(&tag.len())
+        && tag
+            .bytes()
+            .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b"._-".contains(&b))
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::header::HeaderValue;
+
+    use super::*;
+
+    fn tagged(tag: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(CLIENT_TAG_HEADER, HeaderValue::from_static(tag));
+        headers
+    }
+
+    #[test]
+    fn bounds_the_tags_kept() {
+        let tags = ClientTags::new(&ClientTagConfig {
+            allowed: Vec::new(),
+            max_tags: 2,
+        });
+        assert_eq!(tags.resolve(&HeaderMap::new()), None);
+        assert_eq!(
+            tags.resolve(&tagged(" Checkout-Service ")).as_deref(),
+            Some("checkout-service")
+        );
+        assert_eq!(tags.resolve(&tagged("search")).as_deref(), Some("search"));
+        assert_eq!(tags.resolve(&tagged("reports")).as_deref(), Some("other"));
+        assert_eq!(tags.resolve(&tagged("search")).as_deref(), Some("search"));
+        assert_eq!(tags.resolve(&tagged("a b")).as_deref(), Some("other"));
+
+        let tags = ClientTags::new(&ClientTagConfig {
+            allowed: vec!["search".to_string()],
+            max_tags: DEFAULT_MAX_CLIENT_TAGS,
+        });
+        assert_eq!(tags.resolve(&tagged("search")).as_deref(), Some("search"));
+        assert_eq!(tags.resolve(&tagged("checkout")).as_deref(), Some("other"));
+    }
+}
@@ -0,0 +1,280 @@
+//! Micro-batching for burst-heavy supplier endpoints. Under a prefix listed
+//! in `COALESCE_RULES` (`prefix=window_ms`, comma-separated, e.g.
+//! `/prod/api/search=50`), the first request of a kind waits out the window
+//! before going upstream, and identical requests arriving until its answer
+//! is in share that one call instead of making their own. Shared answers
+//! carry `X-Cache: COALESCED`.
+//!
+//! This runs after the response cache lookup, so it catches the burst of
+//! misses the cache can't have answered yet. Only safe methods coalesce
+//! unless the rule ends in `:any` (`/prod/api/search=50:any`), for a search
+//! sent as a POST. Requests are identical when their [`crate::fingerprint`],
+//! their `Accept`, `Accept-Encoding` and `Accept-Language` headers and their
+//! credentials match, so callers never share each other's answers.
+//! Conditional requests and ones with a target override always go alone.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use hyper::header::{self, HeaderMap};
+use hyper::Method;
+use serde::Deserialize;
+use tokio::sync::watch;
+
+use crate::cache::{credential_digest, under_prefix};
+use crate::error::ProxyError;
+use crate::interceptor::UpstreamResponse;
+
+/// Request headers that can change the upstream's answer, so they're part
+/// of what makes requests identical.
+pub(crate) const VARY_HEADERS: [header::HeaderName; 3] = [
+    header::ACCEPT,
+    header::ACCEPT_ENCODING,
+    header::ACCEPT_LANGUAGE,
+];
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct CoalesceRule {
+    /// Inbound path prefix, including the env segment.
+    pub path_prefix: String,
+    pub window: Duration,
+    /// Also coalesce unsafe methods, for reads sent as POSTs.
+    #[serde(default)]
+    pub any_method: bool,
+}
+
+/// Parses `prefix=window_ms` rules, optionally ending in `:any`, separated
+/// by commas.
+pub fn parse_coalesce_rules(value: &str) -> Result<Vec<CoalesceRule>, String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(|rule| {
+            let (prefix, ms) = rule
+                .split_once('=')
+                .ok_or_else(|| format!("expected prefix=window_ms, got {rule}"))?;
+            let (ms, any_method) = match ms.trim().strip_suffix(":any") {
+                Some(ms) => (ms, true),
+                None => (ms, false),
+            };
+            let ms = ms
+                .trim()
+                .parse::<u64>()
+                .map_err(|e| format!("invalid window in {rule}: {e}"))?;
+            Ok(CoalesceRule {
+                path_prefix: prefix.trim().to_string(),
+                window: Duration::from_millis(ms),
+                any_method,
+            })
+        })
+        .collect()
+}
+
+type Shared = Option<Result<UpstreamResponse, ProxyError>>;
+
+#[derive(Debug, Default)]
+pub struct Coalescer {
+    rules: Vec<CoalesceRule>,
+    /// Calls waiting out their window or in flight, by request key.
+    pending: Arc<Mutex<HashMap<String, watch::Receiver<Shared>>>>,
+}
+
+impl Coalescer {
+    pub fn new(rules: Vec<CoalesceRule>) -> Self {
+        Self {
+            rules,
+            pending: Default::default(),
+        }
+    }
+
+    /// The window for `method` requests to `path`, if they're coalesced.
+    pub fn window_for(&self, method: &Method, path: &str) -> Option<Duration> {
+        self.rules
+            .iter()
+            .find(|rule| under_prefix(path, &rule.path_prefix))
+            .filter(|rule| rule.any_method || method.is_safe())
+            .map(|rule| rule.window)
+    }
+
+    /// What makes a request identical to another, given its fingerprint.
+    pub fn key(fingerprint: &str, headers: &HeaderMap, credential_headers: &[String]) -> String {
+        let mut key = fingerprint.to_string();
+        for name in &VARY_HEADERS {
+            key.push('\n');
+            for value in headers.get_all(name) {
+                key.push_str(&String::from_utf8_lossy(value.as_bytes()));
+                key.push(',');
+            }
+        }
+        if let Some(digest) = credential_digest(headers, credential_headers) {
+            key.push_str("\n#");
+            key.push_str(&digest);
+        }
+        key
+    }
+
+    /// Joins the pending call for `key`, or starts one that runs `fetch`
+    /// once `window` has passed. The call runs in its own task, so the
+    /// others still get the answer if the caller that started it goes away.
+    /// Returns the answer and whether it was shared from another request's
+    /// call.
+    pub async fn run<F, Fut>(
+        &self,
+        key: String,
+        window: Duration,
+        fetch: F,
+    ) -> (Result<UpstreamResponse, ProxyError>, bool)
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<UpstreamResponse, ProxyError>> + Send + 'static,
+    {
+        let (mut answer, joined) = {
+            let mut pending = self.pending.lock().unwrap();
+            match pending.get(&key) {
+                Some(answer) => (answer.clone(), true),
+                None => {
+                    let (sender, answer) = watch::channel(None);
+                    pending.insert(key.clone(), answer.clone());
+                    let call = fetch();
+                    let pending = self.pending.clone();
+                    tokio::spawn(async move {
+                        tokio::time::sleep(window).await;
+                        let result = call.await;
+                        pending.lock().unwrap().remove(&key);
+                        let _ = sender.send(Some(result));
+                    });
+                    (answer, false)
+                }
+            }
+        };
+        let result = match answer.wait_for(Option::is_some).await {
+            Ok(result) => result.clone().unwrap(),
+            // The task panicked.
+            Err(_) => Err(ProxyError::Upstream),
+        };
+        (result, joined)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use axum::body::Bytes;
+    use hyper::StatusCode;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn shares_one_call_between_identical_requests() {
+        let coalescer = Arc::new(Coalescer::new(
+            parse_coalesce_rules("/prod/api/search=50").unwrap(),
+        ));
+        assert_eq!(
+            coalescer.window_for(&Method::GET, "/prod/api/search/hotels"),
+            Some(Duration::from_millis(50))
+        );
+        assert_eq!(coalescer.window_for(&Method::GET, "/prod/api/book"), None);
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let request = |key: &'static str| {
+            let (coalescer, calls) = (coalescer.clone(), calls.clone());
+            tokio::spawn(async move {
+                coalescer
+                    .run(
+                        key.to_string(),
+                        Duration::from_millis(50),
+                        move || async move {
+                            calls.fetch_add(1, Ordering::SeqCst);
+                            Ok(UpstreamResponse {
+                                status: StatusCode::OK,
+                                headers: HeaderMap::new(),
+                                body: Bytes::from(key),
+                                trailers: None,
+                            })
+                        },
+                    )
+                    .await
+            })
+        };
+        let (first, second, other) = (request("a"), request("a"), request("b"));
+        let (first, second, other) = (
+            first.await.unwrap(),
+            second.await.unwrap(),
+            other.await.unwrap(),
+        );
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        assert_eq!(first.0.unwrap().body, "a");
+        assert_eq!(second.0.unwrap().body, "a");
+        assert!(first.1 != second.1);
+        assert!(!other.1);
+
+        // Once answered, the next request makes its own call.
+        let (result, joined) = request("a").await.unwrap();
+        assert!(result.is_ok() && !joined);
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn keys_on_the_headers_that_vary_answers() {
+        let mut french = HeaderMap::new();
+        french.insert(header::ACCEPT_LANGUAGE, "fr".parse().unwrap());
+        let key = Coalescer::key("f00d", &french, &[]);
+        assert_ne!(key, Coalescer::key("f00d", &HeaderMap::new(), &[]));
+        french.insert("x-client-tag", "search".parse().unwrap());
+        assert_eq!(key, Coalescer::key("f00d", &french, &[]));
+        assert!(parse_coalesce_rules("/prod/api/search").is_err());
+    }
+
+    #[test]
+    fn keys_on_the_callers_credentials() {
+        let credential_headers = ["x-api-key".to_string()];
+        let key = |name: &str, value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(
+                header::HeaderName::from_bytes(name.as_bytes()).unwrap(),
+                value.parse().unwrap(),
+            );
+            Coalescer::key("f00d", &headers, &credential_headers)
+        };
+        assert_eq!(
+            key("authorization", "Bearer a"),
+            key("authorization", "Bearer a")
+        );
+        assert_ne!(
+            key("authorization", "Bearer a"),
+            key("authorization", "Bearer b")
+        );
+        assert_ne!(key("x-api-key", "a"), key("x-api-key", "b"));
+        assert_ne!(
+            key("x-api-key", "a"),
+            Coalescer::key("f00d", &HeaderMap::new(), &credential_headers)
+        );
+    }
+
+    #[test]
+    fn coalesces_safe_methods_on_segment_boundaries_unless_opted_in() {
+        let coalescer = Coalescer::new(
+            parse_coalesce_rules("/prod/api/search=50, /prod/api/quote=20:any").unwrap(),
+        );
+        assert!(coalescer
+            .window_for(&Method::HEAD, "/prod/api/search")
+            .is_some());
+        assert_eq!(
+            coalescer.window_for(&Method::POST, "/prod/api/search"),
+            None
+        );
+        assert_eq!(
+            coalescer.window_for(&Method::GET, "/prod/api/searches"),
+            None
+        );
+        assert_eq!(
+            coalescer.window_for(&Method::POST, "/prod/api/quote/rooms"),
+            Some(Duration::from_millis(20))
+        );
+        assert!(parse_coalesce_rules("/prod/api/search=50:all").is_err());
+    }
+}
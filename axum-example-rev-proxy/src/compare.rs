@@ -0,0 +1,220 @@
+//! Environment parity checks: the same request is sent to two upstreams
+//! (usually `test` and `prod`), JSON bodies are normalized with
+//! [`sort_json`], and every difference is reported by JSON pointer.
+
+use std::collections::BTreeMap;
+
+use axum::body::Bytes;
+use axum::extract::State;
+use axum::Json;
+use hyper::{HeaderMap, Method, StatusCode};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::app_state::AppState;
+use crate::error::ProxyError;
+use crate::interceptor::UpstreamResponse;
+use crate::proxy::send_to_upstream;
+use crate::sort_json::sort_json;
+
+/// Body of `POST /admin/compare`.
+#[derive(Debug, Deserialize)]
+pub struct CompareRequest {
+    #[serde(default = "default_method")]
+    pub method: String,
+    /// Path and query below the env prefix, e.g. `/api/search?city=goa`.
+    pub path: String,
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    #[serde(default)]
+    pub body: Option<String>,
+    #[serde(default = "default_envs")]
+    pub envs: (String, String),
+    /// JSON pointers left out of the comparison, e.g. timestamps or IDs.
+    #[serde(default)]
+    pub ignore: Vec<String>,
+}
+
+fn default_method() -> String {
+    "GET".to_string()
+}
+
+fn default_envs() -> (String, String) {
+    ("test".to_string(), "prod".to_string())
+}
+
+/// One difference between the two responses. A side is `None` when the
+/// value is missing there.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Mismatch {
+    pub pointer: String,
+    pub left: Option<Value>,
+    pub right: Option<Value>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Side {
+    pub env: String,
+    pub status: Option<u16>,
+    /// Why the upstream call failed, as a [`ProxyError`] code.
+    pub error: Option<&'static str>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CompareReport {
+    pub matched: bool,
+    pub left: Side,
+    pub right: Side,
+    pub mismatches: Vec<Mismatch>,
+}
+
+/// Differences between two JSON documents, ignoring object key order.
+/// Arrays are compared element by element.
+pub fn diff_json(left: &Value, right: &Value, ignore: &[String]) -> Vec<Mismatch> {
+    let mut mismatches = Vec::new();
+    diff_at(
+        String::new(),
+        Some(&sort_json(left)),
+        Some(&sort_json(right)),
+        ignore,
+        &mut mismatches,
+    );
+    mismatches
+}
+
+fn diff_at(
+    pointer: String,
+    left: Option<&Value>,
+    right: Option<&Value>,
+    ignore: &[String],
+    out: &mut Vec<Mismatch>,
+) {
+    if ignore.contains(&pointer) {
+        return;
+    }
+    match (left, right) {
+        (Some(Value::Object(l)), Some(Value::Object(r))) => {
+            let mut keys: Vec<&String> = l.keys().chain(r.keys()).collect();
+            keys.sort();
+            keys.dedup();
+            for key in keys {
+                let escaped = key.replace('~', "~0").replace('/', "~1");
+                diff_at(
+                    format!("{pointer}/{escaped}"),
+                    l.get(key),
+                    r.get(key),
+                    ignore,
+                    out,
+                );
+            }
+        }
+        (Some(Value::Array(l)), Some(Value::Array(r))) => {
+            for index in 0..l.len().max(r.len()) {
+                diff_at(
+                    format!("{pointer}/{index}"),
+                    l.get(index),
+                    r.get(index),
+                    ignore,
+                    out,
+                );
+            }
+        }
+        (l, r) if l != r => out.push(Mismatch {
+            pointer,
+            left: l.cloned(),
+            right: r.cloned(),
+        }),
+        _ => {}
+    }
+}
+
+/// Compares bodies as JSON when both parse, byte for byte otherwise.
+fn diff_bodies(left: &Bytes, right: &Bytes, ignore: &[String]) -> Vec<Mismatch> {
+    let parsed = (
+        serde_json::from_slice::<Value>(left),
+        serde_json::from_slice::<Value>(right),
+    );
+    match parsed {
+        (Ok(l), Ok(r)) => diff_json(&l, &r, ignore),
+        _ if left == right => Vec::new(),
+        _ => vec![Mismatch {
+            pointer: String::new(),
+            left: Some(Value::String(String::from_utf8_lossy(left).into_owned())),
+            right: Some(Value::String(String::from_utf8_lossy(right).into_owned())),
+        }],
+    }
+}
+
+/// `POST /admin/compare`: sends the request to both envs and diffs the answers.
+pub async fn compare_handler(
+    State(state): State<AppState>,
+    Json(request): Json<CompareRequest>,
+) -> Result<Json<CompareReport>, StatusCode> {
+    let method =
+        Method::from_bytes(request.method.as_bytes()).map_err(|_| StatusCode::BAD_REQUEST)?;
+    let mut headers = HeaderMap::new();
+    for (name, value) in &request.headers {
+        let name = name
+            .parse::<hyper::header::HeaderName>()
+            .map_err(|_| StatusCode::BAD_REQUEST)?;
+        headers.insert(name, value.parse().map_err(|_| StatusCode::BAD_REQUEST)?);
+    }
+    let body = Bytes::from(request.body.clone().unwrap_or_default());
+
+    let (left_env, right_env) = &request.envs;
+    let (left, right) = tokio::join!(
+        send_to_upstream(
+            &state,
+            left_env,
+            method.clone(),
+            &request.path,
+            headers.clone(),
+            body.clone(),
+        ),
+        send_to_upstream(&state, right_env, method, &request.path, headers, body),
+    );
+
+    let side = |env: &str, result: &Result<UpstreamResponse, ProxyError>| Side {
+        env: env.to_string(),
+        status: result
+            .as_ref()
+            .ok()
+            .map(|response| response.status.as_u16()),
+        error: result.as_ref().err().map(ProxyError::code),
+    };
+    let (left_side, right_side) = (side(left_env, &left), side(right_env, &right));
+    let mismatches = match (&left, &right) {
+        (Ok(l), Ok(r)) => diff_bodies(&l.body, &r.body, &request.ignore),
+        _ => Vec::new(),
+    };
+    let matched = left.is_ok()
+        && right.is_ok()
+        && left_side.status == right_side.status
+        && mismatches.is_empty();
+    Ok(Json(CompareReport {
+        matched,
+        left: left_side,
+        right: right_side,
+        mismatches,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn reports_differences_by_pointer() {
+        let left = json!({"b": 1, "a": {"x": [1, 2], "t": 5}, "only_left": true});
+        let right = json!({"a": {"t": 6, "x": [1, 3, 4]}, "b": 1});
+
+        let mismatches = diff_json(&left, &right, &["/a/t".to_string()]);
+        let pointers: Vec<&str> = mismatches.iter().map(|m| m.pointer.as_str()).collect();
+        assert_eq!(pointers, ["/a/x/1", "/a/x/2", "/only_left"]);
+        assert_eq!(mismatches[1].left, None);
+        assert_eq!(mismatches[1].right, Some(json!(4)));
+
+        assert!(diff_json(&json!({"a": 1, "b": 2}), &json!({"b": 2, "a": 1}), &[]).is_empty());
+    }
+}
@@ -0,0 +1,196 @@
+//! Conditional request handling (`ETag`/`Last-Modified` validators).
+
+use std::time::SystemTime;
+
+use axum::body::Body;
+use axum::response::Response;
+use hyper::header::{self, HeaderMap, HeaderName, HeaderValue};
+use hyper::StatusCode;
+
+use crate::interceptor::UpstreamResponse;
+
+/// Headers a `304 Not Modified` carries over from the stored response.
+const NOT_MODIFIED_HEADERS: [HeaderName; 6] = [
+    header::ETAG,
+    header::LAST_MODIFIED,
+    header::CACHE_CONTROL,
+    header::EXPIRES,
+    header::VARY,
+    header::DATE,
+];
+
+/// Headers that describe the stored body and must not be taken from a 304.
+const CONTENT_HEADERS: [HeaderName; 4] = [
+    header::CONTENT_LENGTH,
+    header::CONTENT_TYPE,
+    header::CONTENT_ENCODING,
+    header::TRANSFER_ENCODING,
+];
+
+pub fn has_validators(response: &UpstreamResponse) -> bool {
+    response.headers.contains_key(header::ETAG)
+        || response.headers.contains_key(header::LAST_MODIFIED)
+}
+
+/// Whether the caller sent its own validators.
+pub fn has_conditional_headers(headers: &HeaderMap) -> bool {
+    headers.contains_key(header::IF_NONE_MATCH) || headers.contains_key(header::IF_MODIFIED_SINCE)
+}
+
+/// Adds `If-None-Match`/`If-Modified-Since` built from `cached`'s validators.
+/// Returns whether anything was added.
+pub fn add_validators(headers: &mut HeaderMap, cached: &UpstreamResponse) -> bool {
+    let mut added = false;
+    if let Some(etag) = cached.headers.get(header::ETAG) {
+        headers.insert(header::IF_NONE_MATCH, etag.clone());
+        added = true;
+    }
+    if let Some(last_modified) = cached.headers.get(header::LAST_MODIFIED) {
+        headers.insert(header::IF_MODIFIED_SINCE, last_modified.clone());
+        added = true;
+    }
+    added
+}
+
+/// Evaluates the caller's conditional headers against `cached`, per RFC 9110
+/// §13.2.2: `If-None-Match` (weak comparison) takes precedence over
+/// `If-Modified-Since`.
+pub fn client_has_current_copy(request_headers: &HeaderMap, cached: &UpstreamResponse) -> bool {
+    if let Some(if_none_match) = request_headers.get(header::IF_NONE_MATCH) {
+        let Some(etag) = cached.headers.get(header::ETAG) else {
+            return false;
+        };
+        let Ok(if_none_match) = if_none_match.to_str() else {
+            return false;
+        };
+        let etag = opaque_tag(etag.to_str().unwrap_or_default());
+        return if_none_match
+            .split(',')
+            .map(str::trim)
+            .any(|candidate| candidate == "*" || opaque_tag(candidate) == etag);
+    }
+
+    let if_modified_since = request_headers
+        .get(header::IF_MODIFIED_SINCE)
+        .and_then(parse_date);
+    let last_modified = cached
+        .headers
+        .get(header::LAST_MODIFIED)
+        .and_then(parse_date);
+    match (if_modified_since, last_modified) {
+        (Some(since), Some(modified)) => modified <= since,
+        _ => false,
+    }
+}
+
+/// Applies a `304`'s headers to the stored response (RFC 9111 §4.3.4).
+pub fn merge_not_modified(cached: &UpstreamResponse, not_modified: &HeaderMap) -> UpstreamResponse {
+    let mut merged = cached.clone();
+    for name in not_modified.keys() {
+        if CONTENT_HEADERS.contains(name) {
+            continue;
+        }
+        merged.headers.remove(name);
+        for value in not_modified.get_all(name) {
+            merged.headers.append(name.clone(), value.clone());
+        }
+    }
+    merged
+}
+
+/// A bodiless `304` for a caller whose copy matches `cached`.
+pub fn not_modified_response(cached: &UpstreamResponse) -> Response {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = StatusCode::NOT_MODIFIED;
+    for name in NOT_MODIFIED_HEADERS {
+        for value in cached.headers.get_all(&name) {
+            response.headers_mut().append(name.clone(), value.clone());
+        }
+    }
+    response
+}
+
+fn opaque_tag(tag: &str) -> &str {
+    tag.trim().trim_start_matches("W/")
+}
+
+fn parse_date(value: &HeaderValue) -> Option<SystemTime> {
+    httpdate::parse_http_date(value.to_str().ok()?).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Bytes;
+
+    fn cached(headers: &[(HeaderName, &str)]) -> UpstreamResponse {
+        let mut map = HeaderMap::new();
+        for (name, value) in headers {
+            map.insert(name.clone(), value.parse().unwrap());
+        }
+        UpstreamResponse {
+            status: StatusCode::OK,
+            headers: map,
+            body: Bytes::from_static(b"x"),
+            trailers: None,
+        }
+    }
+
+    fn request(headers: &[(HeaderName, &str)]) -> HeaderMap {
+        cached(headers).headers
+    }
+
+    #[test]
+    fn if_none_match_uses_weak_comparison_and_wins_over_date() {
+        let cached = cached(&[
+            (header::ETAG, "W/\"v2\""),
+            (header::LAST_MODIFIED, "Sun, 06 Nov 1994 08:49:37 GMT"),
+        ]);
+
+        assert!(client_has_current_copy(
+            &request(&[(header::IF_NONE_MATCH, "\"v1\", \"v2\"")]),
+            &cached
+        ));
+        assert!(!client_has_current_copy(
+            &request(&[
+                (header::IF_NONE_MATCH, "\"v1\""),
+                (header::IF_MODIFIED_SINCE, "Mon, 07 Nov 1994 08:49:37 GMT"),
+            ]),
+            &cached
+        ));
+    }
+
+    #[test]
+    fn if_modified_since_compares_dates() {
+        let cached = cached(&[(header::LAST_MODIFIED, "Sun, 06 Nov 1994 08:49:37 GMT")]);
+
+        assert!(client_has_current_copy(
+            &request(&[(header::IF_MODIFIED_SINCE, "Sun, 06 Nov 1994 08:49:37 GMT")]),
+            &cached
+        ));
+        assert!(!client_has_current_copy(
+            &request(&[(header::IF_MODIFIED_SINCE, "Sat, 05 Nov 1994 08:49:37 GMT")]),
+            &cached
+        ));
+    }
+
+    #[test]
+    fn merge_keeps_body_headers_and_takes_new_validators() {
+        let cached = cached(&[
+            (header::ETAG, "\"v1\""),
+            (header::CONTENT_TYPE, "application/json"),
+        ]);
+        let not_modified = request(&[
+            (header::ETAG, "\"v1\""),
+            (header::CACHE_CONTROL, "max-age=60"),
+            (header::CONTENT_LENGTH, "0"),
+        ]);
+
+        let merged = merge_not_modified(&cached, &not_modified);
+
+        assert_eq!(merged.headers[header::CACHE_CONTROL], "max-age=60");
+        assert_eq!(merged.headers[header::CONTENT_TYPE], "application/json");
+        assert!(merged.headers.get(header::CONTENT_LENGTH).is_none());
+        assert_eq!(merged.body, cached.body);
+    }
+}
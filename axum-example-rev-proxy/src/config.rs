@@ -0,0 +1,522 @@
+//! Typed, validated settings for the listener, upstreams, security,
+//! metrics and webhooks. In the config file (see [`crate::profile`]) each
+//! section is a table of its own:
+//!
+//! ```toml
+//! [base.listeners]
+//! addr = "[::]:8080"
+//! idle_timeout_secs = 60
+//!
+//! [profiles.prod.upstreams]
+//! prod_nodes = ["https://a.example", "https://b.example"]
+//! ```
+//!
+//! Unknown keys are errors. Every key can also be set by the setting it
+//! replaces, e.g. `LISTEN_ADDR` for `listeners.addr`, in the same table or
+//! the environment; later tables and then the environment win as usual.
+//! Errors name the key, its setting and where the bad value came from.
+
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::time::Duration;
+
+use figment::value::{Dict, Map, Value};
+use figment::{Figment, Metadata, Profile, Provider};
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer, Serialize};
+
+use crate::canonical::Canonicalization;
+use crate::nowpayments_ipn_webhook::IpnSecret;
+use crate::profile::{self, Layer, Source};
+use crate::server::{ServerConfig, DEFAULT_BACKLOG, DEFAULT_LISTEN_ADDR};
+
+/// Config file tables that hold typed sections.
+pub const SECTIONS: [&str; 5] = ["listeners", "upstreams", "security", "metrics", "webhooks"];
+
+/// Each typed key and the setting that can set it instead.
+const SETTINGS: [(&str, &str); 29] = [
+    ("listeners.addr", "LISTEN_ADDR"),
+    ("listeners.keep_alive", "HTTP_KEEP_ALIVE"),
+    ("listeners.idle_timeout_secs", "HTTP_IDLE_TIMEOUT_SECS"),
+    (
+        "listeners.header_read_timeout_secs",
+        "HTTP_HEADER_READ_TIMEOUT_SECS",
+    ),
+    ("listeners.nodelay", "TCP_NODELAY"),
+    ("listeners.reuse_port", "TCP_REUSEPORT"),
+    ("listeners.backlog", "TCP_BACKLOG"),
+    ("listeners.read_timeout_secs", "TCP_READ_TIMEOUT_SECS"),
+    ("listeners.write_timeout_secs", "TCP_WRITE_TIMEOUT_SECS"),
+    ("listeners.workers", "SERVER_WORKERS"),
+    ("listeners.drain_timeout_secs", "SHUTDOWN_DRAIN_SECS"),
+    ("upstreams.test_url", "TEST_UPSTREAM_URL"),
+    ("upstreams.prod_url", "PROD_UPSTREAM_URL"),
+    ("upstreams.test_nodes", "TEST_UPSTREAM_NODES"),
+    ("upstreams.prod_nodes", "PROD_UPSTREAM_NODES"),
+    (
+        "upstreams.test_secondary_url",
+        "TEST_UPSTREAM_SECONDARY_URL",
+    ),
+    (
+        "upstreams.prod_secondary_url",
+        "PROD_UPSTREAM_SECONDARY_URL",
+    ),
+    ("upstreams.sticky_session_header", "STICKY_SESSION_HEADER"),
+    (
+        "upstreams.failover_failure_threshold",
+        "FAILOVER_FAILURE_THRESHOLD",
+    ),
+    (
+        "upstreams.failover_retry_primary_secs",
+        "FAILOVER_RETRY_PRIMARY_SECS",
+    ),
+    ("security.admin_token", "PROXY_ADMIN_TOKEN"),
+    ("security.admin_tokens", "ADMIN_TOKENS"),
+    (
+        "security.nowpayments_allowed_ips",
+        "NOWPAYMENTS_ALLOWED_IPS",
+    ),
+    ("metrics.snapshot_path", "METRICS_SNAPSHOT_PATH"),
+    ("webhooks.ipn_secret", "NOWPAYMENTS_IPN_SECRET"),
+    ("webhooks.ipn_secrets", "NOWPAYMENTS_IPN_SECRETS"),
+    ("webhooks.replay_ttl_secs", "WEBHOOK_REPLAY_TTL_SECS"),
+    ("webhooks.canonicalization", "NOWPAYMENTS_CANONICALIZATION"),
+    ("webhooks.test_mode", "NOWPAYMENTS_TEST_MODE"),
+];
+
+// Default NOWPayments IPN source addresses.
+const DEFAULT_NOWPAYMENTS_ALLOWED_IPS: [&str; 4] = [
+    "51.89.194.21",
+    "51.75.77.69",
+    "138.201.172.58",
+    "65.21.158.36",
+];
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProxyConfig {
+    pub listeners: ListenerSettings,
+    pub upstreams: UpstreamSettings,
+    pub security: SecuritySettings,
+    pub metrics: MetricsSettings,
+    pub webhooks: WebhookSettings,
+}
+
+/// The main listener; see [`ServerConfig`]. Timeouts of 0 are off.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ListenerSettings {
+    pub addr: SocketAddr,
+    pub keep_alive: bool,
+    pub idle_timeout_secs: u64,
+    pub header_read_timeout_secs: u64,
+    pub nodelay: bool,
+    pub reuse_port: bool,
+    pub backlog: u32,
+    pub read_timeout_secs: u64,
+    pub write_timeout_secs: u64,
+    pub workers: usize,
+    pub drain_timeout_secs: u64,
+}
+
+impl Default for ListenerSettings {
+    fn default() -> Self {
+        Self {
+            addr: DEFAULT_LISTEN_ADDR.parse().unwrap(),
+            keep_alive: true,
+            idle_timeout_secs: 75,
+            header_read_timeout_secs: 30,
+            nodelay: true,
+            reuse_port: false,
+            backlog: DEFAULT_BACKLOG,
+            read_timeout_secs: 0,
+            write_timeout_secs: 0,
+            workers: 1,
+            drain_timeout_secs: 30,
+        }
+    }
+}
+
+impl ListenerSettings {
+    pub fn server_config(&self) -> ServerConfig {
+        let timeout = |secs| (secs > 0).then(|| Duration::from_secs(secs));
+        ServerConfig {
+            keep_alive: self.keep_alive,
+            idle_timeout: timeout(self.idle_timeout_secs),
+            header_read_timeout: timeout(self.header_read_timeout_secs),
+            nodelay: self.nodelay,
+            reuse_port: self.reuse_port,
+            backlog: self.backlog,
+            read_timeout: timeout(self.read_timeout_secs),
+            write_timeout: timeout(self.write_timeout_secs),
+            workers: self.workers,
+            drain_timeout: Duration::from_secs(self.drain_timeout_secs),
+            listen_addr: self.addr,
+        }
+    }
+}
+
+/// The `test` and `prod` suppliers and their failover.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UpstreamSettings {
+    pub test_url: String,
+    pub prod_url: String,
+    #[serde(deserialize_with = "comma_list")]
+    pub test_nodes: Vec<String>,
+    #[serde(deserialize_with = "comma_list")]
+    pub prod_nodes: Vec<String>,
+    pub test_secondary_url: Option<String>,
+    pub prod_secondary_url: Option<String>,
+    pub sticky_session_header: Option<String>,
+    pub failover_failure_threshold: u32,
+    pub failover_retry_primary_secs: u64,
+}
+
+impl Default for UpstreamSettings {
+    fn default() -> Self {
+        Self {
+            test_url: "http://test.services.travelomatix.com".to_string(),
+            prod_url: "https://prod.services.travelomatix.com".to_string(),
+            test_nodes: Vec::new(),
+            prod_nodes: Vec::new(),
+            test_secondary_url: None,
+            prod_secondary_url: None,
+            sticky_session_header: None,
+            failover_failure_threshold: 3,
+            failover_retry_primary_secs: 30,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SecuritySettings {
+    pub admin_token: Option<String>,
+    /// `name:role:token` entries; see [`crate::rbac`].
+    #[serde(deserialize_with = "comma_list")]
+    pub admin_tokens: Vec<String>,
+    #[serde(deserialize_with = "comma_list")]
+    pub nowpayments_allowed_ips: Vec<IpAddr>,
+}
+
+impl Default for SecuritySettings {
+    fn default() -> Self {
+        Self {
+            admin_token: None,
+            admin_tokens: Vec::new(),
+            nowpayments_allowed_ips: DEFAULT_NOWPAYMENTS_ALLOWED_IPS
+                .iter()
+                .map(|ip| ip.parse().unwrap())
+                .collect(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsSettings {
+    pub snapshot_path: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebhookSettings {
+    // todo add secret when available in gh actions
+    pub ipn_secret: String,
+    /// Further secrets accepted while `ipn_secret` is rotated.
+    #[serde(deserialize_with = "json_list")]
+    pub ipn_secrets: Vec<IpnSecret>,
+    /// NOWPayments retries failed deliveries for about a day.
+    pub replay_ttl_secs: u64,
+    pub canonicalization: Canonicalization,
+    /// Serves `POST /admin/simulate-webhook`; for staging, never production.
+    pub test_mode: bool,
+}
+
+impl Default for WebhookSettings {
+    fn default() -> Self {
+        Self {
+            ipn_secret: "dummy-secret-for-now".to_string(),
+            ipn_secrets: Vec::new(),
+            replay_ttl_secs: 86400,
+            canonicalization: Canonicalization::default(),
+            test_mode: false,
+        }
+    }
+}
+
+/// A list written as one comma-separated string or as an array.
+fn comma_list<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: std::fmt::Display,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Items {
+        Joined(String),
+        Each(Vec<String>),
+    }
+    let items = match Items::deserialize(deserializer)? {
+        Items::Joined(joined) => joined.split(',').map(str::to_string).collect(),
+        Items::Each(items) => items,
+    };
+    items
+        .iter()
+        .map(|item| item.trim())
+        .filter(|item| !item.is_empty())
+        .map(|item| {
+            item.parse()
+                .map_err(|e| D::Error::custom(format!("invalid item {item:?}: {e}")))
+        })
+        .collect()
+}
+
+/// A list written as a JSON array in a string or as an array.
+fn json_list<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: serde::de::DeserializeOwned,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Items<T> {
+        Json(String),
+        Each(Vec<T>),
+    }
+    match Items::deserialize(deserializer)? {
+        Items::Json(json) if json.trim().is_empty() => Ok(Vec::new()),
+        Items::Json(json) => serde_json::from_str(&json).map_err(D::Error::custom),
+        Items::Each(items) => Ok(items),
+    }
+}
+
+impl ProxyConfig {
+    /// Reads the config file's tables for the selected profile and the
+    /// environment, noting where each value came from for
+    /// [`profile::effective`].
+    pub fn load() -> Result<Self, String> {
+        let layers = profile::file_layers().layers();
+        let figment = figment(layers, |name| std::env::var(name).ok());
+        let config = extract(&figment)?;
+
+        let values = serde_json::to_value(&config).map_err(|e| e.to_string())?;
+        for (path, name) in SETTINGS {
+            let source = figment
+                .find_metadata(path)
+                .and_then(|metadata| {
+                    layers
+                        .iter()
+                        .map(|layer| &layer.source)
+                        .chain([&Source::Env])
+                        .find(|source| source.to_string() == metadata.name)
+                })
+                .cloned()
+                .unwrap_or(Source::Default);
+            let value = path
+                .split('.')
+                .try_fold(&values, |value, key| value.get(key))
+                .map(display)
+                .unwrap_or_default();
+            profile::record(name, &value, source);
+        }
+        Ok(config)
+    }
+
+    /// Reads `layers` of a config file, then settings `env` gives.
+    pub fn from_layers(
+        layers: &[Layer],
+        env: impl Fn(&str) -> Option<String>,
+    ) -> Result<Self, String> {
+        extract(&figment(layers, env))
+    }
+}
+
+fn figment(layers: &[Layer], env: impl Fn(&str) -> Option<String>) -> Figment {
+    let mut figment = Figment::new();
+    for layer in layers {
+        let mut dict = match Value::serialize(&layer.sections) {
+            Ok(Value::Dict(_, dict)) => dict,
+            _ => Dict::new(),
+        };
+        for (path, name) in SETTINGS {
+            if let Some(value) = layer.settings.get(name) {
+                insert(&mut dict, path, Value::from(value.clone()));
+            }
+        }
+        figment = figment.merge(Named {
+            source: layer.source.clone(),
+            dict,
+        });
+    }
+    let mut dict = Dict::new();
+    for (path, name) in SETTINGS {
+        if let Some(value) = env(name) {
+            insert(&mut dict, path, Value::from(value));
+        }
+    }
+    figment.merge(Named {
+        source: Source::Env,
+        dict,
+    })
+}
+
+/// Settings from one place, named after it in errors.
+struct Named {
+    source: Source,
+    dict: Dict,
+}
+
+impl Provider for Named {
+    fn metadata(&self) -> Metadata {
+        Metadata::named(self.source.to_string())
+    }
+
+    fn data(&self) -> Result<Map<Profile, Dict>, figment::Error> {
+        Ok(Profile::Default.collect(self.dict.clone()))
+    }
+}
+
+fn insert(dict: &mut Dict, path: &str, value: Value) {
+    match path.split_once('.') {
+        Some((section, key)) => {
+            let section = dict
+                .entry(section.to_string())
+                .or_insert_with(|| Dict::new().into());
+            if let Value::Dict(_, section) = section {
+                insert(section, key, value);
+            }
+        }
+        None => {
+            dict.insert(path.to_string(), value);
+        }
+    }
+}
+
+/// Strings stand in for numbers and booleans, as environment variables
+/// have to.
+fn extract(figment: &Figment) -> Result<ProxyConfig, String> {
+    figment.extract_lossy().map_err(|error| {
+        error
+            .into_iter()
+            .map(|error| {
+                let key = error.path.join(".");
+                let setting = SETTINGS
+                    .iter()
+                    .find(|(path, _)| *path == key)
+                    .map(|(_, name)| format!(" ({name})"))
+                    .unwrap_or_default();
+                let source = error
+                    .metadata
+                    .as_ref()
+                    .map(|metadata| format!(" from {}", metadata.name))
+                    .unwrap_or_default();
+                format!("{key}{setting}{source}: {}", error.kind)
+            })
+            .collect::<Vec<_>>()
+            .join("; ")
+    })
+}
+
+fn display(value: &serde_json::Value) -> String {
+    match value {
+        serde_json::Value::Null => String::new(),
+        serde_json::Value::String(s) => s.clone(),
+        serde_json::Value::Array(items) => items.iter().map(display).collect::<Vec<_>>().join(","),
+        other => other.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+    use crate::profile::Layers;
+
+    fn load(file: &str, env: &[(&str, &str)]) -> Result<ProxyConfig, String> {
+        let env: BTreeMap<_, _> = env.iter().copied().collect();
+        let layers = Layers::parse(file, Some("prod"), |_| None).unwrap();
+        ProxyConfig::from_layers(layers.layers(), |name| {
+            env.get(name).map(|value| value.to_string())
+        })
+    }
+
+    const FILE: &str = r#"
+        [base]
+        TCP_BACKLOG = 512
+        NOWPAYMENTS_ALLOWED_IPS = "10.0.0.1, 10.0.0.2"
+
+        [base.listeners]
+        addr = "127.0.0.1:8080"
+        workers = 2
+
+        [profiles.prod.listeners]
+        workers = 4
+
+        [profiles.prod.upstreams]
+        prod_nodes = ["https://a.example", "https://b.example"]
+        sticky_session_header = "x-session"
+    "#;
+
+    #[test]
+    fn merges_sections_settings_and_the_environment() {
+        let config = load(
+            FILE,
+            &[("SERVER_WORKERS", "8"), ("HTTP_KEEP_ALIVE", "false")],
+        )
+        .unwrap();
+        let listeners = &config.listeners;
+        assert_eq!(listeners.addr, "127.0.0.1:8080".parse().unwrap());
+        assert_eq!(listeners.backlog, 512);
+        assert_eq!(listeners.workers, 8);
+        assert!(!listeners.keep_alive);
+        assert_eq!(listeners.idle_timeout_secs, 75);
+        assert_eq!(
+            config.upstreams.prod_nodes,
+            ["https://a.example", "https://b.example"]
+        );
+        assert_eq!(
+            config.upstreams.sticky_session_header.as_deref(),
+            Some("x-session")
+        );
+        assert_eq!(config.security.nowpayments_allowed_ips.len(), 2);
+        assert_eq!(config.webhooks.canonicalization, Canonicalization::Sorted);
+
+        let config = load(FILE, &[]).unwrap();
+        assert_eq!(config.listeners.workers, 4);
+        assert_eq!(config.listeners.server_config().read_timeout, None);
+    }
+
+    #[test]
+    fn names_the_offending_key_and_source() {
+        let err = load(FILE, &[("TCP_BACKLOG", "lots")]).unwrap_err();
+        assert!(
+            err.starts_with("listeners.backlog (TCP_BACKLOG) from env:"),
+            "{err}"
+        );
+
+        let err = load("[profiles.prod.listeners]\nadr = \"[::]:80\"", &[]).unwrap_err();
+        assert!(err.contains("from profile prod"), "{err}");
+        assert!(err.contains("unknown field: found `adr`"), "{err}");
+
+        let err = load(
+            "[base]\nNOWPAYMENTS_ALLOWED_IPS = \"10.0.0\"\n[profiles.prod]",
+            &[],
+        )
+        .unwrap_err();
+        assert!(err.starts_with("security.nowpayments_allowed_ips"), "{err}");
+
+        let err = load(
+            "[base.webhooks]\ncanonicalization = \"xml\"\n[profiles.prod]",
+            &[],
+        )
+        .unwrap_err();
+        assert!(
+            err.starts_with("webhooks.canonicalization (NOWPAYMENTS_CANONICALIZATION) from base:"),
+            "{err}"
+        );
+    }
+}
@@ -0,0 +1,89 @@
+//! Caps simultaneous connections to each upstream host, so a burst queues
+//! here instead of opening hundreds of sockets and tripping a supplier's
+//! connection-based protections. The pool's idle settings only bound sockets
+//! kept open between requests, not how many are opened at once.
+//!
+//! Over HTTP/1.1 every in-flight request holds its own connection, so a slot
+//! is held from sending the request until its body has been read.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Hosts with their own slot counts; further hosts share one.
+const MAX_HOSTS: usize = 256;
+const OVERFLOW_HOST: &str = "other";
+
+pub struct ConnectionLimiter {
+    limit: usize,
+    hosts: Mutex<HashMap<String, Arc<Semaphore>>>,
+}
+
+impl ConnectionLimiter {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            limit: limit.max(1),
+            hosts: Mutex::default(),
+        }
+    }
+
+    fn semaphore(&self, host: &str) -> Arc<Semaphore> {
+        let mut hosts = self.hosts.lock().unwrap();
+        let host = if hosts.contains_key(host) || hosts.len() < MAX_HOSTS {
+            host
+        } else {
+            OVERFLOW_HOST
+        };
+        hosts
+            .entry(host.to_string())
+            .or_insert_with(|| Arc::new(Semaphore::new(self.limit)))
+            .clone()
+    }
+
+    /// Waits for a connection slot to `host`. Returns `true` alongside the
+    /// permit when all slots were taken and the caller had to wait.
+    pub async fn acquire(&self, host: &str) -> (OwnedSemaphorePermit, bool) {
+        let semaphore = self.semaphore(host);
+        if let Ok(permit) = semaphore.clone().try_acquire_owned() {
+            return (permit, false);
+        }
+        let permit = semaphore
+            .acquire_owned()
+            .await
+            .expect("connection semaphores are never closed");
+        (permit, true)
+    }
+
+    /// Connections currently open to `host` through the limiter.
+    pub fn in_use(&self, host: &str) -> usize {
+        let hosts = self.hosts.lock().unwrap();
+        hosts
+            .get(host)
+            .map_or(0, |semaphore| self.limit - semaphore.available_permits())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn limits_each_host_separately() {
+        let limiter = ConnectionLimiter::new(2);
+        let (first, waited) = limiter.acquire("a.example:443").await;
+        assert!(!waited);
+        let (_second, _) = limiter.acquire("a.example:443").await;
+        let (_other_host, waited) = limiter.acquire("b.example:443").await;
+        assert!(!waited);
+        assert_eq!(limiter.in_use("a.example:443"), 2);
+
+        let third = limiter.acquire("a.example:443");
+        tokio::pin!(third);
+        assert!(futures_util::poll!(&mut third).is_pending());
+        drop(first);
+        let (_third, waited) = third.await;
+        assert!(waited);
+        assert_eq!(limiter.in_use("a.example:443"), 2);
+    }
+}
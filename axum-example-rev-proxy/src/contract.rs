@@ -0,0 +1,536 @@
+//! Shadow contract testing: requests and responses are checked against the
+//! supplier's OpenAPI spec as they pass, and violations are logged and
+//! counted, never enforced. A rising `proxy_contract_violations_total` is
+//! usually the first sign a supplier changed their API without telling us.
+//!
+//! Specs are OpenAPI 3 documents in JSON. Only JSON bodies are checked, and
+//! schemas support the subset suppliers actually use: `type` (including
+//! `nullable` and 3.1 type lists), `enum`, `required`, `properties`,
+//! `additionalProperties`, `items`, `allOf`, `anyOf`/`oneOf` (both as "at
+//! least one") and local `$ref`s.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use hyper::{Method, Uri};
+use serde_json::Value;
+use tracing::warn;
+
+use crate::interceptor::{OutboundRequest, UpstreamResponse};
+use crate::payload_size::content_family;
+
+/// Schema errors reported per body; the rest are dropped.
+const MAX_ERRORS: usize = 5;
+
+/// Keys of a path item that are operations.
+const METHODS: [&str; 8] = [
+    "get", "put", "post", "delete", "options", "head", "patch", "trace",
+];
+
+/// Parses `OPENAPI_SPECS`: `env=path` pairs separated by commas.
+pub fn parse_openapi_specs(value: &str) -> Result<BTreeMap<String, String>, String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(|pair| {
+            let (env, path) = pair
+                .split_once('=')
+                .ok_or_else(|| format!("expected env=path, got {pair}"))?;
+            Ok((env.trim().to_string(), path.trim().to_string()))
+        })
+        .collect()
+}
+
+/// What part of an exchange broke the contract, used as the metric label.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ViolationKind {
+    /// No operation in the spec matches the method and path.
+    UnknownOperation,
+    RequestBody,
+    /// The spec lists no response for the status, and has no `default`.
+    ResponseStatus,
+    ResponseBody,
+}
+
+impl ViolationKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::UnknownOperation => "unknown_operation",
+            Self::RequestBody => "request_body",
+            Self::ResponseStatus => "response_status",
+            Self::ResponseBody => "response_body",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Violation {
+    pub kind: ViolationKind,
+    pub detail: String,
+}
+
+impl Violation {
+    fn new(kind: ViolationKind, detail: impl Into<String>) -> Self {
+        Self {
+            kind,
+            detail: detail.into(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Operation {
+    method: Method,
+    /// Path template segments; `None` for a `{parameter}`.
+    segments: Vec<Option<String>>,
+    template: String,
+    request_schema: Option<Value>,
+    /// Response schemas by status code, `4XX`-style range or `default`.
+    responses: BTreeMap<String, Option<Value>>,
+}
+
+/// One upstream's OpenAPI spec, ready to check traffic against.
+#[derive(Debug)]
+pub struct Contract {
+    /// Path of the spec's first server URL, which operation paths are
+    /// relative to.
+    base_path: String,
+    operations: Vec<Operation>,
+    /// The whole document, for resolving `$ref`s.
+    document: Value,
+}
+
+impl Contract {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, String> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("failed to read {}: {e}", path.display()))?;
+        let document =
+            serde_json::from_str(&text).map_err(|e| format!("invalid {}: {e}", path.display()))?;
+        Self::from_document(document)
+    }
+
+    pub fn from_document(document: Value) -> Result<Self, String> {
+        let paths = document
+            .get("paths")
+            .and_then(Value::as_object)
+            .ok_or("spec has no paths")?;
+        let mut operations = Vec::new();
+        for (template, item) in paths {
+            let Some(item) = item.as_object() else {
+                continue;
+            };
+            for (method, operation) in item {
+                // Skips path-level keys like `parameters`.
+                if !METHODS.contains(&method.as_str()) {
+                    continue;
+                }
+                let Ok(method) = method.to_ascii_uppercase().parse::<Method>() else {
+                    continue;
+                };
+                operations.push(Operation {
+                    method,
+                    segments: template
+                        .split('/')
+                        .filter(|s| !s.is_empty())
+                        .map(|s| (!(s.starts_with('{') && s.ends_with('}'))).then(|| s.to_string()))
+                        .collect(),
+                    template: template.clone(),
+                    request_schema: json_schema(operation.get("requestBody")),
+                    responses: operation
+                        .get("responses")
+                        .and_then(Value::as_object)
+                        .map(|responses| {
+                            responses
+                                .iter()
+                                .map(|(status, response)| {
+                                    (status.to_ascii_uppercase(), json_schema(Some(response)))
+                                })
+                                .collect()
+                        })
+                        .unwrap_or_default(),
+                });
+            }
+        }
+        let base_path = document
+            .pointer("/servers/0/url")
+            .and_then(Value::as_str)
+            .and_then(|url| url.parse::<Uri>().ok())
+            .map(|uri| uri.path().trim_end_matches('/').to_string())
+            .unwrap_or_default();
+        Ok(Self {
+            base_path,
+            operations,
+            document,
+        })
+    }
+
+    fn operation_for(&self, method: &Method, path: &str) -> Option<&Operation> {
+        let path = path.strip_prefix(&self.base_path).unwrap_or(path);
+        let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+        let matches = |operation: &&Operation| {
+            operation.method == method
+                && operation.segments.len() == segments.len()
+                && operation
+                    .segments
+                    .iter()
+                    .zip(&segments)
+                    .all(|(expected, actual)| expected.as_deref().is_none_or(|e| e == *actual))
+        };
+        // Literal segments beat parameters, as OpenAPI requires.
+        self.operations
+            .iter()
+            .filter(matches)
+            .max_by_key(|operation| operation.segments.iter().filter(|s| s.is_some()).count())
+    }
+
+    /// Checks one exchange as it went over the wire.
+    pub fn check(&self, req: &OutboundRequest, res: &UpstreamResponse) -> Vec<Violation> {
+        let Some(operation) = self.operation_for(&req.method, req.uri.path()) else {
+            return vec![Violation::new(
+                ViolationKind::UnknownOperation,
+                format!("{} {}", req.method, req.uri.path()),
+            )];
+        };
+        let mut violations = Vec::new();
+        if let Some(schema) = &operation.request_schema {
+            if content_family(&req.headers) == "json" {
+                if let Some(detail) = self.check_body(schema, &req.body) {
+                    violations.push(Violation::new(ViolationKind::RequestBody, detail));
+                }
+            }
+        }
+        let status = res.status.as_str();
+        let range = format!("{}XX", &status[..1]);
+        let response = [status, range.as_str(), "DEFAULT"]
+            .into_iter()
+            .find_map(|key| operation.responses.get(key));
+        match response {
+            None => violations.push(Violation::new(
+                ViolationKind::ResponseStatus,
+                format!("{} is not documented for {}", status, operation.template),
+            )),
+            Some(Some(schema)) if content_family(&res.headers) == "json" => {
+                if let Some(detail) = self.check_body(schema, &res.body) {
+                    violations.push(Violation::new(ViolationKind::ResponseBody, detail));
+                }
+            }
+            Some(_) => {}
+        }
+        violations
+    }
+
+    fn check_body(&self, schema: &Value, body: &[u8]) -> Option<String> {
+        let value = match serde_json::from_slice(body) {
+            Ok(value) => value,
+            Err(e) => return Some(format!("body is not JSON: {e}")),
+        };
+        let mut errors = Vec::new();
+        self.validate(schema, &value, "$", &mut errors);
+        (!errors.is_empty()).then(|| {
+            errors.truncate(MAX_ERRORS);
+            errors.join("; ")
+        })
+    }
+
+    fn validate(&self, schema: &Value, value: &Value, at: &str, errors: &mut Vec<String>) {
+        if errors.len() >= MAX_ERRORS {
+            return;
+        }
+        if let Some(reference) = schema.get("$ref").and_then(Value::as_str) {
+            match reference
+                .strip_prefix('#')
+                .and_then(|pointer| self.document.pointer(pointer))
+            {
+                Some(target) => self.validate(target, value, at, errors),
+                None => errors.push(format!("{at}: unresolved $ref {reference}")),
+            }
+            return;
+        }
+        if let Some(schemas) = schema.get("allOf").and_then(Value::as_array) {
+            for schema in schemas {
+                self.validate(schema, value, at, errors);
+            }
+        }
+        for key in ["anyOf", "oneOf"] {
+            if let Some(schemas) = schema.get(key).and_then(Value::as_array) {
+                let matched = schemas.iter().any(|schema| {
+                    let mut errors = Vec::new();
+                    self.validate(schema, value, at, &mut errors);
+                    errors.is_empty()
+                });
+                if !matched {
+                    errors.push(format!("{at}: matches none of {key}"));
+                }
+            }
+        }
+        if !type_matches(schema, value) {
+            errors.push(format!("{at}: expected {}", schema["type"]));
+            return;
+        }
+        if value.is_null() && schema.get("nullable") == Some(&Value::Bool(true)) {
+            return;
+        }
+        if let Some(allowed) = schema.get("enum").and_then(Value::as_array) {
+            if !allowed.contains(value) {
+                errors.push(format!("{at}: {value} is not one of {}", schema["enum"]));
+            }
+        }
+        match value {
+            Value::Object(object) => {
+                for name in schema
+                    .get("required")
+                    .and_then(Value::as_array)
+                    .into_iter()
+                    .flatten()
+                    .filter_map(Value::as_str)
+                {
+                    if !object.contains_key(name) {
+                        errors.push(format!("{at}: missing {name}"));
+                    }
+                }
+                let properties = schema.get("properties").and_then(Value::as_object);
+                for (name, field) in object {
+                    let at = format!("{at}.{name}");
+                    match (
+                        properties.and_then(|p| p.get(name)),
+                        schema.get("additionalProperties"),
+                    ) {
+                        (Some(property), _) => self.validate(property, field, &at, errors),
+                        (None, Some(Value::Bool(false))) => {
+                            errors.push(format!("{at}: unexpected property"))
+                        }
+                        (None, Some(additional)) if additional.is_object() => {
+                            self.validate(additional, field, &at, errors)
+                        }
+                        (None, _) => {}
+                    }
+                }
+            }
+            Value::Array(items) => {
+                if let Some(schema) = schema.get("items") {
+                    for (i, item) in items.iter().enumerate() {
+                        self.validate(schema, item, &format!("{at}[{i}]"), errors);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// The JSON schema of a request body or response object, if it has one.
+fn json_schema(object: Option<&Value>) -> Option<Value> {
+    let content = object?.get("content")?.as_object()?;
+    content
+        .iter()
+        .find(|(media_type, _)| media_type.contains("json"))
+        .and_then(|(_, media)| media.get("schema"))
+        .cloned()
+}
+
+fn type_matches(schema: &Value, value: &Value) -> bool {
+    if value.is_null() && schema.get("nullable") == Some(&Value::Bool(true)) {
+        return true;
+    }
+    let matches = |name: &str| match name {
+        "object" => value.is_object(),
+        "array" => value.is_array(),
+        "string" => value.is_string(),
+        "integer" => value.is_i64() || value.is_u64(),
+        "number" => value.is_number(),
+        "boolean" => value.is_boolean(),
+        "null" => value.is_null(),
+        _ => true,
+    };
+    match schema.get("type") {
+        Some(Value::String(name)) => matches(name),
+        Some(Value::Array(names)) => names.iter().filter_map(Value::as_str).any(matches),
+        _ => true,
+    }
+}
+
+/// Checks an exchange against `env`'s contract, if it has one, logging and
+/// counting what doesn't match.
+pub fn shadow_check(
+    contracts: &BTreeMap<String, Contract>,
+    metrics: &crate::metrics::Metrics,
+    req: &OutboundRequest,
+    res: &UpstreamResponse,
+) {
+    let Some(contract) = contracts.get(&req.env) else {
+        return;
+    };
+    for violation in contract.check(req, res) {
+        warn!(
+            "Contract violation ({}) on {} {}: {}",
+            violation.kind.as_str(),
+            req.method,
+            req.uri.path(),
+            violation.detail
+        );
+        metrics.record_contract_violation(&req.env, violation.kind);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::header::{self, HeaderMap, HeaderValue};
+    use hyper::StatusCode;
+    use serde_json::json;
+
+    use super::*;
+
+    fn contract() -> Contract {
+        Contract::from_document(json!({
+            "openapi": "3.0.3",
+            "servers": [{"url": "https://api.example.com/v2/"}],
+            "paths": {
+                "/hotels/{id}": {
+                    "get": {
+                        "responses": {
+                            "200": {"content": {"application/json": {"schema": {"$ref": "#/components/schemas/Hotel"}}}},
+                            "4XX": {"description": "client error"}
+                        }
+                    }
+                },
+                "/hotels/search": {
+                    "post": {
+                        "requestBody": {"content": {"application/json": {"schema": {
+                            "type": "object",
+                            "required": ["city"],
+                            "properties": {"city": {"type": "string"}, "rooms": {"type": "integer"}},
+                            "additionalProperties": false
+                        }}}},
+                        "responses": {"default": {"description": "anything"}}
+                    }
+                }
+            },
+            "components": {"schemas": {"Hotel": {
+                "type": "object",
+                "required": ["id", "name"],
+                "properties": {
+                    "id": {"type": "integer"},
+                    "name": {"type": "string"},
+                    "stars": {"type": "integer", "nullable": true, "enum": [1, 2, 3, 4, 5]}
+                }
+            }}}
+        }))
+        .unwrap()
+    }
+
+    fn json_headers() -> HeaderMap {
+        HeaderMap::from_iter([(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("application/json"),
+        )])
+    }
+
+    fn exchange(
+        method: Method,
+        path: &str,
+        request: Value,
+        status: StatusCode,
+        response: Value,
+    ) -> (OutboundRequest, UpstreamResponse) {
+        (
+            OutboundRequest {
+                env: "test".to_string(),
+                method,
+                uri: format!("https://api.example.com/v2{path}").parse().unwrap(),
+                headers: json_headers(),
+                body: request.to_string().into(),
+            },
+            UpstreamResponse {
+                status,
+                headers: json_headers(),
+                body: response.to_string().into(),
+                trailers: None,
+            },
+        )
+    }
+
+    fn kinds(violations: Vec<Violation>) -> Vec<&'static str> {
+        violations.iter().map(|v| v.kind.as_str()).collect()
+    }
+
+    #[test]
+    fn accepts_traffic_that_matches_the_spec() {
+        let contract = contract();
+        let (req, res) = exchange(
+            Method::GET,
+            "/hotels/7",
+            Value::Null,
+            StatusCode::OK,
+            json!({"id": 7, "name": "Sea View", "stars": null}),
+        );
+        assert_eq!(contract.check(&req, &res), Vec::new());
+
+        // The literal path wins over `/hotels/{id}`.
+        let (req, res) = exchange(
+            Method::POST,
+            "/hotels/search",
+            json!({"city": "Goa", "rooms": 2}),
+            StatusCode::ACCEPTED,
+            json!([]),
+        );
+        assert_eq!(contract.check(&req, &res), Vec::new());
+    }
+
+    #[test]
+    fn reports_drift() {
+        let contract = contract();
+        let (req, res) = exchange(
+            Method::GET,
+            "/hotels/7",
+            Value::Null,
+            StatusCode::OK,
+            json!({"id": "7", "stars": 6}),
+        );
+        let violations = contract.check(&req, &res);
+        assert_eq!(kinds(violations.clone()), ["response_body"]);
+        assert!(violations[0].detail.contains("$: missing name"));
+        assert!(violations[0].detail.contains("$.id: expected \"integer\""));
+        assert!(violations[0].detail.contains("$.stars: 6 is not one of"));
+
+        let (req, res) = exchange(
+            Method::GET,
+            "/hotels/7",
+            Value::Null,
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Value::Null,
+        );
+        assert_eq!(kinds(contract.check(&req, &res)), ["response_status"]);
+
+        let (req, res) = exchange(
+            Method::POST,
+            "/hotels/search",
+            json!({"town": "Goa"}),
+            StatusCode::OK,
+            Value::Null,
+        );
+        assert_eq!(kinds(contract.check(&req, &res)), ["request_body"]);
+
+        let (req, res) = exchange(
+            Method::DELETE,
+            "/hotels/7",
+            Value::Null,
+            StatusCode::OK,
+            Value::Null,
+        );
+        assert_eq!(kinds(contract.check(&req, &res)), ["unknown_operation"]);
+    }
+
+    #[test]
+    fn parses_spec_list() {
+        assert_eq!(
+            parse_openapi_specs("test=/specs/test.json, prod = /specs/prod.json").unwrap(),
+            BTreeMap::from([
+                ("prod".to_string(), "/specs/prod.json".to_string()),
+                ("test".to_string(), "/specs/test.json".to_string()),
+            ])
+        );
+        assert!(parse_openapi_specs("test").is_err());
+    }
+}
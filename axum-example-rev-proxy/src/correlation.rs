@@ -0,0 +1,108 @@
+//! Correlation IDs shared with the supplier: every request carries one
+//! upstream (the caller's, or a generated one), and the ID the supplier
+//! answers with is what callers, logs and error bodies report, so a support
+//! ticket can quote it directly.
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hyper::header::{HeaderMap, HeaderName, HeaderValue};
+
+pub const DEFAULT_CORRELATION_HEADER: &str = "x-correlation-id";
+
+/// Longest inbound ID that is passed on rather than replaced.
+const MAX_ID_LEN: usize = 128;
+
+pub struct Correlation {
+    header: HeaderName,
+}
+
+impl Correlation {
+    pub fn new(header: &str) -> Result<Self, String> {
+        let header = HeaderName::from_bytes(header.as_bytes())
+            .map_err(|_| format!("invalid correlation header name {header:?}"))?;
+        Ok(Self { header })
+    }
+
+    pub fn header(&self) -> &HeaderName {
+        &self.header
+    }
+
+    /// The request's correlation ID, generating one (and setting the header)
+    /// when the caller didn't send a usable one.
+    pub fn ensure(&self, headers: &mut HeaderMap) -> String {
+        if let Some(id) = self.read(headers) {
+            return id;
+        }
+        let id = generate();
+        headers.insert(
+            self.header.clone(),
+            HeaderValue::from_str(&id).expect("generated IDs are hex"),
+        );
+        id
+    }
+
+    /// A usable correlation ID from `headers`, if present.
+    pub fn read(&self, headers: &HeaderMap) -> Option<String> {
+        let value = headers.get(&self.header)?.to_str().ok()?.trim();
+        let usable = !value.is_empty()
+            && value.len() <= MAX_ID_LEN
+            && value.bytes().all(|b| b.is_ascii_graphic());
+        usable.then(|| value.to_string())
+    }
+
+    /// Sets the response's correlation header to `id`.
+    pub fn set(&self, headers: &mut HeaderMap, id: &str) {
+        if let Ok(value) = HeaderValue::from_str(id) {
+            headers.insert(self.header.clone(), value);
+        }
+    }
+}
+
+impl Default for Correlation {
+    fn default() -> Self {
+        Self::new(DEFAULT_CORRELATION_HEADER).expect("valid default header")
+    }
+}
+
+/// A new random 128-bit ID as 32 hex characters.
+pub fn generate() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let count = COUNTER.fetch_add(1, Ordering::Relaxed);
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    // Each RandomState is freshly keyed, so the halves are independent.
+    let half = || {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u64(count);
+        hasher.write_u128(nanos);
+        hasher.finish()
+    };
+    format!("{:016x}{:016x}", half(), half())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_usable_ids_and_replaces_the_rest() {
+        let correlation = Correlation::default();
+
+        let mut headers = HeaderMap::new();
+        headers.insert("x-correlation-id", "abc-123".parse().unwrap());
+        assert_eq!(correlation.ensure(&mut headers), "abc-123");
+
+        let mut headers = HeaderMap::new();
+        headers.insert("x-correlation-id", "has space".parse().unwrap());
+        let id = correlation.ensure(&mut headers);
+        assert_eq!(id.len(), 32);
+        assert_eq!(headers["x-correlation-id"], id.as_str());
+
+        assert_ne!(generate(), generate());
+    }
+}
@@ -0,0 +1,248 @@
+//! CORS for browser callers: preflights are answered here instead of being
+//! forwarded (suppliers reject OPTIONS), and allowed origins get the
+//! `Access-Control-*` headers on actual responses.
+
+use axum::body::Body;
+use axum::response::Response;
+use hyper::header::{self, HeaderMap, HeaderValue};
+use hyper::{Method, StatusCode};
+use serde::Deserialize;
+use tracing::warn;
+
+/// CORS policy for inbound paths starting with `path_prefix`. The longest
+/// matching prefix wins.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct CorsRule {
+    pub path_prefix: String,
+    /// Exact origins, or `*` for any.
+    pub allowed_origins: Vec<String>,
+    pub allowed_methods: Vec<String>,
+    /// Request headers a browser may send, compared case-insensitively.
+    pub allowed_headers: Vec<String>,
+    pub expose_headers: Vec<String>,
+    pub allow_credentials: bool,
+    /// How long browsers may cache a preflight answer, in seconds.
+    pub max_age_secs: Option<u64>,
+}
+
+impl CorsRule {
+    fn allows_origin(&self, origin: &str) -> bool {
+        self.allowed_origins
+            .iter()
+            .any(|allowed| allowed == "*" || allowed == origin)
+    }
+
+    fn allows_method(&self, method: &str) -> bool {
+        self.allowed_methods
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(method))
+    }
+
+    fn allows_headers(&self, requested: &str) -> bool {
+        requested
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .all(|name| {
+                self.allowed_headers
+                    .iter()
+                    .any(|allowed| allowed == "*" || allowed.eq_ignore_ascii_case(name))
+            })
+    }
+
+    /// `Access-Control-Allow-Origin` and friends for an allowed `origin`.
+    fn add_origin_headers(&self, origin: &HeaderValue, headers: &mut HeaderMap) {
+        // A literal `*` can't be combined with credentials, so echo the origin then.
+        let wildcard = self.allowed_origins.iter().any(|o| o == "*");
+        if wildcard && !self.allow_credentials {
+            headers.insert(
+                header::ACCESS_CONTROL_ALLOW_ORIGIN,
+                HeaderValue::from_static("*"),
+            );
+        } else {
+            headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin.clone());
+            let varies = headers
+                .get_all(header::VARY)
+                .iter()
+                .any(|value| value.as_bytes().eq_ignore_ascii_case(b"origin"));
+            if !varies {
+                headers.append(header::VARY, HeaderValue::from_static("Origin"));
+            }
+        }
+        if self.allow_credentials {
+            headers.insert(
+                header::ACCESS_CONTROL_ALLOW_CREDENTIALS,
+                HeaderValue::from_static("true"),
+            );
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Cors {
+    rules: Vec<CorsRule>,
+}
+
+impl Cors {
+    pub fn new(rules: Vec<CorsRule>) -> Self {
+        Self { rules }
+    }
+
+    fn rule_for(&self, path: &str) -> Option<&CorsRule> {
+        self.rules
+            .iter()
+            .filter(|rule| path.starts_with(&rule.path_prefix))
+            .max_by_key(|rule| rule.path_prefix.len())
+    }
+
+    pub fn applies_to(&self, path: &str) -> bool {
+        self.rule_for(path).is_some()
+    }
+
+    /// Answers a preflight for a path with a CORS rule: 204 with the allow
+    /// headers when origin, method and headers are all allowed, 403 otherwise.
+    /// Returns `None` for anything that isn't a preflight to such a path.
+    pub fn preflight(&self, method: &Method, request: &HeaderMap, path: &str) -> Option<Response> {
+        if method != Method::OPTIONS {
+            return None;
+        }
+        let origin = request.get(header::ORIGIN)?;
+        let requested_method = request.get(header::ACCESS_CONTROL_REQUEST_METHOD)?;
+        let rule = self.rule_for(path)?;
+
+        let requested_headers = request
+            .get(header::ACCESS_CONTROL_REQUEST_HEADERS)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default();
+        let allowed = origin.to_str().is_ok_and(|o| rule.allows_origin(o))
+            && requested_method
+                .to_str()
+                .is_ok_and(|m| rule.allows_method(m))
+            && rule.allows_headers(requested_headers);
+
+        let mut response = Response::new(Body::empty());
+        if !allowed {
+            warn!(
+                "Rejected CORS preflight for {} from {:?} ({:?})",
+                path, origin, requested_method
+            );
+            *response.status_mut() = StatusCode::FORBIDDEN;
+            return Some(response);
+        }
+
+        *response.status_mut() = StatusCode::NO_CONTENT;
+        let headers = response.headers_mut();
+        rule.add_origin_headers(origin, headers);
+        if let Ok(methods) = HeaderValue::from_str(&rule.allowed_methods.join(", ")) {
+            headers.insert(header::ACCESS_CONTROL_ALLOW_METHODS, methods);
+        }
+        if !requested_headers.is_empty() {
+            if let Ok(value) = HeaderValue::from_str(requested_headers) {
+                headers.insert(header::ACCESS_CONTROL_ALLOW_HEADERS, value);
+            }
+        }
+        if let Some(max_age) = rule.max_age_secs {
+            headers.insert(header::ACCESS_CONTROL_MAX_AGE, HeaderValue::from(max_age));
+        }
+        Some(response)
+    }
+
+    /// Adds CORS headers to an actual response when `origin` is allowed.
+    pub fn apply(&self, origin: Option<&HeaderValue>, path: &str, headers: &mut HeaderMap) {
+        let (Some(origin), Some(rule)) = (origin, self.rule_for(path)) else {
+            return;
+        };
+        if !origin.to_str().is_ok_and(|o| rule.allows_origin(o)) {
+            return;
+        }
+        rule.add_origin_headers(origin, headers);
+        if !rule.expose_headers.is_empty() {
+            if let Ok(value) = HeaderValue::from_str(&rule.expose_headers.join(", ")) {
+                headers.insert(header::ACCESS_CONTROL_EXPOSE_HEADERS, value);
+            }
+        }
+    }
+}
+
+/// Parses `CORS_RULES`, a JSON array of [`CorsRule`]s.
+pub fn parse_cors_rules(value: &str) -> Result<Vec<CorsRule>, String> {
+    if value.trim().is_empty() {
+        return Ok(Vec::new());
+    }
+    serde_json::from_str(value).map_err(|e| format!("invalid CORS rules: {e}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cors() -> Cors {
+        Cors::new(
+            parse_cors_rules(
+                r#"[{"path_prefix": "/prod/", "allowed_origins": ["https://app.example.com"],
+                     "allowed_methods": ["GET", "POST"], "allowed_headers": ["content-type"],
+                     "allow_credentials": true, "max_age_secs": 600}]"#,
+            )
+            .unwrap(),
+        )
+    }
+
+    fn preflight(origin: &str, method: &str, headers: &str) -> HeaderMap {
+        let mut request = HeaderMap::new();
+        request.insert(header::ORIGIN, origin.parse().unwrap());
+        request.insert(
+            header::ACCESS_CONTROL_REQUEST_METHOD,
+            method.parse().unwrap(),
+        );
+        request.insert(
+            header::ACCESS_CONTROL_REQUEST_HEADERS,
+            headers.parse().unwrap(),
+        );
+        request
+    }
+
+    #[test]
+    fn answers_allowed_preflights() {
+        let response = cors()
+            .preflight(
+                &Method::OPTIONS,
+                &preflight("https://app.example.com", "POST", "Content-Type"),
+                "/prod/api/book",
+            )
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let headers = response.headers();
+        assert_eq!(
+            headers[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://app.example.com"
+        );
+        assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_METHODS], "GET, POST");
+        assert_eq!(
+            headers[header::ACCESS_CONTROL_ALLOW_HEADERS],
+            "Content-Type"
+        );
+        assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_CREDENTIALS], "true");
+        assert_eq!(headers[header::ACCESS_CONTROL_MAX_AGE], "600");
+    }
+
+    #[test]
+    fn rejects_disallowed_origins_methods_and_headers() {
+        let cors = cors();
+        for request in [
+            preflight("https://evil.example.com", "POST", ""),
+            preflight("https://app.example.com", "DELETE", ""),
+            preflight("https://app.example.com", "POST", "x-admin"),
+        ] {
+            let response = cors
+                .preflight(&Method::OPTIONS, &request, "/prod/api")
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        }
+        let request = preflight("https://app.example.com", "POST", "");
+        assert!(cors
+            .preflight(&Method::OPTIONS, &request, "/test/api")
+            .is_none());
+    }
+}
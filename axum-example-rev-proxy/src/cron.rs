@@ -0,0 +1,308 @@
+//! Cron expressions for schedule-aware config such as maintenance windows
+//! and off-peak rate limits.
+//!
+//! Expressions have the usual five fields, `minute hour day-of-month month
+//! day-of-week`, each `*`, a value, a range `a-b`, a step `*/n` or `a-b/n`,
+//! or a comma-separated list of those. Months and weekdays also take names
+//! (`JAN`, `SUN`); Sunday is 0 or 7. As in cron, a day matches if either
+//! day field does when both are restricted. Times are read in the window's
+//! IANA `timezone`, UTC by default, so DST moves with it.
+
+use std::sync::Mutex;
+use std::time::Duration;
+
+use chrono::{DateTime, Datelike, Timelike, Utc};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+
+/// How far [`TimeWindow::active_until`] looks for the end of a window
+/// without a duration.
+const MAX_OPEN_WINDOW_MINUTES: i64 = 7 * 24 * 60;
+
+const MONTHS: [&str; 12] = [
+    "JAN", "FEB", "MAR", "APR", "MAY", "JUN", "JUL", "AUG", "SEP", "OCT", "NOV", "DEC",
+];
+const WEEKDAYS: [&str; 7] = ["SUN", "MON", "TUE", "WED", "THU", "FRI", "SAT"];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CronSchedule {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    /// Whether each day field was `*`, for cron's either-day rule.
+    any_day: bool,
+    any_weekday: bool,
+    timezone: Tz,
+}
+
+impl CronSchedule {
+    /// Parses `expr`, read in `timezone` (an IANA name) or UTC.
+    pub fn parse(expr: &str, timezone: Option<&str>) -> Result<Self, String> {
+        let fields: Vec<&str> = expr.split_whitespace().collect();
+        let [minute, hour, day, month, weekday] = fields[..] else {
+            return Err(format!("cron expression {expr:?} needs five fields"));
+        };
+        let timezone = match timezone {
+            Some(name) => name
+                .parse::<Tz>()
+                .map_err(|_| format!("unknown timezone {name:?}"))?,
+            None => Tz::UTC,
+        };
+        let mut weekdays = parse_field(weekday, 0, 7, &WEEKDAYS, 0)?;
+        // 7 is Sunday too.
+        if weekdays & (1 << 7) != 0 {
+            weekdays = (weekdays | 1) & !(1 << 7);
+        }
+        Ok(Self {
+            minutes: parse_field(minute, 0, 59, &[], 0)?,
+            hours: parse_field(hour, 0, 23, &[], 0)?,
+            days: parse_field(day, 1, 31, &[], 0)?,
+            months: parse_field(month, 1, 12, &MONTHS, 1)?,
+            weekdays,
+            any_day: day == "*",
+            any_weekday: weekday == "*",
+            timezone,
+        })
+    }
+
+    /// Whether the minute containing `at` matches.
+    pub fn matches(&self, at: DateTime<Utc>) -> bool {
+        let local = at.with_timezone(&self.timezone);
+        let bit = |set: u64, value: u32| set & (1 << value) != 0;
+        let day = bit(self.days, local.day());
+        let weekday = bit(self.weekdays, local.weekday().num_days_from_sunday());
+        let day_matches = match (self.any_day, self.any_weekday) {
+            (false, false) => day || weekday,
+            _ => day && weekday,
+        };
+        bit(self.minutes, local.minute())
+            && bit(self.hours, local.hour())
+            && bit(self.months, local.month())
+            && day_matches
+    }
+}
+
+/// Parses one field into a bit set of the values it allows. `names` stand
+/// for `first_name`, `first_name + 1`, ...
+fn parse_field(
+    field: &str,
+    min: u32,
+    max: u32,
+    names: &[&str],
+    first_name: u32,
+) -> Result<u64, String> {
+    let value = |text: &str| -> Result<u32, String> {
+        let upper = text.to_ascii_uppercase();
+        if let Some(index) = names.iter().position(|name| *name == upper) {
+            return Ok(first_name + index as u32);
+        }
+        let value = text
+            .parse::<u32>()
+            .map_err(|_| format!("invalid cron value {text:?}"))?;
+        if !(min..=max).contains(&value) {
+            return Err(format!("cron value {value} is outside {min}-{max}"));
+        }
+        Ok(value)
+    };
+    let mut set = 0u64;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => {
+                let step = step
+                    .parse::<u32>()
+                    .ok()
+                    .filter(|step| *step > 0)
+                    .ok_or_else(|| format!("invalid cron step in {part:?}"))?;
+                (range, step)
+            }
+            None => (part, 1),
+        };
+        let (start, end) = match range {
+            "*" => (min, max),
+            range => match range.split_once('-') {
+                Some((start, end)) => (value(start)?, value(end)?),
+                // `a/n` runs from `a` to the end of the field.
+                None if step > 1 => (value(range)?, max),
+                None => {
+                    let value = value(range)?;
+                    (value, value)
+                }
+            },
+        };
+        if start > end {
+            return Err(format!("cron range {range:?} runs backwards"));
+        }
+        for value in (start..=end).step_by(step as usize) {
+            set |= 1 << value;
+        }
+    }
+    Ok(set)
+}
+
+/// A recurring window as configured, e.g.
+/// `{"cron": "0 2 * * SUN", "duration_secs": 7200, "timezone": "America/New_York"}`.
+/// With `duration_secs` the window opens at each time the expression
+/// matches and lasts that long; without, it covers every minute the
+/// expression matches, as in `* 0-5 * * *` for midnight to 6am.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct WindowSpec {
+    pub cron: String,
+    #[serde(default)]
+    pub timezone: Option<String>,
+    #[serde(default)]
+    pub duration_secs: Option<u64>,
+}
+
+impl WindowSpec {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.duration_secs == Some(0) {
+            return Err(format!(
+                "window {:?}: duration_secs must be positive",
+                self.cron
+            ));
+        }
+        CronSchedule::parse(&self.cron, self.timezone.as_deref()).map(|_| ())
+    }
+}
+
+#[derive(Debug)]
+pub struct TimeWindow {
+    spec: WindowSpec,
+    schedule: CronSchedule,
+    /// The last answer, by the minute it was worked out for.
+    cached: Mutex<Option<(i64, Option<DateTime<Utc>>)>>,
+}
+
+impl TimeWindow {
+    pub fn new(spec: &WindowSpec) -> Result<Self, String> {
+        spec.validate()?;
+        Ok(Self {
+            spec: spec.clone(),
+            schedule: CronSchedule::parse(&spec.cron, spec.timezone.as_deref())?,
+            cached: Mutex::new(None),
+        })
+    }
+
+    pub fn spec(&self) -> &WindowSpec {
+        &self.spec
+    }
+
+    /// When `at` is inside the window, the time the window closes.
+    pub fn active_until(&self, at: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let minute = at.timestamp().div_euclid(60);
+        if let Some((cached_minute, until)) = *self.cached.lock().unwrap() {
+            if cached_minute == minute {
+                return until.filter(|until| *until > at);
+            }
+        }
+        let until = self.compute(minute);
+        *self.cached.lock().unwrap() = Some((minute, until));
+        until.filter(|until| *until > at)
+    }
+
+    fn compute(&self, minute: i64) -> Option<DateTime<Utc>> {
+        let at_minute = |minute: i64| DateTime::<Utc>::from_timestamp(minute * 60, 0);
+        match self.spec.duration_secs {
+            Some(secs) => {
+                // The latest opening still inside its duration.
+                let span = (secs as i64 - 1).div_euclid(60);
+                (minute - span..=minute)
+                    .rev()
+                    .filter_map(at_minute)
+                    .find(|start| self.schedule.matches(*start))
+                    .map(|start| start + Duration::from_secs(secs))
+            }
+            None => {
+                if !self.schedule.matches(at_minute(minute)?) {
+                    return None;
+                }
+                // An expression that never stops matching, such as
+                // `* * * * *`, is open to the horizon.
+                let horizon = minute + MAX_OPEN_WINDOW_MINUTES;
+                (minute + 1..horizon)
+                    .filter_map(at_minute)
+                    .find(|next| !self.schedule.matches(*next))
+                    .or_else(|| at_minute(horizon))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    fn utc(y: i32, mo: u32, d: u32, h: u32, mi: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(y, mo, d, h, mi, 0).unwrap()
+    }
+
+    #[test]
+    fn matches_cron_fields() {
+        let schedule = CronSchedule::parse("*/15 9-17 * * MON-FRI", None).unwrap();
+        // 2024-03-04 is a Monday.
+        assert!(schedule.matches(utc(2024, 3, 4, 9, 30)));
+        assert!(!schedule.matches(utc(2024, 3, 4, 9, 31)));
+        assert!(!schedule.matches(utc(2024, 3, 4, 18, 0)));
+        assert!(!schedule.matches(utc(2024, 3, 3, 9, 30)));
+
+        // Either day field matches when both are set; 7 is Sunday.
+        let schedule = CronSchedule::parse("0 0 1 * 7", None).unwrap();
+        assert!(schedule.matches(utc(2024, 3, 1, 0, 0)));
+        assert!(schedule.matches(utc(2024, 3, 3, 0, 0)));
+        assert!(!schedule.matches(utc(2024, 3, 4, 0, 0)));
+
+        for bad in [
+            "* * * *",
+            "60 * * * *",
+            "* * * 13 *",
+            "5-1 * * * *",
+            "*/0 * * * *",
+        ] {
+            assert!(CronSchedule::parse(bad, None).is_err(), "{bad}");
+        }
+        assert!(CronSchedule::parse("* * * * *", Some("Mars/Olympus")).is_err());
+    }
+
+    #[test]
+    fn reads_times_in_the_timezone() {
+        let schedule = CronSchedule::parse("0 2 * * *", Some("America/New_York")).unwrap();
+        // 02:00 in New York is 07:00 UTC in winter and 06:00 in summer.
+        assert!(schedule.matches(utc(2024, 1, 10, 7, 0)));
+        assert!(schedule.matches(utc(2024, 7, 10, 6, 0)));
+        assert!(!schedule.matches(utc(2024, 7, 10, 7, 0)));
+    }
+
+    #[test]
+    fn windows_open_and_close() {
+        let window = TimeWindow::new(&WindowSpec {
+            cron: "0 2 * * SUN".to_string(),
+            timezone: None,
+            duration_secs: Some(2 * 60 * 60),
+        })
+        .unwrap();
+        // 2024-03-03 is a Sunday.
+        assert_eq!(window.active_until(utc(2024, 3, 3, 1, 59)), None);
+        assert_eq!(
+            window.active_until(utc(2024, 3, 3, 3, 30)),
+            Some(utc(2024, 3, 3, 4, 0))
+        );
+        assert_eq!(window.active_until(utc(2024, 3, 3, 4, 0)), None);
+
+        let night = TimeWindow::new(&WindowSpec {
+            cron: "* 0-5 * * *".to_string(),
+            timezone: Some("Asia/Kolkata".to_string()),
+            duration_secs: None,
+        })
+        .unwrap();
+        // Midnight to 6am IST is 18:30 to 00:30 UTC.
+        assert_eq!(
+            night.active_until(utc(2024, 3, 3, 20, 0)),
+            Some(utc(2024, 3, 4, 0, 30))
+        );
+        assert_eq!(night.active_until(utc(2024, 3, 4, 1, 0)), None);
+    }
+}
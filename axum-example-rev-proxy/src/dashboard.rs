@@ -0,0 +1,156 @@
+//! A small dashboard for ops, served from the binary: live request rate,
+//! error rates, latency percentiles per path, upstream probe and SLO health,
+//! and the latest slow requests.
+//!
+//! The page at `/admin/ui` is a static shell holding no data, so a browser
+//! can load it without credentials; it asks for the admin token and polls
+//! `/admin/dashboard`, which requires it like the rest of `/admin`.
+
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use axum::extract::State;
+use axum::response::Html;
+use axum::Json;
+use hyper::{Method, StatusCode};
+use serde::Serialize;
+
+use crate::app_state::AppState;
+use crate::metrics::PathStats;
+use crate::prober::ProbeStatus;
+use crate::rolling::{WindowStats, WINDOWS};
+use crate::slo::SloReport;
+
+/// Slow requests kept for the dashboard.
+const MAX_SLOW_REQUESTS: usize = 50;
+/// Paths shown, busiest first.
+const DASHBOARD_PATHS: usize = 15;
+
+pub const DEFAULT_SLOW_REQUEST_THRESHOLD: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SlowRequest {
+    pub at_ms: i64,
+    pub method: String,
+    pub path: String,
+    pub status: u16,
+    pub latency_ms: u64,
+    pub correlation_id: String,
+}
+
+/// The most recent requests that took at least `threshold`.
+#[derive(Debug)]
+pub struct SlowRequests {
+    threshold: Duration,
+    entries: Mutex<VecDeque<SlowRequest>>,
+}
+
+impl SlowRequests {
+    pub fn new(threshold: Duration) -> Self {
+        Self {
+            threshold,
+            entries: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    pub fn record(
+        &self,
+        method: &Method,
+        path: &str,
+        status: StatusCode,
+        latency: Duration,
+        correlation_id: &str,
+    ) {
+        if latency < self.threshold {
+            return;
+        }
+        let at_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as i64;
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() == MAX_SLOW_REQUESTS {
+            entries.pop_back();
+        }
+        entries.push_front(SlowRequest {
+            at_ms,
+            method: method.to_string(),
+            path: path.to_string(),
+            status: status.as_u16(),
+            latency_ms: latency.as_millis() as u64,
+            correlation_id: correlation_id.to_string(),
+        });
+    }
+
+    /// Newest first.
+    pub fn recent(&self) -> Vec<SlowRequest> {
+        self.entries.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Dashboard {
+    /// Traffic over the last 1, 5 and 15 minutes.
+    pub recent: BTreeMap<&'static str, WindowStats>,
+    pub paths: Vec<PathStats>,
+    pub probes: Vec<ProbeStatus>,
+    pub slo: Vec<SloReport>,
+    pub slow_requests: Vec<SlowRequest>,
+    pub slow_threshold_ms: u64,
+}
+
+/// `GET /admin/dashboard`: everything the page shows, in one call.
+pub async fn dashboard_handler(State(state): State<AppState>) -> Json<Dashboard> {
+    let mut paths = state.metrics.path_stats();
+    paths.sort_by_key(|stats| Reverse(stats.requests));
+    paths.truncate(DASHBOARD_PATHS);
+    Json(Dashboard {
+        recent: WINDOWS
+            .iter()
+            .map(|(name, window)| (*name, state.metrics.recent(*window)))
+            .collect(),
+        paths,
+        probes: state.prober.statuses(),
+        slo: state.slo.report(),
+        slow_requests: state.slow_requests.recent(),
+        slow_threshold_ms: state.slow_requests.threshold.as_millis() as u64,
+    })
+}
+
+/// `GET /admin/ui`: the dashboard page.
+pub async fn ui_handler() -> Html<&'static str> {
+    Html(include_str!("dashboard.html"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_the_latest_slow_requests() {
+        let slow = SlowRequests::new(Duration::from_millis(100));
+        slow.record(
+            &Method::GET,
+            "/test/fast",
+            StatusCode::OK,
+            Duration::from_millis(99),
+            "a",
+        );
+        for i in 0..=MAX_SLOW_REQUESTS {
+            slow.record(
+                &Method::POST,
+                &format!("/test/slow/{i}"),
+                StatusCode::BAD_GATEWAY,
+                Duration::from_millis(100),
+                "b",
+            );
+        }
+        let recent = slow.recent();
+        assert_eq!(recent.len(), MAX_SLOW_REQUESTS);
+        assert_eq!(recent[0].path, format!("/test/slow/{MAX_SLOW_REQUESTS}"));
+        assert_eq!(recent.last().unwrap().path, "/test/slow/1");
+        assert_eq!(recent[0].status, 502);
+    }
+}
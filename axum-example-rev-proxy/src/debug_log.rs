@@ -0,0 +1,234 @@
+//! Verbose logging of full request/response headers and bodies for a sample
+//! of traffic: 1 in `sample_rate` requests, plus any request carrying the
+//! trace header (e.g. `X-Debug-Trace: 1`).
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use axum::body::Bytes;
+use hyper::header::{self, HeaderMap, HeaderName};
+use serde::Deserialize;
+use serde_json::Value;
+use tracing::info;
+
+use crate::encoding::decode_body;
+use crate::interceptor::{OutboundRequest, UpstreamResponse};
+use crate::proxy::MAX_BODY_SIZE;
+
+pub const DEFAULT_TRACE_HEADER: &str = "x-debug-trace";
+pub const DEFAULT_MAX_LOGGED_BYTES: usize = 8 * 1024;
+/// Bytes shown in the hexdump preview of a binary body.
+const HEXDUMP_PREVIEW: usize = 256;
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct DebugLogConfig {
+    /// Log 1 in this many requests; 0 turns sampling off.
+    pub sample_rate: u64,
+    /// Requests with this header set to `1`/`true` are always logged. The
+    /// header is not forwarded upstream.
+    pub trace_header: Option<String>,
+    /// Logged bodies are cut off after this many bytes.
+    pub max_logged_bytes: usize,
+    /// Pretty-prints bodies whose content type is JSON.
+    pub pretty_json: bool,
+}
+
+impl Default for DebugLogConfig {
+    fn default() -> Self {
+        Self {
+            sample_rate: 0,
+            trace_header: Some(DEFAULT_TRACE_HEADER.to_string()),
+            max_logged_bytes: DEFAULT_MAX_LOGGED_BYTES,
+            pretty_json: true,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct DebugLog {
+    sample_rate: u64,
+    trace_header: Option<HeaderName>,
+    max_logged_bytes: usize,
+    pretty_json: bool,
+    seen: AtomicU64,
+}
+
+impl DebugLog {
+    pub fn new(config: &DebugLogConfig) -> Self {
+        Self {
+            sample_rate: config.sample_rate,
+            trace_header: config
+                .trace_header
+                .as_deref()
+                .filter(|name| !name.is_empty())
+                .map(|name| HeaderName::from_bytes(name.as_bytes()).expect("invalid trace header")),
+            max_logged_bytes: config.max_logged_bytes,
+            pretty_json: config.pretty_json,
+            seen: AtomicU64::new(0),
+        }
+    }
+
+    /// Decides whether this request is logged verbosely, removing the trace
+    /// header so it doesn't reach the upstream.
+    pub fn select(&self, headers: &mut HeaderMap) -> bool {
+        let traced = self
+            .trace_header
+            .as_ref()
+            .and_then(|name| headers.remove(name))
+            .is_some_and(|value| value == "1" || value.as_bytes().eq_ignore_ascii_case(b"true"));
+        if traced {
+            return true;
+        }
+        self.sample_rate > 0 && self.seen.fetch_add(1, Ordering::Relaxed) % self.sample_rate == 0
+    }
+
+    pub fn log_request(&self, req: &OutboundRequest) {
+        info!("Verbose request: {} {}", req.method, req.uri);
+        log_headers("Request", &req.headers);
+        info!(
+            "Request Body: {}",
+            self.render_body(&req.headers, &req.body)
+        );
+    }
+
+    pub fn log_response(&self, req: &OutboundRequest, response: &UpstreamResponse) {
+        info!("Verbose response: {} for {}", response.status, req.uri);
+        log_headers("Response", &response.headers);
+        info!(
+            "Response Body: {}",
+            self.render_body(&response.headers, &response.body)
+        );
+    }
+
+    /// Decoded body as logged: pretty JSON, capped text, or a hexdump
+    /// preview for binary content.
+    fn render_body(&self, headers: &HeaderMap, body: &Bytes) -> String {
+        let decoded = match decode_body(headers, body, MAX_BODY_SIZE) {
+            Ok(decoded) => decoded,
+            Err(e) => return format!("<{} bytes, {e}>", body.len()),
+        };
+        if decoded.is_empty() {
+            return "<empty>".to_string();
+        }
+        let Some(text) = as_text(&decoded) else {
+            return format!(
+                "<{} bytes, binary>\n{}",
+                decoded.len(),
+                hexdump(&decoded[..decoded.len().min(HEXDUMP_PREVIEW)])
+            );
+        };
+        let pretty = (self.pretty_json && is_json(headers))
+            .then(|| serde_json::from_str::<Value>(text).ok())
+            .flatten()
+            .and_then(|value| serde_json::to_string_pretty(&value).ok());
+        cap(pretty.as_deref().unwrap_or(text), self.max_logged_bytes)
+    }
+}
+
+fn is_json(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(';').next())
+        .map(|mime| mime.trim().to_ascii_lowercase())
+        .is_some_and(|mime| mime == "application/json" || mime.ends_with("+json"))
+}
+
+/// The body as text, unless it isn't UTF-8 or contains control characters
+/// other than whitespace.
+fn as_text(body: &[u8]) -> Option<&str> {
+    let text = std::str::from_utf8(body).ok()?;
+    text.chars()
+        .all(|c| !c.is_control() || c.is_whitespace())
+        .then_some(text)
+}
+
+fn cap(text: &str, max: usize) -> String {
+    if text.len() <= max {
+        return text.to_string();
+    }
+    let mut end = max;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}...[{} more bytes]", &text[..end], text.len() - end)
+}
+
+/// `xxd`-style lines: offset, 16 hex bytes, printable ASCII.
+fn hexdump(bytes: &[u8]) -> String {
+    bytes
+        .chunks(16)
+        .enumerate()
+        .map(|(line, chunk)| {
+            let hex: Vec<String> = chunk.iter().map(|b| format!("{b:02x}")).collect();
+            let ascii: String = chunk
+                .iter()
+                .map(|&b| {
+                    if b.is_ascii_graphic() || b == b' ' {
+                        b as char
+                    } else {
+                        '.'
+                    }
+                })
+                .collect();
+            format!("{:08x}  {:<47}  {}", line * 16, hex.join(" "), ascii)
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn log_headers(kind: &str, headers: &HeaderMap) {
+    for (key, value) in headers.iter() {
+        info!("{} Header: {}: {:?}", kind, key, value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn samples_one_in_n_and_honours_trace_header() {
+        let log = DebugLog::new(&DebugLogConfig {
+            sample_rate: 3,
+            ..Default::default()
+        });
+        let picks: Vec<bool> = (0..6).map(|_| log.select(&mut HeaderMap::new())).collect();
+        assert_eq!(picks, [true, false, false, true, false, false]);
+
+        let off = DebugLog::new(&DebugLogConfig::default());
+        let mut headers = HeaderMap::new();
+        headers.insert(DEFAULT_TRACE_HEADER, "1".parse().unwrap());
+        assert!(off.select(&mut headers));
+        assert!(headers.get(DEFAULT_TRACE_HEADER).is_none());
+        assert!(!off.select(&mut HeaderMap::new()));
+    }
+
+    #[test]
+    fn renders_json_pretty_caps_text_and_dumps_binary() {
+        let log = DebugLog::new(&DebugLogConfig {
+            max_logged_bytes: 16,
+            ..Default::default()
+        });
+        let mut json = HeaderMap::new();
+        json.insert(
+            header::CONTENT_TYPE,
+            "application/json; charset=utf-8".parse().unwrap(),
+        );
+        assert_eq!(
+            log.render_body(&json, &Bytes::from_static(br#"{"a":1}"#)),
+            "{\n  \"a\": 1\n}"
+        );
+
+        let text = HeaderMap::new();
+        assert_eq!(
+            log.render_body(&text, &Bytes::from_static(b"0123456789abcdefXYZ")),
+            "0123456789abcdef...[3 more bytes]"
+        );
+
+        let binary = log.render_body(&text, &Bytes::from_static(b"\x89PNG\r\n\x1a\n\x00"));
+        assert_eq!(
+            binary,
+            "<9 bytes, binary>\n00000000  89 50 4e 47 0d 0a 1a 0a 00                       .PNG....."
+        );
+    }
+}
@@ -0,0 +1,51 @@
+//! Seen-key sets with expiry, for rejecting replays.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use async_trait::async_trait;
+
+#[async_trait]
+pub trait DedupStore: Send + Sync {
+    /// Records `key` for `ttl`. Returns `false` if it was already recorded
+    /// and hasn't expired.
+    async fn first_seen(&self, key: &str, ttl: Duration) -> Result<bool, String>;
+}
+
+/// Per-process set; replays reaching another replica aren't caught.
+#[derive(Debug, Default)]
+pub struct MemoryDedupStore {
+    seen: Mutex<HashMap<String, SystemTime>>,
+}
+
+#[async_trait]
+impl DedupStore for MemoryDedupStore {
+    async fn first_seen(&self, key: &str, ttl: Duration) -> Result<bool, String> {
+        let now = SystemTime::now();
+        let mut seen = self.seen.lock().unwrap();
+        seen.retain(|_, expires| *expires > now);
+        if seen.contains_key(key) {
+            return Ok(false);
+        }
+        seen.insert(key.to_string(), now + ttl);
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn second_sighting_is_a_replay_until_expiry() {
+        let store = MemoryDedupStore::default();
+        let ttl = Duration::from_secs(60);
+
+        assert_eq!(store.first_seen("a", ttl).await, Ok(true));
+        assert_eq!(store.first_seen("a", ttl).await, Ok(false));
+        assert_eq!(store.first_seen("b", ttl).await, Ok(true));
+        assert_eq!(store.first_seen("c", Duration::ZERO).await, Ok(true));
+        assert_eq!(store.first_seen("c", ttl).await, Ok(true));
+    }
+}
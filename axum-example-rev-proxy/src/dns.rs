@@ -0,0 +1,784 @@
+//! Upstream hostname resolution with failover. Resolvers are tried in the
+//! configured order (e.g. the system resolver, then 1.1.1.1, then 8.8.8.8);
+//! one that keeps failing is skipped for a cool-down, after which it is tried
+//! first again, so traffic fails back once it recovers.
+//!
+//! Names that fail to resolve are cached negatively for a short TTL, which
+//! doubles each time the same name fails again, so a dead hostname doesn't
+//! hammer DNS or add lookup latency to every request.
+//!
+//! Nameserver answers are cached for their TTL. When a supplier fails over
+//! to new addresses that cache would keep us on the dead ones, so a failed
+//! connection [`DnsResolver::refresh`]es the name, bypassing both caches,
+//! before the request is retried.
+//!
+//! Answers are ordered, or filtered, by the [`FamilyPolicy`]. The client
+//! tries the first family it's given and falls back to the other only if
+//! that fails to connect, so the order decides which family upstreams see.
+
+use std::collections::HashMap;
+use std::fmt::{self, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use hickory_resolver::config::{
+    LookupIpStrategy, NameServerConfigGroup, ResolverConfig, ResolverOpts,
+};
+use hickory_resolver::error::ResolveErrorKind;
+use hickory_resolver::TokioAsyncResolver;
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::timings;
+
+/// Consecutive failures after which a resolver is skipped.
+const FAILURES_BEFORE_DOWN: u32 = 3;
+/// How long a failing resolver is skipped before it is tried again.
+const COOL_DOWN: Duration = Duration::from_secs(30);
+/// Per-query timeout for explicit nameservers, so a dead one fails over
+/// quickly.
+const NAMESERVER_TIMEOUT: Duration = Duration::from_secs(2);
+/// Longest a failing name is cached negatively, however often it failed.
+const MAX_NEGATIVE_TTL: Duration = Duration::from_secs(5 * 60);
+/// Names cached negatively at once; further failures aren't cached.
+const MAX_NEGATIVE_ENTRIES: usize = 1024;
+/// Shortest time between forced re-resolutions of one name, so a burst of
+/// failed connections re-resolves it once.
+const MIN_REFRESH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// One link in the resolver chain.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum ResolverSpec {
+    /// The OS resolver (`/etc/resolv.conf`, nsswitch, ...).
+    System,
+    /// A nameserver queried directly, e.g. `1.1.1.1` or `9.9.9.9:53`.
+    Nameserver(SocketAddr),
+}
+
+impl FromStr for ResolverSpec {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let value = value.trim();
+        if value == "system" {
+            return Ok(Self::System);
+        }
+        if let Ok(addr) = value.parse::<SocketAddr>() {
+            return Ok(Self::Nameserver(addr));
+        }
+        value
+            .parse::<IpAddr>()
+            .map(|ip| Self::Nameserver(SocketAddr::new(ip, 53)))
+            .map_err(|_| format!("invalid resolver {value:?}: expected `system` or an IP"))
+    }
+}
+
+impl TryFrom<String> for ResolverSpec {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl fmt::Display for ResolverSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::System => f.write_str("system"),
+            Self::Nameserver(addr) => addr.fmt(f),
+        }
+    }
+}
+
+/// Which address families upstream connections use, from
+/// `UPSTREAM_IP_FAMILY`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum FamilyPolicy {
+    /// IPv4 addresses first, IPv6 when none of them connect.
+    #[default]
+    PreferV4,
+    /// IPv6 addresses first, IPv4 when none of them connect.
+    PreferV6,
+    /// IPv4 addresses only; names without any fail to resolve.
+    OnlyV4,
+}
+
+impl FamilyPolicy {
+    /// Orders `ips` by preferred family, keeping the resolver's order
+    /// within each, or drops the IPv6 ones.
+    pub fn apply(self, mut ips: Vec<IpAddr>) -> Vec<IpAddr> {
+        match self {
+            Self::PreferV4 => ips.sort_by_key(IpAddr::is_ipv6),
+            Self::PreferV6 => ips.sort_by_key(IpAddr::is_ipv4),
+            Self::OnlyV4 => ips.retain(IpAddr::is_ipv4),
+        }
+        ips
+    }
+
+    /// The records nameservers are asked for.
+    fn strategy(self) -> LookupIpStrategy {
+        match self {
+            Self::PreferV4 | Self::PreferV6 => LookupIpStrategy::Ipv4AndIpv6,
+            Self::OnlyV4 => LookupIpStrategy::Ipv4Only,
+        }
+    }
+}
+
+impl FromStr for FamilyPolicy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim() {
+            "prefer_v4" => Ok(Self::PreferV4),
+            "prefer_v6" => Ok(Self::PreferV6),
+            "only_v4" => Ok(Self::OnlyV4),
+            other => Err(format!(
+                "invalid IP family policy {other:?}: expected `prefer_v4`, `prefer_v6` or `only_v4`"
+            )),
+        }
+    }
+}
+
+impl TryFrom<String> for FamilyPolicy {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl fmt::Display for FamilyPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::PreferV4 => "prefer_v4",
+            Self::PreferV6 => "prefer_v6",
+            Self::OnlyV4 => "only_v4",
+        })
+    }
+}
+
+/// Parses `DNS_RESOLVERS`, a comma-separated chain such as
+/// `system,1.1.1.1,8.8.8.8`.
+pub fn parse_resolvers(value: &str) -> Result<Vec<ResolverSpec>, String> {
+    value
+        .split(',')
+        .filter(|entry| !entry.trim().is_empty())
+        .map(str::parse)
+        .collect()
+}
+
+enum Backend {
+    System,
+    Nameserver(Box<TokioAsyncResolver>),
+}
+
+/// What a resolver said about a name.
+enum Answer {
+    Found(Vec<IpAddr>),
+    /// A definitive "no such name"; asking another resolver won't help.
+    NotFound(String),
+    /// The resolver itself failed (timeout, refused, ...).
+    Failed(String),
+}
+
+impl Backend {
+    /// Drops cached answers, so the next query asks the nameserver.
+    fn clear_cache(&self) {
+        if let Self::Nameserver(resolver) = self {
+            resolver.clear_cache();
+        }
+    }
+
+    fn new(spec: ResolverSpec, timeout: Duration, strategy: LookupIpStrategy) -> Self {
+        match spec {
+            ResolverSpec::System => Self::System,
+            ResolverSpec::Nameserver(addr) => {
+                let servers =
+                    NameServerConfigGroup::from_ips_clear(&[addr.ip()], addr.port(), true);
+                let mut options = ResolverOpts::default();
+                options.timeout = timeout;
+                options.attempts = 1;
+                options.ip_strategy = strategy;
+                Self::Nameserver(Box::new(TokioAsyncResolver::tokio(
+                    ResolverConfig::from_parts(None, Vec::new(), servers),
+                    options,
+                )))
+            }
+        }
+    }
+
+    async fn query(&self, host: &str) -> Answer {
+        match self {
+            Self::System => match tokio::net::lookup_host((host, 0)).await {
+                Ok(addrs) => Answer::Found(addrs.map(|addr| addr.ip()).collect()),
+                Err(e) => Answer::Failed(e.to_string()),
+            },
+            Self::Nameserver(resolver) => match resolver.lookup_ip(host).await {
+                Ok(lookup) => Answer::Found(lookup.iter().collect()),
+                Err(e) if matches!(e.kind(), ResolveErrorKind::NoRecordsFound { .. }) => {
+                    Answer::NotFound(e.to_string())
+                }
+                Err(e) => Answer::Failed(e.to_string()),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Health {
+    consecutive_failures: u32,
+    down_until: Option<Instant>,
+    successes: u64,
+    failures: u64,
+}
+
+struct Resolver {
+    spec: ResolverSpec,
+    backend: Backend,
+    health: Mutex<Health>,
+}
+
+impl Resolver {
+    fn is_down(&self, now: Instant) -> bool {
+        let health = self.health.lock().unwrap();
+        health.down_until.is_some_and(|until| now < until)
+    }
+
+    fn succeeded(&self) {
+        let mut health = self.health.lock().unwrap();
+        if health.down_until.take().is_some() {
+            info!("DNS resolver {} recovered", self.spec);
+        }
+        health.consecutive_failures = 0;
+        health.successes += 1;
+    }
+
+    fn failed(&self, now: Instant) {
+        let mut health = self.health.lock().unwrap();
+        health.consecutive_failures += 1;
+        health.failures += 1;
+        if health.consecutive_failures >= FAILURES_BEFORE_DOWN {
+            if health.down_until.is_none_or(|until| until <= now) {
+                warn!(
+                    "DNS resolver {} failed {} times in a row; skipping it for {}s",
+                    self.spec,
+                    health.consecutive_failures,
+                    COOL_DOWN.as_secs()
+                );
+            }
+            health.down_until = Some(now + COOL_DOWN);
+        }
+    }
+}
+
+/// State of one resolver in the chain.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ResolverHealth {
+    pub resolver: String,
+    pub up: bool,
+    pub consecutive_failures: u32,
+    pub successes: u64,
+    pub failures: u64,
+}
+
+/// A name that failed to resolve.
+#[derive(Debug)]
+struct NegativeEntry {
+    error: String,
+    /// Consecutive failed lookups, which set the backoff.
+    failures: u32,
+    until: Instant,
+}
+
+/// The latest answer for a name, to tell whether a refresh changed it.
+#[derive(Debug, Default)]
+struct Known {
+    ips: Vec<IpAddr>,
+    refreshed: Option<Instant>,
+}
+
+/// How a forced re-resolution went.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Refresh {
+    /// The name now resolves to different addresses.
+    Changed,
+    Unchanged,
+    Failed,
+    /// The name was refreshed moments ago, so it wasn't again.
+    Skipped,
+}
+
+pub struct DnsResolver {
+    resolvers: Vec<Resolver>,
+    timeout: Duration,
+    policy: FamilyPolicy,
+    /// Base TTL for failed names; no negative caching when unset.
+    negative_ttl: Option<Duration>,
+    negative: Mutex<HashMap<String, NegativeEntry>>,
+    negative_hits: AtomicU64,
+    known: Mutex<HashMap<String, Known>>,
+    /// Forced re-resolutions that changed the answer, didn't, and failed.
+    refreshes: [AtomicU64; 3],
+}
+
+/// How long a name that has now failed `failures` times in a row is cached.
+fn backoff(base: Duration, failures: u32) -> Duration {
+    let doublings = failures.saturating_sub(1).min(16);
+    base.saturating_mul(1 << doublings).min(MAX_NEGATIVE_TTL)
+}
+
+impl DnsResolver {
+    /// A resolver chain in the given order; the system resolver alone when
+    /// `specs` is empty.
+    pub fn new(specs: &[ResolverSpec]) -> Self {
+        Self::with_timeout(specs, NAMESERVER_TIMEOUT)
+    }
+
+    /// Like [`DnsResolver::new`], with `timeout` per nameserver query.
+    pub fn with_timeout(specs: &[ResolverSpec], timeout: Duration) -> Self {
+        let specs = if specs.is_empty() {
+            &[ResolverSpec::System][..]
+        } else {
+            specs
+        };
+        let resolvers = specs
+            .iter()
+            .map(|spec| Resolver {
+                spec: *spec,
+                backend: Backend::new(*spec, timeout, FamilyPolicy::default().strategy()),
+                health: Mutex::default(),
+            })
+            .collect();
+        Self {
+            resolvers,
+            timeout,
+            policy: FamilyPolicy::default(),
+            negative_ttl: None,
+            negative: Mutex::default(),
+            negative_hits: AtomicU64::new(0),
+            known: Mutex::default(),
+            refreshes: Default::default(),
+        }
+    }
+
+    /// Caches failed lookups for `ttl`, doubling on each repeated failure up
+    /// to five minutes. `None` turns negative caching off.
+    pub fn negative_ttl(mut self, ttl: Option<Duration>) -> Self {
+        self.negative_ttl = ttl.filter(|ttl| !ttl.is_zero());
+        self
+    }
+
+    /// Orders or filters answers by address family.
+    pub fn family_policy(mut self, policy: FamilyPolicy) -> Self {
+        self.policy = policy;
+        for resolver in &mut self.resolvers {
+            resolver.backend = Backend::new(resolver.spec, self.timeout, policy.strategy());
+        }
+        self
+    }
+
+    /// Whether this is anything other than the plain system resolver.
+    pub fn is_custom(&self) -> bool {
+        !matches!(
+            self.resolvers.as_slice(),
+            [Resolver {
+                spec: ResolverSpec::System,
+                ..
+            }]
+        )
+    }
+
+    /// Resolves `host`, answering from the negative cache while a recent
+    /// failure for it is still cached.
+    pub async fn lookup(&self, host: &str) -> Result<Vec<IpAddr>, String> {
+        let started = Instant::now();
+        let result = self.lookup_cached(host).await;
+        timings::record_dns(started.elapsed());
+        result
+    }
+
+    /// Re-resolves `host` after a connection to it failed, bypassing the
+    /// negative cache and the nameservers' cached answers, unless that was
+    /// done in the last few seconds.
+    pub async fn refresh(&self, host: &str) -> Refresh {
+        let before = {
+            let mut known = self.known.lock().unwrap();
+            if !known.contains_key(host) && known.len() >= MAX_NEGATIVE_ENTRIES {
+                return Refresh::Skipped;
+            }
+            let entry = known.entry(host.to_string()).or_default();
+            let now = Instant::now();
+            if entry
+                .refreshed
+                .is_some_and(|at| now.duration_since(at) < MIN_REFRESH_INTERVAL)
+            {
+                return Refresh::Skipped;
+            }
+            entry.refreshed = Some(now);
+            entry.ips.clone()
+        };
+        self.negative.lock().unwrap().remove(host);
+        for resolver in &self.resolvers {
+            resolver.backend.clear_cache();
+        }
+
+        let started = Instant::now();
+        let result = self.resolve(host).await;
+        timings::record_dns(started.elapsed());
+        let (refresh, counter) = match result {
+            Err(e) => {
+                warn!("Forced re-resolution of {} failed: {}", host, e);
+                (Refresh::Failed, &self.refreshes[2])
+            }
+            Ok(ips) if !before.is_empty() && before != ips => {
+                info!("{} now resolves to {:?}", host, ips);
+                (Refresh::Changed, &self.refreshes[0])
+            }
+            Ok(_) => (Refresh::Unchanged, &self.refreshes[1]),
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        refresh
+    }
+
+    async fn lookup_cached(&self, host: &str) -> Result<Vec<IpAddr>, String> {
+        let Some(base) = self.negative_ttl else {
+            return self.resolve(host).await;
+        };
+        if let Some(entry) = self.negative.lock().unwrap().get(host) {
+            if Instant::now() < entry.until {
+                self.negative_hits.fetch_add(1, Ordering::Relaxed);
+                return Err(entry.error.clone());
+            }
+        }
+
+        let result = self.resolve(host).await;
+        let mut negative = self.negative.lock().unwrap();
+        match &result {
+            Ok(_) => {
+                negative.remove(host);
+            }
+            Err(error) => {
+                if negative.contains_key(host) || negative.len() < MAX_NEGATIVE_ENTRIES {
+                    let entry = negative
+                        .entry(host.to_string())
+                        .or_insert_with(|| NegativeEntry {
+                            error: String::new(),
+                            failures: 0,
+                            until: Instant::now(),
+                        });
+                    entry.failures += 1;
+                    entry.error = error.clone();
+                    let ttl = backoff(base, entry.failures);
+                    entry.until = Instant::now() + ttl;
+                    info!(
+                        "Caching failed lookup of {} for {}ms ({} failures in a row)",
+                        host,
+                        ttl.as_millis(),
+                        entry.failures
+                    );
+                }
+            }
+        }
+        result
+    }
+
+    /// Resolves `host` with the first healthy resolver that answers.
+    /// Resolvers cooling down are still tried, last, when all else fails.
+    async fn resolve(&self, host: &str) -> Result<Vec<IpAddr>, String> {
+        let now = Instant::now();
+        let (up, down): (Vec<&Resolver>, Vec<&Resolver>) =
+            self.resolvers.iter().partition(|r| !r.is_down(now));
+        let mut last_error = None;
+        for (attempt, resolver) in up.into_iter().chain(down).enumerate() {
+            match resolver.backend.query(host).await {
+                Answer::Found(ips) if !ips.is_empty() => {
+                    resolver.succeeded();
+                    let ips = self.policy.apply(ips);
+                    if ips.is_empty() {
+                        return Err(format!("{host} has no IPv4 addresses"));
+                    }
+                    if attempt > 0 {
+                        info!("Resolved {} with fallback resolver {}", host, resolver.spec);
+                    }
+                    self.remember(host, &ips);
+                    return Ok(ips);
+                }
+                Answer::Found(_) => {
+                    resolver.succeeded();
+                    return Err(format!("{host} has no addresses"));
+                }
+                Answer::NotFound(e) => {
+                    resolver.succeeded();
+                    return Err(e);
+                }
+                Answer::Failed(e) => {
+                    warn!("DNS resolver {} failed for {}: {}", resolver.spec, host, e);
+                    resolver.failed(Instant::now());
+                    last_error = Some(e);
+                }
+            }
+        }
+        Err(last_error.unwrap_or_else(|| format!("no resolver answered for {host}")))
+    }
+
+    fn remember(&self, host: &str, ips: &[IpAddr]) {
+        let mut known = self.known.lock().unwrap();
+        if let Some(entry) = known.get_mut(host) {
+            entry.ips = ips.to_vec();
+        } else if known.len() < MAX_NEGATIVE_ENTRIES {
+            known.insert(
+                host.to_string(),
+                Known {
+                    ips: ips.to_vec(),
+                    refreshed: None,
+                },
+            );
+        }
+    }
+
+    pub fn health(&self) -> Vec<ResolverHealth> {
+        let now = Instant::now();
+        self.resolvers
+            .iter()
+            .map(|resolver| {
+                let up = !resolver.is_down(now);
+                let health = resolver.health.lock().unwrap();
+                ResolverHealth {
+                    resolver: resolver.spec.to_string(),
+                    up,
+                    consecutive_failures: health.consecutive_failures,
+                    successes: health.successes,
+                    failures: health.failures,
+                }
+            })
+            .collect()
+    }
+
+    /// Lookups answered from the negative cache.
+    pub fn negative_cache_hits(&self) -> u64 {
+        self.negative_hits.load(Ordering::Relaxed)
+    }
+
+    /// Resolver health and negative-cache stats in the Prometheus text
+    /// format, appended to `/metrics`.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(
+            out,
+            "proxy_dns_negative_cache_hits_total {}",
+            self.negative_cache_hits()
+        );
+        let _ = writeln!(
+            out,
+            "proxy_dns_negative_cache_entries {}",
+            self.negative.lock().unwrap().len()
+        );
+        for (result, count) in ["changed", "unchanged", "failed"]
+            .into_iter()
+            .zip(&self.refreshes)
+        {
+            let _ = writeln!(
+                out,
+                "proxy_dns_forced_resolutions_total{{result=\"{result}\"}} {}",
+                count.load(Ordering::Relaxed)
+            );
+        }
+        for health in self.health() {
+            let resolver = &health.resolver;
+            let _ = writeln!(
+                out,
+                "proxy_dns_resolver_up{{resolver=\"{resolver}\"}} {}",
+                health.up as u8
+            );
+            let _ = writeln!(
+                out,
+                "proxy_dns_lookups_total{{resolver=\"{resolver}\",result=\"success\"}} {}",
+                health.successes
+            );
+            let _ = writeln!(
+                out,
+                "proxy_dns_lookups_total{{resolver=\"{resolver}\",result=\"failure\"}} {}",
+                health.failures
+            );
+        }
+        out
+    }
+}
+
+/// Plugs a [`DnsResolver`] into the upstream client as is, for when the
+/// egress guard is off.
+pub struct ChainResolver(pub Arc<DnsResolver>);
+
+impl Resolve for ChainResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let dns = self.0.clone();
+        Box::pin(async move {
+            let ips = dns.lookup(name.as_str()).await?;
+            Ok(Box::new(ips.into_iter().map(|ip| SocketAddr::new(ip, 0))) as Addrs)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicU8;
+
+    use super::*;
+
+    #[test]
+    fn parses_resolver_chains() {
+        assert_eq!(
+            parse_resolvers("system, 1.1.1.1,[2606:4700::1111]:5353").unwrap(),
+            [
+                ResolverSpec::System,
+                ResolverSpec::Nameserver("1.1.1.1:53".parse().unwrap()),
+                ResolverSpec::Nameserver("[2606:4700::1111]:5353".parse().unwrap()),
+            ]
+        );
+        assert!(parse_resolvers("system,dns.example").is_err());
+        assert!(!DnsResolver::new(&[]).is_custom());
+    }
+
+    #[test]
+    fn orders_or_filters_answers_by_family() {
+        let v4: IpAddr = "203.0.113.7".parse().unwrap();
+        let v6: IpAddr = "2001:db8::7".parse().unwrap();
+        let other_v4: IpAddr = "203.0.113.8".parse().unwrap();
+        let ips = vec![v6, v4, other_v4];
+
+        assert_eq!(
+            FamilyPolicy::PreferV4.apply(ips.clone()),
+            [v4, other_v4, v6]
+        );
+        assert_eq!(
+            FamilyPolicy::PreferV6.apply(ips.clone()),
+            [v6, v4, other_v4]
+        );
+        assert_eq!(FamilyPolicy::OnlyV4.apply(ips), [v4, other_v4]);
+        assert!(FamilyPolicy::OnlyV4.apply(vec![v6]).is_empty());
+
+        assert_eq!("prefer_v6".parse(), Ok(FamilyPolicy::PreferV6));
+        assert_eq!(FamilyPolicy::OnlyV4.to_string(), "only_v4");
+        assert!("ipv6".parse::<FamilyPolicy>().is_err());
+    }
+
+    #[test]
+    fn backs_off_exponentially_up_to_a_cap() {
+        let base = Duration::from_secs(5);
+        assert_eq!(backoff(base, 1), Duration::from_secs(5));
+        assert_eq!(backoff(base, 2), Duration::from_secs(10));
+        assert_eq!(backoff(base, 4), Duration::from_secs(40));
+        assert_eq!(backoff(base, 7), MAX_NEGATIVE_TTL);
+        assert_eq!(backoff(base, u32::MAX), MAX_NEGATIVE_TTL);
+    }
+
+    #[tokio::test]
+    async fn caches_failed_names_negatively() {
+        let dead = ResolverSpec::Nameserver("127.0.0.1:9".parse().unwrap());
+        let dns = DnsResolver::with_timeout(&[dead], Duration::from_millis(100))
+            .negative_ttl(Some(Duration::from_millis(200)));
+
+        assert!(dns.lookup("gone.supplier.test").await.is_err());
+        assert_eq!(dns.health()[0].failures, 1);
+        // Answered from the cache, without asking the resolver again.
+        assert!(dns.lookup("gone.supplier.test").await.is_err());
+        assert_eq!(dns.health()[0].failures, 1);
+        assert_eq!(dns.negative_cache_hits(), 1);
+
+        // Once the entry expires the name is retried, and cached for longer.
+        tokio::time::sleep(Duration::from_millis(250)).await;
+        assert!(dns.lookup("gone.supplier.test").await.is_err());
+        assert_eq!(dns.health()[0].failures, 2);
+        tokio::time::sleep(Duration::from_millis(250)).await;
+        assert!(dns.lookup("gone.supplier.test").await.is_err());
+        assert_eq!(dns.negative_cache_hits(), 2);
+        assert!(dns
+            .render()
+            .contains("proxy_dns_negative_cache_entries 1\n"));
+    }
+
+    /// A nameserver answering every A query with 203.0.113.7.
+    async fn spawn_nameserver() -> SocketAddr {
+        spawn_nameserver_answering(Arc::new(AtomicU8::new(7))).await
+    }
+
+    /// A nameserver answering every A query with 203.0.113.`last`, with a
+    /// 60s TTL.
+    async fn spawn_nameserver_answering(last: Arc<AtomicU8>) -> SocketAddr {
+        let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0; 512];
+            loop {
+                let (len, peer) = socket.recv_from(&mut buf).await.unwrap();
+                let query = &buf[..len];
+                // Header (12 bytes), then the question: name, type, class.
+                let name_end = 12 + query[12..].iter().position(|b| *b == 0).unwrap() + 1;
+                let is_a = query[name_end..name_end + 2] == [0, 1];
+                let mut reply = query[..name_end + 4].to_vec();
+                reply[2..4].copy_from_slice(&[0x81, 0x80]);
+                reply[6..8].copy_from_slice(&[0, is_a as u8]);
+                reply[8..12].fill(0);
+                if is_a {
+                    reply.extend_from_slice(&[0xc0, 12, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4]);
+                    reply.extend_from_slice(&[203, 0, 113, last.load(Ordering::SeqCst)]);
+                }
+                socket.send_to(&reply, peer).await.unwrap();
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn fails_over_and_skips_a_dead_resolver() {
+        // Nothing listens on the discard port, so queries there fail.
+        let dead = ResolverSpec::Nameserver("127.0.0.1:9".parse().unwrap());
+        let live = ResolverSpec::Nameserver(spawn_nameserver().await);
+        let dns = DnsResolver::with_timeout(&[dead, live], Duration::from_millis(100));
+        assert!(dns.is_custom());
+
+        for _ in 0..FAILURES_BEFORE_DOWN {
+            let ips = dns.lookup("api.supplier.test").await.unwrap();
+            assert_eq!(ips, ["203.0.113.7".parse::<IpAddr>().unwrap()]);
+        }
+        let health = dns.health();
+        assert!(!health[0].up);
+        assert_eq!(health[0].failures, u64::from(FAILURES_BEFORE_DOWN));
+        assert_eq!(health[1].successes, u64::from(FAILURES_BEFORE_DOWN));
+
+        // While it cools down, the dead resolver isn't asked first.
+        dns.lookup("api.supplier.test").await.unwrap();
+        assert_eq!(dns.health()[0].failures, u64::from(FAILURES_BEFORE_DOWN));
+        assert!(dns
+            .render()
+            .contains("proxy_dns_resolver_up{resolver=\"127.0.0.1:9\"} 0"));
+    }
+
+    #[tokio::test]
+    async fn refresh_bypasses_cached_answers() {
+        let last = Arc::new(AtomicU8::new(7));
+        let nameserver = ResolverSpec::Nameserver(spawn_nameserver_answering(last.clone()).await);
+        let dns = DnsResolver::with_timeout(&[nameserver], Duration::from_millis(500));
+        let ip = |last| IpAddr::from([203, 0, 113, last]);
+
+        assert_eq!(dns.lookup("api.supplier.test").await.unwrap(), [ip(7)]);
+        // The supplier moves; the answer is still within its TTL.
+        last.store(8, Ordering::SeqCst);
+        assert_eq!(dns.lookup("api.supplier.test").await.unwrap(), [ip(7)]);
+
+        assert_eq!(dns.refresh("api.supplier.test").await, Refresh::Changed);
+        assert_eq!(dns.lookup("api.supplier.test").await.unwrap(), [ip(8)]);
+        // A burst of failures re-resolves once.
+        assert_eq!(dns.refresh("api.supplier.test").await, Refresh::Skipped);
+        assert_eq!(dns.refresh("other.supplier.test").await, Refresh::Unchanged);
+        let metrics = dns.render();
+        assert!(metrics.contains("proxy_dns_forced_resolutions_total{result=\"changed\"} 1\n"));
+        assert!(metrics.contains("proxy_dns_forced_resolutions_total{result=\"unchanged\"} 1\n"));
+    }
+}
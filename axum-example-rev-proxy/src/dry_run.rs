@@ -0,0 +1,144 @@
+//! Dry runs for debugging signing and header rewriting: a request with
+//! `X-Proxy-Dry-Run: 1` and the admin token
+//! ([`crate::admin::ADMIN_TOKEN_HEADER`]) is answered with exactly what the
+//! proxy would have sent upstream (final URI, headers after the interceptors
+//! ran, transformed body) and is never sent.
+//!
+//! Dry runs skip the cache and leave failover health alone. Every use is
+//! written to the `audit` log target.
+
+use std::collections::BTreeMap;
+
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use hyper::header::{HeaderMap, HeaderValue};
+use serde::Serialize;
+use tracing::warn;
+
+use crate::error::ProxyError;
+use crate::interceptor::OutboundRequest;
+use crate::rbac::{Principal, Role};
+
+pub const DRY_RUN_HEADER: &str = "x-proxy-dry-run";
+
+/// What the upstream would have received.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DryRun {
+    pub method: String,
+    pub uri: String,
+    /// Repeated headers are joined with `, `.
+    pub headers: BTreeMap<String, String>,
+    pub body: String,
+    /// `utf-8`, or `hex` for bodies that aren't text.
+    pub body_encoding: &'static str,
+    /// Whether the egress policy would have let the request out.
+    pub egress_permitted: bool,
+}
+
+impl DryRun {
+    pub fn new(outbound: &OutboundRequest, egress_permitted: bool) -> Self {
+        let mut headers: BTreeMap<String, String> = BTreeMap::new();
+        for (name, value) in &outbound.headers {
+            let value = String::from_utf8_lossy(value.as_bytes());
+            headers
+                .entry(name.to_string())
+                .and_modify(|existing| {
+                    existing.push_str(", ");
+                    existing.push_str(&value);
+                })
+                .or_insert_with(|| value.into_owned());
+        }
+        let (body, body_encoding) = match std::str::from_utf8(&outbound.body) {
+            Ok(text) => (text.to_string(), "utf-8"),
+            Err(_) => (hex::encode(&outbound.body), "hex"),
+        };
+        Self {
+            method: outbound.method.to_string(),
+            uri: outbound.uri.to_string(),
+            headers,
+            body,
+            body_encoding,
+            egress_permitted,
+        }
+    }
+
+    pub fn into_response(self) -> Response {
+        let mut response = Json(self).into_response();
+        response
+            .headers_mut()
+            .insert(DRY_RUN_HEADER, HeaderValue::from_static("1"));
+        response
+    }
+}
+
+/// Removes the dry-run header, returning whether a dry run was asked for by
+/// an operator.
+pub fn take(
+    headers: &mut HeaderMap,
+    route: &str,
+    principal: Option<&Principal>,
+) -> Result<bool, ProxyError> {
+    let requested = headers
+        .remove(DRY_RUN_HEADER)
+        .is_some_and(|value| value == "1" || value.as_bytes().eq_ignore_ascii_case(b"true"));
+    if !requested {
+        return Ok(false);
+    }
+    let Some(principal) = principal.filter(|p| p.role >= Role::Operator) else {
+        warn!(
+            target: "audit",
+            "Refused a dry run of {}: needs an operator's admin token",
+            route
+        );
+        return Err(ProxyError::DryRunRefused);
+    };
+    warn!(target: "audit", "{} dry-ran {}", principal, route);
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::Method;
+
+    use super::*;
+
+    #[test]
+    fn shows_the_request_as_it_would_be_sent() {
+        let mut headers = HeaderMap::new();
+        headers.append("x-signature", HeaderValue::from_static("abc"));
+        headers.append("accept", HeaderValue::from_static("text/xml"));
+        headers.append("accept", HeaderValue::from_static("application/json"));
+        let outbound = OutboundRequest {
+            env: "test".to_string(),
+            method: Method::POST,
+            uri: "http://supplier/api/book?x=1".parse().unwrap(),
+            headers,
+            body: vec![0xff, 0x00].into(),
+        };
+        let dry_run = DryRun::new(&outbound, true);
+        assert_eq!(dry_run.uri, "http://supplier/api/book?x=1");
+        assert_eq!(dry_run.headers["accept"], "text/xml, application/json");
+        assert_eq!(dry_run.headers["x-signature"], "abc");
+        assert_eq!(
+            (dry_run.body.as_str(), dry_run.body_encoding),
+            ("ff00", "hex")
+        );
+    }
+
+    #[test]
+    fn needs_authorization() {
+        let principal = |role| Principal {
+            name: "oncall".to_string(),
+            role,
+        };
+        let mut headers = HeaderMap::new();
+        headers.insert(DRY_RUN_HEADER, HeaderValue::from_static("1"));
+        assert!(take(&mut headers.clone(), "/test/api", None).is_err());
+        let viewer = principal(Role::Viewer);
+        assert!(take(&mut headers.clone(), "/test/api", Some(&viewer)).is_err());
+        let operator = principal(Role::Operator);
+        assert!(take(&mut headers, "/test/api", Some(&operator)).unwrap());
+        assert!(headers.is_empty());
+        assert!(!take(&mut headers, "/test/api", None).unwrap());
+    }
+}
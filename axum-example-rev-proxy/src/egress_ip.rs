@@ -0,0 +1,257 @@
+//! Discovery of the public IP outbound requests leave from, checked against
+//! the static IP upstreams have allowlisted.
+
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use axum::extract::State;
+use axum::Json;
+use hyper::StatusCode;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::task::JoinHandle;
+use tracing::{error, info, warn};
+
+use crate::app_state::AppState;
+use crate::metrics::Metrics;
+
+pub const DEFAULT_ECHO_URL: &str = "https://api.ipify.org";
+
+/// Where the egress IP comes from.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub enum EgressIpSource {
+    /// A service that answers with the caller's IP as plain text.
+    Echo(String),
+    /// The local address of the default route, for hosts whose interface
+    /// holds the public IP directly.
+    Interface,
+}
+
+impl EgressIpSource {
+    /// `interface`, or the URL of an IP echo service.
+    pub fn parse(value: &str) -> Self {
+        match value {
+            "interface" => Self::Interface,
+            url => Self::Echo(url.to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct EgressIpConfig {
+    pub source: EgressIpSource,
+    /// The static IP upstreams expect to see.
+    pub expected: Option<IpAddr>,
+    /// How long a discovered IP is reused before asking again.
+    pub cache_ttl: Duration,
+    /// How often the drift monitor re-checks the IP when `expected` is set.
+    pub check_interval: Duration,
+    /// Receives `{"text": ...}` (Slack-compatible) when drift is detected
+    /// and when it clears.
+    pub alert_webhook_url: Option<String>,
+}
+
+impl Default for EgressIpConfig {
+    fn default() -> Self {
+        Self {
+            source: EgressIpSource::Echo(DEFAULT_ECHO_URL.to_string()),
+            expected: None,
+            cache_ttl: Duration::from_secs(300),
+            check_interval: Duration::from_secs(60),
+            alert_webhook_url: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct EgressIpReport {
+    pub ip: IpAddr,
+    pub expected: Option<IpAddr>,
+    /// `None` when no expected IP is configured.
+    pub matches: Option<bool>,
+}
+
+pub struct EgressIp {
+    config: EgressIpConfig,
+    cached: Mutex<Option<(Instant, IpAddr)>>,
+    /// Set by the drift monitor while the IP differs from the expected one.
+    drifted: AtomicBool,
+}
+
+impl EgressIp {
+    pub fn new(config: EgressIpConfig) -> Self {
+        Self {
+            config,
+            cached: Mutex::new(None),
+            drifted: AtomicBool::new(false),
+        }
+    }
+
+    pub fn config(&self) -> &EgressIpConfig {
+        &self.config
+    }
+
+    /// Whether the last drift check saw an IP other than the expected one.
+    pub fn is_drifted(&self) -> bool {
+        self.drifted.load(Ordering::Relaxed)
+    }
+
+    /// The current egress IP, discovered through `client` unless a recent
+    /// answer is cached.
+    pub async fn current(&self, client: &reqwest::Client) -> Result<IpAddr, String> {
+        if let Some((at, ip)) = *self.cached.lock().unwrap() {
+            if at.elapsed() < self.config.cache_ttl {
+                return Ok(ip);
+            }
+        }
+        self.discover(client).await
+    }
+
+    /// Asks the source for the IP, bypassing and then refreshing the cache.
+    pub async fn discover(&self, client: &reqwest::Client) -> Result<IpAddr, String> {
+        let ip = match &self.config.source {
+            EgressIpSource::Echo(url) => discover_via_echo(client, url).await?,
+            EgressIpSource::Interface => discover_via_interface().await?,
+        };
+        *self.cached.lock().unwrap() = Some((Instant::now(), ip));
+        Ok(ip)
+    }
+
+    /// Discovers the IP and compares it with the expected one, logging an
+    /// error on divergence.
+    pub async fn report(&self, client: &reqwest::Client) -> Result<EgressIpReport, String> {
+        let ip = self.current(client).await?;
+        let matches = self.config.expected.map(|expected| expected == ip);
+        if matches == Some(false) {
+            error!(
+                "Egress IP {} differs from the expected static IP {}",
+                ip,
+                self.config.expected.unwrap()
+            );
+        }
+        Ok(EgressIpReport {
+            ip,
+            expected: self.config.expected,
+            matches,
+        })
+    }
+
+    /// One drift check: rediscovers the IP, counts a mismatch, and alerts
+    /// when the drift state changes. Discovery failures leave the state as is.
+    pub async fn check(&self, client: &reqwest::Client, metrics: &Metrics) {
+        let Some(expected) = self.config.expected else {
+            return;
+        };
+        let ip = match self.discover(client).await {
+            Ok(ip) => ip,
+            Err(e) => {
+                warn!("Egress IP drift check failed: {}", e);
+                return;
+            }
+        };
+
+        let drifted = ip != expected;
+        if drifted {
+            metrics.record_egress_ip_mismatch();
+        }
+        if self.drifted.swap(drifted, Ordering::Relaxed) == drifted {
+            return;
+        }
+        let message = if drifted {
+            error!(
+                "Egress IP drifted to {} (expected {}); marking unready",
+                ip, expected
+            );
+            format!("egress proxy: egress IP is {ip}, expected static IP {expected}")
+        } else {
+            info!("Egress IP is back to the expected {}", expected);
+            format!("egress proxy: egress IP is back to the expected {expected}.")
+        };
+        self.alert(client, &message).await;
+    }
+
+    async fn alert(&self, client: &reqwest::Client, message: &str) {
+        let Some(url) = &self.config.alert_webhook_url else {
+            return;
+        };
+        let result = client
+            .post(url)
+            .json(&json!({ "text": message }))
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+        if let Err(e) = result {
+            error!("Failed to send egress IP alert: {}", e);
+        }
+    }
+}
+
+/// Starts the drift monitor if an expected egress IP is configured.
+pub fn spawn_drift_monitor(state: &AppState) -> Option<JoinHandle<()>> {
+    let egress_ip = state.egress_ip.clone();
+    egress_ip.config.expected?;
+    let (client, metrics) = (state.client.clone(), state.metrics.clone());
+    Some(tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(egress_ip.config.check_interval);
+        loop {
+            ticker.tick().await;
+            egress_ip.check(&client, &metrics).await;
+        }
+    }))
+}
+
+async fn discover_via_echo(client: &reqwest::Client, url: &str) -> Result<IpAddr, String> {
+    let response = client
+        .get(url)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| format!("egress IP echo request failed: {e}"))?;
+    let body = response
+        .text()
+        .await
+        .map_err(|e| format!("failed to read egress IP echo response: {e}"))?;
+    body.trim()
+        .parse()
+        .map_err(|_| format!("egress IP echo returned {:?}, not an IP", body.trim()))
+}
+
+/// Connecting a UDP socket sends nothing but makes the OS pick the source
+/// address of the default route.
+async fn discover_via_interface() -> Result<IpAddr, String> {
+    let socket = tokio::net::UdpSocket::bind("0.0.0.0:0")
+        .await
+        .map_err(|e| e.to_string())?;
+    socket
+        .connect("192.0.2.1:9")
+        .await
+        .map_err(|e| format!("no default route: {e}"))?;
+    Ok(socket.local_addr().map_err(|e| e.to_string())?.ip())
+}
+
+/// `GET /readyz` is 503 while the egress IP has drifted from the expected one.
+pub async fn readyz_handler(State(state): State<AppState>) -> (StatusCode, &'static str) {
+    if state.egress_ip.is_drifted() {
+        (StatusCode::SERVICE_UNAVAILABLE, "egress IP drifted")
+    } else {
+        (StatusCode::OK, "ready")
+    }
+}
+
+/// `GET /egress-ip` reports the current egress IP and whether it matches
+/// `EXPECTED_EGRESS_IP`.
+pub async fn egress_ip_handler(
+    State(state): State<AppState>,
+) -> Result<Json<EgressIpReport>, StatusCode> {
+    state
+        .egress_ip
+        .report(&state.client)
+        .await
+        .map(Json)
+        .map_err(|e| {
+            error!("Egress IP discovery failed: {}", e);
+            StatusCode::BAD_GATEWAY
+        })
+}
@@ -0,0 +1,164 @@
+//! Egress policy: outbound requests may only reach the configured upstreams,
+//! and hostnames may not resolve to private, loopback or link-local
+//! addresses (so a rebound DNS record can't point the proxy inward).
+
+use std::collections::BTreeSet;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+
+use hyper::Uri;
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use tracing::warn;
+
+use crate::dns::DnsResolver;
+use crate::metrics::Metrics;
+
+/// Host/port pairs the proxy is allowed to connect to.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EgressPolicy {
+    allowed: BTreeSet<(String, u16)>,
+}
+
+impl EgressPolicy {
+    /// Allows exactly the authorities of `upstreams` (base URLs).
+    pub fn for_upstreams<'a>(upstreams: impl IntoIterator<Item = &'a str>) -> Self {
+        let allowed = upstreams
+            .into_iter()
+            .filter_map(|url| url.parse::<Uri>().ok())
+            .filter_map(|uri| destination(&uri))
+            .collect();
+        Self { allowed }
+    }
+
+    /// Whether `uri` points at a configured upstream.
+    pub fn permits(&self, uri: &Uri) -> bool {
+        destination(uri).is_some_and(|dest| self.allowed.contains(&dest))
+    }
+}
+
+/// Lower-cased host and effective port, for http(s) URIs only.
+fn destination(uri: &Uri) -> Option<(String, u16)> {
+    let default_port = match uri.scheme_str()? {
+        "http" => 80,
+        "https" => 443,
+        _ => return None,
+    };
+    let host = uri.host()?.to_ascii_lowercase();
+    Some((host, uri.port_u16().unwrap_or(default_port)))
+}
+
+/// Addresses a public hostname should never resolve to.
+pub fn is_internal(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_internal_v4(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(v4) => is_internal_v4(v4),
+            None => is_internal_v6(ip),
+        },
+    }
+}
+
+fn is_internal_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    ip.is_private()
+        || ip.is_loopback()
+        || ip.is_link_local()
+        || ip.is_unspecified()
+        || ip.is_broadcast()
+        || ip.is_multicast()
+        // Carrier-grade NAT, 100.64.0.0/10.
+        || (a == 100 && (b & 0xc0) == 64)
+}
+
+fn is_internal_v6(ip: Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+    ip.is_loopback()
+        || ip.is_unspecified()
+        || ip.is_multicast()
+        // Unique local fc00::/7 and link-local fe80::/10.
+        || (first & 0xfe00) == 0xfc00
+        || (first & 0xffc0) == 0xfe80
+}
+
+/// DNS resolver for the upstream client that drops internal addresses.
+/// Upstreams configured by IP literal never reach the resolver and are
+/// trusted as configured.
+pub struct GuardedResolver {
+    metrics: Arc<Metrics>,
+    dns: Arc<DnsResolver>,
+}
+
+impl GuardedResolver {
+    pub fn new(metrics: Arc<Metrics>, dns: Arc<DnsResolver>) -> Self {
+        Self { metrics, dns }
+    }
+}
+
+impl Resolve for GuardedResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let metrics = self.metrics.clone();
+        let dns = self.dns.clone();
+        Box::pin(async move {
+            let host = name.as_str().to_string();
+            let resolved: Vec<SocketAddr> = dns
+                .lookup(&host)
+                .await?
+                .into_iter()
+                .map(|ip| SocketAddr::new(ip, 0))
+                .collect();
+            let public: Vec<SocketAddr> = resolved
+                .iter()
+                .copied()
+                .filter(|addr| !is_internal(addr.ip()))
+                .collect();
+            if public.len() < resolved.len() {
+                warn!(
+                    "Egress policy dropped internal addresses for {}: {:?}",
+                    host, resolved
+                );
+                metrics.record_egress_violation("internal_address");
+            }
+            if public.is_empty() {
+                return Err(format!("{host} resolves only to internal addresses").into());
+            }
+            Ok(Box::new(public.into_iter()) as Addrs)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn permits_only_configured_authorities() {
+        let policy =
+            EgressPolicy::for_upstreams(["https://API.example.com", "http://10.0.0.5:8080/base"]);
+
+        assert!(policy.permits(&"https://api.example.com/v1/hotels".parse().unwrap()));
+        assert!(policy.permits(&"https://api.example.com:443/".parse().unwrap()));
+        assert!(policy.permits(&"http://10.0.0.5:8080/x".parse().unwrap()));
+        assert!(!policy.permits(&"http://api.example.com/".parse().unwrap()));
+        assert!(!policy.permits(&"https://evil.example.com/".parse().unwrap()));
+        assert!(!policy.permits(&"http://169.254.169.254/latest".parse().unwrap()));
+    }
+
+    #[test]
+    fn classifies_internal_addresses() {
+        for internal in [
+            "10.1.2.3",
+            "127.0.0.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "::1",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:192.168.0.1",
+        ] {
+            assert!(is_internal(internal.parse().unwrap()), "{internal}");
+        }
+        for public in ["203.0.113.7", "8.8.8.8", "2606:4700::1111"] {
+            assert!(!is_internal(public.parse().unwrap()), "{public}");
+        }
+    }
+}
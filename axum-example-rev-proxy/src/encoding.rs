@@ -0,0 +1,217 @@
+//! Content-coding helpers for paths that need to look inside bodies
+//! (logging, transforms), and the switch for the upstream client's own
+//! decompression.
+//!
+//! By default bodies are forwarded as the upstream sent them, with their
+//! `Content-Encoding`, and the caller's `Accept-Encoding` is passed along.
+//! With `UPSTREAM_DECOMPRESS=true` the client decodes gzip, deflate, brotli
+//! and zstd itself: it advertises them upstream in place of the caller's
+//! `Accept-Encoding`, and drops `Content-Encoding` and `Content-Length` from
+//! what it decodes, so callers get the identity body with its own length.
+
+use std::io::{self, Read};
+
+use axum::body::Bytes;
+use flate2::read::{DeflateDecoder, GzDecoder, ZlibDecoder};
+use hyper::header::{self, HeaderMap};
+use reqwest::ClientBuilder;
+
+/// Undoes the `Content-Encoding` in `headers`, including stacked codings
+/// like `gzip, br`. Identity bodies are returned unchanged; unsupported
+/// codings, and bodies that inflate past `limit` bytes, are an error.
+pub fn decode_body(headers: &HeaderMap, body: &Bytes, limit: usize) -> Result<Bytes, String> {
+    let codings = content_codings(headers)?;
+    // Codings are listed in the order they were applied.
+    let mut decoded = body.clone();
+    for coding in codings.iter().rev() {
+        decoded = decode_one(coding, &decoded, limit)?;
+    }
+    Ok(decoded)
+}
+
+/// Decodes `body` for a transform that replaces it, and drops the
+/// `Content-Encoding`/`Content-Length` headers that described the encoded form.
+/// The caller sets `Content-Length` for whatever body it ends up sending.
+pub fn decode_in_place(
+    headers: &mut HeaderMap,
+    body: &mut Bytes,
+    limit: usize,
+) -> Result<(), String> {
+    *body = decode_body(headers, body, limit)?;
+    headers.remove(header::CONTENT_ENCODING);
+    headers.remove(header::CONTENT_LENGTH);
+    Ok(())
+}
+
+/// Sets whether a client decodes compressed upstream bodies itself.
+pub fn configure_client(builder: ClientBuilder, decompress: bool) -> ClientBuilder {
+    builder
+        .gzip(decompress)
+        .deflate(decompress)
+        .brotli(decompress)
+        .zstd(decompress)
+}
+
+/// Drops the caller's `Accept-Encoding` when the client decompresses, so
+/// the upstream is only offered codings the proxy can decode.
+pub fn prepare_accept_encoding(headers: &mut HeaderMap, decompress: bool) {
+    if decompress {
+        headers.remove(header::ACCEPT_ENCODING);
+    }
+}
+
+fn content_codings(headers: &HeaderMap) -> Result<Vec<String>, String> {
+    let mut codings = Vec::new();
+    for value in headers.get_all(header::CONTENT_ENCODING) {
+        let value = value
+            .to_str()
+            .map_err(|_| "invalid Content-Encoding header".to_string())?;
+        codings.extend(
+            value
+                .split(',')
+                .map(|coding| coding.trim().to_ascii_lowercase())
+                .filter(|coding| !coding.is_empty() && coding != "identity"),
+        );
+    }
+    Ok(codings)
+}
+
+fn decode_one(coding: &str, body: &[u8], limit: usize) -> Result<Bytes, String> {
+    let mut decoded = Vec::new();
+    let result = match coding {
+        "gzip" | "x-gzip" => read_capped(GzDecoder::new(body), limit, &mut decoded),
+        // RFC 9110 `deflate` is zlib-wrapped, but some servers send raw deflate.
+        "deflate" => read_capped(ZlibDecoder::new(body), limit, &mut decoded).or_else(|e| {
+            if e.kind() == io::ErrorKind::FileTooLarge {
+                return Err(e);
+            }
+            decoded.clear();
+            read_capped(DeflateDecoder::new(body), limit, &mut decoded)
+        }),
+        "br" => read_capped(brotli::Decompressor::new(body, 4096), limit, &mut decoded),
+        "zstd" => zstd::stream::read::Decoder::new(body)
+            .and_then(|decoder| read_capped(decoder, limit, &mut decoded)),
+        other => return Err(format!("unsupported content-coding {other}")),
+    };
+    result.map_err(|e| format!("undecodable {coding}: {e}"))?;
+    Ok(Bytes::from(decoded))
+}
+
+/// Reads `reader` to the end, failing once it yields more than `limit`
+/// bytes so a small compression bomb can't inflate without bound.
+fn read_capped(reader: impl Read, limit: usize, out: &mut Vec<u8>) -> io::Result<()> {
+    reader.take(limit as u64 + 1).read_to_end(out)?;
+    if out.len() > limit {
+        return Err(io::Error::new(
+            io::ErrorKind::FileTooLarge,
+            format!("decodes to over {limit} bytes"),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::{GzEncoder, ZlibEncoder};
+    use flate2::Compression;
+    use hyper::header::HeaderValue;
+    use std::io::Write;
+
+    const LIMIT: usize = 1024;
+
+    fn gzip(body: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(body).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn brotli(body: &[u8]) -> Vec<u8> {
+        let mut encoded = Vec::new();
+        let mut writer = brotli::CompressorWriter::new(&mut encoded, 4096, 5, 22);
+        writer.write_all(body).unwrap();
+        drop(writer);
+        encoded
+    }
+
+    fn with_encoding(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_ENCODING, value.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn decodes_gzip_and_passes_identity_through() {
+        let gzipped = Bytes::from(gzip(b"hello"));
+
+        assert_eq!(
+            decode_body(&HeaderMap::new(), &gzipped, LIMIT).unwrap(),
+            gzipped
+        );
+        assert_eq!(
+            decode_body(&with_encoding("gzip"), &gzipped, LIMIT).unwrap(),
+            "hello"
+        );
+        assert!(decode_body(&with_encoding("compress"), &gzipped, LIMIT).is_err());
+    }
+
+    #[test]
+    fn decodes_br_deflate_zstd_and_stacked_codings() {
+        let br = Bytes::from(brotli(b"hello"));
+        assert_eq!(
+            decode_body(&with_encoding("br"), &br, LIMIT).unwrap(),
+            "hello"
+        );
+
+        let mut zlib = ZlibEncoder::new(Vec::new(), Compression::default());
+        zlib.write_all(b"hello").unwrap();
+        let deflate = Bytes::from(zlib.finish().unwrap());
+        assert_eq!(
+            decode_body(&with_encoding("deflate"), &deflate, LIMIT).unwrap(),
+            "hello"
+        );
+
+        let zstd = Bytes::from(zstd::encode_all(&b"hello"[..], 3).unwrap());
+        assert_eq!(
+            decode_body(&with_encoding("zstd"), &zstd, LIMIT).unwrap(),
+            "hello"
+        );
+
+        // gzip applied first, then br.
+        let stacked = Bytes::from(brotli(&gzip(b"hello")));
+        let mut headers = with_encoding("gzip, br");
+        headers.insert(header::CONTENT_LENGTH, HeaderValue::from(stacked.len()));
+        let mut body = stacked;
+        decode_in_place(&mut headers, &mut body, LIMIT).unwrap();
+        assert_eq!(body, "hello");
+        assert!(headers.get(header::CONTENT_ENCODING).is_none());
+        assert!(headers.get(header::CONTENT_LENGTH).is_none());
+    }
+
+    #[test]
+    fn refuses_bodies_that_inflate_past_the_limit() {
+        let bomb = vec![0; LIMIT * 64];
+        let gzipped = Bytes::from(gzip(&bomb));
+        assert!(gzipped.len() < LIMIT);
+        let error = decode_body(&with_encoding("gzip"), &gzipped, LIMIT).unwrap_err();
+        assert!(error.contains("decodes to over 1024 bytes"), "{error}");
+
+        let br = Bytes::from(brotli(&bomb));
+        assert!(decode_body(&with_encoding("br"), &br, LIMIT).is_err());
+        let zstd = Bytes::from(zstd::encode_all(&bomb[..], 3).unwrap());
+        assert!(decode_body(&with_encoding("zstd"), &zstd, LIMIT).is_err());
+        let mut zlib = ZlibEncoder::new(Vec::new(), Compression::default());
+        zlib.write_all(&bomb).unwrap();
+        let deflate = Bytes::from(zlib.finish().unwrap());
+        assert!(decode_body(&with_encoding("deflate"), &deflate, LIMIT).is_err());
+
+        // Exactly at the limit is fine.
+        let full = Bytes::from(gzip(&bomb[..LIMIT]));
+        assert_eq!(
+            decode_body(&with_encoding("gzip"), &full, LIMIT)
+                .unwrap()
+                .len(),
+            LIMIT
+        );
+    }
+}
@@ -0,0 +1,291 @@
+//! Why a proxied request failed. Each [`ProxyError`] maps to the status sent
+//! to the caller, a stable machine-readable code (also the metrics label),
+//! and whether retrying the same request may succeed.
+
+use std::error::Error as _;
+
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use hyper::header::{self, HeaderValue};
+use hyper::StatusCode;
+use serde_json::json;
+use thiserror::Error;
+
+use crate::server::ReadTimeout;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+pub enum ProxyError {
+    #[error("no upstream is configured for this environment")]
+    UnknownEnv,
+    #[error("the upstream URI could not be built")]
+    InvalidTarget,
+    #[error("the request path is malformed or escapes the upstream root")]
+    InvalidPath,
+    #[error("the request body exceeds the size limit")]
+    BodyTooLarge,
+    #[error("the request body stalled")]
+    BodyTimeout,
+    #[error("the request body could not be read")]
+    BodyRead,
+    #[error("the request headers exceed the size limits")]
+    HeadersTooLarge,
+    #[error("the request's framing or routing headers are ambiguous")]
+    AmbiguousRequest,
+    #[error("the request's expectation can't be met")]
+    ExpectationFailed,
+    #[error("the destination is not a configured upstream")]
+    EgressRefused,
+    #[error("the target override needs the admin token")]
+    OverrideRefused,
+    #[error("a dry run needs the admin token")]
+    DryRunRefused,
+    #[error("the tenant API key is missing or wrong")]
+    TenantUnauthorized,
+    #[error("the target override names no configured upstream of this environment")]
+    UnknownTarget,
+    #[error("the request waited too long for an upstream slot")]
+    QueueTimeout,
+    #[error("the proxy is buffering too many bytes to take this body")]
+    MemoryBudget,
+    /// The upstream answered, but buffering its response would exceed the
+    /// memory budget.
+    #[error("the upstream response is too large to buffer right now")]
+    UpstreamBodyOverBudget,
+    /// Traffic to the env is paused for this many more seconds after too
+    /// many upstream failures.
+    #[error("traffic to this upstream is paused after repeated failures")]
+    Paused(u64),
+    /// The env is in a maintenance window for this many more seconds.
+    #[error("the upstream is in a scheduled maintenance window")]
+    Maintenance(u64),
+    #[error("the request could not be stored for delivery")]
+    StoreUnavailable,
+    #[error("the upstream did not answer in time")]
+    UpstreamTimeout,
+    #[error("the upstream hostname did not resolve")]
+    UpstreamDns,
+    #[error("the upstream refused or dropped the connection")]
+    UpstreamConnection,
+    #[error("the upstream request failed")]
+    Upstream,
+    #[error("the upstream response could not be read")]
+    UpstreamBody,
+    #[error("the upstream answered with an API version other than the pinned one")]
+    ApiVersionMismatch,
+    /// An interceptor rejected the request or response with this status.
+    #[error("rejected with {0}")]
+    Rejected(StatusCode),
+    /// An admin-set fault rule answered in place of the upstream.
+    #[error("fault injected for testing")]
+    InjectedFault(StatusCode),
+}
+
+impl ProxyError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::UnknownEnv
+            | Self::InvalidPath
+            | Self::BodyRead
+            | Self::AmbiguousRequest
+            | Self::UnknownTarget => StatusCode::BAD_REQUEST,
+            Self::TenantUnauthorized => StatusCode::UNAUTHORIZED,
+            Self::BodyTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            Self::BodyTimeout => StatusCode::REQUEST_TIMEOUT,
+            Self::HeadersTooLarge => StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+            Self::ExpectationFailed => StatusCode::EXPECTATION_FAILED,
+            Self::EgressRefused | Self::OverrideRefused | Self::DryRunRefused => {
+                StatusCode::FORBIDDEN
+            }
+            Self::QueueTimeout
+            | Self::MemoryBudget
+            | Self::UpstreamBodyOverBudget
+            | Self::Paused(_)
+            | Self::Maintenance(_)
+            | Self::StoreUnavailable => StatusCode::SERVICE_UNAVAILABLE,
+            Self::UpstreamTimeout => StatusCode::GATEWAY_TIMEOUT,
+            Self::InvalidTarget
+            | Self::UpstreamDns
+            | Self::UpstreamConnection
+            | Self::Upstream
+            | Self::UpstreamBody
+            | Self::ApiVersionMismatch => StatusCode::BAD_GATEWAY,
+            Self::Rejected(status) | Self::InjectedFault(status) => *status,
+        }
+    }
+
+    /// Stable identifier for clients and metrics.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::UnknownEnv => "unknown_env",
+            Self::InvalidTarget => "invalid_target",
+            Self::InvalidPath => "invalid_path",
+            Self::BodyTooLarge => "body_too_large",
+            Self::BodyTimeout => "body_timeout",
+            Self::BodyRead => "body_read",
+            Self::HeadersTooLarge => "headers_too_large",
+            Self::AmbiguousRequest => "ambiguous_request",
+            Self::ExpectationFailed => "expectation_failed",
+            Self::EgressRefused => "egress_refused",
+            Self::OverrideRefused => "override_refused",
+            Self::DryRunRefused => "dry_run_refused",
+            Self::TenantUnauthorized => "tenant_unauthorized",
+            Self::UnknownTarget => "unknown_target",
+            Self::QueueTimeout => "queue_timeout",
+            Self::MemoryBudget => "memory_budget",
+            Self::UpstreamBodyOverBudget => "upstream_body_over_budget",
+            Self::Paused(_) => "upstream_paused",
+            Self::Maintenance(_) => "maintenance",
+            Self::StoreUnavailable => "store_unavailable",
+            Self::UpstreamTimeout => "upstream_timeout",
+            Self::UpstreamDns => "upstream_dns",
+            Self::UpstreamConnection => "upstream_connection",
+            Self::Upstream => "upstream_error",
+            Self::UpstreamBody => "upstream_body",
+            Self::ApiVersionMismatch => "upstream_version_mismatch",
+            Self::Rejected(_) => "rejected",
+            Self::InjectedFault(_) => "injected_fault",
+        }
+    }
+
+    /// Whether sending the same request again may succeed. Timeouts may have
+    /// reached the supplier, so callers should still only retry idempotent
+    /// calls on those. A response shed over the memory budget was already
+    /// acted on by the supplier. Injected faults act like the supplier
+    /// failing.
+    pub fn retryable(&self) -> bool {
+        match self {
+            Self::InjectedFault(status) => status.is_server_error(),
+            _ => matches!(
+                self,
+                Self::BodyTimeout
+                    | Self::QueueTimeout
+                    | Self::MemoryBudget
+                    | Self::Paused(_)
+                    | Self::Maintenance(_)
+                    | Self::StoreUnavailable
+                    | Self::UpstreamTimeout
+                    | Self::UpstreamDns
+                    | Self::UpstreamConnection
+                    | Self::UpstreamBody
+            ),
+        }
+    }
+
+    /// Whether any of the request may have been sent upstream. Only failures
+    /// before the connection was made are sure it wasn't; injected faults act
+    /// like the supplier failing, and an interceptor may have rejected the
+    /// response.
+    pub fn may_have_reached_upstream(&self) -> bool {
+        matches!(
+            self,
+            Self::UpstreamTimeout
+                | Self::Upstream
+                | Self::UpstreamBody
+                | Self::UpstreamBodyOverBudget
+                | Self::ApiVersionMismatch
+                | Self::Rejected(_)
+                | Self::InjectedFault(_)
+        )
+    }
+
+    /// Whether the upstream itself failed, as opposed to the request or
+    /// the proxy.
+    pub fn is_upstream_failure(&self) -> bool {
+        matches!(
+            self,
+            Self::UpstreamTimeout
+                | Self::UpstreamDns
+                | Self::UpstreamConnection
+                | Self::Upstream
+                | Self::UpstreamBody
+        )
+    }
+
+    /// Classifies a failed upstream call.
+    pub fn from_upstream(e: &reqwest::Error) -> Self {
+        if e.is_timeout() {
+            return Self::UpstreamTimeout;
+        }
+        if e.is_connect() {
+            // hyper-util reports resolver failures as a connect error whose
+            // source chain mentions "dns error".
+            let mut source = e.source();
+            while let Some(err) = source {
+                if err.to_string().contains("dns error") {
+                    return Self::UpstreamDns;
+                }
+                source = err.source();
+            }
+            return Self::UpstreamConnection;
+        }
+        Self::Upstream
+    }
+
+    /// Classifies a failure reading the inbound request body.
+    pub fn from_request_body(e: &axum::Error) -> Self {
+        let mut source: Option<&(dyn std::error::Error + 'static)> = Some(e);
+        while let Some(err) = source {
+            if err.is::<http_body_util::LengthLimitError>() {
+                return Self::BodyTooLarge;
+            }
+            if err.is::<ReadTimeout>() {
+                return Self::BodyTimeout;
+            }
+            source = err.source();
+        }
+        Self::BodyRead
+    }
+
+    /// The JSON error body returned to callers.
+    pub fn to_response(&self, correlation_id: &str) -> Response {
+        let status = self.status();
+        let body = json!({
+            "status": status.as_u16(),
+            "code": self.code(),
+            "error": self.to_string(),
+            "retryable": self.retryable(),
+            "correlation_id": correlation_id,
+        });
+        let mut response = (status, Json(body)).into_response();
+        if let Self::Paused(secs) | Self::Maintenance(secs) = self {
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, HeaderValue::from(*secs));
+        }
+        response
+    }
+}
+
+/// Interceptors reject with a bare status.
+impl From<StatusCode> for ProxyError {
+    fn from(status: StatusCode) -> Self {
+        Self::Rejected(status)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_to_status_code_and_retryability() {
+        let timeout = ProxyError::UpstreamTimeout;
+        assert_eq!(timeout.status(), StatusCode::GATEWAY_TIMEOUT);
+        assert_eq!(timeout.code(), "upstream_timeout");
+        assert!(timeout.retryable());
+
+        let rejected = ProxyError::from(StatusCode::UNAUTHORIZED);
+        assert_eq!(rejected.status(), StatusCode::UNAUTHORIZED);
+        assert!(!rejected.retryable());
+        assert!(!ProxyError::EgressRefused.retryable());
+
+        // Shedding the request body happens before the supplier sees it;
+        // shedding the response happens after.
+        assert!(ProxyError::MemoryBudget.retryable());
+        assert!(!ProxyError::MemoryBudget.may_have_reached_upstream());
+        let shed = ProxyError::UpstreamBodyOverBudget;
+        assert_eq!(shed.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(!shed.retryable());
+        assert!(shed.may_have_reached_upstream());
+    }
+}
@@ -0,0 +1,260 @@
+//! `GET /admin/export` packages recorded exchanges into one file to attach
+//! to a supplier support ticket, instead of copying them out of the logs by
+//! hand. `source=log` (the default) reads the request log, `source=capture`
+//! the current capture; `from_ms`, `to_ms`, `path_prefix` and `status`
+//! narrow either, and `limit` keeps the newest that many (100 by default,
+//! at most 1000). The file is JSONL, one [`Exchange`] per line, oldest
+//! first, or with `format=har` a HAR 1.2 archive ([`crate::har`]).
+//!
+//! Everything exported is redacted: secret headers and the redact keys'
+//! query parameters and JSON values, even in log entries written before a
+//! key was added.
+
+use std::collections::BTreeMap;
+
+use axum::body::Body;
+use axum::extract::{Query, State};
+use axum::response::{IntoResponse, Response};
+use hyper::header::{self, HeaderMap, HeaderValue};
+use hyper::StatusCode;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::error;
+
+use crate::app_state::AppState;
+use crate::capture::{redact_query, CapturedExchange};
+use crate::har::to_har;
+use crate::request_log::{redact, RequestLogEntry, RequestLogQuery};
+
+const DEFAULT_EXPORT_LIMIT: u32 = 100;
+const MAX_EXPORT_LIMIT: u32 = 1000;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportSource {
+    #[default]
+    Log,
+    Capture,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    #[default]
+    Jsonl,
+    Har,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ExportQuery {
+    #[serde(default)]
+    pub source: ExportSource,
+    #[serde(default)]
+    pub format: ExportFormat,
+    pub from_ms: Option<i64>,
+    pub to_ms: Option<i64>,
+    pub path_prefix: Option<String>,
+    pub status: Option<u16>,
+    pub limit: Option<u32>,
+}
+
+/// One exported request and its response.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Exchange {
+    pub at_ms: i64,
+    pub method: String,
+    /// The inbound path and query for logged exchanges, the upstream URL
+    /// for captured ones.
+    pub url: String,
+    pub status: u16,
+    pub duration_ms: Option<i64>,
+    pub correlation_id: Option<String>,
+    pub fingerprint: Option<String>,
+    /// Only captures record headers.
+    pub request_headers: BTreeMap<String, String>,
+    pub request_body: Option<String>,
+    pub response_headers: BTreeMap<String, String>,
+    pub response_body: Option<String>,
+}
+
+impl Exchange {
+    fn from_log(entry: RequestLogEntry, redact_keys: &[String]) -> Self {
+        let url = match &entry.query {
+            Some(query) => format!("{}?{}", entry.path, redact_query(query, redact_keys)),
+            None => entry.path,
+        };
+        let redact_body = |body: String| match serde_json::from_str::<Value>(&body) {
+            Ok(mut json) => {
+                redact(&mut json, redact_keys);
+                json.to_string()
+            }
+            Err(_) => body,
+        };
+        Self {
+            at_ms: entry.timestamp_ms,
+            method: entry.method,
+            url,
+            status: entry.status,
+            duration_ms: Some(entry.duration_ms),
+            correlation_id: entry.correlation_id,
+            fingerprint: entry.fingerprint,
+            request_headers: BTreeMap::new(),
+            request_body: entry.request_body.map(redact_body),
+            response_headers: BTreeMap::new(),
+            response_body: entry.response_body.map(redact_body),
+        }
+    }
+
+    /// Captures are redacted as they are taken.
+    fn from_capture(exchange: CapturedExchange) -> Self {
+        Self {
+            at_ms: exchange.at_ms,
+            method: exchange.request.method,
+            url: exchange.request.uri,
+            status: exchange.response.status,
+            duration_ms: None,
+            correlation_id: None,
+            fingerprint: None,
+            request_headers: exchange.request.headers,
+            request_body: Some(exchange.request.body),
+            response_headers: exchange.response.headers,
+            response_body: Some(exchange.response.body),
+        }
+    }
+}
+
+/// The exchanges `query` selects, oldest first.
+pub async fn exchanges(
+    state: &AppState,
+    query: &ExportQuery,
+) -> Result<Vec<Exchange>, (StatusCode, String)> {
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_EXPORT_LIMIT)
+        .min(MAX_EXPORT_LIMIT) as usize;
+    let mut exchanges: Vec<Exchange> = match query.source {
+        ExportSource::Log => {
+            let Some(log) = &state.request_log else {
+                return Err((StatusCode::NOT_FOUND, "request logging is off".to_string()));
+            };
+            let entries = log
+                .sink()
+                .query(&RequestLogQuery {
+                    from_ms: query.from_ms,
+                    to_ms: query.to_ms,
+                    status: query.status,
+                    path_prefix: query.path_prefix.clone(),
+                    limit: Some(limit as u32),
+                    ..Default::default()
+                })
+                .await
+                .map_err(|e| {
+                    error!("Request log export failed: {}", e);
+                    (StatusCode::INTERNAL_SERVER_ERROR, e)
+                })?;
+            entries
+                .into_iter()
+                .map(|entry| Exchange::from_log(entry, log.redact_keys()))
+                .collect()
+        }
+        ExportSource::Capture => state
+            .capture
+            .exchanges()
+            .await
+            .into_iter()
+            .filter(|exchange| matches(query, exchange))
+            .map(Exchange::from_capture)
+            .collect(),
+    };
+    exchanges.sort_by_key(|exchange| exchange.at_ms);
+    let skip = exchanges.len().saturating_sub(limit);
+    exchanges.drain(..skip);
+    Ok(exchanges)
+}
+
+fn matches(query: &ExportQuery, exchange: &CapturedExchange) -> bool {
+    query.from_ms.is_none_or(|from| exchange.at_ms >= from)
+        && query.to_ms.is_none_or(|to| exchange.at_ms < to)
+        && query
+            .status
+            .is_none_or(|status| exchange.response.status == status)
+        && query
+            .path_prefix
+            .as_deref()
+            .is_none_or(|prefix| exchange.route.starts_with(prefix))
+}
+
+/// `GET /admin/export`: the selected exchanges as a JSONL or HAR attachment.
+pub async fn export_handler(
+    State(state): State<AppState>,
+    Query(query): Query<ExportQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let exchanges = match exchanges(&state, &query).await {
+        Ok(exchanges) => exchanges,
+        Err(rejection) => return rejection.into_response(),
+    };
+    let (content_type, filename, body) = match query.format {
+        ExportFormat::Jsonl => {
+            let mut body = String::new();
+            for exchange in &exchanges {
+                body.push_str(&serde_json::to_string(exchange).unwrap_or_default());
+                body.push('\n');
+            }
+            ("application/x-ndjson", "exchanges.jsonl", body)
+        }
+        ExportFormat::Har => {
+            // Logged URLs are the proxy's own, as the caller reached it.
+            let host = headers
+                .get(header::HOST)
+                .and_then(|host| host.to_str().ok())
+                .unwrap_or("localhost");
+            let har = to_har(&exchanges, &format!("http://{host}"));
+            ("application/json", "exchanges.har", har.to_string())
+        }
+    };
+    (
+        [
+            (header::CONTENT_TYPE, HeaderValue::from_static(content_type)),
+            (
+                header::CONTENT_DISPOSITION,
+                HeaderValue::from_str(&format!("attachment; filename=\"{filename}\""))
+                    .expect("the filenames are valid header values"),
+            ),
+        ],
+        Body::from(body),
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redacts_logged_queries_and_bodies() {
+        let entry = RequestLogEntry {
+            timestamp_ms: 1,
+            env: "prod".to_string(),
+            method: "POST".to_string(),
+            path: "/prod/api/book".to_string(),
+            query: Some("token=abc&city=goa".to_string()),
+            status: 200,
+            duration_ms: 12,
+            request_body: Some(r#"{"card_number":"4111","nights":2}"#.to_string()),
+            response_body: Some("plain text".to_string()),
+            correlation_id: None,
+            fingerprint: Some("f00d".to_string()),
+            client_tag: None,
+        };
+        let keys = vec!["token".to_string(), "card_number".to_string()];
+        let exchange = Exchange::from_log(entry, &keys);
+        assert_eq!(exchange.url, "/prod/api/book?token=[REDACTED]&city=goa");
+        assert_eq!(
+            exchange.request_body.as_deref(),
+            Some(r#"{"card_number":"[REDACTED]","nights":2}"#)
+        );
+        assert_eq!(exchange.response_body.as_deref(), Some("plain text"));
+        assert_eq!(exchange.fingerprint.as_deref(), Some("f00d"));
+    }
+}
@@ -0,0 +1,252 @@
+//! Multi-region failover: an environment may name a secondary base URL, in
+//! another supplier region, next to its primary one.
+//!
+//! After `failure_threshold` failures in a row the primary is marked down
+//! and requests go to the secondary. Once `retry_primary_after` has passed,
+//! the next request tries the primary again; a success fails back, a failure
+//! marks it down for another period. A request the primary fails is also
+//! sent to the secondary straight away when that's safe: always if the
+//! primary was never reached, otherwise only for idempotent methods.
+
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use hyper::{Method, Uri};
+use serde::Deserialize;
+use tracing::{info, warn};
+
+use crate::error::ProxyError;
+use crate::interceptor::UpstreamResponse;
+
+/// Response header naming the region that served a failover-enabled env.
+pub const X_UPSTREAM_TARGET: &str = "x-upstream-target";
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct FailoverConfig {
+    /// Secondary base URLs keyed by env, next to the primaries in `upstreams`.
+    pub secondaries: BTreeMap<String, String>,
+    /// Consecutive primary failures that mark it down.
+    pub failure_threshold: u32,
+    /// How long a primary stays down before it's tried again.
+    pub retry_primary_after: Duration,
+}
+
+impl Default for FailoverConfig {
+    fn default() -> Self {
+        Self {
+            secondaries: BTreeMap::new(),
+            failure_threshold: 3,
+            retry_primary_after: Duration::from_secs(30),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Target {
+    Primary,
+    Secondary,
+}
+
+impl Target {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Primary => "primary",
+            Self::Secondary => "secondary",
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct PrimaryHealth {
+    consecutive_failures: u32,
+    down_until: Option<Instant>,
+}
+
+#[derive(Debug, Default)]
+pub struct Failover {
+    config: FailoverConfig,
+    health: Mutex<BTreeMap<String, PrimaryHealth>>,
+}
+
+impl Failover {
+    pub fn new(config: FailoverConfig) -> Self {
+        Self {
+            config,
+            health: Mutex::default(),
+        }
+    }
+
+    pub fn secondary_for(&self, env: &str) -> Option<&str> {
+        self.config.secondaries.get(env).map(String::as_str)
+    }
+
+    /// Where the next request to `env` goes.
+    pub fn target(&self, env: &str) -> Target {
+        if self.secondary_for(env).is_none() {
+            return Target::Primary;
+        }
+        let health = self.health.lock().unwrap();
+        match health.get(env).and_then(|health| health.down_until) {
+            Some(until) if Instant::now() < until => Target::Secondary,
+            _ => Target::Primary,
+        }
+    }
+
+    /// Updates the primary's health after a call to it; calls to the
+    /// secondary don't change it.
+    pub fn record(&self, env: &str, target: Target, healthy: bool) {
+        if target != Target::Primary || self.secondary_for(env).is_none() {
+            return;
+        }
+        let mut health = self.health.lock().unwrap();
+        let health = health.entry(env.to_string()).or_default();
+        if healthy {
+            if health.down_until.take().is_some() {
+                info!(
+                    "Primary upstream for {} is healthy again; failing back",
+                    env
+                );
+            }
+            health.consecutive_failures = 0;
+            return;
+        }
+        health.consecutive_failures += 1;
+        if health.consecutive_failures >= self.config.failure_threshold {
+            if health.down_until.is_none() {
+                warn!(
+                    "Primary upstream for {} failed {} times in a row; failing over",
+                    env, health.consecutive_failures
+                );
+            }
+            health.down_until = Some(Instant::now() + self.config.retry_primary_after);
+        }
+    }
+}
+
+/// Whether a call's result says anything about the upstream's health:
+/// `Some(false)` for 5xx and transport failures, `None` for errors the
+/// proxy raised itself.
+pub fn healthy(result: &Result<UpstreamResponse, ProxyError>) -> Option<bool> {
+    match result {
+        Ok(upstream) => Some(!upstream.status.is_server_error()),
+        Err(
+            ProxyError::UpstreamTimeout
+            | ProxyError::UpstreamDns
+            | ProxyError::UpstreamConnection
+            | ProxyError::Upstream
+            | ProxyError::UpstreamBody,
+        ) => Some(false),
+        Err(_) => None,
+    }
+}
+
+/// Whether a request the primary failed may be sent again to the secondary.
+pub fn retry_on_secondary(method: &Method, result: &Result<UpstreamResponse, ProxyError>) -> bool {
+    match result {
+        // Never reached the supplier, so nothing was done twice.
+        Err(ProxyError::UpstreamDns | ProxyError::UpstreamConnection) => true,
+        Err(error) => error.retryable() && method.is_idempotent(),
+        Ok(upstream) => matches!(upstream.status.as_u16(), 502..=504) && method.is_idempotent(),
+    }
+}
+
+/// `uri` with the `from` base URL swapped for `to`.
+pub fn rebase(uri: &Uri, from: &str, to: &str) -> Option<Uri> {
+    let uri = uri.to_string();
+    let rest = uri.strip_prefix(from.trim_end_matches('/'))?;
+    format!("{}{}", to.trim_end_matches('/'), rest).parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::{HeaderMap, StatusCode};
+
+    use super::*;
+
+    fn failover(threshold: u32, retry_after: Duration) -> Failover {
+        Failover::new(FailoverConfig {
+            secondaries: BTreeMap::from([("prod".to_string(), "http://eu".to_string())]),
+            failure_threshold: threshold,
+            retry_primary_after: retry_after,
+        })
+    }
+
+    fn response(status: StatusCode) -> Result<UpstreamResponse, ProxyError> {
+        Ok(UpstreamResponse {
+            status,
+            headers: HeaderMap::new(),
+            body: Default::default(),
+            trailers: None,
+        })
+    }
+
+    #[test]
+    fn fails_over_after_consecutive_failures() {
+        let failover = failover(2, Duration::from_secs(60));
+        failover.record("prod", Target::Primary, false);
+        failover.record("prod", Target::Primary, true);
+        failover.record("prod", Target::Primary, false);
+        assert_eq!(failover.target("prod"), Target::Primary);
+        failover.record("prod", Target::Primary, false);
+        assert_eq!(failover.target("prod"), Target::Secondary);
+        // Without a secondary there's nowhere to go.
+        failover.record("test", Target::Primary, false);
+        failover.record("test", Target::Primary, false);
+        assert_eq!(failover.target("test"), Target::Primary);
+    }
+
+    #[test]
+    fn retries_the_primary_and_fails_back() {
+        let failover = failover(1, Duration::ZERO);
+        failover.record("prod", Target::Primary, false);
+        // The down period is over, so the primary gets the next request.
+        assert_eq!(failover.target("prod"), Target::Primary);
+        failover.record("prod", Target::Primary, true);
+        let health = failover.health.lock().unwrap();
+        assert_eq!(health["prod"].down_until, None);
+        assert_eq!(health["prod"].consecutive_failures, 0);
+    }
+
+    #[test]
+    fn retries_only_what_is_safe_to_repeat() {
+        let refused = Err(ProxyError::UpstreamConnection);
+        assert!(retry_on_secondary(&Method::POST, &refused));
+        let timeout = Err(ProxyError::UpstreamTimeout);
+        assert!(retry_on_secondary(&Method::GET, &timeout));
+        assert!(!retry_on_secondary(&Method::POST, &timeout));
+        let unavailable = response(StatusCode::SERVICE_UNAVAILABLE);
+        assert!(retry_on_secondary(&Method::PUT, &unavailable));
+        assert!(!retry_on_secondary(&Method::POST, &unavailable));
+        assert!(!retry_on_secondary(&Method::GET, &response(StatusCode::OK)));
+        assert!(!retry_on_secondary(
+            &Method::GET,
+            &Err(ProxyError::EgressRefused)
+        ));
+
+        assert_eq!(healthy(&unavailable), Some(false));
+        assert_eq!(healthy(&response(StatusCode::NOT_FOUND)), Some(true));
+        assert_eq!(healthy(&Err(ProxyError::QueueTimeout)), None);
+    }
+
+    #[test]
+    fn swaps_the_base_url() {
+        let uri: Uri = "https://us.supplier.com/v2/api/search?city=goa"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            rebase(
+                &uri,
+                "https://us.supplier.com/v2/",
+                "https://eu.supplier.com/v2"
+            )
+            .unwrap()
+            .to_string(),
+            "https://eu.supplier.com/v2/api/search?city=goa"
+        );
+        assert_eq!(
+            rebase(&uri, "https://other.com", "https://eu.supplier.com"),
+            None
+        );
+    }
+}
@@ -0,0 +1,142 @@
+//! Caps concurrent upstream calls. Requests over the cap wait in per-client
+//! queues that are served round-robin, so one chatty client can't starve the
+//! others by filling the queue first.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::oneshot;
+
+pub struct FairQueue {
+    capacity: usize,
+    state: Mutex<QueueState>,
+}
+
+#[derive(Default)]
+struct QueueState {
+    in_flight: usize,
+    /// Clients with waiters, in the order they are next served.
+    rotation: VecDeque<String>,
+    waiters: HashMap<String, VecDeque<oneshot::Sender<FairPermit>>>,
+}
+
+/// A slot for one upstream call, handed to the next waiter when dropped.
+pub struct FairPermit {
+    queue: Arc<FairQueue>,
+    armed: bool,
+}
+
+impl FairQueue {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            state: Mutex::default(),
+        }
+    }
+
+    /// Waits for a slot on behalf of `client`. Returns `true` alongside the
+    /// permit when the request had to queue.
+    pub async fn acquire(self: &Arc<Self>, client: &str) -> (FairPermit, bool) {
+        let receiver = {
+            let mut state = self.state.lock().unwrap();
+            if state.in_flight < self.capacity && state.rotation.is_empty() {
+                state.in_flight += 1;
+                return (self.permit(), false);
+            }
+            let (sender, receiver) = oneshot::channel();
+            let queue = state.waiters.entry(client.to_string()).or_default();
+            let first = queue.is_empty();
+            queue.push_back(sender);
+            if first {
+                state.rotation.push_back(client.to_string());
+            }
+            receiver
+        };
+        // The sender is only dropped once this waiter has been handed a
+        // permit (or skipped after giving up), so this can't fail.
+        let permit = receiver.await.expect("queued waiters are always served");
+        (permit, true)
+    }
+
+    fn permit(self: &Arc<Self>) -> FairPermit {
+        FairPermit {
+            queue: self.clone(),
+            armed: true,
+        }
+    }
+
+    /// Passes a finished call's slot to the next client in the rotation.
+    fn release(self: &Arc<Self>) {
+        loop {
+            let sender = {
+                let mut state = self.state.lock().unwrap();
+                let Some(sender) = state.next_waiter() else {
+                    state.in_flight -= 1;
+                    return;
+                };
+                sender
+            };
+            match sender.send(self.permit()) {
+                Ok(()) => return,
+                // The waiter gave up; offer the slot to the next one.
+                Err(mut permit) => permit.armed = false,
+            }
+        }
+    }
+}
+
+impl QueueState {
+    fn next_waiter(&mut self) -> Option<oneshot::Sender<FairPermit>> {
+        let client = self.rotation.pop_front()?;
+        let queue = self.waiters.get_mut(&client)?;
+        let sender = queue.pop_front();
+        if queue.is_empty() {
+            self.waiters.remove(&client);
+        } else {
+            self.rotation.push_back(client);
+        }
+        sender
+    }
+}
+
+impl Drop for FairPermit {
+    fn drop(&mut self) {
+        if self.armed {
+            self.queue.release();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn serves_waiting_clients_round_robin() {
+        let queue = Arc::new(FairQueue::new(1));
+        let (running, queued) = queue.acquire("chatty").await;
+        assert!(!queued);
+
+        // "chatty" queues three requests before "quiet" queues one.
+        let (order_tx, mut order_rx) = tokio::sync::mpsc::unbounded_channel();
+        for (n, client) in ["chatty", "chatty", "chatty", "quiet"]
+            .into_iter()
+            .enumerate()
+        {
+            let (queue, order_tx) = (queue.clone(), order_tx.clone());
+            tokio::spawn(async move {
+                let (_permit, queued) = queue.acquire(client).await;
+                assert!(queued);
+                order_tx.send(format!("{client}{n}")).unwrap();
+            });
+            tokio::task::yield_now().await;
+        }
+        drop(running);
+
+        let mut order = Vec::new();
+        for _ in 0..4 {
+            order.push(order_rx.recv().await.unwrap());
+        }
+        assert_eq!(order, ["chatty0", "quiet3", "chatty1", "chatty2"]);
+    }
+}
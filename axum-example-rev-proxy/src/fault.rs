@@ -0,0 +1,499 @@
+//! Fault injection for resilience testing: admins set rules that make the
+//! proxy answer some requests with an error, delay them, or drop the
+//! connection, without the supplier being involved.
+//!
+//! Rules under `/test` may also shape the network: draw latency from a
+//! distribution, cap response bandwidth, or reset connections partway
+//! through a response, to reproduce slow supplier conditions seen in prod.
+//!
+//! `PUT /admin/faults` replaces the rules, `GET /admin/faults` lists them and
+//! `DELETE /admin/faults` removes them all. The first rule whose path prefix
+//! matches the inbound path decides a request's fault.
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::RwLock;
+use std::time::Duration;
+
+use axum::body::{to_bytes, Body, Bytes};
+use axum::extract::State;
+use axum::response::Response;
+use axum::Json;
+use futures_util::stream;
+use hyper::StatusCode;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::app_state::AppState;
+use crate::error::ProxyError;
+
+fn default_error_status() -> u16 {
+    502
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct FaultRule {
+    /// Inbound path prefix, e.g. `/test` or `/prod/api/book`.
+    pub path_prefix: String,
+    /// Share of requests, 0 to 100, answered with `error_status`.
+    #[serde(default)]
+    pub error_percent: f64,
+    #[serde(default = "default_error_status")]
+    pub error_status: u16,
+    /// Added to every matching request, before any error or drop.
+    #[serde(default)]
+    pub delay_ms: u64,
+    /// Up to this much more delay, chosen at random per request.
+    #[serde(default)]
+    pub jitter_ms: u64,
+    /// Share of requests, 0 to 100, whose connection is dropped without a
+    /// complete response.
+    #[serde(default)]
+    pub drop_percent: f64,
+    /// More delay, drawn per request. Shaping, so `test` only, like the
+    /// fields below.
+    #[serde(default)]
+    pub latency: Option<LatencyDistribution>,
+    /// Caps how fast response bodies are sent.
+    #[serde(default)]
+    pub bandwidth_bytes_per_sec: Option<u64>,
+    /// Share of responses, 0 to 100, whose connection is reset after part
+    /// of the body was sent.
+    #[serde(default)]
+    pub reset_percent: f64,
+}
+
+/// The env whose rules may shape traffic.
+pub const SHAPING_ENV: &str = "test";
+
+/// Latency added on top of `delay_ms` and `jitter_ms`.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "distribution", rename_all = "snake_case")]
+pub enum LatencyDistribution {
+    Normal {
+        mean_ms: f64,
+        stddev_ms: f64,
+    },
+    /// Long-tailed: most requests near the median, a few far slower.
+    LogNormal {
+        median_ms: f64,
+        sigma: f64,
+    },
+}
+
+impl LatencyDistribution {
+    /// `u1` and `u2` are uniform in `[0, 1)`.
+    fn sample(&self, u1: f64, u2: f64) -> Duration {
+        // Box-Muller; 1 - u1 keeps the logarithm finite.
+        let z = (-2.0 * (1.0 - u1).ln()).sqrt() * (std::f64::consts::TAU * u2).cos();
+        let ms = match *self {
+            Self::Normal { mean_ms, stddev_ms } => mean_ms + stddev_ms * z,
+            Self::LogNormal { median_ms, sigma } => median_ms * (sigma * z).exp(),
+        };
+        Duration::from_secs_f64(ms.max(0.0) / 1000.0)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        let valid = match *self {
+            Self::Normal { mean_ms, stddev_ms } => mean_ms >= 0.0 && stddev_ms >= 0.0,
+            Self::LogNormal { median_ms, sigma } => median_ms >= 0.0 && sigma >= 0.0,
+        };
+        if valid {
+            Ok(())
+        } else {
+            Err("latency parameters must not be negative".to_string())
+        }
+    }
+}
+
+impl FaultRule {
+    fn shapes(&self) -> bool {
+        self.latency.is_some() || self.bandwidth_bytes_per_sec.is_some() || self.reset_percent > 0.0
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        for (name, percent) in [
+            ("error_percent", self.error_percent),
+            ("drop_percent", self.drop_percent),
+            ("reset_percent", self.reset_percent),
+        ] {
+            if !(0.0..=100.0).contains(&percent) {
+                return Err(format!("{name} must be between 0 and 100"));
+            }
+        }
+        if self.error_percent + self.drop_percent > 100.0 {
+            return Err("error_percent and drop_percent add up to more than 100".to_string());
+        }
+        if !(400..=599).contains(&self.error_status) {
+            return Err("error_status must be a 4xx or 5xx status".to_string());
+        }
+        if self.bandwidth_bytes_per_sec == Some(0) {
+            return Err("bandwidth_bytes_per_sec must be at least 1".to_string());
+        }
+        if let Some(latency) = &self.latency {
+            latency.validate()?;
+        }
+        let env = self.path_prefix.trim_start_matches('/').split('/').next();
+        if self.shapes() && env != Some(SHAPING_ENV) {
+            return Err(format!(
+                "latency, bandwidth and reset shaping apply only under /{SHAPING_ENV}"
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// What happens to a request once its delay has passed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultOutcome {
+    Pass,
+    Error(StatusCode),
+    Drop,
+}
+
+/// How a passing response is sent back.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Shaping {
+    pub bytes_per_sec: Option<u64>,
+    /// Share of the body sent before the connection is reset.
+    pub reset_after: Option<f64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Fault {
+    pub delay: Duration,
+    pub outcome: FaultOutcome,
+    pub shaping: Option<Shaping>,
+}
+
+#[derive(Debug, Default)]
+pub struct Faults {
+    rules: RwLock<Vec<FaultRule>>,
+}
+
+impl Faults {
+    /// Replaces all rules, or none when any is invalid.
+    pub fn set(&self, rules: Vec<FaultRule>) -> Result<(), String> {
+        rules.iter().try_for_each(FaultRule::validate)?;
+        *self.rules.write().unwrap() = rules;
+        Ok(())
+    }
+
+    pub fn rules(&self) -> Vec<FaultRule> {
+        self.rules.read().unwrap().clone()
+    }
+
+    /// The fault for a request to `route`, if a rule matches it.
+    pub fn decide(&self, route: &str) -> Option<Fault> {
+        let rules = self.rules.read().unwrap();
+        let rule = rules
+            .iter()
+            .find(|rule| route.starts_with(&rule.path_prefix))?;
+        Some(rule_fault(rule, random_fraction))
+    }
+}
+
+/// `random` yields values uniform in `[0, 1)`.
+fn rule_fault(rule: &FaultRule, mut random: impl FnMut() -> f64) -> Fault {
+    let percent = random() * 100.0;
+    let outcome = if percent < rule.error_percent {
+        FaultOutcome::Error(
+            StatusCode::from_u16(rule.error_status).unwrap_or(StatusCode::BAD_GATEWAY),
+        )
+    } else if percent < rule.error_percent + rule.drop_percent {
+        FaultOutcome::Drop
+    } else {
+        FaultOutcome::Pass
+    };
+    let mut delay =
+        Duration::from_millis(rule.delay_ms + (rule.jitter_ms as f64 * random()) as u64);
+    if let Some(latency) = &rule.latency {
+        delay += latency.sample(random(), random());
+    }
+    let reset_after = (random() * 100.0 < rule.reset_percent).then(&mut random);
+    let shaping =
+        (rule.bandwidth_bytes_per_sec.is_some() || reset_after.is_some()).then_some(Shaping {
+            bytes_per_sec: rule.bandwidth_bytes_per_sec,
+            reset_after,
+        });
+    Fault {
+        delay,
+        outcome,
+        shaping,
+    }
+}
+
+/// Uniform in `[0, 1)`; good enough for sampling, not for secrets.
+fn random_fraction() -> f64 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(0);
+    (hasher.finish() >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// A response whose body fails at once, so the server aborts the connection
+/// instead of completing the exchange.
+pub fn dropped_response() -> Response {
+    let body = stream::once(async {
+        Err::<axum::body::Bytes, _>(std::io::Error::new(
+            std::io::ErrorKind::ConnectionAborted,
+            "connection dropped by fault injection",
+        ))
+    });
+    let mut response = Response::new(Body::from_stream(body));
+    *response.status_mut() = StatusCode::BAD_GATEWAY;
+    response
+}
+
+/// Sleeps out the fault's delay, then answers in place of the upstream
+/// unless the request is to pass.
+pub async fn inject(fault: &Fault, route: &str) -> Result<Option<Response>, ProxyError> {
+    tokio::time::sleep(fault.delay).await;
+    match fault.outcome {
+        FaultOutcome::Pass => Ok(None),
+        FaultOutcome::Error(status) => {
+            warn!("Injected {} for {}", status.as_u16(), route);
+            Err(ProxyError::InjectedFault(status))
+        }
+        FaultOutcome::Drop => {
+            warn!("Injected a dropped connection for {}", route);
+            Ok(Some(dropped_response()))
+        }
+    }
+}
+
+/// Slices chunks sent per second under a bandwidth cap.
+const TICKS_PER_SEC: u64 = 10;
+
+/// Re-sends `response`'s body under `shaping`'s bandwidth cap, cut short by
+/// a connection reset if it calls for one.
+pub async fn shape(response: Response, shaping: Shaping) -> Response {
+    let (parts, body) = response.into_parts();
+    let Ok(body) = to_bytes(body, usize::MAX).await else {
+        return dropped_response();
+    };
+    let end = match shaping.reset_after {
+        Some(share) => (body.len() as f64 * share) as usize,
+        None => body.len(),
+    };
+    let chunk = shaping.bytes_per_sec.map_or(body.len().max(1), |rate| {
+        (rate / TICKS_PER_SEC).max(1) as usize
+    });
+    let pace = shaping
+        .bytes_per_sec
+        .map(|_| Duration::from_millis(1000 / TICKS_PER_SEC));
+    let reset = shaping.reset_after.is_some();
+    let chunks = stream::unfold(Some(0), move |offset| {
+        let body = body.clone();
+        async move {
+            let offset = offset?;
+            if offset >= end {
+                return reset.then(|| {
+                    let error = std::io::Error::new(
+                        std::io::ErrorKind::ConnectionReset,
+                        "connection reset by traffic shaping",
+                    );
+                    (Err(error), None)
+                });
+            }
+            if let (Some(pace), true) = (pace, offset > 0) {
+                tokio::time::sleep(pace).await;
+            }
+            let next = (offset + chunk).min(end);
+            Some((Ok::<Bytes, _>(body.slice(offset..next)), Some(next)))
+        }
+    });
+    Response::from_parts(parts, Body::from_stream(chunks))
+}
+
+/// `GET /admin/faults`: the active rules, in matching order.
+pub async fn list_handler(State(state): State<AppState>) -> Json<Vec<FaultRule>> {
+    Json(state.faults.rules())
+}
+
+/// `PUT /admin/faults` with `[{"path_prefix": "/test/api/book", "error_percent": 20}]`.
+pub async fn set_handler(
+    State(state): State<AppState>,
+    Json(rules): Json<Vec<FaultRule>>,
+) -> Result<Json<Vec<FaultRule>>, (StatusCode, String)> {
+    state
+        .faults
+        .set(rules)
+        .map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    let rules = state.faults.rules();
+    warn!("Fault injection rules set: {:?}", rules);
+    Ok(Json(rules))
+}
+
+/// `DELETE /admin/faults`: stops injecting faults.
+pub async fn clear_handler(State(state): State<AppState>) -> StatusCode {
+    state.faults.set(Vec::new()).expect("no rules are valid");
+    info!("Fault injection rules cleared");
+    StatusCode::NO_CONTENT
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule() -> FaultRule {
+        FaultRule {
+            path_prefix: "/test/api".to_string(),
+            error_percent: 20.0,
+            error_status: 503,
+            delay_ms: 100,
+            jitter_ms: 50,
+            drop_percent: 10.0,
+            latency: None,
+            bandwidth_bytes_per_sec: None,
+            reset_percent: 0.0,
+        }
+    }
+
+    /// Feeds `values` to `rule_fault` in order, then zeros.
+    fn fault(rule: &FaultRule, values: &[f64]) -> Fault {
+        let mut values = values.iter().copied();
+        rule_fault(rule, move || values.next().unwrap_or(0.0))
+    }
+
+    #[test]
+    fn splits_requests_by_percentage() {
+        let rule = rule();
+        assert_eq!(
+            fault(&rule, &[0.1]).outcome,
+            FaultOutcome::Error(StatusCode::SERVICE_UNAVAILABLE)
+        );
+        assert_eq!(fault(&rule, &[0.25]).outcome, FaultOutcome::Drop);
+        assert_eq!(fault(&rule, &[0.3]).outcome, FaultOutcome::Pass);
+        assert_eq!(fault(&rule, &[0.5]).delay, Duration::from_millis(100));
+        assert_eq!(fault(&rule, &[0.5, 0.5]).delay, Duration::from_millis(125));
+        assert_eq!(fault(&rule, &[0.5]).shaping, None);
+    }
+
+    #[test]
+    fn shapes_latency_and_resets() {
+        let shaped = FaultRule {
+            error_percent: 0.0,
+            drop_percent: 0.0,
+            delay_ms: 0,
+            jitter_ms: 0,
+            latency: Some(LatencyDistribution::LogNormal {
+                median_ms: 200.0,
+                sigma: 1.0,
+            }),
+            bandwidth_bytes_per_sec: Some(1000),
+            reset_percent: 50.0,
+            ..rule()
+        };
+        // u1 = 1 - e^-0.5 and u2 = 0 put z at exactly 1.
+        let u1 = 1.0 - (-0.5f64).exp();
+        let fault = fault(&shaped, &[0.9, 0.0, u1, 0.0, 0.2, 0.4]);
+        assert_eq!(fault.outcome, FaultOutcome::Pass);
+        let expected = 200.0 * std::f64::consts::E;
+        assert!((fault.delay.as_secs_f64() * 1000.0 - expected).abs() < 0.01);
+        assert_eq!(
+            fault.shaping,
+            Some(Shaping {
+                bytes_per_sec: Some(1000),
+                reset_after: Some(0.4),
+            })
+        );
+
+        let median = LatencyDistribution::Normal {
+            mean_ms: 50.0,
+            stddev_ms: 10.0,
+        };
+        // u2 = 0.25 puts cos at 0, so z is 0.
+        assert_eq!(median.sample(0.5, 0.25), Duration::from_millis(50));
+    }
+
+    #[tokio::test]
+    async fn caps_bandwidth_and_cuts_bodies_short() {
+        use http_body_util::BodyExt;
+
+        let response = || Response::new(Body::from(vec![b'x'; 300]));
+        let started = std::time::Instant::now();
+        let throttled = shape(
+            response(),
+            Shaping {
+                bytes_per_sec: Some(1000),
+                reset_after: None,
+            },
+        )
+        .await;
+        let body = throttled.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body.len(), 300);
+        // 100 bytes per 100ms tick, the first chunk right away.
+        assert!(started.elapsed() >= Duration::from_millis(200));
+
+        let reset = shape(
+            response(),
+            Shaping {
+                bytes_per_sec: None,
+                reset_after: Some(0.5),
+            },
+        )
+        .await;
+        let mut body = reset.into_body();
+        let first = body.frame().await.unwrap().unwrap().into_data().unwrap();
+        assert_eq!(first.len(), 150);
+        assert!(body.frame().await.unwrap().is_err());
+    }
+
+    #[test]
+    fn applies_the_first_matching_rule() {
+        let faults = Faults::default();
+        let always = FaultRule {
+            error_percent: 100.0,
+            drop_percent: 0.0,
+            ..rule()
+        };
+        faults.set(vec![always]).unwrap();
+        assert_eq!(
+            faults.decide("/test/api/book").unwrap().outcome,
+            FaultOutcome::Error(StatusCode::SERVICE_UNAVAILABLE)
+        );
+        assert_eq!(faults.decide("/prod/api/book"), None);
+    }
+
+    #[test]
+    fn rejects_invalid_rules() {
+        let faults = Faults::default();
+        faults.set(vec![rule()]).unwrap();
+        let invalid = [
+            FaultRule {
+                path_prefix: "/prod/api".to_string(),
+                bandwidth_bytes_per_sec: Some(1000),
+                ..rule()
+            },
+            FaultRule {
+                path_prefix: "/testing".to_string(),
+                reset_percent: 5.0,
+                ..rule()
+            },
+            FaultRule {
+                latency: Some(LatencyDistribution::Normal {
+                    mean_ms: -1.0,
+                    stddev_ms: 1.0,
+                }),
+                ..rule()
+            },
+            FaultRule {
+                error_percent: 101.0,
+                ..rule()
+            },
+            FaultRule {
+                error_percent: 60.0,
+                drop_percent: 50.0,
+                ..rule()
+            },
+            FaultRule {
+                error_status: 200,
+                ..rule()
+            },
+        ];
+        for rule in invalid {
+            assert!(faults.set(vec![rule]).is_err());
+        }
+        assert_eq!(faults.rules().len(), 1);
+    }
+}
@@ -0,0 +1,280 @@
+//! `?_fields=a.b,c` trims a JSON response down to the fields a client
+//! asked for, so a mobile app can take two values out of a large supplier
+//! payload without downloading all of it. It only applies on inbound paths
+//! under a `FIELD_FILTER_PATHS` prefix; elsewhere the parameter goes
+//! upstream like any other.
+//!
+//! Paths are dot-separated keys; arrays are walked through, so
+//! `rooms.price` keeps each room's price. Fields that don't exist are left
+//! out. The parameter is never sent upstream and isn't part of the cache
+//! key, so filtered and unfiltered callers share cached responses.
+
+use std::collections::BTreeMap;
+
+use axum::body::{to_bytes, Body};
+use axum::extract::Request;
+use axum::response::Response;
+use hyper::header::{self, HeaderValue};
+use hyper::Uri;
+use serde_json::{Map, Value};
+use tracing::warn;
+
+use crate::encoding::decode_in_place;
+
+pub const FIELDS_PARAM: &str = "_fields";
+/// Responses larger than this can't be rewritten.
+const MAX_FILTERED_BYTES: usize = 16 * 1024 * 1024;
+
+/// The requested fields as a tree of keys. An empty node keeps the whole
+/// value under it.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Fields(BTreeMap<String, Fields>);
+
+impl Fields {
+    /// Parses `a.b,c`. Empty entries are skipped.
+    pub fn parse(spec: &str) -> Self {
+        let mut root = Fields::default();
+        for path in spec.split(',') {
+            let keys: Vec<&str> = path.trim().split('.').filter(|k| !k.is_empty()).collect();
+            if keys.is_empty() {
+                continue;
+            }
+            let mut node = &mut root;
+            for key in keys {
+                if node.0.contains_key("") {
+                    // An earlier, shorter path keeps all of this already.
+                    break;
+                }
+                node = node.0.entry(key.to_string()).or_default();
+            }
+            // Asking for `a` after `a.b` keeps all of `a`; the empty key
+            // marks the end of a path until `compact`.
+            node.0.clear();
+            node.0.insert(String::new(), Fields::default());
+        }
+        root.compact();
+        root
+    }
+
+    /// Turns the end-of-path markers into empty nodes.
+    fn compact(&mut self) {
+        if self.0.contains_key("") {
+            self.0.clear();
+            return;
+        }
+        for child in self.0.values_mut() {
+            child.compact();
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Calls `f` on each value these fields name, walking through arrays.
+    pub fn visit_mut(&self, value: &mut Value, f: &mut dyn FnMut(&mut Value)) {
+        if self.0.is_empty() {
+            f(value);
+            return;
+        }
+        match value {
+            Value::Object(map) => {
+                for (key, fields) in &self.0 {
+                    if let Some(child) = map.get_mut(key) {
+                        fields.visit_mut(child, f);
+                    }
+                }
+            }
+            Value::Array(items) => {
+                for item in items {
+                    self.visit_mut(item, f);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// The parts of `value` these fields name.
+    pub fn prune(&self, value: &Value) -> Value {
+        if self.0.is_empty() {
+            return value.clone();
+        }
+        match value {
+            Value::Object(map) => {
+                let mut kept = Map::new();
+                for (key, fields) in &self.0 {
+                    if let Some(child) = map.get(key) {
+                        kept.insert(key.clone(), fields.prune(child));
+                    }
+                }
+                Value::Object(kept)
+            }
+            Value::Array(items) => {
+                Value::Array(items.iter().map(|item| self.prune(item)).collect())
+            }
+            // A scalar has no fields to pick.
+            _ => Value::Null,
+        }
+    }
+}
+
+/// Takes `_fields` out of `req`'s query when its path is under one of
+/// `prefixes`, returning the fields asked for.
+pub fn take(prefixes: &[String], req: &mut Request) -> Option<Fields> {
+    let path = req.uri().path();
+    if !prefixes
+        .iter()
+        .any(|prefix| path.starts_with(prefix.as_str()))
+    {
+        return None;
+    }
+    let query = req.uri().query()?;
+    let mut spec = None;
+    let rest: Vec<&str> = query
+        .split('&')
+        .filter(|pair| match pair.split_once('=') {
+            Some((FIELDS_PARAM, value)) => {
+                spec = Some(percent_decode(value));
+                false
+            }
+            None if *pair == FIELDS_PARAM => false,
+            _ => true,
+        })
+        .collect();
+    let spec = spec?;
+
+    let path_and_query = match rest.is_empty() {
+        true => path.to_string(),
+        false => format!("{}?{}", path, rest.join("&")),
+    };
+    let mut parts = req.uri().clone().into_parts();
+    parts.path_and_query = path_and_query.parse().ok();
+    if let Ok(uri) = Uri::from_parts(parts) {
+        *req.uri_mut() = uri;
+    }
+    Some(Fields::parse(&spec)).filter(|fields| !fields.is_empty())
+}
+
+/// Prunes a successful JSON response to `fields`. Anything else is returned
+/// as it was.
+pub async fn apply(fields: &Fields, response: Response) -> Response {
+    if !response.status().is_success() {
+        return response;
+    }
+    rewrite_json(response, |json| fields.prune(&json)).await
+}
+
+/// Replaces a JSON response's body with `f` of it, decoded. Bodies that
+/// aren't JSON are returned as they were.
+pub(crate) async fn rewrite_json(response: Response, f: impl FnOnce(Value) -> Value) -> Response {
+    let is_json = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.contains("json"));
+    if !is_json {
+        return response;
+    }
+    let (mut parts, body) = response.into_parts();
+    let mut bytes = match to_bytes(body, MAX_FILTERED_BYTES).await {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            warn!("Failed to read a JSON response to rewrite: {}", e);
+            return Response::from_parts(parts, Body::empty());
+        }
+    };
+    let original = bytes.clone();
+    let mut headers = parts.headers.clone();
+    let json = match decode_in_place(&mut headers, &mut bytes, MAX_FILTERED_BYTES) {
+        Ok(()) => serde_json::from_slice::<Value>(&bytes).ok(),
+        Err(_) => None,
+    };
+    let Some(json) = json else {
+        return Response::from_parts(parts, Body::from(original));
+    };
+    let body = f(json).to_string();
+    // The validators and length described the original representation.
+    parts.headers = headers;
+    parts.headers.remove(header::ETAG);
+    parts
+        .headers
+        .insert(header::CONTENT_LENGTH, HeaderValue::from(body.len()));
+    Response::from_parts(parts, Body::from(body))
+}
+
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                out.push(byte);
+                i += 3;
+            }
+            (b'+', _) => {
+                out.push(b' ');
+                i += 1;
+            }
+            (byte, _) => {
+                out.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn keeps_only_the_named_fields() {
+        let hotel = json!({
+            "id": 7,
+            "name": "Sea View",
+            "address": {"city": "Goa", "zip": "403001"},
+            "rooms": [
+                {"type": "double", "price": 90, "photos": ["a.jpg"]},
+                {"type": "suite", "price": 210},
+            ],
+        });
+        assert_eq!(
+            Fields::parse("name, address.city,rooms.price,missing.key").prune(&hotel),
+            json!({
+                "name": "Sea View",
+                "address": {"city": "Goa"},
+                "rooms": [{"price": 90}, {"price": 210}],
+            })
+        );
+        // A whole object wins over one of its fields, in either order.
+        let whole = json!({"address": {"city": "Goa", "zip": "403001"}});
+        assert_eq!(Fields::parse("address.city,address").prune(&hotel), whole);
+        assert_eq!(Fields::parse("address,address.city").prune(&hotel), whole);
+        assert!(Fields::parse(" , .").is_empty());
+    }
+
+    #[test]
+    fn takes_the_parameter_out_of_the_query() {
+        let prefixes = vec!["/prod/hotels".to_string()];
+        let mut req = Request::new(Body::empty());
+        *req.uri_mut() = "/prod/hotels/7?lang=en&_fields=name%2Crooms.price&x=1"
+            .parse()
+            .unwrap();
+        let fields = take(&prefixes, &mut req).unwrap();
+        assert_eq!(req.uri(), "/prod/hotels/7?lang=en&x=1");
+        assert_eq!(fields, Fields::parse("name,rooms.price"));
+
+        let mut other = Request::new(Body::empty());
+        *other.uri_mut() = "/prod/book?_fields=id".parse().unwrap();
+        assert_eq!(take(&prefixes, &mut other), None);
+        assert_eq!(other.uri(), "/prod/book?_fields=id");
+    }
+}
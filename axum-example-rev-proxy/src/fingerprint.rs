@@ -0,0 +1,116 @@
+//! A stable fingerprint of each proxied request, so dedup analysis and "was
+//! this exact request sent before" questions can be answered from the logs.
+//! It is the SHA-256 of the method, the normalized inbound path, the query
+//! with its parameters sorted, and the body: JSON bodies are hashed with
+//! their keys sorted ([`crate::sort_json`]), so key order and whitespace
+//! don't change it; other bodies are hashed as sent.
+//!
+//! The fingerprint is returned as `X-Request-Fingerprint`, written to the
+//! access log line and stored with request log entries, where
+//! `GET /admin/request-log?fingerprint=` finds earlier sends.
+
+use std::sync::{Arc, OnceLock};
+
+use hyper::header::HeaderName;
+use hyper::Method;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+use crate::sort_json::sort_json;
+
+pub const FINGERPRINT_HEADER: HeaderName = HeaderName::from_static("x-request-fingerprint");
+
+/// Hex SHA-256 of the request's method, path, sorted query and canonical body.
+pub fn compute(method: &Method, inbound_path: &str, query: Option<&str>, body: &[u8]) -> String {
+    let mut pairs: Vec<&str> = query
+        .unwrap_or_default()
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .collect();
+    pairs.sort_unstable();
+
+    let mut hasher = Sha256::new();
+    hasher.update(method.as_str());
+    hasher.update(b"\n");
+    hasher.update(inbound_path);
+    hasher.update(b"\n");
+    hasher.update(pairs.join("&"));
+    hasher.update(b"\n");
+    match serde_json::from_slice::<Value>(body) {
+        Ok(json) => hasher.update(sort_json(&json).to_string()),
+        Err(_) => hasher.update(body),
+    }
+    hex::encode(hasher.finalize())
+}
+
+/// Carried in a request's extensions so the code that reads the body can
+/// hand the fingerprint back to the access log.
+#[derive(Debug, Clone, Default)]
+pub struct FingerprintSlot(Arc<OnceLock<String>>);
+
+impl FingerprintSlot {
+    pub fn set(&self, fingerprint: String) {
+        let _ = self.0.set(fingerprint);
+    }
+
+    /// The fingerprint, once the request's body has been read.
+    pub fn get(&self) -> Option<&str> {
+        self.0.get().map(String::as_str)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ignores_key_order_and_query_order() {
+        let a = compute(
+            &Method::POST,
+            "/prod/book",
+            Some("b=2&a=1"),
+            br#"{"hotel": "h1", "guest": {"name": "A", "age": 30}}"#,
+        );
+        let b = compute(
+            &Method::POST,
+            "/prod/book",
+            Some("a=1&b=2"),
+            br#"{"guest":{"age":30,"name":"A"},"hotel":"h1"}"#,
+        );
+        assert_eq!(a, b);
+        assert_eq!(a.len(), 64);
+
+        let changed = [
+            compute(
+                &Method::PUT,
+                "/prod/book",
+                Some("a=1&b=2"),
+                br#"{"hotel":"h1"}"#,
+            ),
+            compute(
+                &Method::POST,
+                "/test/book",
+                Some("a=1&b=2"),
+                br#"{"hotel":"h1"}"#,
+            ),
+            compute(
+                &Method::POST,
+                "/prod/book",
+                Some("a=1"),
+                br#"{"hotel":"h1"}"#,
+            ),
+            compute(
+                &Method::POST,
+                "/prod/book",
+                Some("a=1&b=2"),
+                br#"{"hotel":"h2"}"#,
+            ),
+        ];
+        assert!(changed.iter().all(|other| *other != a));
+        // Bodies that aren't JSON are hashed byte for byte.
+        assert_ne!(
+            compute(&Method::POST, "/prod/raw", None, b"a b"),
+            compute(&Method::POST, "/prod/raw", None, b"a  b")
+        );
+    }
+}
@@ -0,0 +1,338 @@
+//! Optional forward-proxy listener for legacy tools that can't rewrite their
+//! paths to `/{env}/...`: they point `HTTPS_PROXY` at it and `CONNECT`
+//! through to their supplier, leaving from the static egress IP.
+//!
+//! Every tunnel needs `Proxy-Authorization: Basic` with one of the
+//! configured credentials, and may only reach an allowlisted `host:port`.
+//! Hostnames resolving to internal addresses are refused, as for the
+//! upstream client, unless internal destinations are allowed.
+
+use std::convert::Infallible;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+use axum::body::Bytes;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use http_body_util::Full;
+use hyper::body::Incoming;
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{header, Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use serde::Deserialize;
+use thiserror::Error;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+use crate::admin::constant_time_eq;
+use crate::app_state::AppState;
+use crate::egress_policy::is_internal;
+
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Settings of a forward-proxy listener; the SOCKS5 listener shares the
+/// credentials and allowlist of the `CONNECT` one.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct ForwardProxyConfig {
+    pub addr: SocketAddr,
+    /// `user:password` pairs accepted in `Proxy-Authorization`.
+    pub credentials: Vec<String>,
+    pub allowed_destinations: Vec<DestinationPattern>,
+    pub connect_timeout: Duration,
+}
+
+impl ForwardProxyConfig {
+    /// Whether tunnels to `host:port` are allowed.
+    pub fn allows(&self, host: &str, port: u16) -> bool {
+        self.allowed_destinations
+            .iter()
+            .any(|pattern| pattern.matches(host, port))
+    }
+}
+
+/// An allowlisted destination: an exact host or `*.` and a domain (matching
+/// its subdomains only), and a port or `*` for any.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct DestinationPattern {
+    pub host: String,
+    pub port: Option<u16>,
+}
+
+impl DestinationPattern {
+    pub fn matches(&self, host: &str, port: u16) -> bool {
+        let host = host
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .to_ascii_lowercase();
+        let host_matches = match self.host.strip_prefix("*.") {
+            Some(domain) => host
+                .strip_suffix(domain)
+                .is_some_and(|sub| sub.len() > 1 && sub.ends_with('.')),
+            None => host == self.host,
+        };
+        host_matches && self.port.is_none_or(|allowed| allowed == port)
+    }
+}
+
+/// `api.supplier.com:443,*.gds.example.com:*,[2001:db8::1]:8443`
+pub fn parse_destinations(value: &str) -> Result<Vec<DestinationPattern>, String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(|entry| {
+            let (host, port) = entry
+                .rsplit_once(':')
+                .ok_or_else(|| format!("destination {entry:?} needs a port (or *)"))?;
+            let port = match port {
+                "*" => None,
+                port => Some(
+                    port.parse()
+                        .map_err(|e| format!("destination {entry:?}: bad port: {e}"))?,
+                ),
+            };
+            let host = host
+                .trim_start_matches('[')
+                .trim_end_matches(']')
+                .to_ascii_lowercase();
+            if host.is_empty() {
+                return Err(format!("destination {entry:?} needs a host"));
+            }
+            Ok(DestinationPattern { host, port })
+        })
+        .collect()
+}
+
+/// Whether `header` (a `Proxy-Authorization` value) carries one of
+/// `credentials`.
+pub fn authorized(credentials: &[String], header: Option<&[u8]>) -> bool {
+    let Some(decoded) = header
+        .and_then(|value| value.strip_prefix(b"Basic "))
+        .and_then(|encoded| BASE64.decode(encoded.trim_ascii()).ok())
+    else {
+        return false;
+    };
+    credentials
+        .iter()
+        .any(|expected| constant_time_eq(&decoded, expected.as_bytes()))
+}
+
+/// Binds the configured listener and serves it in the background.
+pub async fn spawn(state: &AppState) -> std::io::Result<Option<JoinHandle<()>>> {
+    let Some(config) = &state.env_var_config.forward_proxy else {
+        return Ok(None);
+    };
+    let listener = TcpListener::bind(config.addr).await?;
+    info!("Forward proxy listening on {}", listener.local_addr()?);
+    Ok(Some(tokio::spawn(serve(listener, state.clone()))))
+}
+
+/// Accepts `CONNECT` requests on `listener` until the process exits.
+pub async fn serve(listener: TcpListener, state: AppState) {
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                warn!("Forward proxy failed to accept a connection: {}", e);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        let state = state.clone();
+        tokio::spawn(async move {
+            let service = service_fn(move |req| {
+                let state = state.clone();
+                async move { Ok::<_, Infallible>(tunnel(&state, req, peer).await) }
+            });
+            if let Err(e) = http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .with_upgrades()
+                .await
+            {
+                info!("Forward proxy connection from {} ended: {}", peer, e);
+            }
+        });
+    }
+}
+
+async fn tunnel(
+    state: &AppState,
+    mut req: Request<Incoming>,
+    peer: SocketAddr,
+) -> Response<Full<Bytes>> {
+    let Some(config) = &state.env_var_config.forward_proxy else {
+        return reply(StatusCode::NOT_FOUND, "forward proxy is off");
+    };
+    if req.method() != Method::CONNECT {
+        let mut response = reply(StatusCode::METHOD_NOT_ALLOWED, "only CONNECT is supported");
+        response
+            .headers_mut()
+            .insert(header::ALLOW, header::HeaderValue::from_static("CONNECT"));
+        return response;
+    }
+    let Some((host, port)) = req
+        .uri()
+        .authority()
+        .and_then(|authority| Some((authority.host().to_string(), authority.port_u16()?)))
+    else {
+        return reply(StatusCode::BAD_REQUEST, "CONNECT needs host:port");
+    };
+    let destination = format!("{host}:{port}");
+    let credentials = req
+        .headers()
+        .get(header::PROXY_AUTHORIZATION)
+        .map(|value| value.as_bytes());
+    if !authorized(&config.credentials, credentials) {
+        warn!(
+            "Forward proxy refused {} from {}: missing or wrong credentials",
+            destination, peer
+        );
+        state.metrics.record_tunnel("connect", "unauthorized");
+        let mut response = reply(
+            StatusCode::PROXY_AUTHENTICATION_REQUIRED,
+            "proxy credentials required",
+        );
+        response.headers_mut().insert(
+            header::PROXY_AUTHENTICATE,
+            header::HeaderValue::from_static("Basic realm=\"egress\""),
+        );
+        return response;
+    }
+    if !config.allows(&host, port) {
+        warn!(
+            "Forward proxy refused {} from {}: not an allowed destination",
+            destination, peer
+        );
+        state.metrics.record_tunnel("connect", "refused");
+        return reply(StatusCode::FORBIDDEN, "destination not allowed");
+    }
+
+    let upstream = match connect(state, &host, port, config.connect_timeout).await {
+        Ok(upstream) => upstream,
+        Err(e) => {
+            warn!("Forward proxy could not reach {}: {}", destination, e);
+            state.metrics.record_tunnel("connect", "unreachable");
+            let status = match e {
+                ConnectError::Internal(_) => StatusCode::FORBIDDEN,
+                ConnectError::Timeout => StatusCode::GATEWAY_TIMEOUT,
+                ConnectError::Dns(_) | ConnectError::Io(_) => StatusCode::BAD_GATEWAY,
+            };
+            return reply(status, &e.to_string());
+        }
+    };
+    state.metrics.record_tunnel("connect", "opened");
+    info!("Tunnelling {} to {}", peer, destination);
+    let upgrade = hyper::upgrade::on(&mut req);
+    tokio::spawn(async move {
+        let mut upstream = upstream;
+        let mut client = match upgrade.await {
+            Ok(upgraded) => TokioIo::new(upgraded),
+            Err(e) => {
+                warn!("Tunnel to {} failed to upgrade: {}", destination, e);
+                return;
+            }
+        };
+        match tokio::io::copy_bidirectional(&mut client, &mut upstream).await {
+            Ok((sent, received)) => info!(
+                "Tunnel to {} closed after {} bytes out, {} in",
+                destination, sent, received
+            ),
+            Err(e) => info!("Tunnel to {} closed: {}", destination, e),
+        }
+    });
+    Response::new(Full::default())
+}
+
+#[derive(Debug, Error)]
+pub enum ConnectError {
+    #[error("{0} resolves only to internal addresses")]
+    Internal(String),
+    #[error("{0}")]
+    Dns(String),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error("timed out connecting")]
+    Timeout,
+}
+
+/// Opens a TCP connection to `host:port`, never to an internal address of
+/// a hostname unless internal destinations are allowed.
+pub async fn connect(
+    state: &AppState,
+    host: &str,
+    port: u16,
+    timeout: Duration,
+) -> Result<TcpStream, ConnectError> {
+    let addrs: Vec<SocketAddr> = resolve(state, host)
+        .await?
+        .into_iter()
+        .map(|ip| SocketAddr::new(ip, port))
+        .collect();
+    match tokio::time::timeout(timeout, TcpStream::connect(addrs.as_slice())).await {
+        Ok(connected) => Ok(connected?),
+        Err(_) => Err(ConnectError::Timeout),
+    }
+}
+
+/// `host`'s addresses, without internal ones unless internal destinations
+/// are allowed. IP literals are trusted as configured.
+pub async fn resolve(state: &AppState, host: &str) -> Result<Vec<IpAddr>, ConnectError> {
+    let literal = host.trim_start_matches('[').trim_end_matches(']');
+    if let Ok(ip) = literal.parse::<IpAddr>() {
+        return Ok(vec![ip]);
+    }
+    let resolved = state.dns.lookup(host).await.map_err(ConnectError::Dns)?;
+    if state.env_var_config.allow_internal_destinations {
+        return Ok(resolved);
+    }
+    let public: Vec<IpAddr> = resolved
+        .iter()
+        .copied()
+        .filter(|ip| !is_internal(*ip))
+        .collect();
+    if public.len() < resolved.len() {
+        state.metrics.record_egress_violation("internal_address");
+    }
+    if public.is_empty() {
+        return Err(ConnectError::Internal(host.to_string()));
+    }
+    Ok(public)
+}
+
+fn reply(status: StatusCode, reason: &str) -> Response<Full<Bytes>> {
+    let mut response = Response::new(Full::new(Bytes::from(format!("{reason}\n"))));
+    *response.status_mut() = status;
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_allowlisted_destinations() {
+        let patterns =
+            parse_destinations("API.supplier.com:443, *.gds.example.com:*, [2001:db8::1]:8443")
+                .unwrap();
+        let allowed = |host: &str, port| patterns.iter().any(|p| p.matches(host, port));
+        assert!(allowed("api.supplier.com", 443));
+        assert!(!allowed("api.supplier.com", 80));
+        assert!(allowed("eu.gds.example.com", 8080));
+        assert!(!allowed("gds.example.com", 443));
+        assert!(!allowed("evilgds.example.com", 443));
+        assert!(allowed("[2001:db8::1]", 8443));
+        assert!(parse_destinations("api.supplier.com").is_err());
+    }
+
+    #[test]
+    fn checks_basic_credentials() {
+        let credentials = vec!["legacy:s3cret".to_string()];
+        let header = format!("Basic {}", BASE64.encode("legacy:s3cret"));
+        assert!(authorized(&credentials, Some(header.as_bytes())));
+        let wrong = format!("Basic {}", BASE64.encode("legacy:guess"));
+        assert!(!authorized(&credentials, Some(wrong.as_bytes())));
+        assert!(!authorized(&credentials, None));
+    }
+}
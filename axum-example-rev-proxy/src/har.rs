@@ -0,0 +1,199 @@
+//! HAR 1.2 (HTTP Archive) rendering of exported exchanges, so a capture can
+//! be opened in browser devtools, Fiddler or Charles. `GET /admin/export`
+//! answers with one when asked for `format=har`.
+//!
+//! Logged exchanges only know the inbound path, so their URLs are made
+//! absolute against the proxy's own address. Neither source records header
+//! sizes or per-phase timings: sizes are -1, as HAR allows, and the whole
+//! duration is counted as `wait`.
+
+use std::collections::BTreeMap;
+
+use hyper::header::CONTENT_TYPE;
+use hyper::StatusCode;
+use serde_json::{json, Value};
+
+use crate::export::Exchange;
+
+/// A HAR 1.2 document holding `exchanges`. Relative URLs are resolved
+/// against `base_url`, e.g. `http://proxy:3000`.
+pub fn to_har(exchanges: &[Exchange], base_url: &str) -> Value {
+    let entries: Vec<Value> = exchanges
+        .iter()
+        .map(|exchange| entry(exchange, base_url))
+        .collect();
+    json!({
+        "log": {
+            "version": "1.2",
+            "creator": {
+                "name": env!("CARGO_PKG_NAME"),
+                "version": env!("CARGO_PKG_VERSION"),
+            },
+            "entries": entries,
+        }
+    })
+}
+
+fn entry(exchange: &Exchange, base_url: &str) -> Value {
+    let url = match exchange.url.starts_with('/') {
+        true => format!("{}{}", base_url.trim_end_matches('/'), exchange.url),
+        false => exchange.url.clone(),
+    };
+    let query_string: Vec<Value> = url
+        .split_once('?')
+        .map(|(_, query)| query)
+        .unwrap_or_default()
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            json!({"name": name, "value": value})
+        })
+        .collect();
+    let time = exchange.duration_ms.unwrap_or(0).max(0);
+
+    let mut request = json!({
+        "method": exchange.method,
+        "url": url,
+        "httpVersion": "HTTP/1.1",
+        "cookies": [],
+        "headers": headers(&exchange.request_headers),
+        "queryString": query_string,
+        "headersSize": -1,
+        "bodySize": exchange.request_body.as_ref().map_or(-1, |body| body.len() as i64),
+    });
+    if let Some(body) = exchange
+        .request_body
+        .as_ref()
+        .filter(|body| !body.is_empty())
+    {
+        request["postData"] = json!({
+            "mimeType": mime_type(&exchange.request_headers, body),
+            "text": body,
+        });
+    }
+    let response_body = exchange.response_body.as_deref().unwrap_or_default();
+    let mut entry = json!({
+        "startedDateTime": iso8601(exchange.at_ms),
+        "time": time,
+        "request": request,
+        "response": {
+            "status": exchange.status,
+            "statusText": StatusCode::from_u16(exchange.status)
+                .ok()
+                .and_then(|status| status.canonical_reason())
+                .unwrap_or_default(),
+            "httpVersion": "HTTP/1.1",
+            "cookies": [],
+            "headers": headers(&exchange.response_headers),
+            "content": {
+                "size": response_body.len(),
+                "mimeType": mime_type(&exchange.response_headers, response_body),
+                "text": response_body,
+            },
+            "redirectURL": "",
+            "headersSize": -1,
+            "bodySize": exchange.response_body.as_ref().map_or(-1, |body| body.len() as i64),
+        },
+        "cache": {},
+        "timings": {"send": 0, "wait": time, "receive": 0},
+    });
+    // Custom fields start with an underscore.
+    if let Some(correlation_id) = &exchange.correlation_id {
+        entry["_correlationId"] = json!(correlation_id);
+    }
+    if let Some(fingerprint) = &exchange.fingerprint {
+        entry["_fingerprint"] = json!(fingerprint);
+    }
+    entry
+}
+
+fn headers(headers: &BTreeMap<String, String>) -> Vec<Value> {
+    headers
+        .iter()
+        .map(|(name, value)| json!({"name": name, "value": value}))
+        .collect()
+}
+
+/// The recorded content type, or a guess from the body for log entries,
+/// which don't keep headers.
+fn mime_type(headers: &BTreeMap<String, String>, body: &str) -> String {
+    if let Some(content_type) = headers.get(CONTENT_TYPE.as_str()) {
+        return content_type.clone();
+    }
+    match serde_json::from_str::<Value>(body) {
+        Ok(_) => "application/json".to_string(),
+        Err(_) => "text/plain".to_string(),
+    }
+}
+
+/// Unix milliseconds as `2024-03-01T12:00:00.000Z`.
+fn iso8601(ms: i64) -> String {
+    let (days, ms_of_day) = (ms.div_euclid(86_400_000), ms.rem_euclid(86_400_000));
+    // Days since 1970-01-01 to a civil date (Howard Hinnant's algorithm).
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:03}Z",
+        ms_of_day / 3_600_000,
+        ms_of_day / 60_000 % 60,
+        ms_of_day / 1000 % 60,
+        ms_of_day % 1000
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_timestamps() {
+        assert_eq!(iso8601(0), "1970-01-01T00:00:00.000Z");
+        assert_eq!(iso8601(1_709_294_400_123), "2024-03-01T12:00:00.123Z");
+        assert_eq!(iso8601(951_782_400_000), "2000-02-29T00:00:00.000Z");
+    }
+
+    #[test]
+    fn renders_a_har_entry() {
+        let exchange = Exchange {
+            at_ms: 0,
+            method: "POST".to_string(),
+            url: "/prod/api/book?city=goa&token=[REDACTED]".to_string(),
+            status: 201,
+            duration_ms: Some(42),
+            correlation_id: Some("abc".to_string()),
+            fingerprint: None,
+            request_headers: BTreeMap::new(),
+            request_body: Some(r#"{"nights":2}"#.to_string()),
+            response_headers: BTreeMap::from([(
+                "content-type".to_string(),
+                "text/html".to_string(),
+            )]),
+            response_body: Some("<p>ok</p>".to_string()),
+        };
+        let har = to_har(&[exchange], "http://proxy:3000/");
+        assert_eq!(har["log"]["version"], "1.2");
+        let entry = &har["log"]["entries"][0];
+        assert_eq!(
+            entry["request"]["url"],
+            "http://proxy:3000/prod/api/book?city=goa&token=[REDACTED]"
+        );
+        assert_eq!(
+            entry["request"]["queryString"][1],
+            json!({"name": "token", "value": "[REDACTED]"})
+        );
+        assert_eq!(entry["request"]["postData"]["mimeType"], "application/json");
+        assert_eq!(entry["response"]["statusText"], "Created");
+        assert_eq!(entry["response"]["content"]["mimeType"], "text/html");
+        assert_eq!(entry["time"], 42);
+        assert_eq!(entry["_correlationId"], "abc");
+        assert!(entry.get("_fingerprint").is_none());
+    }
+}
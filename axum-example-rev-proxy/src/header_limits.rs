@@ -0,0 +1,130 @@
+//! Limits on inbound request headers. Requests over any of them get a 431
+//! before reaching a handler, so malformed or abusive callers can't make the
+//! proxy buffer and forward oversized heads.
+
+use std::fmt;
+
+use axum::extract::{Request, State};
+use axum::middleware::Next;
+use axum::response::Response;
+use hyper::HeaderMap;
+use serde::Deserialize;
+use tracing::warn;
+
+use crate::app_state::AppState;
+use crate::error::ProxyError;
+
+pub const DEFAULT_MAX_TOTAL_BYTES: usize = 32 * 1024;
+pub const DEFAULT_MAX_HEADER_BYTES: usize = 8 * 1024;
+pub const DEFAULT_MAX_COUNT: usize = 100;
+
+/// Each limit is off when unset. Sizes count header names and values.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub struct HeaderLimits {
+    pub max_total_bytes: Option<usize>,
+    pub max_header_bytes: Option<usize>,
+    pub max_count: Option<usize>,
+}
+
+impl Default for HeaderLimits {
+    fn default() -> Self {
+        Self {
+            max_total_bytes: Some(DEFAULT_MAX_TOTAL_BYTES),
+            max_header_bytes: Some(DEFAULT_MAX_HEADER_BYTES),
+            max_count: Some(DEFAULT_MAX_COUNT),
+        }
+    }
+}
+
+/// Which limit a request broke.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Violation {
+    TotalBytes(usize),
+    HeaderBytes(String, usize),
+    Count(usize),
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::TotalBytes(bytes) => write!(f, "headers total {bytes} bytes"),
+            Self::HeaderBytes(name, bytes) => write!(f, "header {name} is {bytes} bytes"),
+            Self::Count(count) => write!(f, "{count} headers"),
+        }
+    }
+}
+
+impl HeaderLimits {
+    pub fn check(&self, headers: &HeaderMap) -> Result<(), Violation> {
+        if let Some(max) = self.max_count {
+            if headers.len() > max {
+                return Err(Violation::Count(headers.len()));
+            }
+        }
+        let mut total = 0;
+        for (name, value) in headers {
+            let size = name.as_str().len() + value.len();
+            if self.max_header_bytes.is_some_and(|max| size > max) {
+                return Err(Violation::HeaderBytes(name.to_string(), size));
+            }
+            total += size;
+        }
+        match self.max_total_bytes {
+            Some(max) if total > max => Err(Violation::TotalBytes(total)),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Middleware answering 431 to requests over the configured header limits.
+pub async fn enforce(State(state): State<AppState>, req: Request, next: Next) -> Response {
+    match state.env_var_config.header_limits.check(req.headers()) {
+        Ok(()) => next.run(req).await,
+        Err(violation) => {
+            warn!(
+                "Rejected request to {} with oversized headers: {}",
+                req.uri().path(),
+                violation
+            );
+            let correlation_id = state.correlation.read(req.headers()).unwrap_or_default();
+            ProxyError::HeadersTooLarge.to_response(&correlation_id)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checks_count_size_and_total() {
+        let limits = HeaderLimits {
+            max_total_bytes: Some(40),
+            max_header_bytes: Some(20),
+            max_count: Some(3),
+        };
+        let mut headers = HeaderMap::new();
+        headers.insert("accept", "*/*".parse().unwrap());
+        assert_eq!(limits.check(&headers), Ok(()));
+
+        headers.insert("x-long", "a".repeat(15).parse().unwrap());
+        assert_eq!(
+            limits.check(&headers),
+            Err(Violation::HeaderBytes("x-long".to_string(), 21))
+        );
+
+        headers.insert("x-long", "a".repeat(14).parse().unwrap());
+        headers.insert("x-other", "b".repeat(12).parse().unwrap());
+        assert_eq!(limits.check(&headers), Err(Violation::TotalBytes(48)));
+
+        headers.append("accept", "text/html".parse().unwrap());
+        assert_eq!(limits.check(&headers), Err(Violation::Count(4)));
+
+        let unlimited = HeaderLimits {
+            max_total_bytes: None,
+            max_header_bytes: None,
+            max_count: None,
+        };
+        assert_eq!(unlimited.check(&headers), Ok(()));
+    }
+}
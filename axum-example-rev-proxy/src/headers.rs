@@ -0,0 +1,225 @@
+//! Proxy header handling (RFC 9110 §7.6): hop-by-hop fields, `Expect`, and
+//! message framing. Bodies are fully buffered on both legs, so framing is
+//! always re-derived from the buffered body rather than copied across;
+//! responses with trailers go out chunked so the trailers can follow.
+
+use axum::body::{Body, Bytes};
+use std::convert::Infallible;
+
+use futures_util::stream;
+use http_body_util::StreamBody;
+use hyper::body::Frame;
+use hyper::header::{self, HeaderMap, HeaderName, HeaderValue};
+use hyper::{Method, StatusCode};
+
+/// Fields that describe a single connection and are never forwarded.
+/// `Trailer` is end-to-end and deliberately not listed.
+const HOP_BY_HOP: [HeaderName; 8] = [
+    header::CONNECTION,
+    HeaderName::from_static("keep-alive"),
+    HeaderName::from_static("proxy-connection"),
+    header::PROXY_AUTHENTICATE,
+    header::PROXY_AUTHORIZATION,
+    header::TE,
+    header::TRANSFER_ENCODING,
+    header::UPGRADE,
+];
+
+/// Removes the standard hop-by-hop fields plus any named in `Connection`.
+pub fn strip_hop_by_hop(headers: &mut HeaderMap) {
+    let listed: Vec<HeaderName> = headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|token| HeaderName::from_bytes(token.trim().as_bytes()).ok())
+        .collect();
+    for name in listed.iter().chain(HOP_BY_HOP.iter()) {
+        headers.remove(name);
+    }
+}
+
+/// Prepares inbound request headers for the upstream request.
+///
+/// `Expect: 100-continue` has already been honoured by reading the body, so
+/// it is dropped; any other expectation can't be met and is a 417.
+/// `Content-Length` is left to the client, which sets it from the buffered
+/// body. `TE: trailers` is kept when the caller sent it, so the upstream
+/// knows trailers will reach the caller.
+pub fn prepare_request(headers: &mut HeaderMap) -> Result<(), StatusCode> {
+    for expect in headers.get_all(header::EXPECT) {
+        let continue_only = expect
+            .to_str()
+            .is_ok_and(|value| value.trim().eq_ignore_ascii_case("100-continue"));
+        if !continue_only {
+            return Err(StatusCode::EXPECTATION_FAILED);
+        }
+    }
+    headers.remove(header::EXPECT);
+    let accepts_trailers = headers
+        .get_all(header::TE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|token| token.trim().eq_ignore_ascii_case("trailers"));
+    strip_hop_by_hop(headers);
+    headers.remove(header::CONTENT_LENGTH);
+    if accepts_trailers {
+        headers.insert(header::TE, HeaderValue::from_static("trailers"));
+    }
+    Ok(())
+}
+
+/// Whether a response with `status` to `method` may carry a body.
+pub fn permits_body(method: &Method, status: StatusCode) -> bool {
+    !(status.is_informational()
+        || status == StatusCode::NO_CONTENT
+        || status == StatusCode::NOT_MODIFIED
+        || method == Method::HEAD)
+}
+
+/// Prepares an upstream (or cached) response to `method` for the caller and
+/// returns the body to send, which is empty wherever a body is prohibited.
+///
+/// - 1xx and 204 never carry a body or `Content-Length`.
+/// - 304 and responses to HEAD carry no body; an upstream `Content-Length`
+///   describes the representation a GET would return and is kept as is.
+/// - Everything else gets `Content-Length` from the buffered body.
+pub fn prepare_response(
+    headers: &mut HeaderMap,
+    method: &Method,
+    status: StatusCode,
+    body: Bytes,
+) -> Bytes {
+    strip_hop_by_hop(headers);
+    if status.is_informational() || status == StatusCode::NO_CONTENT {
+        headers.remove(header::CONTENT_LENGTH);
+        return Bytes::new();
+    }
+    if status == StatusCode::NOT_MODIFIED || method == Method::HEAD {
+        return Bytes::new();
+    }
+    headers.insert(header::CONTENT_LENGTH, HeaderValue::from(body.len() as u64));
+    body
+}
+
+/// A response body followed by `trailers`. It has no exact size, so hyper
+/// sends it chunked; HTTP/1.1 callers only receive the trailers if they
+/// sent `TE: trailers`.
+pub fn body_with_trailers(body: Bytes, trailers: HeaderMap) -> Body {
+    let frames = [Frame::data(body), Frame::trailers(trailers)];
+    Body::new(StreamBody::new(stream::iter(
+        frames.map(Ok::<_, Infallible>),
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(
+                HeaderName::from_bytes(name.as_bytes()).unwrap(),
+                value.parse().unwrap(),
+            );
+        }
+        headers
+    }
+
+    #[test]
+    fn strips_connection_listed_and_standard_hop_by_hop_fields() {
+        let mut request = headers(&[
+            ("connection", "keep-alive, X-Session"),
+            ("connection", "upgrade"),
+            ("keep-alive", "timeout=5"),
+            ("x-session", "abc"),
+            ("upgrade", "websocket"),
+            ("te", "gzip"),
+            ("trailer", "x-checksum"),
+            ("content-length", "3"),
+            ("expect", "100-continue"),
+            ("x-api-key", "k"),
+        ]);
+
+        prepare_request(&mut request).unwrap();
+
+        let names: Vec<&str> = request.keys().map(HeaderName::as_str).collect();
+        assert_eq!(names, ["trailer", "x-api-key"]);
+    }
+
+    #[test]
+    fn forwards_only_the_trailers_te_token() {
+        let mut request = headers(&[("te", "gzip, Trailers"), ("connection", "te")]);
+
+        prepare_request(&mut request).unwrap();
+
+        assert_eq!(request[header::TE], "trailers");
+        assert!(request.get(header::CONNECTION).is_none());
+    }
+
+    #[test]
+    fn rejects_unknown_expectations() {
+        let mut request = headers(&[("expect", "something-else")]);
+        assert_eq!(
+            prepare_request(&mut request),
+            Err(StatusCode::EXPECTATION_FAILED)
+        );
+    }
+
+    #[test]
+    fn response_framing_follows_the_buffered_body() {
+        let mut response = headers(&[
+            ("transfer-encoding", "chunked"),
+            ("content-length", "99"),
+            ("content-type", "application/json"),
+        ]);
+
+        let body = prepare_response(
+            &mut response,
+            &Method::GET,
+            StatusCode::OK,
+            Bytes::from_static(b"{\"ok\":true}"),
+        );
+
+        assert_eq!(body, "{\"ok\":true}");
+        assert!(response.get(header::TRANSFER_ENCODING).is_none());
+        assert_eq!(response[header::CONTENT_LENGTH], "11");
+        assert_eq!(response[header::CONTENT_TYPE], "application/json");
+    }
+
+    #[test]
+    fn no_content_has_neither_body_nor_length() {
+        let mut response = headers(&[("content-length", "2")]);
+
+        let body = prepare_response(
+            &mut response,
+            &Method::DELETE,
+            StatusCode::NO_CONTENT,
+            Bytes::from_static(b"{}"),
+        );
+
+        assert!(body.is_empty());
+        assert!(response.get(header::CONTENT_LENGTH).is_none());
+    }
+
+    #[test]
+    fn head_and_not_modified_keep_the_representation_length() {
+        for (method, status) in [
+            (Method::HEAD, StatusCode::OK),
+            (Method::GET, StatusCode::NOT_MODIFIED),
+        ] {
+            let mut response = headers(&[("content-length", "512")]);
+
+            let body = prepare_response(&mut response, &method, status, Bytes::new());
+
+            assert!(body.is_empty());
+            assert_eq!(response[header::CONTENT_LENGTH], "512");
+        }
+
+        let mut response = HeaderMap::new();
+        prepare_response(&mut response, &Method::HEAD, StatusCode::OK, Bytes::new());
+        assert!(response.get(header::CONTENT_LENGTH).is_none());
+    }
+}
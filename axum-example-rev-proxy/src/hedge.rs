@@ -0,0 +1,139 @@
+//! Hedged GETs: when the upstream hasn't answered within the route's recent
+//! p95, a second identical attempt races the first and the earlier answer
+//! wins. Hedges are capped both in flight and as a share of requests, so a
+//! slow supplier isn't hit with double the load.
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use hyper::Method;
+use serde::Deserialize;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::latency::LatencyTracker;
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct HedgeConfig {
+    /// Inbound path prefixes (e.g. `/prod/api/search`) whose GETs are hedged.
+    pub path_prefixes: Vec<String>,
+    /// Latency quantile after which the hedge fires.
+    pub percentile: f64,
+    /// Never hedge sooner than this, however fast the route usually is.
+    pub min_delay: Duration,
+    /// Hedges allowed in flight at once.
+    pub max_in_flight: usize,
+    /// Hedges allowed as a fraction of eligible requests.
+    pub budget_ratio: f64,
+}
+
+impl Default for HedgeConfig {
+    fn default() -> Self {
+        Self {
+            path_prefixes: Vec::new(),
+            percentile: 0.95,
+            min_delay: Duration::from_millis(50),
+            max_in_flight: 10,
+            budget_ratio: 0.1,
+        }
+    }
+}
+
+/// Most hedges that can be saved up by a quiet route.
+const MAX_BUDGET: f64 = 10.0;
+
+pub struct Hedger {
+    config: HedgeConfig,
+    permits: Arc<Semaphore>,
+    budget: Mutex<f64>,
+}
+
+impl Hedger {
+    pub fn new(config: HedgeConfig) -> Self {
+        Self {
+            permits: Arc::new(Semaphore::new(config.max_in_flight)),
+            budget: Mutex::new(0.0),
+            config,
+        }
+    }
+
+    /// How long to wait before hedging a request to `route`, or `None` if it
+    /// isn't eligible or there isn't enough latency history yet. Eligible
+    /// requests also earn hedge budget.
+    pub fn delay_for(
+        &self,
+        method: &Method,
+        route: &str,
+        latency: &LatencyTracker,
+    ) -> Option<Duration> {
+        if method != Method::GET
+            || !self
+                .config
+                .path_prefixes
+                .iter()
+                .any(|prefix| route.starts_with(prefix.as_str()))
+        {
+            return None;
+        }
+        {
+            let mut budget = self.budget.lock().unwrap();
+            *budget = (*budget + self.config.budget_ratio).min(MAX_BUDGET);
+        }
+        let delay = latency.percentile(route, self.config.percentile)?;
+        Some(delay.max(self.config.min_delay))
+    }
+
+    /// Claims a hedge if both the concurrency cap and the budget allow one.
+    pub fn try_start(&self) -> Option<OwnedSemaphorePermit> {
+        let mut budget = self.budget.lock().unwrap();
+        if *budget < 1.0 {
+            return None;
+        }
+        let permit = self.permits.clone().try_acquire_owned().ok()?;
+        *budget -= 1.0;
+        Some(permit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::latency::MIN_SAMPLES;
+
+    #[test]
+    fn hedges_only_eligible_gets_within_budget() {
+        let hedger = Hedger::new(HedgeConfig {
+            path_prefixes: vec!["/prod/api/search".to_string()],
+            max_in_flight: 1,
+            budget_ratio: 0.5,
+            ..Default::default()
+        });
+        let latency = LatencyTracker::default();
+        for _ in 0..MIN_SAMPLES {
+            latency.record("/prod/api/search", Duration::from_millis(200));
+        }
+
+        assert_eq!(
+            hedger.delay_for(&Method::POST, "/prod/api/search", &latency),
+            None
+        );
+        assert_eq!(
+            hedger.delay_for(&Method::GET, "/prod/api/book", &latency),
+            None
+        );
+        assert_eq!(
+            hedger.delay_for(&Method::GET, "/prod/api/search", &latency),
+            Some(Duration::from_millis(200))
+        );
+        // Half a hedge earned so far.
+        assert!(hedger.try_start().is_none());
+
+        hedger.delay_for(&Method::GET, "/prod/api/search", &latency);
+        hedger.delay_for(&Method::GET, "/prod/api/search", &latency);
+        let permit = hedger.try_start().unwrap();
+        // Budget left, but the one slot is taken.
+        hedger.delay_for(&Method::GET, "/prod/api/search", &latency);
+        assert!(hedger.try_start().is_none());
+        drop(permit);
+        assert!(hedger.try_start().is_some());
+    }
+}
@@ -0,0 +1,183 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use axum::body::Bytes;
+use hmac::{Hmac, Mac};
+use hyper::header::{self, HeaderName, HeaderValue};
+use hyper::{HeaderMap, Method, StatusCode, Uri};
+use sha2::Sha512;
+use tracing::{error, info};
+
+type HmacSha512 = Hmac<Sha512>;
+
+/// The request as it will be sent upstream. Interceptors may rewrite any part.
+#[derive(Debug, Clone)]
+pub struct OutboundRequest {
+    /// The `{env}` path segment the request arrived under.
+    pub env: String,
+    pub method: Method,
+    pub uri: Uri,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+}
+
+/// The upstream response before it is returned to the caller.
+#[derive(Debug, Clone)]
+pub struct UpstreamResponse {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+    /// Trailer fields the upstream sent after a chunked body.
+    pub trailers: Option<HeaderMap>,
+}
+
+/// Mutates an outbound request. Returning an error aborts the request with
+/// that status.
+#[async_trait]
+pub trait RequestInterceptor: Send + Sync {
+    async fn on_request(&self, req: &mut OutboundRequest) -> Result<(), StatusCode>;
+}
+
+/// Mutates an upstream response. `req` is the request as it was sent.
+#[async_trait]
+pub trait ResponseInterceptor: Send + Sync {
+    async fn on_response(
+        &self,
+        req: &OutboundRequest,
+        res: &mut UpstreamResponse,
+    ) -> Result<(), StatusCode>;
+}
+
+/// An ordered list of request and response interceptors.
+#[derive(Clone, Default)]
+pub struct InterceptorChain {
+    pub request: Vec<Arc<dyn RequestInterceptor>>,
+    pub response: Vec<Arc<dyn ResponseInterceptor>>,
+}
+
+/// Global interceptors plus per-route (per `{env}`) additions.
+///
+/// Request interceptors run global-first, then the route's. Response
+/// interceptors run in the opposite order, so a route's own interceptors
+/// see the response before the global ones do.
+#[derive(Clone, Default)]
+pub struct Interceptors {
+    pub global: InterceptorChain,
+    pub routes: BTreeMap<String, InterceptorChain>,
+}
+
+impl Interceptors {
+    /// The chain every route gets by default: Host rewriting and logging.
+    pub fn with_defaults() -> Self {
+        let mut interceptors = Self::default();
+        interceptors.global.request.push(Arc::new(HostRewrite));
+        interceptors.global.request.push(Arc::new(RequestLogger));
+        interceptors.global.response.push(Arc::new(ResponseLogger));
+        interceptors
+    }
+
+    pub async fn run_request(&self, req: &mut OutboundRequest) -> Result<(), StatusCode> {
+        let route = self.routes.get(&req.env);
+        let chain = self
+            .global
+            .request
+            .iter()
+            .chain(route.into_iter().flat_map(|c| c.request.iter()));
+        for interceptor in chain {
+            interceptor.on_request(req).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn run_response(
+        &self,
+        req: &OutboundRequest,
+        res: &mut UpstreamResponse,
+    ) -> Result<(), StatusCode> {
+        let route = self.routes.get(&req.env);
+        let chain = route
+            .into_iter()
+            .flat_map(|c| c.response.iter())
+            .chain(self.global.response.iter());
+        for interceptor in chain {
+            interceptor.on_response(req, res).await?;
+        }
+        Ok(())
+    }
+}
+
+//
+// BUILT-IN INTERCEPTORS
+//
+
+/// Replaces the inbound `Host` header with the upstream authority.
+pub struct HostRewrite;
+
+#[async_trait]
+impl RequestInterceptor for HostRewrite {
+    async fn on_request(&self, req: &mut OutboundRequest) -> Result<(), StatusCode> {
+        // Use the full authority so non-default ports survive the rewrite.
+        let target_host = req.uri.authority().ok_or(StatusCode::BAD_GATEWAY)?;
+        let value =
+            HeaderValue::from_str(target_host.as_str()).map_err(|_| StatusCode::BAD_GATEWAY)?;
+        req.headers.insert(header::HOST, value);
+        Ok(())
+    }
+}
+
+pub struct RequestLogger;
+
+#[async_trait]
+impl RequestInterceptor for RequestLogger {
+    async fn on_request(&self, req: &mut OutboundRequest) -> Result<(), StatusCode> {
+        info!("Forwarding to URI: {}", req.uri);
+        Ok(())
+    }
+}
+
+pub struct ResponseLogger;
+
+#[async_trait]
+impl ResponseInterceptor for ResponseLogger {
+    async fn on_response(
+        &self,
+        _req: &OutboundRequest,
+        res: &mut UpstreamResponse,
+    ) -> Result<(), StatusCode> {
+        info!("Response Status: {}", res.status);
+        Ok(())
+    }
+}
+
+/// Signs the outbound body with HMAC-SHA512 and sends the hex digest in `header`.
+pub struct HmacBodySigner {
+    header: HeaderName,
+    secret: Vec<u8>,
+}
+
+impl HmacBodySigner {
+    pub fn new(header: HeaderName, secret: impl Into<Vec<u8>>) -> Self {
+        Self {
+            header,
+            secret: secret.into(),
+        }
+    }
+}
+
+#[async_trait]
+impl RequestInterceptor for HmacBodySigner {
+    async fn on_request(&self, req: &mut OutboundRequest) -> Result<(), StatusCode> {
+        let mut mac = HmacSha512::new_from_slice(&self.secret).map_err(|e| {
+            error!("Invalid signing key: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+        mac.update(&req.body);
+        let signature = hex::encode(mac.finalize().into_bytes());
+        req.headers.insert(
+            self.header.clone(),
+            HeaderValue::from_str(&signature).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?,
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,284 @@
+//! `GET /admin/extract/{env}/{path}` calls `env`'s upstream like the proxy
+//! would and answers with just the part of its JSON named by the JSONPath in
+//! `X-Proxy-JsonPath`, for monitoring scripts that poll one nested value.
+//!
+//! The supported subset is `$`, `.key`, `['key']`, `[n]` (negative counts
+//! from the end), `[*]`, `.*` and `..key`. A path naming one value answers
+//! with that value, or 404 when it isn't there; one with a wildcard or `..`
+//! answers with the array of everything it matched. The upstream's status
+//! is passed on.
+
+use axum::extract::{Path, State};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use hyper::header::{HeaderMap, HeaderName};
+use hyper::{Method, StatusCode};
+use serde_json::{json, Value};
+use tracing::warn;
+
+use crate::app_state::AppState;
+use crate::correlation;
+use crate::encoding::decode_body;
+use crate::memory_budget::DEFAULT_MAX_INFLIGHT_BYTES;
+use crate::proxy::send_to_upstream;
+
+pub const JSONPATH_HEADER: HeaderName = HeaderName::from_static("x-proxy-jsonpath");
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Step {
+    Key(String),
+    Index(i64),
+    Wildcard,
+    /// `..key`: `key` at any depth.
+    Descend(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JsonPath(Vec<Step>);
+
+impl JsonPath {
+    pub fn parse(expr: &str) -> Result<Self, String> {
+        let rest = expr
+            .trim()
+            .strip_prefix('$')
+            .ok_or("a JSONPath starts with $")?;
+        let mut steps = Vec::new();
+        let mut chars = rest.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '.' if chars.peek() == Some(&'.') => {
+                    chars.next();
+                    let key = take_name(&mut chars);
+                    if key.is_empty() {
+                        return Err("`..` needs a key".to_string());
+                    }
+                    steps.push(Step::Descend(key));
+                }
+                '.' if chars.peek() == Some(&'*') => {
+                    chars.next();
+                    steps.push(Step::Wildcard);
+                }
+                '.' => {
+                    let key = take_name(&mut chars);
+                    if key.is_empty() {
+                        return Err("`.` needs a key".to_string());
+                    }
+                    steps.push(Step::Key(key));
+                }
+                '[' => {
+                    let mut inner = String::new();
+                    let mut quote = None;
+                    loop {
+                        match chars.next() {
+                            None => return Err("unclosed [".to_string()),
+                            Some(c) if Some(c) == quote => quote = None,
+                            Some(c @ ('\'' | '"')) if quote.is_none() && inner.is_empty() => {
+                                quote = Some(c);
+                                inner.push(c);
+                            }
+                            Some(']') if quote.is_none() => break,
+                            Some(c) => inner.push(c),
+                        }
+                    }
+                    let inner = inner.trim();
+                    let step = if inner == "*" {
+                        Step::Wildcard
+                    } else if let Some(key) =
+                        inner.strip_prefix('\'').or_else(|| inner.strip_prefix('"'))
+                    {
+                        Step::Key(key.to_string())
+                    } else {
+                        Step::Index(
+                            inner
+                                .parse()
+                                .map_err(|_| format!("invalid index [{inner}]"))?,
+                        )
+                    };
+                    steps.push(step);
+                }
+                other => return Err(format!("unexpected {other:?}")),
+            }
+        }
+        Ok(Self(steps))
+    }
+
+    /// Whether the path names at most one value.
+    pub fn is_definite(&self) -> bool {
+        self.0
+            .iter()
+            .all(|step| matches!(step, Step::Key(_) | Step::Index(_)))
+    }
+
+    /// Everything the path matches in `value`, in document order.
+    pub fn select<'a>(&self, value: &'a Value) -> Vec<&'a Value> {
+        let mut current = vec![value];
+        for step in &self.0 {
+            let mut next = Vec::new();
+            for value in current {
+                match step {
+                    Step::Key(key) => next.extend(value.get(key)),
+                    Step::Index(index) => {
+                        if let Value::Array(items) = value {
+                            let index = match *index {
+                                i if i < 0 => items.len() as i64 + i,
+                                i => i,
+                            };
+                            next.extend(usize::try_from(index).ok().and_then(|i| items.get(i)));
+                        }
+                    }
+                    Step::Wildcard => match value {
+                        Value::Array(items) => next.extend(items),
+                        Value::Object(map) => next.extend(map.values()),
+                        _ => {}
+                    },
+                    Step::Descend(key) => descend(value, key, &mut next),
+                }
+            }
+            current = next;
+        }
+        current
+    }
+}
+
+fn take_name(chars: &mut std::iter::Peekable<std::str::Chars<'_>>) -> String {
+    let mut name = String::new();
+    while let Some(&c) = chars.peek() {
+        if c == '.' || c == '[' {
+            break;
+        }
+        name.push(c);
+        chars.next();
+    }
+    name
+}
+
+fn descend<'a>(value: &'a Value, key: &str, out: &mut Vec<&'a Value>) {
+    match value {
+        Value::Object(map) => {
+            if let Some(found) = map.get(key) {
+                out.push(found);
+            }
+            for child in map.values() {
+                descend(child, key, out);
+            }
+        }
+        Value::Array(items) => {
+            for child in items {
+                descend(child, key, out);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn error(status: StatusCode, message: impl Into<String>) -> Response {
+    (status, Json(json!({ "error": message.into() }))).into_response()
+}
+
+/// `GET /admin/extract/{env}/{path}`: the part of the upstream's JSON named
+/// by `X-Proxy-JsonPath`.
+pub async fn extract_handler(
+    State(state): State<AppState>,
+    Path((env, path)): Path<(String, String)>,
+    uri: hyper::Uri,
+    mut headers: HeaderMap,
+) -> Response {
+    let expr = match headers.remove(JSONPATH_HEADER) {
+        Some(expr) => expr,
+        None => return error(StatusCode::BAD_REQUEST, "X-Proxy-JsonPath is required"),
+    };
+    let path_expr = match expr
+        .to_str()
+        .map_err(|e| e.to_string())
+        .and_then(JsonPath::parse)
+    {
+        Ok(path_expr) => path_expr,
+        Err(e) => return error(StatusCode::BAD_REQUEST, format!("invalid JSONPath: {e}")),
+    };
+    // The admin token is for us, not the supplier.
+    headers.remove(hyper::header::AUTHORIZATION);
+    let correlation_id = state
+        .correlation
+        .read(&headers)
+        .unwrap_or_else(correlation::generate);
+    let path_and_query = match uri.query() {
+        Some(query) => format!("/{path}?{query}"),
+        None => format!("/{path}"),
+    };
+    let upstream = match send_to_upstream(
+        &state,
+        &env,
+        Method::GET,
+        &path_and_query,
+        headers,
+        Default::default(),
+    )
+    .await
+    {
+        Ok(upstream) => upstream,
+        Err(e) => return e.to_response(&correlation_id),
+    };
+    let limit = state
+        .env_var_config
+        .max_inflight_body_bytes
+        .unwrap_or(DEFAULT_MAX_INFLIGHT_BYTES);
+    let json = decode_body(&upstream.headers, &upstream.body, limit)
+        .ok()
+        .and_then(|body| serde_json::from_slice::<Value>(&body).ok());
+    let Some(json) = json else {
+        warn!("Upstream answer to {} {} is not JSON", env, path_and_query);
+        return error(StatusCode::BAD_GATEWAY, "the upstream response is not JSON");
+    };
+    let selected = path_expr.select(&json);
+    let body = if path_expr.is_definite() {
+        match selected.first() {
+            Some(&value) => value.clone(),
+            None => return error(StatusCode::NOT_FOUND, "the JSONPath matched nothing"),
+        }
+    } else {
+        Value::Array(selected.into_iter().cloned().collect())
+    };
+    (upstream.status, Json(body)).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn select(expr: &str, value: &Value) -> Vec<Value> {
+        JsonPath::parse(expr)
+            .unwrap()
+            .select(value)
+            .into_iter()
+            .cloned()
+            .collect()
+    }
+
+    #[test]
+    fn selects_the_supported_subset() {
+        let doc = json!({
+            "status": {"db": "up", "queue": {"depth": 3}},
+            "hotels": [
+                {"name": "Sea View", "rate": {"amount": 90}},
+                {"name": "Hill Top", "rate": {"amount": 120}},
+            ],
+        });
+        assert_eq!(select("$.status.queue.depth", &doc), [json!(3)]);
+        assert_eq!(select("$['status'][\"db\"]", &doc), [json!("up")]);
+        assert_eq!(select("$.hotels[-1].name", &doc), [json!("Hill Top")]);
+        assert_eq!(
+            select("$.hotels[*].rate.amount", &doc),
+            [json!(90), json!(120)]
+        );
+        assert_eq!(select("$..amount", &doc), [json!(90), json!(120)]);
+        assert_eq!(select("$.status.*", &doc).len(), 2);
+        assert!(select("$.hotels[5]", &doc).is_empty());
+        assert_eq!(select("$", &doc), std::slice::from_ref(&doc));
+
+        assert!(JsonPath::parse("$.a").unwrap().is_definite());
+        assert!(!JsonPath::parse("$..a").unwrap().is_definite());
+        for bad in ["a.b", "$.", "$[1", "$[x]", "$..", "$ a"] {
+            assert!(JsonPath::parse(bad).is_err(), "{bad}");
+        }
+    }
+}
@@ -0,0 +1,133 @@
+//! Recent upstream latencies per route, for decisions that need percentiles
+//! (when to hedge, how long to wait for an answer).
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use serde::Deserialize;
+
+/// Samples kept per route.
+const WINDOW: usize = 256;
+/// Fewer samples than this give no percentile.
+pub const MIN_SAMPLES: usize = 20;
+/// Routes tracked at once; paths with IDs in them would otherwise grow the
+/// map without bound. New routes are ignored once full.
+const MAX_ROUTES: usize = 1024;
+
+#[derive(Debug, Default)]
+pub struct LatencyTracker {
+    routes: Mutex<HashMap<String, VecDeque<Duration>>>,
+}
+
+impl LatencyTracker {
+    pub fn record(&self, route: &str, latency: Duration) {
+        let mut routes = self.routes.lock().unwrap();
+        if !routes.contains_key(route) && routes.len() >= MAX_ROUTES {
+            return;
+        }
+        let samples = routes.entry(route.to_string()).or_default();
+        if samples.len() == WINDOW {
+            samples.pop_front();
+        }
+        samples.push_back(latency);
+    }
+
+    /// The `q` quantile (0.0-1.0) of the route's recent latencies, once
+    /// there are at least [`MIN_SAMPLES`] of them.
+    pub fn percentile(&self, route: &str, q: f64) -> Option<Duration> {
+        let routes = self.routes.lock().unwrap();
+        let samples = routes.get(route)?;
+        if samples.len() < MIN_SAMPLES {
+            return None;
+        }
+        let mut sorted: Vec<Duration> = samples.iter().copied().collect();
+        sorted.sort_unstable();
+        let rank = ((sorted.len() as f64) * q.clamp(0.0, 1.0)).ceil() as usize;
+        Some(sorted[rank.saturating_sub(1).min(sorted.len() - 1)])
+    }
+}
+
+/// Upstream timeouts derived from each route's latency history, instead of
+/// one static timeout for every endpoint.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct AdaptiveTimeoutConfig {
+    /// Latency quantile the timeout is based on.
+    pub percentile: f64,
+    /// Multiplier applied to that quantile.
+    pub factor: f64,
+    pub min: Duration,
+    /// Also used for routes without enough history yet.
+    pub max: Duration,
+}
+
+impl Default for AdaptiveTimeoutConfig {
+    fn default() -> Self {
+        Self {
+            percentile: 0.99,
+            factor: 3.0,
+            min: Duration::from_secs(1),
+            max: Duration::from_secs(30),
+        }
+    }
+}
+
+impl AdaptiveTimeoutConfig {
+    pub fn timeout_for(&self, route: &str, latency: &LatencyTracker) -> Duration {
+        latency
+            .percentile(route, self.percentile)
+            .map_or(self.max, |quantile| quantile.mul_f64(self.factor))
+            .clamp(self.min, self.max)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percentiles_need_enough_recent_samples() {
+        let tracker = LatencyTracker::default();
+        for ms in 1..MIN_SAMPLES as u64 {
+            tracker.record("/test/api", Duration::from_millis(ms));
+        }
+        assert_eq!(tracker.percentile("/test/api", 0.95), None);
+
+        for ms in MIN_SAMPLES as u64..=100 {
+            tracker.record("/test/api", Duration::from_millis(ms));
+        }
+        assert_eq!(
+            tracker.percentile("/test/api", 0.95),
+            Some(Duration::from_millis(95))
+        );
+        assert_eq!(
+            tracker.percentile("/test/api", 1.0),
+            Some(Duration::from_millis(100))
+        );
+        assert_eq!(tracker.percentile("/test/other", 0.5), None);
+    }
+
+    #[test]
+    fn adaptive_timeouts_scale_the_percentile_within_bounds() {
+        let config = AdaptiveTimeoutConfig {
+            percentile: 0.99,
+            factor: 2.0,
+            min: Duration::from_millis(100),
+            max: Duration::from_secs(5),
+        };
+        let tracker = LatencyTracker::default();
+        assert_eq!(config.timeout_for("/test/api", &tracker), config.max);
+
+        for _ in 0..MIN_SAMPLES {
+            tracker.record("/test/api", Duration::from_millis(400));
+            tracker.record("/test/fast", Duration::from_millis(10));
+            tracker.record("/test/slow", Duration::from_secs(4));
+        }
+        assert_eq!(
+            config.timeout_for("/test/api", &tracker),
+            Duration::from_millis(800)
+        );
+        assert_eq!(config.timeout_for("/test/fast", &tracker), config.min);
+        assert_eq!(config.timeout_for("/test/slow", &tracker), config.max);
+    }
+}
@@ -0,0 +1,199 @@
+use axum::routing::{any, get, post};
+use axum::{Json, Router};
+use tower_http::trace::TraceLayer;
+
+pub mod admin;
+pub mod api_version;
+pub mod app_state;
+#[cfg(feature = "archive")]
+pub mod archive;
+pub mod balancer;
+pub mod batch;
+pub mod builder;
+pub mod cache;
+pub mod canonical;
+pub mod capture;
+pub mod client_tag;
+pub mod coalesce;
+pub mod compare;
+pub mod conditional;
+pub mod config;
+pub mod connection_limit;
+pub mod contract;
+pub mod correlation;
+pub mod cors;
+pub mod cron;
+pub mod dashboard;
+pub mod debug_log;
+pub mod dedup;
+pub mod dns;
+pub mod dry_run;
+pub mod egress_ip;
+pub mod egress_policy;
+pub mod email;
+pub mod encoding;
+pub mod error;
+pub mod export;
+pub mod failover;
+pub mod fair_queue;
+pub mod fault;
+pub mod fields;
+pub mod fingerprint;
+pub mod forward_proxy;
+pub mod har;
+pub mod header_limits;
+pub mod headers;
+pub mod hedge;
+pub mod info;
+pub mod interceptor;
+pub mod jsonpath;
+pub mod latency;
+pub mod log_control;
+pub mod maintenance;
+pub mod masking;
+pub mod memory_budget;
+pub mod metrics;
+pub mod normalize;
+pub mod nowpayments_ipn_webhook;
+pub mod openapi;
+pub mod pause;
+pub mod payload_size;
+pub mod prober;
+pub mod profile;
+pub mod proxy;
+pub mod rate_limit;
+pub mod rbac;
+#[cfg(feature = "redis")]
+pub mod redis_store;
+pub mod replay;
+pub mod request_log;
+pub mod response_headers;
+pub mod rolling;
+pub mod runtime_metrics;
+pub mod schedule;
+#[cfg(feature = "scripting")]
+pub mod scripting;
+pub mod server;
+pub mod slo;
+#[cfg(feature = "smtp")]
+pub mod smtp;
+pub mod smuggling;
+pub mod soap;
+#[cfg(feature = "socks5")]
+pub mod socks5;
+pub mod sort_json;
+#[cfg(feature = "request_log")]
+pub mod sql_request_log;
+#[cfg(feature = "sql_storage")]
+pub mod sql_storage;
+pub mod storage;
+pub mod store_forward;
+pub mod tail;
+pub mod target_override;
+pub mod tcp_tunnel;
+pub mod tenant;
+pub mod timings;
+pub mod transfers;
+pub mod udp_relay;
+pub mod upstream_errors;
+pub mod xml_json;
+
+use app_state::AppState;
+use nowpayments_ipn_webhook::nowpayments_webhook;
+
+/// Which optional routes and layers to wire up. See [`builder::ProxyBuilder`].
+#[derive(Debug, Clone, Copy)]
+pub struct RouteOptions {
+    pub trace: bool,
+    pub webhook: bool,
+    pub metrics_endpoint: bool,
+    /// Mounts `/admin` and `/egress-ip` when an admin token is configured.
+    pub admin: bool,
+}
+
+impl Default for RouteOptions {
+    fn default() -> Self {
+        Self {
+            trace: true,
+            webhook: true,
+            metrics_endpoint: true,
+            admin: true,
+        }
+    }
+}
+
+/// Builds the proxy router with all routes wired to the given state.
+///
+/// The router must be served with
+/// `into_make_service_with_connect_info::<SocketAddr>()` since the webhook
+/// handler inspects the peer address.
+pub fn router(app_state: AppState) -> Router {
+    router_with_options(app_state, RouteOptions::default())
+}
+
+pub fn router_with_options(app_state: AppState, options: RouteOptions) -> Router {
+    let spec = Json(openapi::document(&app_state, &options));
+    let mut router = Router::new().route("/openapi.json", get(move || async move { spec }));
+    if options.webhook {
+        // NOWPayments webhook route.
+        router = router.route("/nowpayments-webhook", post(nowpayments_webhook));
+    }
+    if options.metrics_endpoint {
+        router = router
+            .route("/metrics", get(metrics::metrics_handler))
+            .route("/metrics/paths", get(metrics::path_metrics_handler))
+            .route("/slo", get(slo::slo_handler))
+            .route("/info", get(info::info_handler));
+    }
+    if options.admin && rbac::enabled(&app_state.env_var_config) {
+        router = router
+            .nest("/admin", admin::admin_router(app_state.clone()))
+            .route(
+                "/egress-ip",
+                get(egress_ip::egress_ip_handler).route_layer(
+                    axum::middleware::from_fn_with_state(app_state.clone(), admin::require_viewer),
+                ),
+            );
+    }
+    if app_state.store_forward.is_some() {
+        router = router.route("/deliveries/{id}", get(store_forward::status_handler));
+    }
+    if app_state.email.is_some() {
+        router = router.route("/email/send", post(email::send_handler));
+    }
+    for tenant in &app_state.env_var_config.tenants {
+        let prefix = &tenant.name;
+        router = router
+            .route(&format!("/{prefix}/metrics"), get(tenant::metrics_handler))
+            .route(
+                &format!("/{prefix}/{{env}}/{{*wildcard_path}}"),
+                any(tenant::proxy_handler),
+            );
+    }
+    if app_state.env_var_config.batch_max_requests.is_some() {
+        router = router.route("/{env}/_batch", post(batch::batch_handler));
+    }
+    let router = router
+        .route("/readyz", get(egress_ip::readyz_handler))
+        .route("/{env}/{*wildcard_path}", any(proxy::handler))
+        .layer(axum::middleware::from_fn_with_state(
+            app_state.clone(),
+            header_limits::enforce,
+        ))
+        .layer(axum::middleware::from_fn_with_state(
+            app_state.clone(),
+            smuggling::enforce,
+        ))
+        .with_state(app_state);
+
+    if !options.trace {
+        return router;
+    }
+
+    let trace_layer =
+        TraceLayer::new_for_http().make_span_with(|request: &axum::extract::Request<_>| {
+            let uri = request.uri().to_string();
+            tracing::info_span!("proxifier_http_request", method = ?request.method(), uri)
+        });
+    router.layer(trace_layer)
+}
@@ -0,0 +1,66 @@
+//! Runtime-adjustable tracing filter, driven by `PUT /admin/loglevel`.
+
+use tracing::Subscriber;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, reload, EnvFilter, Registry};
+
+/// Swaps the active [`EnvFilter`] of the subscriber it was created with.
+#[derive(Clone)]
+pub struct LogControl {
+    handle: reload::Handle<EnvFilter, Registry>,
+}
+
+impl LogControl {
+    /// Replaces the filter with `directives`, e.g. `info,axum_example_rev_proxy::proxy=debug`.
+    pub fn set(&self, directives: &str) -> Result<(), String> {
+        let filter = EnvFilter::try_new(directives).map_err(|e| e.to_string())?;
+        self.handle.reload(filter).map_err(|e| e.to_string())
+    }
+
+    /// The filter currently in effect.
+    pub fn current(&self) -> String {
+        self.handle
+            .with_current(|filter| filter.to_string())
+            .unwrap_or_default()
+    }
+}
+
+/// A fmt subscriber whose filter can be changed through the returned handle.
+/// The handle stops working once the subscriber is dropped.
+pub fn subscriber(filter: EnvFilter) -> (impl Subscriber + Send + Sync, LogControl) {
+    let (filter, handle) = reload::Layer::new(filter);
+    let subscriber = tracing_subscriber::registry()
+        .with(filter)
+        .with(fmt::layer());
+    (subscriber, LogControl { handle })
+}
+
+/// Installs the global subscriber, filtered by `RUST_LOG` to start with.
+pub fn init_tracing() -> LogControl {
+    let (subscriber, control) = subscriber(EnvFilter::from_default_env());
+    subscriber.init();
+    control
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replaces_filter_and_rejects_invalid_directives() {
+        let (_subscriber, control) = subscriber(EnvFilter::new("info"));
+
+        control
+            .set("warn,axum_example_rev_proxy::proxy=debug")
+            .unwrap();
+        let current = control.current();
+        assert!(current.contains("warn"));
+        assert!(current.contains("axum_example_rev_proxy::proxy=debug"));
+
+        assert!(control.set("proxy=loud").is_err());
+        assert!(control
+            .current()
+            .contains("axum_example_rev_proxy::proxy=debug"));
+    }
+}
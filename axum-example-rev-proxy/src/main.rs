@@ -1,236 +1,134 @@
-use axum::body::to_bytes;
-use axum::extract::Path;
-use axum::routing::{any, post};
-use axum::{
-    body::Body,
-    extract::{Request, State},
-    http::uri::Uri,
-    response::{IntoResponse, Response},
-    Router,
-};
-use flate2::read::GzDecoder;
-#[cfg(feature = "debug_response")]
-use flate2::write::GzEncoder;
-#[cfg(feature = "debug_response")]
-use flate2::Compression;
-use hyper::{header, StatusCode};
-use reqwest;
-use serde::Deserialize;
-use std::io::Read;
-#[cfg(feature = "debug_response")]
-use std::io::Write;
-use tower_http::trace::TraceLayer;
-use tracing::{error, info};
-
-mod app_state;
-mod nowpayments_ipn_webhook;
-mod sort_json;
-
-use app_state::AppState;
-use nowpayments_ipn_webhook::nowpayments_webhook;
-
-type Client = reqwest::Client;
-
-/// Struct to deserialize path parameters.
-/// - `env`: Represents the environment (`test` or `prod`).
-/// - `wildcard_path`: Represents the remaining path after the environment prefix.
-#[derive(Deserialize)]
-struct PathParams {
-    env: String,
-    wildcard_path: String,
-}
-
-const MAX_BODY_SIZE: usize = 8 * 1024 * 1024; // 8 MB
-
-#[tokio::main]
-async fn main() {
-    // Initialize tracing for logging
-    tracing_subscriber::fmt::init();
-
-    let trace_layer =
-        TraceLayer::new_for_http().make_span_with(|request: &axum::extract::Request<_>| {
-            let uri = request.uri().to_string();
-            tracing::info_span!("proxifier_http_request", method = ?request.method(), uri)
-        });
-
-    let client = Client::builder()
+use std::path::Path;
+
+use axum_example_rev_proxy::app_state::EnvVarConfig;
+use axum_example_rev_proxy::builder::ProxyBuilder;
+use axum_example_rev_proxy::metrics::{self, Metrics};
+use axum_example_rev_proxy::replay::{self, ReplayOptions};
+use axum_example_rev_proxy::server::RuntimeConfig;
+use axum_example_rev_proxy::{log_control, profile, server};
+
+fn main() {
+    // Initialize tracing for logging; the filter can be changed at /admin/loglevel.
+    let log_control = log_control::init_tracing();
+    let runtime = RuntimeConfig::from_env()
         .build()
-        .expect("Failed to create reqwest client");
-
-    let app_state = AppState::build(client).await;
-
-    let app = Router::new()
-        .route("/nowpayments-webhook", post(nowpayments_webhook))
-        .route("/{env}/{*wildcard_path}", any(handler))
-        // NOWPayments webhook route.
-        .with_state(app_state)
-        .layer(trace_layer);
-
-    // Create listener for IPv6
-    let ipv6_listener = tokio::net::TcpListener::bind("[::]:80").await.unwrap();
-    tracing::info!("Listening on IPv6 {}", ipv6_listener.local_addr().unwrap());
-
-    axum::serve(ipv6_listener, app).await.unwrap();
-}
-
-async fn handler(
-    State(app_state): State<AppState>,
-    Path(PathParams { env, wildcard_path }): Path<PathParams>,
-    req: Request,
-) -> Result<Response, StatusCode> {
-    // Determine the target_base URL based on the environment
-    let target_base = match env.as_str() {
-        "test" => "http://test.services.travelomatix.com",
-        "prod" => "https://prod.services.travelomatix.com",
-        _ => {
-            error!("Invalid environment: {}", env);
-            return Err(StatusCode::BAD_REQUEST);
+        .expect("Failed to build the Tokio runtime");
+    let mut args = std::env::args().skip(1);
+    match args.next().as_deref() {
+        Some("replay") => {
+            let options = ReplayOptions::parse(args).unwrap_or_else(|e| {
+                eprintln!("{e}");
+                std::process::exit(2);
+            });
+            runtime.block_on(run_replay(log_control, options));
         }
-    };
-
-    // Construct the new path by removing the `/test` or `/prod` prefix
-    let new_path = format!("/{}", wildcard_path);
-
-    let query = req
-        .uri()
-        .query()
-        .map(|q| format!("?{}", q))
-        .unwrap_or_default();
-    let uri = format!("{}{}{}", target_base, new_path, query);
-    info!("Forwarding to URI: {}", uri);
-
-    // Parse target URI to extract host
-    let target_uri = uri.parse::<Uri>().map_err(|e| {
-        error!("Failed to parse target URI: {}", e);
-        StatusCode::BAD_GATEWAY
-    })?;
-
-    //
-    // adjust headers
-    //
-
-    let target_host = target_uri.host().ok_or(StatusCode::BAD_GATEWAY)?;
-
-    let mut headers = req.headers().clone();
-    headers.remove(header::HOST);
-    headers.insert(
-        header::HOST,
-        header::HeaderValue::from_str(target_host).map_err(|_| StatusCode::BAD_GATEWAY)?,
-    );
-
-    // Build outbound request
-    let client = app_state.client;
-    let mut request_builder = client.request(req.method().clone(), &uri).headers(headers);
-
-    // Forward body if present
-    if let Ok(bytes) = to_bytes(req.into_body(), MAX_BODY_SIZE).await {
-        request_builder = request_builder.body(bytes);
-    }
-
-    let response = request_builder.send().await.map_err(|e| {
-        error!("Request failed: {}", e);
-        StatusCode::BAD_GATEWAY
-    })?;
-
-    //
-    // == Handling the response ==
-    //
-    let status = response.status();
-    let headers = response.headers().clone();
-    let body_bytes = response.bytes().await.map_err(|e| {
-        error!("Failed to read response body: {}", e);
-        StatusCode::BAD_GATEWAY
-    })?;
-
-    info!("Response Status: {}", status);
-
-    // If the `debug_response` feature is enabled, we decode, log, and optionally re-encode.
-    // Otherwise, we forward as-is.
-    #[cfg(feature = "debug_response")]
-    {
-        for (key, value) in headers.iter() {
-            info!("Response Header: {}: {:?}", key, value);
+        // `config`: prints the effective settings instead of serving.
+        Some("config") => {
+            EnvVarConfig::try_from_env();
+            print!("{}", profile::effective());
         }
+        _ => runtime.block_on(run(log_control)),
+    }
+}
 
-        info!("`debug_response` feature is enabled: decoding and re-encoding the response.");
-
-        // Check if the response is gzip-compressed
-        let is_gzipped = headers
-            .get(header::CONTENT_ENCODING)
-            .map_or(false, |val| val == "gzip");
-
-        // Decode the body into a string
-        let decoded_data = if is_gzipped {
-            let mut decoder = GzDecoder::new(&body_bytes[..]);
-            let mut decoded_data = Vec::new();
-            decoder.read_to_end(&mut decoded_data).map_err(|e| {
-                error!("Failed to decode gzipped response: {}", e);
-                StatusCode::BAD_GATEWAY
-            })?;
-            decoded_data
-        } else {
-            body_bytes.to_vec()
-        };
-
-        let body_string = String::from_utf8_lossy(&decoded_data).into_owned();
-
-        // Log the decoded response
-        info!("Decoded Response Body: {:?}", body_string);
-
-        let final_body_bytes = body_string.into_bytes();
-
-        // Optionally re-encode if it was originally gzipped
-        // let final_body_bytes = if is_gzipped {
-        //     let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
-        //     encoder.write_all(body_string.as_bytes()).map_err(|e| {
-        //         error!("Failed to re-encode gzipped response: {}", e);
-        //         StatusCode::BAD_GATEWAY
-        //     })?;
-        //     match encoder.finish() {
-        //         Ok(b) => b,
-        //         Err(e) => {
-        //             error!("Failed to finish gzip encoder: {}", e);
-        //             return Err(StatusCode::BAD_GATEWAY);
-        //         }
-        //     }
-        // } else {
-        //     // If it wasn't gzipped, just forward the plain bytes
-        //     body_string.into_bytes()
-        // };
-
-        let body_len = final_body_bytes.len();
-        let mut new_response = Response::new(Body::from(final_body_bytes));
-        *new_response.status_mut() = status;
-        *new_response.headers_mut() = headers.clone();
+/// `replay ARCHIVE [...]`: replays recorded traffic instead of serving.
+async fn run_replay(log_control: log_control::LogControl, options: ReplayOptions) {
+    let entries = replay::load_entries(&options.archive).unwrap_or_else(|e| {
+        eprintln!("Failed to read {}: {e}", options.archive.display());
+        std::process::exit(1);
+    });
+    let (state, _) = ProxyBuilder::from_env()
+        .log_control(log_control)
+        .build_state();
+    if state.env_var_config.upstream_for(&options.env).is_none() {
+        eprintln!("No upstream configured for {}", options.env);
+        std::process::exit(2);
+    }
+    tracing::info!(
+        "Replaying {} recorded requests against {}",
+        entries.len(),
+        options.env
+    );
+    let report = replay::replay(&state, entries, &options).await;
+    print!("{}", report.render());
+}
 
-        // Make sure we set the correct headers
-        new_response.headers_mut().remove(header::TRANSFER_ENCODING);
-        new_response.headers_mut().remove(header::CONNECTION);
-        new_response.headers_mut().insert(
-            header::CONTENT_LENGTH,
-            header::HeaderValue::from(body_len as u64),
+async fn run(log_control: log_control::LogControl) {
+    let (state, options) = ProxyBuilder::from_env()
+        .log_control(log_control)
+        .build_state();
+    #[cfg(feature = "archive")]
+    axum_example_rev_proxy::archive::spawn_archiver(&state);
+    axum_example_rev_proxy::egress_ip::spawn_drift_monitor(&state);
+    axum_example_rev_proxy::prober::spawn_probers(&state);
+    axum_example_rev_proxy::store_forward::spawn_deliverer(&state);
+    axum_example_rev_proxy::schedule::spawn_scheduler(&state);
+    axum_example_rev_proxy::forward_proxy::spawn(&state)
+        .await
+        .expect("Failed to bind the forward proxy listener");
+    axum_example_rev_proxy::tcp_tunnel::spawn_tunnels(&state)
+        .await
+        .expect("Failed to bind a TCP tunnel listener");
+    axum_example_rev_proxy::udp_relay::spawn_relays(&state)
+        .await
+        .expect("Failed to bind a UDP relay socket");
+    #[cfg(feature = "socks5")]
+    axum_example_rev_proxy::socks5::spawn(&state)
+        .await
+        .expect("Failed to bind the SOCKS5 listener");
+    #[cfg(not(feature = "socks5"))]
+    if let Some(config) = &state.env_var_config.socks5 {
+        tracing::warn!(
+            "SOCKS5_ADDR={} ignored: built without the `socks5` feature",
+            config.addr
         );
-
-        return Ok(new_response);
+    }
+    let server_config = state.env_var_config.server.clone();
+    let metrics = state.metrics.clone();
+    let snapshot_path = state.env_var_config.metrics_snapshot_path.clone();
+    if let Some(path) = &snapshot_path {
+        metrics::restore_snapshot(&metrics, Path::new(path));
+    }
+    let app = axum_example_rev_proxy::router_with_options(state, options);
+
+    let addr = server_config.listen_addr;
+    let activated = server::activated_listener().unwrap();
+    if server_config.workers > 1 && activated.is_none() {
+        let workers = server_config.workers;
+        let (addr, handles) =
+            server::spawn_workers(addr, app, server_config, metrics.clone()).unwrap();
+        tracing::info!("Listening on {} with {} workers", addr, workers);
+        // Background tasks stay on this runtime while the workers serve.
+        tokio::task::spawn_blocking(move || {
+            for handle in handles {
+                let _ = handle.join();
+            }
+        })
+        .await
+        .unwrap();
+        save_metrics(&metrics, snapshot_path.as_deref());
+        return;
     }
 
-    // If `debug_response` is NOT enabled, forward everything as-is.
-    #[cfg(not(feature = "debug_response"))]
-    {
-        info!("`debug_response` feature is disabled: forwarding response as-is.");
-
-        let body_len = body_bytes.len();
-        let mut new_response = Response::new(Body::from(body_bytes));
-        *new_response.status_mut() = status;
-        *new_response.headers_mut() = headers;
+    if server_config.workers > 1 {
+        tracing::warn!("SERVER_WORKERS ignored: serving the socket-activated listener");
+    }
+    let listener = match activated {
+        Some(listener) => listener,
+        None => server::bind(addr, &server_config).unwrap(),
+    };
+    tracing::info!("Listening on {}", listener.local_addr().unwrap());
 
-        new_response.headers_mut().remove(header::TRANSFER_ENCODING);
-        new_response.headers_mut().remove(header::CONNECTION);
-        new_response.headers_mut().insert(
-            header::CONTENT_LENGTH,
-            header::HeaderValue::from(body_len as u64),
-        );
+    server::serve_with_shutdown(listener, app, server_config, server::shutdown_signal()).await;
+    save_metrics(&metrics, snapshot_path.as_deref());
+}
 
-        Ok(new_response)
+fn save_metrics(metrics: &Metrics, path: Option<&str>) {
+    let Some(path) = path else {
+        return;
+    };
+    match metrics::persist_snapshot(metrics, Path::new(path)) {
+        Ok(()) => tracing::info!("Saved metrics snapshot to {}", path),
+        Err(e) => tracing::warn!("Failed to save metrics snapshot to {}: {}", path, e),
     }
 }
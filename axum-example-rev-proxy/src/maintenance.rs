@@ -0,0 +1,121 @@
+//! Supplier maintenance windows. During a window declared in
+//! `MAINTENANCE_WINDOWS`, a JSON array such as
+//!
+//! ```json
+//! [{"env": "prod", "cron": "0 2 * * SUN", "duration_secs": 7200,
+//!   "timezone": "America/New_York"}]
+//! ```
+//!
+//! the env's traffic is refused with a 503 (`maintenance`) and a
+//! `Retry-After` for the window's end, without reaching the supplier.
+//! Writes that store-and-forward would queue are still accepted, and their
+//! delivery waits for the window to close. Windows follow
+//! [`crate::cron`]. `GET /admin/maintenance` lists them and which are open.
+
+use axum::extract::State;
+use axum::Json;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::app_state::AppState;
+use crate::cron::{TimeWindow, WindowSpec};
+use crate::error::ProxyError;
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct MaintenanceWindow {
+    pub env: String,
+    #[serde(flatten)]
+    pub window: WindowSpec,
+}
+
+/// Parses `MAINTENANCE_WINDOWS`, a JSON array of [`MaintenanceWindow`]s.
+pub fn parse_maintenance_windows(value: &str) -> Result<Vec<MaintenanceWindow>, String> {
+    if value.trim().is_empty() {
+        return Ok(Vec::new());
+    }
+    let windows: Vec<MaintenanceWindow> =
+        serde_json::from_str(value).map_err(|e| format!("invalid maintenance windows: {e}"))?;
+    for window in &windows {
+        window
+            .window
+            .validate()
+            .map_err(|e| format!("maintenance window for {}: {e}", window.env))?;
+    }
+    Ok(windows)
+}
+
+/// A window and whether it's open, for `GET /admin/maintenance`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct MaintenanceStatus {
+    pub env: String,
+    #[serde(flatten)]
+    pub window: WindowSpec,
+    pub active: bool,
+    /// Seconds until the window closes, while open.
+    pub ends_in_secs: Option<u64>,
+}
+
+#[derive(Debug)]
+pub struct Maintenance {
+    windows: Vec<(String, TimeWindow)>,
+}
+
+impl Maintenance {
+    /// Windows that fail to parse were already rejected with the config.
+    pub fn new(windows: &[MaintenanceWindow]) -> Self {
+        Self {
+            windows: windows
+                .iter()
+                .filter_map(|window| {
+                    let time_window = TimeWindow::new(&window.window).ok()?;
+                    Some((window.env.clone(), time_window))
+                })
+                .collect(),
+        }
+    }
+
+    /// When `env` is in a maintenance window, the time the last open one
+    /// closes.
+    pub fn active_until(&self, env: &str, at: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        self.windows
+            .iter()
+            .filter(|(window_env, _)| window_env == env)
+            .filter_map(|(_, window)| window.active_until(at))
+            .max()
+    }
+
+    /// Refuses a request to `env` during its maintenance.
+    pub fn check(&self, env: &str) -> Result<(), ProxyError> {
+        let now = Utc::now();
+        match self.active_until(env, now) {
+            Some(until) => {
+                let remaining = (until - now).num_seconds().max(1) as u64;
+                info!("Refusing a request to {} during maintenance", env);
+                Err(ProxyError::Maintenance(remaining))
+            }
+            None => Ok(()),
+        }
+    }
+
+    pub fn status(&self) -> Vec<MaintenanceStatus> {
+        let now = Utc::now();
+        self.windows
+            .iter()
+            .map(|(env, window)| {
+                let until = window.active_until(now);
+                MaintenanceStatus {
+                    env: env.clone(),
+                    window: window.spec().clone(),
+                    active: until.is_some(),
+                    ends_in_secs: until.map(|until| (until - now).num_seconds().max(0) as u64),
+                }
+            })
+            .collect()
+    }
+}
+
+/// `GET /admin/maintenance`: each window and whether it's open.
+pub async fn list_handler(State(state): State<AppState>) -> Json<Vec<MaintenanceStatus>> {
+    Json(state.maintenance.status())
+}
@@ -0,0 +1,300 @@
+//! Masks personal data in JSON responses for chosen callers, so a data team
+//! can read supplier responses without seeing the customers in them. Rules
+//! come from `MASKING_RULES`, a JSON array such as
+//!
+//! ```json
+//! [{"api_keys": ["analytics-key"],
+//!   "fields": ["guest.email", "payment.card.number", "guests.phone"],
+//!   "action": "hash"}]
+//! ```
+//!
+//! A request whose `X-Api-Key` (or tenant key) is one of a rule's keys gets
+//! every string and number under the rule's fields replaced, in responses
+//! to inbound paths under `path_prefix` when one is set. Fields are dotted
+//! paths as in [`crate::fields`], walking through arrays. `hash` replaces a
+//! value with its HMAC-SHA256 under `MASKING_HASH_KEY`, so the same card
+//! or email still joins across responses; `redact` replaces it with
+//! `[REDACTED]`.
+//!
+//! A key that matches a rule is the proxy's credential and isn't sent
+//! upstream. Masking runs after the cache, so cached responses stay whole.
+
+use std::sync::Arc;
+
+use axum::extract::Request;
+use axum::response::Response;
+use hmac::{Hmac, Mac};
+use hyper::header::HeaderValue;
+use serde::Deserialize;
+use serde_json::Value;
+use sha2::Sha256;
+use tracing::warn;
+
+use crate::admin::constant_time_eq;
+use crate::fields::{rewrite_json, Fields};
+use crate::tenant::API_KEY_HEADER;
+
+const REDACTED: &str = "[REDACTED]";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MaskAction {
+    #[default]
+    Hash,
+    Redact,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct MaskingRule {
+    pub api_keys: Vec<String>,
+    pub fields: Vec<String>,
+    #[serde(default)]
+    pub action: MaskAction,
+    /// Only responses to inbound paths under this prefix; all when unset.
+    #[serde(default)]
+    pub path_prefix: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct MaskingConfig {
+    pub rules: Vec<MaskingRule>,
+    /// Keys the `hash` action's HMAC. Without one a random key is used, and
+    /// hashes change with every restart.
+    pub hash_key: Option<String>,
+}
+
+/// Parses `MASKING_RULES`, a JSON array of [`MaskingRule`]s.
+pub fn parse_masking_rules(value: &str) -> Result<Vec<MaskingRule>, String> {
+    if value.trim().is_empty() {
+        return Ok(Vec::new());
+    }
+    let rules: Vec<MaskingRule> =
+        serde_json::from_str(value).map_err(|e| format!("invalid masking rules: {e}"))?;
+    for rule in &rules {
+        if rule.api_keys.is_empty() || rule.fields.is_empty() {
+            return Err("a masking rule needs api_keys and fields".to_string());
+        }
+    }
+    Ok(rules)
+}
+
+/// The masking owed to one request.
+#[derive(Debug, Clone)]
+pub struct Mask {
+    fields: Vec<(Fields, MaskAction)>,
+    hash_key: Arc<[u8]>,
+}
+
+impl Mask {
+    /// Masks the named fields of a JSON response.
+    pub async fn apply(&self, response: Response) -> Response {
+        rewrite_json(response, |mut json| {
+            for (fields, action) in &self.fields {
+                fields.visit_mut(&mut json, &mut |value| {
+                    mask_value(value, *action, &self.hash_key)
+                });
+            }
+            json
+        })
+        .await
+    }
+}
+
+#[derive(Debug)]
+pub struct Masking {
+    rules: Vec<(MaskingRule, Fields)>,
+    hash_key: Arc<[u8]>,
+}
+
+impl Masking {
+    pub fn new(config: &MaskingConfig) -> Self {
+        let hash_key: Arc<[u8]> = match &config.hash_key {
+            Some(key) => key.as_bytes().into(),
+            None => {
+                if config
+                    .rules
+                    .iter()
+                    .any(|rule| rule.action == MaskAction::Hash)
+                {
+                    warn!("MASKING_HASH_KEY is unset; masked hashes change on restart");
+                }
+                crate::correlation::generate().into_bytes().into()
+            }
+        };
+        Self {
+            rules: config
+                .rules
+                .iter()
+                .map(|rule| (rule.clone(), Fields::parse(&rule.fields.join(","))))
+                .collect(),
+            hash_key,
+        }
+    }
+
+    /// The masking owed to `req`'s caller on its path, if any. A matching
+    /// key is taken off the request.
+    pub fn take(&self, req: &mut Request) -> Option<Mask> {
+        if self.rules.is_empty() {
+            return None;
+        }
+        let key = match req.extensions().get::<CallerKey>() {
+            Some(CallerKey(key)) => key.clone(),
+            None => req.headers().get(API_KEY_HEADER)?.clone(),
+        };
+        let path = req.uri().path();
+        let fields: Vec<(Fields, MaskAction)> = self
+            .rules
+            .iter()
+            .filter(|(rule, _)| {
+                rule.api_keys
+                    .iter()
+                    .any(|candidate| constant_time_eq(candidate.as_bytes(), key.as_bytes()))
+            })
+            .filter(|(rule, _)| {
+                rule.path_prefix
+                    .as_deref()
+                    .is_none_or(|prefix| path.starts_with(prefix))
+            })
+            .map(|(rule, fields)| (fields.clone(), rule.action))
+            .collect();
+        if fields.is_empty() {
+            return None;
+        }
+        req.headers_mut().remove(API_KEY_HEADER);
+        Some(Mask {
+            fields,
+            hash_key: self.hash_key.clone(),
+        })
+    }
+}
+
+/// Set by routes that authenticate the caller's `X-Api-Key` themselves and
+/// take it off the request, e.g. tenants.
+#[derive(Debug, Clone)]
+pub struct CallerKey(pub HeaderValue);
+
+/// Replaces every string and number in `value`, keeping its shape.
+fn mask_value(value: &mut Value, action: MaskAction, hash_key: &[u8]) {
+    match value {
+        Value::Array(items) => items
+            .iter_mut()
+            .for_each(|item| mask_value(item, action, hash_key)),
+        Value::Object(map) => map
+            .values_mut()
+            .for_each(|item| mask_value(item, action, hash_key)),
+        Value::String(_) | Value::Number(_) => {
+            let masked = match action {
+                MaskAction::Redact => REDACTED.to_string(),
+                MaskAction::Hash => {
+                    let plain = match &*value {
+                        Value::String(text) => text.clone(),
+                        other => other.to_string(),
+                    };
+                    let mut mac = Hmac::<Sha256>::new_from_slice(hash_key)
+                        .expect("HMAC takes keys of any length");
+                    mac.update(plain.as_bytes());
+                    hex::encode(mac.finalize().into_bytes())
+                }
+            };
+            *value = Value::String(masked);
+        }
+        Value::Bool(_) | Value::Null => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::{to_bytes, Body};
+    use hyper::header;
+    use serde_json::json;
+
+    use super::*;
+
+    fn masking() -> Masking {
+        Masking::new(&MaskingConfig {
+            rules: vec![
+                MaskingRule {
+                    api_keys: vec!["analytics".to_string()],
+                    fields: vec!["guest.email".to_string(), "cards.number".to_string()],
+                    action: MaskAction::Hash,
+                    path_prefix: None,
+                },
+                MaskingRule {
+                    api_keys: vec!["analytics".to_string()],
+                    fields: vec!["guest.phone".to_string()],
+                    action: MaskAction::Redact,
+                    path_prefix: Some("/prod/bookings".to_string()),
+                },
+            ],
+            hash_key: Some("secret".to_string()),
+        })
+    }
+
+    fn request(path: &str, key: Option<&'static str>) -> Request {
+        let mut req = Request::new(Body::empty());
+        *req.uri_mut() = path.parse().unwrap();
+        if let Some(key) = key {
+            req.headers_mut()
+                .insert(API_KEY_HEADER, HeaderValue::from_static(key));
+        }
+        req
+    }
+
+    #[tokio::test]
+    async fn masks_the_named_fields_for_matching_keys() {
+        let masking = masking();
+        assert!(masking
+            .take(&mut request("/prod/bookings/1", None))
+            .is_none());
+        assert!(masking
+            .take(&mut request("/prod/bookings/1", Some("other")))
+            .is_none());
+        let mut req = request("/prod/bookings/1", Some("analytics"));
+        let mask = masking.take(&mut req).unwrap();
+        assert!(req.headers().get(API_KEY_HEADER).is_none());
+
+        let booking = json!({
+            "id": 7,
+            "guest": {"email": "a@example.com", "phone": "+91 98", "name": "A"},
+            "cards": [{"number": 4111111111111111u64, "brand": "visa"}],
+        });
+        let response = Response::builder()
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(booking.to_string()))
+            .unwrap();
+        let body = to_bytes(mask.apply(response).await.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let masked: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(masked["id"], 7);
+        assert_eq!(masked["guest"]["name"], "A");
+        assert_eq!(masked["guest"]["phone"], REDACTED);
+        assert_eq!(masked["cards"][0]["brand"], "visa");
+        let email = masked["guest"]["email"].as_str().unwrap();
+        assert_eq!(email.len(), 64);
+        assert_ne!(email, masked["cards"][0]["number"].as_str().unwrap());
+
+        // Same key and value, same hash; the redaction is path-scoped.
+        let mask = masking
+            .take(&mut request("/prod/search", Some("analytics")))
+            .unwrap();
+        let response = Response::builder()
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(booking.to_string()))
+            .unwrap();
+        let body = to_bytes(mask.apply(response).await.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let again: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(again["guest"]["email"], email);
+        assert_eq!(again["guest"]["phone"], "+91 98");
+    }
+
+    #[test]
+    fn rejects_rules_without_keys_or_fields() {
+        assert!(parse_masking_rules(r#"[{"api_keys": [], "fields": ["a"]}]"#).is_err());
+        let rules = parse_masking_rules(r#"[{"api_keys": ["k"], "fields": ["a"]}]"#).unwrap();
+        assert_eq!(rules[0].action, MaskAction::Hash);
+    }
+}
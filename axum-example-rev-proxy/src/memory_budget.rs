@@ -0,0 +1,125 @@
+//! A global budget for body bytes buffered by in-flight requests. Bodies are
+//! read into memory whole, so a burst of large payloads could otherwise grow
+//! the single proxy instance until it is OOM-killed, taking every request
+//! with it; past the budget, new bytes are refused and the request shed
+//! with a 503.
+//!
+//! A request body counts from when it is read until the proxied call ends;
+//! an upstream body counts while it is being read.
+
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+pub const DEFAULT_MAX_INFLIGHT_BYTES: usize = 256 * 1024 * 1024;
+
+#[derive(Debug)]
+pub struct MemoryBudget {
+    limit: usize,
+    used: AtomicUsize,
+    rejections: AtomicU64,
+}
+
+impl MemoryBudget {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            used: AtomicUsize::new(0),
+            rejections: AtomicU64::new(0),
+        }
+    }
+
+    /// An empty reservation that [`Reservation::try_grow`] extends.
+    pub fn reservation(self: &Arc<Self>) -> Reservation {
+        Reservation {
+            budget: self.clone(),
+            bytes: 0,
+        }
+    }
+
+    /// Bytes currently reserved.
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    pub fn rejections(&self) -> u64 {
+        self.rejections.load(Ordering::Relaxed)
+    }
+
+    fn try_take(&self, bytes: usize) -> bool {
+        let taken = self
+            .used
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |used| {
+                used.checked_add(bytes).filter(|total| *total <= self.limit)
+            })
+            .is_ok();
+        if !taken {
+            self.rejections.fetch_add(1, Ordering::Relaxed);
+        }
+        taken
+    }
+
+    /// Budget gauges and the shed counter, in Prometheus text format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(out, "proxy_inflight_body_bytes {}", self.used());
+        let _ = writeln!(out, "proxy_inflight_body_bytes_limit {}", self.limit);
+        let _ = writeln!(
+            out,
+            "proxy_memory_budget_rejections_total {}",
+            self.rejections()
+        );
+        out
+    }
+}
+
+/// Bytes held against the budget, released on drop.
+#[derive(Debug)]
+pub struct Reservation {
+    budget: Arc<MemoryBudget>,
+    bytes: usize,
+}
+
+impl Reservation {
+    /// Reserves `bytes` more, or returns `false` if that would exceed the
+    /// budget, leaving the reservation as it was.
+    pub fn try_grow(&mut self, bytes: usize) -> bool {
+        if !self.budget.try_take(bytes) {
+            return false;
+        }
+        self.bytes += bytes;
+        true
+    }
+
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.budget.used.fetch_sub(self.bytes, Ordering::AcqRel);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refuses_bytes_past_the_limit_until_released() {
+        let budget = Arc::new(MemoryBudget::new(100));
+        let mut first = budget.reservation();
+        assert!(first.try_grow(60));
+        let mut second = budget.reservation();
+        assert!(!second.try_grow(50));
+        assert!(second.try_grow(40));
+        assert_eq!(budget.used(), 100);
+        assert_eq!(budget.rejections(), 1);
+
+        drop(first);
+        assert_eq!(budget.used(), 40);
+        assert!(second.try_grow(50));
+        assert_eq!(second.bytes(), 90);
+    }
+}
@@ -0,0 +1,919 @@
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use axum::extract::{Query, State};
+use axum::Json;
+use hyper::{HeaderMap, StatusCode};
+use serde::{Deserialize, Serialize};
+
+use crate::app_state::AppState;
+use crate::contract::ViolationKind;
+use crate::error::ProxyError;
+use crate::failover::Target;
+use crate::latency::LatencyTracker;
+use crate::nowpayments_ipn_webhook::render_key_ages;
+use crate::payload_size::{content_family, Direction, PayloadSizes};
+use crate::rolling::{RollingWindow, WindowStats, WINDOWS};
+
+/// Paths with their own counters; later paths are only counted in the totals.
+const MAX_TRACKED_PATHS: usize = 1024;
+
+/// In-process counters for proxied traffic, rendered as plain text at `/metrics`.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    requests_total: AtomicU64,
+    upstream_errors_total: AtomicU64,
+    latency_ms_total: AtomicU64,
+    cache_hits_total: AtomicU64,
+    cache_misses_total: AtomicU64,
+    cache_invalidations_total: AtomicU64,
+    egress_ip_mismatches_total: AtomicU64,
+    hedges_fired_total: AtomicU64,
+    hedges_won_total: AtomicU64,
+    hedges_wasted_total: AtomicU64,
+    partial_responses_total: AtomicU64,
+    coalesced_requests_total: AtomicU64,
+    upstream_ipv4_responses_total: AtomicU64,
+    upstream_ipv6_responses_total: AtomicU64,
+    egress_bytes_total: AtomicU64,
+    ingress_bytes_total: AtomicU64,
+    payload_sizes: PayloadSizes,
+    /// Set once a snapshot from an earlier run has been added in.
+    imported: AtomicBool,
+    path_latency: LatencyTracker,
+    recent: RollingWindow,
+    inner: Mutex<MetricsInner>,
+}
+
+#[derive(Debug, Default)]
+struct MetricsInner {
+    requests_by_env: BTreeMap<String, u64>,
+    responses_by_status: BTreeMap<u16, u64>,
+    upstream_errors_by_kind: BTreeMap<String, u64>,
+    egress_violations_by_reason: BTreeMap<String, u64>,
+    requests_by_worker: BTreeMap<usize, u64>,
+    queue_waits_by_client: BTreeMap<String, QueueWait>,
+    connection_waits_by_host: BTreeMap<String, QueueWait>,
+    ipn_signatures_by_key: BTreeMap<String, u64>,
+    contract_violations_by_env: BTreeMap<String, BTreeMap<String, u64>>,
+    upstream_targets_by_env: BTreeMap<String, BTreeMap<String, u64>>,
+    tunnels_by_protocol: BTreeMap<String, BTreeMap<String, u64>>,
+    udp_relay_traffic: BTreeMap<String, RelayTraffic>,
+    emails_by_outcome: BTreeMap<String, u64>,
+    deliveries_by_outcome: BTreeMap<String, BTreeMap<String, u64>>,
+    requests_by_client_tag: BTreeMap<String, ClientTagCounts>,
+    paths: BTreeMap<String, PathCounts>,
+}
+
+#[derive(Debug, Default)]
+struct PathCounts {
+    requests: u64,
+    errors: u64,
+}
+
+/// Point-in-time copy of the counters, mostly useful for tests and tooling.
+/// Exported as JSON, it can be imported into the next run so lifetime
+/// counters carry over a planned restart.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MetricsSnapshot {
+    pub requests_total: u64,
+    pub upstream_errors_total: u64,
+    pub latency_ms_total: u64,
+    pub cache_hits_total: u64,
+    pub cache_misses_total: u64,
+    pub cache_invalidations_total: u64,
+    pub egress_ip_mismatches_total: u64,
+    /// Second attempts sent for slow hedged GETs.
+    pub hedges_fired_total: u64,
+    /// Hedges whose response was the one used.
+    pub hedges_won_total: u64,
+    /// Hedges sent where the original attempt answered first anyway.
+    pub hedges_wasted_total: u64,
+    /// Responses returned truncated after the upstream body failed partway.
+    pub partial_responses_total: u64,
+    /// Requests answered by sharing an identical request's upstream call.
+    pub coalesced_requests_total: u64,
+    /// Upstream responses received over IPv4.
+    pub upstream_ipv4_responses_total: u64,
+    /// Upstream responses received over IPv6.
+    pub upstream_ipv6_responses_total: u64,
+    /// Request body bytes sent to upstreams.
+    pub egress_bytes_total: u64,
+    /// Response body bytes received from upstreams.
+    pub ingress_bytes_total: u64,
+    pub requests_by_env: BTreeMap<String, u64>,
+    pub responses_by_status: BTreeMap<u16, u64>,
+    pub upstream_errors_by_kind: BTreeMap<String, u64>,
+    pub egress_violations_by_reason: BTreeMap<String, u64>,
+    /// Requests accepted by each worker when running with several workers.
+    pub requests_by_worker: BTreeMap<usize, u64>,
+    /// Requests that queued for an upstream slot, by client.
+    pub queue_waits_by_client: BTreeMap<String, QueueWait>,
+    /// Requests that waited for a connection slot, by upstream host.
+    pub connection_waits_by_host: BTreeMap<String, QueueWait>,
+    /// Verified IPNs by the id of the secret that signed them.
+    pub ipn_signatures_by_key: BTreeMap<String, u64>,
+    /// Exchanges that broke the upstream's OpenAPI contract, by environment
+    /// and kind.
+    pub contract_violations_by_env: BTreeMap<String, BTreeMap<String, u64>>,
+    /// Upstream calls by environment and the region that served them
+    /// (`primary` or `secondary`), for envs with failover.
+    pub upstream_targets_by_env: BTreeMap<String, BTreeMap<String, u64>>,
+    /// Tunnels (UDP relay sessions included) by protocol (`connect`,
+    /// `socks5`, `tcp` or `udp`) and outcome (`opened`, `unauthorized`,
+    /// `refused` or `unreachable`).
+    pub tunnels_by_protocol: BTreeMap<String, BTreeMap<String, u64>>,
+    /// Datagrams through each UDP relay, by its `listen=host:port` mapping.
+    pub udp_relay_traffic: BTreeMap<String, RelayTraffic>,
+    /// Messages through the email bridge by outcome (`sent`, `rejected`,
+    /// `failed`, `invalid`, `refused` or `rate_limited`).
+    pub emails_by_outcome: BTreeMap<String, u64>,
+    /// Store-and-forward delivery attempts by env, then outcome (`accepted`,
+    /// `delivered`, `retried` or `failed`).
+    pub deliveries_by_outcome: BTreeMap<String, BTreeMap<String, u64>>,
+    /// Proxied requests by the caller's `X-Client-Tag`; see
+    /// [`crate::client_tag`].
+    pub requests_by_client_tag: BTreeMap<String, ClientTagCounts>,
+}
+
+/// Datagrams relayed out to a UDP relay's remote and back in from it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RelayTraffic {
+    pub packets_out: u64,
+    pub bytes_out: u64,
+    pub packets_in: u64,
+    pub bytes_in: u64,
+}
+
+/// Time requests from one client spent queued for an upstream slot.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QueueWait {
+    pub requests: u64,
+    pub wait_ms_total: u64,
+}
+
+/// Requests from callers sending one client tag.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ClientTagCounts {
+    pub requests: u64,
+    /// Responses with a 5xx status.
+    pub errors: u64,
+    pub latency_ms_total: u64,
+}
+
+/// Traffic to one inbound path, as served at `/metrics/paths`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PathStats {
+    pub path: String,
+    pub requests: u64,
+    /// Responses with a 5xx status.
+    pub errors: u64,
+    pub error_rate: f64,
+    /// Latency percentiles over recent requests; absent until the path has
+    /// enough of them.
+    pub p50_ms: Option<u64>,
+    pub p95_ms: Option<u64>,
+    pub p99_ms: Option<u64>,
+}
+
+impl Metrics {
+    pub fn record_response(&self, env: &str, path: &str, status: StatusCode, latency: Duration) {
+        self.requests_total.fetch_add(1, Ordering::Relaxed);
+        self.latency_ms_total
+            .fetch_add(latency.as_millis() as u64, Ordering::Relaxed);
+        self.path_latency.record(path, latency);
+        self.recent.record(status, latency);
+
+        let mut inner = self.inner.lock().unwrap();
+        if inner.paths.contains_key(path) || inner.paths.len() < MAX_TRACKED_PATHS {
+            let counts = inner.paths.entry(path.to_string()).or_default();
+            counts.requests += 1;
+            if status.is_server_error() {
+                counts.errors += 1;
+            }
+        }
+        *inner.requests_by_env.entry(env.to_string()).or_default() += 1;
+        *inner
+            .responses_by_status
+            .entry(status.as_u16())
+            .or_default() += 1;
+    }
+
+    /// Counts a response to a caller that sent a client tag.
+    pub fn record_client_tag(&self, tag: &str, status: StatusCode, latency: Duration) {
+        let mut inner = self.inner.lock().unwrap();
+        let entry = inner
+            .requests_by_client_tag
+            .entry(tag.to_string())
+            .or_default();
+        entry.requests += 1;
+        if status.is_server_error() {
+            entry.errors += 1;
+        }
+        entry.latency_ms_total += latency.as_millis() as u64;
+    }
+
+    pub fn record_upstream_error(&self, env: &str, error: ProxyError) {
+        self.upstream_errors_total.fetch_add(1, Ordering::Relaxed);
+        let kind = error.code();
+        tracing::debug!(env, kind, "upstream error recorded");
+
+        let mut inner = self.inner.lock().unwrap();
+        *inner
+            .upstream_errors_by_kind
+            .entry(kind.to_string())
+            .or_default() += 1;
+    }
+
+    pub fn record_cache_lookup(&self, hit: bool) {
+        let counter = if hit {
+            &self.cache_hits_total
+        } else {
+            &self.cache_misses_total
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_cache_invalidations(&self, removed: u64) {
+        self.cache_invalidations_total
+            .fetch_add(removed, Ordering::Relaxed);
+    }
+
+    /// Counts a request or resolution the egress policy refused.
+    pub fn record_egress_violation(&self, reason: &'static str) {
+        let mut inner = self.inner.lock().unwrap();
+        *inner
+            .egress_violations_by_reason
+            .entry(reason.to_string())
+            .or_default() += 1;
+    }
+
+    pub fn record_contract_violation(&self, env: &str, kind: ViolationKind) {
+        let mut inner = self.inner.lock().unwrap();
+        *inner
+            .contract_violations_by_env
+            .entry(env.to_string())
+            .or_default()
+            .entry(kind.as_str().to_string())
+            .or_default() += 1;
+    }
+
+    pub fn record_upstream_target(&self, env: &str, target: Target) {
+        let mut inner = self.inner.lock().unwrap();
+        *inner
+            .upstream_targets_by_env
+            .entry(env.to_string())
+            .or_default()
+            .entry(target.as_str().to_string())
+            .or_default() += 1;
+    }
+
+    pub fn record_tunnel(&self, protocol: &str, outcome: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        *inner
+            .tunnels_by_protocol
+            .entry(protocol.to_string())
+            .or_default()
+            .entry(outcome.to_string())
+            .or_default() += 1;
+    }
+
+    pub fn record_email(&self, outcome: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        *inner
+            .emails_by_outcome
+            .entry(outcome.to_string())
+            .or_default() += 1;
+    }
+
+    pub fn record_delivery(&self, env: &str, outcome: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        *inner
+            .deliveries_by_outcome
+            .entry(env.to_string())
+            .or_default()
+            .entry(outcome.to_string())
+            .or_default() += 1;
+    }
+
+    /// Counts a datagram through `mapping`, `outbound` to the remote or back.
+    pub fn record_udp_datagram(&self, mapping: &str, outbound: bool, bytes: usize) {
+        let mut inner = self.inner.lock().unwrap();
+        let entry = inner
+            .udp_relay_traffic
+            .entry(mapping.to_string())
+            .or_default();
+        if outbound {
+            entry.packets_out += 1;
+            entry.bytes_out += bytes as u64;
+        } else {
+            entry.packets_in += 1;
+            entry.bytes_in += bytes as u64;
+        }
+    }
+
+    pub fn record_worker_request(&self, worker: usize) {
+        let mut inner = self.inner.lock().unwrap();
+        *inner.requests_by_worker.entry(worker).or_default() += 1;
+    }
+
+    pub fn record_queue_wait(&self, client: &str, wait: Duration) {
+        let mut inner = self.inner.lock().unwrap();
+        let entry = inner
+            .queue_waits_by_client
+            .entry(client.to_string())
+            .or_default();
+        entry.requests += 1;
+        entry.wait_ms_total += wait.as_millis() as u64;
+    }
+
+    pub fn record_connection_wait(&self, host: &str, wait: Duration) {
+        let mut inner = self.inner.lock().unwrap();
+        let entry = inner
+            .connection_waits_by_host
+            .entry(host.to_string())
+            .or_default();
+        entry.requests += 1;
+        entry.wait_ms_total += wait.as_millis() as u64;
+    }
+
+    pub fn record_ipn_key_match(&self, key: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        *inner
+            .ipn_signatures_by_key
+            .entry(key.to_string())
+            .or_default() += 1;
+    }
+
+    pub fn record_egress_ip_mismatch(&self) {
+        self.egress_ip_mismatches_total
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_hedge_fired(&self) {
+        self.hedges_fired_total.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_hedge_won(&self) {
+        self.hedges_won_total.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_hedge_wasted(&self) {
+        self.hedges_wasted_total.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_partial_response(&self, env: &str) {
+        tracing::debug!(env, "partial response recorded");
+        self.partial_responses_total.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_coalesced(&self) {
+        self.coalesced_requests_total
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts an upstream response by the address family it came over.
+    pub fn record_upstream_family(&self, ipv6: bool) {
+        let counter = if ipv6 {
+            &self.upstream_ipv6_responses_total
+        } else {
+            &self.upstream_ipv4_responses_total
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a body sent to or received from `env`'s upstream, with the
+    /// headers that describe it.
+    pub fn record_payload(
+        &self,
+        env: &str,
+        direction: Direction,
+        headers: &HeaderMap,
+        bytes: usize,
+    ) {
+        let bytes = bytes as u64;
+        let total = match direction {
+            Direction::Request => &self.egress_bytes_total,
+            Direction::Response => &self.ingress_bytes_total,
+        };
+        total.fetch_add(bytes, Ordering::Relaxed);
+        self.payload_sizes
+            .record(env, direction, content_family(headers), bytes);
+    }
+
+    /// Request rate, error rate and latency over the last `window` (at most
+    /// 15 minutes).
+    pub fn recent(&self, window: Duration) -> WindowStats {
+        self.recent.stats(window)
+    }
+
+    /// Per-path counters and latency percentiles, in path order.
+    pub fn path_stats(&self) -> Vec<PathStats> {
+        let counts: Vec<(String, u64, u64)> = {
+            let inner = self.inner.lock().unwrap();
+            inner
+                .paths
+                .iter()
+                .map(|(path, counts)| (path.clone(), counts.requests, counts.errors))
+                .collect()
+        };
+        let percentile = |path: &str, q: f64| {
+            self.path_latency
+                .percentile(path, q)
+                .map(|latency| latency.as_millis() as u64)
+        };
+        counts
+            .into_iter()
+            .map(|(path, requests, errors)| PathStats {
+                error_rate: errors as f64 / requests.max(1) as f64,
+                p50_ms: percentile(&path, 0.5),
+                p95_ms: percentile(&path, 0.95),
+                p99_ms: percentile(&path, 0.99),
+                path,
+                requests,
+                errors,
+            })
+            .collect()
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        let inner = self.inner.lock().unwrap();
+        MetricsSnapshot {
+            requests_total: self.requests_total.load(Ordering::Relaxed),
+            upstream_errors_total: self.upstream_errors_total.load(Ordering::Relaxed),
+            latency_ms_total: self.latency_ms_total.load(Ordering::Relaxed),
+            cache_hits_total: self.cache_hits_total.load(Ordering::Relaxed),
+            cache_misses_total: self.cache_misses_total.load(Ordering::Relaxed),
+            cache_invalidations_total: self.cache_invalidations_total.load(Ordering::Relaxed),
+            egress_ip_mismatches_total: self.egress_ip_mismatches_total.load(Ordering::Relaxed),
+            hedges_fired_total: self.hedges_fired_total.load(Ordering::Relaxed),
+            hedges_won_total: self.hedges_won_total.load(Ordering::Relaxed),
+            hedges_wasted_total: self.hedges_wasted_total.load(Ordering::Relaxed),
+            partial_responses_total: self.partial_responses_total.load(Ordering::Relaxed),
+            coalesced_requests_total: self.coalesced_requests_total.load(Ordering::Relaxed),
+            upstream_ipv4_responses_total: self
+                .upstream_ipv4_responses_total
+                .load(Ordering::Relaxed),
+            upstream_ipv6_responses_total: self
+                .upstream_ipv6_responses_total
+                .load(Ordering::Relaxed),
+            egress_bytes_total: self.egress_bytes_total.load(Ordering::Relaxed),
+            ingress_bytes_total: self.ingress_bytes_total.load(Ordering::Relaxed),
+            requests_by_env: inner.requests_by_env.clone(),
+            responses_by_status: inner.responses_by_status.clone(),
+            upstream_errors_by_kind: inner.upstream_errors_by_kind.clone(),
+            egress_violations_by_reason: inner.egress_violations_by_reason.clone(),
+            requests_by_worker: inner.requests_by_worker.clone(),
+            queue_waits_by_client: inner.queue_waits_by_client.clone(),
+            connection_waits_by_host: inner.connection_waits_by_host.clone(),
+            ipn_signatures_by_key: inner.ipn_signatures_by_key.clone(),
+            contract_violations_by_env: inner.contract_violations_by_env.clone(),
+            upstream_targets_by_env: inner.upstream_targets_by_env.clone(),
+            tunnels_by_protocol: inner.tunnels_by_protocol.clone(),
+            udp_relay_traffic: inner.udp_relay_traffic.clone(),
+            emails_by_outcome: inner.emails_by_outcome.clone(),
+            deliveries_by_outcome: inner.deliveries_by_outcome.clone(),
+            requests_by_client_tag: inner.requests_by_client_tag.clone(),
+        }
+    }
+
+    /// Adds the counters from an earlier run's snapshot to these. Returns
+    /// `false`, changing nothing, if a snapshot was already imported.
+    pub fn import(&self, snapshot: &MetricsSnapshot) -> bool {
+        if self.imported.swap(true, Ordering::AcqRel) {
+            return false;
+        }
+        for (counter, value) in [
+            (&self.requests_total, snapshot.requests_total),
+            (&self.upstream_errors_total, snapshot.upstream_errors_total),
+            (&self.latency_ms_total, snapshot.latency_ms_total),
+            (&self.cache_hits_total, snapshot.cache_hits_total),
+            (&self.cache_misses_total, snapshot.cache_misses_total),
+            (
+                &self.cache_invalidations_total,
+                snapshot.cache_invalidations_total,
+            ),
+            (
+                &self.egress_ip_mismatches_total,
+                snapshot.egress_ip_mismatches_total,
+            ),
+            (&self.hedges_fired_total, snapshot.hedges_fired_total),
+            (&self.hedges_won_total, snapshot.hedges_won_total),
+            (&self.hedges_wasted_total, snapshot.hedges_wasted_total),
+            (
+                &self.partial_responses_total,
+                snapshot.partial_responses_total,
+            ),
+            (
+                &self.coalesced_requests_total,
+                snapshot.coalesced_requests_total,
+            ),
+            (
+                &self.upstream_ipv4_responses_total,
+                snapshot.upstream_ipv4_responses_total,
+            ),
+            (
+                &self.upstream_ipv6_responses_total,
+                snapshot.upstream_ipv6_responses_total,
+            ),
+            (&self.egress_bytes_total, snapshot.egress_bytes_total),
+            (&self.ingress_bytes_total, snapshot.ingress_bytes_total),
+        ] {
+            counter.fetch_add(value, Ordering::Relaxed);
+        }
+
+        let mut inner = self.inner.lock().unwrap();
+        merge_counts(&mut inner.requests_by_env, &snapshot.requests_by_env);
+        merge_counts(
+            &mut inner.responses_by_status,
+            &snapshot.responses_by_status,
+        );
+        merge_counts(
+            &mut inner.upstream_errors_by_kind,
+            &snapshot.upstream_errors_by_kind,
+        );
+        merge_counts(
+            &mut inner.egress_violations_by_reason,
+            &snapshot.egress_violations_by_reason,
+        );
+        merge_counts(&mut inner.requests_by_worker, &snapshot.requests_by_worker);
+        merge_counts(&mut inner.emails_by_outcome, &snapshot.emails_by_outcome);
+        merge_counts(
+            &mut inner.ipn_signatures_by_key,
+            &snapshot.ipn_signatures_by_key,
+        );
+        merge_waits(
+            &mut inner.queue_waits_by_client,
+            &snapshot.queue_waits_by_client,
+        );
+        merge_waits(
+            &mut inner.connection_waits_by_host,
+            &snapshot.connection_waits_by_host,
+        );
+        for (env, counts) in &snapshot.contract_violations_by_env {
+            merge_counts(
+                inner
+                    .contract_violations_by_env
+                    .entry(env.clone())
+                    .or_default(),
+                counts,
+            );
+        }
+        for (env, counts) in &snapshot.deliveries_by_outcome {
+            merge_counts(
+                inner.deliveries_by_outcome.entry(env.clone()).or_default(),
+                counts,
+            );
+        }
+        for (env, counts) in &snapshot.upstream_targets_by_env {
+            merge_counts(
+                inner
+                    .upstream_targets_by_env
+                    .entry(env.clone())
+                    .or_default(),
+                counts,
+            );
+        }
+        for (tag, counts) in &snapshot.requests_by_client_tag {
+            let entry = inner.requests_by_client_tag.entry(tag.clone()).or_default();
+            entry.requests += counts.requests;
+            entry.errors += counts.errors;
+            entry.latency_ms_total += counts.latency_ms_total;
+        }
+        for (mapping, traffic) in &snapshot.udp_relay_traffic {
+            let entry = inner.udp_relay_traffic.entry(mapping.clone()).or_default();
+            entry.packets_out += traffic.packets_out;
+            entry.bytes_out += traffic.bytes_out;
+            entry.packets_in += traffic.packets_in;
+            entry.bytes_in += traffic.bytes_in;
+        }
+        for (protocol, counts) in &snapshot.tunnels_by_protocol {
+            merge_counts(
+                inner
+                    .tunnels_by_protocol
+                    .entry(protocol.clone())
+                    .or_default(),
+                counts,
+            );
+        }
+        true
+    }
+
+    /// Renders the counters in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let snapshot = self.snapshot();
+        let mut out = String::new();
+
+        let _ = writeln!(out, "proxy_requests_total {}", snapshot.requests_total);
+        let _ = writeln!(
+            out,
+            "proxy_upstream_errors_total {}",
+            snapshot.upstream_errors_total
+        );
+        let _ = writeln!(out, "proxy_latency_ms_total {}", snapshot.latency_ms_total);
+        let _ = writeln!(out, "proxy_cache_hits_total {}", snapshot.cache_hits_total);
+        let _ = writeln!(
+            out,
+            "proxy_cache_misses_total {}",
+            snapshot.cache_misses_total
+        );
+        let _ = writeln!(
+            out,
+            "proxy_cache_invalidations_total {}",
+            snapshot.cache_invalidations_total
+        );
+        let _ = writeln!(
+            out,
+            "proxy_egress_ip_mismatches_total {}",
+            snapshot.egress_ip_mismatches_total
+        );
+        let _ = writeln!(
+            out,
+            "proxy_hedges_fired_total {}",
+            snapshot.hedges_fired_total
+        );
+        let _ = writeln!(out, "proxy_hedges_won_total {}", snapshot.hedges_won_total);
+        let _ = writeln!(
+            out,
+            "proxy_hedges_wasted_total {}",
+            snapshot.hedges_wasted_total
+        );
+        let _ = writeln!(
+            out,
+            "proxy_partial_responses_total {}",
+            snapshot.partial_responses_total
+        );
+        let _ = writeln!(
+            out,
+            "proxy_coalesced_requests_total {}",
+            snapshot.coalesced_requests_total
+        );
+        for (family, count) in [
+            ("ipv4", snapshot.upstream_ipv4_responses_total),
+            ("ipv6", snapshot.upstream_ipv6_responses_total),
+        ] {
+            let _ = writeln!(
+                out,
+                "proxy_upstream_responses_by_family_total{{family=\"{family}\"}} {count}"
+            );
+        }
+        let _ = writeln!(
+            out,
+            "proxy_egress_bytes_total {}",
+            snapshot.egress_bytes_total
+        );
+        let _ = writeln!(
+            out,
+            "proxy_ingress_bytes_total {}",
+            snapshot.ingress_bytes_total
+        );
+        self.payload_sizes.render(&mut out);
+        for (env, count) in &snapshot.requests_by_env {
+            let _ = writeln!(out, "proxy_requests_by_env{{env=\"{env}\"}} {count}");
+        }
+        for (status, count) in &snapshot.responses_by_status {
+            let _ = writeln!(
+                out,
+                "proxy_responses_by_status{{status=\"{status}\"}} {count}"
+            );
+        }
+        for (kind, count) in &snapshot.upstream_errors_by_kind {
+            let _ = writeln!(
+                out,
+                "proxy_upstream_errors_by_kind{{kind=\"{kind}\"}} {count}"
+            );
+        }
+        for (reason, count) in &snapshot.egress_violations_by_reason {
+            let _ = writeln!(
+                out,
+                "proxy_egress_violations{{reason=\"{reason}\"}} {count}"
+            );
+        }
+        for (worker, count) in &snapshot.requests_by_worker {
+            let _ = writeln!(
+                out,
+                "proxy_worker_requests_total{{worker=\"{worker}\"}} {count}"
+            );
+        }
+        for (client, wait) in &snapshot.queue_waits_by_client {
+            let _ = writeln!(
+                out,
+                "proxy_queued_requests_total{{client=\"{client}\"}} {}",
+                wait.requests
+            );
+            let _ = writeln!(
+                out,
+                "proxy_queue_wait_ms_total{{client=\"{client}\"}} {}",
+                wait.wait_ms_total
+            );
+        }
+        for (host, wait) in &snapshot.connection_waits_by_host {
+            let _ = writeln!(
+                out,
+                "proxy_connection_limit_waits_total{{host=\"{host}\"}} {}",
+                wait.requests
+            );
+            let _ = writeln!(
+                out,
+                "proxy_connection_limit_wait_ms_total{{host=\"{host}\"}} {}",
+                wait.wait_ms_total
+            );
+        }
+        for (key, count) in &snapshot.ipn_signatures_by_key {
+            let _ = writeln!(
+                out,
+                "proxy_ipn_signatures_verified_total{{key=\"{key}\"}} {count}"
+            );
+        }
+        for (env, counts) in &snapshot.contract_violations_by_env {
+            for (kind, count) in counts {
+                let _ = writeln!(
+                    out,
+                    "proxy_contract_violations_total{{env=\"{env}\",kind=\"{kind}\"}} {count}"
+                );
+            }
+        }
+        for (env, counts) in &snapshot.upstream_targets_by_env {
+            for (target, count) in counts {
+                let _ = writeln!(
+                    out,
+                    "proxy_upstream_target_total{{env=\"{env}\",target=\"{target}\"}} {count}"
+                );
+            }
+        }
+        for (protocol, counts) in &snapshot.tunnels_by_protocol {
+            for (outcome, count) in counts {
+                let _ = writeln!(
+                    out,
+                    "proxy_tunnels_total{{protocol=\"{protocol}\",outcome=\"{outcome}\"}} {count}"
+                );
+            }
+        }
+        for (outcome, count) in &snapshot.emails_by_outcome {
+            let _ = writeln!(out, "proxy_emails_total{{outcome=\"{outcome}\"}} {count}");
+        }
+        for (env, outcomes) in &snapshot.deliveries_by_outcome {
+            for (outcome, count) in outcomes {
+                let _ = writeln!(
+                    out,
+                    "proxy_deliveries_total{{env=\"{env}\",outcome=\"{outcome}\"}} {count}"
+                );
+            }
+        }
+        for (tag, counts) in &snapshot.requests_by_client_tag {
+            let _ = writeln!(
+                out,
+                "proxy_client_tag_requests_total{{tag=\"{tag}\"}} {}",
+                counts.requests
+            );
+            let _ = writeln!(
+                out,
+                "proxy_client_tag_errors_total{{tag=\"{tag}\"}} {}",
+                counts.errors
+            );
+            let _ = writeln!(
+                out,
+                "proxy_client_tag_latency_ms_total{{tag=\"{tag}\"}} {}",
+                counts.latency_ms_total
+            );
+        }
+        for (mapping, traffic) in &snapshot.udp_relay_traffic {
+            for (direction, packets, bytes) in [
+                ("out", traffic.packets_out, traffic.bytes_out),
+                ("in", traffic.packets_in, traffic.bytes_in),
+            ] {
+                let _ = writeln!(
+                    out,
+                    "proxy_udp_relay_packets_total{{mapping=\"{mapping}\",direction=\"{direction}\"}} {packets}"
+                );
+                let _ = writeln!(
+                    out,
+                    "proxy_udp_relay_bytes_total{{mapping=\"{mapping}\",direction=\"{direction}\"}} {bytes}"
+                );
+            }
+        }
+        for (name, window) in WINDOWS {
+            let stats = self.recent(window);
+            let _ = writeln!(
+                out,
+                "proxy_recent_requests_per_second{{window=\"{name}\"}} {}",
+                stats.requests_per_second
+            );
+            let _ = writeln!(
+                out,
+                "proxy_recent_error_rate{{window=\"{name}\"}} {}",
+                stats.error_rate
+            );
+            let _ = writeln!(
+                out,
+                "proxy_recent_latency_ms_avg{{window=\"{name}\"}} {}",
+                stats.avg_latency_ms
+            );
+        }
+
+        out
+    }
+}
+
+fn merge_counts<K: Ord + Clone>(into: &mut BTreeMap<K, u64>, from: &BTreeMap<K, u64>) {
+    for (key, count) in from {
+        *into.entry(key.clone()).or_default() += count;
+    }
+}
+
+fn merge_waits(into: &mut BTreeMap<String, QueueWait>, from: &BTreeMap<String, QueueWait>) {
+    for (key, wait) in from {
+        let entry = into.entry(key.clone()).or_default();
+        entry.requests += wait.requests;
+        entry.wait_ms_total += wait.wait_ms_total;
+    }
+}
+
+/// Imports the snapshot left at `path` by the previous run, if there is one.
+pub fn restore_snapshot(metrics: &Metrics, path: &Path) {
+    let contents = match std::fs::read(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return,
+        Err(e) => {
+            tracing::warn!("Failed to read metrics snapshot {}: {}", path.display(), e);
+            return;
+        }
+    };
+    match serde_json::from_slice::<MetricsSnapshot>(&contents) {
+        Ok(snapshot) => {
+            metrics.import(&snapshot);
+            tracing::info!(
+                "Carried over {} requests from the metrics snapshot",
+                snapshot.requests_total
+            );
+        }
+        Err(e) => tracing::warn!("Ignoring metrics snapshot {}: {}", path.display(), e),
+    }
+}
+
+/// Writes the current counters to `path` for the next run to import.
+pub fn persist_snapshot(metrics: &Metrics, path: &Path) -> std::io::Result<()> {
+    let json = serde_json::to_vec(&metrics.snapshot()).expect("snapshots serialize");
+    let temp = path.with_extension("tmp");
+    std::fs::write(&temp, json)?;
+    std::fs::rename(temp, path)
+}
+
+pub async fn metrics_handler(State(state): State<AppState>) -> String {
+    let mut out = state.metrics.render();
+    out.push_str(&state.slo.render());
+    out.push_str(&state.traffic_pause.render());
+    out.push_str(&state.prober.render());
+    out.push_str(&render_key_ages(&state.env_var_config));
+    out.push_str(&state.dns.render());
+    if let Some(budget) = &state.memory_budget {
+        out.push_str(&budget.render());
+    }
+    out.push_str(&crate::runtime_metrics::render());
+    out
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PathSort {
+    /// Slowest p99 first.
+    Latency,
+    #[default]
+    Count,
+    /// Highest error rate first.
+    Errors,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PathStatsQuery {
+    #[serde(default)]
+    pub sort: PathSort,
+    pub limit: Option<usize>,
+}
+
+const DEFAULT_PATH_LIMIT: usize = 20;
+
+/// `GET /metrics/paths?sort=latency|count|errors&limit=N`: the top paths as JSON.
+pub async fn path_metrics_handler(
+    State(state): State<AppState>,
+    Query(query): Query<PathStatsQuery>,
+) -> Json<Vec<PathStats>> {
+    let mut stats = state.metrics.path_stats();
+    match query.sort {
+        PathSort::Latency => stats.sort_by_key(|stats| Reverse(stats.p99_ms)),
+        PathSort::Count => stats.sort_by_key(|stats| Reverse(stats.requests)),
+        PathSort::Errors => stats.sort_by(|a, b| {
+            b.error_rate
+                .total_cmp(&a.error_rate)
+                .then(b.errors.cmp(&a.errors))
+        }),
+    }
+    stats.truncate(query.limit.unwrap_or(DEFAULT_PATH_LIMIT));
+    Json(stats)
+}
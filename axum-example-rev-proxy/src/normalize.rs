@@ -0,0 +1,134 @@
+//! Normalizes the path forwarded upstream, so equivalent spellings of a path
+//! reach the supplier (and the cache) as one, and encoded traversal tricks
+//! can't smuggle a different path past the proxy:
+//!
+//! - percent-escapes of unreserved characters are decoded, the rest
+//!   upper-cased (`%7e` → `~`, `%2f` → `%2F`);
+//! - runs of slashes collapse to one;
+//! - `.` and `..` segments are resolved, and climbing above the root is
+//!   refused;
+//! - NUL and other control characters, raw or encoded, are refused.
+
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PathError {
+    /// A `%` not followed by two hex digits.
+    InvalidEscape,
+    ControlCharacter,
+    /// `..` segments reaching above the root.
+    Traversal,
+}
+
+impl fmt::Display for PathError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::InvalidEscape => "invalid percent-encoding",
+            Self::ControlCharacter => "control character",
+            Self::Traversal => "path escapes the root",
+        })
+    }
+}
+
+/// Normalizes `raw`, a still percent-encoded path relative to the upstream
+/// base, into an absolute path starting with `/`.
+pub fn normalize_path(raw: &str) -> Result<String, PathError> {
+    let decoded = clean_escapes(raw)?;
+    let mut segments: Vec<&str> = Vec::new();
+    let mut parts = decoded.split('/').filter(|s| !s.is_empty()).peekable();
+    // A trailing slash (or one left by a final `.`/`..`) is kept.
+    let mut directory = decoded.ends_with('/');
+    while let Some(segment) = parts.next() {
+        let last = parts.peek().is_none();
+        match segment {
+            "." => directory |= last,
+            ".." => {
+                segments.pop().ok_or(PathError::Traversal)?;
+                directory |= last;
+            }
+            segment => segments.push(segment),
+        }
+    }
+    let mut path = format!("/{}", segments.join("/"));
+    if directory && !segments.is_empty() {
+        path.push('/');
+    }
+    Ok(path)
+}
+
+/// Decodes escapes of unreserved characters, upper-cases the others, and
+/// refuses control characters.
+fn clean_escapes(raw: &str) -> Result<String, PathError> {
+    let bytes = raw.as_bytes();
+    let mut out = String::with_capacity(raw.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let byte = bytes[i];
+        if byte.is_ascii_control() {
+            return Err(PathError::ControlCharacter);
+        }
+        if byte != b'%' {
+            out.push(byte as char);
+            i += 1;
+            continue;
+        }
+        let hex = bytes.get(i + 1..i + 3).ok_or(PathError::InvalidEscape)?;
+        let hex = std::str::from_utf8(hex).map_err(|_| PathError::InvalidEscape)?;
+        let decoded = u8::from_str_radix(hex, 16).map_err(|_| PathError::InvalidEscape)?;
+        if decoded.is_ascii_control() {
+            return Err(PathError::ControlCharacter);
+        }
+        if decoded.is_ascii_alphanumeric() || matches!(decoded, b'-' | b'.' | b'_' | b'~') {
+            out.push(decoded as char);
+        } else {
+            out.push('%');
+            out.push_str(&hex.to_ascii_uppercase());
+        }
+        i += 3;
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalizes_equivalent_spellings() {
+        let cases = [
+            ("api/hotels", "/api/hotels"),
+            ("api//hotels///list", "/api/hotels/list"),
+            ("api/./hotels/../rooms", "/api/rooms"),
+            ("api/hotels/", "/api/hotels/"),
+            ("api/hotels/.", "/api/hotels/"),
+            ("api/hotels/x/..", "/api/hotels/"),
+            ("api/%7euser/%41%62c", "/api/~user/Abc"),
+            ("api/a%2fb/c%3Fd", "/api/a%2Fb/c%3Fd"),
+            ("api/%2e%2e/rooms", "/rooms"),
+            ("api/caf%c3%a9", "/api/caf%C3%A9"),
+        ];
+        for (raw, expected) in cases {
+            assert_eq!(normalize_path(raw).as_deref(), Ok(expected), "{raw}");
+        }
+    }
+
+    #[test]
+    fn refuses_smuggling_attempts() {
+        assert_eq!(normalize_path("../etc/passwd"), Err(PathError::Traversal));
+        assert_eq!(
+            normalize_path("api/%2e%2e/%2E%2E/x"),
+            Err(PathError::Traversal)
+        );
+        assert_eq!(
+            normalize_path("api/a%00b"),
+            Err(PathError::ControlCharacter)
+        );
+        assert_eq!(
+            normalize_path("api/a%0d%0aX: 1"),
+            Err(PathError::ControlCharacter)
+        );
+        assert_eq!(normalize_path("api/a\tb"), Err(PathError::ControlCharacter));
+        assert_eq!(normalize_path("api/100%"), Err(PathError::InvalidEscape));
+        assert_eq!(normalize_path("api/%zz"), Err(PathError::InvalidEscape));
+    }
+}
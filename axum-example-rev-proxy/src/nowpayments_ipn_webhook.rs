@@ -1,27 +1,83 @@
+use std::fmt::Write;
+
 use axum::body::Bytes;
-use axum::{
-    http::{HeaderMap, StatusCode},
-    routing::post,
-};
+use axum::http::{HeaderMap, StatusCode};
 use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use sha2::Sha512;
 use tracing::{error, info};
 type HmacSha512 = Hmac<Sha512>;
 use axum::extract::ConnectInfo;
 use axum::extract::State;
-use std::net::IpAddr;
+use axum::Json;
+
+use crate::app_state::{AppState, EnvVarConfig};
+use crate::canonical::canonicalize;
+use crate::request_log::now_ms;
+
+/// Key id reported for `NOWPAYMENTS_IPN_SECRET`.
+pub const PRIMARY_KEY_ID: &str = "primary";
+
+/// An extra IPN secret accepted while the NOWPayments secret is rotated.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IpnSecret {
+    /// Shows up in logs and metrics; never the secret itself.
+    pub id: String,
+    pub secret: String,
+    /// Unix seconds when the secret was issued, for the key-age gauge.
+    #[serde(default)]
+    pub created_at: Option<u64>,
+}
 
-use crate::app_state::AppState;
-use crate::sort_json::sort_json;
+/// Parses `NOWPAYMENTS_IPN_SECRETS`, a JSON array of [`IpnSecret`]s.
+pub fn parse_ipn_secrets(value: &str) -> Result<Vec<IpnSecret>, String> {
+    if value.trim().is_empty() {
+        return Ok(Vec::new());
+    }
+    serde_json::from_str(value).map_err(|e| format!("invalid IPN secrets: {e}"))
+}
+
+/// The id of the first configured secret whose HMAC over `payload` equals
+/// `signature`, with that HMAC in hex.
+fn matching_key<'a>(
+    config: &'a EnvVarConfig,
+    payload: &[u8],
+    signature: &str,
+) -> Option<(&'a str, String)> {
+    let primary = (PRIMARY_KEY_ID, config.ipn_secret.as_str());
+    let rotated = config
+        .ipn_secrets
+        .iter()
+        .map(|key| (key.id.as_str(), key.secret.as_str()));
+    std::iter::once(primary)
+        .chain(rotated)
+        .find_map(|(id, secret)| {
+            let mut mac =
+                HmacSha512::new_from_slice(secret.as_bytes()).expect("HMAC key creation failed");
+            mac.update(payload);
+            let computed_hex = hex::encode(mac.finalize().into_bytes());
+            computed_hex.eq(signature).then_some((id, computed_hex))
+        })
+}
 
-// Define whitelist (could be a lazy_static or const once computed)
-static NOWPAYMENTS_ALLOWED_IPS: &[&str] = &[
-    "51.89.194.21",
-    "51.75.77.69",
-    "138.201.172.58",
-    "65.21.158.36",
-];
+/// Age of each rotated secret with a known `created_at`, in the Prometheus
+/// text format, appended to `/metrics`.
+pub fn render_key_ages(config: &EnvVarConfig) -> String {
+    let now = (now_ms() / 1000).max(0) as u64;
+    let mut out = String::new();
+    for key in &config.ipn_secrets {
+        if let Some(created_at) = key.created_at {
+            let _ = writeln!(
+                out,
+                "proxy_ipn_key_age_seconds{{key=\"{}\"}} {}",
+                key.id,
+                now.saturating_sub(created_at)
+            );
+        }
+    }
+    out
+}
 
 // todo see scratchpad_me.md for more security hardening
 pub async fn nowpayments_webhook(
@@ -30,16 +86,29 @@ pub async fn nowpayments_webhook(
     headers: HeaderMap,
     body: Bytes,
 ) -> (StatusCode, &'static str) {
-    let client_ip = remote_addr.ip();
+    // The listener is dual-stack, so IPv4 peers show up as IPv4-mapped IPv6.
+    let client_ip = remote_addr.ip().to_canonical();
     // Only allow if in whitelist
-    let allowed = NOWPAYMENTS_ALLOWED_IPS
-        .iter()
-        .any(|&ip| client_ip == ip.parse::<IpAddr>().unwrap());
+    let allowed = state
+        .env_var_config
+        .nowpayments_allowed_ips
+        .contains(&client_ip);
 
     if !allowed {
         tracing::warn!("Rejected webhook from unauthorized IP: {}", client_ip);
         return (StatusCode::FORBIDDEN, "Forbidden");
     }
+    process_ipn(&state, &headers, &body, "nowpayments").await
+}
+
+/// Everything after the source-IP check: signature verification and replay
+/// protection, remembering IPNs under `dedup_namespace`.
+async fn process_ipn(
+    state: &AppState,
+    headers: &HeaderMap,
+    body: &Bytes,
+    dedup_namespace: &str,
+) -> (StatusCode, &'static str) {
     // 1. Extract signature from headers
     let signature = match headers.get("x-nowpayments-sig") {
         Some(sig) => sig,
@@ -57,7 +126,7 @@ pub async fn nowpayments_webhook(
     };
 
     // 2. Parse JSON body
-    let payload: Value = match serde_json::from_slice(&body) {
+    let payload: Value = match serde_json::from_slice(body) {
         Ok(val) => val,
         Err(e) => {
             error!("Failed to parse JSON body: {}", e);
@@ -65,26 +134,98 @@ pub async fn nowpayments_webhook(
         }
     };
 
-    // 3. Sort JSON object
-    let sorted_payload = sort_json(&payload);
-    let payload_str = serde_json::to_string(&sorted_payload).unwrap_or_default();
+    // 3. Canonicalize the body the way NOWPayments signed it
+    let payload_str = match canonicalize(body, state.env_var_config.nowpayments_canonicalization) {
+        Ok(canonical) => canonical,
+        Err(e) => {
+            error!("Failed to canonicalize JSON body: {}", e);
+            return (StatusCode::INTERNAL_SERVER_ERROR, "JSON parsing error");
+        }
+    };
 
-    // 4. Compute HMAC-SHA512 signature
-    let mut mac = HmacSha512::new_from_slice(state.env_var_config.ipn_secret.as_bytes())
-        .expect("HMAC key creation failed");
-    mac.update(payload_str.as_bytes());
-    let computed_hmac = mac.finalize().into_bytes();
-    let computed_hex = hex::encode(computed_hmac);
-
-    // 5. Compare signatures
-    if computed_hex.eq(signature) {
-        info!("NowPayments webhook signature verified successfully");
-        (StatusCode::OK, "OK")
-    } else {
+    // 4. Compute HMAC-SHA512 signatures with each active secret and compare
+    let Some((key_id, computed_hex)) =
+        matching_key(&state.env_var_config, payload_str.as_bytes(), signature)
+    else {
         error!(
-            "Signature verification failed: expected {}, got {}",
-            computed_hex, signature
+            "Signature verification failed: no configured secret produced {}",
+            signature
         );
-        (StatusCode::BAD_REQUEST, "Invalid signature")
+        return (StatusCode::BAD_REQUEST, "Invalid signature");
+    };
+    state.metrics.record_ipn_key_match(key_id);
+    info!(
+        "NowPayments webhook signature verified successfully with key {}",
+        key_id
+    );
+
+    // 5. Skip replays. The signature covers the whole payload, so a new
+    // status for the same payment is a different key. Only verified IPNs are
+    // recorded, so forged requests can't block real ones.
+    let ttl = state.env_var_config.webhook_replay_ttl;
+    if !ttl.is_zero() {
+        match state
+            .webhook_dedup
+            .first_seen(&format!("{dedup_namespace}:{computed_hex}"), ttl)
+            .await
+        {
+            Ok(true) => {}
+            Ok(false) => {
+                tracing::warn!(
+                    "Ignored replayed webhook for payment {}",
+                    payload["payment_id"]
+                );
+                // Acknowledged, so NOWPayments stops retrying, but not acted
+                // on a second time.
+                return (StatusCode::OK, "Already processed");
+            }
+            Err(e) => {
+                // Fail closed; NOWPayments retries, a duplicate might not be harmless.
+                error!("Webhook replay check failed: {}", e);
+                return (StatusCode::SERVICE_UNAVAILABLE, "Try again later");
+            }
+        }
     }
+
+    (StatusCode::OK, "OK")
+}
+
+/// Result of `POST /admin/simulate-webhook`.
+#[derive(Debug, Serialize)]
+pub struct SimulatedWebhook {
+    pub status: u16,
+    pub body: &'static str,
+}
+
+/// `POST /admin/simulate-webhook`: signs the JSON payload in the request body
+/// with the primary IPN secret, the way NOWPayments would, and runs it through
+/// the same verification as a real IPN. The source-IP allowlist is skipped,
+/// and replays are tracked apart from real IPNs so a simulation can't make
+/// NOWPayments' own delivery look like one. Only routed in test mode.
+pub async fn simulate_webhook_handler(
+    State(state): State<AppState>,
+    Json(payload): Json<Value>,
+) -> Result<Json<SimulatedWebhook>, (StatusCode, String)> {
+    let body = Bytes::from(serde_json::to_vec(&payload).expect("JSON values serialize"));
+    let signed = canonicalize(&body, state.env_var_config.nowpayments_canonicalization)
+        .map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    let mut mac = HmacSha512::new_from_slice(state.env_var_config.ipn_secret.as_bytes())
+        .expect("HMAC key creation failed");
+    mac.update(signed.as_bytes());
+    let signature = hex::encode(mac.finalize().into_bytes());
+
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        "x-nowpayments-sig",
+        signature.parse().expect("hex is a valid header value"),
+    );
+    info!(
+        "Simulating NowPayments webhook for payment {}",
+        payload["payment_id"]
+    );
+    let (status, body) = process_ipn(&state, &headers, &body, "nowpayments-simulated").await;
+    Ok(Json(SimulatedWebhook {
+        status: status.as_u16(),
+        body,
+    }))
 }
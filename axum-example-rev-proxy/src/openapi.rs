@@ -0,0 +1,545 @@
+//! `GET /openapi.json`: an OpenAPI 3 description of the proxy's own surface,
+//! so teams can discover and generate clients for the admin and metrics
+//! APIs. It lists only the routes this instance mounted, with the proxy
+//! route's `{env}` limited to the configured environments.
+
+use serde_json::{json, Map, Value};
+
+use crate::app_state::AppState;
+use crate::RouteOptions;
+
+/// Builds the document for a router assembled from `state` and `options`.
+pub fn document(state: &AppState, options: &RouteOptions) -> Value {
+    let mut paths = Map::new();
+    let envs: Vec<&String> = state.env_var_config.upstreams.keys().collect();
+
+    paths.insert(
+        "/readyz".to_string(),
+        json!({"get": {
+            "summary": "Readiness: 503 while the egress IP has drifted from the expected one",
+            "responses": {
+                "200": text_response("ready"),
+                "503": text_response("egress IP drifted"),
+            },
+        }}),
+    );
+    paths.insert(
+        "/openapi.json".to_string(),
+        json!({"get": {
+            "summary": "This document",
+            "responses": {"200": json_response(json!({"type": "object"}))},
+        }}),
+    );
+    if options.webhook {
+        paths.insert(
+            "/nowpayments-webhook".to_string(),
+            json!({"post": {
+                "summary": "NOWPayments IPN callback, verified by signature and source address",
+                "parameters": [{
+                    "name": "x-nowpayments-sig",
+                    "in": "header",
+                    "required": true,
+                    "schema": {"type": "string"},
+                }],
+                "requestBody": json_body(json!({"type": "object"})),
+                "responses": {
+                    "200": text_response("processed, or already processed"),
+                    "400": text_response("missing or invalid signature"),
+                    "403": text_response("source address not allowed"),
+                    "503": text_response("try again later"),
+                },
+            }}),
+        );
+    }
+    if options.metrics_endpoint {
+        paths.insert(
+            "/metrics".to_string(),
+            json!({"get": {
+                "summary": "Counters in the Prometheus text format",
+                "responses": {"200": text_response("metrics")},
+            }}),
+        );
+        paths.insert(
+            "/metrics/paths".to_string(),
+            json!({"get": {
+                "summary": "Busiest inbound paths",
+                "parameters": [
+                    query_param("sort", json!({"type": "string", "enum": ["latency", "count", "errors"]})),
+                    query_param("limit", json!({"type": "integer", "minimum": 1})),
+                ],
+                "responses": {"200": json_response(json!({
+                    "type": "array",
+                    "items": {"$ref": "#/components/schemas/PathStats"},
+                }))},
+            }}),
+        );
+        paths.insert(
+            "/slo".to_string(),
+            json!({"get": {
+                "summary": "Availability and latency objectives with their error budgets",
+                "responses": {"200": json_response(json!({"type": "array", "items": {"type": "object"}}))},
+            }}),
+        );
+        paths.insert(
+            "/info".to_string(),
+            json!({"get": {
+                "summary": "Build, upstream and process information",
+                "responses": {"200": json_response(json!({"type": "object"}))},
+            }}),
+        );
+    }
+    if options.admin && crate::rbac::enabled(&state.env_var_config) {
+        admin_paths(&mut paths, state.env_var_config.nowpayments_test_mode);
+    }
+    if state.store_forward.is_some() {
+        paths.insert(
+            "/deliveries/{id}".to_string(),
+            json!({"get": {
+                "summary": "Delivery status of a request accepted for store-and-forward",
+                "parameters": [{"name": "id", "in": "path", "required": true, "schema": {"type": "string"}}],
+                "responses": {
+                    "200": json_response(json!({"type": "object"})),
+                    "404": {"description": "No such delivery"},
+                },
+            }}),
+        );
+    }
+    if state.email.is_some() {
+        paths.insert(
+            "/email/send".to_string(),
+            json!({"post": {
+                "summary": "Send an email through the SMTP provider from the static egress IP",
+                "security": [{"email": []}],
+                "requestBody": json_body(json!({
+                    "type": "object",
+                    "required": ["from", "to", "subject"],
+                    "properties": {
+                        "from": {"type": "string"},
+                        "to": {"type": "array", "items": {"type": "string"}},
+                        "cc": {"type": "array", "items": {"type": "string"}},
+                        "bcc": {"type": "array", "items": {"type": "string"}},
+                        "reply_to": {"type": "string"},
+                        "subject": {"type": "string"},
+                        "text": {"type": "string"},
+                        "html": {"type": "string"},
+                    },
+                })),
+                "responses": {
+                    "200": json_response(json!({"type": "object"})),
+                    "400": {"description": "Invalid message"},
+                    "401": {"description": "Missing or wrong bridge token"},
+                    "403": {"description": "Sender not allowed"},
+                    "422": {"description": "Rejected by the SMTP server"},
+                    "429": {"description": "Sender rate limited"},
+                    "502": {"description": "SMTP delivery failed"},
+                },
+            }}),
+        );
+    }
+    paths.insert(
+        "/{env}/{path}".to_string(),
+        json!({"parameters": [
+            {
+                "name": "env",
+                "in": "path",
+                "required": true,
+                "description": "Upstream environment",
+                "schema": {"type": "string", "enum": envs},
+            },
+            {
+                "name": "path",
+                "in": "path",
+                "required": true,
+                "description": "Path forwarded to the upstream, may contain slashes",
+                "schema": {"type": "string"},
+            },
+        ]}),
+    );
+    if state.env_var_config.batch_max_requests.is_some() {
+        paths.insert(
+            "/{env}/_batch".to_string(),
+            json!({"post": {
+                "summary": "Send several requests to the environment's upstream in one round trip",
+                "parameters": [{
+                    "name": "env",
+                    "in": "path",
+                    "required": true,
+                    "schema": {"type": "string", "enum": envs},
+                }],
+                "requestBody": json_body(json!({
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "required": ["path"],
+                        "properties": {
+                            "method": {"type": "string"},
+                            "path": {"type": "string"},
+                            "headers": {"type": "object"},
+                            "body": {},
+                        },
+                    },
+                })),
+                "responses": {
+                    "200": json_response(json!({"type": "array", "items": {"type": "object"}})),
+                    "4XX": {"description": "Malformed or oversized batch"},
+                },
+            }}),
+        );
+    }
+    let proxy = paths
+        .get_mut("/{env}/{path}")
+        .and_then(Value::as_object_mut)
+        .expect("just inserted");
+    for method in ["get", "post", "put", "patch", "delete"] {
+        proxy.insert(
+            method.to_string(),
+            json!({
+                "summary": "Forward to the environment's upstream through the static egress IP",
+                "responses": {
+                    "default": {"description": "The upstream's response"},
+                    "4XX": error_response(),
+                    "5XX": error_response(),
+                },
+            }),
+        );
+    }
+
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "Static IP egress proxy",
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "paths": paths,
+        "components": {
+            "securitySchemes": {
+                "admin": {"type": "http", "scheme": "bearer"},
+                "email": {"type": "http", "scheme": "bearer"},
+            },
+            "schemas": {
+                "Error": {
+                    "type": "object",
+                    "required": ["status", "code", "error", "retryable", "correlation_id"],
+                    "properties": {
+                        "status": {"type": "integer"},
+                        "code": {"type": "string"},
+                        "error": {"type": "string"},
+                        "retryable": {"type": "boolean"},
+                        "correlation_id": {"type": "string"},
+                    },
+                },
+                "PathStats": {
+                    "type": "object",
+                    "properties": {
+                        "path": {"type": "string"},
+                        "requests": {"type": "integer"},
+                        "errors": {"type": "integer"},
+                        "error_rate": {"type": "number"},
+                    },
+                },
+                "MetricsSnapshot": {
+                    "type": "object",
+                    "additionalProperties": true,
+                },
+            },
+        },
+    })
+}
+
+fn admin_paths(paths: &mut Map<String, Value>, webhook_simulator: bool) {
+    let admin = |operation: Value| {
+        let mut operation = operation;
+        operation["security"] = json!([{"admin": []}]);
+        operation["responses"]["401"] = json!({"description": "Missing or wrong admin token"});
+        operation
+    };
+    paths.insert(
+        "/admin/cache".to_string(),
+        json!({"delete": admin(json!({
+            "summary": "Drop cached responses under a path prefix",
+            "parameters": [query_param("path_prefix", json!({"type": "string"}))],
+            "responses": {"200": json_response(json!({
+                "type": "object",
+                "properties": {"removed": {"type": "integer"}},
+            }))},
+        }))}),
+    );
+    paths.insert(
+        "/admin/request-log".to_string(),
+        json!({"get": admin(json!({
+            "summary": "Matching request summaries, newest first",
+            "parameters": [
+                query_param("from_ms", json!({"type": "integer"})),
+                query_param("to_ms", json!({"type": "integer"})),
+                query_param("status", json!({"type": "integer"})),
+                query_param("path_prefix", json!({"type": "string"})),
+                query_param("correlation_id", json!({"type": "string"})),
+                query_param("fingerprint", json!({"type": "string"})),
+                query_param("client_tag", json!({"type": "string"})),
+                query_param("limit", json!({"type": "integer"})),
+            ],
+            "responses": {
+                "200": json_response(json!({"type": "array", "items": {"type": "object"}})),
+                "404": {"description": "Request logging is off"},
+            },
+        }))}),
+    );
+    let filter = json!({
+        "type": "object",
+        "required": ["filter"],
+        "properties": {"filter": {"type": "string"}},
+    });
+    paths.insert(
+        "/admin/loglevel".to_string(),
+        json!({
+            "get": admin(json!({
+                "summary": "The active tracing filter",
+                "responses": {"200": json_response(filter.clone())},
+            })),
+            "put": admin(json!({
+                "summary": "Replace the tracing filter",
+                "requestBody": json_body(filter.clone()),
+                "responses": {
+                    "200": json_response(filter),
+                    "400": text_response("invalid filter"),
+                },
+            })),
+        }),
+    );
+    paths.insert(
+        "/admin/probes".to_string(),
+        json!({"get": admin(json!({
+            "summary": "Latest synthetic probe results",
+            "responses": {"200": json_response(json!({"type": "array", "items": {"type": "object"}}))},
+        }))}),
+    );
+    let snapshot = json!({"$ref": "#/components/schemas/MetricsSnapshot"});
+    paths.insert(
+        "/admin/metrics/snapshot".to_string(),
+        json!({"get": admin(json!({
+            "summary": "Lifetime counters as JSON",
+            "responses": {"200": json_response(snapshot.clone())},
+        }))}),
+    );
+    paths.insert(
+        "/admin/metrics/import".to_string(),
+        json!({"post": admin(json!({
+            "summary": "Add an earlier run's snapshot to the counters, once per run",
+            "requestBody": json_body(snapshot),
+            "responses": {
+                "200": json_response(json!({"type": "object"})),
+                "409": {"description": "A snapshot was already imported"},
+            },
+        }))}),
+    );
+    paths.insert(
+        "/admin/compare".to_string(),
+        json!({"post": admin(json!({
+            "summary": "Send one request to two environments and diff the responses",
+            "requestBody": json_body(json!({
+                "type": "object",
+                "required": ["path"],
+                "properties": {
+                    "method": {"type": "string"},
+                    "path": {"type": "string"},
+                    "headers": {"type": "object", "additionalProperties": {"type": "string"}},
+                    "body": {"type": "string"},
+                    "envs": {"type": "array", "items": {"type": "string"}, "minItems": 2, "maxItems": 2},
+                    "ignore": {"type": "array", "items": {"type": "string"}},
+                },
+            })),
+            "responses": {"200": json_response(json!({"type": "object"}))},
+        }))}),
+    );
+    if webhook_simulator {
+        paths.insert(
+            "/admin/simulate-webhook".to_string(),
+            json!({"post": admin(json!({
+                "summary": "Sign a sample IPN and run it through the webhook handler",
+                "requestBody": json_body(json!({"type": "object"})),
+                "responses": {"200": json_response(json!({"type": "object"}))},
+            }))}),
+        );
+    }
+    paths.insert(
+        "/admin/dashboard".to_string(),
+        json!({"get": admin(json!({
+            "summary": "Recent traffic, busiest paths, probes, SLOs and slow requests",
+            "responses": {"200": json_response(json!({"type": "object"}))},
+        }))}),
+    );
+    paths.insert(
+        "/admin/tail".to_string(),
+        json!({"get": admin(json!({
+            "summary": "Live feed of completed requests as Server-Sent Events",
+            "parameters": [
+                query_param("env", json!({"type": "string"})),
+                query_param("status", json!({"type": "string", "example": "5xx"})),
+            ],
+            "responses": {"200": {
+                "description": "`request` events with JSON summaries, `lagged` when some were skipped",
+                "content": {"text/event-stream": {"schema": {"type": "string"}}},
+            }},
+        }))}),
+    );
+    let capture_status = json!({
+        "type": "object",
+        "properties": {
+            "rule": {"type": "object", "nullable": true},
+            "armed": {"type": "boolean"},
+            "captured": {"type": "integer"},
+            "expires_in_secs": {"type": "integer", "nullable": true},
+        },
+    });
+    paths.insert(
+        "/admin/capture".to_string(),
+        json!({
+            "get": admin(json!({
+                "summary": "The capture rule and how many exchanges it has recorded",
+                "responses": {"200": json_response(capture_status.clone())},
+            })),
+            "post": admin(json!({
+                "summary": "Record the next matching exchanges, redacted, replacing any earlier capture",
+                "requestBody": json_body(json!({
+                    "type": "object",
+                    "required": ["path_prefix", "count"],
+                    "properties": {
+                        "path_prefix": {"type": "string"},
+                        "count": {"type": "integer", "minimum": 1, "maximum": crate::capture::MAX_CAPTURE_COUNT},
+                        "ttl_secs": {"type": "integer"},
+                    },
+                })),
+                "responses": {
+                    "200": json_response(capture_status),
+                    "400": text_response("invalid count"),
+                },
+            })),
+            "delete": admin(json!({
+                "summary": "Disarm and drop captured exchanges",
+                "responses": {"204": {"description": "Cleared"}},
+            })),
+        }),
+    );
+    paths.insert(
+        "/admin/capture/download".to_string(),
+        json!({"get": admin(json!({
+            "summary": "Captured request/response pairs as a JSON file",
+            "responses": {"200": json_response(json!({"type": "array", "items": {"type": "object"}}))},
+        }))}),
+    );
+    paths.insert(
+        "/admin/export".to_string(),
+        json!({"get": admin(json!({
+            "summary": "Recorded exchanges, redacted, as a JSONL or HAR 1.2 file for support tickets",
+            "parameters": [
+                query_param("source", json!({"type": "string", "enum": ["log", "capture"]})),
+                query_param("format", json!({"type": "string", "enum": ["jsonl", "har"]})),
+                query_param("from_ms", json!({"type": "integer"})),
+                query_param("to_ms", json!({"type": "integer"})),
+                query_param("path_prefix", json!({"type": "string"})),
+                query_param("status", json!({"type": "integer"})),
+                query_param("limit", json!({"type": "integer", "maximum": 1000})),
+            ],
+            "responses": {
+                "200": {"description": "One exchange per line, or a HAR archive", "content": {
+                    "application/x-ndjson": {"schema": {"type": "string"}},
+                    "application/json": {"schema": {"type": "object"}},
+                }},
+                "404": {"description": "Request logging is off"},
+            },
+        }))}),
+    );
+    let fault_rules = json!({
+        "type": "array",
+        "items": {
+            "type": "object",
+            "required": ["path_prefix"],
+            "properties": {
+                "path_prefix": {"type": "string"},
+                "error_percent": {"type": "number", "minimum": 0, "maximum": 100},
+                "error_status": {"type": "integer", "minimum": 400, "maximum": 599},
+                "delay_ms": {"type": "integer"},
+                "jitter_ms": {"type": "integer"},
+                "drop_percent": {"type": "number", "minimum": 0, "maximum": 100},
+                "latency": {
+                    "type": "object",
+                    "nullable": true,
+                    "required": ["distribution"],
+                    "properties": {
+                        "distribution": {"type": "string", "enum": ["normal", "log_normal"]},
+                        "mean_ms": {"type": "number"},
+                        "stddev_ms": {"type": "number"},
+                        "median_ms": {"type": "number"},
+                        "sigma": {"type": "number"},
+                    },
+                },
+                "bandwidth_bytes_per_sec": {"type": "integer", "minimum": 1, "nullable": true},
+                "reset_percent": {"type": "number", "minimum": 0, "maximum": 100},
+            },
+        },
+    });
+    paths.insert(
+        "/admin/faults".to_string(),
+        json!({
+            "get": admin(json!({
+                "summary": "Active fault injection rules, in matching order",
+                "responses": {"200": json_response(fault_rules.clone())},
+            })),
+            "put": admin(json!({
+                "summary": "Replace the fault injection rules",
+                "requestBody": json_body(fault_rules.clone()),
+                "responses": {
+                    "200": json_response(fault_rules),
+                    "400": text_response("invalid rule"),
+                },
+            })),
+            "delete": admin(json!({
+                "summary": "Stop injecting faults",
+                "responses": {"204": {"description": "Cleared"}},
+            })),
+        }),
+    );
+    paths.insert(
+        "/admin/ui".to_string(),
+        json!({"get": {
+            "summary": "Dashboard page; asks for the admin token to load its data",
+            "responses": {"200": {
+                "description": "OK",
+                "content": {"text/html": {"schema": {"type": "string"}}},
+            }},
+        }}),
+    );
+    paths.insert(
+        "/egress-ip".to_string(),
+        json!({"get": admin(json!({
+            "summary": "The discovered egress IP and whether it is the expected one",
+            "responses": {
+                "200": json_response(json!({"type": "object"})),
+                "502": {"description": "Egress IP discovery failed"},
+            },
+        }))}),
+    );
+}
+
+fn query_param(name: &str, schema: Value) -> Value {
+    json!({"name": name, "in": "query", "schema": schema})
+}
+
+fn json_body(schema: Value) -> Value {
+    json!({"required": true, "content": {"application/json": {"schema": schema}}})
+}
+
+fn json_response(schema: Value) -> Value {
+    json!({"description": "OK", "content": {"application/json": {"schema": schema}}})
+}
+
+fn text_response(description: &str) -> Value {
+    json!({"description": description, "content": {"text/plain": {"schema": {"type": "string"}}}})
+}
+
+fn error_response() -> Value {
+    json!({
+        "description": "Rejected by the proxy",
+        "content": {"application/json": {"schema": {"$ref": "#/components/schemas/Error"}}},
+    })
+}
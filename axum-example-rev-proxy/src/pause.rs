@@ -0,0 +1,353 @@
+//! A safety valve for a broken upstream deployment: once an environment's
+//! upstream failures pass `max_error_rate` over the last `window_secs`, its
+//! traffic is paused for `pause_secs`. Paused requests get a 503
+//! (`upstream_paused`) with `Retry-After` and never leave the proxy, so a
+//! failing supplier doesn't also spend our rate limits with it.
+//!
+//! Rules come from `TRAFFIC_PAUSE_RULES`, a JSON array such as
+//! `[{"env": "prod", "max_error_rate": 0.5, "window_secs": 60}]`. Pausing
+//! and resuming are logged and posted to `TRAFFIC_PAUSE_ALERT_WEBHOOK_URL`.
+//! `GET /admin/pauses` shows each rule's state and
+//! `DELETE /admin/pauses/{env}` resumes an env early.
+
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use axum::extract::{Path, State};
+use axum::Json;
+use hyper::StatusCode;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tracing::{error, info, warn};
+
+use crate::app_state::AppState;
+use crate::error::ProxyError;
+use crate::request_log::now_ms;
+use crate::rolling::RollingWindow;
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct PauseRule {
+    pub env: String,
+    /// Share of upstream calls failing (5xx or unreachable) that pauses.
+    pub max_error_rate: f64,
+    #[serde(default = "default_window_secs")]
+    pub window_secs: u64,
+    /// Calls the window needs before its error rate counts.
+    #[serde(default = "default_min_requests")]
+    pub min_requests: u64,
+    #[serde(default = "default_pause_secs")]
+    pub pause_secs: u64,
+}
+
+fn default_window_secs() -> u64 {
+    60
+}
+
+fn default_min_requests() -> u64 {
+    20
+}
+
+fn default_pause_secs() -> u64 {
+    300
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct PauseConfig {
+    pub rules: Vec<PauseRule>,
+    /// Posted `{"text": ...}` when an env pauses or resumes.
+    pub alert_webhook_url: Option<String>,
+}
+
+/// Parses `TRAFFIC_PAUSE_RULES`, a JSON array of [`PauseRule`]s.
+pub fn parse_pause_rules(value: &str) -> Result<Vec<PauseRule>, String> {
+    if value.trim().is_empty() {
+        return Ok(Vec::new());
+    }
+    let rules: Vec<PauseRule> =
+        serde_json::from_str(value).map_err(|e| format!("invalid traffic pause rules: {e}"))?;
+    for rule in &rules {
+        if !(rule.max_error_rate > 0.0 && rule.max_error_rate <= 1.0) {
+            return Err(format!(
+                "traffic pause for {}: max_error_rate must be in (0, 1]",
+                rule.env
+            ));
+        }
+        if rule.window_secs == 0 || rule.window_secs > 15 * 60 {
+            return Err(format!(
+                "traffic pause for {}: window_secs must be 1 to 900",
+                rule.env
+            ));
+        }
+    }
+    Ok(rules)
+}
+
+#[derive(Debug, Clone)]
+struct Paused {
+    since_ms: i64,
+    until: Instant,
+    error_rate: f64,
+}
+
+#[derive(Debug)]
+struct Breaker {
+    rule: PauseRule,
+    /// Upstream calls since the env last resumed.
+    recent: Mutex<RollingWindow>,
+    paused: Mutex<Option<Paused>>,
+}
+
+/// A rule and where its env stands, for `GET /admin/pauses`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PauseStatus {
+    pub env: String,
+    pub max_error_rate: f64,
+    pub window_secs: u64,
+    pub error_rate: f64,
+    pub requests: u64,
+    pub paused: bool,
+    pub paused_since_ms: Option<i64>,
+    /// Seconds until traffic resumes by itself, while paused.
+    pub resumes_in_secs: Option<u64>,
+}
+
+#[derive(Debug)]
+pub struct TrafficPause {
+    breakers: Vec<Breaker>,
+    client: reqwest::Client,
+    alert_webhook_url: Option<String>,
+}
+
+impl TrafficPause {
+    /// `client` posts the alerts.
+    pub fn new(config: &PauseConfig, client: reqwest::Client) -> Self {
+        Self {
+            breakers: config
+                .rules
+                .iter()
+                .map(|rule| Breaker {
+                    rule: rule.clone(),
+                    recent: Mutex::default(),
+                    paused: Mutex::new(None),
+                })
+                .collect(),
+            client,
+            alert_webhook_url: config.alert_webhook_url.clone(),
+        }
+    }
+
+    /// Refuses a request to `env` while it's paused. A pause that has run
+    /// its course ends here, with a fresh window.
+    pub fn check(&self, env: &str) -> Result<(), ProxyError> {
+        let Some(breaker) = self.breaker(env) else {
+            return Ok(());
+        };
+        let mut paused = breaker.paused.lock().unwrap();
+        let Some(state) = paused.as_ref() else {
+            return Ok(());
+        };
+        let now = Instant::now();
+        if state.until > now {
+            let remaining = state.until.duration_since(now).as_secs_f64().ceil() as u64;
+            return Err(ProxyError::Paused(remaining.max(1)));
+        }
+        *paused = None;
+        *breaker.recent.lock().unwrap() = RollingWindow::default();
+        info!("Resuming traffic to {} after its pause", env);
+        self.alert(format!(
+            "egress proxy: resuming traffic to {env} after its pause."
+        ));
+        Ok(())
+    }
+
+    /// Counts an upstream call to `env`, pausing the env if failures are
+    /// over its rule.
+    pub fn record(&self, env: &str, failed: bool) {
+        let Some(breaker) = self.breaker(env) else {
+            return;
+        };
+        let mut paused = breaker.paused.lock().unwrap();
+        if paused.is_some() {
+            return;
+        }
+        let stats = {
+            let recent = breaker.recent.lock().unwrap();
+            let status = if failed {
+                StatusCode::BAD_GATEWAY
+            } else {
+                StatusCode::OK
+            };
+            recent.record(status, Duration::ZERO);
+            recent.stats(Duration::from_secs(breaker.rule.window_secs))
+        };
+        let rule = &breaker.rule;
+        if stats.requests < rule.min_requests || stats.error_rate <= rule.max_error_rate {
+            return;
+        }
+        *paused = Some(Paused {
+            since_ms: now_ms(),
+            until: Instant::now() + Duration::from_secs(rule.pause_secs),
+            error_rate: stats.error_rate,
+        });
+        error!(
+            "Pausing traffic to {} for {}s: {:.0}% of {} upstream calls failed over {}s",
+            env,
+            rule.pause_secs,
+            stats.error_rate * 100.0,
+            stats.requests,
+            rule.window_secs
+        );
+        self.alert(format!(
+            "egress proxy: paused traffic to {env} for {}s, {:.0}% of upstream calls failed over the last {}s.",
+            rule.pause_secs,
+            stats.error_rate * 100.0,
+            rule.window_secs
+        ));
+    }
+
+    /// Ends `env`'s pause early; false if it wasn't paused.
+    pub fn resume(&self, env: &str) -> bool {
+        let Some(breaker) = self.breaker(env) else {
+            return false;
+        };
+        if breaker.paused.lock().unwrap().take().is_none() {
+            return false;
+        }
+        *breaker.recent.lock().unwrap() = RollingWindow::default();
+        self.alert(format!(
+            "egress proxy: traffic to {env} resumed by an admin."
+        ));
+        true
+    }
+
+    pub fn status(&self) -> Vec<PauseStatus> {
+        let now = Instant::now();
+        self.breakers
+            .iter()
+            .map(|breaker| {
+                let rule = &breaker.rule;
+                let stats = breaker
+                    .recent
+                    .lock()
+                    .unwrap()
+                    .stats(Duration::from_secs(rule.window_secs));
+                let paused = breaker.paused.lock().unwrap().clone();
+                let paused = paused.filter(|paused| paused.until > now);
+                PauseStatus {
+                    env: rule.env.clone(),
+                    max_error_rate: rule.max_error_rate,
+                    window_secs: rule.window_secs,
+                    error_rate: paused.as_ref().map_or(stats.error_rate, |p| p.error_rate),
+                    requests: stats.requests,
+                    paused: paused.is_some(),
+                    paused_since_ms: paused.as_ref().map(|paused| paused.since_ms),
+                    resumes_in_secs: paused
+                        .as_ref()
+                        .map(|paused| paused.until.duration_since(now).as_secs()),
+                }
+            })
+            .collect()
+    }
+
+    /// Whether each env is paused, in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        for status in self.status() {
+            let _ = writeln!(
+                out,
+                "proxy_upstream_paused{{env=\"{}\"}} {}",
+                status.env, status.paused as u8
+            );
+        }
+        out
+    }
+
+    fn breaker(&self, env: &str) -> Option<&Breaker> {
+        self.breakers.iter().find(|breaker| breaker.rule.env == env)
+    }
+
+    fn alert(&self, message: String) {
+        let Some(url) = self.alert_webhook_url.clone() else {
+            return;
+        };
+        let client = self.client.clone();
+        tokio::spawn(async move {
+            let result = client
+                .post(url)
+                .json(&json!({ "text": message }))
+                .send()
+                .await
+                .and_then(|response| response.error_for_status());
+            if let Err(e) = result {
+                error!("Failed to send traffic pause alert: {}", e);
+            }
+        });
+    }
+}
+
+/// `GET /admin/pauses`: each rule and whether its env is paused.
+pub async fn list_handler(State(state): State<AppState>) -> Json<Vec<PauseStatus>> {
+    Json(state.traffic_pause.status())
+}
+
+/// `DELETE /admin/pauses/{env}`: resumes `env` now.
+pub async fn resume_handler(State(state): State<AppState>, Path(env): Path<String>) -> StatusCode {
+    if state.traffic_pause.resume(&env) {
+        warn!(target: "audit", "Traffic to {} resumed before its pause ended", env);
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::NOT_FOUND
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pause(max_error_rate: f64) -> TrafficPause {
+        let config = PauseConfig {
+            rules: vec![PauseRule {
+                env: "prod".to_string(),
+                max_error_rate,
+                window_secs: 60,
+                min_requests: 4,
+                pause_secs: 60,
+            }],
+            alert_webhook_url: None,
+        };
+        TrafficPause::new(&config, reqwest::Client::new())
+    }
+
+    #[test]
+    fn pauses_an_env_once_failures_pass_the_threshold() {
+        let pause = pause(0.5);
+        for failed in [true, true, true] {
+            pause.record("prod", failed);
+        }
+        // Too few calls yet to judge.
+        assert!(pause.check("prod").is_ok());
+        pause.record("prod", false);
+        pause.record("test", true);
+        assert_eq!(pause.check("prod"), Err(ProxyError::Paused(60)));
+        assert!(pause.check("test").is_ok());
+        assert!(pause.status()[0].paused);
+
+        assert!(pause.resume("prod"));
+        assert!(pause.check("prod").is_ok());
+        assert_eq!(pause.status()[0].requests, 0);
+        assert!(!pause.resume("prod"));
+    }
+
+    #[test]
+    fn rejects_bad_rules() {
+        assert!(parse_pause_rules(r#"[{"env": "prod", "max_error_rate": 0}]"#).is_err());
+        assert!(parse_pause_rules(
+            r#"[{"env": "prod", "max_error_rate": 0.5, "window_secs": 3600}]"#
+        )
+        .is_err());
+        let rules = parse_pause_rules(r#"[{"env": "prod", "max_error_rate": 0.5}]"#).unwrap();
+        assert_eq!(rules[0].pause_secs, 300);
+    }
+}
@@ -0,0 +1,169 @@
+//! Body sizes of upstream calls, as Prometheus histograms per environment,
+//! direction and content-type family, to watch bandwidth on the static-IP
+//! link (billed per GB) and see which kind of payload drives it.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+
+use hyper::header::{self, HeaderMap};
+
+/// Upper bounds of the histogram buckets, in bytes.
+const BUCKETS: [u64; 8] = [
+    1 << 10,
+    4 << 10,
+    16 << 10,
+    64 << 10,
+    256 << 10,
+    1 << 20,
+    4 << 20,
+    16 << 20,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Direction {
+    /// Request bodies sent upstream, out through the static IP.
+    Request,
+    /// Response bodies received from upstream.
+    Response,
+}
+
+impl Direction {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Request => "request",
+            Self::Response => "response",
+        }
+    }
+}
+
+/// Groups a `Content-Type` into `json`, `xml`, `text`, `binary`, or `none`
+/// when absent.
+pub fn content_family(headers: &HeaderMap) -> &'static str {
+    let Some(content_type) = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+    else {
+        return "none";
+    };
+    let essence = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    if essence.ends_with("/json") || essence.ends_with("+json") {
+        "json"
+    } else if essence.ends_with("/xml") || essence.ends_with("+xml") {
+        "xml"
+    } else if essence.starts_with("text/") || essence == "application/x-www-form-urlencoded" {
+        "text"
+    } else {
+        "binary"
+    }
+}
+
+#[derive(Debug, Default)]
+struct Histogram {
+    /// Counts per bucket, not cumulative; the last is for larger bodies.
+    buckets: [u64; BUCKETS.len() + 1],
+    count: u64,
+    sum: u64,
+}
+
+type Key = (String, Direction, &'static str);
+
+#[derive(Debug, Default)]
+pub struct PayloadSizes {
+    histograms: Mutex<BTreeMap<Key, Histogram>>,
+}
+
+impl PayloadSizes {
+    pub fn record(&self, env: &str, direction: Direction, family: &'static str, bytes: u64) {
+        let mut histograms = self.histograms.lock().unwrap();
+        let histogram = histograms
+            .entry((env.to_string(), direction, family))
+            .or_default();
+        let bucket = BUCKETS
+            .iter()
+            .position(|bound| bytes <= *bound)
+            .unwrap_or(BUCKETS.len());
+        histogram.buckets[bucket] += 1;
+        histogram.count += 1;
+        histogram.sum += bytes;
+    }
+
+    pub fn render(&self, out: &mut String) {
+        let histograms = self.histograms.lock().unwrap();
+        for ((env, direction, family), histogram) in histograms.iter() {
+            let labels = format!(
+                "env=\"{env}\",direction=\"{}\",family=\"{family}\"",
+                direction.as_str()
+            );
+            let mut cumulative = 0;
+            for (bound, count) in BUCKETS.iter().zip(histogram.buckets) {
+                cumulative += count;
+                let _ = writeln!(
+                    out,
+                    "proxy_payload_bytes_bucket{{{labels},le=\"{bound}\"}} {cumulative}"
+                );
+            }
+            let _ = writeln!(
+                out,
+                "proxy_payload_bytes_bucket{{{labels},le=\"+Inf\"}} {}",
+                histogram.count
+            );
+            let _ = writeln!(out, "proxy_payload_bytes_sum{{{labels}}} {}", histogram.sum);
+            let _ = writeln!(
+                out,
+                "proxy_payload_bytes_count{{{labels}}} {}",
+                histogram.count
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn groups_content_types_into_families() {
+        let family = |value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::CONTENT_TYPE, value.parse().unwrap());
+            content_family(&headers)
+        };
+        assert_eq!(family("application/json; charset=utf-8"), "json");
+        assert_eq!(family("application/problem+json"), "json");
+        assert_eq!(family("text/xml"), "xml");
+        assert_eq!(family("application/soap+xml"), "xml");
+        assert_eq!(family("text/plain"), "text");
+        assert_eq!(family("image/png"), "binary");
+        assert_eq!(content_family(&HeaderMap::new()), "none");
+    }
+
+    #[test]
+    fn renders_cumulative_buckets() {
+        let sizes = PayloadSizes::default();
+        sizes.record("test", Direction::Response, "json", 100);
+        sizes.record("test", Direction::Response, "json", 3000);
+        sizes.record("test", Direction::Response, "json", 100 << 20);
+        let mut out = String::new();
+        sizes.render(&mut out);
+        let labels = "env=\"test\",direction=\"response\",family=\"json\"";
+        assert!(out.contains(&format!(
+            "proxy_payload_bytes_bucket{{{labels},le=\"1024\"}} 1\n"
+        )));
+        assert!(out.contains(&format!(
+            "proxy_payload_bytes_bucket{{{labels},le=\"4096\"}} 2\n"
+        )));
+        assert!(out.contains(&format!(
+            "proxy_payload_bytes_bucket{{{labels},le=\"16777216\"}} 2\n"
+        )));
+        assert!(out.contains(&format!(
+            "proxy_payload_bytes_bucket{{{labels},le=\"+Inf\"}} 3\n"
+        )));
+        assert!(out.contains(&format!("proxy_payload_bytes_count{{{labels}}} 3\n")));
+    }
+}
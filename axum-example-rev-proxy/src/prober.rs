@@ -0,0 +1,228 @@
+//! Synthetic probes: cheap, known-good calls made against supplier
+//! endpoints on a timer, so degradation shows up before user traffic notices.
+//! Results are kept apart from the proxied-request metrics.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use axum::extract::State;
+use axum::Json;
+use hyper::Method;
+use serde::{Deserialize, Serialize};
+use tokio::task::JoinHandle;
+use tracing::warn;
+
+use crate::app_state::{AppState, EnvVarConfig};
+use crate::request_log::now_ms;
+
+/// One synthetic call, sent straight to the upstream for `env`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct ProbeConfig {
+    pub name: String,
+    pub env: String,
+    /// Path and query on the upstream, e.g. `/api/search?city=goa`.
+    pub path: String,
+    #[serde(default = "default_method")]
+    pub method: String,
+    /// Sent as-is; probes don't go through the interceptors.
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    #[serde(default)]
+    pub body: Option<String>,
+    #[serde(default = "default_interval_secs")]
+    pub interval_secs: u64,
+    #[serde(default = "default_timeout_secs")]
+    pub timeout_secs: u64,
+    /// Required status; any 2xx when unset.
+    #[serde(default)]
+    pub expect_status: Option<u16>,
+    /// JSON pointers (e.g. `/data/hotels`) the response body must contain.
+    #[serde(default)]
+    pub expect_fields: Vec<String>,
+}
+
+fn default_method() -> String {
+    "GET".to_string()
+}
+
+fn default_interval_secs() -> u64 {
+    60
+}
+
+fn default_timeout_secs() -> u64 {
+    10
+}
+
+/// Parses `PROBES`, a JSON array of [`ProbeConfig`]s.
+pub fn parse_probes(value: &str) -> Result<Vec<ProbeConfig>, String> {
+    if value.trim().is_empty() {
+        return Ok(Vec::new());
+    }
+    serde_json::from_str(value).map_err(|e| format!("invalid probes: {e}"))
+}
+
+/// Outcome of a probe's runs so far.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct ProbeStatus {
+    pub name: String,
+    pub env: String,
+    pub successes: u64,
+    pub failures: u64,
+    pub last_success: Option<bool>,
+    pub last_latency_ms: Option<u64>,
+    pub last_error: Option<String>,
+    pub last_run_ms: Option<i64>,
+}
+
+pub struct Prober {
+    probes: Vec<(ProbeConfig, Mutex<ProbeStatus>)>,
+}
+
+impl Prober {
+    pub fn new(probes: Vec<ProbeConfig>) -> Self {
+        let probes = probes
+            .into_iter()
+            .map(|probe| {
+                let status = ProbeStatus {
+                    name: probe.name.clone(),
+                    env: probe.env.clone(),
+                    ..Default::default()
+                };
+                (probe, Mutex::new(status))
+            })
+            .collect();
+        Self { probes }
+    }
+
+    /// Runs every probe once, e.g. at startup or from tests.
+    pub async fn run_all(&self, client: &reqwest::Client, config: &EnvVarConfig) {
+        for index in 0..self.probes.len() {
+            self.run(index, client, config).await;
+        }
+    }
+
+    async fn run(&self, index: usize, client: &reqwest::Client, config: &EnvVarConfig) {
+        let (probe, status) = &self.probes[index];
+        let started = Instant::now();
+        let outcome = execute(probe, client, config).await;
+        let latency = started.elapsed();
+
+        let mut status = status.lock().unwrap();
+        status.last_run_ms = Some(now_ms());
+        status.last_latency_ms = Some(latency.as_millis() as u64);
+        status.last_success = Some(outcome.is_ok());
+        match outcome {
+            Ok(()) => {
+                status.successes += 1;
+                status.last_error = None;
+            }
+            Err(e) => {
+                warn!("Probe {} failed: {}", probe.name, e);
+                status.failures += 1;
+                status.last_error = Some(e);
+            }
+        }
+    }
+
+    pub fn statuses(&self) -> Vec<ProbeStatus> {
+        self.probes
+            .iter()
+            .map(|(_, status)| status.lock().unwrap().clone())
+            .collect()
+    }
+
+    /// Probe results in the Prometheus text format, appended to `/metrics`.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        for status in self.statuses() {
+            let name = &status.name;
+            let _ = writeln!(
+                out,
+                "proxy_probe_runs_total{{probe=\"{name}\",result=\"success\"}} {}",
+                status.successes
+            );
+            let _ = writeln!(
+                out,
+                "proxy_probe_runs_total{{probe=\"{name}\",result=\"failure\"}} {}",
+                status.failures
+            );
+            if let (Some(success), Some(latency)) = (status.last_success, status.last_latency_ms) {
+                let _ = writeln!(out, "proxy_probe_up{{probe=\"{name}\"}} {}", success as u8);
+                let _ = writeln!(out, "proxy_probe_latency_ms{{probe=\"{name}\"}} {latency}");
+            }
+        }
+        out
+    }
+}
+
+async fn execute(
+    probe: &ProbeConfig,
+    client: &reqwest::Client,
+    config: &EnvVarConfig,
+) -> Result<(), String> {
+    let base = config
+        .upstream_for(&probe.env)
+        .ok_or_else(|| format!("no upstream for env {}", probe.env))?;
+    let method = Method::from_bytes(probe.method.as_bytes())
+        .map_err(|_| format!("invalid method {}", probe.method))?;
+    let mut request = client
+        .request(method, format!("{}{}", base, probe.path))
+        .timeout(Duration::from_secs(probe.timeout_secs));
+    for (name, value) in &probe.headers {
+        request = request.header(name, value);
+    }
+    if let Some(body) = &probe.body {
+        request = request.body(body.clone());
+    }
+
+    let response = request.send().await.map_err(|e| e.to_string())?;
+    let status = response.status();
+    let status_ok = match probe.expect_status {
+        Some(expected) => status.as_u16() == expected,
+        None => status.is_success(),
+    };
+    if !status_ok {
+        return Err(format!("unexpected status {}", status.as_u16()));
+    }
+    let body = response.bytes().await.map_err(|e| e.to_string())?;
+    if probe.expect_fields.is_empty() {
+        return Ok(());
+    }
+    let json: serde_json::Value =
+        serde_json::from_slice(&body).map_err(|e| format!("response is not JSON: {e}"))?;
+    match probe
+        .expect_fields
+        .iter()
+        .find(|pointer| json.pointer(pointer).is_none())
+    {
+        Some(missing) => Err(format!("response is missing {missing}")),
+        None => Ok(()),
+    }
+}
+
+/// Starts one task per configured probe, each on its own interval.
+pub fn spawn_probers(state: &AppState) -> Vec<JoinHandle<()>> {
+    (0..state.prober.probes.len())
+        .map(|index| {
+            let state = state.clone();
+            tokio::spawn(async move {
+                let interval = state.prober.probes[index].0.interval_secs.max(1);
+                let mut ticker = tokio::time::interval(Duration::from_secs(interval));
+                loop {
+                    ticker.tick().await;
+                    state
+                        .prober
+                        .run(index, &state.client, &state.env_var_config)
+                        .await;
+                }
+            })
+        })
+        .collect()
+}
+
+/// `GET /admin/probes`: the latest result of each probe.
+pub async fn probes_handler(State(state): State<AppState>) -> Json<Vec<ProbeStatus>> {
+    Json(state.prober.statuses())
+}
@@ -0,0 +1,476 @@
+//! Layered configuration. Every setting is still named by its environment
+//! variable, but can also come from a TOML file named by `PROXY_CONFIG_FILE`:
+//!
+//! ```toml
+//! [base]
+//! RESPONSE_CACHE_RULES = "/prod/api/static=300"
+//! FORWARD_PROXY_CREDENTIALS = ["ops:${OPS_PASSWORD}"]
+//!
+//! [base.listeners]
+//! addr = "[::]:8080"
+//!
+//! [profiles.staging]
+//! PROD_UPSTREAM_URL = "https://staging.services.travelomatix.com"
+//!
+//! [profiles.prod]
+//! inherits = "staging"
+//! PROD_UPSTREAM_URL = "https://prod.services.travelomatix.com"
+//! ```
+//!
+//! `PROXY_PROFILE` picks a profile, which applies over its `inherits` chain
+//! and `[base]`. The process environment wins over the file, and the file
+//! over built-in defaults. File values may use `${VAR}` or
+//! `${VAR:-default}` to read the environment (`$$` is a literal `$`);
+//! numbers and booleans are taken as written and lists are joined with
+//! commas. Tables named after a [`crate::config`] section hold its typed
+//! settings. `egress-proxy config` prints the effective settings and where
+//! each came from, with secrets redacted.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::env::VarError;
+use std::fmt;
+use std::sync::{Mutex, OnceLock};
+
+use crate::config::SECTIONS;
+
+pub const CONFIG_FILE_VAR: &str = "PROXY_CONFIG_FILE";
+pub const PROFILE_VAR: &str = "PROXY_PROFILE";
+
+/// Setting names containing any of these hold secrets.
+const SECRET_MARKERS: [&str; 6] = [
+    "SECRET",
+    "TOKEN",
+    "PASSWORD",
+    "CREDENTIALS",
+    "API_KEYS",
+    "PRIVATE_KEY",
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Source {
+    Env,
+    Profile(String),
+    Base,
+    Default,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::Env => f.write_str("env"),
+            Source::Profile(name) => write!(f, "profile {name}"),
+            Source::Base => f.write_str("base"),
+            Source::Default => f.write_str("default"),
+        }
+    }
+}
+
+/// One table of the config file: `[base]` or a profile.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Layer {
+    pub source: Source,
+    /// Typed sections such as `listeners`; see [`crate::config`].
+    pub sections: toml::Table,
+    /// Settings named like environment variables.
+    pub settings: BTreeMap<String, String>,
+}
+
+/// The tables a config file applies for one profile, interpolated, from
+/// `[base]` to the profile itself.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Layers {
+    layers: Vec<Layer>,
+}
+
+impl Layers {
+    /// Reads the file and profile the process environment names.
+    pub fn load() -> Result<Self, String> {
+        let profile = std::env::var(PROFILE_VAR).ok();
+        let Ok(path) = std::env::var(CONFIG_FILE_VAR) else {
+            return match profile {
+                Some(profile) => Err(format!(
+                    "{PROFILE_VAR}={profile} needs {CONFIG_FILE_VAR} to be set"
+                )),
+                None => Ok(Self::default()),
+            };
+        };
+        let text = std::fs::read_to_string(&path).map_err(|e| format!("can't read {path}: {e}"))?;
+        Self::parse(&text, profile.as_deref(), |name| std::env::var(name).ok())
+            .map_err(|e| format!("{path}: {e}"))
+    }
+
+    /// Parses a config file, applying `profile` when given. `env` answers
+    /// `${VAR}` references.
+    pub fn parse(
+        text: &str,
+        profile: Option<&str>,
+        env: impl Fn(&str) -> Option<String>,
+    ) -> Result<Self, String> {
+        let mut file: toml::Table = text.parse().map_err(|e| format!("{e}"))?;
+        let base = take_table(&mut file, "base")?;
+        let mut profiles = take_table(&mut file, "profiles")?;
+        if let Some(key) = file.keys().next() {
+            return Err(format!(
+                "unexpected top-level key {key}; settings go under [base] or [profiles.NAME]"
+            ));
+        }
+
+        let mut tables = vec![(Source::Base, base)];
+        let mut chain = Vec::new();
+        let mut next = profile.map(str::to_string);
+        while let Some(name) = next {
+            if chain.contains(&name) {
+                chain.push(name);
+                return Err(format!(
+                    "profiles inherit in a cycle: {}",
+                    chain.join(" -> ")
+                ));
+            }
+            let mut table = match profiles.remove(&name) {
+                Some(toml::Value::Table(table)) => table,
+                Some(_) => return Err(format!("profiles.{name} must be a table")),
+                None => return Err(format!("no profile named {name}")),
+            };
+            next = match table.remove("inherits") {
+                Some(toml::Value::String(parent)) => Some(parent),
+                Some(_) => return Err(format!("profiles.{name}.inherits must be a string")),
+                None => None,
+            };
+            chain.push(name.clone());
+            tables.insert(1, (Source::Profile(name), table));
+        }
+
+        let mut layers = Vec::with_capacity(tables.len());
+        for (source, table) in tables {
+            let mut layer = Layer {
+                source,
+                sections: toml::Table::new(),
+                settings: BTreeMap::new(),
+            };
+            for (key, mut value) in table {
+                if SECTIONS.contains(&key.as_str()) && value.is_table() {
+                    interpolate_strings(&mut value, &env).map_err(|e| format!("{key}.{e}"))?;
+                    layer.sections.insert(key, value);
+                    continue;
+                }
+                let value = scalar(&value)
+                    .or_else(|| list(&value))
+                    .ok_or_else(|| format!("{key}: expected a string, number, boolean or list"))?;
+                let value = interpolate(&value, &env).map_err(|e| format!("{key}: {e}"))?;
+                layer.settings.insert(key, value);
+            }
+            layers.push(layer);
+        }
+        Ok(Self { layers })
+    }
+
+    /// From `[base]` to the selected profile.
+    pub fn layers(&self) -> &[Layer] {
+        &self.layers
+    }
+
+    /// The setting named `key`, from the last table that gives it.
+    pub fn get(&self, key: &str) -> Option<(String, Source)> {
+        self.layers.iter().rev().find_map(|layer| {
+            let value = layer.settings.get(key)?;
+            Some((value.clone(), layer.source.clone()))
+        })
+    }
+}
+
+fn take_table(file: &mut toml::Table, key: &str) -> Result<toml::Table, String> {
+    match file.remove(key) {
+        Some(toml::Value::Table(table)) => Ok(table),
+        Some(_) => Err(format!("{key} must be a table")),
+        None => Ok(toml::Table::new()),
+    }
+}
+
+fn scalar(value: &toml::Value) -> Option<String> {
+    match value {
+        toml::Value::String(s) => Some(s.clone()),
+        toml::Value::Integer(n) => Some(n.to_string()),
+        toml::Value::Float(n) => Some(n.to_string()),
+        toml::Value::Boolean(b) => Some(b.to_string()),
+        _ => None,
+    }
+}
+
+fn list(value: &toml::Value) -> Option<String> {
+    let items = value.as_array()?;
+    let items = items.iter().map(scalar).collect::<Option<Vec<_>>>()?;
+    Some(items.join(","))
+}
+
+/// [`interpolate`]s every string in a typed section.
+fn interpolate_strings(
+    value: &mut toml::Value,
+    env: &impl Fn(&str) -> Option<String>,
+) -> Result<(), String> {
+    match value {
+        toml::Value::String(s) => *s = interpolate(s, env)?,
+        toml::Value::Array(items) => {
+            for item in items {
+                interpolate_strings(item, env)?;
+            }
+        }
+        toml::Value::Table(table) => {
+            for (key, value) in table {
+                interpolate_strings(value, env).map_err(|e| format!("{key}: {e}"))?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Expands `${VAR}`, `${VAR:-default}` and `$$`.
+fn interpolate(value: &str, env: &impl Fn(&str) -> Option<String>) -> Result<String, String> {
+    let mut out = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(at) = rest.find('$') {
+        out.push_str(&rest[..at]);
+        rest = &rest[at + 1..];
+        if let Some(after) = rest.strip_prefix('$') {
+            out.push('$');
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix('{') {
+            let end = after
+                .find('}')
+                .ok_or_else(|| format!("unterminated ${{ in {value}"))?;
+            let (name, default) = match after[..end].split_once(":-") {
+                Some((name, default)) => (name, Some(default)),
+                None => (&after[..end], None),
+            };
+            match env(name).or_else(|| default.map(str::to_string)) {
+                Some(found) => out.push_str(&found),
+                None => return Err(format!("${{{name}}} is not set")),
+            }
+            rest = &after[end + 1..];
+        } else {
+            out.push('$');
+        }
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+struct Settings {
+    layers: Layers,
+    /// Every setting asked for so far, to spot ones in the file nothing
+    /// reads.
+    read: Mutex<BTreeSet<String>>,
+    /// Every setting found so far, for [`effective`].
+    resolved: Mutex<BTreeMap<String, (String, Source)>>,
+}
+
+fn settings() -> &'static Settings {
+    static SETTINGS: OnceLock<Settings> = OnceLock::new();
+    SETTINGS.get_or_init(|| Settings {
+        layers: Layers::load().unwrap_or_else(|e| panic!("invalid config file: {e}")),
+        read: Mutex::default(),
+        resolved: Mutex::default(),
+    })
+}
+
+/// The config file's tables for the selected profile.
+pub fn file_layers() -> &'static Layers {
+    &settings().layers
+}
+
+/// A setting from the environment, or else the config file.
+pub fn lookup(key: &str) -> Result<Option<String>, VarError> {
+    let settings = settings();
+    settings.read.lock().unwrap().insert(key.to_string());
+    let found = match std::env::var(key) {
+        Ok(value) => Some((value, Source::Env)),
+        Err(VarError::NotPresent) => settings.layers.get(key),
+        Err(e) => return Err(e),
+    };
+    if let Some((value, source)) = &found {
+        record(key, value, source.clone());
+    }
+    Ok(found.map(|(value, _)| value))
+}
+
+/// Notes where `key`'s value came from, for [`effective`]. Empty defaults
+/// are left out.
+pub fn record(key: &str, value: &str, source: Source) {
+    let settings = settings();
+    settings.read.lock().unwrap().insert(key.to_string());
+    if value.is_empty() && source == Source::Default {
+        return;
+    }
+    settings
+        .resolved
+        .lock()
+        .unwrap()
+        .insert(key.to_string(), (value.to_string(), source));
+}
+
+/// Notes that `key` fell back to its built-in default.
+pub fn record_default(key: &str, value: &str) {
+    record(key, value, Source::Default);
+}
+
+/// Settings the config file gives that nothing has read: misspelt, or for
+/// a feature that's off. Each comes with the table it's in and the closest
+/// setting that was read.
+pub fn unread() -> Vec<(String, Source, Option<String>)> {
+    let settings = settings();
+    let read = settings.read.lock().unwrap();
+    settings
+        .layers
+        .layers()
+        .iter()
+        .flat_map(|layer| {
+            layer
+                .settings
+                .keys()
+                .filter(|key| !read.contains(*key))
+                .map(|key| (key.clone(), layer.source.clone(), closest(key, &read)))
+        })
+        .collect()
+}
+
+/// The candidate at most a few edits from `key`.
+fn closest(key: &str, candidates: &BTreeSet<String>) -> Option<String> {
+    candidates
+        .iter()
+        .map(|candidate| (edit_distance(key, candidate), candidate))
+        .filter(|(distance, _)| *distance <= 3)
+        .min()
+        .map(|(_, candidate)| candidate.clone())
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let next = (row[j + 1] + 1)
+                .min(row[j] + 1)
+                .min(diagonal + usize::from(ca != *cb));
+            diagonal = row[j + 1];
+            row[j + 1] = next;
+        }
+    }
+    row[b.len()]
+}
+
+/// The settings read so far as `KEY=value  # source` lines, secrets
+/// redacted. Read the config first, e.g. with
+/// [`crate::app_state::EnvVarConfig::try_from_env`].
+pub fn effective() -> String {
+    let resolved = settings().resolved.lock().unwrap();
+    resolved
+        .iter()
+        .map(|(key, (value, source))| format!("{key}={}  # {source}\n", redact(key, value)))
+        .collect()
+}
+
+fn redact(key: &str, value: &str) -> String {
+    if SECRET_MARKERS.iter().any(|marker| key.contains(marker)) {
+        return "<redacted>".to_string();
+    }
+    // Passwords in URLs, e.g. redis://:password@host.
+    let mut out = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(at) = rest.find("://") {
+        let (head, tail) = rest.split_at(at + 3);
+        out.push_str(head);
+        let authority = tail.find(['/', ',', ' ']).unwrap_or(tail.len());
+        match tail[..authority].rfind('@') {
+            Some(userinfo) => {
+                out.push_str("<redacted>");
+                rest = &tail[userinfo..];
+            }
+            None => rest = tail,
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FILE: &str = r#"
+        [base]
+        TEST_UPSTREAM_URL = "http://test.local"
+        RESPONSE_CACHE_RULES = "/prod/static=300"
+        TCP_BACKLOG = 512
+
+        [profiles.staging]
+        PROD_UPSTREAM_URL = "https://staging.local"
+        FORWARD_PROXY_CREDENTIALS = ["ops:${OPS_PASSWORD}", "ci:${CI_PASSWORD:-ci}"]
+
+        [profiles.prod]
+        inherits = "staging"
+        PROD_UPSTREAM_URL = "https://prod.local"
+        METRICS_SNAPSHOT_PATH = "/var/lib/$${HOME}"
+    "#;
+
+    fn env(name: &str) -> Option<String> {
+        (name == "OPS_PASSWORD").then(|| "hunter2".to_string())
+    }
+
+    fn value(layers: &Layers, key: &str) -> Option<(String, Source)> {
+        layers.get(key)
+    }
+
+    #[test]
+    fn applies_profiles_over_their_parents_and_the_base() {
+        let prod = Layers::parse(FILE, Some("prod"), env).unwrap();
+        assert_eq!(
+            value(&prod, "PROD_UPSTREAM_URL"),
+            Some(("https://prod.local".into(), Source::Profile("prod".into())))
+        );
+        assert_eq!(
+            value(&prod, "FORWARD_PROXY_CREDENTIALS"),
+            Some((
+                "ops:hunter2,ci:ci".into(),
+                Source::Profile("staging".into())
+            ))
+        );
+        assert_eq!(
+            value(&prod, "TCP_BACKLOG"),
+            Some(("512".into(), Source::Base))
+        );
+        assert_eq!(
+            value(&prod, "METRICS_SNAPSHOT_PATH").unwrap().0,
+            "/var/lib/${HOME}"
+        );
+        assert_eq!(value(&prod, "inherits"), None);
+
+        let base = Layers::parse(FILE, None, env).unwrap();
+        assert_eq!(value(&base, "PROD_UPSTREAM_URL"), None);
+    }
+
+    #[test]
+    fn rejects_bad_files() {
+        let err = |text: &str, profile| Layers::parse(text, profile, env).unwrap_err();
+        assert!(err(FILE, Some("dev")).contains("no profile named dev"));
+        assert!(err("[base]\nA = \"${MISSING}\"", None).contains("${MISSING} is not set"));
+        assert!(err("A = 1", None).contains("unexpected top-level key A"));
+        assert!(err("[base.A]\nB = 1", None).contains("A: expected"));
+        let cycle = "[profiles.a]\ninherits = \"b\"\n[profiles.b]\ninherits = \"a\"";
+        assert!(err(cycle, Some("a")).contains("a -> b -> a"));
+    }
+
+    #[test]
+    fn redacts_secrets() {
+        assert_eq!(redact("PROXY_ADMIN_TOKEN", "abc"), "<redacted>");
+        assert_eq!(
+            redact("REDIS_URL", "redis://:pw@cache:6379/0"),
+            "redis://<redacted>@cache:6379/0"
+        );
+        assert_eq!(
+            redact("TEST_UPSTREAM_URL", "http://test.local/a@b"),
+            "http://test.local/a@b"
+        );
+    }
+}
@@ -0,0 +1,1305 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use axum::body::{to_bytes, Bytes};
+use axum::extract::Path;
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    http::uri::Uri,
+    response::Response,
+};
+use http_body_util::BodyExt;
+use hyper::{header, HeaderMap, Method, StatusCode};
+use tokio::sync::OwnedSemaphorePermit;
+use tracing::{error, info, info_span, warn, Instrument};
+
+use crate::admin;
+use crate::app_state::AppState;
+use crate::cache::{cache_key, CacheLookup, CacheRule};
+use crate::client_tag::CLIENT_TAG_HEADER;
+use crate::coalesce::Coalescer;
+use crate::conditional::{
+    add_validators, client_has_current_copy, has_conditional_headers, merge_not_modified,
+    not_modified_response,
+};
+use crate::connection_limit::ConnectionLimiter;
+use crate::contract;
+use crate::dns::Refresh;
+use crate::dry_run::{self, DryRun};
+use crate::encoding;
+use crate::error::ProxyError;
+use crate::failover::{self, Target};
+use crate::fair_queue::{FairPermit, FairQueue};
+use crate::fault;
+use crate::fields;
+use crate::fingerprint::{self, FingerprintSlot, FINGERPRINT_HEADER};
+use crate::headers::{
+    body_with_trailers, permits_body, prepare_request, prepare_response, strip_hop_by_hop,
+};
+use crate::interceptor::{OutboundRequest, UpstreamResponse};
+use crate::memory_budget::Reservation;
+use crate::normalize::normalize_path;
+use crate::payload_size::Direction;
+use crate::rate_limit::{client_key, Quota, RateLimitDecision};
+use crate::request_log::{buffer_request, buffer_response, now_ms, RequestLog, RequestLogEntry};
+use crate::target_override;
+use crate::tenant;
+use crate::timings::{self, RequestTimings};
+use crate::transfers::{Download, TransferKind, UploadBody};
+
+pub(crate) const MAX_BODY_SIZE: usize = 8 * 1024 * 1024; // 8 MB
+
+const X_CACHE: header::HeaderName = header::HeaderName::from_static("x-cache");
+
+/// On a 502, 503 or 504: `true` when the request is idempotent or never left
+/// the proxy, so an automatic retry can't act on it twice.
+pub const X_PROXY_RETRY_SAFE: header::HeaderName =
+    header::HeaderName::from_static("x-proxy-retry-safe");
+
+/// `Warning` sent with a response whose upstream body broke off partway.
+const TRUNCATED_WARNING: &str = "199 - \"upstream body truncated\"";
+
+pub async fn handler(
+    State(app_state): State<AppState>,
+    // The path below the env is read from the raw URI instead; see `handle`.
+    Path((env, _)): Path<(String, String)>,
+    mut req: Request,
+) -> Response {
+    if tenant::split_env(&env).is_some() {
+        // Tenant envs are only served under their tenant's prefix.
+        let correlation_id = app_state.correlation.ensure(req.headers_mut());
+        return ProxyError::UnknownEnv.to_response(&correlation_id);
+    }
+    serve(app_state, env, req).await
+}
+
+/// Proxies `req`, whose URI is `/{env}/...`, to `env`'s upstream. Tenant envs
+/// are only served through [`crate::tenant::proxy_handler`], which
+/// authenticates the caller first.
+pub(crate) async fn serve(app_state: AppState, env: String, mut req: Request) -> Response {
+    let correlation_id = app_state.correlation.ensure(req.headers_mut());
+    let span = info_span!("request", correlation_id = %correlation_id);
+    handle(app_state, env, req, correlation_id)
+        .instrument(span)
+        .await
+}
+
+async fn handle(
+    app_state: AppState,
+    env: String,
+    mut req: Request,
+    correlation_id: String,
+) -> Response {
+    // The raw path is used rather than the decoded parameter, so encoded dot
+    // segments are resolved instead of reaching the upstream. Everything
+    // after this sees the normalized path only.
+    let raw_path = req
+        .uri()
+        .path()
+        .trim_start_matches('/')
+        .split_once('/')
+        .map_or("", |(_, rest)| rest);
+    let upstream_path = match normalize_path(raw_path) {
+        Ok(path) => path,
+        Err(e) => {
+            warn!("Rejected path {:?}: {}", req.uri().path(), e);
+            return ProxyError::InvalidPath.to_response(&correlation_id);
+        }
+    };
+    let inbound_path = format!("/{}{}", env, upstream_path);
+
+    let started = Instant::now();
+    let timings = timings::wants_timings(req.headers()).then(Arc::<RequestTimings>::default);
+    req.headers_mut().remove(timings::DEBUG_HEADER);
+    let client_tag = app_state.client_tags.resolve(req.headers());
+    req.headers_mut().remove(CLIENT_TAG_HEADER);
+    // Filled in once the body has been read.
+    let fingerprint = FingerprintSlot::default();
+    req.extensions_mut().insert(fingerprint.clone());
+
+    let (req, logged) = match &app_state.request_log {
+        Some(log) => {
+            let (req, logged) = start_request_log(log, &env, &inbound_path, req).await;
+            (req, Some(logged))
+        }
+        None => (req, None),
+    };
+
+    let origin = req.headers().get(header::ORIGIN).cloned();
+    let method = req.method().clone();
+    let preflight = app_state
+        .cors
+        .preflight(req.method(), req.headers(), &inbound_path);
+    let fault = match preflight {
+        Some(_) => None,
+        None => app_state.faults.decide(&inbound_path),
+    };
+    let mut quota = None;
+    let dispatch = async {
+        if let Some(fault) = &fault {
+            if let Some(response) = fault::inject(fault, &inbound_path).await? {
+                return Ok(response);
+            }
+        }
+        match (preflight, &app_state.rate_limiter) {
+            (Some(preflight), _) => Ok(preflight),
+            (None, Some(limiter)) => {
+                let decision = limiter.check(&client_key(&req)).await;
+                quota = decision.quota().copied();
+                match decision {
+                    RateLimitDecision::Allowed { .. } => {
+                        forward(&app_state, &env, &upstream_path, req).await
+                    }
+                    RateLimitDecision::Limited { quota } => {
+                        warn!("Rate limited request to {}", req.uri().path());
+                        Ok(too_many_requests(&quota))
+                    }
+                }
+            }
+            (None, None) => forward(&app_state, &env, &upstream_path, req).await,
+        }
+    };
+    let result = match &timings {
+        Some(timings) => timings::scope(timings.clone(), dispatch).await,
+        None => dispatch.await,
+    };
+
+    // Feeds the traffic pause: upstream 5xxs and failures to reach it.
+    let upstream_failed = match &result {
+        Ok(response) => Some(response.status().is_server_error()),
+        Err(error) if error.is_upstream_failure() => Some(true),
+        Err(_) => None,
+    };
+    // Anything answered by the upstream was sent to it.
+    let reached_upstream = result
+        .as_ref()
+        .err()
+        .is_none_or(ProxyError::may_have_reached_upstream);
+    // Errors carry the correlation ID in their body, and get the route's
+    // headers too, so browsers can read a 502.
+    let mut response = result.unwrap_or_else(|error| error.to_response(&correlation_id));
+
+    // The supplier's own ID wins on a fresh response; a cached one belongs
+    // to an earlier exchange.
+    let fresh = response
+        .headers()
+        .get(X_CACHE)
+        .is_none_or(|value| value == "MISS");
+    let correlation_id = match app_state.correlation.read(response.headers()) {
+        Some(supplier_id) if fresh => supplier_id,
+        _ => correlation_id,
+    };
+    let status = response.status();
+    let headers = response.headers_mut();
+    app_state.correlation.set(headers, &correlation_id);
+    app_state
+        .cors
+        .apply(origin.as_ref(), &inbound_path, headers);
+    app_state.response_headers.apply(&inbound_path, headers);
+    if let Some(timings) = &timings {
+        timings.apply(headers, started.elapsed());
+    }
+    if let Some(quota) = &quota {
+        quota.apply(headers);
+    }
+    let fingerprint = fingerprint.get().map(str::to_string);
+    if let Some(value) = fingerprint
+        .as_deref()
+        .and_then(|value| header::HeaderValue::from_str(value).ok())
+    {
+        headers.insert(FINGERPRINT_HEADER, value);
+    }
+    if let Some(value) = client_tag
+        .as_deref()
+        .and_then(|tag| header::HeaderValue::from_str(tag).ok())
+    {
+        headers.insert(CLIENT_TAG_HEADER, value);
+    }
+    if matches!(
+        status,
+        StatusCode::BAD_GATEWAY | StatusCode::SERVICE_UNAVAILABLE | StatusCode::GATEWAY_TIMEOUT
+    ) {
+        // Whether resending can't double-submit, e.g. a booking.
+        let safe = method.is_idempotent() || !reached_upstream;
+        headers.insert(
+            X_PROXY_RETRY_SAFE,
+            header::HeaderValue::from_static(if safe { "true" } else { "false" }),
+        );
+    }
+
+    app_state
+        .metrics
+        .record_response(&env, &inbound_path, status, started.elapsed());
+    if let Some(tag) = &client_tag {
+        app_state
+            .metrics
+            .record_client_tag(tag, status, started.elapsed());
+    }
+    app_state.slo.record(&env, status, started.elapsed());
+    if let Some(failed) = upstream_failed {
+        app_state.traffic_pause.record(&env, failed);
+    }
+    app_state.slow_requests.record(
+        &method,
+        &inbound_path,
+        status,
+        started.elapsed(),
+        &correlation_id,
+    );
+    app_state.tail.publish(
+        &env,
+        &method,
+        &inbound_path,
+        status,
+        started.elapsed(),
+        &correlation_id,
+    );
+    info!(
+        "{} {} -> {} (correlation id {}, fingerprint {}, client tag {})",
+        method,
+        inbound_path,
+        status.as_u16(),
+        correlation_id,
+        fingerprint.as_deref().unwrap_or("-"),
+        client_tag.as_deref().unwrap_or("-")
+    );
+
+    let response = match (&app_state.request_log, logged) {
+        (Some(log), Some(mut logged)) => {
+            logged.entry.status = status.as_u16();
+            logged.entry.duration_ms = started.elapsed().as_millis() as i64;
+            logged.entry.correlation_id = Some(correlation_id);
+            logged.entry.fingerprint = fingerprint;
+            logged.entry.client_tag = client_tag;
+            finish_request_log(log, logged, response).await
+        }
+        _ => response,
+    };
+    // Shaped last, so the log and metrics see the response as it was.
+    match fault.and_then(|fault| fault.shaping) {
+        Some(shaping) => fault::shape(response, shaping).await,
+        None => response,
+    }
+}
+
+/// What's known about a request before it is forwarded.
+struct LoggedRequest {
+    entry: RequestLogEntry,
+    request: Option<(HeaderMap, Bytes)>,
+}
+
+async fn start_request_log(
+    log: &RequestLog,
+    env: &str,
+    inbound_path: &str,
+    req: Request,
+) -> (Request, LoggedRequest) {
+    let entry = RequestLogEntry {
+        timestamp_ms: now_ms(),
+        env: env.to_string(),
+        method: req.method().to_string(),
+        path: inbound_path.to_string(),
+        query: req.uri().query().map(str::to_string),
+        status: 0,
+        duration_ms: 0,
+        request_body: None,
+        response_body: None,
+        correlation_id: None,
+        fingerprint: None,
+        client_tag: None,
+    };
+    if !log.captures_bodies() {
+        return (
+            req,
+            LoggedRequest {
+                entry,
+                request: None,
+            },
+        );
+    }
+    let (req, body) = buffer_request(req, MAX_BODY_SIZE).await;
+    let headers = req.headers().clone();
+    (
+        req,
+        LoggedRequest {
+            entry,
+            request: Some((headers, body)),
+        },
+    )
+}
+
+async fn finish_request_log(
+    log: &RequestLog,
+    logged: LoggedRequest,
+    response: Response,
+) -> Response {
+    let request = logged.request.as_ref().map(|(h, b)| (h, b));
+    if !log.captures_bodies() {
+        log.record(logged.entry, request, None);
+        return response;
+    }
+    let (response, body) = buffer_response(response).await;
+    log.record(logged.entry, request, Some((response.headers(), &body)));
+    response
+}
+
+/// Forwards `req` to `new_path`, already normalized, under `env`'s upstream.
+async fn forward(
+    app_state: &AppState,
+    env: &str,
+    new_path: &str,
+    mut req: Request,
+) -> Result<Response, ProxyError> {
+    let fields = fields::take(&app_state.env_var_config.field_filter_paths, &mut req);
+    let mask = app_state.masking.take(&mut req);
+    let mut response = forward_unfiltered(app_state, env, new_path, req).await?;
+    if let Some(mask) = mask {
+        response = mask.apply(response).await;
+    }
+    if let Some(fields) = fields {
+        response = fields::apply(&fields, response).await;
+    }
+    Ok(response)
+}
+
+async fn forward_unfiltered(
+    app_state: &AppState,
+    env: &str,
+    new_path: &str,
+    req: Request,
+) -> Result<Response, ProxyError> {
+    // Determine the target_base URL based on the environment
+    let target_base = match app_state.env_var_config.upstream_for(env) {
+        Some(base) => base,
+        None => {
+            error!("Invalid environment: {}", env);
+            return Err(ProxyError::UnknownEnv);
+        }
+    };
+    app_state.traffic_pause.check(env)?;
+
+    let inbound_path = format!("/{}{}", env, new_path);
+
+    let query = req
+        .uri()
+        .query()
+        .map(|q| format!("?{}", q))
+        .unwrap_or_default();
+    let uri = format!("{}{}{}", target_base, new_path, query);
+
+    // Parse target URI to extract host
+    let target_uri = uri.parse::<Uri>().map_err(|e| {
+        error!("Failed to parse target URI: {}", e);
+        ProxyError::InvalidTarget
+    })?;
+
+    let cache_rule = app_state
+        .cache
+        .rule_for(req.method(), &inbound_path)
+        .cloned();
+    let cache_key = cache_key(
+        &inbound_path,
+        req.uri().query(),
+        req.headers(),
+        &app_state.env_var_config.credential_headers,
+    );
+    let client = client_key(&req);
+
+    let (parts, body) = req.into_parts();
+
+    // Held until the proxied call ends. A declared length is reserved before
+    // reading, so an oversized body is shed without being buffered.
+    let mut request_bytes = app_state
+        .memory_budget
+        .as_ref()
+        .map(|budget| budget.reservation());
+    let declared = parts
+        .headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok()?.parse::<usize>().ok())
+        .unwrap_or(0);
+    reserve(&mut request_bytes, declared.min(MAX_BODY_SIZE))?;
+
+    // Forward body if present
+    let body = to_bytes(body, MAX_BODY_SIZE).await.map_err(|e| {
+        let error = ProxyError::from_request_body(&e);
+        warn!("Failed to read request body ({}): {}", error.code(), e);
+        error
+    })?;
+    reserve(&mut request_bytes, body.len())?;
+    if let Some(slot) = parts.extensions.get::<FingerprintSlot>() {
+        slot.set(fingerprint::compute(
+            &parts.method,
+            &inbound_path,
+            parts.uri.query(),
+            &body,
+        ));
+    }
+
+    let mut outbound = OutboundRequest {
+        env: env.to_string(),
+        method: parts.method,
+        uri: target_uri,
+        headers: parts.headers,
+        body,
+    };
+    let verbose = app_state.debug_log.select(&mut outbound.headers);
+    prepare_request(&mut outbound.headers).map_err(|_| ProxyError::ExpectationFailed)?;
+    encoding::prepare_accept_encoding(
+        &mut outbound.headers,
+        app_state.env_var_config.upstream_decompression,
+    );
+    let principal = admin::take_admin_token(app_state, &mut outbound.headers);
+    let forced = target_override::take(
+        app_state,
+        &outbound.env,
+        &inbound_path,
+        &mut outbound.headers,
+        principal.as_ref(),
+    )?;
+    if dry_run::take(&mut outbound.headers, &inbound_path, principal.as_ref())? {
+        return dry_run(app_state, outbound, forced.as_deref()).await;
+    }
+    if let Some(store_forward) = &app_state.store_forward {
+        if forced.is_none() && store_forward.applies(&outbound.method, &inbound_path) {
+            return store_forward
+                .accept(app_state, &outbound, format!("{}{}", new_path, query))
+                .await;
+        }
+    }
+    // Writes stored for delivery were taken above; their delivery waits
+    // for the window to close.
+    app_state.maintenance.check(&outbound.env)?;
+    // A forced target is for looking at one node, so the cache stays out of it.
+    let cache_rule = cache_rule.filter(|_| forced.is_none());
+
+    // Cached GETs are answered before the request interceptors run; what's
+    // stored already went through the response interceptors.
+    let mut expired = None;
+    if let Some(rule) = &cache_rule {
+        match app_state.cache.lookup(&cache_key).await {
+            CacheLookup::Fresh(cached) => {
+                app_state.metrics.record_cache_lookup(true);
+                if client_has_current_copy(&outbound.headers, &cached) {
+                    return Ok(not_modified_response(&cached));
+                }
+                return build_response(&outbound.method, cached, "HIT", true);
+            }
+            CacheLookup::Revalidate(cached) => {
+                app_state.metrics.record_cache_lookup(true);
+                let response = if client_has_current_copy(&outbound.headers, &cached) {
+                    Ok(not_modified_response(&cached))
+                } else {
+                    build_response(&outbound.method, cached.clone(), "STALE", true)
+                };
+                spawn_revalidation(
+                    app_state,
+                    outbound,
+                    cached,
+                    cache_key,
+                    inbound_path,
+                    rule.clone(),
+                );
+                return response;
+            }
+            CacheLookup::Expired {
+                response,
+                serve_on_error,
+            } => {
+                app_state.metrics.record_cache_lookup(false);
+                expired = Some((response, serve_on_error));
+            }
+            CacheLookup::Miss => app_state.metrics.record_cache_lookup(false),
+        }
+    }
+
+    // Revalidate an expired entry with its validators, unless the caller sent
+    // its own; then the upstream's 304 is theirs to handle.
+    let revalidating = match &expired {
+        Some((cached, _)) if !has_conditional_headers(&outbound.headers) => {
+            add_validators(&mut outbound.headers, cached)
+        }
+        _ => false,
+    };
+
+    // Bursts of identical requests share one call; see `crate::coalesce`.
+    let coalesce = app_state
+        .coalescer
+        .window_for(&outbound.method, &inbound_path)
+        .filter(|_| forced.is_none() && !has_conditional_headers(&outbound.headers));
+    let (result, coalesced) = match coalesce {
+        Some(window) => {
+            let fingerprint = fingerprint::compute(
+                &outbound.method,
+                &inbound_path,
+                outbound.uri.query(),
+                &outbound.body,
+            );
+            let key = Coalescer::key(
+                &fingerprint,
+                &outbound.headers,
+                &app_state.env_var_config.credential_headers,
+            );
+            app_state
+                .coalescer
+                .run(key, window, || {
+                    let (app_state, mut outbound) = (app_state.clone(), outbound.clone());
+                    let (inbound_path, client) = (inbound_path.clone(), client.clone());
+                    async move {
+                        fetch_upstream(
+                            &app_state,
+                            &mut outbound,
+                            &inbound_path,
+                            &client,
+                            verbose,
+                            None,
+                        )
+                        .await
+                    }
+                })
+                .await
+        }
+        None => {
+            let result = fetch_upstream(
+                app_state,
+                &mut outbound,
+                &inbound_path,
+                &client,
+                verbose,
+                forced.as_deref(),
+            )
+            .await;
+            (result, false)
+        }
+    };
+
+    if let (Some((cached, _)), Some(rule), true) = (&expired, &cache_rule, revalidating) {
+        if let Ok(upstream) = &result {
+            if upstream.status == StatusCode::NOT_MODIFIED {
+                let refreshed = merge_not_modified(cached, &upstream.headers);
+                app_state
+                    .cache
+                    .put(cache_key, &inbound_path, &refreshed, rule)
+                    .await;
+                return build_response(&outbound.method, refreshed, "REVALIDATED", true);
+            }
+        }
+    }
+
+    if let Some((cached, true)) = expired {
+        if result
+            .as_ref()
+            .map_or(true, |upstream| upstream.status.is_server_error())
+        {
+            warn!(
+                "Serving stale response for {} after upstream failure",
+                inbound_path
+            );
+            return build_response(&outbound.method, cached, "STALE", true);
+        }
+    }
+    let upstream = result?;
+    if coalesced {
+        // Only the leading request stores the answer in the cache.
+        app_state.metrics.record_coalesced();
+        return build_response(&outbound.method, upstream, "COALESCED", true);
+    }
+
+    if let Some(rule) = &cache_rule {
+        if !is_truncated(&upstream) {
+            app_state
+                .cache
+                .put(cache_key, &inbound_path, &upstream, rule)
+                .await;
+        }
+    } else if !outbound.method.is_safe() && upstream.status.is_success() {
+        let removed = app_state.cache.invalidate_after_write(&inbound_path).await;
+        if removed > 0 {
+            info!("Invalidated {} cached responses after write", removed);
+            app_state.metrics.record_cache_invalidations(removed as u64);
+        }
+    }
+
+    build_response(&outbound.method, upstream, "MISS", cache_rule.is_some())
+}
+
+/// Grows `reservation`, if there's a budget, to at least `bytes`.
+fn reserve(reservation: &mut Option<Reservation>, bytes: usize) -> Result<(), ProxyError> {
+    let Some(reservation) = reservation else {
+        return Ok(());
+    };
+    let more = bytes.saturating_sub(reservation.bytes());
+    if more > 0 && !reservation.try_grow(more) {
+        warn!(
+            "Shedding a {} byte request body over the memory budget",
+            bytes
+        );
+        return Err(ProxyError::MemoryBudget);
+    }
+    Ok(())
+}
+
+/// The `RateLimit-*` headers are added with the other response headers.
+fn too_many_requests(quota: &Quota) -> Response {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = StatusCode::TOO_MANY_REQUESTS;
+    let (name, value) = quota.retry_after();
+    response.headers_mut().insert(name, value);
+    response
+}
+
+/// Sends a request to `env`'s upstream through the interceptors, outside of
+/// any inbound request (environment comparisons and other tooling).
+pub async fn send_to_upstream(
+    app_state: &AppState,
+    env: &str,
+    method: Method,
+    path_and_query: &str,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<UpstreamResponse, ProxyError> {
+    let target_base = app_state
+        .env_var_config
+        .upstream_for(env)
+        .ok_or(ProxyError::UnknownEnv)?;
+    let uri = format!("{}{}", target_base, path_and_query)
+        .parse::<Uri>()
+        .map_err(|_| ProxyError::InvalidTarget)?;
+    let path = path_and_query.split('?').next().unwrap_or_default();
+    let route = format!("/{}{}", env, path);
+    let mut outbound = OutboundRequest {
+        env: env.to_string(),
+        method,
+        uri,
+        headers,
+        body,
+    };
+    prepare_request(&mut outbound.headers).map_err(|_| ProxyError::ExpectationFailed)?;
+    encoding::prepare_accept_encoding(
+        &mut outbound.headers,
+        app_state.env_var_config.upstream_decompression,
+    );
+    fetch_upstream(app_state, &mut outbound, &route, "tooling", false, None).await
+}
+
+/// Runs the interceptor chains around one upstream call. `route` is the
+/// inbound path, which keys latency history and hedging; `client` is who
+/// queues for an upstream slot.
+async fn fetch_upstream(
+    app_state: &AppState,
+    outbound: &mut OutboundRequest,
+    route: &str,
+    client: &str,
+    verbose: bool,
+    forced: Option<&str>,
+) -> Result<UpstreamResponse, ProxyError> {
+    let env = outbound.env.clone();
+    let failover = &app_state.failover;
+    let Placement::Failover { primary, secondary } = place(app_state, outbound, forced) else {
+        return fetch_once(app_state, outbound, route, client, verbose).await;
+    };
+    // Interceptors run again for a failover attempt, on the request as it
+    // was before they first ran.
+    let original = outbound.clone();
+    let mut target = failover.target(&env);
+    if target == Target::Secondary {
+        retarget(outbound, primary, secondary);
+    }
+    let mut result = fetch_once(app_state, outbound, route, client, verbose).await;
+    if let Some(healthy) = failover::healthy(&result) {
+        failover.record(&env, target, healthy);
+    }
+    if target == Target::Primary && failover::retry_on_secondary(&original.method, &result) {
+        warn!(
+            "Primary upstream failed for {}; retrying on the secondary",
+            route
+        );
+        *outbound = original;
+        retarget(outbound, primary, secondary);
+        target = Target::Secondary;
+        result = fetch_once(app_state, outbound, route, client, verbose).await;
+    }
+    info!("{} served by the {} upstream", route, target.as_str());
+    app_state.metrics.record_upstream_target(&env, target);
+    let mut upstream = result?;
+    upstream.headers.insert(
+        failover::X_UPSTREAM_TARGET,
+        header::HeaderValue::from_static(target.as_str()),
+    );
+    Ok(upstream)
+}
+
+/// Where a request goes: straight to one base URL, or to a primary with a
+/// secondary to fail over to.
+enum Placement<'a> {
+    Single,
+    Failover {
+        primary: &'a str,
+        secondary: &'a str,
+    },
+}
+
+/// Points `outbound` at its forced target or balanced node.
+fn place<'a>(
+    app_state: &'a AppState,
+    outbound: &mut OutboundRequest,
+    forced: Option<&str>,
+) -> Placement<'a> {
+    let env = &outbound.env;
+    let configured = app_state.env_var_config.upstream_for(env);
+    if let (Some(configured), Some(forced)) = (configured, forced) {
+        retarget(outbound, configured, forced);
+        return Placement::Single;
+    }
+    // The node is chosen before the interceptors run, so they see its URI.
+    let primary = match (configured, app_state.balancer.pick(env, &outbound.headers)) {
+        (Some(configured), Some(node)) => {
+            retarget(outbound, configured, node);
+            Some(node)
+        }
+        (configured, _) => configured,
+    };
+    match (primary, app_state.failover.secondary_for(&outbound.env)) {
+        (Some(primary), Some(secondary)) => Placement::Failover { primary, secondary },
+        _ => Placement::Single,
+    }
+}
+
+/// Answers a dry run with `outbound` as it would have been sent: placed,
+/// through the request interceptors, and checked against the egress policy.
+async fn dry_run(
+    app_state: &AppState,
+    mut outbound: OutboundRequest,
+    forced: Option<&str>,
+) -> Result<Response, ProxyError> {
+    if let Placement::Failover { primary, secondary } = place(app_state, &mut outbound, forced) {
+        if app_state.failover.target(&outbound.env) == Target::Secondary {
+            retarget(&mut outbound, primary, secondary);
+        }
+    }
+    app_state.interceptors.run_request(&mut outbound).await?;
+    let permitted = app_state.egress_policy.permits(&outbound.uri);
+    info!("Dry run of {} {}", outbound.method, outbound.uri);
+    Ok(DryRun::new(&outbound, permitted).into_response())
+}
+
+/// Points `outbound` at the `to` base URL instead of `from`.
+fn retarget(outbound: &mut OutboundRequest, from: &str, to: &str) {
+    if let Some(uri) = failover::rebase(&outbound.uri, from, to) {
+        outbound.uri = uri;
+    }
+}
+
+/// One attempt at `outbound`'s upstream, through the interceptors.
+async fn fetch_once(
+    app_state: &AppState,
+    outbound: &mut OutboundRequest,
+    route: &str,
+    client: &str,
+    verbose: bool,
+) -> Result<UpstreamResponse, ProxyError> {
+    // Header rewriting, signing and logging all happen in the interceptor chain.
+    let interceptors = &app_state.interceptors;
+    app_state.api_versions.apply(outbound);
+    interceptors.run_request(outbound).await?;
+    // Checked after the interceptors, since they may rewrite the URI.
+    if !app_state.egress_policy.permits(&outbound.uri) {
+        warn!(
+            "Egress policy refused {} {}: not a configured upstream",
+            outbound.method, outbound.uri
+        );
+        app_state.metrics.record_egress_violation("destination");
+        return Err(ProxyError::EgressRefused);
+    }
+    if verbose {
+        app_state.debug_log.log_request(outbound);
+    }
+
+    // Held until the upstream call, and any hedge of it, is done.
+    let _slot = match &app_state.upstream_queue {
+        Some(queue) => Some(wait_for_slot(app_state, queue, client).await?),
+        None => None,
+    };
+    let timeout = app_state
+        .env_var_config
+        .adaptive_timeout
+        .as_ref()
+        .map(|config| config.timeout_for(route, &app_state.latency));
+    if let Some(timeout) = timeout {
+        info!(
+            "Upstream timeout for {} is {}ms",
+            route,
+            timeout.as_millis()
+        );
+    }
+    let started = Instant::now();
+    let hedge_delay = app_state
+        .hedger
+        .as_ref()
+        .and_then(|hedger| hedger.delay_for(&outbound.method, route, &app_state.latency));
+    let partial_ok = app_state
+        .env_var_config
+        .partial_response_paths
+        .iter()
+        .any(|prefix| route.starts_with(prefix.as_str()));
+    let mut upstream = match hedge_delay {
+        Some(delay) => send_hedged(app_state, outbound, delay, timeout, partial_ok).await?,
+        None => send_upstream(app_state, outbound, timeout, partial_ok).await?,
+    };
+    app_state.latency.record(route, started.elapsed());
+    app_state.upstream_errors.offer(
+        route,
+        app_state.correlation.read(&outbound.headers),
+        outbound,
+        &upstream,
+    );
+    app_state.api_versions.check(outbound, &upstream)?;
+    if verbose {
+        app_state.debug_log.log_response(outbound, &upstream);
+    }
+    contract::shadow_check(
+        &app_state.contracts,
+        &app_state.metrics,
+        outbound,
+        &upstream,
+    );
+    interceptors.run_response(outbound, &mut upstream).await?;
+    app_state.capture.offer(route, outbound, &upstream);
+    Ok(upstream)
+}
+
+async fn wait_for_slot(
+    app_state: &AppState,
+    queue: &Arc<FairQueue>,
+    client: &str,
+) -> Result<FairPermit, ProxyError> {
+    let started = Instant::now();
+    let acquired = tokio::time::timeout(
+        app_state.env_var_config.queue_timeout,
+        queue.acquire(client),
+    )
+    .await;
+    match acquired {
+        Ok((permit, queued)) => {
+            if queued {
+                app_state
+                    .metrics
+                    .record_queue_wait(client, started.elapsed());
+            }
+            Ok(permit)
+        }
+        Err(_) => {
+            warn!("Gave up waiting for an upstream slot for {}", client);
+            app_state
+                .metrics
+                .record_queue_wait(client, started.elapsed());
+            Err(ProxyError::QueueTimeout)
+        }
+    }
+}
+
+async fn wait_for_connection(
+    app_state: &AppState,
+    limiter: &ConnectionLimiter,
+    uri: &Uri,
+) -> Result<OwnedSemaphorePermit, ProxyError> {
+    let host = uri.authority().map_or("", |authority| authority.as_str());
+    let started = Instant::now();
+    let acquired = tokio::time::timeout(
+        app_state.env_var_config.queue_timeout,
+        limiter.acquire(host),
+    )
+    .await;
+    match acquired {
+        Ok((permit, waited)) => {
+            if waited {
+                app_state
+                    .metrics
+                    .record_connection_wait(host, started.elapsed());
+            }
+            Ok(permit)
+        }
+        Err(_) => {
+            warn!("Gave up waiting for a connection to {}", host);
+            app_state
+                .metrics
+                .record_connection_wait(host, started.elapsed());
+            Err(ProxyError::QueueTimeout)
+        }
+    }
+}
+
+/// The host of `uri` when it's a name rather than an IP literal.
+fn dns_name(uri: &Uri) -> Option<&str> {
+    let host = uri.host()?;
+    let literal = host.trim_start_matches('[').trim_end_matches(']');
+    literal.parse::<std::net::IpAddr>().is_err().then_some(host)
+}
+
+/// Sends `outbound` and reads the whole response, retrying once after
+/// re-resolving the host if connecting fails. `timeout` overrides the
+/// client's for this call. With `partial_ok`, a body that fails partway
+/// is returned as far as it got, flagged with a `Warning` header.
+async fn send_upstream(
+    app_state: &AppState,
+    outbound: &OutboundRequest,
+    timeout: Option<Duration>,
+    partial_ok: bool,
+) -> Result<UpstreamResponse, ProxyError> {
+    // Held until the body has been read, which is when the connection frees up.
+    let _connection = match &app_state.connection_limiter {
+        Some(limiter) => Some(wait_for_connection(app_state, limiter, &outbound.uri).await?),
+        None => None,
+    };
+
+    app_state.metrics.record_payload(
+        &outbound.env,
+        Direction::Request,
+        &outbound.headers,
+        outbound.body.len(),
+    );
+    let client = app_state.client_for(&outbound.env);
+    let mut refreshed = false;
+    let response = loop {
+        // Build outbound request
+        let body = if app_state.transfers.is_large(outbound.body.len()) {
+            let upload = app_state.transfers.start(
+                TransferKind::Upload,
+                &outbound.env,
+                &outbound.uri,
+                Some(outbound.body.len() as u64),
+            );
+            reqwest::Body::wrap(UploadBody::new(outbound.body.clone(), upload))
+        } else {
+            outbound.body.clone().into()
+        };
+        let mut request_builder = client
+            .request(outbound.method.clone(), outbound.uri.to_string())
+            .headers(outbound.headers.clone())
+            .body(body);
+        if let Some(timeout) = timeout {
+            request_builder = request_builder.timeout(timeout);
+        }
+
+        let sent = Instant::now();
+        let e = match request_builder.send().await {
+            Ok(response) => {
+                timings::record_ttfb(sent.elapsed());
+                if let Some(addr) = response.remote_addr() {
+                    app_state
+                        .metrics
+                        .record_upstream_family(addr.ip().to_canonical().is_ipv6());
+                }
+                break response;
+            }
+            Err(e) => e,
+        };
+        let error = ProxyError::from_upstream(&e);
+        // The supplier may have moved to new addresses our cached answer
+        // doesn't have yet. Nothing was sent, so any method can retry.
+        if error == ProxyError::UpstreamConnection
+            && !refreshed
+            && app_state.env_var_config.dns_refresh_on_connect_failure
+        {
+            if let Some(host) = dns_name(&outbound.uri) {
+                refreshed = true;
+                if app_state.dns.refresh(host).await != Refresh::Failed {
+                    warn!(
+                        "Connecting to {} failed ({}); retrying after re-resolving it",
+                        host, e
+                    );
+                    continue;
+                }
+            }
+        }
+        error!("Request failed ({}): {}", error.code(), e);
+        app_state
+            .metrics
+            .record_upstream_error(&outbound.env, error);
+        return Err(error);
+    };
+
+    //
+    // == Handling the response ==
+    //
+    // Read through `http_body` rather than `Response::bytes`, which drops trailers.
+    let (parts, body) = hyper::Response::from(response).into_parts();
+    let mut headers = parts.headers;
+    strip_hop_by_hop(&mut headers);
+    let idle_timeout = app_state.env_var_config.upstream_body_idle_timeout;
+    let reservation = app_state
+        .memory_budget
+        .as_ref()
+        .map(|budget| budget.reservation());
+    let expected_bytes = headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok()?.parse().ok());
+    let download = Download::new(
+        &app_state.transfers,
+        &outbound.env,
+        &outbound.uri,
+        expected_bytes,
+    );
+    let read = read_upstream_body(body, idle_timeout, reservation, download).await;
+    let (body, trailers) = match read {
+        Ok(read) => read,
+        Err((_, BodyFailure::OverBudget)) => {
+            warn!(
+                "Shedding the response from {} over the memory budget",
+                outbound.uri
+            );
+            return Err(ProxyError::UpstreamBodyOverBudget);
+        }
+        Err((partial, error)) if partial_ok && !partial.is_empty() => {
+            warn!(
+                "Returning {} bytes of {} after the body failed: {}",
+                partial.len(),
+                outbound.uri,
+                error
+            );
+            app_state.metrics.record_partial_response(&outbound.env);
+            headers.remove(header::CONTENT_LENGTH);
+            headers.insert(
+                header::WARNING,
+                header::HeaderValue::from_static(TRUNCATED_WARNING),
+            );
+            (partial, None)
+        }
+        Err((_, error)) => {
+            error!("Failed to read response body: {}", error);
+            let error = error.into_proxy_error();
+            app_state
+                .metrics
+                .record_upstream_error(&outbound.env, error);
+            return Err(error);
+        }
+    };
+
+    app_state
+        .metrics
+        .record_payload(&outbound.env, Direction::Response, &headers, body.len());
+    Ok(UpstreamResponse {
+        status: parts.status,
+        headers,
+        body,
+        trailers,
+    })
+}
+
+/// Why reading an upstream body stopped early.
+#[derive(Debug)]
+enum BodyFailure {
+    Read(reqwest::Error),
+    /// Nothing arrived for the configured idle timeout.
+    Stalled(Duration),
+    /// Buffering more would exceed the memory budget.
+    OverBudget,
+}
+
+impl BodyFailure {
+    fn into_proxy_error(self) -> ProxyError {
+        match self {
+            Self::Read(e) if e.is_timeout() => ProxyError::UpstreamTimeout,
+            Self::Read(_) | Self::Stalled(_) => ProxyError::UpstreamBody,
+            Self::OverBudget => ProxyError::UpstreamBodyOverBudget,
+        }
+    }
+}
+
+impl std::fmt::Display for BodyFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Read(e) => e.fmt(f),
+            Self::Stalled(idle) => write!(f, "no data for {}ms", idle.as_millis()),
+            Self::OverBudget => f.write_str("over the memory budget"),
+        }
+    }
+}
+
+/// Reads a body frame by frame, so a failure can still hand back the bytes
+/// that arrived before it. Each frame is first reserved against the memory
+/// budget, when there is one.
+async fn read_upstream_body(
+    mut body: reqwest::Body,
+    idle_timeout: Option<Duration>,
+    mut reservation: Option<Reservation>,
+    mut download: Download,
+) -> Result<(Bytes, Option<HeaderMap>), (Bytes, BodyFailure)> {
+    let mut data = Vec::new();
+    let mut trailers = None;
+    loop {
+        let frame = match idle_timeout {
+            Some(idle) => match tokio::time::timeout(idle, body.frame()).await {
+                Ok(frame) => frame,
+                Err(_) => return Err((data.into(), BodyFailure::Stalled(idle))),
+            },
+            None => body.frame().await,
+        };
+        match frame {
+            None => return Ok((data.into(), trailers)),
+            Some(Err(e)) => return Err((data.into(), BodyFailure::Read(e))),
+            Some(Ok(frame)) => match frame.into_data() {
+                Ok(chunk) => {
+                    if let Some(reservation) = &mut reservation {
+                        if !reservation.try_grow(chunk.len()) {
+                            return Err((data.into(), BodyFailure::OverBudget));
+                        }
+                    }
+                    download.advance(chunk.len());
+                    data.extend_from_slice(&chunk);
+                }
+                Err(frame) => {
+                    if let Ok(received) = frame.into_trailers() {
+                        trailers.get_or_insert_with(HeaderMap::new).extend(received);
+                    }
+                }
+            },
+        }
+    }
+}
+
+/// Whether `upstream` is the front of a body that broke off partway.
+fn is_truncated(upstream: &UpstreamResponse) -> bool {
+    upstream
+        .headers
+        .get(header::WARNING)
+        .is_some_and(|warning| warning == TRUNCATED_WARNING)
+}
+
+/// Sends `outbound`, and if it hasn't answered after `delay`, races a second
+/// identical attempt against it. The first success wins and the other attempt
+/// is dropped, cancelling it.
+async fn send_hedged(
+    app_state: &AppState,
+    outbound: &OutboundRequest,
+    delay: Duration,
+    timeout: Option<Duration>,
+    partial_ok: bool,
+) -> Result<UpstreamResponse, ProxyError> {
+    let primary = send_upstream(app_state, outbound, timeout, partial_ok);
+    tokio::pin!(primary);
+    tokio::select! {
+        result = &mut primary => return result,
+        _ = tokio::time::sleep(delay) => {}
+    }
+    let hedger = app_state.hedger.as_ref().expect("hedging is configured");
+    let Some(_permit) = hedger.try_start() else {
+        return primary.await;
+    };
+    app_state.metrics.record_hedge_fired();
+    info!(
+        "Hedging {} {} after {}ms",
+        outbound.method,
+        outbound.uri,
+        delay.as_millis()
+    );
+
+    let hedge = send_upstream(app_state, outbound, timeout, partial_ok);
+    tokio::pin!(hedge);
+    tokio::select! {
+        result = &mut primary => match result {
+            Ok(upstream) => {
+                app_state.metrics.record_hedge_wasted();
+                Ok(upstream)
+            }
+            Err(_) => hedge.await.inspect(|_| app_state.metrics.record_hedge_won()),
+        },
+        result = &mut hedge => match result {
+            Ok(upstream) => {
+                app_state.metrics.record_hedge_won();
+                Ok(upstream)
+            }
+            Err(_) => primary.await.inspect(|_| app_state.metrics.record_hedge_wasted()),
+        },
+    }
+}
+
+/// Refreshes a stale cache entry off the request path, at most once per key.
+fn spawn_revalidation(
+    app_state: &AppState,
+    mut outbound: OutboundRequest,
+    cached: UpstreamResponse,
+    cache_key: String,
+    inbound_path: String,
+    rule: CacheRule,
+) {
+    if !app_state.cache.begin_revalidation(&cache_key) {
+        return;
+    }
+
+    // The refresh is on the cache's behalf, so it uses the cached validators
+    // rather than whatever the triggering caller sent.
+    outbound.headers.remove(header::IF_NONE_MATCH);
+    outbound.headers.remove(header::IF_MODIFIED_SINCE);
+    add_validators(&mut outbound.headers, &cached);
+
+    let app_state = app_state.clone();
+    tokio::spawn(async move {
+        match fetch_upstream(
+            &app_state,
+            &mut outbound,
+            &inbound_path,
+            "cache-revalidation",
+            false,
+            None,
+        )
+        .await
+        {
+            Ok(upstream) if upstream.status == StatusCode::NOT_MODIFIED => {
+                let refreshed = merge_not_modified(&cached, &upstream.headers);
+                app_state
+                    .cache
+                    .put(cache_key.clone(), &inbound_path, &refreshed, &rule)
+                    .await
+            }
+            Ok(upstream) => {
+                app_state
+                    .cache
+                    .put(cache_key.clone(), &inbound_path, &upstream, &rule)
+                    .await
+            }
+            Err(error) => warn!(
+                "Background revalidation of {} failed: {}",
+                inbound_path,
+                error.code()
+            ),
+        }
+        app_state.cache.end_revalidation(&cache_key);
+    });
+}
+
+/// Turns an upstream (or cached) response into the response sent to the caller.
+fn build_response(
+    method: &Method,
+    upstream: UpstreamResponse,
+    cache_status: &'static str,
+    cacheable: bool,
+) -> Result<Response, ProxyError> {
+    let UpstreamResponse {
+        status,
+        mut headers,
+        body: body_bytes,
+        trailers,
+    } = upstream;
+
+    let body_bytes = prepare_response(&mut headers, method, status, body_bytes);
+    let body = match trailers {
+        Some(trailers) if permits_body(method, status) => {
+            headers.remove(header::CONTENT_LENGTH);
+            body_with_trailers(body_bytes, trailers)
+        }
+        _ => Body::from(body_bytes),
+    };
+    let mut new_response = Response::new(body);
+    *new_response.status_mut() = status;
+    *new_response.headers_mut() = headers;
+
+    if cacheable {
+        new_response
+            .headers_mut()
+            .insert(X_CACHE, header::HeaderValue::from_static(cache_status));
+    }
+
+    Ok(new_response)
+}
@@ -0,0 +1,294 @@
+//! Fixed-window request limits per client. `RATE_LIMIT_SCHEDULES` swaps in
+//! other limits during recurring windows, e.g. more headroom off-peak:
+//! `[{"cron": "* 0-5 * * *", "timezone": "Asia/Kolkata", "limit": "1200/60"}]`.
+//! The first open window's limit applies; outside them, `RATE_LIMIT` does.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use axum::extract::{ConnectInfo, Request};
+use axum::http::{header, HeaderMap, HeaderName, HeaderValue};
+use chrono::Utc;
+use serde::{Deserialize, Deserializer};
+use tracing::{error, warn};
+
+use crate::cron::{TimeWindow, WindowSpec};
+
+/// Allows `requests` per client in each `window`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub struct RateLimit {
+    pub requests: u64,
+    pub window: Duration,
+}
+
+/// Outcome of counting one request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateLimitDecision {
+    /// `quota` is unknown when the store failed.
+    Allowed { quota: Option<Quota> },
+    /// The quota is spent until `quota.reset`.
+    Limited { quota: Quota },
+}
+
+impl RateLimitDecision {
+    pub fn quota(&self) -> Option<&Quota> {
+        match self {
+            Self::Allowed { quota } => quota.as_ref(),
+            Self::Limited { quota } => Some(quota),
+        }
+    }
+}
+
+/// Where a caller stands in the current window, sent back as the
+/// `RateLimit-*` headers so clients can pace themselves.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Quota {
+    pub limit: u64,
+    pub remaining: u64,
+    /// Until the window ends and the quota is restored.
+    pub reset: Duration,
+}
+
+impl Quota {
+    /// Sets `RateLimit-Limit`, `RateLimit-Remaining` and `RateLimit-Reset`.
+    /// A 429 also gets `Retry-After` from [`Quota::retry_after`].
+    pub fn apply(&self, headers: &mut HeaderMap) {
+        headers.insert(RATELIMIT_LIMIT, HeaderValue::from(self.limit));
+        headers.insert(RATELIMIT_REMAINING, HeaderValue::from(self.remaining));
+        headers.insert(RATELIMIT_RESET, HeaderValue::from(self.reset.as_secs()));
+    }
+
+    pub fn retry_after(&self) -> (HeaderName, HeaderValue) {
+        (header::RETRY_AFTER, HeaderValue::from(self.reset.as_secs()))
+    }
+}
+
+pub const RATELIMIT_LIMIT: HeaderName = HeaderName::from_static("ratelimit-limit");
+pub const RATELIMIT_REMAINING: HeaderName = HeaderName::from_static("ratelimit-remaining");
+pub const RATELIMIT_RESET: HeaderName = HeaderName::from_static("ratelimit-reset");
+
+/// Counters shared by everything enforcing the same limit.
+#[async_trait]
+pub trait RateLimitStore: Send + Sync {
+    /// Adds one to `key`'s counter, which lives for `ttl`, and returns the new count.
+    async fn increment(&self, key: &str, ttl: Duration) -> Result<u64, String>;
+}
+
+/// Per-process counters; the default when no shared backend is configured.
+#[derive(Debug, Default)]
+pub struct MemoryRateLimitStore {
+    counters: Mutex<HashMap<String, (u64, SystemTime)>>,
+}
+
+#[async_trait]
+impl RateLimitStore for MemoryRateLimitStore {
+    async fn increment(&self, key: &str, ttl: Duration) -> Result<u64, String> {
+        let now = SystemTime::now();
+        let mut counters = self.counters.lock().unwrap();
+        // Keys carry their window, so finished windows are never read again.
+        counters.retain(|_, (_, expires)| *expires > now);
+        let (count, _) = counters.entry(key.to_string()).or_insert((0, now + ttl));
+        *count += 1;
+        Ok(*count)
+    }
+}
+
+/// A limit that replaces `RATE_LIMIT` while its window is open.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct ScheduledRateLimit {
+    #[serde(flatten)]
+    pub window: WindowSpec,
+    /// `requests/window_secs`, as in `RATE_LIMIT`.
+    #[serde(deserialize_with = "deserialize_rate_limit")]
+    pub limit: RateLimit,
+}
+
+fn deserialize_rate_limit<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<RateLimit, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    parse_rate_limit(&value).map_err(serde::de::Error::custom)
+}
+
+/// Parses `RATE_LIMIT_SCHEDULES`, a JSON array of [`ScheduledRateLimit`]s.
+pub fn parse_rate_limit_schedules(value: &str) -> Result<Vec<ScheduledRateLimit>, String> {
+    if value.trim().is_empty() {
+        return Ok(Vec::new());
+    }
+    let schedules: Vec<ScheduledRateLimit> =
+        serde_json::from_str(value).map_err(|e| format!("invalid rate limit schedules: {e}"))?;
+    for schedule in &schedules {
+        schedule.window.validate()?;
+    }
+    Ok(schedules)
+}
+
+pub struct RateLimiter {
+    /// Outside scheduled windows; unlimited when unset.
+    limit: Option<RateLimit>,
+    schedules: Vec<(TimeWindow, RateLimit)>,
+    store: Arc<dyn RateLimitStore>,
+}
+
+impl RateLimiter {
+    pub fn new(limit: RateLimit, store: Arc<dyn RateLimitStore>) -> Self {
+        Self {
+            limit: Some(limit),
+            schedules: Vec::new(),
+            store,
+        }
+    }
+
+    /// `limit` outside the schedules' windows, and each schedule's limit
+    /// inside its window. Schedules that fail to parse were already
+    /// rejected with the config.
+    pub fn scheduled(
+        limit: Option<RateLimit>,
+        schedules: &[ScheduledRateLimit],
+        store: Arc<dyn RateLimitStore>,
+    ) -> Self {
+        Self {
+            limit,
+            schedules: schedules
+                .iter()
+                .filter_map(|schedule| {
+                    Some((TimeWindow::new(&schedule.window).ok()?, schedule.limit))
+                })
+                .collect(),
+            store,
+        }
+    }
+
+    /// The limit in force now.
+    pub fn current_limit(&self) -> Option<RateLimit> {
+        let now = Utc::now();
+        self.schedules
+            .iter()
+            .find(|(window, _)| window.active_until(now).is_some())
+            .map(|(_, limit)| *limit)
+            .or(self.limit)
+    }
+
+    /// Counts a request from `client`. Store failures let the request through
+    /// so an unreachable backend doesn't take the proxy down with it.
+    pub async fn check(&self, client: &str) -> RateLimitDecision {
+        let Some(limit) = self.current_limit() else {
+            return RateLimitDecision::Allowed { quota: None };
+        };
+        let window_secs = limit.window.as_secs().max(1);
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let window_index = now / window_secs;
+        let key = format!("{client}:{window_index}");
+
+        match self
+            .store
+            .increment(&key, Duration::from_secs(window_secs))
+            .await
+        {
+            Ok(count) => {
+                let quota = Quota {
+                    limit: limit.requests,
+                    remaining: limit.requests.saturating_sub(count),
+                    reset: Duration::from_secs((window_index + 1) * window_secs - now),
+                };
+                if count > limit.requests {
+                    RateLimitDecision::Limited { quota }
+                } else {
+                    RateLimitDecision::Allowed { quota: Some(quota) }
+                }
+            }
+            Err(e) => {
+                error!("Rate limit store failed, allowing request: {}", e);
+                RateLimitDecision::Allowed { quota: None }
+            }
+        }
+    }
+}
+
+/// Set on a request to identify its caller by something other than the peer
+/// IP, e.g. a tenant, for rate limiting and upstream queueing.
+#[derive(Debug, Clone)]
+pub struct ClientKey(pub String);
+
+/// Identifies the caller by peer IP. Requires the router to be served with
+/// connect info; without it every caller shares one bucket.
+pub fn client_key(req: &Request) -> String {
+    if let Some(ClientKey(key)) = req.extensions().get() {
+        return key.clone();
+    }
+    match req.extensions().get::<ConnectInfo<SocketAddr>>() {
+        Some(ConnectInfo(addr)) => addr.ip().to_canonical().to_string(),
+        None => {
+            warn!("No peer address for rate limiting; serve with connect info");
+            "unknown".to_string()
+        }
+    }
+}
+
+/// Parses `requests/window_secs`, e.g. `600/60`.
+pub fn parse_rate_limit(value: &str) -> Result<RateLimit, String> {
+    let (requests, window) = value
+        .trim()
+        .split_once('/')
+        .ok_or_else(|| format!("expected requests/window_secs, got {value}"))?;
+    let requests = requests
+        .trim()
+        .parse::<u64>()
+        .map_err(|e| format!("invalid request count in {value}: {e}"))?;
+    let window = window
+        .trim()
+        .parse::<u64>()
+        .map_err(|e| format!("invalid window in {value}: {e}"))?;
+    if window == 0 {
+        return Err(format!("window must be positive in {value}"));
+    }
+    Ok(RateLimit {
+        requests,
+        window: Duration::from_secs(window),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn limits_each_client_separately() {
+        let limiter = RateLimiter::new(
+            parse_rate_limit("2/60").unwrap(),
+            Arc::new(MemoryRateLimitStore::default()),
+        );
+
+        let remaining = |decision: RateLimitDecision| match decision {
+            RateLimitDecision::Allowed { quota } => quota.map(|quota| quota.remaining),
+            RateLimitDecision::Limited { .. } => None,
+        };
+        assert_eq!(remaining(limiter.check("a").await), Some(1));
+        assert_eq!(remaining(limiter.check("a").await), Some(0));
+        assert!(matches!(
+            limiter.check("a").await,
+            RateLimitDecision::Limited { quota }
+                if quota.remaining == 0 && quota.reset <= Duration::from_secs(60)
+        ));
+        assert_eq!(remaining(limiter.check("b").await), Some(1));
+    }
+
+    #[test]
+    fn parses_limits() {
+        assert_eq!(
+            parse_rate_limit("600/60"),
+            Ok(RateLimit {
+                requests: 600,
+                window: Duration::from_secs(60)
+            })
+        );
+        assert!(parse_rate_limit("600").is_err());
+        assert!(parse_rate_limit("600/0").is_err());
+    }
+}
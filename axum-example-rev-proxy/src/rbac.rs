@@ -0,0 +1,163 @@
+//! Roles for the admin API. Each admin token belongs to a named principal
+//! with one role, and each role can do everything the ones below it can:
+//!
+//! - `viewer` reads: metrics, probes, the dashboard, the live tail;
+//! - `operator` also acts on traffic: cache flushes, log levels, captures,
+//!   request log queries, exports of recorded traffic, upstream error
+//!   payloads, comparisons, JSONPath extractions, scheduled requests, target
+//!   overrides and dry runs;
+//! - `admin` also changes how the proxy behaves: fault injection, metrics
+//!   imports and simulated webhooks.
+//!
+//! `PROXY_ADMIN_TOKEN` is the principal `admin` with the `admin` role;
+//! `ADMIN_TOKENS` adds more. Every admin action is written to the `audit`
+//! log target with the principal that took it.
+
+use std::fmt;
+use std::str::FromStr;
+
+use serde::Deserialize;
+
+use crate::admin::constant_time_eq;
+use crate::app_state::EnvVarConfig;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Viewer,
+    Operator,
+    Admin,
+}
+
+impl Role {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Viewer => "viewer",
+            Self::Operator => "operator",
+            Self::Admin => "admin",
+        }
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim() {
+            "viewer" => Ok(Self::Viewer),
+            "operator" => Ok(Self::Operator),
+            "admin" => Ok(Self::Admin),
+            other => Err(format!(
+                "unknown role {other:?}; expected viewer, operator or admin"
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct AdminToken {
+    /// Who holds the token, as written in the audit log.
+    pub name: String,
+    pub role: Role,
+    pub token: String,
+}
+
+/// Who an admin request was made by.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Principal {
+    pub name: String,
+    pub role: Role,
+}
+
+impl fmt::Display for Principal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({})", self.name, self.role)
+    }
+}
+
+/// Parses `ADMIN_TOKENS`: `name:role:token` entries separated by commas.
+pub fn parse_admin_tokens(value: &str) -> Result<Vec<AdminToken>, String> {
+    let tokens = value
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(|entry| {
+            let mut parts = entry.splitn(3, ':');
+            let (Some(name), Some(role), Some(token)) = (parts.next(), parts.next(), parts.next())
+            else {
+                return Err("admin tokens must be name:role:token".to_string());
+            };
+            if name.trim().is_empty() || token.trim().is_empty() {
+                return Err("admin tokens need a name and a token".to_string());
+            }
+            Ok(AdminToken {
+                name: name.trim().to_string(),
+                role: role.parse()?,
+                token: token.trim().to_string(),
+            })
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+    for (i, token) in tokens.iter().enumerate() {
+        if tokens[..i].iter().any(|other| other.token == token.token) {
+            return Err(format!("admin token of {} is not unique", token.name));
+        }
+    }
+    Ok(tokens)
+}
+
+/// Whether any admin token is configured, which mounts the admin API.
+pub fn enabled(config: &EnvVarConfig) -> bool {
+    config.admin_token.is_some() || !config.admin_tokens.is_empty()
+}
+
+/// The principal holding `token`, if any.
+pub fn authenticate(config: &EnvVarConfig, token: &[u8]) -> Option<Principal> {
+    let legacy = config.admin_token.as_deref().map(|expected| {
+        (
+            Principal {
+                name: "admin".to_string(),
+                role: Role::Admin,
+            },
+            expected,
+        )
+    });
+    let named = config.admin_tokens.iter().map(|entry| {
+        (
+            Principal {
+                name: entry.name.clone(),
+                role: entry.role,
+            },
+            entry.token.as_str(),
+        )
+    });
+    // Every token is compared, so timing doesn't tell which one was close.
+    legacy
+        .into_iter()
+        .chain(named)
+        .fold(None, |found, (principal, expected)| {
+            let matched = constant_time_eq(token, expected.as_bytes());
+            found.or(matched.then_some(principal))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_tokens_and_orders_roles() {
+        let tokens = parse_admin_tokens("grafana:viewer:t1, oncall:operator:t:2").unwrap();
+        assert_eq!(tokens[0].role, Role::Viewer);
+        assert_eq!(tokens[1].token, "t:2");
+        assert!(parse_admin_tokens("grafana:owner:t1").is_err());
+        assert!(parse_admin_tokens("grafana:viewer").is_err());
+        assert!(parse_admin_tokens("a:viewer:t1,b:admin:t1").is_err());
+        assert!(Role::Viewer < Role::Operator && Role::Operator < Role::Admin);
+    }
+}
@@ -0,0 +1,272 @@
+//! Redis-backed cache, rate-limit and webhook replay state (behind the
+//! `redis` feature), so replicas behind the same static IP share them.
+//!
+//! Keys are namespaced as `{prefix}cache:{cache key}`,
+//! `{prefix}ratelimit:{client}:{window}` and `{prefix}seen:{key}`, and
+//! expire on their own. As a [`Storage`] backend its keys are
+//! `{prefix}kv:{key}`.
+
+use std::time::{Duration, SystemTime};
+
+use async_trait::async_trait;
+use redis::aio::ConnectionManager;
+use redis::AsyncCommands;
+use tokio::sync::OnceCell;
+use tracing::error;
+
+use crate::cache::{under_prefix, CacheEntry, CacheStore, StoredEntry};
+use crate::dedup::DedupStore;
+use crate::rate_limit::RateLimitStore;
+use crate::storage::Storage;
+
+pub const DEFAULT_KEY_PREFIX: &str = "egress-proxy:";
+
+/// One connection (re-established on failure) shared by every store.
+/// Connecting is deferred to first use so the router can be built outside a
+/// runtime.
+pub struct RedisStore {
+    client: redis::Client,
+    key_prefix: String,
+    connection: OnceCell<ConnectionManager>,
+}
+
+impl RedisStore {
+    pub fn open(url: &str) -> Result<Self, String> {
+        let client = redis::Client::open(url).map_err(|e| format!("invalid redis url: {e}"))?;
+        Ok(Self {
+            client,
+            key_prefix: DEFAULT_KEY_PREFIX.to_string(),
+            connection: OnceCell::new(),
+        })
+    }
+
+    /// Namespaces every key, for deployments sharing one Redis.
+    pub fn key_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.key_prefix = prefix.into();
+        self
+    }
+
+    async fn connection(&self) -> Result<ConnectionManager, String> {
+        self.connection
+            .get_or_try_init(|| ConnectionManager::new(self.client.clone()))
+            .await
+            .cloned()
+            .map_err(|e| format!("redis connection failed: {e}"))
+    }
+
+    fn cache_key(&self, key: &str) -> String {
+        format!("{}cache:{}", self.key_prefix, key)
+    }
+
+    fn storage_key(&self, key: &str) -> String {
+        format!("{}kv:{}", self.key_prefix, key)
+    }
+}
+
+#[async_trait]
+impl CacheStore for RedisStore {
+    async fn get(&self, key: &str) -> Option<CacheEntry> {
+        let result: Result<Option<String>, String> = async {
+            let mut conn = self.connection().await?;
+            conn.get(self.cache_key(key))
+                .await
+                .map_err(|e| e.to_string())
+        }
+        .await;
+        match result {
+            Ok(Some(json)) => serde_json::from_str::<StoredEntry>(&json)
+                .ok()
+                .and_then(StoredEntry::into_entry),
+            Ok(None) => None,
+            Err(e) => {
+                error!("Redis cache read failed: {}", e);
+                None
+            }
+        }
+    }
+
+    async fn insert(&self, key: String, entry: CacheEntry) {
+        let ttl = entry
+            .retain_until
+            .duration_since(SystemTime::now())
+            .unwrap_or_default()
+            .as_secs()
+            .max(1);
+        let json = match serde_json::to_string(&StoredEntry::from_entry(&entry)) {
+            Ok(json) => json,
+            Err(e) => return error!("Failed to serialize cache entry: {}", e),
+        };
+        let result: Result<(), String> = async {
+            let mut conn = self.connection().await?;
+            conn.set_ex(self.cache_key(&key), json, ttl)
+                .await
+                .map_err(|e| e.to_string())
+        }
+        .await;
+        if let Err(e) = result {
+            error!("Redis cache write failed: {}", e);
+        }
+    }
+
+    async fn remove(&self, key: &str) {
+        let result: Result<(), String> = async {
+            let mut conn = self.connection().await?;
+            conn.del(self.cache_key(key))
+                .await
+                .map_err(|e| e.to_string())
+        }
+        .await;
+        if let Err(e) = result {
+            error!("Redis cache delete failed: {}", e);
+        }
+    }
+
+    async fn remove_prefix(&self, path_prefix: &str) -> usize {
+        let namespace = self.cache_key("");
+        let pattern = format!("{}*", escape_glob(&self.cache_key(path_prefix)));
+        let result: Result<usize, String> = async {
+            let mut conn = self.connection().await?;
+            let keys: Vec<String> = {
+                let mut scan = conn
+                    .scan_match::<_, String>(pattern)
+                    .await
+                    .map_err(|e| e.to_string())?;
+                let mut keys = Vec::new();
+                while let Some(key) = scan.next_item().await {
+                    if key
+                        .strip_prefix(&namespace)
+                        .is_some_and(|cached| under_prefix(cached, path_prefix))
+                    {
+                        keys.push(key);
+                    }
+                }
+                keys
+            };
+            if keys.is_empty() {
+                return Ok(0);
+            }
+            conn.del(keys).await.map_err(|e| e.to_string())
+        }
+        .await;
+        result.unwrap_or_else(|e| {
+            error!("Redis cache invalidation failed: {}", e);
+            0
+        })
+    }
+}
+
+#[async_trait]
+impl RateLimitStore for RedisStore {
+    async fn increment(&self, key: &str, ttl: Duration) -> Result<u64, String> {
+        let key = format!("{}ratelimit:{}", self.key_prefix, key);
+        let mut conn = self.connection().await?;
+        let (count,): (u64,) = redis::pipe()
+            .atomic()
+            .incr(&key, 1)
+            .expire(&key, ttl.as_secs().max(1) as i64)
+            .ignore()
+            .query_async(&mut conn)
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok(count)
+    }
+}
+
+#[async_trait]
+impl DedupStore for RedisStore {
+    async fn first_seen(&self, key: &str, ttl: Duration) -> Result<bool, String> {
+        let key = format!("{}seen:{}", self.key_prefix, key);
+        let mut conn = self.connection().await?;
+        // SET NX answers OK when it wrote the key and nil when it already existed.
+        let written: Option<String> = redis::cmd("SET")
+            .arg(&key)
+            .arg(1)
+            .arg("NX")
+            .arg("EX")
+            .arg(ttl.as_secs().max(1))
+            .query_async(&mut conn)
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok(written.is_some())
+    }
+}
+
+#[async_trait]
+impl Storage for RedisStore {
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, String> {
+        let mut conn = self.connection().await?;
+        conn.get(self.storage_key(key))
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    async fn put(&self, key: &str, value: Vec<u8>, ttl: Option<Duration>) -> Result<(), String> {
+        let mut conn = self.connection().await?;
+        let key = self.storage_key(key);
+        match ttl {
+            Some(ttl) => {
+                conn.pset_ex(key, value, ttl.as_millis().max(1) as u64)
+                    .await
+            }
+            None => conn.set(key, value).await,
+        }
+        .map_err(|e| e.to_string())
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<String>, String> {
+        let namespace = self.storage_key("");
+        let pattern = format!("{}*", escape_glob(&self.storage_key(prefix)));
+        let mut conn = self.connection().await?;
+        let mut scan = conn
+            .scan_match::<_, String>(pattern)
+            .await
+            .map_err(|e| e.to_string())?;
+        let mut keys = Vec::new();
+        while let Some(key) = scan.next_item().await {
+            if let Some(key) = key.strip_prefix(&namespace) {
+                keys.push(key.to_string());
+            }
+        }
+        keys.sort();
+        Ok(keys)
+    }
+
+    async fn delete(&self, key: &str) -> Result<bool, String> {
+        let mut conn = self.connection().await?;
+        let removed: u64 = conn
+            .del(self.storage_key(key))
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok(removed > 0)
+    }
+
+    async fn put_new(
+        &self,
+        key: &str,
+        value: Vec<u8>,
+        ttl: Option<Duration>,
+    ) -> Result<bool, String> {
+        let mut conn = self.connection().await?;
+        let mut command = redis::cmd("SET");
+        command.arg(self.storage_key(key)).arg(value).arg("NX");
+        if let Some(ttl) = ttl {
+            command.arg("PX").arg(ttl.as_millis().max(1) as u64);
+        }
+        let written: Option<String> = command
+            .query_async(&mut conn)
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok(written.is_some())
+    }
+}
+
+fn escape_glob(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, '*' | '?' | '[' | ']' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
@@ -0,0 +1,346 @@
+//! `replay` subcommand: sends recorded traffic from a request log archive
+//! (JSONL, optionally gzipped, as the archiver writes them) to an
+//! upstream, by default `test`, and compares the new statuses and latencies
+//! with the recorded ones. Meant for load-testing a supplier's test
+//! environment before their changes go live.
+//!
+//! ```text
+//! axum-example-rev-proxy replay ARCHIVE [--env test] [--rate 10] [--concurrency 4]
+//!     [--limit 1000] [--methods GET,POST]
+//! ```
+//!
+//! Requests go through the interceptors like proxied ones, so they are
+//! signed the same way. Bodies are replayed as recorded, which means
+//! redacted values stay redacted and truncated bodies stay truncated.
+
+use std::io::{BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use axum::body::Bytes;
+use flate2::read::GzDecoder;
+use hyper::header::{self, HeaderMap, HeaderValue};
+use hyper::Method;
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+
+use crate::app_state::AppState;
+use crate::proxy::send_to_upstream;
+use crate::request_log::RequestLogEntry;
+
+const USAGE: &str = "usage: replay ARCHIVE [--env ENV] [--rate PER_SEC] [--concurrency N] \
+                     [--limit N] [--methods GET,POST]";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReplayOptions {
+    pub archive: PathBuf,
+    /// Upstream the traffic is sent to.
+    pub env: String,
+    /// Requests started per second; as fast as concurrency allows when unset.
+    pub rate: Option<f64>,
+    pub concurrency: usize,
+    /// Replays only the first this many matching entries.
+    pub limit: Option<usize>,
+    /// Replays only these methods; all when empty.
+    pub methods: Vec<Method>,
+}
+
+impl ReplayOptions {
+    /// Parses the arguments after `replay`.
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Self, String> {
+        let mut args = args.into_iter();
+        let mut options = Self {
+            archive: PathBuf::new(),
+            env: "test".to_string(),
+            rate: None,
+            concurrency: 4,
+            limit: None,
+            methods: Vec::new(),
+        };
+        let mut archive = None;
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or(format!("{arg} needs a value\n{USAGE}"));
+            match arg.as_str() {
+                "--env" => options.env = value()?,
+                "--rate" => {
+                    let rate: f64 = value()?.parse().map_err(|e| format!("--rate: {e}"))?;
+                    options.rate = (rate > 0.0).then_some(rate);
+                }
+                "--concurrency" => {
+                    options.concurrency = value()?
+                        .parse()
+                        .map_err(|e| format!("--concurrency: {e}"))?;
+                    if options.concurrency == 0 {
+                        return Err("--concurrency must be at least 1".to_string());
+                    }
+                }
+                "--limit" => {
+                    options.limit = Some(value()?.parse().map_err(|e| format!("--limit: {e}"))?)
+                }
+                "--methods" => {
+                    options.methods = value()?
+                        .split(',')
+                        .map(|m| Method::from_bytes(m.trim().to_ascii_uppercase().as_bytes()))
+                        .collect::<Result<_, _>>()
+                        .map_err(|e| format!("--methods: {e}"))?
+                }
+                flag if flag.starts_with("--") => return Err(format!("unknown {flag}\n{USAGE}")),
+                path if archive.is_none() => archive = Some(PathBuf::from(path)),
+                extra => return Err(format!("unexpected {extra}\n{USAGE}")),
+            }
+        }
+        options.archive = archive.ok_or(USAGE)?;
+        Ok(options)
+    }
+}
+
+/// Reads a request log archive, gzipped or not.
+pub fn load_entries(path: &Path) -> Result<Vec<RequestLogEntry>, String> {
+    let file = std::fs::File::open(path).map_err(|e| format!("{}: {e}", path.display()))?;
+    let mut reader = BufReader::new(file);
+    let gzipped = reader
+        .fill_buf()
+        .map_err(|e| e.to_string())?
+        .starts_with(&[0x1f, 0x8b]);
+    let reader: Box<dyn Read> = if gzipped {
+        Box::new(GzDecoder::new(reader))
+    } else {
+        Box::new(reader)
+    };
+    BufReader::new(reader)
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| line.as_ref().map_or(true, |line| !line.trim().is_empty()))
+        .map(|(number, line)| {
+            let line = line.map_err(|e| e.to_string())?;
+            serde_json::from_str(&line).map_err(|e| format!("line {}: {e}", number + 1))
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Side {
+    pub requests: usize,
+    /// 5xx responses, plus requests that got no response at all.
+    pub errors: usize,
+    pub p50_ms: u64,
+    pub p95_ms: u64,
+    pub p99_ms: u64,
+}
+
+impl Side {
+    fn new(mut latencies: Vec<u64>, errors: usize) -> Self {
+        latencies.sort_unstable();
+        let quantile = |q: f64| {
+            let rank = ((latencies.len() as f64) * q).ceil() as usize;
+            latencies
+                .get(rank.saturating_sub(1))
+                .copied()
+                .unwrap_or_default()
+        };
+        Self {
+            requests: latencies.len(),
+            errors,
+            p50_ms: quantile(0.5),
+            p95_ms: quantile(0.95),
+            p99_ms: quantile(0.99),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ReplayReport {
+    pub recorded: Side,
+    pub replayed: Side,
+    /// Requests whose replayed status differs from the recorded one.
+    pub status_mismatches: usize,
+    pub elapsed: Duration,
+}
+
+impl ReplayReport {
+    pub fn render(&self) -> String {
+        let row = |name: &str, side: &Side| {
+            format!(
+                "{name:<10}{:>10}{:>10}{:>10}{:>10}{:>10}\n",
+                side.requests, side.errors, side.p50_ms, side.p95_ms, side.p99_ms
+            )
+        };
+        format!(
+            "{:<10}{:>10}{:>10}{:>10}{:>10}{:>10}\n{}{}status mismatches: {}, elapsed: {:.1}s\n",
+            "",
+            "requests",
+            "errors",
+            "p50 ms",
+            "p95 ms",
+            "p99 ms",
+            row("recorded", &self.recorded),
+            row("replayed", &self.replayed),
+            self.status_mismatches,
+            self.elapsed.as_secs_f64()
+        )
+    }
+}
+
+struct Outcome {
+    recorded_status: u16,
+    recorded_ms: u64,
+    /// `None` when no response came back.
+    status: Option<u16>,
+    latency_ms: u64,
+}
+
+/// Replays `entries` against `options.env` and compares the results.
+pub async fn replay(
+    state: &AppState,
+    entries: Vec<RequestLogEntry>,
+    options: &ReplayOptions,
+) -> ReplayReport {
+    let started = Instant::now();
+    let permits = Arc::new(Semaphore::new(options.concurrency));
+    let mut ticks = options
+        .rate
+        .map(|rate| tokio::time::interval(Duration::from_secs_f64(1.0 / rate)));
+    let mut tasks = JoinSet::new();
+    let selected = entries
+        .into_iter()
+        .filter(|entry| {
+            options.methods.is_empty()
+                || options
+                    .methods
+                    .iter()
+                    .any(|m| m.as_str().eq_ignore_ascii_case(&entry.method))
+        })
+        .take(options.limit.unwrap_or(usize::MAX));
+    for entry in selected {
+        if let Some(ticks) = &mut ticks {
+            ticks.tick().await;
+        }
+        let permit = permits.clone().acquire_owned().await.expect("never closed");
+        let state = state.clone();
+        let env = options.env.clone();
+        tasks.spawn(async move {
+            let _permit = permit;
+            replay_one(&state, &env, entry).await
+        });
+    }
+    let mut outcomes = Vec::new();
+    while let Some(outcome) = tasks.join_next().await {
+        outcomes.extend(outcome.ok().flatten());
+    }
+    compare(outcomes, started.elapsed())
+}
+
+async fn replay_one(state: &AppState, env: &str, entry: RequestLogEntry) -> Option<Outcome> {
+    let method = Method::from_bytes(entry.method.as_bytes()).ok()?;
+    // Recorded paths start with the env they came in under.
+    let path = entry
+        .path
+        .strip_prefix(&format!("/{}", entry.env))
+        .unwrap_or(&entry.path);
+    let path_and_query = match &entry.query {
+        Some(query) => format!("{path}?{query}"),
+        None => path.to_string(),
+    };
+    let body = entry.request_body.unwrap_or_default();
+    let mut headers = HeaderMap::new();
+    if serde_json::from_str::<serde_json::Value>(&body).is_ok() {
+        headers.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("application/json"),
+        );
+    }
+    let sent = Instant::now();
+    let result = send_to_upstream(
+        state,
+        env,
+        method,
+        &path_and_query,
+        headers,
+        Bytes::from(body),
+    )
+    .await;
+    Some(Outcome {
+        recorded_status: entry.status,
+        recorded_ms: entry.duration_ms.max(0) as u64,
+        status: result.ok().map(|response| response.status.as_u16()),
+        latency_ms: sent.elapsed().as_millis() as u64,
+    })
+}
+
+fn compare(outcomes: Vec<Outcome>, elapsed: Duration) -> ReplayReport {
+    let recorded_errors = outcomes.iter().filter(|o| o.recorded_status >= 500).count();
+    let replayed_errors = outcomes
+        .iter()
+        .filter(|o| o.status.is_none_or(|status| status >= 500))
+        .count();
+    let status_mismatches = outcomes
+        .iter()
+        .filter(|o| o.status != Some(o.recorded_status))
+        .count();
+    ReplayReport {
+        recorded: Side::new(
+            outcomes.iter().map(|o| o.recorded_ms).collect(),
+            recorded_errors,
+        ),
+        replayed: Side::new(
+            outcomes.iter().map(|o| o.latency_ms).collect(),
+            replayed_errors,
+        ),
+        status_mismatches,
+        elapsed,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(str::to_string).collect()
+    }
+
+    #[test]
+    fn parses_arguments() {
+        let options = ReplayOptions::parse(args(
+            "log.jsonl.gz --rate 20 --concurrency 8 --limit 100 --methods get,post",
+        ))
+        .unwrap();
+        assert_eq!(options.archive, PathBuf::from("log.jsonl.gz"));
+        assert_eq!(options.env, "test");
+        assert_eq!(options.rate, Some(20.0));
+        assert_eq!(options.concurrency, 8);
+        assert_eq!(options.limit, Some(100));
+        assert_eq!(options.methods, [Method::GET, Method::POST]);
+
+        assert!(ReplayOptions::parse(args("")).is_err());
+        assert!(ReplayOptions::parse(args("log.jsonl --concurrency 0")).is_err());
+        assert!(ReplayOptions::parse(args("log.jsonl --speed 2")).is_err());
+    }
+
+    #[test]
+    fn compares_statuses_and_latencies() {
+        let outcome = |recorded_status, status, latency_ms| Outcome {
+            recorded_status,
+            recorded_ms: 100,
+            status,
+            latency_ms,
+        };
+        let report = compare(
+            vec![
+                outcome(200, Some(200), 50),
+                outcome(200, Some(502), 300),
+                outcome(503, None, 10),
+                outcome(404, Some(404), 70),
+            ],
+            Duration::from_secs(1),
+        );
+        assert_eq!(report.recorded.requests, 4);
+        assert_eq!(report.recorded.errors, 1);
+        assert_eq!(report.replayed.errors, 2);
+        assert_eq!(report.status_mismatches, 2);
+        assert_eq!(report.replayed.p50_ms, 50);
+        assert_eq!(report.replayed.p99_ms, 300);
+        assert!(report.render().contains("status mismatches: 2"));
+    }
+}
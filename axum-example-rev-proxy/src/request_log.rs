@@ -0,0 +1,288 @@
+//! Per-request summaries written to a queryable sink, so support can look
+//! up what a supplier returned without grepping logs.
+//!
+//! The SQL sink lives in [`crate::sql_request_log`] (`request_log` feature).
+
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use axum::body::{to_bytes, Body, Bytes};
+use axum::extract::Request;
+use axum::response::Response;
+use hyper::header::HeaderMap;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::error;
+
+use crate::encoding::decode_body;
+use crate::proxy::MAX_BODY_SIZE;
+
+/// Bodies are logged up to this many bytes.
+const MAX_LOGGED_BODY: usize = 64 * 1024;
+
+/// JSON keys whose values are replaced with `"[REDACTED]"` by default.
+pub const DEFAULT_REDACT_KEYS: &[&str] = &[
+    "password",
+    "token",
+    "secret",
+    "api_key",
+    "apikey",
+    "authorization",
+    "card_number",
+    "cvv",
+    "passport_number",
+];
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct RequestLogConfig {
+    /// `sqlite://path/to/log.db` or `postgres://...`.
+    pub url: String,
+    /// Also store (redacted) request and response bodies.
+    pub bodies: bool,
+    pub redact_keys: Vec<String>,
+}
+
+impl RequestLogConfig {
+    pub fn new(url: impl Into<String>) -> Self {
+        Self {
+            url: url.into(),
+            bodies: false,
+            redact_keys: DEFAULT_REDACT_KEYS.iter().map(|k| k.to_string()).collect(),
+        }
+    }
+}
+
+/// Moves aged request log entries to object storage (`archive` feature).
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct ArchiveConfig {
+    /// `s3://bucket/prefix` or `gs://bucket/prefix`; credentials come from
+    /// the usual `AWS_*`/`GOOGLE_*` environment variables.
+    pub url: String,
+    pub interval: Duration,
+    /// Entries older than this are uploaded and removed from the database.
+    pub archive_after: Duration,
+    /// Archives older than this are deleted; kept forever when unset.
+    pub retention: Option<Duration>,
+    /// gzip level 1-9, or 0 for plain JSONL.
+    pub compression_level: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RequestLogEntry {
+    /// Unix time in milliseconds when the request arrived.
+    pub timestamp_ms: i64,
+    pub env: String,
+    pub method: String,
+    /// Inbound path, including the env segment.
+    pub path: String,
+    pub query: Option<String>,
+    pub status: u16,
+    pub duration_ms: i64,
+    pub request_body: Option<String>,
+    pub response_body: Option<String>,
+    /// The correlation ID returned to the caller (the supplier's when it sent one).
+    #[serde(default)]
+    pub correlation_id: Option<String>,
+    /// See [`crate::fingerprint`]; unset when the body was never read.
+    #[serde(default)]
+    pub fingerprint: Option<String>,
+    /// The caller's `X-Client-Tag`, as recorded; see [`crate::client_tag`].
+    #[serde(default)]
+    pub client_tag: Option<String>,
+}
+
+/// Filters for [`RequestLogSink::query`]; unset fields match everything.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct RequestLogQuery {
+    pub from_ms: Option<i64>,
+    pub to_ms: Option<i64>,
+    pub status: Option<u16>,
+    pub path_prefix: Option<String>,
+    pub correlation_id: Option<String>,
+    pub fingerprint: Option<String>,
+    pub client_tag: Option<String>,
+    pub limit: Option<u32>,
+}
+
+#[async_trait]
+pub trait RequestLogSink: Send + Sync {
+    async fn record(&self, entry: RequestLogEntry) -> Result<(), String>;
+    /// Matching entries, newest first.
+    async fn query(&self, query: &RequestLogQuery) -> Result<Vec<RequestLogEntry>, String>;
+    /// Up to `limit` entries from before `before_ms`, oldest first.
+    async fn oldest_before(
+        &self,
+        before_ms: i64,
+        limit: u32,
+    ) -> Result<Vec<RequestLogEntry>, String>;
+    /// Deletes entries at or before `through_ms`, returning how many.
+    async fn delete_through(&self, through_ms: i64) -> Result<u64, String>;
+}
+
+/// Builds entries for the proxy handler and hands them to the sink off the
+/// request path.
+pub struct RequestLog {
+    sink: Arc<dyn RequestLogSink>,
+    bodies: bool,
+    redact_keys: Vec<String>,
+}
+
+impl RequestLog {
+    pub fn new(sink: Arc<dyn RequestLogSink>, bodies: bool, redact_keys: Vec<String>) -> Self {
+        Self {
+            sink,
+            bodies,
+            redact_keys,
+        }
+    }
+
+    pub fn sink(&self) -> &Arc<dyn RequestLogSink> {
+        &self.sink
+    }
+
+    pub fn captures_bodies(&self) -> bool {
+        self.bodies
+    }
+
+    pub fn redact_keys(&self) -> &[String] {
+        &self.redact_keys
+    }
+
+    /// Writes `entry` in the background, adding bodies if enabled.
+    pub fn record(
+        &self,
+        mut entry: RequestLogEntry,
+        request: Option<(&HeaderMap, &Bytes)>,
+        response: Option<(&HeaderMap, &Bytes)>,
+    ) {
+        if self.bodies {
+            entry.request_body =
+                request.map(|(headers, body)| render_body(headers, body, &self.redact_keys));
+            entry.response_body =
+                response.map(|(headers, body)| render_body(headers, body, &self.redact_keys));
+        }
+        let sink = self.sink.clone();
+        tokio::spawn(async move {
+            if let Err(e) = sink.record(entry).await {
+                error!("Failed to write request log entry: {}", e);
+            }
+        });
+    }
+}
+
+/// Decodes the content-coding, redacts JSON and caps the size of a body for storage.
+pub fn render_body(headers: &HeaderMap, body: &Bytes, redact_keys: &[String]) -> String {
+    if body.is_empty() {
+        return String::new();
+    }
+    let decoded = match decode_body(headers, body, MAX_BODY_SIZE) {
+        Ok(decoded) => decoded,
+        Err(e) => return format!("<{} bytes, {e}>", body.len()),
+    };
+
+    if let Ok(mut json) = serde_json::from_slice::<Value>(&decoded) {
+        redact(&mut json, redact_keys);
+        return truncate(json.to_string());
+    }
+    match std::str::from_utf8(&decoded) {
+        Ok(text) => truncate(text.to_string()),
+        Err(_) => format!("<{} bytes, binary>", decoded.len()),
+    }
+}
+
+/// Replaces values under any of `keys` (case-insensitive), at any depth.
+pub fn redact(value: &mut Value, keys: &[String]) {
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map.iter_mut() {
+                if keys.iter().any(|k| k.eq_ignore_ascii_case(key)) {
+                    *value = Value::String("[REDACTED]".to_string());
+                } else {
+                    redact(value, keys);
+                }
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(|item| redact(item, keys)),
+        _ => {}
+    }
+}
+
+fn truncate(mut text: String) -> String {
+    if text.len() > MAX_LOGGED_BODY {
+        let mut end = MAX_LOGGED_BODY;
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+        text.truncate(end);
+        text.push_str("...[truncated]");
+    }
+    text
+}
+
+pub fn now_ms() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as i64
+}
+
+/// Reads the request body so it can be logged, returning an equivalent request.
+pub async fn buffer_request(req: Request, limit: usize) -> (Request, Bytes) {
+    let (parts, body) = req.into_parts();
+    match to_bytes(body, limit).await {
+        Ok(bytes) => (Request::from_parts(parts, Body::from(bytes.clone())), bytes),
+        // Hand the failure on so the proxy still rejects the request.
+        Err(e) => {
+            let body = Body::from_stream(futures_util::stream::once(async { Err::<Bytes, _>(e) }));
+            (Request::from_parts(parts, body), Bytes::new())
+        }
+    }
+}
+
+/// Reads the response body so it can be logged, returning an equivalent response.
+pub async fn buffer_response(response: Response) -> (Response, Bytes) {
+    let (parts, body) = response.into_parts();
+    let bytes = to_bytes(body, usize::MAX).await.unwrap_or_default();
+    (
+        Response::from_parts(parts, Body::from(bytes.clone())),
+        bytes,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn redacts_nested_keys_case_insensitively() {
+        let mut value = json!({
+            "Password": "hunter2",
+            "guest": {"name": "A", "card_number": "4111"},
+            "rooms": [{"token": "t"}]
+        });
+
+        redact(
+            &mut value,
+            &["password".into(), "card_number".into(), "token".into()],
+        );
+
+        assert_eq!(
+            value,
+            json!({
+                "Password": "[REDACTED]",
+                "guest": {"name": "A", "card_number": "[REDACTED]"},
+                "rooms": [{"token": "[REDACTED]"}]
+            })
+        );
+    }
+
+    #[test]
+    fn truncates_on_a_char_boundary() {
+        let text = "é".repeat(MAX_LOGGED_BODY);
+        let truncated = truncate(text);
+        assert!(truncated.ends_with("...[truncated]"));
+        assert!(truncated.len() <= MAX_LOGGED_BODY + "...[truncated]".len());
+    }
+}
@@ -0,0 +1,145 @@
+//! Per-route overrides of the headers returned to callers, e.g. a
+//! `Cache-Control` that replaces the supplier's. CORS has its own rules in
+//! [`crate::cors`].
+
+use std::collections::BTreeMap;
+
+use hyper::header::{HeaderMap, HeaderName, HeaderValue};
+use serde::Deserialize;
+
+/// Sets and removes response headers for inbound paths starting with
+/// `path_prefix` (including the env segment, e.g. `/prod/api/static`).
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+pub struct ResponseHeaderRule {
+    pub path_prefix: String,
+    /// Headers to set, replacing any the upstream sent.
+    #[serde(default)]
+    pub set: BTreeMap<String, String>,
+    #[serde(default)]
+    pub remove: Vec<String>,
+}
+
+struct CompiledRule {
+    path_prefix: String,
+    set: Vec<(HeaderName, HeaderValue)>,
+    remove: Vec<HeaderName>,
+}
+
+impl TryFrom<&ResponseHeaderRule> for CompiledRule {
+    type Error = String;
+
+    fn try_from(rule: &ResponseHeaderRule) -> Result<Self, String> {
+        let name = |name: &str| {
+            HeaderName::from_bytes(name.as_bytes())
+                .map_err(|_| format!("invalid header name {name:?} for {}", rule.path_prefix))
+        };
+        let set = rule
+            .set
+            .iter()
+            .map(|(key, value)| {
+                let value = HeaderValue::from_str(value)
+                    .map_err(|_| format!("invalid value for {key} on {}", rule.path_prefix))?;
+                Ok((name(key)?, value))
+            })
+            .collect::<Result<_, String>>()?;
+        let remove = rule
+            .remove
+            .iter()
+            .map(|key| name(key))
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            path_prefix: rule.path_prefix.clone(),
+            set,
+            remove,
+        })
+    }
+}
+
+/// The configured rules, applied from the shortest matching prefix to the
+/// longest so specific routes override broad ones.
+#[derive(Default)]
+pub struct ResponseHeaders {
+    rules: Vec<CompiledRule>,
+}
+
+impl ResponseHeaders {
+    pub fn new(rules: &[ResponseHeaderRule]) -> Result<Self, String> {
+        let mut rules = rules
+            .iter()
+            .map(CompiledRule::try_from)
+            .collect::<Result<Vec<_>, _>>()?;
+        rules.sort_by_key(|rule| rule.path_prefix.len());
+        Ok(Self { rules })
+    }
+
+    fn matching<'a>(&'a self, path: &'a str) -> impl Iterator<Item = &'a CompiledRule> + 'a {
+        self.rules
+            .iter()
+            .filter(move |rule| path.starts_with(&rule.path_prefix))
+    }
+
+    pub fn applies_to(&self, path: &str) -> bool {
+        self.matching(path).next().is_some()
+    }
+
+    pub fn apply(&self, path: &str, headers: &mut HeaderMap) {
+        for rule in self.matching(path) {
+            for name in &rule.remove {
+                headers.remove(name);
+            }
+            for (name, value) in &rule.set {
+                headers.insert(name.clone(), value.clone());
+            }
+        }
+    }
+}
+
+/// Parses `RESPONSE_HEADER_RULES`: a JSON array of [`ResponseHeaderRule`]s
+/// (JSON because header values such as `Cache-Control` contain commas).
+pub fn parse_response_header_rules(value: &str) -> Result<Vec<ResponseHeaderRule>, String> {
+    if value.trim().is_empty() {
+        return Ok(Vec::new());
+    }
+    let rules: Vec<ResponseHeaderRule> =
+        serde_json::from_str(value).map_err(|e| format!("invalid response header rules: {e}"))?;
+    ResponseHeaders::new(&rules)?;
+    Ok(rules)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header;
+
+    #[test]
+    fn specific_rules_override_broad_ones() {
+        let rules = parse_response_header_rules(
+            r#"[
+                {"path_prefix": "/prod/api/static", "set": {"cache-control": "public, max-age=300"}},
+                {"path_prefix": "/prod/", "set": {"cache-control": "no-store", "x-env": "prod"},
+                 "remove": ["set-cookie"]}
+            ]"#,
+        )
+        .unwrap();
+        let headers = ResponseHeaders::new(&rules).unwrap();
+
+        let mut response = HeaderMap::new();
+        response.insert(header::CACHE_CONTROL, "private".parse().unwrap());
+        response.insert(header::SET_COOKIE, "a=b".parse().unwrap());
+        headers.apply("/prod/api/static/cities", &mut response);
+
+        assert_eq!(response[header::CACHE_CONTROL], "public, max-age=300");
+        assert_eq!(response["x-env"], "prod");
+        assert!(response.get(header::SET_COOKIE).is_none());
+        assert!(!headers.applies_to("/test/api/static"));
+    }
+
+    #[test]
+    fn rejects_invalid_headers() {
+        assert!(
+            parse_response_header_rules(r#"[{"path_prefix": "/", "remove": ["bad name"]}]"#)
+                .is_err()
+        );
+        assert_eq!(parse_response_header_rules(""), Ok(Vec::new()));
+    }
+}
@@ -0,0 +1,179 @@
+//! Recent traffic in per-second buckets, so dashboards can see the current
+//! request rate and error rate rather than lifetime totals.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use hyper::StatusCode;
+use serde::Serialize;
+
+/// Longest window that can be asked for (15 minutes).
+const BUCKETS: u64 = 15 * 60;
+
+/// The windows reported at `/metrics`.
+pub const WINDOWS: [(&str, Duration); 3] = [
+    ("1m", Duration::from_secs(60)),
+    ("5m", Duration::from_secs(5 * 60)),
+    ("15m", Duration::from_secs(15 * 60)),
+];
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Bucket {
+    /// Seconds since the window started; stale buckets are reset on reuse.
+    second: u64,
+    requests: u64,
+    errors: u64,
+    slow: u64,
+    latency_ms_total: u64,
+}
+
+#[derive(Debug)]
+pub struct RollingWindow {
+    started: Instant,
+    /// Requests taking longer than this count as slow.
+    slow_threshold: Option<Duration>,
+    buckets: Mutex<Vec<Bucket>>,
+}
+
+/// Aggregates over the last `window` of traffic.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct WindowStats {
+    pub requests: u64,
+    pub requests_per_second: f64,
+    /// Share of responses with a 5xx status.
+    pub error_rate: f64,
+    /// Share of requests over the slow threshold, if one is set.
+    pub slow_rate: f64,
+    pub avg_latency_ms: f64,
+}
+
+impl Default for RollingWindow {
+    fn default() -> Self {
+        Self {
+            started: Instant::now(),
+            slow_threshold: None,
+            buckets: Mutex::new(vec![Bucket::default(); BUCKETS as usize]),
+        }
+    }
+}
+
+impl RollingWindow {
+    /// Also counts requests slower than `threshold`.
+    pub fn with_slow_threshold(threshold: Duration) -> Self {
+        Self {
+            slow_threshold: Some(threshold),
+            ..Default::default()
+        }
+    }
+
+    pub fn record(&self, status: StatusCode, latency: Duration) {
+        self.record_at(self.now(), status, latency);
+    }
+
+    /// Stats for the last `window`, capped at 15 minutes.
+    pub fn stats(&self, window: Duration) -> WindowStats {
+        self.stats_at(self.now(), window)
+    }
+
+    fn now(&self) -> u64 {
+        self.started.elapsed().as_secs()
+    }
+
+    fn record_at(&self, second: u64, status: StatusCode, latency: Duration) {
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = &mut buckets[(second % BUCKETS) as usize];
+        if bucket.second != second {
+            *bucket = Bucket {
+                second,
+                ..Default::default()
+            };
+        }
+        bucket.requests += 1;
+        if status.is_server_error() {
+            bucket.errors += 1;
+        }
+        if self
+            .slow_threshold
+            .is_some_and(|threshold| latency > threshold)
+        {
+            bucket.slow += 1;
+        }
+        bucket.latency_ms_total += latency.as_millis() as u64;
+    }
+
+    fn stats_at(&self, now: u64, window: Duration) -> WindowStats {
+        let window = window.as_secs().clamp(1, BUCKETS);
+        let buckets = self.buckets.lock().unwrap();
+        let (mut requests, mut errors, mut slow, mut latency_ms_total) = (0, 0, 0, 0);
+        for bucket in buckets.iter() {
+            if bucket.requests > 0 && bucket.second <= now && now - bucket.second < window {
+                requests += bucket.requests;
+                errors += bucket.errors;
+                slow += bucket.slow;
+                latency_ms_total += bucket.latency_ms_total;
+            }
+        }
+        // A process younger than the window has only been up this long.
+        let elapsed = window.min(now + 1);
+        let per_request = |total: u64| match requests {
+            0 => 0.0,
+            n => total as f64 / n as f64,
+        };
+        WindowStats {
+            requests,
+            requests_per_second: requests as f64 / elapsed as f64,
+            error_rate: per_request(errors),
+            slow_rate: per_request(slow),
+            avg_latency_ms: per_request(latency_ms_total),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn aggregates_only_the_requested_window() {
+        let rolling = RollingWindow::default();
+        let ms = Duration::from_millis;
+        // An old burst of errors, then steady traffic.
+        for _ in 0..10 {
+            rolling.record_at(500, StatusCode::BAD_GATEWAY, ms(900));
+        }
+        for second in 1000..1060 {
+            rolling.record_at(second, StatusCode::OK, ms(20));
+            rolling.record_at(second, StatusCode::OK, ms(40));
+        }
+
+        let last_minute = rolling.stats_at(1059, Duration::from_secs(60));
+        assert_eq!(last_minute.requests, 120);
+        assert_eq!(last_minute.requests_per_second, 2.0);
+        assert_eq!(last_minute.error_rate, 0.0);
+        assert_eq!(last_minute.avg_latency_ms, 30.0);
+
+        let last_15m = rolling.stats_at(1059, Duration::from_secs(15 * 60));
+        assert_eq!(last_15m.requests, 130);
+        assert_eq!(last_15m.error_rate, 10.0 / 130.0);
+
+        // The burst's bucket is reused 15 minutes later.
+        rolling.record_at(500 + BUCKETS, StatusCode::OK, ms(10));
+        let later = rolling.stats_at(500 + BUCKETS, Duration::from_secs(60));
+        assert_eq!(later.requests, 1);
+        assert_eq!(later.error_rate, 0.0);
+    }
+
+    #[test]
+    fn counts_requests_over_the_slow_threshold() {
+        let rolling = RollingWindow::with_slow_threshold(Duration::from_millis(100));
+        rolling.record_at(10, StatusCode::OK, Duration::from_millis(100));
+        rolling.record_at(10, StatusCode::OK, Duration::from_millis(101));
+        assert_eq!(rolling.stats_at(10, Duration::from_secs(60)).slow_rate, 0.5);
+        assert_eq!(
+            RollingWindow::default()
+                .stats_at(10, Duration::from_secs(60))
+                .slow_rate,
+            0.0
+        );
+    }
+}
@@ -0,0 +1,70 @@
+//! Tokio runtime figures for `/metrics`, to tell executor saturation apart
+//! from upstream latency. Worker count, live tasks and the global queue are
+//! always reported; per-worker busy time, local queues and the blocking
+//! pool need a build with `RUSTFLAGS="--cfg tokio_unstable"`.
+//!
+//! With several server workers, each has its own runtime and this reports
+//! the one that served the scrape.
+
+use std::fmt::Write;
+
+use tokio::runtime::Handle;
+
+/// Renders the current runtime's metrics in Prometheus text format.
+pub fn render() -> String {
+    let Ok(handle) = Handle::try_current() else {
+        return String::new();
+    };
+    let metrics = handle.metrics();
+    let mut out = String::new();
+    let _ = writeln!(out, "proxy_runtime_workers {}", metrics.num_workers());
+    let _ = writeln!(
+        out,
+        "proxy_runtime_alive_tasks {}",
+        metrics.num_alive_tasks()
+    );
+    let _ = writeln!(
+        out,
+        "proxy_runtime_global_queue_depth {}",
+        metrics.global_queue_depth()
+    );
+    #[cfg(tokio_unstable)]
+    render_unstable(&metrics, &mut out);
+    out
+}
+
+#[cfg(tokio_unstable)]
+fn render_unstable(metrics: &tokio::runtime::RuntimeMetrics, out: &mut String) {
+    for worker in 0..metrics.num_workers() {
+        let _ = writeln!(
+            out,
+            "proxy_runtime_worker_busy_seconds_total{{worker=\"{worker}\"}} {:.3}",
+            metrics.worker_total_busy_duration(worker).as_secs_f64()
+        );
+        let _ = writeln!(
+            out,
+            "proxy_runtime_worker_parks_total{{worker=\"{worker}\"}} {}",
+            metrics.worker_park_count(worker)
+        );
+        let _ = writeln!(
+            out,
+            "proxy_runtime_worker_local_queue_depth{{worker=\"{worker}\"}} {}",
+            metrics.worker_local_queue_depth(worker)
+        );
+    }
+    let _ = writeln!(
+        out,
+        "proxy_runtime_blocking_threads {}",
+        metrics.num_blocking_threads()
+    );
+    let _ = writeln!(
+        out,
+        "proxy_runtime_idle_blocking_threads {}",
+        metrics.num_idle_blocking_threads()
+    );
+    let _ = writeln!(
+        out,
+        "proxy_runtime_blocking_queue_depth {}",
+        metrics.blocking_queue_depth()
+    );
+}
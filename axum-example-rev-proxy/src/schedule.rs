@@ -0,0 +1,481 @@
+//! Requests scheduled to run later, such as retrying a supplier
+//! reconciliation call at 02:00. `POST /admin/schedules` takes the request
+//! and when to send it (`run_at_ms`, Unix milliseconds, or `in_secs`) and
+//! returns a job ID; `GET /admin/schedules` lists the jobs,
+//! `GET /admin/schedules/{id}` shows one with its result and
+//! `DELETE /admin/schedules/{id}` cancels it before it runs.
+//!
+//! Jobs go upstream through the interceptors like any other tooling call,
+//! once, with no retries. They are kept in memory, and in
+//! `SCHEDULED_REQUESTS_PATH` when set so pending jobs survive a restart; a
+//! job that was running when the proxy stopped is marked failed rather than
+//! sent twice. Finished jobs are dropped after a week.
+
+use std::collections::BTreeMap;
+use std::path::{Path as FsPath, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use axum::body::Bytes;
+use axum::extract::{Path, State};
+use axum::{Extension, Json};
+use hyper::header::{HeaderMap, HeaderName, HeaderValue};
+use hyper::{Method, StatusCode};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+use tracing::{error, info, warn};
+
+use crate::app_state::AppState;
+use crate::correlation;
+use crate::proxy::send_to_upstream;
+use crate::rbac::Principal;
+use crate::request_log::{now_ms, render_body};
+
+/// Jobs kept at once, finished ones included.
+const MAX_JOBS: usize = 1000;
+/// How long finished jobs can still be looked up.
+const RETENTION: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+/// How often the scheduler looks for due jobs between wake-ups.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// Longest response body kept in a job's result.
+const MAX_RESULT_BODY: usize = 64 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct ScheduleRequest {
+    pub env: String,
+    #[serde(default = "default_method")]
+    pub method: String,
+    /// Path and query below the env prefix, e.g. `/api/reconcile?day=1`.
+    pub path: String,
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    #[serde(default)]
+    pub body: Option<String>,
+    /// When to send it, in Unix milliseconds.
+    #[serde(default)]
+    pub run_at_ms: Option<i64>,
+    /// Or how many seconds from now.
+    #[serde(default)]
+    pub in_secs: Option<u64>,
+}
+
+fn default_method() -> String {
+    "GET".to_string()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobState {
+    Scheduled,
+    Running,
+    Succeeded,
+    Failed,
+    Cancelled,
+}
+
+/// What came back: the upstream's answer, or why there was none.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct JobResult {
+    pub status: Option<u16>,
+    pub error: Option<String>,
+    /// Redacted as in the request log, and cut at 64 KiB.
+    pub body: String,
+    pub started_ms: i64,
+    pub finished_ms: i64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Job {
+    id: String,
+    created_by: String,
+    created_ms: i64,
+    run_at_ms: i64,
+    state: JobState,
+    env: String,
+    method: String,
+    path: String,
+    headers: BTreeMap<String, String>,
+    body: Option<String>,
+    result: Option<JobResult>,
+}
+
+/// A job as the admin API shows it, without the request's headers and body.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct JobView {
+    pub id: String,
+    pub created_by: String,
+    pub created_ms: i64,
+    pub run_at_ms: i64,
+    pub state: JobState,
+    pub env: String,
+    pub method: String,
+    pub path: String,
+    pub result: Option<JobResult>,
+}
+
+impl From<&Job> for JobView {
+    fn from(job: &Job) -> Self {
+        Self {
+            id: job.id.clone(),
+            created_by: job.created_by.clone(),
+            created_ms: job.created_ms,
+            run_at_ms: job.run_at_ms,
+            state: job.state,
+            env: job.env.clone(),
+            method: job.method.clone(),
+            path: job.path.clone(),
+            result: job.result.clone(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Scheduler {
+    jobs: Mutex<BTreeMap<String, Job>>,
+    path: Option<PathBuf>,
+    redact_keys: Vec<String>,
+    wake: Notify,
+}
+
+impl Scheduler {
+    /// Loads the jobs saved at `path`, if any. `redact_keys` are masked in
+    /// kept response bodies.
+    pub fn new(path: Option<&str>, redact_keys: Vec<String>) -> Self {
+        let path = path.map(PathBuf::from);
+        let mut jobs = path.as_deref().map(load_jobs).unwrap_or_default();
+        for job in jobs.values_mut() {
+            if job.state == JobState::Running {
+                warn!("Scheduled request {} was cut short by a restart", job.id);
+                job.state = JobState::Failed;
+                job.result = Some(JobResult {
+                    status: None,
+                    error: Some("interrupted".to_string()),
+                    body: String::new(),
+                    started_ms: job.run_at_ms,
+                    finished_ms: now_ms(),
+                });
+            }
+        }
+        Self {
+            jobs: Mutex::new(jobs),
+            path,
+            redact_keys,
+            wake: Notify::new(),
+        }
+    }
+
+    /// Schedules `request` on behalf of `principal`.
+    pub fn schedule(&self, request: ScheduleRequest, principal: &str) -> Result<JobView, String> {
+        Method::from_bytes(request.method.as_bytes())
+            .map_err(|_| format!("invalid method {:?}", request.method))?;
+        if !request.path.starts_with('/') {
+            return Err("path must start with /".to_string());
+        }
+        request_headers(&request.headers)?;
+        let now = now_ms();
+        let run_at_ms = match (request.run_at_ms, request.in_secs) {
+            (Some(at), None) => at,
+            (None, Some(secs)) => now + (secs as i64).saturating_mul(1000),
+            _ => return Err("set exactly one of run_at_ms and in_secs".to_string()),
+        };
+        let job = Job {
+            id: correlation::generate(),
+            created_by: principal.to_string(),
+            created_ms: now,
+            run_at_ms,
+            state: JobState::Scheduled,
+            env: request.env,
+            method: request.method.to_ascii_uppercase(),
+            path: request.path,
+            headers: request.headers,
+            body: request.body,
+            result: None,
+        };
+        let view = JobView::from(&job);
+        {
+            let mut jobs = self.jobs.lock().unwrap();
+            if jobs.len() >= MAX_JOBS {
+                return Err(format!("at most {MAX_JOBS} jobs are kept"));
+            }
+            jobs.insert(job.id.clone(), job);
+            self.persist(&jobs);
+        }
+        self.wake.notify_one();
+        Ok(view)
+    }
+
+    /// Newest first.
+    pub fn list(&self) -> Vec<JobView> {
+        let jobs = self.jobs.lock().unwrap();
+        let mut views: Vec<JobView> = jobs.values().map(JobView::from).collect();
+        views.sort_by_key(|view| std::cmp::Reverse(view.created_ms));
+        views
+    }
+
+    pub fn get(&self, id: &str) -> Option<JobView> {
+        self.jobs.lock().unwrap().get(id).map(JobView::from)
+    }
+
+    /// Cancels a job that hasn't started; `None` if there's no such job.
+    pub fn cancel(&self, id: &str) -> Option<Result<JobView, JobState>> {
+        let mut jobs = self.jobs.lock().unwrap();
+        let job = jobs.get_mut(id)?;
+        if job.state != JobState::Scheduled {
+            return Some(Err(job.state));
+        }
+        job.state = JobState::Cancelled;
+        let view = JobView::from(&*job);
+        self.persist(&jobs);
+        Some(Ok(view))
+    }
+
+    /// Starts every due job and drops expired ones.
+    pub fn run_due(&self, app_state: &AppState) {
+        let now = now_ms();
+        let mut jobs = self.jobs.lock().unwrap();
+        let before = jobs.len();
+        jobs.retain(|_, job| {
+            let finished = job.result.as_ref().map_or(job.run_at_ms, |r| r.finished_ms);
+            matches!(job.state, JobState::Scheduled | JobState::Running)
+                || now - finished <= RETENTION.as_millis() as i64
+        });
+        let mut changed = jobs.len() != before;
+        for job in jobs.values_mut() {
+            if job.state != JobState::Scheduled || job.run_at_ms > now {
+                continue;
+            }
+            job.state = JobState::Running;
+            changed = true;
+            let job = job.clone();
+            let state = app_state.clone();
+            tokio::spawn(async move {
+                let result = run(&state, &job).await;
+                state.scheduler.finish(&job.id, result);
+            });
+        }
+        if changed {
+            self.persist(&jobs);
+        }
+    }
+
+    fn finish(&self, id: &str, result: JobResult) {
+        let mut jobs = self.jobs.lock().unwrap();
+        let Some(job) = jobs.get_mut(id) else {
+            return;
+        };
+        job.state = match result.status {
+            Some(status) if status < 400 => JobState::Succeeded,
+            _ => JobState::Failed,
+        };
+        info!(
+            "Scheduled request {} to {} {}: {:?} {:?}",
+            job.id, job.env, job.path, result.status, result.error
+        );
+        job.result = Some(result);
+        self.persist(&jobs);
+    }
+
+    fn persist(&self, jobs: &BTreeMap<String, Job>) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        if let Err(e) = save_jobs(jobs, path) {
+            error!(
+                "Failed to save scheduled requests to {}: {}",
+                path.display(),
+                e
+            );
+        }
+    }
+}
+
+async fn run(state: &AppState, job: &Job) -> JobResult {
+    let started_ms = now_ms();
+    let method = Method::from_bytes(job.method.as_bytes()).unwrap_or(Method::GET);
+    let headers = request_headers(&job.headers).unwrap_or_default();
+    let body = Bytes::from(job.body.clone().unwrap_or_default());
+    let outcome = match state.traffic_pause.check(&job.env) {
+        Ok(()) => send_to_upstream(state, &job.env, method, &job.path, headers, body).await,
+        Err(e) => Err(e),
+    };
+    let (status, error, body) = match outcome {
+        Ok(response) => {
+            let mut body = render_body(
+                &response.headers,
+                &response.body,
+                &state.scheduler.redact_keys,
+            );
+            if body.len() > MAX_RESULT_BODY {
+                let mut end = MAX_RESULT_BODY;
+                while !body.is_char_boundary(end) {
+                    end -= 1;
+                }
+                body.truncate(end);
+            }
+            (Some(response.status.as_u16()), None, body)
+        }
+        Err(e) => (None, Some(e.code().to_string()), String::new()),
+    };
+    JobResult {
+        status,
+        error,
+        body,
+        started_ms,
+        finished_ms: now_ms(),
+    }
+}
+
+fn request_headers(headers: &BTreeMap<String, String>) -> Result<HeaderMap, String> {
+    let mut map = HeaderMap::new();
+    for (name, value) in headers {
+        let name = HeaderName::from_bytes(name.as_bytes())
+            .map_err(|_| format!("invalid header name {name:?}"))?;
+        let value =
+            HeaderValue::from_str(value).map_err(|_| format!("invalid value for {name}"))?;
+        map.insert(name, value);
+    }
+    Ok(map)
+}
+
+fn load_jobs(path: &FsPath) -> BTreeMap<String, Job> {
+    match std::fs::read(path) {
+        Ok(json) => serde_json::from_slice(&json)
+            .unwrap_or_else(|e| panic!("Invalid scheduled requests in {}: {e}", path.display())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+        Err(e) => panic!("Failed to read {}: {e}", path.display()),
+    }
+}
+
+fn save_jobs(jobs: &BTreeMap<String, Job>, path: &FsPath) -> std::io::Result<()> {
+    let json = serde_json::to_vec(jobs).expect("jobs serialize");
+    let temp = path.with_extension("tmp");
+    std::fs::write(&temp, json)?;
+    std::fs::rename(temp, path)
+}
+
+/// Runs scheduled requests as they come due.
+pub fn spawn_scheduler(state: &AppState) -> JoinHandle<()> {
+    let state = state.clone();
+    tokio::spawn(async move {
+        loop {
+            state.scheduler.run_due(&state);
+            tokio::select! {
+                _ = tokio::time::sleep(POLL_INTERVAL) => {}
+                _ = state.scheduler.wake.notified() => {}
+            }
+        }
+    })
+}
+
+/// `POST /admin/schedules`: schedules a request and returns its job.
+pub async fn create_handler(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Json(request): Json<ScheduleRequest>,
+) -> Result<(StatusCode, Json<JobView>), (StatusCode, String)> {
+    if state.env_var_config.upstream_for(&request.env).is_none() {
+        return Err((StatusCode::BAD_REQUEST, "unknown env".to_string()));
+    }
+    let job = state
+        .scheduler
+        .schedule(request, &principal.name)
+        .map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    warn!(
+        target: "audit",
+        "{} scheduled {} {}{} for {} as job {}",
+        principal, job.method, job.env, job.path, job.run_at_ms, job.id
+    );
+    Ok((StatusCode::CREATED, Json(job)))
+}
+
+/// `GET /admin/schedules`: every job, newest first.
+pub async fn list_handler(State(state): State<AppState>) -> Json<Vec<JobView>> {
+    Json(state.scheduler.list())
+}
+
+/// `GET /admin/schedules/{id}`: one job and its result.
+pub async fn get_handler(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Json<JobView>, StatusCode> {
+    state
+        .scheduler
+        .get(&id)
+        .map(Json)
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+/// `DELETE /admin/schedules/{id}`: cancels a job that hasn't run yet.
+pub async fn cancel_handler(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Json<JobView>, StatusCode> {
+    match state.scheduler.cancel(&id) {
+        Some(Ok(job)) => {
+            warn!(target: "audit", "Scheduled request {} cancelled", id);
+            Ok(Json(job))
+        }
+        Some(Err(_)) => Err(StatusCode::CONFLICT),
+        None => Err(StatusCode::NOT_FOUND),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(in_secs: u64) -> ScheduleRequest {
+        ScheduleRequest {
+            env: "test".to_string(),
+            method: "post".to_string(),
+            path: "/reconcile".to_string(),
+            headers: BTreeMap::from([("x-api-key".to_string(), "secret".to_string())]),
+            body: Some("{}".to_string()),
+            run_at_ms: None,
+            in_secs: Some(in_secs),
+        }
+    }
+
+    #[test]
+    fn keeps_jobs_across_restarts() {
+        let path = std::env::temp_dir().join(format!("schedules-{}.json", correlation::generate()));
+        let path_str = path.to_str().unwrap();
+        let scheduler = Scheduler::new(Some(path_str), Vec::new());
+        let kept = scheduler.schedule(request(3600), "ops").unwrap();
+        let cancelled = scheduler.schedule(request(3600), "ops").unwrap();
+        assert_eq!(kept.method, "POST");
+        assert!(scheduler.cancel(&cancelled.id).unwrap().is_ok());
+        assert_eq!(
+            scheduler.cancel(&cancelled.id),
+            Some(Err(JobState::Cancelled))
+        );
+        scheduler
+            .jobs
+            .lock()
+            .unwrap()
+            .get_mut(&kept.id)
+            .unwrap()
+            .state = JobState::Running;
+        scheduler.persist(&scheduler.jobs.lock().unwrap());
+
+        let restarted = Scheduler::new(Some(path_str), Vec::new());
+        let job = restarted.get(&kept.id).unwrap();
+        assert_eq!(job.state, JobState::Failed);
+        assert_eq!(job.result.unwrap().error.as_deref(), Some("interrupted"));
+        assert_eq!(restarted.list().len(), 2);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn rejects_malformed_jobs() {
+        let scheduler = Scheduler::new(None, Vec::new());
+        let mut both = request(1);
+        both.run_at_ms = Some(1);
+        assert!(scheduler.schedule(both, "ops").is_err());
+        let mut relative = request(1);
+        relative.path = "reconcile".to_string();
+        assert!(scheduler.schedule(relative, "ops").is_err());
+        assert!(scheduler.list().is_empty());
+    }
+}
@@ -0,0 +1,388 @@
+//! Rhai request-transformation scripts (behind the `scripting` feature).
+//!
+//! A script sees a `request` map in scope and may modify it in place:
+//!
+//! ```rhai
+//! request.headers["api-key"] = "abc";
+//! if request.json != () { request.json.currency = "INR"; }
+//! request.path = "/v2" + request.path;
+//! ```
+//!
+//! `request` has `method`, `path`, `query`, `headers` (first value per name),
+//! `body` (UTF-8 lossy) and `json` (the parsed body, or `()`). Changes to
+//! `method` are ignored. A changed `json` wins over a changed `body`.
+//!
+//! Scripts are reloaded when the file's modification time changes. A script
+//! that fails to compile on reload is skipped, and not retried until the file
+//! changes again, while the previous version keeps running; a script that
+//! errors or exceeds its limits fails the request.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+
+use async_trait::async_trait;
+use axum::body::Bytes;
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::{StatusCode, Uri};
+use rhai::{Dynamic, Engine, Scope, AST};
+use serde::{Deserialize, Serialize};
+use tracing::{error, info, warn};
+
+use crate::interceptor::{OutboundRequest, RequestInterceptor};
+
+/// Sandbox limits applied to every script run.
+#[derive(Debug, Clone, Copy)]
+pub struct ScriptLimits {
+    pub max_operations: u64,
+    pub max_duration: Duration,
+    pub max_string_size: usize,
+    pub max_array_size: usize,
+    pub max_map_size: usize,
+    pub max_call_levels: usize,
+}
+
+impl Default for ScriptLimits {
+    fn default() -> Self {
+        Self {
+            max_operations: 100_000,
+            max_duration: Duration::from_millis(50),
+            max_string_size: 1024 * 1024,
+            max_array_size: 10_000,
+            max_map_size: 10_000,
+            max_call_levels: 32,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct ScriptRequest {
+    method: String,
+    path: String,
+    query: String,
+    headers: BTreeMap<String, String>,
+    body: String,
+    json: Option<serde_json::Value>,
+}
+
+struct LoadedScript {
+    ast: AST,
+    modified: Option<SystemTime>,
+}
+
+/// A [`RequestInterceptor`] that runs a Rhai script loaded from a file.
+pub struct ScriptInterceptor {
+    path: PathBuf,
+    limits: ScriptLimits,
+    script: RwLock<Arc<LoadedScript>>,
+    /// Modification time of the last version compiled, good or not, so a
+    /// broken one isn't recompiled on every request.
+    last_attempt: Mutex<Option<SystemTime>>,
+}
+
+impl ScriptInterceptor {
+    /// Compiles the script at `path`, failing if it can't be read or parsed.
+    pub fn load(path: impl AsRef<Path>, limits: ScriptLimits) -> Result<Self, String> {
+        let path = path.as_ref().to_path_buf();
+        let script = compile(&path, &limits)?;
+        Ok(Self {
+            path,
+            limits,
+            last_attempt: Mutex::new(script.modified),
+            script: RwLock::new(Arc::new(script)),
+        })
+    }
+
+    /// Returns the current script, recompiling first if the file changed.
+    async fn current(&self) -> Arc<LoadedScript> {
+        let current = self.script.read().unwrap().clone();
+        let modified = tokio::fs::metadata(&self.path)
+            .await
+            .and_then(|m| m.modified())
+            .ok();
+        if modified.is_none() || modified == current.modified {
+            return current;
+        }
+        {
+            let mut last_attempt = self.last_attempt.lock().unwrap();
+            if *last_attempt == modified {
+                return current;
+            }
+            *last_attempt = modified;
+        }
+
+        let path = self.path.clone();
+        let limits = self.limits;
+        let compiled = tokio::task::spawn_blocking(move || compile(&path, &limits))
+            .await
+            .unwrap_or_else(|e| Err(format!("compiling {} panicked: {e}", self.path.display())));
+        match compiled {
+            Ok(script) => {
+                info!("Reloaded request script {}", self.path.display());
+                let script = Arc::new(script);
+                *self.script.write().unwrap() = script.clone();
+                script
+            }
+            Err(e) => {
+                warn!("Keeping previous request script: {}", e);
+                current
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl RequestInterceptor for ScriptInterceptor {
+    async fn on_request(&self, req: &mut OutboundRequest) -> Result<(), StatusCode> {
+        let script = self.current().await;
+        let before = ScriptRequest::from_outbound(req);
+
+        let mut scope = Scope::new();
+        scope.push(
+            "request",
+            rhai::serde::to_dynamic(&before).map_err(|e| {
+                error!("Failed to expose request to script: {}", e);
+                StatusCode::INTERNAL_SERVER_ERROR
+            })?,
+        );
+
+        let engine = engine(&self.limits, Instant::now() + self.limits.max_duration);
+        engine
+            .run_ast_with_scope(&mut scope, &script.ast)
+            .map_err(|e| {
+                error!("Request script {} failed: {}", self.path.display(), e);
+                StatusCode::INTERNAL_SERVER_ERROR
+            })?;
+
+        let after: ScriptRequest = scope
+            .get_value::<Dynamic>("request")
+            .ok_or(StatusCode::INTERNAL_SERVER_ERROR)
+            .and_then(|d| {
+                rhai::serde::from_dynamic(&d).map_err(|e| {
+                    error!("Script left `request` in an invalid shape: {}", e);
+                    StatusCode::INTERNAL_SERVER_ERROR
+                })
+            })?;
+
+        before.apply_changes(&after, req)
+    }
+}
+
+impl ScriptRequest {
+    fn from_outbound(req: &OutboundRequest) -> Self {
+        let mut headers = BTreeMap::new();
+        for (name, value) in req.headers.iter() {
+            headers
+                .entry(name.to_string())
+                .or_insert_with(|| String::from_utf8_lossy(value.as_bytes()).into_owned());
+        }
+        Self {
+            method: req.method.to_string(),
+            path: req.uri.path().to_string(),
+            query: req.uri.query().unwrap_or_default().to_string(),
+            headers,
+            body: String::from_utf8_lossy(&req.body).into_owned(),
+            json: serde_json::from_slice(&req.body).ok(),
+        }
+    }
+
+    /// Writes only what the script changed back into `req`, so untouched
+    /// multi-valued headers and non-UTF-8 bodies survive unchanged.
+    fn apply_changes(&self, after: &Self, req: &mut OutboundRequest) -> Result<(), StatusCode> {
+        if after.path != self.path || after.query != self.query {
+            let authority = req.uri.authority().map(|a| a.as_str()).unwrap_or_default();
+            let scheme = req.uri.scheme_str().unwrap_or("http");
+            let query = if after.query.is_empty() {
+                String::new()
+            } else {
+                format!("?{}", after.query)
+            };
+            req.uri = format!("{scheme}://{authority}{}{query}", after.path)
+                .parse::<Uri>()
+                .map_err(|e| {
+                    error!("Script produced an invalid URI: {}", e);
+                    StatusCode::INTERNAL_SERVER_ERROR
+                })?;
+        }
+
+        for name in self.headers.keys() {
+            if !after.headers.contains_key(name) {
+                req.headers.remove(name.as_str());
+            }
+        }
+        for (name, value) in &after.headers {
+            if self.headers.get(name) == Some(value) {
+                continue;
+            }
+            let name = HeaderName::from_bytes(name.as_bytes());
+            let value = HeaderValue::from_str(value);
+            match (name, value) {
+                (Ok(name), Ok(value)) => {
+                    req.headers.insert(name, value);
+                }
+                _ => {
+                    error!("Script produced an invalid header");
+                    return Err(StatusCode::INTERNAL_SERVER_ERROR);
+                }
+            }
+        }
+
+        if after.json != self.json {
+            let json = after.json.as_ref().unwrap_or(&serde_json::Value::Null);
+            req.body = Bytes::from(serde_json::to_vec(json).unwrap_or_default());
+        } else if after.body != self.body {
+            req.body = Bytes::from(after.body.clone());
+        }
+
+        Ok(())
+    }
+}
+
+fn compile(path: &Path, limits: &ScriptLimits) -> Result<LoadedScript, String> {
+    let modified = std::fs::metadata(path).and_then(|m| m.modified()).ok();
+    let source = std::fs::read_to_string(path)
+        .map_err(|e| format!("failed to read {}: {e}", path.display()))?;
+    let ast = engine(limits, Instant::now())
+        .compile(&source)
+        .map_err(|e| format!("failed to compile {}: {e}", path.display()))?;
+    Ok(LoadedScript { ast, modified })
+}
+
+fn engine(limits: &ScriptLimits, deadline: Instant) -> Engine {
+    let mut engine = Engine::new();
+    engine.set_max_operations(limits.max_operations);
+    engine.set_max_string_size(limits.max_string_size);
+    engine.set_max_array_size(limits.max_array_size);
+    engine.set_max_map_size(limits.max_map_size);
+    engine.set_max_call_levels(limits.max_call_levels);
+    engine.on_progress(move |_| {
+        if Instant::now() > deadline {
+            Some(Dynamic::from("script time limit exceeded"))
+        } else {
+            None
+        }
+    });
+    engine
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::{HeaderMap, Method};
+    use std::io::Write;
+
+    fn outbound(body: &str) -> OutboundRequest {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-keep", HeaderValue::from_static("1"));
+        headers.insert("x-drop", HeaderValue::from_static("1"));
+        OutboundRequest {
+            env: "test".to_string(),
+            method: Method::POST,
+            uri: "http://supplier.example/api/search?page=1".parse().unwrap(),
+            headers,
+            body: Bytes::from(body.to_string()),
+        }
+    }
+
+    fn script(source: &str) -> tempfile::NamedTempFile {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(source.as_bytes()).unwrap();
+        file
+    }
+
+    #[tokio::test]
+    async fn script_rewrites_path_headers_and_json() {
+        let file = script(
+            r#"
+            request.path = "/v2" + request.path;
+            request.headers["api-key"] = "abc";
+            request.headers.remove("x-drop");
+            request.json.currency = "INR";
+            "#,
+        );
+        let interceptor = ScriptInterceptor::load(file.path(), ScriptLimits::default()).unwrap();
+        let mut req = outbound(r#"{"city":"goa"}"#);
+
+        interceptor.on_request(&mut req).await.unwrap();
+
+        assert_eq!(
+            req.uri.to_string(),
+            "http://supplier.example/v2/api/search?page=1"
+        );
+        assert_eq!(req.headers["api-key"], "abc");
+        assert_eq!(req.headers["x-keep"], "1");
+        assert!(req.headers.get("x-drop").is_none());
+        let body: serde_json::Value = serde_json::from_slice(&req.body).unwrap();
+        assert_eq!(body, serde_json::json!({"city": "goa", "currency": "INR"}));
+    }
+
+    #[tokio::test]
+    async fn untouched_body_is_forwarded_byte_for_byte() {
+        let file = script(r#"request.headers["x-seen"] = "1";"#);
+        let interceptor = ScriptInterceptor::load(file.path(), ScriptLimits::default()).unwrap();
+        let raw = r#"{"b": 1.50, "a": 2}"#;
+        let mut req = outbound(raw);
+
+        interceptor.on_request(&mut req).await.unwrap();
+
+        assert_eq!(req.body, raw.as_bytes());
+    }
+
+    #[tokio::test]
+    async fn runaway_script_is_stopped() {
+        let file = script("loop { }");
+        let interceptor = ScriptInterceptor::load(file.path(), ScriptLimits::default()).unwrap();
+
+        let result = interceptor.on_request(&mut outbound("")).await;
+
+        assert_eq!(result, Err(StatusCode::INTERNAL_SERVER_ERROR));
+    }
+
+    #[tokio::test]
+    async fn reloads_when_file_changes_and_keeps_last_good_script() {
+        let file = script(r#"request.headers["x-version"] = "1";"#);
+        let interceptor = ScriptInterceptor::load(file.path(), ScriptLimits::default()).unwrap();
+
+        // Make sure the new mtime differs even on coarse-grained filesystems.
+        let later = SystemTime::now() + Duration::from_secs(5);
+        let mut f = std::fs::File::create(file.path()).unwrap();
+        f.write_all(br#"request.headers["x-version"] = "2";"#)
+            .unwrap();
+        f.set_modified(later).unwrap();
+        drop(f);
+
+        let mut req = outbound("");
+        interceptor.on_request(&mut req).await.unwrap();
+        assert_eq!(req.headers["x-version"], "2");
+
+        let mut f = std::fs::File::create(file.path()).unwrap();
+        f.write_all(b"this is not rhai {").unwrap();
+        f.set_modified(later + Duration::from_secs(5)).unwrap();
+        drop(f);
+
+        let mut req = outbound("");
+        interceptor.on_request(&mut req).await.unwrap();
+        assert_eq!(req.headers["x-version"], "2");
+
+        // The broken version isn't compiled again until the mtime moves on.
+        let mut f = std::fs::File::create(file.path()).unwrap();
+        f.write_all(br#"request.headers["x-version"] = "3";"#)
+            .unwrap();
+        f.set_modified(later + Duration::from_secs(5)).unwrap();
+        drop(f);
+        let mut req = outbound("");
+        interceptor.on_request(&mut req).await.unwrap();
+        assert_eq!(req.headers["x-version"], "2");
+
+        std::fs::File::options()
+            .write(true)
+            .open(file.path())
+            .unwrap()
+            .set_modified(later + Duration::from_secs(10))
+            .unwrap();
+        let mut req = outbound("");
+        interceptor.on_request(&mut req).await.unwrap();
+        assert_eq!(req.headers["x-version"], "3");
+    }
+}
@@ -0,0 +1,640 @@
+//! Inbound connection handling. `axum::serve` keeps idle keep-alive
+//! connections open forever, so our app servers' pools end up holding
+//! sockets we've long forgotten about; this accept loop closes them after
+//! a configurable idle period instead. Socket options for the listener and
+//! accepted connections are set here too.
+//!
+//! For restarts without dropped connections, run under a systemd `.socket`
+//! unit: [`activated_listener`] picks up the socket systemd holds open, and
+//! [`serve_with_shutdown`] drains in-flight requests on SIGTERM while new
+//! connections queue in the kernel for the next process.
+
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use axum::extract::ConnectInfo;
+use axum::middleware::Next;
+use axum::Router;
+use hyper::body::{Body, Frame, Incoming, SizeHint};
+use hyper::Request;
+use hyper_util::rt::{TokioExecutor, TokioIo, TokioTimer};
+use hyper_util::server::conn::auto;
+use serde::Deserialize;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+use tokio::sync::watch;
+use tokio::time::Sleep;
+use tower::ServiceExt;
+use tracing::{debug, info, warn};
+
+use crate::metrics::Metrics;
+
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(75);
+pub const DEFAULT_HEADER_READ_TIMEOUT: Duration = Duration::from_secs(30);
+pub const DEFAULT_BACKLOG: u32 = 1024;
+pub const DEFAULT_DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+/// IPv6 and dual-stack, so IPv4 clients are served too.
+pub const DEFAULT_LISTEN_ADDR: &str = "[::]:80";
+
+/// First file descriptor systemd passes with socket activation.
+#[cfg(unix)]
+const SD_LISTEN_FDS_START: std::os::fd::RawFd = 3;
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct ServerConfig {
+    /// Whether HTTP/1.1 connections are reused across requests.
+    pub keep_alive: bool,
+    /// Closes a connection once it has had no traffic and no request in
+    /// flight for this long. Keep it above the clients' own pool idle
+    /// timeout so they, not we, decide when to drop a connection.
+    pub idle_timeout: Option<Duration>,
+    /// Time a client gets to send a complete request head.
+    pub header_read_timeout: Option<Duration>,
+    /// Sets `TCP_NODELAY` on accepted connections.
+    pub nodelay: bool,
+    /// Sets `SO_REUSEPORT` on the listener so several instances can share a
+    /// port (Unix only).
+    pub reuse_port: bool,
+    /// Pending connections the kernel queues before `accept`.
+    pub backlog: u32,
+    /// Longest a request body may stall between reads.
+    pub read_timeout: Option<Duration>,
+    /// Longest a response write may stall on a client that isn't reading.
+    pub write_timeout: Option<Duration>,
+    /// Accept loops for [`spawn_workers`], each on its own thread.
+    pub workers: usize,
+    /// How long shutdown waits for in-flight requests before giving up.
+    pub drain_timeout: Duration,
+    /// Where the proxy listens unless systemd hands it a socket.
+    pub listen_addr: SocketAddr,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            keep_alive: true,
+            idle_timeout: Some(DEFAULT_IDLE_TIMEOUT),
+            header_read_timeout: Some(DEFAULT_HEADER_READ_TIMEOUT),
+            nodelay: true,
+            reuse_port: false,
+            backlog: DEFAULT_BACKLOG,
+            read_timeout: None,
+            write_timeout: None,
+            workers: 1,
+            drain_timeout: DEFAULT_DRAIN_TIMEOUT,
+            listen_addr: DEFAULT_LISTEN_ADDR.parse().unwrap(),
+        }
+    }
+}
+
+/// Settings for the main Tokio runtime, for hosts where the proxy shares its
+/// CPUs and the one-thread-per-core default is too greedy. Read once at
+/// startup; [`spawn_workers`] runs each worker on its own single-threaded
+/// runtime regardless.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RuntimeConfig {
+    /// Async worker threads; one per core when unset.
+    pub worker_threads: Option<usize>,
+    /// Cap on the blocking pool; Tokio's default (512) when unset.
+    pub max_blocking_threads: Option<usize>,
+    /// Name given to every runtime thread, as seen in `top -H` and panics.
+    pub thread_name: String,
+    /// Stack size for runtime threads; Tokio's default (2 MiB) when unset.
+    pub thread_stack_size: Option<usize>,
+}
+
+pub const DEFAULT_THREAD_NAME: &str = "proxy-runtime";
+
+impl Default for RuntimeConfig {
+    fn default() -> Self {
+        Self {
+            worker_threads: None,
+            max_blocking_threads: None,
+            thread_name: DEFAULT_THREAD_NAME.to_string(),
+            thread_stack_size: None,
+        }
+    }
+}
+
+impl RuntimeConfig {
+    /// Builds a multi-threaded runtime with these settings.
+    pub fn build(&self) -> io::Result<tokio::runtime::Runtime> {
+        let mut builder = tokio::runtime::Builder::new_multi_thread();
+        builder.enable_all().thread_name(self.thread_name.clone());
+        if let Some(threads) = self.worker_threads {
+            builder.worker_threads(threads);
+        }
+        if let Some(threads) = self.max_blocking_threads {
+            builder.max_blocking_threads(threads);
+        }
+        if let Some(bytes) = self.thread_stack_size {
+            builder.thread_stack_size(bytes);
+        }
+        builder.build()
+    }
+}
+
+/// Binds a listener on `addr` with the socket options from `config`.
+pub fn bind(addr: SocketAddr, config: &ServerConfig) -> io::Result<TcpListener> {
+    let socket = match addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    socket.set_reuseaddr(true)?;
+    if config.reuse_port {
+        #[cfg(unix)]
+        socket.set_reuseport(true)?;
+        #[cfg(not(unix))]
+        warn!("SO_REUSEPORT ignored: not supported on this platform");
+    }
+    socket.bind(addr)?;
+    socket.listen(config.backlog)
+}
+
+/// Takes the listening socket systemd passed through socket activation
+/// (`LISTEN_PID`/`LISTEN_FDS`), if there is one for this process.
+#[cfg(unix)]
+pub fn activated_listener() -> io::Result<Option<TcpListener>> {
+    use std::os::fd::FromRawFd;
+
+    let count = listen_fds(
+        std::env::var("LISTEN_PID").ok().as_deref(),
+        std::env::var("LISTEN_FDS").ok().as_deref(),
+        std::process::id(),
+    );
+    if count == 0 {
+        return Ok(None);
+    }
+    // The variables are left set, since changing the environment isn't safe
+    // once the runtime's threads are up. LISTEN_PID names this process, so a
+    // child process never takes the sockets for its own.
+    if count > 1 {
+        warn!(
+            "Socket activation passed {} sockets; using the first",
+            count
+        );
+    }
+    // SAFETY: LISTEN_PID names this process, so systemd handed us ownership
+    // of fd 3 and nothing else in the process has claimed it.
+    let listener = unsafe { std::net::TcpListener::from_raw_fd(SD_LISTEN_FDS_START) };
+    listener.set_nonblocking(true)?;
+    TcpListener::from_std(listener).map(Some)
+}
+
+#[cfg(not(unix))]
+pub fn activated_listener() -> io::Result<Option<TcpListener>> {
+    Ok(None)
+}
+
+/// Number of sockets passed to process `pid`, per the systemd protocol.
+fn listen_fds(listen_pid: Option<&str>, listen_fds: Option<&str>, pid: u32) -> u32 {
+    if listen_pid.and_then(|value| value.parse::<u32>().ok()) != Some(pid) {
+        return 0;
+    }
+    listen_fds.and_then(|value| value.parse().ok()).unwrap_or(0)
+}
+
+/// Resolves on SIGTERM or Ctrl-C.
+pub async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            warn!("Failed to listen for Ctrl-C: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(e) => {
+                warn!("Failed to listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+}
+
+/// Serves `app` on `listener` until the process exits. Handlers see the
+/// peer address as `ConnectInfo<SocketAddr>`, as with
+/// `into_make_service_with_connect_info`.
+pub async fn serve(listener: TcpListener, app: Router, config: ServerConfig) {
+    serve_with_shutdown(listener, app, config, std::future::pending()).await
+}
+
+/// Like [`serve`], but once `shutdown` resolves stops accepting, closes
+/// connections as their in-flight requests finish, and returns when all are
+/// closed or after `config.drain_timeout`.
+pub async fn serve_with_shutdown(
+    listener: TcpListener,
+    app: Router,
+    config: ServerConfig,
+    shutdown: impl Future<Output = ()>,
+) {
+    let config = Arc::new(config);
+    let (drain_tx, drain_rx) = watch::channel(false);
+    tokio::pin!(shutdown);
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = &mut shutdown => break,
+        };
+        let (stream, peer) = match accepted {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                // Usually fd exhaustion; back off rather than spin.
+                warn!("Failed to accept connection: {}", e);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        if let Err(e) = stream.set_nodelay(config.nodelay) {
+            warn!("Failed to set TCP_NODELAY for {}: {}", peer, e);
+        }
+        tokio::spawn(serve_connection(
+            stream,
+            peer,
+            app.clone(),
+            config.clone(),
+            drain_rx.clone(),
+        ));
+    }
+
+    drop(listener);
+    drop(drain_rx);
+    drain_tx.send_replace(true);
+    info!(
+        "Shutting down; draining {} connections",
+        drain_tx.receiver_count()
+    );
+    // Each connection holds a receiver until it closes.
+    if tokio::time::timeout(config.drain_timeout, drain_tx.closed())
+        .await
+        .is_err()
+    {
+        warn!(
+            "Drain timed out with {} connections still open",
+            drain_tx.receiver_count()
+        );
+    }
+}
+
+/// Runs `config.workers` accept loops, each on its own single-threaded
+/// runtime and `SO_REUSEPORT` listener, so the kernel spreads connections
+/// across cores. Workers share `app`'s state, so `/metrics` covers all of
+/// them; `metrics` also gets per-worker request counts.
+///
+/// Workers drain and exit on SIGTERM or Ctrl-C. Must be called within a
+/// Tokio runtime. Returns the bound address (useful
+/// with port 0) once every listener is bound.
+pub fn spawn_workers(
+    addr: SocketAddr,
+    app: Router,
+    config: ServerConfig,
+    metrics: Arc<Metrics>,
+) -> io::Result<(SocketAddr, Vec<JoinHandle<()>>)> {
+    let config = ServerConfig {
+        reuse_port: true,
+        ..config
+    };
+    let mut addr = addr;
+    let mut handles = Vec::with_capacity(config.workers);
+    for worker in 0..config.workers.max(1) {
+        let listener = bind(addr, &config)?;
+        // Later workers join whatever port the first one got.
+        addr = listener.local_addr()?;
+        let listener = listener.into_std()?;
+        let metrics = metrics.clone();
+        let app = app.clone().layer(axum::middleware::from_fn(
+            move |req: Request<axum::body::Body>, next: Next| {
+                metrics.record_worker_request(worker);
+                next.run(req)
+            },
+        ));
+        let config = config.clone();
+        let handle = std::thread::Builder::new()
+            .name(format!("proxy-worker-{worker}"))
+            .spawn(move || {
+                let runtime = tokio::runtime::Builder::new_current_thread()
+                    .enable_all()
+                    .build()
+                    .expect("Failed to build worker runtime");
+                runtime.block_on(async move {
+                    let listener =
+                        TcpListener::from_std(listener).expect("Failed to register listener");
+                    serve_with_shutdown(listener, app, config, shutdown_signal()).await
+                });
+            })?;
+        handles.push(handle);
+    }
+    Ok((addr, handles))
+}
+
+async fn serve_connection(
+    stream: TcpStream,
+    peer: SocketAddr,
+    app: Router,
+    config: Arc<ServerConfig>,
+    mut drain: watch::Receiver<bool>,
+) {
+    let activity = Arc::new(Activity::new());
+    let io = TokioIo::new(TrackedIo {
+        inner: stream,
+        activity: activity.clone(),
+        write_deadline: Deadline::new(config.write_timeout),
+    });
+    let read_timeout = config.read_timeout;
+    let service = {
+        let activity = activity.clone();
+        hyper::service::service_fn(move |request: Request<Incoming>| {
+            let mut request = request.map(|body| TimedBody {
+                inner: body,
+                deadline: Deadline::new(read_timeout),
+            });
+            request.extensions_mut().insert(ConnectInfo(peer));
+            let in_flight = InFlight::start(&activity);
+            let app = app.clone();
+            async move {
+                let response = app.oneshot(request).await;
+                drop(in_flight);
+                response
+            }
+        })
+    };
+
+    let mut builder = auto::Builder::new(TokioExecutor::new());
+    builder
+        .http1()
+        .timer(TokioTimer::new())
+        .keep_alive(config.keep_alive)
+        .header_read_timeout(config.header_read_timeout);
+    let connection = builder.serve_connection(io, service);
+    tokio::pin!(connection);
+
+    let idle_timeout = config.idle_timeout.unwrap_or(Duration::MAX);
+    let mut ticks = tokio::time::interval(
+        config
+            .idle_timeout
+            .map_or(Duration::from_secs(3600), |timeout| {
+                (timeout / 4).max(Duration::from_millis(10))
+            }),
+    );
+    let mut closing = false;
+    loop {
+        tokio::select! {
+            result = connection.as_mut() => {
+                if let Err(e) = result {
+                    debug!("Connection from {} ended with an error: {}", peer, e);
+                }
+                return;
+            }
+            // Also fires if the server went away without draining.
+            _ = drain.wait_for(|draining| *draining), if !closing => {
+                connection.as_mut().graceful_shutdown();
+                closing = true;
+            }
+            _ = ticks.tick(), if !closing => {
+                if activity.idle_for() >= idle_timeout {
+                    debug!("Closing connection from {} after {:?} idle", peer, idle_timeout);
+                    connection.as_mut().graceful_shutdown();
+                    closing = true;
+                }
+            }
+        }
+    }
+}
+
+/// When a connection last moved bytes, and how many requests it is serving.
+struct Activity {
+    opened: Instant,
+    last_ms: AtomicU64,
+    in_flight: AtomicUsize,
+}
+
+impl Activity {
+    fn new() -> Self {
+        Self {
+            opened: Instant::now(),
+            last_ms: AtomicU64::new(0),
+            in_flight: AtomicUsize::new(0),
+        }
+    }
+
+    fn touch(&self) {
+        let elapsed = self.opened.elapsed().as_millis() as u64;
+        self.last_ms.store(elapsed, Ordering::Relaxed);
+    }
+
+    /// Zero while a request is in flight, so slow upstreams don't count as idle.
+    fn idle_for(&self) -> Duration {
+        if self.in_flight.load(Ordering::Relaxed) > 0 {
+            return Duration::ZERO;
+        }
+        let last = Duration::from_millis(self.last_ms.load(Ordering::Relaxed));
+        self.opened.elapsed().saturating_sub(last)
+    }
+}
+
+struct InFlight(Arc<Activity>);
+
+impl InFlight {
+    fn start(activity: &Arc<Activity>) -> Self {
+        activity.in_flight.fetch_add(1, Ordering::Relaxed);
+        Self(activity.clone())
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.touch();
+        self.0.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Returned when a request body stalls for longer than the read timeout.
+#[derive(Debug)]
+pub struct ReadTimeout;
+
+impl fmt::Display for ReadTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("timed out reading the request body")
+    }
+}
+
+impl std::error::Error for ReadTimeout {}
+
+/// Limits how long one operation may stay pending; restarted whenever the
+/// operation makes progress.
+struct Deadline {
+    limit: Option<Duration>,
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl Deadline {
+    fn new(limit: Option<Duration>) -> Self {
+        Self { limit, sleep: None }
+    }
+
+    /// Call when the operation is pending; true once it has been for `limit`.
+    fn expired(&mut self, cx: &mut Context<'_>) -> bool {
+        let Some(limit) = self.limit else {
+            return false;
+        };
+        let sleep = self
+            .sleep
+            .get_or_insert_with(|| Box::pin(tokio::time::sleep(limit)));
+        sleep.as_mut().poll(cx).is_ready()
+    }
+
+    fn reset(&mut self) {
+        self.sleep = None;
+    }
+}
+
+/// A request body that fails with [`ReadTimeout`] when it stalls.
+struct TimedBody {
+    inner: Incoming,
+    deadline: Deadline,
+}
+
+impl Body for TimedBody {
+    type Data = axum::body::Bytes;
+    type Error = Box<dyn std::error::Error + Send + Sync>;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        match Pin::new(&mut self.inner).poll_frame(cx) {
+            Poll::Pending if self.deadline.expired(cx) => {
+                Poll::Ready(Some(Err(ReadTimeout.into())))
+            }
+            Poll::Pending => Poll::Pending,
+            Poll::Ready(frame) => {
+                self.deadline.reset();
+                Poll::Ready(frame.map(|frame| frame.map_err(Into::into)))
+            }
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+/// A socket that records activity on every read and write, and fails writes
+/// that stall past the write timeout.
+struct TrackedIo {
+    inner: TcpStream,
+    activity: Arc<Activity>,
+    write_deadline: Deadline,
+}
+
+impl TrackedIo {
+    fn track_write<T>(
+        &mut self,
+        cx: &mut Context<'_>,
+        result: Poll<io::Result<T>>,
+    ) -> Poll<io::Result<T>> {
+        match result {
+            Poll::Pending if self.write_deadline.expired(cx) => Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "timed out writing the response",
+            ))),
+            Poll::Pending => Poll::Pending,
+            Poll::Ready(result) => {
+                self.write_deadline.reset();
+                Poll::Ready(result)
+            }
+        }
+    }
+}
+
+impl AsyncRead for TrackedIo {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if buf.filled().len() > before {
+            self.activity.touch();
+        }
+        result
+    }
+}
+
+impl AsyncWrite for TrackedIo {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let result = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if matches!(result, Poll::Ready(Ok(n)) if n > 0) {
+            self.activity.touch();
+        }
+        self.track_write(cx, result)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let result = Pin::new(&mut self.inner).poll_flush(cx);
+        self.track_write(cx, result)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let result = Pin::new(&mut self.inner).poll_write_vectored(cx, bufs);
+        if matches!(result, Poll::Ready(Ok(n)) if n > 0) {
+            self.activity.touch();
+        }
+        self.track_write(cx, result)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_takes_sockets_meant_for_this_process() {
+        assert_eq!(listen_fds(Some("42"), Some("1"), 42), 1);
+        assert_eq!(listen_fds(Some("41"), Some("1"), 42), 0);
+        assert_eq!(listen_fds(None, Some("1"), 42), 0);
+        assert_eq!(listen_fds(Some("42"), None, 42), 0);
+        assert_eq!(listen_fds(Some("42"), Some("x"), 42), 0);
+    }
+}
@@ -0,0 +1,242 @@
+//! Per-upstream service level objectives: how much of each upstream's error
+//! budget recent traffic is burning, served at `/slo` and as burn-rate
+//! gauges on `/metrics`.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use axum::extract::State;
+use axum::Json;
+use hyper::StatusCode;
+use serde::{Deserialize, Serialize};
+
+use crate::app_state::AppState;
+use crate::rolling::{RollingWindow, WindowStats, WINDOWS};
+
+/// Objectives for the upstream behind `/{env}/...`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct SloRule {
+    pub env: String,
+    /// Latency objective: `latency_target` of requests finish within this.
+    pub latency_ms: Option<u64>,
+    #[serde(default = "default_latency_target")]
+    pub latency_target: f64,
+    /// Error objective: at most this share of responses are 5xx.
+    pub max_error_rate: Option<f64>,
+}
+
+fn default_latency_target() -> f64 {
+    0.99
+}
+
+/// Parses `SLO_RULES`, a JSON array of [`SloRule`]s.
+pub fn parse_slo_rules(value: &str) -> Result<Vec<SloRule>, String> {
+    if value.trim().is_empty() {
+        return Ok(Vec::new());
+    }
+    serde_json::from_str(value).map_err(|e| format!("invalid SLO rules: {e}"))
+}
+
+/// Compliance over one window. A burn rate of 1 spends the error budget
+/// exactly as fast as the objective allows; above 1 it runs out early.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SloWindowReport {
+    pub requests: u64,
+    pub error_rate: f64,
+    pub slow_rate: f64,
+    pub error_burn_rate: Option<f64>,
+    pub latency_burn_rate: Option<f64>,
+    /// Share of the budget left; negative once overspent.
+    pub budget_remaining: f64,
+    pub compliant: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SloReport {
+    pub env: String,
+    pub latency_ms: Option<u64>,
+    pub latency_target: f64,
+    pub max_error_rate: Option<f64>,
+    /// Keyed by window name (`1m`, `5m`, `15m`).
+    pub windows: BTreeMap<&'static str, SloWindowReport>,
+    /// Since the process started.
+    pub lifetime: SloWindowReport,
+}
+
+struct Objective {
+    rule: SloRule,
+    recent: RollingWindow,
+    lifetime: Mutex<Totals>,
+}
+
+#[derive(Default)]
+struct Totals {
+    requests: u64,
+    errors: u64,
+    slow: u64,
+}
+
+pub struct SloTracker {
+    objectives: Vec<Objective>,
+}
+
+impl SloTracker {
+    pub fn new(rules: Vec<SloRule>) -> Self {
+        let objectives = rules
+            .into_iter()
+            .map(|rule| Objective {
+                recent: match rule.latency_ms {
+                    Some(ms) => RollingWindow::with_slow_threshold(Duration::from_millis(ms)),
+                    None => RollingWindow::default(),
+                },
+                lifetime: Mutex::default(),
+                rule,
+            })
+            .collect();
+        Self { objectives }
+    }
+
+    pub fn record(&self, env: &str, status: StatusCode, latency: Duration) {
+        for objective in self.objectives.iter().filter(|o| o.rule.env == env) {
+            objective.recent.record(status, latency);
+            let mut totals = objective.lifetime.lock().unwrap();
+            totals.requests += 1;
+            if status.is_server_error() {
+                totals.errors += 1;
+            }
+            if objective
+                .rule
+                .latency_ms
+                .is_some_and(|ms| latency > Duration::from_millis(ms))
+            {
+                totals.slow += 1;
+            }
+        }
+    }
+
+    pub fn report(&self) -> Vec<SloReport> {
+        self.objectives
+            .iter()
+            .map(|objective| {
+                let rule = &objective.rule;
+                let windows = WINDOWS
+                    .iter()
+                    .map(|(name, window)| {
+                        (*name, compliance(rule, objective.recent.stats(*window)))
+                    })
+                    .collect();
+                let lifetime = {
+                    let totals = objective.lifetime.lock().unwrap();
+                    let rate = |count: u64| count as f64 / totals.requests.max(1) as f64;
+                    WindowStats {
+                        requests: totals.requests,
+                        error_rate: rate(totals.errors),
+                        slow_rate: rate(totals.slow),
+                        ..Default::default()
+                    }
+                };
+                SloReport {
+                    env: rule.env.clone(),
+                    latency_ms: rule.latency_ms,
+                    latency_target: rule.latency_target,
+                    max_error_rate: rule.max_error_rate,
+                    windows,
+                    lifetime: compliance(rule, lifetime),
+                }
+            })
+            .collect()
+    }
+
+    /// Burn rates in the Prometheus text format, appended to `/metrics`.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        for report in self.report() {
+            for (window, compliance) in &report.windows {
+                let burns = [
+                    ("errors", compliance.error_burn_rate),
+                    ("latency", compliance.latency_burn_rate),
+                ];
+                for (slo, burn) in burns {
+                    if let Some(burn) = burn {
+                        let _ = writeln!(
+                            out,
+                            "proxy_slo_burn_rate{{env=\"{}\",slo=\"{slo}\",window=\"{window}\"}} {burn}",
+                            report.env
+                        );
+                    }
+                }
+            }
+        }
+        out
+    }
+}
+
+fn compliance(rule: &SloRule, stats: WindowStats) -> SloWindowReport {
+    let error_burn_rate = rule
+        .max_error_rate
+        .filter(|max| *max > 0.0)
+        .map(|max| stats.error_rate / max);
+    let latency_burn_rate = rule
+        .latency_ms
+        .filter(|_| rule.latency_target < 1.0)
+        .map(|_| stats.slow_rate / (1.0 - rule.latency_target));
+    let worst = error_burn_rate
+        .into_iter()
+        .chain(latency_burn_rate)
+        .fold(0.0, f64::max);
+    SloWindowReport {
+        requests: stats.requests,
+        error_rate: stats.error_rate,
+        slow_rate: stats.slow_rate,
+        error_burn_rate,
+        latency_burn_rate,
+        budget_remaining: 1.0 - worst,
+        compliant: worst <= 1.0,
+    }
+}
+
+/// `GET /slo`: compliance with each configured objective.
+pub async fn slo_handler(State(state): State<AppState>) -> Json<Vec<SloReport>> {
+    Json(state.slo.report())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn burn_rates_compare_against_each_objective() {
+        let tracker = SloTracker::new(
+            parse_slo_rules(
+                r#"[{"env": "prod", "latency_ms": 100, "latency_target": 0.5,
+                     "max_error_rate": 0.125}]"#,
+            )
+            .unwrap(),
+        );
+        let ms = Duration::from_millis;
+        for _ in 0..5 {
+            tracker.record("prod", StatusCode::OK, ms(10));
+            tracker.record("prod", StatusCode::OK, ms(10));
+            tracker.record("prod", StatusCode::OK, ms(500));
+            tracker.record("prod", StatusCode::BAD_GATEWAY, ms(10));
+            tracker.record("test", StatusCode::BAD_GATEWAY, ms(10));
+        }
+
+        let report = &tracker.report()[0];
+        let lifetime = &report.lifetime;
+        assert_eq!(lifetime.requests, 20);
+        // A quarter are errors against 12.5% allowed, and a quarter are slow
+        // against half allowed.
+        assert_eq!(lifetime.error_burn_rate, Some(2.0));
+        assert_eq!(lifetime.latency_burn_rate, Some(0.5));
+        assert_eq!(lifetime.budget_remaining, -1.0);
+        assert!(!lifetime.compliant);
+        assert_eq!(report.windows["1m"], *lifetime);
+
+        assert!(tracker
+            .render()
+            .contains("proxy_slo_burn_rate{env=\"prod\",slo=\"errors\",window=\"5m\"} 2"));
+    }
+}
@@ -0,0 +1,107 @@
+//! [`Mailer`] sending through an SMTP provider with lettre (`smtp` feature).
+
+use async_trait::async_trait;
+use lettre::message::{Mailbox, MultiPart};
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+
+use crate::email::{EmailRequest, MailError, Mailer};
+
+pub struct SmtpMailer {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+}
+
+impl SmtpMailer {
+    /// Connects lazily, on the first message.
+    pub fn from_url(url: &str) -> Result<Self, String> {
+        let transport = AsyncSmtpTransport::<Tokio1Executor>::from_url(url)
+            .map_err(|e| e.to_string())?
+            .build();
+        Ok(Self { transport })
+    }
+}
+
+#[async_trait]
+impl Mailer for SmtpMailer {
+    async fn send(&self, email: &EmailRequest) -> Result<(), MailError> {
+        let message = build_message(email)?;
+        self.transport.send(message).await.map_err(|e| {
+            if e.is_permanent() {
+                MailError::Rejected(e.to_string())
+            } else {
+                MailError::Failed(e.to_string())
+            }
+        })?;
+        Ok(())
+    }
+}
+
+fn build_message(email: &EmailRequest) -> Result<Message, MailError> {
+    let mailbox = |address: &str| {
+        address
+            .parse::<Mailbox>()
+            .map_err(|e| MailError::Invalid(format!("{address:?}: {e}")))
+    };
+    let mut builder = Message::builder()
+        .from(mailbox(&email.from)?)
+        .subject(&email.subject);
+    for to in &email.to {
+        builder = builder.to(mailbox(to)?);
+    }
+    for cc in &email.cc {
+        builder = builder.cc(mailbox(cc)?);
+    }
+    for bcc in &email.bcc {
+        builder = builder.bcc(mailbox(bcc)?);
+    }
+    if let Some(reply_to) = &email.reply_to {
+        builder = builder.reply_to(mailbox(reply_to)?);
+    }
+    let built = match (&email.text, &email.html) {
+        (Some(text), Some(html)) => builder.multipart(MultiPart::alternative_plain_html(
+            text.clone(),
+            html.clone(),
+        )),
+        (None, Some(html)) => builder.singlepart(lettre::message::SinglePart::html(html.clone())),
+        (text, None) => builder.singlepart(lettre::message::SinglePart::plain(
+            text.clone().unwrap_or_default(),
+        )),
+    };
+    built.map_err(|e| MailError::Invalid(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builds_alternative_messages() {
+        let message = build_message(&EmailRequest {
+            from: "Estate <bookings@estate.example>".to_string(),
+            to: vec!["guest@example.com".to_string()],
+            cc: Vec::new(),
+            bcc: vec!["audit@estate.example".to_string()],
+            reply_to: None,
+            subject: "Your booking".to_string(),
+            text: Some("Confirmed".to_string()),
+            html: Some("<p>Confirmed</p>".to_string()),
+        })
+        .unwrap();
+        let formatted = String::from_utf8(message.formatted()).unwrap();
+        assert!(formatted.contains("Subject: Your booking"));
+        assert!(formatted.contains("multipart/alternative"));
+        assert!(!formatted.contains("audit@estate.example"));
+        assert_eq!(message.envelope().to().len(), 2);
+
+        let invalid = build_message(&EmailRequest {
+            from: "not an address".to_string(),
+            to: vec!["guest@example.com".to_string()],
+            cc: Vec::new(),
+            bcc: Vec::new(),
+            reply_to: None,
+            subject: String::new(),
+            text: Some(String::new()),
+            html: None,
+        });
+        assert!(matches!(invalid, Err(MailError::Invalid(_))));
+    }
+}
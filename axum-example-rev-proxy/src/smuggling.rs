@@ -0,0 +1,145 @@
+//! Refuses requests whose framing or routing headers could be read two ways.
+//! The proxy buffers the body and rebuilds the upstream request, which hides
+//! such ambiguity from the supplier without settling it, so an intermediary
+//! in front of the proxy may have seen a different request than we did.
+//! Rejecting them keeps the two views from diverging.
+//!
+//! Obsolete line folding never gets this far: the HTTP parser refuses it.
+
+use std::fmt;
+
+use axum::extract::{Request, State};
+use axum::middleware::Next;
+use axum::response::Response;
+use hyper::header::{self, HeaderName};
+use hyper::HeaderMap;
+use tracing::warn;
+
+use crate::app_state::AppState;
+use crate::error::ProxyError;
+
+/// Headers that may appear only once, because intermediaries disagree on
+/// which copy wins.
+const SINGLE_VALUED: [HeaderName; 3] = [header::HOST, header::CONTENT_LENGTH, header::CONTENT_TYPE];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Ambiguity {
+    /// Both `Content-Length` and `Transfer-Encoding`.
+    LengthAndEncoding,
+    /// A `Transfer-Encoding` other than a single final `chunked`.
+    Encoding(String),
+    /// A single-valued header sent more than once with different values.
+    Duplicate(HeaderName),
+}
+
+impl fmt::Display for Ambiguity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::LengthAndEncoding => f.write_str("both content-length and transfer-encoding"),
+            Self::Encoding(value) => write!(f, "transfer-encoding {value:?}"),
+            Self::Duplicate(name) => write!(f, "conflicting {name} headers"),
+        }
+    }
+}
+
+pub fn check(headers: &HeaderMap) -> Result<(), Ambiguity> {
+    let encodings: Vec<_> = headers.get_all(header::TRANSFER_ENCODING).iter().collect();
+    if !encodings.is_empty() {
+        if headers.contains_key(header::CONTENT_LENGTH) {
+            return Err(Ambiguity::LengthAndEncoding);
+        }
+        let joined = encodings
+            .iter()
+            .map(|value| String::from_utf8_lossy(value.as_bytes()))
+            .collect::<Vec<_>>()
+            .join(",");
+        let codings: Vec<_> = joined.split(',').map(str::trim).collect();
+        if codings.len() != 1 || !codings[0].eq_ignore_ascii_case("chunked") {
+            return Err(Ambiguity::Encoding(joined));
+        }
+    }
+    for name in SINGLE_VALUED {
+        let mut values = headers.get_all(&name).iter();
+        if let Some(first) = values.next() {
+            if values.any(|value| value != first) {
+                return Err(Ambiguity::Duplicate(name));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Middleware answering 400 to requests with ambiguous framing or routing.
+pub async fn enforce(State(state): State<AppState>, req: Request, next: Next) -> Response {
+    match check(req.headers()) {
+        Ok(()) => next.run(req).await,
+        Err(ambiguity) => {
+            warn!(
+                "Rejected ambiguous request to {}: {}",
+                req.uri().path(),
+                ambiguity
+            );
+            let correlation_id = state.correlation.read(req.headers()).unwrap_or_default();
+            ProxyError::AmbiguousRequest.to_response(&correlation_id)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(
+                HeaderName::from_bytes(name.as_bytes()).unwrap(),
+                value.parse().unwrap(),
+            );
+        }
+        headers
+    }
+
+    #[test]
+    fn flags_conflicting_framing_and_duplicates() {
+        assert_eq!(check(&headers(&[("content-length", "5")])), Ok(()));
+        assert_eq!(check(&headers(&[("transfer-encoding", "Chunked")])), Ok(()));
+        assert_eq!(
+            check(&headers(&[
+                ("content-length", "5"),
+                ("content-length", "5")
+            ])),
+            Ok(())
+        );
+
+        assert_eq!(
+            check(&headers(&[
+                ("content-length", "5"),
+                ("transfer-encoding", "chunked")
+            ])),
+            Err(Ambiguity::LengthAndEncoding)
+        );
+        assert_eq!(
+            check(&headers(&[("transfer-encoding", "gzip, chunked")])),
+            Err(Ambiguity::Encoding("gzip, chunked".to_string()))
+        );
+        assert_eq!(
+            check(&headers(&[
+                ("transfer-encoding", "chunked"),
+                ("transfer-encoding", "chunked")
+            ])),
+            Err(Ambiguity::Encoding("chunked,chunked".to_string()))
+        );
+        assert_eq!(
+            check(&headers(&[("host", "a.example"), ("host", "b.example")])),
+            Err(Ambiguity::Duplicate(header::HOST))
+        );
+        assert_eq!(
+            check(&headers(&[
+                ("content-length", "5"),
+                ("content-length", "6")
+            ])),
+            Err(Ambiguity::Duplicate(header::CONTENT_LENGTH))
+        );
+    }
+}
@@ -0,0 +1,295 @@
+//! SOAP envelopes for supplier endpoints that only speak SOAP: outbound XML
+//! bodies are wrapped in a configured envelope with the action header, and
+//! the body is taken back out of response envelopes, so callers never build
+//! or parse SOAP themselves.
+//!
+//! Combined with [`crate::xml_json`], a caller's JSON is converted to XML and
+//! then wrapped, and a response is unwrapped and then converted.
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use hyper::header::{self, HeaderName, HeaderValue};
+use hyper::StatusCode;
+use quick_xml::events::Event;
+use quick_xml::Reader;
+use serde::Deserialize;
+use tracing::warn;
+
+use crate::interceptor::{
+    Interceptors, OutboundRequest, RequestInterceptor, ResponseInterceptor, UpstreamResponse,
+};
+use crate::payload_size::content_family;
+use crate::xml_json::{XmlJson, XmlJsonRule};
+
+const SOAP_ACTION: HeaderName = HeaderName::from_static("soapaction");
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+pub enum SoapVersion {
+    #[default]
+    #[serde(rename = "1.1")]
+    Soap11,
+    #[serde(rename = "1.2")]
+    Soap12,
+}
+
+impl SoapVersion {
+    fn namespace(self) -> &'static str {
+        match self {
+            Self::Soap11 => "http://schemas.xmlsoap.org/soap/envelope/",
+            Self::Soap12 => "http://www.w3.org/2003/05/soap-envelope",
+        }
+    }
+}
+
+/// Wraps request bodies and unwraps responses on inbound paths under
+/// `path_prefix`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct SoapRule {
+    pub path_prefix: String,
+    /// Sent as `SOAPAction` (1.1) or the content type's `action` (1.2).
+    #[serde(default)]
+    pub action: Option<String>,
+    #[serde(default)]
+    pub version: SoapVersion,
+    /// Extra namespace declarations on the envelope, by prefix.
+    #[serde(default)]
+    pub namespaces: BTreeMap<String, String>,
+    /// Raw XML placed in `soap:Header`, e.g. credentials.
+    #[serde(default)]
+    pub header: Option<String>,
+}
+
+/// Parses `SOAP_RULES`, a JSON array of [`SoapRule`]s.
+pub fn parse_soap_rules(value: &str) -> Result<Vec<SoapRule>, String> {
+    if value.trim().is_empty() {
+        return Ok(Vec::new());
+    }
+    serde_json::from_str(value).map_err(|e| format!("invalid SOAP_RULES: {e}"))
+}
+
+/// Runs the body transformations ahead of the other global interceptors,
+/// so request signers see what is actually sent: requests are converted to
+/// XML and then wrapped, responses unwrapped and then converted to JSON.
+pub fn install(
+    interceptors: &mut Interceptors,
+    xml_json_rules: &[XmlJsonRule],
+    soap_rules: &[SoapRule],
+) {
+    let mut request: Vec<Arc<dyn RequestInterceptor>> = Vec::new();
+    let mut response: Vec<Arc<dyn ResponseInterceptor>> = Vec::new();
+    if !xml_json_rules.is_empty() {
+        let converter = Arc::new(XmlJson::new(xml_json_rules.to_vec()));
+        request.push(converter.clone());
+        response.push(converter);
+    }
+    if !soap_rules.is_empty() {
+        let envelope = Arc::new(Soap::new(soap_rules.to_vec()));
+        request.push(envelope.clone());
+        response.insert(0, envelope);
+    }
+    interceptors.global.request.splice(0..0, request);
+    interceptors.global.response.splice(0..0, response);
+}
+
+pub struct Soap {
+    rules: Vec<SoapRule>,
+}
+
+impl Soap {
+    pub fn new(rules: Vec<SoapRule>) -> Self {
+        Self { rules }
+    }
+
+    fn rule_for(&self, req: &OutboundRequest) -> Option<&SoapRule> {
+        let inbound = format!("/{}{}", req.env, req.uri.path());
+        self.rules
+            .iter()
+            .find(|rule| inbound.starts_with(&rule.path_prefix))
+    }
+}
+
+/// Wraps `body`, an XML fragment or document, in `rule`'s envelope.
+pub fn wrap(body: &[u8], rule: &SoapRule) -> Vec<u8> {
+    let body = String::from_utf8_lossy(body);
+    let body = strip_declaration(body.trim_start());
+    let mut envelope = format!(
+        r#"<?xml version="1.0" encoding="utf-8"?><soap:Envelope xmlns:soap="{}""#,
+        rule.version.namespace()
+    );
+    for (prefix, namespace) in &rule.namespaces {
+        envelope.push_str(&format!(r#" xmlns:{prefix}="{namespace}""#));
+    }
+    envelope.push('>');
+    if let Some(header) = &rule.header {
+        envelope.push_str(&format!("<soap:Header>{header}</soap:Header>"));
+    }
+    envelope.push_str(&format!("<soap:Body>{body}</soap:Body></soap:Envelope>"));
+    envelope.into_bytes()
+}
+
+fn strip_declaration(xml: &str) -> &str {
+    match xml.strip_prefix("<?xml") {
+        Some(rest) => rest.split_once("?>").map_or(xml, |(_, rest)| rest),
+        None => xml,
+    }
+}
+
+/// Returns the contents of the envelope's `Body`, whatever its prefix.
+pub fn unwrap(envelope: &[u8]) -> Result<Vec<u8>, String> {
+    let mut reader = Reader::from_reader(envelope);
+    let mut buf = Vec::new();
+    let mut depth = 0;
+    loop {
+        match reader
+            .read_event_into(&mut buf)
+            .map_err(|e| e.to_string())?
+        {
+            Event::Start(tag) => {
+                let local = tag.local_name();
+                match (depth, local.as_ref()) {
+                    (0, b"Envelope") => depth = 1,
+                    (0, _) => return Err("not a SOAP envelope".to_string()),
+                    (1, b"Body") => {
+                        let end = tag.to_end().into_owned();
+                        let span = reader
+                            .read_to_end_into(end.name(), &mut Vec::new())
+                            .map_err(|e| e.to_string())?;
+                        let content = &envelope[span.start as usize..span.end as usize];
+                        return Ok(content.trim_ascii().to_vec());
+                    }
+                    _ => {
+                        let end = tag.to_end().into_owned();
+                        reader
+                            .read_to_end_into(end.name(), &mut Vec::new())
+                            .map_err(|e| e.to_string())?;
+                    }
+                }
+            }
+            Event::Empty(tag) if depth == 1 && tag.local_name().as_ref() == b"Body" => {
+                return Ok(Vec::new());
+            }
+            Event::Eof => return Err("no SOAP body".to_string()),
+            _ => {}
+        }
+        buf.clear();
+    }
+}
+
+#[async_trait]
+impl RequestInterceptor for Soap {
+    async fn on_request(&self, req: &mut OutboundRequest) -> Result<(), StatusCode> {
+        let Some(rule) = self.rule_for(req) else {
+            return Ok(());
+        };
+        req.body = wrap(&req.body, rule).into();
+        req.headers.remove(header::CONTENT_LENGTH);
+        let action = rule.action.as_deref().unwrap_or_default();
+        let (content_type, soap_action) = match rule.version {
+            SoapVersion::Soap11 => (
+                "text/xml; charset=utf-8".to_string(),
+                Some(format!("\"{action}\"")),
+            ),
+            SoapVersion::Soap12 if action.is_empty() => {
+                ("application/soap+xml; charset=utf-8".to_string(), None)
+            }
+            SoapVersion::Soap12 => (
+                format!("application/soap+xml; charset=utf-8; action=\"{action}\""),
+                None,
+            ),
+        };
+        let value = |text: String| {
+            HeaderValue::try_from(text).map_err(|_| {
+                warn!("SOAP action {:?} is not a valid header value", action);
+                StatusCode::INTERNAL_SERVER_ERROR
+            })
+        };
+        req.headers
+            .insert(header::CONTENT_TYPE, value(content_type)?);
+        if let Some(soap_action) = soap_action {
+            req.headers.insert(SOAP_ACTION, value(soap_action)?);
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ResponseInterceptor for Soap {
+    async fn on_response(
+        &self,
+        req: &OutboundRequest,
+        res: &mut UpstreamResponse,
+    ) -> Result<(), StatusCode> {
+        if self.rule_for(req).is_none()
+            || res.body.is_empty()
+            || content_family(&res.headers) != "xml"
+        {
+            return Ok(());
+        }
+        // Faults come out of the envelope too; the status says what they are.
+        let body = unwrap(&res.body).map_err(|e| {
+            warn!("Response from {} is not a SOAP envelope: {}", req.uri, e);
+            StatusCode::BAD_GATEWAY
+        })?;
+        res.body = body.into();
+        res.headers.remove(header::CONTENT_LENGTH);
+        res.headers.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("application/xml; charset=utf-8"),
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule() -> SoapRule {
+        SoapRule {
+            path_prefix: "/test/soap".to_string(),
+            action: Some("urn:Search".to_string()),
+            version: SoapVersion::Soap11,
+            namespaces: BTreeMap::from([("h".to_string(), "urn:hotels".to_string())]),
+            header: Some("<h:Auth>key</h:Auth>".to_string()),
+        }
+    }
+
+    #[test]
+    fn wraps_bodies_in_the_configured_envelope() {
+        let wrapped = wrap(br#"<?xml version="1.0"?><h:Search>Goa</h:Search>"#, &rule());
+        assert_eq!(
+            String::from_utf8(wrapped).unwrap(),
+            concat!(
+                r#"<?xml version="1.0" encoding="utf-8"?>"#,
+                r#"<soap:Envelope xmlns:soap="http://schemas.xmlsoap.org/soap/envelope/" xmlns:h="urn:hotels">"#,
+                r#"<soap:Header><h:Auth>key</h:Auth></soap:Header>"#,
+                r#"<soap:Body><h:Search>Goa</h:Search></soap:Body></soap:Envelope>"#
+            )
+        );
+    }
+
+    #[test]
+    fn unwraps_response_bodies_and_faults() {
+        let envelope = br#"<?xml version="1.0"?>
+            <S:Envelope xmlns:S="http://schemas.xmlsoap.org/soap/envelope/">
+              <S:Header><Trace>1</Trace></S:Header>
+              <S:Body>
+                <Result><Body>nested</Body></Result>
+              </S:Body>
+            </S:Envelope>"#;
+        assert_eq!(
+            unwrap(envelope).unwrap(),
+            b"<Result><Body>nested</Body></Result>"
+        );
+
+        let fault = br#"<soap:Envelope xmlns:soap="x"><soap:Body><soap:Fault><faultstring>bad</faultstring></soap:Fault></soap:Body></soap:Envelope>"#;
+        assert_eq!(
+            unwrap(fault).unwrap(),
+            b"<soap:Fault><faultstring>bad</faultstring></soap:Fault>"
+        );
+        assert_eq!(unwrap(br#"<Envelope><Body/></Envelope>"#).unwrap(), b"");
+        assert!(unwrap(b"<Result/>").is_err());
+    }
+}
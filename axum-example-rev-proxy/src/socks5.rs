@@ -0,0 +1,217 @@
+//! SOCKS5 listener (RFC 1928) for batch jobs that only speak SOCKS, with
+//! username/password auth (RFC 1929). Only `CONNECT` is supported, and
+//! tunnels follow the forward proxy's credentials, destination allowlist and
+//! internal-address rules (see [`crate::forward_proxy`]).
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+use crate::admin::constant_time_eq;
+use crate::app_state::AppState;
+use crate::forward_proxy::{self, ConnectError, ForwardProxyConfig};
+
+/// Longest a client may take to authenticate and name its destination.
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+const VERSION: u8 = 5;
+const METHOD_USER_PASSWORD: u8 = 2;
+const NO_ACCEPTABLE_METHODS: u8 = 0xff;
+const CMD_CONNECT: u8 = 1;
+const ATYP_IPV4: u8 = 1;
+const ATYP_DOMAIN: u8 = 3;
+const ATYP_IPV6: u8 = 4;
+
+/// Reply codes.
+const SUCCEEDED: u8 = 0;
+const NOT_ALLOWED: u8 = 2;
+const NETWORK_UNREACHABLE: u8 = 3;
+const HOST_UNREACHABLE: u8 = 4;
+const CONNECTION_REFUSED: u8 = 5;
+const TTL_EXPIRED: u8 = 6;
+const COMMAND_NOT_SUPPORTED: u8 = 7;
+const ADDRESS_TYPE_NOT_SUPPORTED: u8 = 8;
+
+/// Binds the configured listener and serves it in the background.
+pub async fn spawn(state: &AppState) -> std::io::Result<Option<JoinHandle<()>>> {
+    let Some(config) = &state.env_var_config.socks5 else {
+        return Ok(None);
+    };
+    let listener = TcpListener::bind(config.addr).await?;
+    info!("SOCKS5 proxy listening on {}", listener.local_addr()?);
+    Ok(Some(tokio::spawn(serve(listener, state.clone()))))
+}
+
+/// Accepts SOCKS5 clients on `listener` until the process exits.
+pub async fn serve(listener: TcpListener, state: AppState) {
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                warn!("SOCKS5 proxy failed to accept a connection: {}", e);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        let state = state.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle(&state, stream, peer).await {
+                info!("SOCKS5 connection from {} ended: {}", peer, e);
+            }
+        });
+    }
+}
+
+async fn handle(state: &AppState, mut client: TcpStream, peer: SocketAddr) -> std::io::Result<()> {
+    let Some(config) = &state.env_var_config.socks5 else {
+        return Ok(());
+    };
+    let handshake = tokio::time::timeout(
+        HANDSHAKE_TIMEOUT,
+        handshake(state, config, &mut client, peer),
+    )
+    .await
+    .map_err(|_| std::io::Error::new(std::io::ErrorKind::TimedOut, "handshake timed out"))??;
+    let Some((mut upstream, destination)) = handshake else {
+        return Ok(());
+    };
+    info!("Tunnelling {} to {} over SOCKS5", peer, destination);
+    let (sent, received) = tokio::io::copy_bidirectional(&mut client, &mut upstream).await?;
+    info!(
+        "SOCKS5 tunnel to {} closed after {} bytes out, {} in",
+        destination, sent, received
+    );
+    Ok(())
+}
+
+/// Authenticates the client and connects to its destination, returning the
+/// upstream stream once the client has been told it succeeded.
+async fn handshake(
+    state: &AppState,
+    config: &ForwardProxyConfig,
+    client: &mut TcpStream,
+    peer: SocketAddr,
+) -> std::io::Result<Option<(TcpStream, String)>> {
+    let [version, count] = read_array(client).await?;
+    if version != VERSION {
+        return Err(invalid("not a SOCKS5 client"));
+    }
+    let methods = read_vec(client, count.into()).await?;
+    if !methods.contains(&METHOD_USER_PASSWORD) {
+        warn!("SOCKS5 client {} offered no username/password auth", peer);
+        state.metrics.record_tunnel("socks5", "unauthorized");
+        client.write_all(&[VERSION, NO_ACCEPTABLE_METHODS]).await?;
+        return Ok(None);
+    }
+    client.write_all(&[VERSION, METHOD_USER_PASSWORD]).await?;
+
+    let [_, user_len] = read_array(client).await?;
+    let user = read_vec(client, user_len.into()).await?;
+    let [password_len] = read_array(client).await?;
+    let password = read_vec(client, password_len.into()).await?;
+    let mut presented = user;
+    presented.push(b':');
+    presented.extend(password);
+    if !config
+        .credentials
+        .iter()
+        .any(|expected| constant_time_eq(&presented, expected.as_bytes()))
+    {
+        warn!("SOCKS5 client {} sent wrong credentials", peer);
+        state.metrics.record_tunnel("socks5", "unauthorized");
+        client.write_all(&[1, 1]).await?;
+        return Ok(None);
+    }
+    client.write_all(&[1, 0]).await?;
+
+    let [_, command, _, address_type] = read_array(client).await?;
+    let host = match address_type {
+        ATYP_IPV4 => Ipv4Addr::from(read_array::<4>(client).await?).to_string(),
+        ATYP_IPV6 => format!("[{}]", Ipv6Addr::from(read_array::<16>(client).await?)),
+        ATYP_DOMAIN => {
+            let [len] = read_array(client).await?;
+            String::from_utf8(read_vec(client, len.into()).await?)
+                .map_err(|_| invalid("destination is not UTF-8"))?
+        }
+        _ => {
+            reply(client, ADDRESS_TYPE_NOT_SUPPORTED, None).await?;
+            return Ok(None);
+        }
+    };
+    let port = u16::from_be_bytes(read_array(client).await?);
+    let destination = format!("{host}:{port}");
+    if command != CMD_CONNECT {
+        reply(client, COMMAND_NOT_SUPPORTED, None).await?;
+        return Ok(None);
+    }
+    if !config.allows(&host, port) {
+        warn!(
+            "SOCKS5 proxy refused {} from {}: not an allowed destination",
+            destination, peer
+        );
+        state.metrics.record_tunnel("socks5", "refused");
+        reply(client, NOT_ALLOWED, None).await?;
+        return Ok(None);
+    }
+
+    match forward_proxy::connect(state, &host, port, config.connect_timeout).await {
+        Ok(upstream) => {
+            state.metrics.record_tunnel("socks5", "opened");
+            reply(client, SUCCEEDED, upstream.local_addr().ok()).await?;
+            Ok(Some((upstream, destination)))
+        }
+        Err(e) => {
+            warn!("SOCKS5 proxy could not reach {}: {}", destination, e);
+            state.metrics.record_tunnel("socks5", "unreachable");
+            let code = match &e {
+                ConnectError::Internal(_) => NOT_ALLOWED,
+                ConnectError::Dns(_) => HOST_UNREACHABLE,
+                ConnectError::Timeout => TTL_EXPIRED,
+                ConnectError::Io(e) if e.kind() == std::io::ErrorKind::ConnectionRefused => {
+                    CONNECTION_REFUSED
+                }
+                ConnectError::Io(_) => NETWORK_UNREACHABLE,
+            };
+            reply(client, code, None).await?;
+            Ok(None)
+        }
+    }
+}
+
+/// Answers the client's request, with the address the proxy bound on success.
+async fn reply(client: &mut TcpStream, code: u8, bound: Option<SocketAddr>) -> std::io::Result<()> {
+    let bound = bound.unwrap_or(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0));
+    let mut message = vec![VERSION, code, 0];
+    match bound.ip() {
+        IpAddr::V4(ip) => {
+            message.push(ATYP_IPV4);
+            message.extend(ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            message.push(ATYP_IPV6);
+            message.extend(ip.octets());
+        }
+    }
+    message.extend(bound.port().to_be_bytes());
+    client.write_all(&message).await
+}
+
+async fn read_array<const N: usize>(client: &mut TcpStream) -> std::io::Result<[u8; N]> {
+    let mut buf = [0; N];
+    client.read_exact(&mut buf).await?;
+    Ok(buf)
+}
+
+async fn read_vec(client: &mut TcpStream, len: usize) -> std::io::Result<Vec<u8>> {
+    let mut buf = vec![0; len];
+    client.read_exact(&mut buf).await?;
+    Ok(buf)
+}
+
+fn invalid(message: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message.to_string())
+}
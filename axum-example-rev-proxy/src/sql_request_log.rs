@@ -0,0 +1,323 @@
+//! SQLite/Postgres [`RequestLogSink`] (behind the `request_log` feature).
+
+use async_trait::async_trait;
+use sqlx::any::{AnyPoolOptions, AnyRow};
+use sqlx::{AnyPool, Row};
+use tokio::sync::OnceCell;
+
+use crate::request_log::{RequestLogEntry, RequestLogQuery, RequestLogSink};
+
+const DEFAULT_QUERY_LIMIT: u32 = 100;
+const MAX_QUERY_LIMIT: u32 = 1000;
+
+// Plain types only, so one schema works on both databases.
+const SCHEMA: [&str; 2] = [
+    "CREATE TABLE IF NOT EXISTS request_log (
+        timestamp_ms BIGINT NOT NULL,
+        env TEXT NOT NULL,
+        method TEXT NOT NULL,
+        path TEXT NOT NULL,
+        query TEXT,
+        status INTEGER NOT NULL,
+        duration_ms BIGINT NOT NULL,
+        request_body TEXT,
+        response_body TEXT,
+        correlation_id TEXT,
+        fingerprint TEXT,
+        client_tag TEXT
+    )",
+    "CREATE INDEX IF NOT EXISTS request_log_timestamp ON request_log (timestamp_ms)",
+];
+
+/// Columns added after the table first shipped. SQLite has no
+/// `ADD COLUMN IF NOT EXISTS`, so failures (column already there) are ignored.
+const MIGRATIONS: [&str; 3] = [
+    "ALTER TABLE request_log ADD COLUMN correlation_id TEXT",
+    "ALTER TABLE request_log ADD COLUMN fingerprint TEXT",
+    "ALTER TABLE request_log ADD COLUMN client_tag TEXT",
+];
+
+const SELECT_ENTRIES: &str = "SELECT timestamp_ms, env, method, path, query, status, \
+     duration_ms, request_body, response_body, correlation_id, fingerprint, client_tag \
+     FROM request_log";
+
+pub struct SqlRequestLog {
+    pool: AnyPool,
+    schema: OnceCell<()>,
+}
+
+impl SqlRequestLog {
+    /// Opens a pool that connects on first use, so the router can be built
+    /// outside a runtime. The table is created on first use as well.
+    pub fn connect_lazy(url: &str) -> Result<Self, String> {
+        sqlx::any::install_default_drivers();
+        let pool = AnyPoolOptions::new()
+            .max_connections(4)
+            .connect_lazy(url)
+            .map_err(|e| format!("invalid request log url: {e}"))?;
+        Ok(Self {
+            pool,
+            schema: OnceCell::new(),
+        })
+    }
+
+    async fn ready(&self) -> Result<&AnyPool, String> {
+        self.schema
+            .get_or_try_init(|| async {
+                for statement in SCHEMA {
+                    sqlx::query(statement)
+                        .execute(&self.pool)
+                        .await
+                        .map_err(|e| format!("failed to create request_log table: {e}"))?;
+                }
+                for statement in MIGRATIONS {
+                    let _ = sqlx::query(statement).execute(&self.pool).await;
+                }
+                Ok::<_, String>(())
+            })
+            .await?;
+        Ok(&self.pool)
+    }
+}
+
+#[async_trait]
+impl RequestLogSink for SqlRequestLog {
+    async fn record(&self, entry: RequestLogEntry) -> Result<(), String> {
+        let pool = self.ready().await?;
+        sqlx::query(
+            "INSERT INTO request_log (timestamp_ms, env, method, path, query, status, \
+             duration_ms, request_body, response_body, correlation_id, fingerprint, client_tag) \
+             VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12)",
+        )
+        .bind(entry.timestamp_ms)
+        .bind(entry.env)
+        .bind(entry.method)
+        .bind(entry.path)
+        .bind(entry.query)
+        .bind(entry.status as i32)
+        .bind(entry.duration_ms)
+        .bind(entry.request_body)
+        .bind(entry.response_body)
+        .bind(entry.correlation_id)
+        .bind(entry.fingerprint)
+        .bind(entry.client_tag)
+        .execute(pool)
+        .await
+        .map_err(|e| e.to_string())?;
+        Ok(())
+    }
+
+    async fn query(&self, query: &RequestLogQuery) -> Result<Vec<RequestLogEntry>, String> {
+        let pool = self.ready().await?;
+
+        let mut conditions = Vec::new();
+        let mut placeholder = 0;
+        let mut next = || {
+            placeholder += 1;
+            format!("${placeholder}")
+        };
+        if query.from_ms.is_some() {
+            conditions.push(format!("timestamp_ms >= {}", next()));
+        }
+        if query.to_ms.is_some() {
+            conditions.push(format!("timestamp_ms < {}", next()));
+        }
+        if query.status.is_some() {
+            conditions.push(format!("status = {}", next()));
+        }
+        if query.path_prefix.is_some() {
+            // Prefix match without LIKE, so `%`/`_` in paths aren't wildcards.
+            conditions.push(format!("substr(path, 1, {}) = {}", next(), next()));
+        }
+        if query.correlation_id.is_some() {
+            conditions.push(format!("correlation_id = {}", next()));
+        }
+        if query.fingerprint.is_some() {
+            conditions.push(format!("fingerprint = {}", next()));
+        }
+        if query.client_tag.is_some() {
+            conditions.push(format!("client_tag = {}", next()));
+        }
+        let limit = next();
+
+        let mut sql = String::from(SELECT_ENTRIES);
+        if !conditions.is_empty() {
+            sql.push_str(" WHERE ");
+            sql.push_str(&conditions.join(" AND "));
+        }
+        sql.push_str(&format!(" ORDER BY timestamp_ms DESC LIMIT {limit}"));
+
+        let mut statement = sqlx::query(&sql);
+        if let Some(from_ms) = query.from_ms {
+            statement = statement.bind(from_ms);
+        }
+        if let Some(to_ms) = query.to_ms {
+            statement = statement.bind(to_ms);
+        }
+        if let Some(status) = query.status {
+            statement = statement.bind(status as i32);
+        }
+        if let Some(prefix) = &query.path_prefix {
+            statement = statement
+                .bind(prefix.chars().count() as i32)
+                .bind(prefix.clone());
+        }
+        if let Some(correlation_id) = &query.correlation_id {
+            statement = statement.bind(correlation_id.clone());
+        }
+        if let Some(fingerprint) = &query.fingerprint {
+            statement = statement.bind(fingerprint.clone());
+        }
+        if let Some(client_tag) = &query.client_tag {
+            statement = statement.bind(client_tag.clone());
+        }
+        let limit = query
+            .limit
+            .unwrap_or(DEFAULT_QUERY_LIMIT)
+            .min(MAX_QUERY_LIMIT);
+        statement = statement.bind(limit as i64);
+
+        let rows = statement.fetch_all(pool).await.map_err(|e| e.to_string())?;
+        rows.iter().map(entry_from_row).collect()
+    }
+
+    async fn oldest_before(
+        &self,
+        before_ms: i64,
+        limit: u32,
+    ) -> Result<Vec<RequestLogEntry>, String> {
+        let pool = self.ready().await?;
+        let sql =
+            format!("{SELECT_ENTRIES} WHERE timestamp_ms < $1 ORDER BY timestamp_ms LIMIT $2");
+        let rows = sqlx::query(&sql)
+            .bind(before_ms)
+            .bind(limit as i64)
+            .fetch_all(pool)
+            .await
+            .map_err(|e| e.to_string())?;
+        rows.iter().map(entry_from_row).collect()
+    }
+
+    async fn delete_through(&self, through_ms: i64) -> Result<u64, String> {
+        let pool = self.ready().await?;
+        let result = sqlx::query("DELETE FROM request_log WHERE timestamp_ms <= $1")
+            .bind(through_ms)
+            .execute(pool)
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok(result.rows_affected())
+    }
+}
+
+fn entry_from_row(row: &AnyRow) -> Result<RequestLogEntry, String> {
+    let read = |e: sqlx::Error| e.to_string();
+    Ok(RequestLogEntry {
+        timestamp_ms: row.try_get("timestamp_ms").map_err(read)?,
+        env: row.try_get("env").map_err(read)?,
+        method: row.try_get("method").map_err(read)?,
+        path: row.try_get("path").map_err(read)?,
+        query: row.try_get("query").map_err(read)?,
+        status: row.try_get::<i32, _>("status").map_err(read)? as u16,
+        duration_ms: row.try_get("duration_ms").map_err(read)?,
+        request_body: row.try_get("request_body").map_err(read)?,
+        response_body: row.try_get("response_body").map_err(read)?,
+        correlation_id: row.try_get("correlation_id").map_err(read)?,
+        fingerprint: row.try_get("fingerprint").map_err(read)?,
+        client_tag: row.try_get("client_tag").map_err(read)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(timestamp_ms: i64, path: &str, status: u16) -> RequestLogEntry {
+        RequestLogEntry {
+            timestamp_ms,
+            env: "test".to_string(),
+            method: "GET".to_string(),
+            path: path.to_string(),
+            query: None,
+            status,
+            duration_ms: 5,
+            request_body: None,
+            response_body: Some("{}".to_string()),
+            correlation_id: None,
+            fingerprint: None,
+            client_tag: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn filters_by_time_status_and_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let url = format!("sqlite://{}?mode=rwc", dir.path().join("log.db").display());
+        let log = SqlRequestLog::connect_lazy(&url).unwrap();
+        log.record(entry(1_000, "/test/api/booking/1", 200))
+            .await
+            .unwrap();
+        log.record(entry(2_000, "/test/api/booking_%", 502))
+            .await
+            .unwrap();
+        log.record(entry(3_000, "/test/api/hotels", 200))
+            .await
+            .unwrap();
+
+        let all = log.query(&RequestLogQuery::default()).await.unwrap();
+        assert_eq!(
+            all.iter().map(|e| e.timestamp_ms).collect::<Vec<_>>(),
+            [3_000, 2_000, 1_000]
+        );
+
+        let bookings = log
+            .query(&RequestLogQuery {
+                path_prefix: Some("/test/api/booking/".to_string()),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(bookings, [entry(1_000, "/test/api/booking/1", 200)]);
+
+        let failures = log
+            .query(&RequestLogQuery {
+                from_ms: Some(1_500),
+                to_ms: Some(3_000),
+                status: Some(502),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].path, "/test/api/booking_%");
+
+        let mut tagged = entry(4_000, "/test/api/hotels", 200);
+        tagged.correlation_id = Some("tmx-42".to_string());
+        tagged.fingerprint = Some("f00d".to_string());
+        tagged.client_tag = Some("checkout".to_string());
+        log.record(tagged.clone()).await.unwrap();
+        let found = log
+            .query(&RequestLogQuery {
+                correlation_id: Some("tmx-42".to_string()),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(found, [tagged.clone()]);
+        let found = log
+            .query(&RequestLogQuery {
+                fingerprint: Some("f00d".to_string()),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(found, [tagged.clone()]);
+        let found = log
+            .query(&RequestLogQuery {
+                client_tag: Some("checkout".to_string()),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(found, [tagged]);
+    }
+}
@@ -0,0 +1,195 @@
+//! SQLite/Postgres [`Storage`] (behind the `sql_storage` feature).
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use sqlx::any::AnyPoolOptions;
+use sqlx::{AnyPool, Row};
+use tokio::sync::OnceCell;
+
+use crate::storage::Storage;
+
+// Plain types only, so one schema works on both databases. Values are
+// base64 so they fit a TEXT column everywhere.
+const SCHEMA: &str = "CREATE TABLE IF NOT EXISTS storage (
+    key TEXT PRIMARY KEY,
+    value TEXT NOT NULL,
+    expires_ms BIGINT
+)";
+
+/// Rows are live until `expires_ms`; `$2` is the current time.
+const LIVE: &str = "(expires_ms IS NULL OR expires_ms > $2)";
+
+pub struct SqlStorage {
+    pool: AnyPool,
+    schema: OnceCell<()>,
+}
+
+impl SqlStorage {
+    /// Opens a pool that connects on first use, so the router can be built
+    /// outside a runtime. The table is created on first use as well.
+    pub fn connect_lazy(url: &str) -> Result<Self, String> {
+        sqlx::any::install_default_drivers();
+        let pool = AnyPoolOptions::new()
+            .max_connections(4)
+            .connect_lazy(url)
+            .map_err(|e| format!("invalid storage url: {e}"))?;
+        Ok(Self {
+            pool,
+            schema: OnceCell::new(),
+        })
+    }
+
+    async fn ready(&self) -> Result<&AnyPool, String> {
+        self.schema
+            .get_or_try_init(|| async {
+                sqlx::query(SCHEMA)
+                    .execute(&self.pool)
+                    .await
+                    .map(|_| ())
+                    .map_err(|e| format!("failed to create storage table: {e}"))
+            })
+            .await?;
+        Ok(&self.pool)
+    }
+}
+
+fn now_ms() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as i64
+}
+
+fn expires_ms(ttl: Option<Duration>) -> Option<i64> {
+    ttl.map(|ttl| now_ms() + ttl.as_millis() as i64)
+}
+
+#[async_trait]
+impl Storage for SqlStorage {
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, String> {
+        let pool = self.ready().await?;
+        let row = sqlx::query(&format!(
+            "SELECT value FROM storage WHERE key = $1 AND {LIVE}"
+        ))
+        .bind(key)
+        .bind(now_ms())
+        .fetch_optional(pool)
+        .await
+        .map_err(|e| e.to_string())?;
+        row.map(|row| {
+            let value: String = row.try_get("value").map_err(|e| e.to_string())?;
+            BASE64.decode(value).map_err(|e| e.to_string())
+        })
+        .transpose()
+    }
+
+    async fn put(&self, key: &str, value: Vec<u8>, ttl: Option<Duration>) -> Result<(), String> {
+        let pool = self.ready().await?;
+        sqlx::query(
+            "INSERT INTO storage (key, value, expires_ms) VALUES ($1, $2, $3) \
+             ON CONFLICT (key) DO UPDATE SET value = excluded.value, \
+             expires_ms = excluded.expires_ms",
+        )
+        .bind(key)
+        .bind(BASE64.encode(value))
+        .bind(expires_ms(ttl))
+        .execute(pool)
+        .await
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<String>, String> {
+        let pool = self.ready().await?;
+        // A prefix match without LIKE, so `%` and `_` in keys mean themselves.
+        let rows = sqlx::query(&format!(
+            "SELECT key FROM storage WHERE substr(key, 1, $3) = $1 AND {LIVE} ORDER BY key"
+        ))
+        .bind(prefix)
+        .bind(now_ms())
+        .bind(prefix.chars().count() as i32)
+        .fetch_all(pool)
+        .await
+        .map_err(|e| e.to_string())?;
+        rows.iter()
+            .map(|row| row.try_get("key").map_err(|e| e.to_string()))
+            .collect()
+    }
+
+    async fn delete(&self, key: &str) -> Result<bool, String> {
+        let pool = self.ready().await?;
+        let result = sqlx::query(&format!("DELETE FROM storage WHERE key = $1 AND {LIVE}"))
+            .bind(key)
+            .bind(now_ms())
+            .execute(pool)
+            .await
+            .map_err(|e| e.to_string())?;
+        // Expired rows go too, without counting.
+        sqlx::query("DELETE FROM storage WHERE key = $1")
+            .bind(key)
+            .execute(pool)
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn put_new(
+        &self,
+        key: &str,
+        value: Vec<u8>,
+        ttl: Option<Duration>,
+    ) -> Result<bool, String> {
+        let pool = self.ready().await?;
+        sqlx::query("DELETE FROM storage WHERE key = $1 AND expires_ms <= $2")
+            .bind(key)
+            .bind(now_ms())
+            .execute(pool)
+            .await
+            .map_err(|e| e.to_string())?;
+        let result = sqlx::query(
+            "INSERT INTO storage (key, value, expires_ms) VALUES ($1, $2, $3) \
+             ON CONFLICT (key) DO NOTHING",
+        )
+        .bind(key)
+        .bind(BASE64.encode(value))
+        .bind(expires_ms(ttl))
+        .execute(pool)
+        .await
+        .map_err(|e| e.to_string())?;
+        Ok(result.rows_affected() > 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn stores_expires_and_lists_by_prefix() {
+        let dir = tempfile::tempdir().unwrap();
+        let url = format!(
+            "sqlite://{}?mode=rwc",
+            dir.path().join("state.db").display()
+        );
+        let storage = SqlStorage::connect_lazy(&url).unwrap();
+        storage.put("a:1", b"one".to_vec(), None).await.unwrap();
+        storage.put("a:1", b"uno".to_vec(), None).await.unwrap();
+        storage.put("a_2", b"two".to_vec(), None).await.unwrap();
+        storage
+            .put("a:3", b"gone".to_vec(), Some(Duration::ZERO))
+            .await
+            .unwrap();
+        assert_eq!(storage.get("a:1").await.unwrap(), Some(b"uno".to_vec()));
+        assert_eq!(storage.get("a:3").await.unwrap(), None);
+        assert_eq!(storage.list("a:").await.unwrap(), ["a:1"]);
+
+        assert!(!storage.put_new("a:1", b"x".to_vec(), None).await.unwrap());
+        assert!(storage.put_new("a:3", b"x".to_vec(), None).await.unwrap());
+        assert!(storage.delete("a:1").await.unwrap());
+        assert!(!storage.delete("a:1").await.unwrap());
+        assert_eq!(storage.list("a").await.unwrap(), ["a:3", "a_2"]);
+    }
+}
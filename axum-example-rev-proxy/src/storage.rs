@@ -0,0 +1,433 @@
+//! One key-value backend for the state that persistent features keep,
+//! chosen with `STORAGE_URL`:
+//!
+//! - `memory` (the default): per process and lost on restart;
+//! - `file:///var/lib/egress-proxy`: one JSON file per key in a directory;
+//! - `sqlite://state.db` or `postgres://...`: a `storage` table
+//!   (`sql_storage` feature);
+//! - `redis://...`: shared by replicas (`redis` feature).
+//!
+//! With it set, the response cache, webhook replay (idempotency) keys,
+//! store-and-forward deliveries and captured traffic all live there, under
+//! the `cache:`, `seen:`, `delivery:` and `capture:` key prefixes. Settings
+//! for one feature still win for that feature: `REDIS_URL` for the cache
+//! and replay keys, `STORE_AND_FORWARD_DIR` for deliveries.
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::error;
+
+use crate::cache::{under_prefix, CacheEntry, CacheStore, StoredEntry};
+use crate::dedup::DedupStore;
+use crate::store_forward::{Delivery, DeliveryStore};
+
+const CACHE_PREFIX: &str = "cache:";
+const SEEN_PREFIX: &str = "seen:";
+const DELIVERY_PREFIX: &str = "delivery:";
+
+#[async_trait]
+pub trait Storage: Send + Sync {
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, String>;
+    /// Stores `value` under `key`, dropping it after `ttl` when set.
+    async fn put(&self, key: &str, value: Vec<u8>, ttl: Option<Duration>) -> Result<(), String>;
+    /// Live keys starting with `prefix`, sorted.
+    async fn list(&self, prefix: &str) -> Result<Vec<String>, String>;
+    /// Returns whether `key` was there.
+    async fn delete(&self, key: &str) -> Result<bool, String>;
+
+    /// Stores `value` only if `key` isn't live, returning whether it did.
+    /// Backends that can do this atomically should; the default can race.
+    async fn put_new(
+        &self,
+        key: &str,
+        value: Vec<u8>,
+        ttl: Option<Duration>,
+    ) -> Result<bool, String> {
+        if self.get(key).await?.is_some() {
+            return Ok(false);
+        }
+        self.put(key, value, ttl).await?;
+        Ok(true)
+    }
+}
+
+/// Opens the backend `url` names; see the module docs.
+pub fn open_storage(url: &str) -> Result<Arc<dyn Storage>, String> {
+    if url == "memory" {
+        return Ok(Arc::new(MemoryStorage::default()));
+    }
+    if let Some(dir) = url.strip_prefix("file://") {
+        return Ok(Arc::new(FileStorage::open(dir)?));
+    }
+    if url.starts_with("sqlite:") || url.starts_with("postgres:") {
+        #[cfg(feature = "sql_storage")]
+        return Ok(Arc::new(crate::sql_storage::SqlStorage::connect_lazy(url)?));
+        #[cfg(not(feature = "sql_storage"))]
+        return Err(format!("STORAGE_URL={url} needs the `sql_storage` feature"));
+    }
+    if url.starts_with("redis:") || url.starts_with("rediss:") {
+        #[cfg(feature = "redis")]
+        return Ok(Arc::new(crate::redis_store::RedisStore::open(url)?));
+        #[cfg(not(feature = "redis"))]
+        return Err(format!("STORAGE_URL={url} needs the `redis` feature"));
+    }
+    Err(format!("unsupported STORAGE_URL {url}"))
+}
+
+fn expiry(ttl: Option<Duration>) -> Option<SystemTime> {
+    ttl.map(|ttl| SystemTime::now() + ttl)
+}
+
+fn is_live(expires: Option<SystemTime>) -> bool {
+    expires.is_none_or(|expires| expires > SystemTime::now())
+}
+
+/// A value and when it expires.
+type MemoryEntry = (Vec<u8>, Option<SystemTime>);
+
+/// Per-process storage.
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    entries: Mutex<BTreeMap<String, MemoryEntry>>,
+}
+
+#[async_trait]
+impl Storage for MemoryStorage {
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, String> {
+        let entries = self.entries.lock().unwrap();
+        Ok(entries
+            .get(key)
+            .filter(|(_, expires)| is_live(*expires))
+            .map(|(value, _)| value.clone()))
+    }
+
+    async fn put(&self, key: &str, value: Vec<u8>, ttl: Option<Duration>) -> Result<(), String> {
+        let mut entries = self.entries.lock().unwrap();
+        entries.insert(key.to_string(), (value, expiry(ttl)));
+        Ok(())
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<String>, String> {
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, (_, expires)| is_live(*expires));
+        Ok(entries
+            .range(prefix.to_string()..)
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .map(|(key, _)| key.clone())
+            .collect())
+    }
+
+    async fn delete(&self, key: &str) -> Result<bool, String> {
+        let removed = self.entries.lock().unwrap().remove(key);
+        Ok(removed.is_some_and(|(_, expires)| is_live(expires)))
+    }
+
+    async fn put_new(
+        &self,
+        key: &str,
+        value: Vec<u8>,
+        ttl: Option<Duration>,
+    ) -> Result<bool, String> {
+        let mut entries = self.entries.lock().unwrap();
+        if entries
+            .get(key)
+            .is_some_and(|(_, expires)| is_live(*expires))
+        {
+            return Ok(false);
+        }
+        entries.insert(key.to_string(), (value, expiry(ttl)));
+        Ok(true)
+    }
+}
+
+/// A key's file: named by the key's hash, since keys such as cache paths
+/// can be longer than a file name may be.
+#[derive(Serialize, Deserialize)]
+struct StoredFile {
+    key: String,
+    expires_ms: Option<u64>,
+    /// Base64.
+    value: String,
+}
+
+/// One JSON file per key in a directory, written atomically. Listing reads
+/// every file, so this suits a single replica's modest state.
+#[derive(Debug)]
+pub struct FileStorage {
+    dir: PathBuf,
+}
+
+impl FileStorage {
+    pub fn open(dir: impl Into<PathBuf>) -> Result<Self, String> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir)
+            .map_err(|e| format!("can't create {}: {e}", dir.display()))?;
+        Ok(Self { dir })
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir
+            .join(format!("{}.json", hex::encode(Sha256::digest(key))))
+    }
+
+    async fn read(path: &PathBuf) -> Result<Option<StoredFile>, String> {
+        match tokio::fs::read(path).await {
+            Ok(json) => serde_json::from_slice(&json)
+                .map(Some)
+                .map_err(|e| format!("can't read {}: {e}", path.display())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(format!("can't read {}: {e}", path.display())),
+        }
+    }
+
+    fn file_is_live(file: &StoredFile) -> bool {
+        is_live(
+            file.expires_ms
+                .map(|ms| UNIX_EPOCH + Duration::from_millis(ms)),
+        )
+    }
+}
+
+#[async_trait]
+impl Storage for FileStorage {
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, String> {
+        let Some(file) = Self::read(&self.path(key)).await? else {
+            return Ok(None);
+        };
+        if file.key != key || !Self::file_is_live(&file) {
+            return Ok(None);
+        }
+        BASE64
+            .decode(file.value)
+            .map(Some)
+            .map_err(|e| e.to_string())
+    }
+
+    async fn put(&self, key: &str, value: Vec<u8>, ttl: Option<Duration>) -> Result<(), String> {
+        let file = StoredFile {
+            key: key.to_string(),
+            expires_ms: expiry(ttl).map(|expires| {
+                expires
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_millis() as u64
+            }),
+            value: BASE64.encode(value),
+        };
+        let json = serde_json::to_vec(&file).map_err(|e| e.to_string())?;
+        let path = self.path(key);
+        let temp = path.with_extension("tmp");
+        tokio::fs::write(&temp, json)
+            .await
+            .map_err(|e| format!("can't write {}: {e}", temp.display()))?;
+        tokio::fs::rename(&temp, &path)
+            .await
+            .map_err(|e| format!("can't write {}: {e}", path.display()))
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<String>, String> {
+        let mut dir = tokio::fs::read_dir(&self.dir)
+            .await
+            .map_err(|e| format!("can't list {}: {e}", self.dir.display()))?;
+        let mut keys = Vec::new();
+        while let Some(entry) = dir.next_entry().await.map_err(|e| e.to_string())? {
+            let path = entry.path();
+            if path.extension().is_none_or(|ext| ext != "json") {
+                continue;
+            }
+            let Some(file) = Self::read(&path).await? else {
+                continue;
+            };
+            if !Self::file_is_live(&file) {
+                let _ = tokio::fs::remove_file(&path).await;
+            } else if file.key.starts_with(prefix) {
+                keys.push(file.key);
+            }
+        }
+        keys.sort();
+        Ok(keys)
+    }
+
+    async fn delete(&self, key: &str) -> Result<bool, String> {
+        let path = self.path(key);
+        let live = Self::read(&path)
+            .await?
+            .is_some_and(|file| file.key == key && Self::file_is_live(&file));
+        match tokio::fs::remove_file(&path).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.to_string()),
+            _ => Ok(live),
+        }
+    }
+}
+
+/// The cache, replay-key and delivery stores, kept in one [`Storage`].
+pub struct StorageStores {
+    storage: Arc<dyn Storage>,
+}
+
+impl StorageStores {
+    pub fn new(storage: Arc<dyn Storage>) -> Self {
+        Self { storage }
+    }
+}
+
+#[async_trait]
+impl CacheStore for StorageStores {
+    async fn get(&self, key: &str) -> Option<CacheEntry> {
+        match self.storage.get(&format!("{CACHE_PREFIX}{key}")).await {
+            Ok(Some(json)) => serde_json::from_slice::<StoredEntry>(&json)
+                .ok()
+                .and_then(StoredEntry::into_entry),
+            Ok(None) => None,
+            Err(e) => {
+                error!("Cache read failed: {}", e);
+                None
+            }
+        }
+    }
+
+    async fn insert(&self, key: String, entry: CacheEntry) {
+        let ttl = entry
+            .retain_until
+            .duration_since(SystemTime::now())
+            .unwrap_or_default();
+        let json = match serde_json::to_vec(&StoredEntry::from_entry(&entry)) {
+            Ok(json) => json,
+            Err(e) => return error!("Failed to serialize cache entry: {}", e),
+        };
+        let key = format!("{CACHE_PREFIX}{key}");
+        if let Err(e) = self.storage.put(&key, json, Some(ttl)).await {
+            error!("Cache write failed: {}", e);
+        }
+    }
+
+    async fn remove(&self, key: &str) {
+        if let Err(e) = self.storage.delete(&format!("{CACHE_PREFIX}{key}")).await {
+            error!("Cache delete failed: {}", e);
+        }
+    }
+
+    async fn remove_prefix(&self, path_prefix: &str) -> usize {
+        let keys = match self
+            .storage
+            .list(&format!("{CACHE_PREFIX}{path_prefix}"))
+            .await
+        {
+            Ok(keys) => keys,
+            Err(e) => {
+                error!("Cache invalidation failed: {}", e);
+                return 0;
+            }
+        };
+        let mut removed = 0;
+        for key in keys {
+            let cached = key.strip_prefix(CACHE_PREFIX).unwrap_or(&key);
+            if !under_prefix(cached, path_prefix) {
+                continue;
+            }
+            if let Ok(true) = self.storage.delete(&key).await {
+                removed += 1;
+            }
+        }
+        removed
+    }
+}
+
+#[async_trait]
+impl DedupStore for StorageStores {
+    async fn first_seen(&self, key: &str, ttl: Duration) -> Result<bool, String> {
+        self.storage
+            .put_new(&format!("{SEEN_PREFIX}{key}"), b"1".to_vec(), Some(ttl))
+            .await
+    }
+}
+
+#[async_trait]
+impl DeliveryStore for StorageStores {
+    async fn save(&self, delivery: &Delivery) -> Result<(), String> {
+        let json = serde_json::to_vec(delivery).map_err(|e| e.to_string())?;
+        self.storage
+            .put(&format!("{DELIVERY_PREFIX}{}", delivery.id), json, None)
+            .await
+    }
+
+    async fn load(&self, id: &str) -> Result<Option<Delivery>, String> {
+        self.storage
+            .get(&format!("{DELIVERY_PREFIX}{id}"))
+            .await?
+            .map(|json| serde_json::from_slice(&json).map_err(|e| e.to_string()))
+            .transpose()
+    }
+
+    async fn all(&self) -> Result<Vec<Delivery>, String> {
+        let mut deliveries = Vec::new();
+        for key in self.storage.list(DELIVERY_PREFIX).await? {
+            if let Some(json) = self.storage.get(&key).await? {
+                deliveries.push(serde_json::from_slice(&json).map_err(|e| e.to_string())?);
+            }
+        }
+        Ok(deliveries)
+    }
+
+    async fn remove(&self, id: &str) -> Result<(), String> {
+        self.storage
+            .delete(&format!("{DELIVERY_PREFIX}{id}"))
+            .await
+            .map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn exercise(storage: &dyn Storage) {
+        storage.put("a:1", b"one".to_vec(), None).await.unwrap();
+        storage.put("a:2", b"two".to_vec(), None).await.unwrap();
+        storage.put("b:1", b"three".to_vec(), None).await.unwrap();
+        storage
+            .put("a:3", b"gone".to_vec(), Some(Duration::ZERO))
+            .await
+            .unwrap();
+        assert_eq!(storage.get("a:1").await.unwrap(), Some(b"one".to_vec()));
+        assert_eq!(storage.get("a:3").await.unwrap(), None);
+        assert_eq!(storage.list("a:").await.unwrap(), ["a:1", "a:2"]);
+
+        assert!(!storage.put_new("a:1", b"x".to_vec(), None).await.unwrap());
+        assert!(storage.put_new("a:3", b"x".to_vec(), None).await.unwrap());
+        assert!(storage.delete("a:1").await.unwrap());
+        assert!(!storage.delete("a:1").await.unwrap());
+        assert_eq!(storage.list("a:").await.unwrap(), ["a:2", "a:3"]);
+    }
+
+    #[tokio::test]
+    async fn memory_storage_expires_and_lists_by_prefix() {
+        exercise(&MemoryStorage::default()).await;
+    }
+
+    #[tokio::test]
+    async fn file_storage_expires_and_lists_by_prefix() {
+        let dir = tempfile::tempdir().unwrap();
+        exercise(&FileStorage::open(dir.path()).unwrap()).await;
+        // Another instance over the same directory sees the same state.
+        let reopened = FileStorage::open(dir.path()).unwrap();
+        assert_eq!(reopened.get("b:1").await.unwrap(), Some(b"three".to_vec()));
+    }
+
+    #[tokio::test]
+    async fn backs_the_feature_stores() {
+        let stores = StorageStores::new(open_storage("memory").unwrap());
+        let ttl = Duration::from_secs(60);
+        assert_eq!(stores.first_seen("ipn-1", ttl).await, Ok(true));
+        assert_eq!(stores.first_seen("ipn-1", ttl).await, Ok(false));
+        assert!(open_storage("ftp://elsewhere").is_err());
+    }
+}
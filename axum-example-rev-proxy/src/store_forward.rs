@@ -0,0 +1,558 @@
+//! Store-and-forward for calls nobody waits on, such as post-booking
+//! notifications to a supplier. A non-GET request under one of
+//! `STORE_AND_FORWARD_PATHS` is saved and answered with 202 and a delivery
+//! ID; a background task then sends it upstream through the usual
+//! interceptors, retrying with backoff, and `GET /deliveries/{id}` reports
+//! how it went.
+//!
+//! A delivery is done once the upstream answers 2xx or 3xx, and has failed
+//! on any other 4xx or after `STORE_AND_FORWARD_MAX_ATTEMPTS` attempts.
+//! Deliveries live in memory, in `STORE_AND_FORWARD_DIR` (one JSON file
+//! each) or in the [`crate::storage`] backend, so they survive a restart;
+//! finished ones are dropped after a day.
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use axum::body::Bytes;
+use axum::extract::{Path, State};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use hyper::header::{self, HeaderMap, HeaderName, HeaderValue};
+use hyper::{Method, StatusCode};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+use tracing::{error, info, warn};
+
+use crate::app_state::AppState;
+use crate::correlation;
+use crate::error::ProxyError;
+use crate::interceptor::OutboundRequest;
+use crate::proxy::send_to_upstream;
+use crate::request_log::now_ms;
+use crate::storage::{Storage, StorageStores};
+
+pub const DEFAULT_MAX_ATTEMPTS: u32 = 8;
+pub const DEFAULT_RETRY_BASE: Duration = Duration::from_secs(5);
+/// Longest wait between two attempts.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60 * 60);
+/// How long finished deliveries can still be looked up.
+const RETENTION: Duration = Duration::from_secs(24 * 60 * 60);
+/// How often the deliverer looks for due deliveries between wake-ups.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct StoreForwardConfig {
+    /// Inbound path prefixes, e.g. `/prod/notifications`.
+    pub paths: Vec<String>,
+    /// Keeps deliveries here rather than in memory.
+    pub dir: Option<String>,
+    pub max_attempts: u32,
+    /// Wait before the first retry, doubling after each one.
+    pub retry_base: Duration,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeliveryState {
+    Pending,
+    Delivered,
+    Failed,
+}
+
+/// A stored request and how its delivery is going.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Delivery {
+    pub id: String,
+    pub env: String,
+    pub method: String,
+    /// Relative to the env's upstream.
+    pub path_and_query: String,
+    pub headers: Vec<(String, String)>,
+    /// Base64.
+    pub body: String,
+    pub created_ms: i64,
+    pub state: DeliveryState,
+    pub attempts: u32,
+    pub next_attempt_ms: i64,
+    pub last_status: Option<u16>,
+    pub last_error: Option<String>,
+    pub finished_ms: Option<i64>,
+}
+
+/// What `GET /deliveries/{id}` shows: everything but the request itself.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DeliveryStatus {
+    pub id: String,
+    pub state: DeliveryState,
+    pub attempts: u32,
+    pub last_status: Option<u16>,
+    pub last_error: Option<String>,
+    pub created_ms: i64,
+    pub finished_ms: Option<i64>,
+}
+
+impl From<&Delivery> for DeliveryStatus {
+    fn from(delivery: &Delivery) -> Self {
+        Self {
+            id: delivery.id.clone(),
+            state: delivery.state,
+            attempts: delivery.attempts,
+            last_status: delivery.last_status,
+            last_error: delivery.last_error.clone(),
+            created_ms: delivery.created_ms,
+            finished_ms: delivery.finished_ms,
+        }
+    }
+}
+
+#[async_trait]
+pub trait DeliveryStore: Send + Sync {
+    async fn save(&self, delivery: &Delivery) -> Result<(), String>;
+    async fn load(&self, id: &str) -> Result<Option<Delivery>, String>;
+    async fn all(&self) -> Result<Vec<Delivery>, String>;
+    async fn remove(&self, id: &str) -> Result<(), String>;
+}
+
+/// Lost on restart.
+#[derive(Debug, Default)]
+pub struct MemoryDeliveryStore {
+    deliveries: Mutex<BTreeMap<String, Delivery>>,
+}
+
+#[async_trait]
+impl DeliveryStore for MemoryDeliveryStore {
+    async fn save(&self, delivery: &Delivery) -> Result<(), String> {
+        self.deliveries
+            .lock()
+            .unwrap()
+            .insert(delivery.id.clone(), delivery.clone());
+        Ok(())
+    }
+
+    async fn load(&self, id: &str) -> Result<Option<Delivery>, String> {
+        Ok(self.deliveries.lock().unwrap().get(id).cloned())
+    }
+
+    async fn all(&self) -> Result<Vec<Delivery>, String> {
+        Ok(self.deliveries.lock().unwrap().values().cloned().collect())
+    }
+
+    async fn remove(&self, id: &str) -> Result<(), String> {
+        self.deliveries.lock().unwrap().remove(id);
+        Ok(())
+    }
+}
+
+/// One `{id}.json` per delivery in a directory, written atomically.
+#[derive(Debug)]
+pub struct FileDeliveryStore {
+    dir: PathBuf,
+}
+
+impl FileDeliveryStore {
+    pub fn open(dir: impl Into<PathBuf>) -> Result<Self, String> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir)
+            .map_err(|e| format!("can't create {}: {e}", dir.display()))?;
+        Ok(Self { dir })
+    }
+
+    fn path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{id}.json"))
+    }
+}
+
+#[async_trait]
+impl DeliveryStore for FileDeliveryStore {
+    async fn save(&self, delivery: &Delivery) -> Result<(), String> {
+        let json = serde_json::to_vec(delivery).map_err(|e| e.to_string())?;
+        let path = self.path(&delivery.id);
+        let temp = path.with_extension("tmp");
+        tokio::fs::write(&temp, json)
+            .await
+            .map_err(|e| format!("can't write {}: {e}", temp.display()))?;
+        tokio::fs::rename(&temp, &path)
+            .await
+            .map_err(|e| format!("can't write {}: {e}", path.display()))
+    }
+
+    async fn load(&self, id: &str) -> Result<Option<Delivery>, String> {
+        // IDs are hex; anything else can't name a file of ours.
+        if id.is_empty() || !id.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Ok(None);
+        }
+        match tokio::fs::read(self.path(id)).await {
+            Ok(json) => serde_json::from_slice(&json)
+                .map(Some)
+                .map_err(|e| e.to_string()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+
+    async fn all(&self) -> Result<Vec<Delivery>, String> {
+        let mut entries = tokio::fs::read_dir(&self.dir)
+            .await
+            .map_err(|e| e.to_string())?;
+        let mut deliveries = Vec::new();
+        while let Some(entry) = entries.next_entry().await.map_err(|e| e.to_string())? {
+            let path = entry.path();
+            if path.extension().is_none_or(|ext| ext != "json") {
+                continue;
+            }
+            match tokio::fs::read(&path)
+                .await
+                .map(|json| serde_json::from_slice(&json))
+            {
+                Ok(Ok(delivery)) => deliveries.push(delivery),
+                Ok(Err(e)) => warn!("Skipping unreadable delivery {}: {}", path.display(), e),
+                Err(e) => warn!("Skipping unreadable delivery {}: {}", path.display(), e),
+            }
+        }
+        Ok(deliveries)
+    }
+
+    async fn remove(&self, id: &str) -> Result<(), String> {
+        match tokio::fs::remove_file(self.path(id)).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.to_string()),
+            _ => Ok(()),
+        }
+    }
+}
+
+pub struct StoreForward {
+    config: StoreForwardConfig,
+    store: Arc<dyn DeliveryStore>,
+    wake: Notify,
+}
+
+impl StoreForward {
+    pub fn new(config: StoreForwardConfig, store: Arc<dyn DeliveryStore>) -> Self {
+        Self {
+            config,
+            store,
+            wake: Notify::new(),
+        }
+    }
+
+    /// From the config: on disk when `dir` is set, else in `storage` when
+    /// there is one, in memory otherwise.
+    pub fn for_config(config: &StoreForwardConfig, storage: Option<Arc<dyn Storage>>) -> Self {
+        let store: Arc<dyn DeliveryStore> = match (&config.dir, storage) {
+            (Some(dir), _) => Arc::new(
+                FileDeliveryStore::open(dir).expect("Failed to open the store-and-forward dir"),
+            ),
+            (None, Some(storage)) => Arc::new(StorageStores::new(storage)),
+            (None, None) => Arc::new(MemoryDeliveryStore::default()),
+        };
+        Self::new(config.clone(), store)
+    }
+
+    /// Whether a request is stored rather than proxied.
+    pub fn applies(&self, method: &Method, inbound_path: &str) -> bool {
+        !matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS)
+            && self
+                .config
+                .paths
+                .iter()
+                .any(|prefix| inbound_path.starts_with(prefix.as_str()))
+    }
+
+    /// Saves `outbound` for delivery, answering 202 with its ID.
+    /// `path_and_query` is relative to the env's upstream.
+    pub async fn accept(
+        &self,
+        app_state: &AppState,
+        outbound: &OutboundRequest,
+        path_and_query: String,
+    ) -> Result<Response, ProxyError> {
+        let now = now_ms();
+        let delivery = Delivery {
+            id: correlation::generate(),
+            env: outbound.env.clone(),
+            method: outbound.method.to_string(),
+            path_and_query,
+            headers: outbound
+                .headers
+                .iter()
+                .filter_map(|(name, value)| {
+                    Some((name.to_string(), value.to_str().ok()?.to_string()))
+                })
+                .collect(),
+            body: BASE64.encode(&outbound.body),
+            created_ms: now,
+            state: DeliveryState::Pending,
+            attempts: 0,
+            next_attempt_ms: now,
+            last_status: None,
+            last_error: None,
+            finished_ms: None,
+        };
+        self.store.save(&delivery).await.map_err(|e| {
+            error!("Failed to store a delivery to {}: {}", delivery.env, e);
+            ProxyError::StoreUnavailable
+        })?;
+        app_state.metrics.record_delivery(&delivery.env, "accepted");
+        info!(
+            "Stored {} {} for delivery {}",
+            delivery.method, delivery.path_and_query, delivery.id
+        );
+        self.wake.notify_one();
+
+        let location = format!("/deliveries/{}", delivery.id);
+        let mut response = (
+            StatusCode::ACCEPTED,
+            Json(serde_json::json!({
+                "id": delivery.id,
+                "state": delivery.state,
+                "status_url": location,
+            })),
+        )
+            .into_response();
+        if let Ok(location) = HeaderValue::from_str(&location) {
+            response.headers_mut().insert(header::LOCATION, location);
+        }
+        Ok(response)
+    }
+
+    pub async fn status(&self, id: &str) -> Result<Option<DeliveryStatus>, String> {
+        Ok(self
+            .store
+            .load(id)
+            .await?
+            .as_ref()
+            .map(DeliveryStatus::from))
+    }
+
+    /// Makes one attempt at every due delivery and drops expired ones.
+    pub async fn deliver_due(&self, app_state: &AppState) {
+        let deliveries = match self.store.all().await {
+            Ok(deliveries) => deliveries,
+            Err(e) => {
+                error!("Failed to read stored deliveries: {}", e);
+                return;
+            }
+        };
+        let now = now_ms();
+        for mut delivery in deliveries {
+            if delivery.state != DeliveryState::Pending {
+                let finished = delivery.finished_ms.unwrap_or(delivery.created_ms);
+                if now - finished > RETENTION.as_millis() as i64 {
+                    if let Err(e) = self.store.remove(&delivery.id).await {
+                        warn!("Failed to drop delivery {}: {}", delivery.id, e);
+                    }
+                }
+                continue;
+            }
+            if delivery.next_attempt_ms > now {
+                continue;
+            }
+            // A paused env gets its deliveries once it resumes, and one in
+            // maintenance once its window closes.
+            if app_state.traffic_pause.check(&delivery.env).is_err()
+                || app_state
+                    .maintenance
+                    .active_until(&delivery.env, chrono::Utc::now())
+                    .is_some()
+            {
+                continue;
+            }
+            self.attempt(app_state, &mut delivery).await;
+            if let Err(e) = self.store.save(&delivery).await {
+                error!("Failed to update delivery {}: {}", delivery.id, e);
+            }
+        }
+    }
+
+    async fn attempt(&self, app_state: &AppState, delivery: &mut Delivery) {
+        delivery.attempts += 1;
+        let result = match request_parts(delivery) {
+            Ok((method, headers, body)) => {
+                send_to_upstream(
+                    app_state,
+                    &delivery.env,
+                    method,
+                    &delivery.path_and_query,
+                    headers,
+                    body,
+                )
+                .await
+            }
+            Err(e) => {
+                warn!("Delivery {} can't be sent: {}", delivery.id, e);
+                Err(ProxyError::InvalidTarget)
+            }
+        };
+        let retry = match &result {
+            Ok(response) => {
+                delivery.last_status = Some(response.status.as_u16());
+                delivery.last_error = None;
+                let status = response.status;
+                status.is_server_error()
+                    || status == StatusCode::REQUEST_TIMEOUT
+                    || status == StatusCode::TOO_MANY_REQUESTS
+            }
+            Err(error) => {
+                delivery.last_error = Some(error.code().to_string());
+                error.retryable()
+            }
+        };
+        let delivered = result
+            .as_ref()
+            .is_ok_and(|response| response.status.as_u16() < 400);
+        let outcome = if delivered {
+            delivery.state = DeliveryState::Delivered;
+            "delivered"
+        } else if retry && delivery.attempts < self.config.max_attempts {
+            let delay = self
+                .config
+                .retry_base
+                .saturating_mul(1 << (delivery.attempts - 1).min(16))
+                .min(MAX_RETRY_DELAY);
+            delivery.next_attempt_ms = now_ms() + delay.as_millis() as i64;
+            "retried"
+        } else {
+            delivery.state = DeliveryState::Failed;
+            "failed"
+        };
+        if delivery.state != DeliveryState::Pending {
+            delivery.finished_ms = Some(now_ms());
+        }
+        app_state.metrics.record_delivery(&delivery.env, outcome);
+        match delivery.state {
+            DeliveryState::Failed => error!(
+                "Delivery {} to {} failed after {} attempts ({:?}, {:?})",
+                delivery.id,
+                delivery.env,
+                delivery.attempts,
+                delivery.last_status,
+                delivery.last_error
+            ),
+            _ => info!(
+                "Delivery {} to {}: {} on attempt {}",
+                delivery.id, delivery.env, outcome, delivery.attempts
+            ),
+        }
+    }
+}
+
+fn request_parts(delivery: &Delivery) -> Result<(Method, HeaderMap, Bytes), String> {
+    let method = Method::from_bytes(delivery.method.as_bytes()).map_err(|e| e.to_string())?;
+    let mut headers = HeaderMap::new();
+    for (name, value) in &delivery.headers {
+        headers.append(
+            HeaderName::from_bytes(name.as_bytes()).map_err(|e| e.to_string())?,
+            HeaderValue::from_str(value).map_err(|e| e.to_string())?,
+        );
+    }
+    let body = BASE64
+        .decode(&delivery.body)
+        .map_err(|e| format!("invalid body: {e}"))?;
+    Ok((method, headers, body.into()))
+}
+
+/// Starts delivering stored requests, if store-and-forward is configured.
+/// Deliveries left over from an earlier run are picked up too.
+pub fn spawn_deliverer(state: &AppState) -> Option<JoinHandle<()>> {
+    let store_forward = state.store_forward.clone()?;
+    let state = state.clone();
+    Some(tokio::spawn(async move {
+        loop {
+            store_forward.deliver_due(&state).await;
+            tokio::select! {
+                _ = tokio::time::sleep(POLL_INTERVAL) => {}
+                _ = store_forward.wake.notified() => {}
+            }
+        }
+    }))
+}
+
+/// `GET /deliveries/{id}`: how a stored request's delivery is going.
+pub async fn status_handler(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Json<DeliveryStatus>, StatusCode> {
+    let store_forward = state.store_forward.as_ref().ok_or(StatusCode::NOT_FOUND)?;
+    match store_forward.status(&id).await {
+        Ok(Some(status)) => Ok(Json(status)),
+        Ok(None) => Err(StatusCode::NOT_FOUND),
+        Err(e) => {
+            error!("Failed to look up delivery {}: {}", id, e);
+            Err(StatusCode::SERVICE_UNAVAILABLE)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn delivery(id: &str, state: DeliveryState) -> Delivery {
+        Delivery {
+            id: id.to_string(),
+            env: "prod".to_string(),
+            method: "POST".to_string(),
+            path_and_query: "/notify?booking=1".to_string(),
+            headers: vec![("content-type".to_string(), "application/json".to_string())],
+            body: BASE64.encode(b"{}"),
+            created_ms: 1,
+            state,
+            attempts: 0,
+            next_attempt_ms: 1,
+            last_status: None,
+            last_error: None,
+            finished_ms: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn file_store_round_trips_deliveries() {
+        let dir = std::env::temp_dir().join(format!("deliveries-{}", correlation::generate()));
+        let store = FileDeliveryStore::open(&dir).unwrap();
+        store
+            .save(&delivery("ab12", DeliveryState::Pending))
+            .await
+            .unwrap();
+        store
+            .save(&delivery("cd34", DeliveryState::Delivered))
+            .await
+            .unwrap();
+        assert_eq!(
+            store.load("ab12").await.unwrap(),
+            Some(delivery("ab12", DeliveryState::Pending))
+        );
+        assert_eq!(store.load("../etc/passwd").await.unwrap(), None);
+        assert_eq!(store.all().await.unwrap().len(), 2);
+        store.remove("ab12").await.unwrap();
+        assert_eq!(store.load("ab12").await.unwrap(), None);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn stores_writes_under_configured_paths() {
+        let store_forward = StoreForward::new(
+            StoreForwardConfig {
+                paths: vec!["/prod/notify".to_string()],
+                dir: None,
+                max_attempts: DEFAULT_MAX_ATTEMPTS,
+                retry_base: DEFAULT_RETRY_BASE,
+            },
+            Arc::new(MemoryDeliveryStore::default()),
+        );
+        assert!(store_forward.applies(&Method::POST, "/prod/notify/booking"));
+        assert!(!store_forward.applies(&Method::GET, "/prod/notify/booking"));
+        assert!(!store_forward.applies(&Method::POST, "/prod/book"));
+        let (method, headers, body) =
+            request_parts(&delivery("ab12", DeliveryState::Pending)).unwrap();
+        assert_eq!(method, Method::POST);
+        assert_eq!(headers["content-type"], "application/json");
+        assert_eq!(&body[..], b"{}");
+    }
+}
@@ -0,0 +1,170 @@
+//! `GET /admin/tail`: a live Server-Sent Events feed of requests as they
+//! complete, for watching traffic during an incident.
+//!
+//! Each event carries only the method, inbound path (without its query),
+//! status, latency and correlation ID; headers, bodies and client addresses
+//! never enter the feed. Subscribers that fall behind skip ahead and are
+//! told how many events they missed.
+
+use std::convert::Infallible;
+use std::time::Duration;
+
+use axum::extract::{Query, State};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use futures_util::stream::{self, Stream};
+use hyper::{Method, StatusCode};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::app_state::AppState;
+
+/// Events buffered per subscriber before it starts missing some.
+const CAPACITY: usize = 1024;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TailEvent {
+    pub env: String,
+    pub method: String,
+    pub path: String,
+    pub status: u16,
+    pub latency_ms: u64,
+    pub correlation_id: String,
+}
+
+/// Fans completed requests out to `/admin/tail` subscribers. Publishing
+/// with nobody subscribed costs next to nothing.
+#[derive(Debug)]
+pub struct Tail {
+    sender: broadcast::Sender<TailEvent>,
+}
+
+impl Default for Tail {
+    fn default() -> Self {
+        Self {
+            sender: broadcast::channel(CAPACITY).0,
+        }
+    }
+}
+
+impl Tail {
+    pub fn publish(
+        &self,
+        env: &str,
+        method: &Method,
+        path: &str,
+        status: StatusCode,
+        latency: Duration,
+        correlation_id: &str,
+    ) {
+        if self.sender.receiver_count() == 0 {
+            return;
+        }
+        let _ = self.sender.send(TailEvent {
+            env: env.to_string(),
+            method: method.to_string(),
+            path: path.split('?').next().unwrap_or_default().to_string(),
+            status: status.as_u16(),
+            latency_ms: latency.as_millis() as u64,
+            correlation_id: correlation_id.to_string(),
+        });
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<TailEvent> {
+        self.sender.subscribe()
+    }
+}
+
+/// `?env=test&status=5xx`: `status` is a code (`502`) or a class (`5xx`).
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct TailFilter {
+    pub env: Option<String>,
+    pub status: Option<String>,
+}
+
+impl TailFilter {
+    pub fn matches(&self, event: &TailEvent) -> bool {
+        if self.env.as_ref().is_some_and(|env| *env != event.env) {
+            return false;
+        }
+        match self.status.as_deref().map(str::to_ascii_lowercase) {
+            None => true,
+            Some(status) => match status.strip_suffix("xx") {
+                Some(class) => class == (event.status / 100).to_string(),
+                None => status == event.status.to_string(),
+            },
+        }
+    }
+}
+
+/// `GET /admin/tail?env=&status=`: one `request` event per completed
+/// request, and a `lagged` event with the number skipped when the
+/// subscriber falls behind.
+pub async fn tail_handler(
+    State(state): State<AppState>,
+    Query(filter): Query<TailFilter>,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let receiver = state.tail.subscribe();
+    let events = stream::unfold((receiver, filter), |(mut receiver, filter)| async move {
+        loop {
+            let event = match receiver.recv().await {
+                Ok(event) if filter.matches(&event) => Event::default()
+                    .event("request")
+                    .json_data(&event)
+                    .expect("tail events serialize"),
+                Ok(_) => continue,
+                Err(RecvError::Lagged(missed)) => {
+                    Event::default().event("lagged").data(missed.to_string())
+                }
+                Err(RecvError::Closed) => return None,
+            };
+            return Some((Ok(event), (receiver, filter)));
+        }
+    });
+    Sse::new(events).keep_alive(KeepAlive::default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(env: &str, status: u16) -> TailEvent {
+        TailEvent {
+            env: env.to_string(),
+            method: "GET".to_string(),
+            path: "/test/api".to_string(),
+            status,
+            latency_ms: 12,
+            correlation_id: "abc".to_string(),
+        }
+    }
+
+    #[test]
+    fn filters_by_env_and_status() {
+        let filter = |env: Option<&str>, status: Option<&str>| TailFilter {
+            env: env.map(str::to_string),
+            status: status.map(str::to_string),
+        };
+        assert!(filter(None, None).matches(&event("test", 200)));
+        assert!(filter(Some("test"), None).matches(&event("test", 200)));
+        assert!(!filter(Some("prod"), None).matches(&event("test", 200)));
+        assert!(filter(None, Some("5XX")).matches(&event("test", 503)));
+        assert!(!filter(None, Some("5xx")).matches(&event("test", 404)));
+        assert!(filter(None, Some("404")).matches(&event("test", 404)));
+        assert!(!filter(None, Some("404")).matches(&event("test", 400)));
+    }
+
+    #[test]
+    fn drops_the_query_from_paths() {
+        let tail = Tail::default();
+        let mut receiver = tail.subscribe();
+        tail.publish(
+            "test",
+            &Method::GET,
+            "/test/api?token=secret",
+            StatusCode::OK,
+            Duration::from_millis(5),
+            "abc",
+        );
+        assert_eq!(receiver.try_recv().unwrap().path, "/test/api");
+    }
+}
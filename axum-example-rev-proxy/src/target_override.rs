@@ -0,0 +1,110 @@
+//! Operator override of a request's upstream target, for debugging one
+//! supplier node: `X-Proxy-Target-Override` names a configured target of the
+//! request's env (its upstream, a balanced node or its secondary) by base URL
+//! or `host:port`, and the request goes there, bypassing balancing, failover
+//! and the response cache.
+//!
+//! The override is honoured only with `X-Proxy-Admin-Token` set to an
+//! operator's admin token ([`crate::admin::ADMIN_TOKEN_HEADER`]). Neither header is forwarded,
+//! and every use is written to the `audit` log target.
+
+use hyper::header::HeaderMap;
+use hyper::Uri;
+use tracing::warn;
+
+use crate::app_state::AppState;
+use crate::error::ProxyError;
+use crate::rbac::{Principal, Role};
+
+pub const TARGET_OVERRIDE_HEADER: &str = "x-proxy-target-override";
+
+/// Every target `env`'s requests may be sent to.
+pub fn targets<'a>(state: &'a AppState, env: &str) -> Vec<&'a str> {
+    let mut targets: Vec<&str> = state.env_var_config.upstream_for(env).into_iter().collect();
+    for target in state
+        .balancer
+        .pool(env)
+        .iter()
+        .map(String::as_str)
+        .chain(state.failover.secondary_for(env))
+    {
+        if !targets.contains(&target) {
+            targets.push(target);
+        }
+    }
+    targets
+}
+
+/// Removes the override header, returning the base URL to force when an
+/// override was asked for by an operator.
+pub fn take(
+    state: &AppState,
+    env: &str,
+    route: &str,
+    headers: &mut HeaderMap,
+    principal: Option<&Principal>,
+) -> Result<Option<String>, ProxyError> {
+    let Some(requested) = headers.remove(TARGET_OVERRIDE_HEADER) else {
+        return Ok(None);
+    };
+    let requested = String::from_utf8_lossy(requested.as_bytes())
+        .trim()
+        .to_string();
+    let Some(principal) = principal.filter(|p| p.role >= Role::Operator) else {
+        warn!(
+            target: "audit",
+            "Refused target override to {:?} for {}: needs an operator's admin token",
+            requested, route
+        );
+        return Err(ProxyError::OverrideRefused);
+    };
+    let Some(target) = targets(state, env)
+        .into_iter()
+        .find(|target| names(target, &requested))
+    else {
+        warn!(
+            target: "audit",
+            "Refused target override to {:?} for {}: not a configured target of {}",
+            requested, route, env
+        );
+        return Err(ProxyError::UnknownTarget);
+    };
+    warn!(
+        target: "audit",
+        "{} forced {} to {} with a target override",
+        principal, route, target
+    );
+    Ok(Some(target.to_string()))
+}
+
+/// Whether `requested` names `target`, by base URL or by authority.
+fn names(target: &str, requested: &str) -> bool {
+    if target.trim_end_matches('/') == requested.trim_end_matches('/') {
+        return true;
+    }
+    target
+        .parse::<Uri>()
+        .ok()
+        .and_then(|uri| uri.authority().cloned())
+        .is_some_and(|authority| authority.as_str().eq_ignore_ascii_case(requested))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_targets_by_url_or_authority() {
+        assert!(names(
+            "https://node2.supplier.com/v1",
+            "https://node2.supplier.com/v1/"
+        ));
+        assert!(names("https://node2.supplier.com/v1", "NODE2.supplier.com"));
+        assert!(names("http://10.0.0.5:8080", "10.0.0.5:8080"));
+        assert!(!names("http://10.0.0.5:8080", "10.0.0.5"));
+        assert!(!names(
+            "https://node2.supplier.com",
+            "https://node3.supplier.com"
+        ));
+    }
+}
@@ -0,0 +1,170 @@
+//! L4 tunnels for suppliers whose protocols aren't HTTP (e.g. a raw TLS
+//! socket): each listens on a local address and pipes every connection to a
+//! fixed remote `host:port`, leaving from the static egress IP. TLS passes
+//! through untouched, end to end.
+//!
+//! Only allowlisted client IPs may connect, and remote hostnames follow the
+//! forward proxy's internal-address rules (see
+//! [`crate::forward_proxy::connect`]).
+
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::Deserialize;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+use crate::app_state::AppState;
+use crate::forward_proxy::{self, DEFAULT_CONNECT_TIMEOUT};
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct TcpTunnel {
+    pub listen: SocketAddr,
+    pub host: String,
+    pub port: u16,
+}
+
+impl TcpTunnel {
+    pub fn remote(&self) -> String {
+        format!("{}:{}", self.host, self.port)
+    }
+}
+
+/// `0.0.0.0:7443=xml.supplier.com:7443,127.0.0.1:7000=[2001:db8::1]:700`
+pub fn parse_tunnels(value: &str) -> Result<Vec<TcpTunnel>, String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(|entry| {
+            let (listen, remote) = entry
+                .split_once('=')
+                .ok_or_else(|| format!("tunnel {entry:?} must be listen=host:port"))?;
+            let listen = listen
+                .trim()
+                .parse()
+                .map_err(|e| format!("tunnel {entry:?}: bad listen address: {e}"))?;
+            let (host, port) = remote
+                .trim()
+                .rsplit_once(':')
+                .ok_or_else(|| format!("tunnel {entry:?} needs a remote port"))?;
+            let port = port
+                .parse()
+                .map_err(|e| format!("tunnel {entry:?}: bad remote port: {e}"))?;
+            if host.is_empty() {
+                return Err(format!("tunnel {entry:?} needs a remote host"));
+            }
+            Ok(TcpTunnel {
+                listen,
+                host: host.to_string(),
+                port,
+            })
+        })
+        .collect()
+}
+
+/// Binds every configured tunnel and serves them in the background.
+pub async fn spawn_tunnels(state: &AppState) -> std::io::Result<Vec<JoinHandle<()>>> {
+    let mut handles = Vec::new();
+    for tunnel in &state.env_var_config.tcp_tunnels {
+        let listener = TcpListener::bind(tunnel.listen).await?;
+        info!(
+            "TCP tunnel listening on {} for {}",
+            listener.local_addr()?,
+            tunnel.remote()
+        );
+        handles.push(tokio::spawn(serve(
+            listener,
+            Arc::new(tunnel.clone()),
+            state.clone(),
+        )));
+    }
+    Ok(handles)
+}
+
+/// Pipes connections accepted on `listener` to `tunnel`'s remote until the
+/// process exits.
+pub async fn serve(listener: TcpListener, tunnel: Arc<TcpTunnel>, state: AppState) {
+    loop {
+        let (client, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                warn!("TCP tunnel failed to accept a connection: {}", e);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        let (state, tunnel) = (state.clone(), tunnel.clone());
+        tokio::spawn(async move { pipe(&state, &tunnel, client, peer).await });
+    }
+}
+
+async fn pipe(state: &AppState, tunnel: &TcpTunnel, mut client: TcpStream, peer: SocketAddr) {
+    let remote = tunnel.remote();
+    if !client_allowed(&state.env_var_config.tcp_tunnel_allowed_clients, peer.ip()) {
+        warn!(
+            "TCP tunnel to {} refused {}: not an allowed client",
+            remote, peer
+        );
+        state.metrics.record_tunnel("tcp", "refused");
+        return;
+    }
+    let mut upstream =
+        match forward_proxy::connect(state, &tunnel.host, tunnel.port, DEFAULT_CONNECT_TIMEOUT)
+            .await
+        {
+            Ok(upstream) => upstream,
+            Err(e) => {
+                warn!("TCP tunnel could not reach {}: {}", remote, e);
+                state.metrics.record_tunnel("tcp", "unreachable");
+                return;
+            }
+        };
+    state.metrics.record_tunnel("tcp", "opened");
+    info!("Tunnelling {} to {} over TCP", peer, remote);
+    match tokio::io::copy_bidirectional(&mut client, &mut upstream).await {
+        Ok((sent, received)) => info!(
+            "TCP tunnel to {} closed after {} bytes out, {} in",
+            remote, sent, received
+        ),
+        Err(e) => info!("TCP tunnel to {} closed: {}", remote, e),
+    }
+}
+
+/// Any client is allowed when the list is empty. IPv4-mapped peers (from a
+/// dual-stack listener) match their IPv4 entry.
+pub(crate) fn client_allowed(allowed: &[IpAddr], peer: IpAddr) -> bool {
+    let peer = match peer {
+        IpAddr::V6(ip) => ip.to_ipv4_mapped().map_or(peer, IpAddr::V4),
+        IpAddr::V4(_) => peer,
+    };
+    allowed.is_empty() || allowed.contains(&peer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_tunnels() {
+        let tunnels =
+            parse_tunnels("0.0.0.0:7443=xml.supplier.com:7443, [::1]:7000=[2001:db8::1]:700")
+                .unwrap();
+        assert_eq!(tunnels[0].remote(), "xml.supplier.com:7443");
+        assert_eq!(tunnels[1].listen, "[::1]:7000".parse().unwrap());
+        assert_eq!(tunnels[1].host, "[2001:db8::1]");
+        assert!(parse_tunnels("0.0.0.0:7443").is_err());
+        assert!(parse_tunnels("0.0.0.0:7443=supplier").is_err());
+    }
+
+    #[test]
+    fn allows_listed_clients() {
+        let allowed = ["10.0.0.7".parse().unwrap()];
+        assert!(client_allowed(&allowed, "10.0.0.7".parse().unwrap()));
+        assert!(client_allowed(&allowed, "::ffff:10.0.0.7".parse().unwrap()));
+        assert!(!client_allowed(&allowed, "10.0.0.8".parse().unwrap()));
+        assert!(client_allowed(&[], "10.0.0.8".parse().unwrap()));
+    }
+}
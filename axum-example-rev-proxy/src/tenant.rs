@@ -0,0 +1,289 @@
+//! Tenant namespaces, for internal products sharing this egress box. Each
+//! tenant is served under its own route prefix, `/{tenant}/{env}/...`, with
+//! its own upstreams, API keys, rate limit and metrics:
+//!
+//! - requests must carry one of the tenant's keys in `X-Api-Key`, which is
+//!   not forwarded; another tenant's key is refused like a wrong one;
+//! - the tenant's envs are known internally as `{tenant}.{env}` (the label
+//!   in its metrics and logs) and can't be reached through the shared
+//!   `/{env}/...` route;
+//! - the rate limit, when set, is one budget for the whole tenant, which
+//!   also queues for upstream slots as one client;
+//! - traffic is counted in the tenant's own [`Metrics`], served at
+//!   `/{tenant}/metrics` to the tenant's keys and the admin token, and kept
+//!   out of the shared `/metrics`.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Arc;
+
+use axum::extract::{Path, Request, State};
+use axum::http::{HeaderMap, HeaderValue, StatusCode, Uri};
+use axum::response::{IntoResponse, Response};
+use serde::Deserialize;
+use tracing::warn;
+
+use crate::admin::{self, constant_time_eq};
+use crate::app_state::AppState;
+use crate::error::ProxyError;
+use crate::masking::CallerKey;
+use crate::metrics::Metrics;
+use crate::proxy;
+use crate::rate_limit::{ClientKey, RateLimit, RateLimitStore, RateLimiter};
+
+pub const API_KEY_HEADER: &str = "x-api-key";
+
+/// First path segments taken by the proxy's own routes.
+const RESERVED_NAMES: [&str; 10] = [
+    "admin",
+    "deliveries",
+    "email",
+    "egress-ip",
+    "info",
+    "metrics",
+    "nowpayments-webhook",
+    "openapi.json",
+    "readyz",
+    "slo",
+];
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct TenantConfig {
+    /// The route prefix; lowercase letters, digits, `-` and `_`.
+    pub name: String,
+    /// Upstream base URLs by env name.
+    pub upstreams: BTreeMap<String, String>,
+    pub api_keys: Vec<String>,
+    /// Requests allowed for the whole tenant; unlimited when unset.
+    pub rate_limit: Option<RateLimit>,
+}
+
+/// Parses a tenant's upstreams, `env=url` pairs separated by commas.
+pub fn parse_upstreams(value: &str) -> Result<BTreeMap<String, String>, String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(|pair| match pair.split_once('=') {
+            Some((env, url)) if !env.trim().is_empty() && !url.trim().is_empty() => {
+                Ok((env.trim().to_string(), url.trim().to_string()))
+            }
+            _ => Err(format!("expected env=url, got {pair}")),
+        })
+        .collect()
+}
+
+/// The internal env name of a tenant's `env`.
+pub fn qualified_env(tenant: &str, env: &str) -> String {
+    format!("{tenant}.{env}")
+}
+
+/// Splits a `{tenant}.{env}` name; shared envs never contain a dot.
+pub fn split_env(env: &str) -> Option<(&str, &str)> {
+    env.split_once('.')
+}
+
+/// Checks tenant names and keys before anything is served: names must be
+/// usable as a route prefix without shadowing a shared env or route, and no
+/// key may open two tenants.
+pub fn validate(
+    tenants: &[TenantConfig],
+    upstreams: &BTreeMap<String, String>,
+) -> Result<(), String> {
+    let mut names = BTreeSet::new();
+    let mut keys = BTreeSet::new();
+    for tenant in tenants {
+        let name = &tenant.name;
+        let valid = !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_');
+        if !valid {
+            return Err(format!(
+                "tenant {name:?} must be lowercase letters, digits, - and _"
+            ));
+        }
+        if RESERVED_NAMES.contains(&name.as_str()) || upstreams.contains_key(name) {
+            return Err(format!("tenant {name:?} clashes with a route or env"));
+        }
+        if !names.insert(name) {
+            return Err(format!("tenant {name:?} is configured twice"));
+        }
+        if tenant.upstreams.is_empty() {
+            return Err(format!("tenant {name:?} has no upstreams"));
+        }
+        if let Some(env) = tenant.upstreams.keys().find(|env| env.contains('.')) {
+            return Err(format!(
+                "tenant {name:?} env {env:?} must not contain a dot"
+            ));
+        }
+        if tenant.api_keys.is_empty() {
+            return Err(format!("tenant {name:?} has no API keys"));
+        }
+        if !tenant.api_keys.iter().all(|key| keys.insert(key)) {
+            return Err(format!("tenant {name:?} reuses an API key"));
+        }
+    }
+    Ok(())
+}
+
+pub struct Tenant {
+    pub config: TenantConfig,
+    pub metrics: Arc<Metrics>,
+    limiter: Option<Arc<RateLimiter>>,
+}
+
+impl Tenant {
+    fn accepts(&self, key: &[u8]) -> bool {
+        // Every key is compared, so timing doesn't tell which one was close.
+        self.config.api_keys.iter().fold(false, |found, expected| {
+            constant_time_eq(key, expected.as_bytes()) | found
+        })
+    }
+}
+
+#[derive(Default)]
+pub struct Tenants {
+    by_name: BTreeMap<String, Arc<Tenant>>,
+}
+
+impl Tenants {
+    pub fn new(configs: &[TenantConfig], store: Arc<dyn RateLimitStore>) -> Self {
+        let by_name = configs
+            .iter()
+            .map(|config| {
+                let tenant = Tenant {
+                    config: config.clone(),
+                    metrics: Arc::new(Metrics::default()),
+                    limiter: config
+                        .rate_limit
+                        .map(|limit| Arc::new(RateLimiter::new(limit, store.clone()))),
+                };
+                (config.name.clone(), Arc::new(tenant))
+            })
+            .collect();
+        Self { by_name }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Arc<Tenant>> {
+        self.by_name.get(name)
+    }
+}
+
+/// The tenant whose prefix `uri` is under.
+fn tenant_for<'a>(state: &'a AppState, uri: &Uri) -> Option<&'a Arc<Tenant>> {
+    let name = uri.path().trim_start_matches('/').split('/').next()?;
+    state.tenants.get(name)
+}
+
+/// The caller's key, if it opens `tenant`.
+fn take_api_key(tenant: &Tenant, headers: &mut HeaderMap) -> Option<HeaderValue> {
+    headers
+        .remove(API_KEY_HEADER)
+        .filter(|key| tenant.accepts(key.as_bytes()))
+}
+
+/// `/{tenant}/{env}/{*path}`: proxies to the tenant's `env` upstream.
+pub async fn proxy_handler(
+    State(state): State<AppState>,
+    Path((env, _)): Path<(String, String)>,
+    mut req: Request,
+) -> Response {
+    let Some(tenant) = tenant_for(&state, req.uri()).cloned() else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let name = &tenant.config.name;
+    let Some(key) = take_api_key(&tenant, req.headers_mut()) else {
+        warn!(
+            target: "audit",
+            "Refused a request to tenant {}: missing or wrong API key", name
+        );
+        let correlation_id = state.correlation.ensure(req.headers_mut());
+        return ProxyError::TenantUnauthorized.to_response(&correlation_id);
+    };
+
+    // From here on the request is the shared route's, under the qualified
+    // env, counted and limited as the tenant's.
+    let env = qualified_env(name, &env);
+    let rest = req
+        .uri()
+        .path_and_query()
+        .map_or("", |pq| pq.as_str())
+        .trim_start_matches('/')
+        .splitn(3, '/')
+        .nth(2)
+        .unwrap_or_default()
+        .to_string();
+    // Only ever drops the prefix of a URI that already parsed.
+    *req.uri_mut() = format!("/{env}/{rest}").parse().unwrap();
+    req.extensions_mut()
+        .insert(ClientKey(format!("tenant:{name}")));
+    // Masking rules may name tenant keys.
+    req.extensions_mut().insert(CallerKey(key));
+    let mut scoped = state.clone();
+    scoped.metrics = tenant.metrics.clone();
+    scoped.rate_limiter = tenant.limiter.clone();
+    proxy::serve(scoped, env, req).await
+}
+
+/// `/{tenant}/metrics`: the tenant's own counters.
+pub async fn metrics_handler(
+    State(state): State<AppState>,
+    req: Request,
+) -> Result<String, StatusCode> {
+    let Some(tenant) = tenant_for(&state, req.uri()).cloned() else {
+        return Err(StatusCode::NOT_FOUND);
+    };
+    let mut headers = req.headers().clone();
+    if take_api_key(&tenant, &mut headers).is_some()
+        || admin::take_admin_token(&state, &mut headers).is_some()
+    {
+        return Ok(tenant.metrics.render());
+    }
+    warn!(
+        target: "audit",
+        "Refused tenant {} metrics: missing or wrong API key", tenant.config.name
+    );
+    Err(StatusCode::UNAUTHORIZED)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tenant(name: &str, key: &str) -> TenantConfig {
+        TenantConfig {
+            name: name.to_string(),
+            upstreams: BTreeMap::from([("prod".to_string(), "https://a.example".to_string())]),
+            api_keys: vec![key.to_string()],
+            rate_limit: None,
+        }
+    }
+
+    #[test]
+    fn validates_names_and_keys() {
+        let shared = BTreeMap::from([("prod".to_string(), "https://b.example".to_string())]);
+        assert!(validate(&[tenant("booking", "k1"), tenant("pay-2", "k2")], &shared).is_ok());
+        for bad in ["Booking", "a.b", "", "admin", "prod"] {
+            assert!(validate(&[tenant(bad, "k1")], &shared).is_err(), "{bad}");
+        }
+        assert!(validate(&[tenant("a", "k1"), tenant("b", "k1")], &shared).is_err());
+        assert!(validate(&[tenant("a", "k1"), tenant("a", "k2")], &shared).is_err());
+    }
+
+    #[test]
+    fn parses_env_url_pairs() {
+        assert_eq!(
+            parse_upstreams("prod=https://a.example, test = http://b.example:8080").unwrap(),
+            BTreeMap::from([
+                ("prod".to_string(), "https://a.example".to_string()),
+                ("test".to_string(), "http://b.example:8080".to_string()),
+            ])
+        );
+        for bad in ["https://a.example", "prod=", "=https://a.example"] {
+            assert_eq!(
+                parse_upstreams(bad),
+                Err(format!("expected env=url, got {bad}"))
+            );
+        }
+    }
+}
@@ -0,0 +1,170 @@
+//! Where a request's time went, sent back as `X-Proxy-Timing-*` headers when
+//! the caller asks with `X-Proxy-Debug: timings`, so developers can see it
+//! without server logs.
+//!
+//! DNS and connect times are recorded by the resolver and connector while
+//! the request's own task drives them; a pooled connection needed neither,
+//! so both read 0.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use hyper::header::{HeaderMap, HeaderName, HeaderValue};
+use tower::{Layer, Service};
+
+pub const DEBUG_HEADER: HeaderName = HeaderName::from_static("x-proxy-debug");
+
+const TIMING_DNS: HeaderName = HeaderName::from_static("x-proxy-timing-dns");
+const TIMING_CONNECT: HeaderName = HeaderName::from_static("x-proxy-timing-connect");
+const TIMING_TTFB: HeaderName = HeaderName::from_static("x-proxy-timing-ttfb");
+const TIMING_TOTAL: HeaderName = HeaderName::from_static("x-proxy-timing-total");
+
+tokio::task_local! {
+    static CURRENT: Arc<RequestTimings>;
+}
+
+/// Microseconds spent in each phase of one request.
+#[derive(Debug, Default)]
+pub struct RequestTimings {
+    dns_us: AtomicU64,
+    /// The whole connector call, which includes DNS.
+    connect_us: AtomicU64,
+    ttfb_us: AtomicU64,
+}
+
+/// Whether the caller asked for timing headers.
+pub fn wants_timings(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(DEBUG_HEADER)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|token| token.trim().eq_ignore_ascii_case("timings"))
+}
+
+/// Runs `fut` with `timings` collecting what the resolver, connector and
+/// upstream call record.
+pub async fn scope<F: Future>(timings: Arc<RequestTimings>, fut: F) -> F::Output {
+    CURRENT.scope(timings, fut).await
+}
+
+fn add(field: fn(&RequestTimings) -> &AtomicU64, elapsed: Duration) {
+    let _ = CURRENT.try_with(|timings| {
+        field(timings).fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    });
+}
+
+pub fn record_dns(elapsed: Duration) {
+    add(|timings| &timings.dns_us, elapsed);
+}
+
+pub fn record_connect(elapsed: Duration) {
+    add(|timings| &timings.connect_us, elapsed);
+}
+
+/// Time until the upstream's response headers arrived. With hedging, the
+/// attempt that answered last is kept.
+pub fn record_ttfb(elapsed: Duration) {
+    let _ = CURRENT.try_with(|timings| {
+        timings
+            .ttfb_us
+            .store(elapsed.as_micros() as u64, Ordering::Relaxed);
+    });
+}
+
+impl RequestTimings {
+    /// Adds the timing headers, in milliseconds, to a response.
+    pub fn apply(&self, headers: &mut HeaderMap, total: Duration) {
+        let dns = self.dns_us.load(Ordering::Relaxed);
+        let connect = self.connect_us.load(Ordering::Relaxed).saturating_sub(dns);
+        let ttfb = self.ttfb_us.load(Ordering::Relaxed);
+        let total = total.as_micros() as u64;
+        for (name, us) in [
+            (TIMING_DNS, dns),
+            (TIMING_CONNECT, connect),
+            (TIMING_TTFB, ttfb),
+            (TIMING_TOTAL, total),
+        ] {
+            let value = format!("{:.3}", us as f64 / 1000.0);
+            headers.insert(
+                name,
+                HeaderValue::from_str(&value).expect("numbers are valid"),
+            );
+        }
+    }
+}
+
+/// Connector layer for the upstream client that records connect times.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ConnectTimingLayer;
+
+impl<S> Layer<S> for ConnectTimingLayer {
+    type Service = ConnectTiming<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        ConnectTiming { inner }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ConnectTiming<S> {
+    inner: S,
+}
+
+impl<S, R> Service<R> for ConnectTiming<S>
+where
+    S: Service<R>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<S::Response, S::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: R) -> Self::Future {
+        let started = Instant::now();
+        let connecting = self.inner.call(request);
+        Box::pin(async move {
+            let connected = connecting.await;
+            record_connect(started.elapsed());
+            connected
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn records_only_inside_a_scope() {
+        record_dns(Duration::from_millis(5));
+
+        let timings = Arc::new(RequestTimings::default());
+        scope(timings.clone(), async {
+            record_dns(Duration::from_micros(1500));
+            record_connect(Duration::from_micros(4000));
+            record_ttfb(Duration::from_millis(20));
+        })
+        .await;
+
+        let mut headers = HeaderMap::new();
+        timings.apply(&mut headers, Duration::from_millis(25));
+        assert_eq!(headers[TIMING_DNS], "1.500");
+        assert_eq!(headers[TIMING_CONNECT], "2.500");
+        assert_eq!(headers[TIMING_TTFB], "20.000");
+        assert_eq!(headers[TIMING_TOTAL], "25.000");
+
+        let mut asked = HeaderMap::new();
+        asked.insert(DEBUG_HEADER, HeaderValue::from_static("trace, Timings"));
+        assert!(wants_timings(&asked));
+        assert!(!wants_timings(&HeaderMap::new()));
+    }
+}
@@ -0,0 +1,378 @@
+//! Telemetry for large bodies going to and coming from upstreams. A body of
+//! at least `LARGE_TRANSFER_BYTES` is tracked while it moves: its progress
+//! (bytes so far and throughput) is logged every `TRANSFER_PROGRESS_SECS`,
+//! with a warning while it makes none, and `GET /admin/transfers` lists the
+//! ones in flight. A stuck upload to a supplier shows up there long before
+//! the request times out.
+
+use std::collections::BTreeMap;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use axum::body::Bytes;
+use axum::extract::State;
+use axum::Json;
+use hyper::body::{Body, Frame, SizeHint};
+use serde::Serialize;
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+use crate::app_state::AppState;
+
+pub const DEFAULT_LARGE_TRANSFER_BYTES: usize = 1024 * 1024;
+pub const DEFAULT_PROGRESS_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Uploads are handed to the connection in pieces of this size, so progress
+/// follows what the socket takes.
+const UPLOAD_CHUNK: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TransferKind {
+    /// A request body going upstream.
+    Upload,
+    /// A response body coming back.
+    Download,
+}
+
+impl TransferKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Upload => "upload",
+            Self::Download => "download",
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Progress {
+    id: u64,
+    kind: TransferKind,
+    env: String,
+    /// The upstream URI without its query, which may hold credentials.
+    target: String,
+    expected_bytes: Option<u64>,
+    started: Instant,
+    bytes: AtomicU64,
+    /// Since `started`, when the last bytes moved.
+    last_progress_ms: AtomicU64,
+}
+
+impl Progress {
+    fn view(&self) -> TransferView {
+        let elapsed = self.started.elapsed();
+        let bytes = self.bytes.load(Ordering::Relaxed);
+        let last_progress = Duration::from_millis(self.last_progress_ms.load(Ordering::Relaxed));
+        TransferView {
+            id: self.id,
+            kind: self.kind,
+            env: self.env.clone(),
+            target: self.target.clone(),
+            bytes,
+            expected_bytes: self.expected_bytes,
+            elapsed_ms: elapsed.as_millis() as u64,
+            bytes_per_sec: throughput(bytes, elapsed),
+            idle_ms: elapsed.saturating_sub(last_progress).as_millis() as u64,
+        }
+    }
+}
+
+/// A transfer in flight, as listed by `GET /admin/transfers`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct TransferView {
+    pub id: u64,
+    pub kind: TransferKind,
+    pub env: String,
+    pub target: String,
+    pub bytes: u64,
+    pub expected_bytes: Option<u64>,
+    pub elapsed_ms: u64,
+    pub bytes_per_sec: u64,
+    /// How long since any bytes moved.
+    pub idle_ms: u64,
+}
+
+fn throughput(bytes: u64, elapsed: Duration) -> u64 {
+    match elapsed.as_millis() as u64 {
+        0 => 0,
+        ms => bytes.saturating_mul(1000) / ms,
+    }
+}
+
+/// The large transfers in flight.
+#[derive(Debug)]
+pub struct Transfers {
+    /// Bodies this big or bigger are tracked; none when unset.
+    threshold: Option<usize>,
+    interval: Duration,
+    next_id: AtomicU64,
+    active: Mutex<BTreeMap<u64, Arc<Progress>>>,
+}
+
+impl Transfers {
+    pub fn new(threshold: Option<usize>, interval: Duration) -> Self {
+        Self {
+            threshold,
+            interval,
+            next_id: AtomicU64::new(1),
+            active: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn is_large(&self, bytes: usize) -> bool {
+        self.threshold.is_some_and(|threshold| bytes >= threshold)
+    }
+
+    /// Starts tracking a transfer until the returned guard is dropped, and
+    /// logs its progress meanwhile. Needs a Tokio runtime.
+    pub fn start(
+        self: &Arc<Self>,
+        kind: TransferKind,
+        env: &str,
+        uri: &hyper::Uri,
+        expected_bytes: Option<u64>,
+    ) -> Transfer {
+        let target = match (uri.scheme_str(), uri.authority()) {
+            (Some(scheme), Some(authority)) => format!("{scheme}://{authority}{}", uri.path()),
+            _ => uri.path().to_string(),
+        };
+        let progress = Arc::new(Progress {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            kind,
+            env: env.to_string(),
+            target,
+            expected_bytes,
+            started: Instant::now(),
+            bytes: AtomicU64::new(0),
+            last_progress_ms: AtomicU64::new(0),
+        });
+        self.active
+            .lock()
+            .unwrap()
+            .insert(progress.id, progress.clone());
+        info!(
+            "Started {} #{} for {} ({} bytes expected)",
+            kind.as_str(),
+            progress.id,
+            progress.target,
+            expected_bytes.map_or("unknown".to_string(), |bytes| bytes.to_string())
+        );
+        Transfer {
+            ticker: tokio::spawn(report(progress.clone(), self.interval)),
+            registry: self.clone(),
+            progress,
+        }
+    }
+
+    /// In flight, oldest first.
+    pub fn active(&self) -> Vec<TransferView> {
+        self.active
+            .lock()
+            .unwrap()
+            .values()
+            .map(|progress| progress.view())
+            .collect()
+    }
+}
+
+/// Logs `progress` every `interval` until aborted.
+async fn report(progress: Arc<Progress>, interval: Duration) {
+    let mut ticks = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+    loop {
+        ticks.tick().await;
+        let view = progress.view();
+        if view.idle_ms >= interval.as_millis() as u64 {
+            warn!(
+                "{} #{} to {} has stalled: no progress for {}ms at {} bytes",
+                view.kind.as_str(),
+                view.id,
+                view.target,
+                view.idle_ms,
+                view.bytes
+            );
+        } else {
+            info!(
+                "{} #{} to {}: {} of {} bytes, {} bytes/s",
+                view.kind.as_str(),
+                view.id,
+                view.target,
+                view.bytes,
+                view.expected_bytes
+                    .map_or("unknown".to_string(), |bytes| bytes.to_string()),
+                view.bytes_per_sec
+            );
+        }
+    }
+}
+
+/// Tracks one transfer; it's over when this is dropped.
+#[derive(Debug)]
+pub struct Transfer {
+    registry: Arc<Transfers>,
+    progress: Arc<Progress>,
+    ticker: JoinHandle<()>,
+}
+
+impl Transfer {
+    pub fn advance(&self, bytes: usize) {
+        let progress = &self.progress;
+        progress.bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+        progress.last_progress_ms.store(
+            progress.started.elapsed().as_millis() as u64,
+            Ordering::Relaxed,
+        );
+    }
+}
+
+impl Drop for Transfer {
+    fn drop(&mut self) {
+        self.ticker.abort();
+        self.registry
+            .active
+            .lock()
+            .unwrap()
+            .remove(&self.progress.id);
+        let view = self.progress.view();
+        info!(
+            "{} #{} to {} ended after {} bytes in {}ms, {} bytes/s",
+            view.kind.as_str(),
+            view.id,
+            view.target,
+            view.bytes,
+            view.elapsed_ms,
+            view.bytes_per_sec
+        );
+    }
+}
+
+/// Follows a response body as it's read, which becomes a tracked transfer
+/// once it's known to be large: up front from its `Content-Length`, or when
+/// enough of it has arrived.
+pub struct Download {
+    transfers: Arc<Transfers>,
+    env: String,
+    uri: hyper::Uri,
+    expected_bytes: Option<u64>,
+    bytes: usize,
+    transfer: Option<Transfer>,
+}
+
+impl Download {
+    pub fn new(
+        transfers: &Arc<Transfers>,
+        env: &str,
+        uri: &hyper::Uri,
+        expected_bytes: Option<u64>,
+    ) -> Self {
+        let transfer = expected_bytes
+            .filter(|&expected| transfers.is_large(expected as usize))
+            .map(|expected| transfers.start(TransferKind::Download, env, uri, Some(expected)));
+        Self {
+            transfers: transfers.clone(),
+            env: env.to_string(),
+            uri: uri.clone(),
+            expected_bytes,
+            bytes: 0,
+            transfer,
+        }
+    }
+
+    pub fn advance(&mut self, bytes: usize) {
+        self.bytes += bytes;
+        match &self.transfer {
+            Some(transfer) => transfer.advance(bytes),
+            None if self.transfers.is_large(self.bytes) => {
+                let transfer = self.transfers.start(
+                    TransferKind::Download,
+                    &self.env,
+                    &self.uri,
+                    self.expected_bytes,
+                );
+                transfer.advance(self.bytes);
+                self.transfer = Some(transfer);
+            }
+            None => {}
+        }
+    }
+}
+
+/// A request body that reports its progress as the connection takes it.
+/// Its exact size is known, so it's still sent with a `Content-Length`.
+pub struct UploadBody {
+    remaining: Bytes,
+    transfer: Transfer,
+}
+
+impl UploadBody {
+    pub fn new(body: Bytes, transfer: Transfer) -> Self {
+        Self {
+            remaining: body,
+            transfer,
+        }
+    }
+}
+
+impl Body for UploadBody {
+    type Data = Bytes;
+    type Error = std::convert::Infallible;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, Self::Error>>> {
+        if self.remaining.is_empty() {
+            return Poll::Ready(None);
+        }
+        let size = self.remaining.len().min(UPLOAD_CHUNK);
+        let chunk = self.remaining.split_to(size);
+        self.transfer.advance(size);
+        Poll::Ready(Some(Ok(Frame::data(chunk))))
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.remaining.is_empty()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        SizeHint::with_exact(self.remaining.len() as u64)
+    }
+}
+
+/// `GET /admin/transfers`: large transfers in flight.
+pub async fn transfers_handler(State(state): State<AppState>) -> Json<Vec<TransferView>> {
+    Json(state.transfers.active())
+}
+
+#[cfg(test)]
+mod tests {
+    use http_body_util::BodyExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn tracks_uploads_until_sent() {
+        let transfers = Arc::new(Transfers::new(Some(100), Duration::from_secs(5)));
+        assert!(!transfers.is_large(99));
+        assert!(transfers.is_large(100));
+
+        let uri = "https://supplier.example/v1/upload?key=secret"
+            .parse()
+            .unwrap();
+        let transfer = transfers.start(TransferKind::Upload, "prod", &uri, Some(150_000));
+        let mut body = UploadBody::new(Bytes::from(vec![7u8; 150_000]), transfer);
+        assert_eq!(body.size_hint().exact(), Some(150_000));
+
+        let first = body.frame().await.unwrap().unwrap().into_data().unwrap();
+        assert_eq!(first.len(), UPLOAD_CHUNK);
+        let active = transfers.active();
+        assert_eq!(active[0].target, "https://supplier.example/v1/upload");
+        assert_eq!(active[0].bytes, UPLOAD_CHUNK as u64);
+
+        let rest = body.collect().await.unwrap().to_bytes();
+        assert_eq!(rest.len(), 150_000 - UPLOAD_CHUNK);
+        assert!(transfers.active().is_empty());
+    }
+}
@@ -0,0 +1,195 @@
+//! Experimental UDP relays, for QUIC/HTTP-3 supplier endpoints and NTP-like
+//! services that must also leave from the static egress IP. Each relay
+//! listens on a local address and forwards datagrams to a fixed remote
+//! `host:port`; replies go back to the client that sent them.
+//!
+//! Every client address gets its own upstream socket (a session), dropped
+//! once the remote has been quiet for the idle timeout. Clients follow the
+//! TCP tunnels' allowlist, remotes the forward proxy's internal-address
+//! rules, and traffic is counted per relay.
+
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use serde::Deserialize;
+use tokio::net::UdpSocket;
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+use crate::app_state::AppState;
+use crate::forward_proxy;
+use crate::tcp_tunnel::{self, client_allowed};
+
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Big enough for any UDP payload.
+const MAX_DATAGRAM: usize = 65_535;
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct UdpRelay {
+    pub listen: SocketAddr,
+    pub host: String,
+    pub port: u16,
+}
+
+impl UdpRelay {
+    /// `listen=host:port`, as configured; labels the relay's counters.
+    pub fn mapping(&self) -> String {
+        format!("{}={}:{}", self.listen, self.host, self.port)
+    }
+}
+
+/// `0.0.0.0:4433=quic.supplier.com:443,0.0.0.0:1123=time.supplier.com:123`,
+/// as for [`tcp_tunnel::parse_tunnels`].
+pub fn parse_relays(value: &str) -> Result<Vec<UdpRelay>, String> {
+    Ok(tcp_tunnel::parse_tunnels(value)?
+        .into_iter()
+        .map(|tunnel| UdpRelay {
+            listen: tunnel.listen,
+            host: tunnel.host,
+            port: tunnel.port,
+        })
+        .collect())
+}
+
+/// Binds every configured relay and serves them in the background.
+pub async fn spawn_relays(state: &AppState) -> std::io::Result<Vec<JoinHandle<()>>> {
+    let mut handles = Vec::new();
+    for relay in &state.env_var_config.udp_relays {
+        let socket = UdpSocket::bind(relay.listen).await?;
+        info!(
+            "UDP relay listening on {} for {}:{}",
+            socket.local_addr()?,
+            relay.host,
+            relay.port
+        );
+        handles.push(tokio::spawn(serve(
+            socket,
+            Arc::new(relay.clone()),
+            state.clone(),
+        )));
+    }
+    Ok(handles)
+}
+
+type Sessions = Arc<Mutex<HashMap<SocketAddr, Arc<UdpSocket>>>>;
+
+/// Relays datagrams received on `socket` until the process exits.
+pub async fn serve(socket: UdpSocket, relay: Arc<UdpRelay>, state: AppState) {
+    let socket = Arc::new(socket);
+    let sessions: Sessions = Default::default();
+    let mapping = relay.mapping();
+    let mut buf = vec![0; MAX_DATAGRAM];
+    loop {
+        let (len, client) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(e) => {
+                // Often an ICMP error for an earlier reply; keep serving.
+                info!("UDP relay {} failed to receive: {}", mapping, e);
+                continue;
+            }
+        };
+        if !client_allowed(
+            &state.env_var_config.tcp_tunnel_allowed_clients,
+            client.ip(),
+        ) {
+            warn!(
+                "UDP relay {} dropped a datagram from {}: not an allowed client",
+                mapping, client
+            );
+            state.metrics.record_tunnel("udp", "refused");
+            continue;
+        }
+        let existing = sessions.lock().unwrap().get(&client).cloned();
+        let upstream = match existing {
+            Some(upstream) => upstream,
+            None => match open_session(&state, &relay, &socket, &sessions, client).await {
+                Some(upstream) => upstream,
+                None => continue,
+            },
+        };
+        match upstream.send(&buf[..len]).await {
+            Ok(sent) => state.metrics.record_udp_datagram(&mapping, true, sent),
+            Err(e) => info!("UDP relay {} failed to send: {}", mapping, e),
+        }
+    }
+}
+
+/// Connects a socket for `client` to the relay's remote, and spawns the
+/// task carrying replies back.
+async fn open_session(
+    state: &AppState,
+    relay: &Arc<UdpRelay>,
+    socket: &Arc<UdpSocket>,
+    sessions: &Sessions,
+    client: SocketAddr,
+) -> Option<Arc<UdpSocket>> {
+    let mapping = relay.mapping();
+    let connected = async {
+        let ip = *forward_proxy::resolve(state, &relay.host)
+            .await
+            .map_err(|e| e.to_string())?
+            .first()
+            .ok_or("no addresses")?;
+        let local = match ip {
+            IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        };
+        let upstream = UdpSocket::bind(SocketAddr::new(local, 0))
+            .await
+            .map_err(|e| e.to_string())?;
+        upstream
+            .connect(SocketAddr::new(ip, relay.port))
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok::<_, String>(Arc::new(upstream))
+    };
+    let upstream = match connected.await {
+        Ok(upstream) => upstream,
+        Err(e) => {
+            warn!("UDP relay {} could not reach its remote: {}", mapping, e);
+            state.metrics.record_tunnel("udp", "unreachable");
+            return None;
+        }
+    };
+    state.metrics.record_tunnel("udp", "opened");
+    info!("Relaying UDP from {} over {}", client, mapping);
+    sessions.lock().unwrap().insert(client, upstream.clone());
+
+    let (state, socket, sessions) = (state.clone(), socket.clone(), sessions.clone());
+    let replies = upstream.clone();
+    let idle = state.env_var_config.udp_relay_idle_timeout;
+    tokio::spawn(async move {
+        let mut buf = vec![0; MAX_DATAGRAM];
+        loop {
+            match tokio::time::timeout(idle, replies.recv(&mut buf)).await {
+                Ok(Ok(len)) => match socket.send_to(&buf[..len], client).await {
+                    Ok(sent) => state.metrics.record_udp_datagram(&mapping, false, sent),
+                    Err(e) => info!("UDP relay {} failed to reply: {}", mapping, e),
+                },
+                Ok(Err(e)) => {
+                    info!("UDP relay {} session for {} failed: {}", mapping, client, e);
+                    break;
+                }
+                Err(_) => break,
+            }
+        }
+        sessions.lock().unwrap().remove(&client);
+        info!("UDP relay {} session for {} closed", mapping, client);
+    });
+    Some(upstream)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_relays() {
+        let relays = parse_relays("0.0.0.0:4433=quic.supplier.com:443").unwrap();
+        assert_eq!(relays[0].mapping(), "0.0.0.0:4433=quic.supplier.com:443");
+        assert!(parse_relays("0.0.0.0:4433=quic.supplier.com").is_err());
+    }
+}
@@ -0,0 +1,179 @@
+//! The last few 5xx responses from upstreams, kept with their headers and
+//! body so a supplier's error payload can go into a support ticket after the
+//! logs have rotated. Secrets are redacted as in a capture, and the response
+//! is kept as the supplier sent it, before any interceptor ran.
+//!
+//! `GET /admin/upstream-errors` lists them newest first (`?env=` narrows to
+//! one environment) and `DELETE /admin/upstream-errors` clears them.
+//! `UPSTREAM_ERROR_CAPTURE` sets how many are kept; 0 turns it off.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::Mutex;
+
+use axum::extract::{Query, State};
+use axum::Json;
+use hyper::StatusCode;
+use serde::{Deserialize, Serialize};
+
+use crate::app_state::AppState;
+use crate::capture::{redact_headers, redact_uri};
+use crate::interceptor::{OutboundRequest, UpstreamResponse};
+use crate::request_log::{now_ms, render_body};
+
+pub const DEFAULT_UPSTREAM_ERROR_CAPACITY: usize = 50;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct UpstreamError {
+    pub at_ms: i64,
+    pub env: String,
+    pub route: String,
+    pub method: String,
+    /// The upstream URI, with redacted query parameters.
+    pub uri: String,
+    pub correlation_id: Option<String>,
+    pub status: u16,
+    pub headers: BTreeMap<String, String>,
+    pub body: String,
+}
+
+/// A ring buffer of the most recent upstream 5xx responses.
+#[derive(Debug)]
+pub struct UpstreamErrors {
+    capacity: usize,
+    redact_keys: Vec<String>,
+    entries: Mutex<VecDeque<UpstreamError>>,
+}
+
+impl UpstreamErrors {
+    /// Keeps up to `capacity` responses, none when unset. `redact_keys` are
+    /// JSON keys, query parameters and headers whose values are replaced.
+    pub fn new(capacity: Option<usize>, redact_keys: Vec<String>) -> Self {
+        Self {
+            capacity: capacity.unwrap_or(0),
+            redact_keys,
+            entries: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Keeps `res` if it's a 5xx.
+    pub fn offer(
+        &self,
+        route: &str,
+        correlation_id: Option<String>,
+        req: &OutboundRequest,
+        res: &UpstreamResponse,
+    ) {
+        if self.capacity == 0 || !res.status.is_server_error() {
+            return;
+        }
+        let entry = UpstreamError {
+            at_ms: now_ms(),
+            env: req.env.clone(),
+            route: route.to_string(),
+            method: req.method.to_string(),
+            uri: redact_uri(&req.uri, &self.redact_keys),
+            correlation_id,
+            status: res.status.as_u16(),
+            headers: redact_headers(&res.headers, &self.redact_keys),
+            body: render_body(&res.headers, &res.body, &self.redact_keys),
+        };
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() == self.capacity {
+            entries.pop_back();
+        }
+        entries.push_front(entry);
+    }
+
+    /// Newest first.
+    pub fn recent(&self, env: Option<&str>) -> Vec<UpstreamError> {
+        self.entries
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|entry| env.is_none_or(|env| entry.env == env))
+            .cloned()
+            .collect()
+    }
+
+    pub fn clear(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpstreamErrorQuery {
+    pub env: Option<String>,
+}
+
+/// `GET /admin/upstream-errors`: the kept 5xx responses, newest first.
+pub async fn list_handler(
+    State(state): State<AppState>,
+    Query(query): Query<UpstreamErrorQuery>,
+) -> Json<Vec<UpstreamError>> {
+    Json(state.upstream_errors.recent(query.env.as_deref()))
+}
+
+/// `DELETE /admin/upstream-errors`: drops the kept responses.
+pub async fn clear_handler(State(state): State<AppState>) -> StatusCode {
+    state.upstream_errors.clear();
+    StatusCode::NO_CONTENT
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::header::{self, HeaderMap, HeaderValue};
+    use hyper::Method;
+
+    use super::*;
+
+    fn exchange(env: &str, status: StatusCode) -> (OutboundRequest, UpstreamResponse) {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("application/json"),
+        );
+        headers.insert(header::SET_COOKIE, HeaderValue::from_static("session=abc"));
+        (
+            OutboundRequest {
+                env: env.to_string(),
+                method: Method::POST,
+                uri: "http://supplier/api/book?token=abc".parse().unwrap(),
+                headers: HeaderMap::new(),
+                body: Default::default(),
+            },
+            UpstreamResponse {
+                status,
+                headers,
+                body: r#"{"error":"db down","token":"abc"}"#.into(),
+                trailers: None,
+            },
+        )
+    }
+
+    #[test]
+    fn keeps_the_latest_server_errors_redacted() {
+        let errors = UpstreamErrors::new(Some(2), vec!["token".to_string()]);
+        for (env, status) in [
+            ("test", StatusCode::BAD_GATEWAY),
+            ("test", StatusCode::NOT_FOUND),
+            ("prod", StatusCode::INTERNAL_SERVER_ERROR),
+            ("prod", StatusCode::SERVICE_UNAVAILABLE),
+        ] {
+            let (req, res) = exchange(env, status);
+            errors.offer("/test/api/book", None, &req, &res);
+        }
+        let recent = errors.recent(None);
+        let statuses: Vec<u16> = recent.iter().map(|entry| entry.status).collect();
+        assert_eq!(statuses, [503, 500]);
+        assert_eq!(recent[0].uri, "http://supplier/api/book?token=[REDACTED]");
+        assert_eq!(recent[0].headers["set-cookie"], "[REDACTED]");
+        assert!(recent[0].body.contains("db down"));
+        assert!(!recent[0].body.contains("abc"));
+        assert!(errors.recent(Some("test")).is_empty());
+
+        let disabled = UpstreamErrors::new(None, Vec::new());
+        let (req, res) = exchange("test", StatusCode::BAD_GATEWAY);
+        disabled.offer("/test/api/book", None, &req, &res);
+        assert!(disabled.recent(None).is_empty());
+    }
+}
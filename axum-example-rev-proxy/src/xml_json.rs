@@ -0,0 +1,381 @@
+//! Per-route conversion between the JSON our services speak and the XML some
+//! supplier endpoints expect: request bodies go out as XML and XML responses
+//! come back as JSON, so callers never touch XML.
+//!
+//! The mapping is deliberately plain. An element becomes an object keyed by
+//! child name, or a string when it holds only text; attributes are `@name`
+//! keys, text beside children is `#text`, and repeated children (or names
+//! listed in `arrays`) become arrays. Empty elements map to `null`. Values
+//! stay strings: XML doesn't say what is a number. Outbound, child elements
+//! are written in key order.
+
+use async_trait::async_trait;
+use hyper::header::{self, HeaderValue};
+use hyper::StatusCode;
+use quick_xml::events::{BytesDecl, BytesEnd, BytesStart, BytesText, Event};
+use quick_xml::{Reader, Writer};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use tracing::warn;
+
+use crate::interceptor::{
+    OutboundRequest, RequestInterceptor, ResponseInterceptor, UpstreamResponse,
+};
+use crate::payload_size::content_family;
+
+const ATTRIBUTE_PREFIX: char = '@';
+const TEXT_KEY: &str = "#text";
+
+/// Converts bodies on inbound paths under `path_prefix`
+/// (e.g. `/test/api/xml`).
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct XmlJsonRule {
+    pub path_prefix: String,
+    /// Root element requests are wrapped in and responses unwrapped from.
+    /// Without it, the JSON is an object with the root element as its only
+    /// key.
+    #[serde(default)]
+    pub root: Option<String>,
+    /// Convert JSON request bodies to XML.
+    #[serde(default = "enabled")]
+    pub request: bool,
+    /// Convert XML responses to JSON.
+    #[serde(default = "enabled")]
+    pub response: bool,
+    /// Element names that are always arrays, even with one occurrence.
+    #[serde(default)]
+    pub arrays: Vec<String>,
+}
+
+fn enabled() -> bool {
+    true
+}
+
+/// Parses `XML_JSON_RULES`, a JSON array of [`XmlJsonRule`]s.
+pub fn parse_xml_json_rules(value: &str) -> Result<Vec<XmlJsonRule>, String> {
+    if value.trim().is_empty() {
+        return Ok(Vec::new());
+    }
+    serde_json::from_str(value).map_err(|e| format!("invalid XML_JSON_RULES: {e}"))
+}
+
+pub struct XmlJson {
+    rules: Vec<XmlJsonRule>,
+}
+
+impl XmlJson {
+    pub fn new(rules: Vec<XmlJsonRule>) -> Self {
+        Self { rules }
+    }
+
+    fn rule_for(&self, req: &OutboundRequest) -> Option<&XmlJsonRule> {
+        let inbound = format!("/{}{}", req.env, req.uri.path());
+        self.rules
+            .iter()
+            .find(|rule| inbound.starts_with(&rule.path_prefix))
+    }
+}
+
+#[async_trait]
+impl RequestInterceptor for XmlJson {
+    async fn on_request(&self, req: &mut OutboundRequest) -> Result<(), StatusCode> {
+        let Some(rule) = self.rule_for(req).filter(|rule| rule.request) else {
+            return Ok(());
+        };
+        if req.body.is_empty() || !matches!(content_family(&req.headers), "json" | "none") {
+            return Ok(());
+        }
+        let json: Value = serde_json::from_slice(&req.body).map_err(|e| {
+            warn!("Request body for {} is not JSON: {}", req.uri, e);
+            StatusCode::BAD_REQUEST
+        })?;
+        let xml = json_to_xml(&json, rule.root.as_deref()).map_err(|e| {
+            warn!("Request body for {} can't become XML: {}", req.uri, e);
+            StatusCode::BAD_REQUEST
+        })?;
+        req.body = xml.into();
+        req.headers.remove(header::CONTENT_LENGTH);
+        req.headers.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("application/xml; charset=utf-8"),
+        );
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ResponseInterceptor for XmlJson {
+    async fn on_response(
+        &self,
+        req: &OutboundRequest,
+        res: &mut UpstreamResponse,
+    ) -> Result<(), StatusCode> {
+        let Some(rule) = self.rule_for(req).filter(|rule| rule.response) else {
+            return Ok(());
+        };
+        if res.body.is_empty() || content_family(&res.headers) != "xml" {
+            return Ok(());
+        }
+        let json = xml_to_json(&res.body, rule).map_err(|e| {
+            warn!("Response from {} is not valid XML: {}", req.uri, e);
+            StatusCode::BAD_GATEWAY
+        })?;
+        res.body = serde_json::to_vec(&json)
+            .expect("JSON values serialize")
+            .into();
+        res.headers.remove(header::CONTENT_LENGTH);
+        res.headers.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("application/json"),
+        );
+        Ok(())
+    }
+}
+
+/// Serializes `json` as an XML document with `root` (or the object's only
+/// key) as the document element.
+pub fn json_to_xml(json: &Value, root: Option<&str>) -> Result<Vec<u8>, String> {
+    let (name, value) = match (root, json) {
+        (Some(root), value) => (root, value),
+        (None, Value::Object(map)) if map.len() == 1 => {
+            let (name, value) = map.iter().next().expect("one entry");
+            (name.as_str(), value)
+        }
+        _ => return Err("expected an object with a single root key".to_string()),
+    };
+    let mut writer = Writer::new(Vec::new());
+    writer
+        .write_event(Event::Decl(BytesDecl::new("1.0", Some("UTF-8"), None)))
+        .map_err(|e| e.to_string())?;
+    write_element(&mut writer, name, value)?;
+    Ok(writer.into_inner())
+}
+
+fn write_element(writer: &mut Writer<Vec<u8>>, name: &str, value: &Value) -> Result<(), String> {
+    let write = |writer: &mut Writer<Vec<u8>>, event: Event| {
+        writer.write_event(event).map_err(|e| e.to_string())
+    };
+    match value {
+        Value::Array(items) => {
+            for item in items {
+                write_element(writer, name, item)?;
+            }
+            Ok(())
+        }
+        Value::Null => write(writer, Event::Empty(BytesStart::new(name))),
+        Value::Object(map) => {
+            let mut start = BytesStart::new(name);
+            for (key, value) in map {
+                if let Some(attribute) = key.strip_prefix(ATTRIBUTE_PREFIX) {
+                    start.push_attribute((attribute, scalar_text(value)?.as_str()));
+                }
+            }
+            let children: Vec<_> = map
+                .iter()
+                .filter(|(key, _)| !key.starts_with(ATTRIBUTE_PREFIX))
+                .collect();
+            if children.is_empty() {
+                return write(writer, Event::Empty(start));
+            }
+            write(writer, Event::Start(start))?;
+            for (key, value) in children {
+                if key == TEXT_KEY {
+                    write(writer, Event::Text(BytesText::new(&scalar_text(value)?)))?;
+                } else {
+                    write_element(writer, key, value)?;
+                }
+            }
+            write(writer, Event::End(BytesEnd::new(name)))
+        }
+        scalar => {
+            write(writer, Event::Start(BytesStart::new(name)))?;
+            write(writer, Event::Text(BytesText::new(&scalar_text(scalar)?)))?;
+            write(writer, Event::End(BytesEnd::new(name)))
+        }
+    }
+}
+
+fn scalar_text(value: &Value) -> Result<String, String> {
+    match value {
+        Value::String(text) => Ok(text.clone()),
+        Value::Number(number) => Ok(number.to_string()),
+        Value::Bool(flag) => Ok(flag.to_string()),
+        Value::Null => Ok(String::new()),
+        _ => Err("attributes and text must be scalars".to_string()),
+    }
+}
+
+#[derive(Default)]
+struct Element {
+    name: String,
+    fields: Map<String, Value>,
+    text: String,
+}
+
+impl Element {
+    fn start(tag: &BytesStart) -> Result<Self, String> {
+        let mut element = Self {
+            name: String::from_utf8_lossy(tag.name().as_ref()).into_owned(),
+            ..Self::default()
+        };
+        for attribute in tag.attributes() {
+            let attribute = attribute.map_err(|e| e.to_string())?;
+            let key = String::from_utf8_lossy(attribute.key.as_ref());
+            let value = attribute.unescape_value().map_err(|e| e.to_string())?;
+            element.fields.insert(
+                format!("{ATTRIBUTE_PREFIX}{key}"),
+                Value::String(value.into_owned()),
+            );
+        }
+        Ok(element)
+    }
+
+    fn add_child(&mut self, name: String, value: Value, arrays: &[String]) {
+        match self.fields.get_mut(&name) {
+            Some(Value::Array(items)) => items.push(value),
+            Some(existing) => {
+                let first = existing.take();
+                *existing = Value::Array(vec![first, value]);
+            }
+            None if arrays.contains(&name) => {
+                self.fields.insert(name, Value::Array(vec![value]));
+            }
+            None => {
+                self.fields.insert(name, value);
+            }
+        }
+    }
+
+    fn into_value(mut self) -> (String, Value) {
+        let text = self.text.trim();
+        let value = match (self.fields.is_empty(), text.is_empty()) {
+            (true, true) => Value::Null,
+            (true, false) => Value::String(text.to_string()),
+            (false, text_empty) => {
+                if !text_empty {
+                    self.fields
+                        .insert(TEXT_KEY.to_string(), Value::String(text.to_string()));
+                }
+                Value::Object(self.fields)
+            }
+        };
+        (self.name, value)
+    }
+}
+
+/// Parses an XML document into JSON following `rule`'s mapping.
+pub fn xml_to_json(xml: &[u8], rule: &XmlJsonRule) -> Result<Value, String> {
+    let mut reader = Reader::from_reader(xml);
+    let mut stack: Vec<Element> = Vec::new();
+    let mut root = None;
+    let mut finish = |stack: &mut Vec<Element>, element: Element| {
+        let (name, value) = element.into_value();
+        match stack.last_mut() {
+            Some(parent) => parent.add_child(name, value, &rule.arrays),
+            None => root = Some((name, value)),
+        }
+    };
+    let mut buf = Vec::new();
+    loop {
+        match reader
+            .read_event_into(&mut buf)
+            .map_err(|e| e.to_string())?
+        {
+            Event::Start(tag) => stack.push(Element::start(&tag)?),
+            Event::Empty(tag) => {
+                let element = Element::start(&tag)?;
+                finish(&mut stack, element);
+            }
+            Event::End(_) => {
+                let element = stack.pop().ok_or("unbalanced closing tag")?;
+                finish(&mut stack, element);
+            }
+            Event::Text(text) => {
+                if let Some(element) = stack.last_mut() {
+                    element
+                        .text
+                        .push_str(&text.unescape().map_err(|e| e.to_string())?);
+                }
+            }
+            Event::CData(data) => {
+                if let Some(element) = stack.last_mut() {
+                    element.text.push_str(&String::from_utf8_lossy(&data));
+                }
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+        buf.clear();
+    }
+    if !stack.is_empty() {
+        return Err("unclosed element".to_string());
+    }
+    let (name, value) = root.ok_or("no root element")?;
+    Ok(match &rule.root {
+        Some(_) => value,
+        None => Value::Object(Map::from_iter([(name, value)])),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn rule(root: Option<&str>, arrays: &[&str]) -> XmlJsonRule {
+        XmlJsonRule {
+            path_prefix: "/test".to_string(),
+            root: root.map(str::to_string),
+            request: true,
+            response: true,
+            arrays: arrays.iter().map(|name| name.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn converts_xml_responses_to_json() {
+        let xml = br#"<?xml version="1.0"?>
+            <Hotels count="2">
+              <Hotel id="h1"><Name>Sea &amp; Sun</Name><Stars>4</Stars></Hotel>
+              <Hotel id="h2"><Name><![CDATA[Hill <View>]]></Name><Closed/></Hotel>
+              <Note lang="en">Prices in USD</Note>
+            </Hotels>"#;
+        let json = xml_to_json(xml, &rule(None, &[])).unwrap();
+        assert_eq!(
+            json,
+            json!({"Hotels": {
+                "@count": "2",
+                "Hotel": [
+                    {"@id": "h1", "Name": "Sea & Sun", "Stars": "4"},
+                    {"@id": "h2", "Name": "Hill <View>", "Closed": null},
+                ],
+                "Note": {"@lang": "en", "#text": "Prices in USD"},
+            }})
+        );
+
+        let single = xml_to_json(
+            b"<Hotels><Hotel>h1</Hotel></Hotels>",
+            &rule(Some("Hotels"), &["Hotel"]),
+        )
+        .unwrap();
+        assert_eq!(single, json!({"Hotel": ["h1"]}));
+        assert!(xml_to_json(b"<Hotels><Hotel></Hotels>", &rule(None, &[])).is_err());
+    }
+
+    #[test]
+    fn converts_json_requests_to_xml() {
+        let json = json!({"@version": "2", "City": "Goa", "Rooms": [{"Adults": 2}, {"Adults": 1}], "Promo": null});
+        let xml = String::from_utf8(json_to_xml(&json, Some("Search")).unwrap()).unwrap();
+        assert_eq!(
+            xml,
+            r#"<?xml version="1.0" encoding="UTF-8"?><Search version="2"><City>Goa</City><Promo/><Rooms><Adults>2</Adults></Rooms><Rooms><Adults>1</Adults></Rooms></Search>"#
+        );
+
+        let escaped = json_to_xml(&json!({"Note": "a < b & c"}), None).unwrap();
+        assert!(String::from_utf8(escaped)
+            .unwrap()
+            .ends_with("<Note>a &lt; b &amp; c</Note>"));
+        assert!(json_to_xml(&json!({"a": 1, "b": 2}), None).is_err());
+    }
+}
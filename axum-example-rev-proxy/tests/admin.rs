@@ -0,0 +1,908 @@
+mod common;
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::routing::get;
+use axum_example_rev_proxy::egress_ip::{EgressIpConfig, EgressIpSource};
+use axum_example_rev_proxy::rbac::parse_admin_tokens;
+use axum_example_rev_proxy::{log_control, metrics};
+use common::{
+    spawn_mock_upstream, spawn_proxy, spawn_router, test_config, test_state, TEST_ADMIN_TOKEN,
+    TEST_IPN_SECRET,
+};
+use hmac::{Hmac, Mac};
+use reqwest::{Method, StatusCode};
+use serde_json::{json, Value};
+use sha2::Sha512;
+use tracing_subscriber::EnvFilter;
+
+#[tokio::test]
+async fn changes_log_filter_at_runtime() {
+    let (_subscriber, control) = log_control::subscriber(EnvFilter::new("info"));
+    let mut state = test_state(test_config("http://unused", "http://unused"));
+    state.log_control = Some(control.clone());
+    let proxy = spawn_proxy(state).await;
+    let client = reqwest::Client::new();
+
+    let response = client
+        .put(format!("{proxy}/admin/loglevel"))
+        .bearer_auth(TEST_ADMIN_TOKEN)
+        .json(&json!({"filter": "warn,axum_example_rev_proxy::proxy=debug"}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(control
+        .current()
+        .contains("axum_example_rev_proxy::proxy=debug"));
+
+    let current: Value = client
+        .get(format!("{proxy}/admin/loglevel"))
+        .bearer_auth(TEST_ADMIN_TOKEN)
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(current["filter"], control.current());
+
+    let invalid = client
+        .put(format!("{proxy}/admin/loglevel"))
+        .bearer_auth(TEST_ADMIN_TOKEN)
+        .json(&json!({"filter": "proxy=loud"}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(invalid.status(), StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn log_filter_endpoint_requires_admin_token() {
+    let (_subscriber, control) = log_control::subscriber(EnvFilter::new("info"));
+    let mut state = test_state(test_config("http://unused", "http://unused"));
+    state.log_control = Some(control);
+    let proxy = spawn_proxy(state).await;
+
+    let response = reqwest::Client::new()
+        .put(format!("{proxy}/admin/loglevel"))
+        .json(&json!({"filter": "trace"}))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn reports_egress_ip_against_the_expected_one() {
+    let lookups = Arc::new(AtomicUsize::new(0));
+    let counter = lookups.clone();
+    let echo = spawn_router(axum::Router::new().route(
+        "/",
+        get(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+            async { "203.0.113.7\n" }
+        }),
+    ))
+    .await;
+    let mut config = test_config("http://unused", "http://unused");
+    config.egress_ip = EgressIpConfig {
+        source: EgressIpSource::Echo(echo),
+        expected: Some("203.0.113.9".parse().unwrap()),
+        cache_ttl: Duration::from_secs(60),
+        ..Default::default()
+    };
+    let proxy = spawn_proxy(test_state(config)).await;
+    let client = reqwest::Client::new();
+
+    let unauthorized = client
+        .get(format!("{proxy}/egress-ip"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(unauthorized.status(), StatusCode::UNAUTHORIZED);
+
+    for _ in 0..2 {
+        let report: Value = client
+            .get(format!("{proxy}/egress-ip"))
+            .bearer_auth(TEST_ADMIN_TOKEN)
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(
+            report,
+            json!({"ip": "203.0.113.7", "expected": "203.0.113.9", "matches": false})
+        );
+    }
+    assert_eq!(lookups.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test]
+async fn compares_responses_across_environments() {
+    let upstream = spawn_mock_upstream().await;
+    let prod = spawn_router(axum::Router::new().fallback(|| async {
+        axum::Json(json!({"path": "/api/hotels", "method": "POST", "extra": true}))
+    }))
+    .await;
+    let proxy = spawn_proxy(test_state(test_config(&upstream.base_url, &prod))).await;
+    let client = reqwest::Client::new();
+
+    let report: Value = client
+        .post(format!("{proxy}/admin/compare"))
+        .bearer_auth(TEST_ADMIN_TOKEN)
+        .json(&json!({
+            "path": "/api/hotels?city=goa",
+            "ignore": ["/headers", "/body", "/query"],
+        }))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(report["matched"], false);
+    assert_eq!(
+        report["left"],
+        json!({"env": "test", "status": 200, "error": null})
+    );
+    assert_eq!(
+        report["mismatches"],
+        json!([
+            {"pointer": "/extra", "left": null, "right": true},
+            {"pointer": "/method", "left": "GET", "right": "POST"},
+        ])
+    );
+
+    // The same upstream on both sides matches.
+    let report: Value = client
+        .post(format!("{proxy}/admin/compare"))
+        .bearer_auth(TEST_ADMIN_TOKEN)
+        .json(&json!({"path": "/status/200", "envs": ["test", "test"]}))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(report["matched"], true);
+}
+
+#[tokio::test]
+async fn simulated_webhooks_go_through_verification() {
+    let mut config = test_config("http://unused", "http://unused");
+    let live = spawn_proxy(test_state(config.clone())).await;
+    config.nowpayments_test_mode = true;
+    let proxy = spawn_proxy(test_state(config)).await;
+    let client = reqwest::Client::new();
+
+    // Outside test mode there's no simulator; the path falls through to the
+    // proxy, which has no `admin` env.
+    let missing = client
+        .post(format!("{live}/admin/simulate-webhook"))
+        .bearer_auth(TEST_ADMIN_TOKEN)
+        .json(&json!({"payment_id": 7}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(missing.status(), StatusCode::BAD_REQUEST);
+
+    let simulate = || {
+        client
+            .post(format!("{proxy}/admin/simulate-webhook"))
+            .bearer_auth(TEST_ADMIN_TOKEN)
+            .json(&json!({"payment_id": 7, "payment_status": "finished"}))
+            .send()
+    };
+
+    let first: Value = simulate().await.unwrap().json().await.unwrap();
+    assert_eq!(first["status"], 200);
+    assert_eq!(first["body"], "OK");
+    assert!(first.get("signature").is_none());
+
+    // Replay protection still applies.
+    let again: Value = simulate().await.unwrap().json().await.unwrap();
+    assert_eq!(again["status"], 200);
+    assert_eq!(again["body"], "Already processed");
+
+    // NOWPayments' own delivery of the same IPN isn't taken for a replay.
+    let payload = r#"{"payment_id":7,"payment_status":"finished"}"#;
+    let mut mac = Hmac::<Sha512>::new_from_slice(TEST_IPN_SECRET.as_bytes()).unwrap();
+    mac.update(payload.as_bytes());
+    let real = client
+        .post(format!("{proxy}/nowpayments-webhook"))
+        .header(
+            "x-nowpayments-sig",
+            hex::encode(mac.finalize().into_bytes()),
+        )
+        .body(payload)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(real.status(), StatusCode::OK);
+    assert_eq!(real.text().await.unwrap(), "OK");
+
+    let unauthorized = client
+        .post(format!("{proxy}/admin/simulate-webhook"))
+        .json(&json!({"payment_id": 8}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(unauthorized.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn carries_metrics_over_a_restart() {
+    let upstream = spawn_mock_upstream().await;
+    let config = test_config(&upstream.base_url, &upstream.base_url);
+    let client = reqwest::Client::new();
+
+    // The previous run served two requests and saved its counters.
+    let before = test_state(config.clone());
+    let proxy = spawn_proxy(before.clone()).await;
+    for _ in 0..2 {
+        client
+            .get(format!("{proxy}/test/api/hotels"))
+            .send()
+            .await
+            .unwrap();
+    }
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("metrics.json");
+    metrics::persist_snapshot(&before.metrics, &path).unwrap();
+
+    let after = test_state(config.clone());
+    metrics::restore_snapshot(&after.metrics, &path);
+    let restored = after.metrics.snapshot();
+    assert_eq!(restored.requests_total, 2);
+    assert_eq!(restored.requests_by_env["test"], 2);
+
+    // Or imported by hand from an exported snapshot, once.
+    let exported: Value = client
+        .get(format!("{proxy}/admin/metrics/snapshot"))
+        .bearer_auth(TEST_ADMIN_TOKEN)
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    let fresh = spawn_proxy(test_state(config)).await;
+    let import = |snapshot: &Value| {
+        client
+            .post(format!("{fresh}/admin/metrics/import"))
+            .bearer_auth(TEST_ADMIN_TOKEN)
+            .json(snapshot)
+            .send()
+    };
+    let imported: Value = import(&exported).await.unwrap().json().await.unwrap();
+    assert_eq!(imported["requests_total"], 2);
+    assert_eq!(
+        import(&exported).await.unwrap().status(),
+        StatusCode::CONFLICT
+    );
+
+    let rendered = reqwest::get(format!("{fresh}/metrics"))
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    assert!(rendered.contains("proxy_requests_total 2\n"));
+}
+
+#[tokio::test]
+async fn serves_the_dashboard() {
+    let upstream = spawn_mock_upstream().await;
+    let mut config = test_config(&upstream.base_url, &upstream.base_url);
+    config.slow_request_threshold = Duration::ZERO;
+    let proxy = spawn_proxy(test_state(config)).await;
+    let client = reqwest::Client::new();
+    client
+        .get(format!("{proxy}/test/api/hotels"))
+        .send()
+        .await
+        .unwrap();
+
+    // The page itself holds no data and loads without the token.
+    let page = client
+        .get(format!("{proxy}/admin/ui"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(page.status(), StatusCode::OK);
+    assert!(page.headers()["content-type"]
+        .to_str()
+        .unwrap()
+        .starts_with("text/html"));
+    let denied = client
+        .get(format!("{proxy}/admin/dashboard"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(denied.status(), StatusCode::UNAUTHORIZED);
+
+    let dashboard: Value = client
+        .get(format!("{proxy}/admin/dashboard"))
+        .bearer_auth(TEST_ADMIN_TOKEN)
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(dashboard["recent"]["1m"]["requests"], 1);
+    assert_eq!(dashboard["paths"][0]["path"], "/test/api/hotels");
+    assert_eq!(dashboard["slow_requests"][0]["method"], "GET");
+    assert_eq!(dashboard["slow_requests"][0]["path"], "/test/api/hotels");
+    assert_eq!(dashboard["slow_requests"][0]["status"], 200);
+}
+
+#[tokio::test]
+async fn streams_completed_requests() {
+    let upstream = spawn_mock_upstream().await;
+    let proxy = spawn_proxy(test_state(test_config(
+        &upstream.base_url,
+        &upstream.base_url,
+    )))
+    .await;
+    let client = reqwest::Client::new();
+    let mut tail = client
+        .get(format!("{proxy}/admin/tail?env=test&status=2xx"))
+        .bearer_auth(TEST_ADMIN_TOKEN)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(tail.status(), StatusCode::OK);
+    assert_eq!(tail.headers()["content-type"], "text/event-stream");
+
+    // Filtered out by env, then reported.
+    client
+        .get(format!("{proxy}/prod/api/skipped"))
+        .send()
+        .await
+        .unwrap();
+    client
+        .get(format!("{proxy}/test/api/hotels?key=secret"))
+        .send()
+        .await
+        .unwrap();
+
+    let mut received = String::new();
+    while !received.contains("\n\n") {
+        let chunk = tokio::time::timeout(Duration::from_secs(5), tail.chunk())
+            .await
+            .expect("no tail event")
+            .unwrap()
+            .unwrap();
+        received.push_str(std::str::from_utf8(&chunk).unwrap());
+    }
+    let data = received
+        .lines()
+        .find_map(|line| line.strip_prefix("data: "))
+        .unwrap();
+    assert!(received.starts_with("event: request\n"));
+    let event: Value = serde_json::from_str(data).unwrap();
+    assert_eq!(event["env"], "test");
+    assert_eq!(event["path"], "/test/api/hotels");
+    assert_eq!(event["status"], 200);
+}
+
+#[tokio::test]
+async fn captures_requests_by_filter() {
+    let upstream = spawn_mock_upstream().await;
+    let proxy = spawn_proxy(test_state(test_config(
+        &upstream.base_url,
+        &upstream.base_url,
+    )))
+    .await;
+    let client = reqwest::Client::new();
+    let armed: Value = client
+        .post(format!("{proxy}/admin/capture"))
+        .bearer_auth(TEST_ADMIN_TOKEN)
+        .json(&json!({"path_prefix": "/test/api/book", "count": 1}))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(armed["armed"], true);
+
+    for path in ["search", "book", "book"] {
+        client
+            .post(format!("{proxy}/test/api/{path}"))
+            .json(&json!({"password": "hunter2", "nights": 2}))
+            .send()
+            .await
+            .unwrap();
+    }
+
+    let status: Value = client
+        .get(format!("{proxy}/admin/capture"))
+        .bearer_auth(TEST_ADMIN_TOKEN)
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(status["armed"], false);
+    assert_eq!(status["captured"], 1);
+
+    let download = client
+        .get(format!("{proxy}/admin/capture/download"))
+        .bearer_auth(TEST_ADMIN_TOKEN)
+        .send()
+        .await
+        .unwrap();
+    assert!(download.headers()["content-disposition"]
+        .to_str()
+        .unwrap()
+        .starts_with("attachment"));
+    let exchanges: Value = download.json().await.unwrap();
+    assert_eq!(exchanges.as_array().unwrap().len(), 1);
+    assert_eq!(exchanges[0]["route"], "/test/api/book");
+    assert_eq!(
+        exchanges[0]["request"]["body"],
+        r#"{"nights":2,"password":"[REDACTED]"}"#
+    );
+    assert_eq!(exchanges[0]["response"]["status"], 200);
+
+    let export = client
+        .get(format!(
+            "{proxy}/admin/export?source=capture&path_prefix=/test/api/book"
+        ))
+        .bearer_auth(TEST_ADMIN_TOKEN)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(export.status(), StatusCode::OK);
+    assert_eq!(export.headers()["content-type"], "application/x-ndjson");
+    let lines: Vec<Value> = export
+        .text()
+        .await
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(lines.len(), 1);
+    assert_eq!(lines[0]["method"], "POST");
+    assert_eq!(lines[0]["status"], 200);
+    assert_eq!(
+        lines[0]["request_body"],
+        r#"{"nights":2,"password":"[REDACTED]"}"#
+    );
+    let har: Value = client
+        .get(format!("{proxy}/admin/export?source=capture&format=har"))
+        .bearer_auth(TEST_ADMIN_TOKEN)
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(har["log"]["version"], "1.2");
+    let entry = &har["log"]["entries"][0];
+    assert_eq!(entry["request"]["method"], "POST");
+    assert!(entry["request"]["url"]
+        .as_str()
+        .unwrap()
+        .ends_with("/api/book"));
+    assert_eq!(entry["response"]["status"], 200);
+    // Without a request log there is nothing to export from it.
+    let no_log = client
+        .get(format!("{proxy}/admin/export"))
+        .bearer_auth(TEST_ADMIN_TOKEN)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(no_log.status(), StatusCode::NOT_FOUND);
+
+    let cleared = client
+        .delete(format!("{proxy}/admin/capture"))
+        .bearer_auth(TEST_ADMIN_TOKEN)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(cleared.status(), StatusCode::NO_CONTENT);
+}
+
+#[tokio::test]
+async fn injects_faults_per_route() {
+    let upstream = spawn_mock_upstream().await;
+    let proxy = spawn_proxy(test_state(test_config(
+        &upstream.base_url,
+        &upstream.base_url,
+    )))
+    .await;
+    let client = reqwest::Client::new();
+    let rules = json!([
+        {"path_prefix": "/test/api/book", "error_percent": 100, "error_status": 503, "delay_ms": 50},
+        {"path_prefix": "/test/api/pay", "drop_percent": 100},
+    ]);
+    let response = client
+        .put(format!("{proxy}/admin/faults"))
+        .bearer_auth(TEST_ADMIN_TOKEN)
+        .json(&rules)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let started = std::time::Instant::now();
+    let response = client
+        .post(format!("{proxy}/test/api/book"))
+        .send()
+        .await
+        .unwrap();
+    assert!(started.elapsed() >= Duration::from_millis(50));
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    let body: Value = response.json().await.unwrap();
+    assert_eq!(body["code"], "injected_fault");
+    assert_eq!(body["retryable"], true);
+
+    let dropped = match client.get(format!("{proxy}/test/api/pay")).send().await {
+        Ok(response) => response.bytes().await.is_err(),
+        Err(_) => true,
+    };
+    assert!(dropped);
+    assert_eq!(upstream.hits(), 0);
+
+    let response = client
+        .get(format!("{proxy}/test/api/search"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let invalid = client
+        .put(format!("{proxy}/admin/faults"))
+        .bearer_auth(TEST_ADMIN_TOKEN)
+        .json(&json!([{"path_prefix": "/test", "error_percent": 150}]))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(invalid.status(), StatusCode::BAD_REQUEST);
+
+    let cleared = client
+        .delete(format!("{proxy}/admin/faults"))
+        .bearer_auth(TEST_ADMIN_TOKEN)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(cleared.status(), StatusCode::NO_CONTENT);
+    let response = client
+        .post(format!("{proxy}/test/api/book"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn shapes_test_traffic_only() {
+    let upstream = spawn_mock_upstream().await;
+    let proxy = spawn_proxy(test_state(test_config(
+        &upstream.base_url,
+        &upstream.base_url,
+    )))
+    .await;
+    let client = reqwest::Client::new();
+    let set = |rules: Value| {
+        client
+            .put(format!("{proxy}/admin/faults"))
+            .bearer_auth(TEST_ADMIN_TOKEN)
+            .json(&rules)
+            .send()
+    };
+
+    let refused = set(json!([{"path_prefix": "/prod", "bandwidth_bytes_per_sec": 1000}]))
+        .await
+        .unwrap();
+    assert_eq!(refused.status(), StatusCode::BAD_REQUEST);
+
+    let response = set(json!([{
+        "path_prefix": "/test/api/hotels",
+        "bandwidth_bytes_per_sec": 1000,
+        "latency": {"distribution": "normal", "mean_ms": 30, "stddev_ms": 0},
+    }]))
+    .await
+    .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let started = std::time::Instant::now();
+    let echo: Value = client
+        .get(format!("{proxy}/test/api/hotels?city={}", "x".repeat(150)))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(echo["path"], "/api/hotels");
+    // 30ms of latency, then over 100 bytes at 100 bytes per tick.
+    assert!(started.elapsed() >= Duration::from_millis(130));
+}
+
+#[tokio::test]
+async fn admin_routes_follow_token_roles() {
+    let mut config = test_config("http://unused", "http://unused");
+    config.admin_token = None;
+    config.admin_tokens = parse_admin_tokens(
+        "grafana:viewer:viewer-token,oncall:operator:operator-token,lead:admin:admin-token",
+    )
+    .unwrap();
+    let proxy = spawn_proxy(test_state(config)).await;
+    let client = reqwest::Client::new();
+    let call = |method: Method, path: &str, token: &str| {
+        client
+            .request(method, format!("{proxy}/admin{path}"))
+            .bearer_auth(token)
+    };
+
+    let cases = [
+        (
+            "viewer-token",
+            Method::GET,
+            "/metrics/snapshot",
+            StatusCode::OK,
+        ),
+        ("viewer-token", Method::GET, "/faults", StatusCode::OK),
+        (
+            "viewer-token",
+            Method::DELETE,
+            "/cache?path_prefix=/test",
+            StatusCode::FORBIDDEN,
+        ),
+        (
+            "operator-token",
+            Method::DELETE,
+            "/cache?path_prefix=/test",
+            StatusCode::OK,
+        ),
+        (
+            "operator-token",
+            Method::DELETE,
+            "/faults",
+            StatusCode::FORBIDDEN,
+        ),
+        (
+            "admin-token",
+            Method::DELETE,
+            "/faults",
+            StatusCode::NO_CONTENT,
+        ),
+        (
+            "unknown-token",
+            Method::GET,
+            "/metrics/snapshot",
+            StatusCode::UNAUTHORIZED,
+        ),
+    ];
+    for (token, method, path, expected) in cases {
+        let response = call(method.clone(), path, token).send().await.unwrap();
+        assert_eq!(response.status(), expected, "{token} {method} {path}");
+    }
+}
+
+#[tokio::test]
+async fn keeps_upstream_server_errors_for_operators() {
+    let upstream = spawn_mock_upstream().await;
+    let mut config = test_config(&upstream.base_url, &upstream.base_url);
+    config.admin_tokens = parse_admin_tokens("grafana:viewer:viewer-token").unwrap();
+    let proxy = spawn_proxy(test_state(config)).await;
+    let client = reqwest::Client::new();
+
+    for path in [
+        "/test/status/502?token=abc",
+        "/test/status/404",
+        "/prod/status/503",
+    ] {
+        client.get(format!("{proxy}{path}")).send().await.unwrap();
+    }
+    let errors: Value = client
+        .get(format!("{proxy}/admin/upstream-errors?env=test"))
+        .bearer_auth(TEST_ADMIN_TOKEN)
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    let errors = errors.as_array().unwrap();
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0]["status"], 502);
+    assert_eq!(errors[0]["body"], "status 502");
+    assert!(errors[0]["uri"]
+        .as_str()
+        .unwrap()
+        .ends_with("token=[REDACTED]"));
+    assert!(errors[0]["correlation_id"].is_string());
+
+    let response = client
+        .get(format!("{proxy}/admin/upstream-errors"))
+        .bearer_auth("viewer-token")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    let response = client
+        .delete(format!("{proxy}/admin/upstream-errors"))
+        .bearer_auth(TEST_ADMIN_TOKEN)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    let errors: Value = client
+        .get(format!("{proxy}/admin/upstream-errors"))
+        .bearer_auth(TEST_ADMIN_TOKEN)
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(errors, json!([]));
+}
+
+#[tokio::test]
+async fn runs_scheduled_requests_and_keeps_their_results() {
+    let upstream = spawn_mock_upstream().await;
+    let mut config = test_config(&upstream.base_url, &upstream.base_url);
+    config.admin_tokens = parse_admin_tokens("grafana:viewer:viewer-token").unwrap();
+    let state = test_state(config);
+    let proxy = spawn_proxy(state.clone()).await;
+    let scheduler = axum_example_rev_proxy::schedule::spawn_scheduler(&state);
+    let client = reqwest::Client::new();
+
+    let schedule = |body: Value| {
+        client
+            .post(format!("{proxy}/admin/schedules"))
+            .bearer_auth(TEST_ADMIN_TOKEN)
+            .json(&body)
+            .send()
+    };
+    let soon: Value = schedule(json!({
+        "env": "test",
+        "method": "POST",
+        "path": "/status/202",
+        "in_secs": 0,
+    }))
+    .await
+    .unwrap()
+    .json()
+    .await
+    .unwrap();
+    assert_eq!(soon["state"], "scheduled");
+    assert_eq!(soon["created_by"], "admin");
+    let later: Value = schedule(json!({"env": "test", "path": "/ok", "in_secs": 3600}))
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    let response = schedule(json!({"env": "nowhere", "path": "/ok", "in_secs": 1}))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    let job_url = format!("{proxy}/admin/schedules/{}", soon["id"].as_str().unwrap());
+    let mut job = Value::Null;
+    for _ in 0..50 {
+        job = client
+            .get(&job_url)
+            .bearer_auth(TEST_ADMIN_TOKEN)
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        if job["state"] != "scheduled" && job["state"] != "running" {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    scheduler.abort();
+    assert_eq!(job["state"], "succeeded");
+    assert_eq!(job["result"]["status"], 202);
+    assert_eq!(job["result"]["body"], "status 202");
+    assert_eq!(upstream.hits(), 1);
+
+    let later_url = format!("{proxy}/admin/schedules/{}", later["id"].as_str().unwrap());
+    let response = client
+        .delete(&later_url)
+        .bearer_auth(TEST_ADMIN_TOKEN)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = client
+        .delete(&job_url)
+        .bearer_auth(TEST_ADMIN_TOKEN)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+
+    let response = client
+        .get(format!("{proxy}/admin/schedules"))
+        .bearer_auth("viewer-token")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    let jobs: Value = client
+        .get(format!("{proxy}/admin/schedules"))
+        .bearer_auth(TEST_ADMIN_TOKEN)
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    let mut states: Vec<&str> = jobs
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|job| job["state"].as_str().unwrap())
+        .collect();
+    states.sort();
+    assert_eq!(states, ["cancelled", "succeeded"]);
+}
+
+#[tokio::test]
+async fn extracts_one_value_from_an_upstream_response() {
+    let upstream = spawn_mock_upstream().await;
+    let mut config = test_config(&upstream.base_url, &upstream.base_url);
+    config.admin_tokens = parse_admin_tokens("grafana:viewer:viewer-token").unwrap();
+    let proxy = spawn_proxy(test_state(config)).await;
+    let client = reqwest::Client::new();
+    let extract = |path: &str, expr: &str| {
+        client
+            .get(format!("{proxy}/admin/extract/test/{path}"))
+            .bearer_auth(TEST_ADMIN_TOKEN)
+            .header("x-proxy-jsonpath", expr)
+            .header("x-supplier-key", "k1")
+            .send()
+    };
+
+    let response = extract("health/db?verbose=1", "$.query").await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.json::<Value>().await.unwrap(), "verbose=1");
+    let headers: Value = extract("health", "$.headers[*]")
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    // The caller's headers reach the upstream, the admin token doesn't.
+    assert!(headers.as_array().unwrap().contains(&Value::from("k1")));
+    assert!(!headers
+        .as_array()
+        .unwrap()
+        .contains(&Value::from(format!("Bearer {TEST_ADMIN_TOKEN}"))));
+
+    let response = extract("health", "$.missing").await.unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    let response = extract("health", "query").await.unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let response = extract("status/503", "$").await.unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+
+    let response = client
+        .get(format!("{proxy}/admin/extract/test/health"))
+        .bearer_auth("viewer-token")
+        .header("x-proxy-jsonpath", "$.query")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+}
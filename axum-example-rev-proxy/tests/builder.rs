@@ -0,0 +1,225 @@
+mod common;
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use axum::routing::get;
+use axum::Router;
+use axum_example_rev_proxy::builder::ProxyBuilder;
+use axum_example_rev_proxy::interceptor::{OutboundRequest, RequestInterceptor};
+use axum_example_rev_proxy::metrics::Metrics;
+use common::{spawn_mock_upstream, spawn_router, Echo};
+use reqwest::StatusCode;
+
+#[tokio::test]
+async fn mounts_under_sub_path_of_host_router() {
+    let upstream = spawn_mock_upstream().await;
+    let metrics = Arc::new(Metrics::default());
+    let proxy = ProxyBuilder::new()
+        .upstream("supplier", &upstream.base_url)
+        .request_timeout(Duration::from_secs(5))
+        .metrics(metrics.clone())
+        .build();
+    let app = Router::new()
+        .route("/health", get(|| async { "ok" }))
+        .nest("/egress", proxy);
+    let base = spawn_router(app).await;
+
+    let echo: Echo = reqwest::get(format!("{base}/egress/supplier/api/rates?x=1"))
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+
+    assert_eq!(echo.path, "/api/rates");
+    assert_eq!(echo.query.as_deref(), Some("x=1"));
+    assert_eq!(metrics.snapshot().requests_by_env["supplier"], 1);
+    assert_eq!(
+        reqwest::get(format!("{base}/health"))
+            .await
+            .unwrap()
+            .status(),
+        StatusCode::OK
+    );
+}
+
+#[tokio::test]
+async fn disabled_routes_are_not_mounted() {
+    let proxy = ProxyBuilder::new()
+        .upstream("test", "http://unused")
+        .webhook(false)
+        .metrics_endpoint(false)
+        .build();
+    let base = spawn_router(proxy).await;
+
+    let metrics = reqwest::get(format!("{base}/metrics")).await.unwrap();
+    let webhook = reqwest::Client::new()
+        .post(format!("{base}/nowpayments-webhook"))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(metrics.status(), StatusCode::NOT_FOUND);
+    assert_eq!(webhook.status(), StatusCode::NOT_FOUND);
+}
+
+/// Points every request at `target` instead of its upstream.
+struct Redirect {
+    target: String,
+}
+
+#[async_trait]
+impl RequestInterceptor for Redirect {
+    async fn on_request(&self, req: &mut OutboundRequest) -> Result<(), hyper::StatusCode> {
+        req.uri = self.target.parse().unwrap();
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn egress_policy_refuses_unlisted_and_internal_destinations() {
+    let upstream = spawn_mock_upstream().await;
+    let other = spawn_mock_upstream().await;
+    let metrics = Arc::new(Metrics::default());
+    let proxy = ProxyBuilder::new()
+        .upstream("supplier", &upstream.base_url)
+        .upstream(
+            "local",
+            format!("http://localhost:{}", upstream.addr.port()),
+        )
+        .route_request_interceptor(
+            "supplier",
+            Redirect {
+                target: format!("{}/steal", other.base_url),
+            },
+        )
+        .metrics(metrics.clone())
+        .build();
+    let base = spawn_router(proxy).await;
+
+    let redirected = reqwest::get(format!("{base}/supplier/api")).await.unwrap();
+    assert_eq!(redirected.status(), StatusCode::FORBIDDEN);
+    assert_eq!(other.hits(), 0);
+
+    // `localhost` only resolves to loopback addresses.
+    let internal = reqwest::get(format!("{base}/local/api")).await.unwrap();
+    assert_eq!(internal.status(), StatusCode::BAD_GATEWAY);
+    assert_eq!(upstream.hits(), 0);
+
+    let violations = metrics.snapshot().egress_violations_by_reason;
+    assert_eq!(violations["destination"], 1);
+    assert_eq!(violations["internal_address"], 1);
+
+    let allowed = ProxyBuilder::new()
+        .upstream(
+            "local",
+            format!("http://localhost:{}", upstream.addr.port()),
+        )
+        .allow_internal_destinations(true)
+        .build();
+    let base = spawn_router(allowed).await;
+    let response = reqwest::get(format!("{base}/local/api")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn reports_timings_when_asked() {
+    let upstream = spawn_mock_upstream().await;
+    let proxy = ProxyBuilder::new()
+        .upstream("supplier", &upstream.base_url)
+        .build();
+    let base = spawn_router(proxy).await;
+    let client = reqwest::Client::new();
+
+    let response = client
+        .get(format!("{base}/supplier/delay/50"))
+        .header("x-proxy-debug", "timings")
+        .send()
+        .await
+        .unwrap();
+    let timing = |phase: &str| -> f64 {
+        response.headers()[format!("x-proxy-timing-{phase}").as_str()]
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap()
+    };
+    // A fresh connection to an IP literal: no DNS, but a connect.
+    assert_eq!(timing("dns"), 0.0);
+    assert!(timing("connect") > 0.0);
+    assert!(timing("ttfb") >= 50.0);
+    assert!(timing("total") >= timing("ttfb"));
+
+    let plain = client
+        .get(format!("{base}/supplier/api"))
+        .send()
+        .await
+        .unwrap();
+    assert!(plain.headers().get("x-proxy-timing-total").is_none());
+    let echo: Echo = client
+        .get(format!("{base}/supplier/api"))
+        .header("x-proxy-debug", "timings")
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert!(!echo.headers.contains_key("x-proxy-debug"));
+}
+
+/// Accepts connections, returning each request head as it arrived.
+async fn spawn_head_capture() -> (String, tokio::sync::mpsc::UnboundedReceiver<String>) {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (heads, received) = tokio::sync::mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut head = Vec::new();
+            let mut buf = [0; 1024];
+            while !head.ends_with(b"\r\n\r\n") {
+                let n = stream.read(&mut buf).await.unwrap();
+                if n == 0 {
+                    break;
+                }
+                head.extend_from_slice(&buf[..n]);
+            }
+            let _ = heads.send(String::from_utf8_lossy(&head).into_owned());
+            let _ = stream
+                .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\nconnection: close\r\n\r\nok")
+                .await;
+        }
+    });
+    (format!("http://{addr}"), received)
+}
+
+#[tokio::test]
+async fn title_cases_header_names_for_configured_upstreams() {
+    let (upstream, mut heads) = spawn_head_capture().await;
+    let proxy = ProxyBuilder::new()
+        .upstream("legacy", &upstream)
+        .upstream("modern", &upstream)
+        .title_case_headers("legacy")
+        .build();
+    let base = spawn_router(proxy).await;
+    let client = reqwest::Client::new();
+
+    for env in ["legacy", "modern"] {
+        let response = client
+            .get(format!("{base}/{env}/api/rates"))
+            .header("api-key", "k1")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+    let legacy = heads.recv().await.unwrap();
+    assert!(legacy.contains("\r\nApi-Key: k1\r\n"), "{legacy}");
+    let modern = heads.recv().await.unwrap();
+    assert!(modern.contains("\r\napi-key: k1\r\n"), "{modern}");
+}
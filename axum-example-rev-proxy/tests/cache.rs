@@ -0,0 +1,322 @@
+mod common;
+
+use std::time::Duration;
+
+use axum_example_rev_proxy::builder::ProxyBuilder;
+use axum_example_rev_proxy::cache::CacheRule;
+use common::{spawn_mock_upstream, spawn_router, TEST_ADMIN_TOKEN};
+use reqwest::StatusCode;
+
+async fn get(url: &str) -> reqwest::Response {
+    reqwest::get(url).await.unwrap()
+}
+
+#[tokio::test]
+async fn serves_repeated_gets_from_cache() {
+    let upstream = spawn_mock_upstream().await;
+    let proxy = ProxyBuilder::new()
+        .upstream("test", &upstream.base_url)
+        .cache_rule("/test/static", Duration::from_secs(60))
+        .build();
+    let base = spawn_router(proxy).await;
+
+    let first = get(&format!("{base}/test/static/cities")).await;
+    let second = get(&format!("{base}/test/static/cities")).await;
+    get(&format!("{base}/test/static/cities?page=2")).await;
+    get(&format!("{base}/test/live/rates")).await;
+
+    assert_eq!(first.headers()["x-cache"], "MISS");
+    assert_eq!(second.headers()["x-cache"], "HIT");
+    // The query is part of the key, and uncached paths always go upstream.
+    assert_eq!(upstream.hits(), 3);
+}
+
+#[tokio::test]
+async fn keys_cached_gets_on_accept_encoding() {
+    let upstream = spawn_mock_upstream().await;
+    let proxy = ProxyBuilder::new()
+        .upstream("test", &upstream.base_url)
+        .cache_rule("/test/static", Duration::from_secs(60))
+        .build();
+    let base = spawn_router(proxy).await;
+    let client = reqwest::Client::new();
+    let get_encoded = |encoding: &'static str| {
+        client
+            .get(format!("{base}/test/static/cities"))
+            .header("accept-encoding", encoding)
+            .send()
+    };
+
+    assert_eq!(
+        get_encoded("gzip").await.unwrap().headers()["x-cache"],
+        "MISS"
+    );
+    assert_eq!(
+        get_encoded("gzip").await.unwrap().headers()["x-cache"],
+        "HIT"
+    );
+    // A caller that can't take gzip doesn't get the gzip caller's entry.
+    assert_eq!(
+        get_encoded("identity").await.unwrap().headers()["x-cache"],
+        "MISS"
+    );
+    assert_eq!(upstream.hits(), 2);
+}
+
+#[tokio::test]
+async fn never_shares_cached_gets_between_credentials() {
+    let upstream = spawn_mock_upstream().await;
+    let proxy = ProxyBuilder::new()
+        .upstream("test", &upstream.base_url)
+        .cache_rule("/test/static", Duration::from_secs(60))
+        .build();
+    let base = spawn_router(proxy).await;
+    let client = reqwest::Client::new();
+    let get_as = |name: &'static str, value: &'static str| {
+        client
+            .get(format!("{base}/test/static/cities"))
+            .header(name, value)
+            .send()
+    };
+    let cache = |response: reqwest::Response| response.headers()["x-cache"].clone();
+
+    assert_eq!(
+        cache(get_as("authorization", "Bearer a").await.unwrap()),
+        "MISS"
+    );
+    assert_eq!(
+        cache(get_as("authorization", "Bearer a").await.unwrap()),
+        "HIT"
+    );
+    assert_eq!(
+        cache(get_as("authorization", "Bearer b").await.unwrap()),
+        "MISS"
+    );
+    assert_eq!(cache(get_as("x-api-key", "a").await.unwrap()), "MISS");
+    // No credentials at all doesn't get anyone's entry either.
+    assert_eq!(cache(get_as("x-trace", "1").await.unwrap()), "MISS");
+    assert_eq!(upstream.hits(), 4);
+}
+
+#[tokio::test]
+async fn write_invalidates_cached_gets_under_same_prefix_and_rules() {
+    let upstream = spawn_mock_upstream().await;
+    let proxy = ProxyBuilder::new()
+        .upstream("test", &upstream.base_url)
+        .cache_rule("/test", Duration::from_secs(60))
+        .cache_invalidation_rule("/test/bookings", ["/test/availability"])
+        .build();
+    let base = spawn_router(proxy).await;
+
+    get(&format!("{base}/test/bookings/7")).await;
+    get(&format!("{base}/test/availability/goa")).await;
+    get(&format!("{base}/test/hotels/1")).await;
+    assert_eq!(upstream.hits(), 3);
+
+    reqwest::Client::new()
+        .post(format!("{base}/test/bookings"))
+        .body("{}")
+        .send()
+        .await
+        .unwrap();
+
+    let booking = get(&format!("{base}/test/bookings/7")).await;
+    let availability = get(&format!("{base}/test/availability/goa")).await;
+    let hotel = get(&format!("{base}/test/hotels/1")).await;
+    assert_eq!(booking.headers()["x-cache"], "MISS");
+    assert_eq!(availability.headers()["x-cache"], "MISS");
+    assert_eq!(hotel.headers()["x-cache"], "HIT");
+}
+
+#[tokio::test]
+async fn admin_endpoint_invalidates_by_prefix() {
+    let upstream = spawn_mock_upstream().await;
+    let proxy = ProxyBuilder::new()
+        .upstream("test", &upstream.base_url)
+        .admin_token(TEST_ADMIN_TOKEN)
+        .cache_rule("/test", Duration::from_secs(60))
+        .build();
+    let base = spawn_router(proxy).await;
+    get(&format!("{base}/test/a/1")).await;
+    get(&format!("{base}/test/b/1")).await;
+
+    let client = reqwest::Client::new();
+    let unauthorized = client
+        .delete(format!("{base}/admin/cache?path_prefix=/test/a"))
+        .send()
+        .await
+        .unwrap();
+    let response = client
+        .delete(format!("{base}/admin/cache?path_prefix=/test/a"))
+        .bearer_auth(TEST_ADMIN_TOKEN)
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(unauthorized.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(response.status(), StatusCode::OK);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["removed"], 1);
+    assert_eq!(
+        get(&format!("{base}/test/a/1")).await.headers()["x-cache"],
+        "MISS"
+    );
+    assert_eq!(
+        get(&format!("{base}/test/b/1")).await.headers()["x-cache"],
+        "HIT"
+    );
+}
+
+#[tokio::test]
+async fn admin_api_is_absent_without_token() {
+    let proxy = ProxyBuilder::new()
+        .upstream("test", "http://unused")
+        .build();
+    let base = spawn_router(proxy).await;
+
+    let response = reqwest::Client::new()
+        .delete(format!("{base}/admin/cache?path_prefix=/"))
+        .send()
+        .await
+        .unwrap();
+
+    // `/admin/cache` falls through to the proxy route, where `admin` is not a known env.
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn serves_stale_while_revalidating_in_background() {
+    let upstream = spawn_mock_upstream().await;
+    let mut rule = CacheRule::new("/test", Duration::ZERO);
+    rule.stale_while_revalidate = Duration::from_secs(60);
+    let proxy = ProxyBuilder::new()
+        .upstream("test", &upstream.base_url)
+        .add_cache_rule(rule)
+        .build();
+    let base = spawn_router(proxy).await;
+
+    let first = get(&format!("{base}/test/rates")).await;
+    let second = get(&format!("{base}/test/rates")).await;
+
+    assert_eq!(first.headers()["x-cache"], "MISS");
+    assert_eq!(second.headers()["x-cache"], "STALE");
+    for _ in 0..50 {
+        if upstream.hits() == 2 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(upstream.hits(), 2);
+}
+
+#[tokio::test]
+async fn serves_stale_when_upstream_errors() {
+    let upstream = spawn_mock_upstream().await;
+    let mut rule = CacheRule::new("/test", Duration::ZERO);
+    rule.stale_if_error = Duration::from_secs(60);
+    let proxy = ProxyBuilder::new()
+        .upstream("test", &upstream.base_url)
+        .add_cache_rule(rule)
+        .build();
+    let base = spawn_router(proxy).await;
+    get(&format!("{base}/test/rates")).await;
+
+    upstream.set_failing(true);
+    let stale = get(&format!("{base}/test/rates")).await;
+    let uncached = get(&format!("{base}/test/other")).await;
+
+    assert_eq!(stale.status(), StatusCode::OK);
+    assert_eq!(stale.headers()["x-cache"], "STALE");
+    assert_eq!(uncached.status(), StatusCode::SERVICE_UNAVAILABLE);
+}
+
+#[tokio::test]
+async fn revalidates_expired_entry_with_if_none_match() {
+    let upstream = spawn_mock_upstream().await;
+    let proxy = ProxyBuilder::new()
+        .upstream("test", &upstream.base_url)
+        .cache_rule("/test", Duration::ZERO)
+        .build();
+    let base = spawn_router(proxy).await;
+
+    let first = get(&format!("{base}/test/etag/v1")).await;
+    let second = get(&format!("{base}/test/etag/v1")).await;
+
+    assert_eq!(first.headers()["x-cache"], "MISS");
+    assert_eq!(second.status(), StatusCode::OK);
+    assert_eq!(second.headers()["x-cache"], "REVALIDATED");
+    assert_eq!(second.text().await.unwrap(), "body for v1");
+    assert_eq!(upstream.hits(), 2);
+}
+
+#[tokio::test]
+async fn answers_matching_client_validators_from_cache() {
+    let upstream = spawn_mock_upstream().await;
+    let proxy = ProxyBuilder::new()
+        .upstream("test", &upstream.base_url)
+        .cache_rule("/test", Duration::from_secs(60))
+        .build();
+    let base = spawn_router(proxy).await;
+    get(&format!("{base}/test/etag/v1")).await;
+
+    let client = reqwest::Client::new();
+    let matching = client
+        .get(format!("{base}/test/etag/v1"))
+        .header("if-none-match", "\"v1\"")
+        .send()
+        .await
+        .unwrap();
+    let stale_copy = client
+        .get(format!("{base}/test/etag/v1"))
+        .header("if-none-match", "\"v0\"")
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(matching.status(), StatusCode::NOT_MODIFIED);
+    assert_eq!(matching.headers()["etag"], "\"v1\"");
+    assert_eq!(stale_copy.status(), StatusCode::OK);
+    assert_eq!(upstream.hits(), 1);
+}
+
+#[tokio::test]
+async fn passes_client_conditionals_through_on_uncached_routes() {
+    let upstream = spawn_mock_upstream().await;
+    let proxy = ProxyBuilder::new()
+        .upstream("test", &upstream.base_url)
+        .build();
+    let base = spawn_router(proxy).await;
+
+    let response = reqwest::Client::new()
+        .get(format!("{base}/test/etag/v1"))
+        .header("if-none-match", "\"v1\"")
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+}
+
+#[tokio::test]
+async fn shares_cache_through_configured_storage() {
+    let upstream = spawn_mock_upstream().await;
+    let dir = tempfile::tempdir().unwrap();
+    let url = format!("file://{}", dir.path().display());
+    let proxy = |url: &str| {
+        ProxyBuilder::new()
+            .upstream("test", &upstream.base_url)
+            .cache_rule("/test/static", Duration::from_secs(60))
+            .storage_url(url)
+            .build()
+    };
+    let first = spawn_router(proxy(&url)).await;
+    let second = spawn_router(proxy(&url)).await;
+
+    let miss = get(&format!("{first}/test/static/cities")).await;
+    let hit = get(&format!("{second}/test/static/cities")).await;
+
+    assert_eq!(miss.headers()["x-cache"], "MISS");
+    // A replica on the same backend answers from what the other stored.
+    assert_eq!(hit.headers()["x-cache"], "HIT");
+    assert_eq!(upstream.hits(), 1);
+}
@@ -0,0 +1,296 @@
+//! Shared harness for the integration tests: an in-process mock upstream and
+//! a proxy instance pointed at it, both bound to ephemeral localhost ports.
+#![allow(dead_code)]
+
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::body::Bytes;
+use axum::extract::{Path, Request};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::any;
+use axum::{Json, Router};
+use axum_example_rev_proxy::app_state::{AppState, EnvVarConfig};
+use axum_example_rev_proxy::dns::{FamilyPolicy, ResolverSpec};
+use axum_example_rev_proxy::encoding;
+use axum_example_rev_proxy::headers::body_with_trailers;
+use axum_example_rev_proxy::router;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde::{Deserialize, Serialize};
+
+pub const TEST_IPN_SECRET: &str = "test-ipn-secret";
+pub const TEST_ADMIN_TOKEN: &str = "test-admin-token";
+
+/// What the mock upstream saw, echoed back as the JSON response body.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Echo {
+    pub method: String,
+    pub path: String,
+    pub query: Option<String>,
+    pub headers: BTreeMap<String, String>,
+    pub body: String,
+}
+
+pub struct MockUpstream {
+    pub addr: SocketAddr,
+    pub base_url: String,
+    hits: Arc<AtomicUsize>,
+    failing: Arc<AtomicBool>,
+    stall_next: Arc<AtomicBool>,
+}
+
+impl MockUpstream {
+    /// Makes the next request take two seconds to be answered.
+    pub fn stall_next_request(&self) {
+        self.stall_next.store(true, Ordering::SeqCst);
+    }
+
+    /// While set, every request is answered with 503.
+    pub fn set_failing(&self, failing: bool) {
+        self.failing.store(failing, Ordering::SeqCst);
+    }
+
+    /// Number of requests the upstream has received so far.
+    pub fn hits(&self) -> usize {
+        self.hits.load(Ordering::SeqCst)
+    }
+}
+
+/// Spawns an upstream that echoes every request as [`Echo`] JSON, except
+/// `/status/{code}` which replies with that status code.
+pub async fn spawn_mock_upstream() -> MockUpstream {
+    let hits = Arc::new(AtomicUsize::new(0));
+    let failing = Arc::new(AtomicBool::new(false));
+    let stall_next = Arc::new(AtomicBool::new(false));
+    let (counter, fail_flag, stall_flag) = (hits.clone(), failing.clone(), stall_next.clone());
+    let app = Router::new()
+        .route("/status/{code}", any(status_handler))
+        .route("/etag/{tag}", any(etag_handler))
+        .route("/trailers", any(trailers_handler))
+        .route("/delay/{ms}", any(delay_handler))
+        .route("/correlation", any(correlation_handler))
+        .route("/gzip/{text}", any(gzip_handler))
+        .fallback(echo_handler)
+        .layer(middleware::from_fn(move |req: Request, next: Next| {
+            let (counter, fail_flag) = (counter.clone(), fail_flag.clone());
+            let stall_flag = stall_flag.clone();
+            async move {
+                counter.fetch_add(1, Ordering::SeqCst);
+                if fail_flag.load(Ordering::SeqCst) {
+                    return StatusCode::SERVICE_UNAVAILABLE.into_response();
+                }
+                if stall_flag.swap(false, Ordering::SeqCst) {
+                    tokio::time::sleep(Duration::from_secs(2)).await;
+                }
+                let response: Response = next.run(req).await;
+                response
+            }
+        }));
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        axum::serve(listener, app).await.unwrap();
+    });
+
+    MockUpstream {
+        addr,
+        base_url: format!("http://{addr}"),
+        hits,
+        failing,
+        stall_next,
+    }
+}
+
+async fn echo_handler(req: Request) -> Json<Echo> {
+    let (parts, body) = req.into_parts();
+    let body: Bytes = axum::body::to_bytes(body, usize::MAX).await.unwrap();
+    let headers = parts
+        .headers
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_str().unwrap_or_default().to_string()))
+        .collect();
+
+    Json(Echo {
+        method: parts.method.to_string(),
+        path: parts.uri.path().to_string(),
+        query: parts.uri.query().map(str::to_string),
+        headers,
+        body: String::from_utf8_lossy(&body).into_owned(),
+    })
+}
+
+async fn status_handler(Path(code): Path<u16>) -> (StatusCode, String) {
+    (
+        StatusCode::from_u16(code).unwrap(),
+        format!("status {code}"),
+    )
+}
+
+/// Serves `"{tag}"` as the ETag and answers a matching `If-None-Match` with 304.
+async fn etag_handler(Path(tag): Path<String>, headers: HeaderMap) -> Response {
+    let etag = format!("\"{tag}\"");
+    if headers
+        .get(header::IF_NONE_MATCH)
+        .is_some_and(|value| value == etag.as_str())
+    {
+        return (StatusCode::NOT_MODIFIED, [(header::ETAG, etag)]).into_response();
+    }
+    ([(header::ETAG, etag)], format!("body for {tag}")).into_response()
+}
+
+/// Answers with its own `x-correlation-id`, derived from the one it received.
+async fn correlation_handler(headers: HeaderMap) -> Response {
+    let received = headers
+        .get("x-correlation-id")
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("none");
+    ([("x-correlation-id", format!("supplier-{received}"))], "ok").into_response()
+}
+
+/// Sends `text` gzipped, whatever the request accepts.
+async fn gzip_handler(Path(text): Path<String>) -> Response {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(text.as_bytes()).unwrap();
+    let body = encoder.finish().unwrap();
+    ([(header::CONTENT_ENCODING, "gzip")], body).into_response()
+}
+
+/// Replies after sleeping for `ms` milliseconds.
+async fn delay_handler(Path(ms): Path<u64>) -> String {
+    tokio::time::sleep(Duration::from_millis(ms)).await;
+    format!("slept {ms}ms")
+}
+
+/// Sends `payload` followed by an `x-checksum: abc` trailer.
+async fn trailers_handler() -> Response {
+    let mut trailers = HeaderMap::new();
+    trailers.insert("x-checksum", "abc".parse().unwrap());
+    let body = body_with_trailers(Bytes::from_static(b"payload"), trailers);
+    ([(header::TRAILER, "x-checksum")], body).into_response()
+}
+
+/// Returns a base URL on which nothing is listening.
+pub async fn unreachable_url() -> String {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    drop(listener);
+    format!("http://{addr}")
+}
+
+pub fn test_config(test_upstream_url: &str, prod_upstream_url: &str) -> EnvVarConfig {
+    EnvVarConfig {
+        ipn_secret: TEST_IPN_SECRET.to_string(),
+        ipn_secrets: Vec::new(),
+        upstreams: BTreeMap::from([
+            ("test".to_string(), test_upstream_url.to_string()),
+            ("prod".to_string(), prod_upstream_url.to_string()),
+        ]),
+        upstream_nodes: BTreeMap::new(),
+        sticky_session_header: None,
+        failover: Default::default(),
+        nowpayments_allowed_ips: vec!["127.0.0.1".parse().unwrap()],
+        webhook_replay_ttl: Duration::from_secs(60),
+        nowpayments_canonicalization: Default::default(),
+        nowpayments_test_mode: false,
+        request_script_path: None,
+        admin_token: Some(TEST_ADMIN_TOKEN.to_string()),
+        admin_tokens: Vec::new(),
+        metrics_snapshot_path: None,
+        xml_json_rules: Vec::new(),
+        soap_rules: Vec::new(),
+        openapi_specs: BTreeMap::new(),
+        cache_rules: Vec::new(),
+        coalesce_rules: Vec::new(),
+        credential_headers: vec!["x-api-key".to_string()],
+        cache_invalidation_rules: Vec::new(),
+        response_header_rules: Vec::new(),
+        cors_rules: Vec::new(),
+        rate_limit: None,
+        rate_limit_schedules: Vec::new(),
+        redis_url: None,
+        storage_url: None,
+        request_log: None,
+        archive: None,
+        debug_log: Default::default(),
+        egress_ip: Default::default(),
+        allow_internal_destinations: false,
+        dns_resolvers: vec![ResolverSpec::System],
+        dns_negative_ttl: None,
+        dns_refresh_on_connect_failure: false,
+        upstream_ip_family: FamilyPolicy::PreferV4,
+        title_case_header_envs: Vec::new(),
+        upstream_decompression: false,
+        server: Default::default(),
+        correlation_header: "x-correlation-id".to_string(),
+        header_limits: Default::default(),
+        hedge: None,
+        adaptive_timeout: None,
+        upstream_concurrency: None,
+        queue_timeout: Duration::from_secs(30),
+        max_connections_per_host: None,
+        max_inflight_body_bytes: None,
+        slow_request_threshold: Duration::from_secs(2),
+        partial_response_paths: Vec::new(),
+        field_filter_paths: Vec::new(),
+        masking: Default::default(),
+        upstream_body_idle_timeout: None,
+        large_transfer_bytes: None,
+        transfer_progress_interval: Duration::from_secs(5),
+        upstream_error_capacity: Some(50),
+        slo_rules: Vec::new(),
+        traffic_pause: Default::default(),
+        maintenance_windows: Vec::new(),
+        api_version_pins: Vec::new(),
+        client_tags: Default::default(),
+        store_forward: None,
+        scheduled_requests_path: None,
+        batch_max_requests: Some(10),
+        batch_concurrency: 2,
+        probes: Vec::new(),
+        forward_proxy: None,
+        socks5: None,
+        tcp_tunnels: Vec::new(),
+        tcp_tunnel_allowed_clients: Vec::new(),
+        udp_relays: Vec::new(),
+        udp_relay_idle_timeout: Duration::from_secs(60),
+        email: None,
+        tenants: Vec::new(),
+    }
+}
+
+pub fn test_state(config: EnvVarConfig) -> AppState {
+    let client =
+        encoding::configure_client(reqwest::Client::builder(), config.upstream_decompression)
+            .build()
+            .unwrap();
+    AppState::new(client, config)
+}
+
+/// Serves the proxy router for `state` and returns its base URL.
+pub async fn spawn_proxy(state: AppState) -> String {
+    spawn_router(router(state)).await
+}
+
+/// Serves an already-built router and returns its base URL.
+pub async fn spawn_router(app: axum::Router) -> String {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        axum::serve(
+            listener,
+            app.into_make_service_with_connect_info::<SocketAddr>(),
+        )
+        .await
+        .unwrap();
+    });
+
+    format!("http://{addr}")
+}
@@ -0,0 +1,63 @@
+mod common;
+
+use std::sync::{Arc, Mutex};
+
+use axum::routing::{get, post};
+use axum::Json;
+use axum_example_rev_proxy::egress_ip::{EgressIpConfig, EgressIpSource};
+use common::{spawn_proxy, spawn_router, test_config, test_state};
+use reqwest::StatusCode;
+use serde_json::Value;
+
+#[tokio::test]
+async fn drift_marks_unready_alerts_and_counts() {
+    let observed = Arc::new(Mutex::new("203.0.113.7"));
+    let ip = observed.clone();
+    let echo = spawn_router(axum::Router::new().route(
+        "/",
+        get(move || {
+            let ip = *ip.lock().unwrap();
+            async move { ip }
+        }),
+    ))
+    .await;
+    let alerts = Arc::new(Mutex::new(Vec::<Value>::new()));
+    let received = alerts.clone();
+    let alert_sink = spawn_router(axum::Router::new().route(
+        "/alert",
+        post(move |Json(alert): Json<Value>| {
+            received.lock().unwrap().push(alert);
+            async {}
+        }),
+    ))
+    .await;
+
+    let mut config = test_config("http://unused", "http://unused");
+    config.egress_ip = EgressIpConfig {
+        source: EgressIpSource::Echo(echo),
+        expected: Some("203.0.113.9".parse().unwrap()),
+        alert_webhook_url: Some(format!("{alert_sink}/alert")),
+        ..Default::default()
+    };
+    let state = test_state(config);
+    let proxy = spawn_proxy(state.clone()).await;
+    let readyz = || async { reqwest::get(format!("{proxy}/readyz")).await.unwrap() };
+
+    assert_eq!(readyz().await.status(), StatusCode::OK);
+
+    for _ in 0..2 {
+        state.egress_ip.check(&state.client, &state.metrics).await;
+    }
+    assert_eq!(readyz().await.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(state.metrics.snapshot().egress_ip_mismatches_total, 2);
+    {
+        let alerts = alerts.lock().unwrap();
+        assert_eq!(alerts.len(), 1, "alerts only on state changes");
+        assert!(alerts[0]["text"].as_str().unwrap().contains("203.0.113.7"));
+    }
+
+    *observed.lock().unwrap() = "203.0.113.9";
+    state.egress_ip.check(&state.client, &state.metrics).await;
+    assert_eq!(readyz().await.status(), StatusCode::OK);
+    assert_eq!(alerts.lock().unwrap().len(), 2);
+}
@@ -0,0 +1,96 @@
+mod common;
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use axum_example_rev_proxy::email::{EmailBridge, EmailConfig, EmailRequest, MailError, Mailer};
+use axum_example_rev_proxy::rate_limit::{MemoryRateLimitStore, RateLimit};
+use common::{spawn_proxy, test_config, test_state};
+use reqwest::StatusCode;
+use serde_json::{json, Value};
+
+/// Records messages instead of sending them; rejects `bounce@` recipients.
+#[derive(Default)]
+struct RecordingMailer {
+    sent: Mutex<Vec<EmailRequest>>,
+}
+
+#[async_trait]
+impl Mailer for RecordingMailer {
+    async fn send(&self, email: &EmailRequest) -> Result<(), MailError> {
+        if email.to.iter().any(|to| to.starts_with("bounce@")) {
+            return Err(MailError::Rejected("550 mailbox unavailable".to_string()));
+        }
+        self.sent.lock().unwrap().push(email.clone());
+        Ok(())
+    }
+}
+
+async fn send(proxy: &str, token: &str, from: &str, to: &str) -> reqwest::Response {
+    reqwest::Client::new()
+        .post(format!("{proxy}/email/send"))
+        .bearer_auth(token)
+        .json(&json!({
+            "from": from,
+            "to": [to],
+            "subject": "Your booking",
+            "text": "Confirmed",
+        }))
+        .send()
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn relays_authorized_mail_with_sender_rules_and_limits() {
+    let mailer = Arc::new(RecordingMailer::default());
+    let mut state = test_state(test_config("http://unused", "http://unused"));
+    state.email = Some(Arc::new(EmailBridge::new(
+        EmailConfig {
+            smtp_url: "smtps://smtp.example.com".to_string(),
+            token: "mail-token".to_string(),
+            allowed_senders: vec!["estate.example".to_string()],
+            rate_limit: Some(RateLimit {
+                requests: 2,
+                window: Duration::from_secs(60),
+            }),
+        },
+        mailer.clone(),
+        Arc::new(MemoryRateLimitStore::default()),
+    )));
+    let proxy = spawn_proxy(state.clone()).await;
+    let from = "Estate <bookings@estate.example>";
+
+    let response = send(&proxy, "wrong", from, "guest@example.com").await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let response = send(&proxy, "mail-token", "x@other.example", "guest@example.com").await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    let response = send(&proxy, "mail-token", from, "guest@example.com").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.json::<Value>().await.unwrap()["status"], "sent");
+    assert_eq!(mailer.sent.lock().unwrap()[0].to, ["guest@example.com"]);
+
+    let response = send(&proxy, "mail-token", from, "bounce@example.com").await;
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(
+        response.json::<Value>().await.unwrap()["status"],
+        "not_sent"
+    );
+
+    let response = send(&proxy, "mail-token", from, "guest@example.com").await;
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert!(response.headers().contains_key("retry-after"));
+
+    let emails = state.metrics.snapshot().emails_by_outcome;
+    assert_eq!(emails["sent"], 1);
+    assert_eq!(emails["rejected"], 1);
+    assert_eq!(emails["refused"], 1);
+    assert_eq!(emails["rate_limited"], 1);
+    assert!(state
+        .metrics
+        .render()
+        .contains("proxy_emails_total{outcome=\"sent\"} 1"));
+}
@@ -0,0 +1,91 @@
+mod common;
+
+use std::time::Duration;
+
+use axum_example_rev_proxy::forward_proxy::{self, parse_destinations, ForwardProxyConfig};
+use common::{spawn_mock_upstream, test_config, test_state};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Serves a forward proxy letting `legacy:s3cret` reach `allowed`, and
+/// returns its address.
+async fn spawn_forward_proxy(allowed: &str) -> String {
+    let mut config = test_config("http://127.0.0.1:9", "http://127.0.0.1:9");
+    config.forward_proxy = Some(ForwardProxyConfig {
+        addr: "127.0.0.1:0".parse().unwrap(),
+        credentials: vec!["legacy:s3cret".to_string()],
+        allowed_destinations: parse_destinations(allowed).unwrap(),
+        connect_timeout: Duration::from_secs(2),
+    });
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    tokio::spawn(forward_proxy::serve(listener, test_state(config)));
+    addr
+}
+
+/// Sends a `CONNECT` for `destination` and returns the open stream and the
+/// proxy's response head.
+async fn connect(proxy: &str, destination: &str, credentials: Option<&str>) -> (TcpStream, String) {
+    let mut stream = TcpStream::connect(proxy).await.unwrap();
+    let mut request = format!("CONNECT {destination} HTTP/1.1\r\nHost: {destination}\r\n");
+    if let Some(credentials) = credentials {
+        request.push_str(&format!("Proxy-Authorization: Basic {credentials}\r\n"));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        let mut byte = [0u8];
+        if stream.read(&mut byte).await.unwrap() == 0 {
+            break;
+        }
+        head.push(byte[0]);
+    }
+    (stream, String::from_utf8(head).unwrap())
+}
+
+// base64("legacy:s3cret")
+const CREDENTIALS: &str = "bGVnYWN5OnMzY3JldA==";
+
+#[tokio::test]
+async fn tunnels_to_allowed_destinations() {
+    let upstream = spawn_mock_upstream().await;
+    let destination = upstream.addr.to_string();
+    let proxy = spawn_forward_proxy(&destination).await;
+
+    let (mut stream, head) = connect(&proxy, &destination, Some(CREDENTIALS)).await;
+    assert!(head.starts_with("HTTP/1.1 200"), "{head}");
+    stream
+        .write_all(
+            format!(
+                "GET /legacy/ping HTTP/1.1\r\nHost: {destination}\r\nConnection: close\r\n\r\n"
+            )
+            .as_bytes(),
+        )
+        .await
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+    assert!(response.contains("\"path\":\"/legacy/ping\""), "{response}");
+    assert_eq!(upstream.hits(), 1);
+}
+
+#[tokio::test]
+async fn refuses_unauthenticated_or_unlisted_tunnels() {
+    let upstream = spawn_mock_upstream().await;
+    let destination = upstream.addr.to_string();
+    let proxy = spawn_forward_proxy(&destination).await;
+
+    let (_, head) = connect(&proxy, &destination, None).await;
+    assert!(head.starts_with("HTTP/1.1 407"), "{head}");
+    assert!(head
+        .to_ascii_lowercase()
+        .contains("proxy-authenticate: basic"));
+    let (_, head) = connect(&proxy, &destination, Some("bGVnYWN5Omd1ZXNz")).await;
+    assert!(head.starts_with("HTTP/1.1 407"), "{head}");
+
+    let (_, head) = connect(&proxy, "127.0.0.1:22", Some(CREDENTIALS)).await;
+    assert!(head.starts_with("HTTP/1.1 403"), "{head}");
+    assert_eq!(upstream.hits(), 0);
+}
@@ -0,0 +1,156 @@
+mod common;
+
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use axum_example_rev_proxy::builder::ProxyBuilder;
+use axum_example_rev_proxy::interceptor::{
+    HmacBodySigner, OutboundRequest, RequestInterceptor, ResponseInterceptor, UpstreamResponse,
+};
+use common::{spawn_mock_upstream, spawn_router, Echo};
+use hyper::header::{HeaderName, HeaderValue};
+use reqwest::StatusCode;
+
+/// Appends its label to a shared log and to the `x-trail` request header.
+struct Trail {
+    label: &'static str,
+    log: Arc<Mutex<Vec<&'static str>>>,
+}
+
+#[async_trait]
+impl RequestInterceptor for Trail {
+    async fn on_request(&self, req: &mut OutboundRequest) -> Result<(), hyper::StatusCode> {
+        self.log.lock().unwrap().push(self.label);
+        let trail = match req.headers.get("x-trail") {
+            Some(existing) => format!("{},{}", existing.to_str().unwrap(), self.label),
+            None => self.label.to_string(),
+        };
+        req.headers
+            .insert("x-trail", HeaderValue::from_str(&trail).unwrap());
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ResponseInterceptor for Trail {
+    async fn on_response(
+        &self,
+        _req: &OutboundRequest,
+        res: &mut UpstreamResponse,
+    ) -> Result<(), hyper::StatusCode> {
+        self.log.lock().unwrap().push(self.label);
+        res.headers
+            .insert("x-seen-by", HeaderValue::from_static("interceptor"));
+        Ok(())
+    }
+}
+
+struct Reject;
+
+#[async_trait]
+impl RequestInterceptor for Reject {
+    async fn on_request(&self, _req: &mut OutboundRequest) -> Result<(), hyper::StatusCode> {
+        Err(hyper::StatusCode::FORBIDDEN)
+    }
+}
+
+fn trail(label: &'static str, log: &Arc<Mutex<Vec<&'static str>>>) -> Trail {
+    Trail {
+        label,
+        log: log.clone(),
+    }
+}
+
+#[tokio::test]
+async fn runs_global_then_route_request_interceptors_in_order() {
+    let upstream = spawn_mock_upstream().await;
+    let log = Arc::new(Mutex::new(Vec::new()));
+    let proxy = ProxyBuilder::new()
+        .upstream("test", &upstream.base_url)
+        .upstream("prod", &upstream.base_url)
+        .request_interceptor(trail("global-1", &log))
+        .request_interceptor(trail("global-2", &log))
+        .route_request_interceptor("test", trail("test-only", &log))
+        .build();
+    let base = spawn_router(proxy).await;
+
+    let test: Echo = reqwest::get(format!("{base}/test/ping"))
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    let prod: Echo = reqwest::get(format!("{base}/prod/ping"))
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+
+    assert_eq!(test.headers["x-trail"], "global-1,global-2,test-only");
+    assert_eq!(prod.headers["x-trail"], "global-1,global-2");
+    // Built-in Host rewriting still ran ahead of the custom chain.
+    assert_eq!(test.headers["host"], upstream.addr.to_string());
+}
+
+#[tokio::test]
+async fn response_interceptors_run_route_first_and_can_mutate() {
+    let upstream = spawn_mock_upstream().await;
+    let log = Arc::new(Mutex::new(Vec::new()));
+    let proxy = ProxyBuilder::new()
+        .upstream("test", &upstream.base_url)
+        .response_interceptor(trail("global", &log))
+        .route_response_interceptor("test", trail("route", &log))
+        .build();
+    let base = spawn_router(proxy).await;
+
+    let response = reqwest::get(format!("{base}/test/ping")).await.unwrap();
+
+    assert_eq!(response.headers()["x-seen-by"], "interceptor");
+    assert_eq!(*log.lock().unwrap(), vec!["route", "global"]);
+}
+
+#[tokio::test]
+async fn request_interceptor_error_short_circuits() {
+    let upstream = spawn_mock_upstream().await;
+    let proxy = ProxyBuilder::new()
+        .upstream("test", &upstream.base_url)
+        .route_request_interceptor("test", Reject)
+        .build();
+    let base = spawn_router(proxy).await;
+
+    let response = reqwest::get(format!("{base}/test/ping")).await.unwrap();
+
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn hmac_signer_signs_outbound_body() {
+    let upstream = spawn_mock_upstream().await;
+    let proxy = ProxyBuilder::new()
+        .upstream("test", &upstream.base_url)
+        .request_interceptor(HmacBodySigner::new(
+            HeaderName::from_static("x-signature"),
+            "supplier-secret",
+        ))
+        .build();
+    let base = spawn_router(proxy).await;
+
+    let echo: Echo = reqwest::Client::new()
+        .post(format!("{base}/test/book"))
+        .body("{\"id\":1}")
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+
+    use hmac::Mac;
+    let mut mac = hmac::Hmac::<sha2::Sha512>::new_from_slice(b"supplier-secret").unwrap();
+    mac.update(b"{\"id\":1}");
+    assert_eq!(
+        echo.headers["x-signature"],
+        hex::encode(mac.finalize().into_bytes())
+    );
+}
@@ -0,0 +1,58 @@
+mod common;
+
+use axum_example_rev_proxy::prober::parse_probes;
+use common::{spawn_mock_upstream, spawn_proxy, test_config, test_state, TEST_ADMIN_TOKEN};
+use serde_json::Value;
+
+#[tokio::test]
+async fn probes_validate_status_and_shape_and_report_separately() {
+    let upstream = spawn_mock_upstream().await;
+    let mut config = test_config(&upstream.base_url, &upstream.base_url);
+    config.probes = parse_probes(
+        r#"[
+            {"name": "search", "env": "test", "path": "/api/search?city=goa",
+             "expect_fields": ["/method", "/query"]},
+            {"name": "shape", "env": "test", "path": "/api/search",
+             "expect_fields": ["/data/hotels"]},
+            {"name": "down", "env": "prod", "path": "/status/503"}
+        ]"#,
+    )
+    .unwrap();
+    let state = test_state(config);
+    state
+        .prober
+        .run_all(&state.client, &state.env_var_config)
+        .await;
+    let metrics = state.metrics.clone();
+    let proxy = spawn_proxy(state).await;
+
+    let statuses: Vec<Value> = reqwest::Client::new()
+        .get(format!("{proxy}/admin/probes"))
+        .bearer_auth(TEST_ADMIN_TOKEN)
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(statuses[0]["name"], "search");
+    assert_eq!(statuses[0]["last_success"], true);
+    assert_eq!(statuses[0]["successes"], 1);
+    assert_eq!(statuses[1]["last_success"], false);
+    assert_eq!(
+        statuses[1]["last_error"],
+        "response is missing /data/hotels"
+    );
+    assert_eq!(statuses[2]["last_error"], "unexpected status 503");
+
+    // Probe calls don't count as proxied traffic.
+    assert_eq!(metrics.snapshot().requests_total, 0);
+    let rendered = reqwest::get(format!("{proxy}/metrics"))
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    assert!(rendered.contains("proxy_probe_up{probe=\"search\"} 1"));
+    assert!(rendered.contains("proxy_probe_runs_total{probe=\"down\",result=\"failure\"} 1"));
+}
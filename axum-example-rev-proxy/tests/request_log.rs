@@ -0,0 +1,88 @@
+#![cfg(feature = "request_log")]
+
+mod common;
+
+use std::time::Duration;
+
+use axum_example_rev_proxy::request_log::{RequestLogConfig, RequestLogEntry};
+use common::{spawn_mock_upstream, spawn_proxy, test_config, test_state, TEST_ADMIN_TOKEN};
+use reqwest::StatusCode;
+
+/// Polls the admin query API until `expected` entries show up; writes are async.
+async fn wait_for_entries(proxy: &str, query: &str, expected: usize) -> Vec<RequestLogEntry> {
+    for _ in 0..50 {
+        let entries: Vec<RequestLogEntry> = reqwest::Client::new()
+            .get(format!("{proxy}/admin/request-log?{query}"))
+            .bearer_auth(TEST_ADMIN_TOKEN)
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        if entries.len() >= expected {
+            return entries;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    panic!("request log never reached {expected} entries");
+}
+
+#[tokio::test]
+async fn logs_redacted_bodies_and_filters_by_status_and_path() {
+    let upstream = spawn_mock_upstream().await;
+    let dir = tempfile::tempdir().unwrap();
+    let mut log = RequestLogConfig::new(format!(
+        "sqlite://{}?mode=rwc",
+        dir.path().join("log.db").display()
+    ));
+    log.bodies = true;
+    let mut config = test_config(&upstream.base_url, &upstream.base_url);
+    config.request_log = Some(log);
+    let proxy = spawn_proxy(test_state(config)).await;
+
+    let client = reqwest::Client::new();
+    client
+        .post(format!("{proxy}/test/api/booking/7?lang=en"))
+        .body(r#"{"guest":"A","card_number":"4111"}"#)
+        .send()
+        .await
+        .unwrap();
+    client
+        .get(format!("{proxy}/test/status/503"))
+        .send()
+        .await
+        .unwrap();
+
+    let bookings = wait_for_entries(&proxy, "path_prefix=/test/api/booking", 1).await;
+    assert_eq!(bookings.len(), 1);
+    let booking = &bookings[0];
+    assert_eq!(booking.method, "POST");
+    assert_eq!(booking.query.as_deref(), Some("lang=en"));
+    assert_eq!(booking.status, 200);
+    let request_body = booking.request_body.as_deref().unwrap();
+    assert!(request_body.contains("[REDACTED]"));
+    assert!(!request_body.contains("4111"));
+    assert!(booking
+        .response_body
+        .as_deref()
+        .unwrap()
+        .contains(r#""method":"POST""#));
+
+    let failures = wait_for_entries(&proxy, "status=503", 1).await;
+    assert_eq!(failures[0].path, "/test/status/503");
+}
+
+#[tokio::test]
+async fn query_api_is_absent_without_a_sink() {
+    let proxy = spawn_proxy(test_state(test_config("http://unused", "http://unused"))).await;
+
+    let response = reqwest::Client::new()
+        .get(format!("{proxy}/admin/request-log"))
+        .bearer_auth(TEST_ADMIN_TOKEN)
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
@@ -0,0 +1,190 @@
+mod common;
+
+use std::time::Duration;
+
+use axum_example_rev_proxy::router;
+use axum_example_rev_proxy::server::{
+    bind, serve, serve_with_shutdown, spawn_workers, RuntimeConfig, ServerConfig,
+};
+use common::{spawn_mock_upstream, test_config, test_state};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+async fn spawn_server(config: ServerConfig) -> TcpStream {
+    let upstream = spawn_mock_upstream().await;
+    let state = test_state(test_config(&upstream.base_url, &upstream.base_url));
+    let listener = bind("127.0.0.1:0".parse().unwrap(), &config).unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(serve(listener, router(state), config));
+    TcpStream::connect(addr).await.unwrap()
+}
+
+/// Sends a keep-alive GET and reads the start of the response.
+async fn request(stream: &mut TcpStream) -> String {
+    stream
+        .write_all(b"GET /test/ping HTTP/1.1\r\nhost: proxy\r\n\r\n")
+        .await
+        .unwrap();
+    let mut buf = vec![0; 4096];
+    let n = stream.read(&mut buf).await.unwrap();
+    String::from_utf8_lossy(&buf[..n]).into_owned()
+}
+
+/// Waits for the server to close the connection, failing after `limit`.
+async fn closed_within(stream: &mut TcpStream, limit: Duration) -> bool {
+    let mut rest = Vec::new();
+    tokio::time::timeout(limit, stream.read_to_end(&mut rest))
+        .await
+        .is_ok()
+}
+
+#[tokio::test]
+async fn closes_keep_alive_connections_after_the_idle_timeout() {
+    let mut stream = spawn_server(ServerConfig {
+        idle_timeout: Some(Duration::from_millis(300)),
+        ..Default::default()
+    })
+    .await;
+
+    assert!(request(&mut stream).await.starts_with("HTTP/1.1 200"));
+    // Still usable before the timeout.
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(request(&mut stream).await.starts_with("HTTP/1.1 200"));
+
+    assert!(closed_within(&mut stream, Duration::from_secs(2)).await);
+}
+
+#[tokio::test]
+async fn closes_after_one_response_without_keep_alive() {
+    let mut stream = spawn_server(ServerConfig {
+        keep_alive: false,
+        idle_timeout: None,
+        ..Default::default()
+    })
+    .await;
+
+    let response = request(&mut stream).await;
+    assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+    assert!(closed_within(&mut stream, Duration::from_secs(2)).await);
+}
+
+#[tokio::test]
+async fn answers_408_when_a_request_body_stalls() {
+    let mut stream = spawn_server(ServerConfig {
+        read_timeout: Some(Duration::from_millis(200)),
+        ..Default::default()
+    })
+    .await;
+
+    stream
+        .write_all(b"POST /test/echo HTTP/1.1\r\nhost: proxy\r\ncontent-length: 10\r\n\r\nab")
+        .await
+        .unwrap();
+    let mut buf = vec![0; 4096];
+    let n = tokio::time::timeout(Duration::from_secs(2), stream.read(&mut buf))
+        .await
+        .unwrap()
+        .unwrap();
+    let response = String::from_utf8_lossy(&buf[..n]);
+    assert!(response.starts_with("HTTP/1.1 408"), "{response}");
+}
+
+#[tokio::test]
+async fn reuse_port_lets_instances_share_a_port() {
+    let config = ServerConfig {
+        reuse_port: true,
+        ..Default::default()
+    };
+    let first = bind("127.0.0.1:0".parse().unwrap(), &config).unwrap();
+    let addr = first.local_addr().unwrap();
+
+    assert!(bind(addr, &config).is_ok());
+    assert!(bind(addr, &ServerConfig::default()).is_err());
+}
+
+#[tokio::test]
+async fn workers_share_state_and_report_per_worker_counts() {
+    let upstream = spawn_mock_upstream().await;
+    let state = test_state(test_config(&upstream.base_url, &upstream.base_url));
+    let metrics = state.metrics.clone();
+    let config = ServerConfig {
+        workers: 2,
+        ..Default::default()
+    };
+    let (addr, _workers) = spawn_workers(
+        "127.0.0.1:0".parse().unwrap(),
+        router(state),
+        config,
+        metrics.clone(),
+    )
+    .unwrap();
+
+    // A fresh connection per request, so the kernel gets to pick a worker each time.
+    let client = reqwest::Client::builder()
+        .pool_max_idle_per_host(0)
+        .build()
+        .unwrap();
+    for _ in 0..8 {
+        let response = client
+            .get(format!("http://{addr}/test/ping"))
+            .send()
+            .await
+            .unwrap();
+        assert!(response.status().is_success());
+    }
+
+    let snapshot = metrics.snapshot();
+    assert_eq!(snapshot.requests_by_env["test"], 8);
+    assert_eq!(snapshot.requests_by_worker.values().sum::<u64>(), 8);
+    assert!(metrics
+        .render()
+        .contains("proxy_worker_requests_total{worker="));
+}
+
+#[tokio::test]
+async fn shutdown_drains_in_flight_requests_then_stops() {
+    let upstream = spawn_mock_upstream().await;
+    let state = test_state(test_config(&upstream.base_url, &upstream.base_url));
+    let listener = bind("127.0.0.1:0".parse().unwrap(), &ServerConfig::default()).unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+    let server = tokio::spawn(serve_with_shutdown(
+        listener,
+        router(state),
+        ServerConfig::default(),
+        async {
+            let _ = stopped.await;
+        },
+    ));
+
+    let slow = tokio::spawn(reqwest::get(format!("http://{addr}/test/delay/300")));
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    stop.send(()).unwrap();
+
+    let response = slow.await.unwrap().unwrap();
+    assert!(response.status().is_success());
+    assert_eq!(response.text().await.unwrap(), "slept 300ms");
+    tokio::time::timeout(Duration::from_secs(2), server)
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(TcpStream::connect(addr).await.is_err());
+}
+
+#[test]
+fn builds_the_runtime_from_its_config() {
+    let config = RuntimeConfig {
+        worker_threads: Some(2),
+        max_blocking_threads: Some(4),
+        thread_name: "tuned-runtime".to_string(),
+        thread_stack_size: Some(1024 * 1024),
+    };
+    let runtime = config.build().unwrap();
+    assert_eq!(runtime.metrics().num_workers(), 2);
+    let name = runtime.block_on(async {
+        tokio::spawn(async { std::thread::current().name().map(str::to_string) })
+            .await
+            .unwrap()
+    });
+    assert_eq!(name.as_deref(), Some("tuned-runtime"));
+}
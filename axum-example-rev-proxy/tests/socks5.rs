@@ -0,0 +1,85 @@
+#![cfg(feature = "socks5")]
+
+mod common;
+
+use std::time::Duration;
+
+use axum_example_rev_proxy::forward_proxy::{parse_destinations, ForwardProxyConfig};
+use axum_example_rev_proxy::socks5;
+use common::{spawn_mock_upstream, test_config, test_state};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Serves a SOCKS5 proxy letting `batch:s3cret` reach `allowed`, and
+/// returns its address.
+async fn spawn_socks5(allowed: &str) -> String {
+    let mut config = test_config("http://127.0.0.1:9", "http://127.0.0.1:9");
+    config.socks5 = Some(ForwardProxyConfig {
+        addr: "127.0.0.1:0".parse().unwrap(),
+        credentials: vec!["batch:s3cret".to_string()],
+        allowed_destinations: parse_destinations(allowed).unwrap(),
+        connect_timeout: Duration::from_secs(2),
+    });
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    tokio::spawn(socks5::serve(listener, test_state(config)));
+    addr
+}
+
+/// Authenticates as `user:password` and asks for a tunnel to `localhost`
+/// at `port`, returning the stream and the reply code (or the auth status
+/// when authentication fails).
+async fn open(proxy: &str, user: &str, password: &str, port: u16) -> (TcpStream, u8) {
+    let mut stream = TcpStream::connect(proxy).await.unwrap();
+    stream.write_all(&[5, 1, 2]).await.unwrap();
+    let mut choice = [0u8; 2];
+    stream.read_exact(&mut choice).await.unwrap();
+    assert_eq!(choice, [5, 2]);
+
+    let mut auth = vec![1, user.len() as u8];
+    auth.extend(user.as_bytes());
+    auth.push(password.len() as u8);
+    auth.extend(password.as_bytes());
+    stream.write_all(&auth).await.unwrap();
+    let mut status = [0u8; 2];
+    stream.read_exact(&mut status).await.unwrap();
+    if status[1] != 0 {
+        return (stream, status[1]);
+    }
+
+    let host = b"127.0.0.1";
+    let mut request = vec![5, 1, 0, 3, host.len() as u8];
+    request.extend(host);
+    request.extend(port.to_be_bytes());
+    stream.write_all(&request).await.unwrap();
+    let mut reply = [0u8; 10];
+    stream.read_exact(&mut reply).await.unwrap();
+    (stream, reply[1])
+}
+
+#[tokio::test]
+async fn tunnels_authenticated_clients_to_allowed_destinations() {
+    let upstream = spawn_mock_upstream().await;
+    let port = upstream.addr.port();
+    let proxy = spawn_socks5(&format!("127.0.0.1:{port}")).await;
+
+    let (mut stream, code) = open(&proxy, "batch", "s3cret", port).await;
+    assert_eq!(code, 0);
+    stream
+        .write_all(b"GET /batch/export HTTP/1.1\r\nHost: supplier\r\nConnection: close\r\n\r\n")
+        .await
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+    assert!(
+        response.contains("\"path\":\"/batch/export\""),
+        "{response}"
+    );
+
+    let (_, status) = open(&proxy, "batch", "guess", port).await;
+    assert_eq!(status, 1);
+    let (_, code) = open(&proxy, "batch", "s3cret", 22).await;
+    assert_eq!(code, 2);
+    assert_eq!(upstream.hits(), 1);
+}
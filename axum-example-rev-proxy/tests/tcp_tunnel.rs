@@ -0,0 +1,61 @@
+mod common;
+
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use axum_example_rev_proxy::tcp_tunnel::{self, TcpTunnel};
+use common::{spawn_mock_upstream, test_config, test_state};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Serves a tunnel to `remote_port` on localhost for `allowed_clients`, and
+/// returns its address.
+async fn spawn_tunnel(remote_port: u16, allowed_clients: Vec<IpAddr>) -> String {
+    let mut config = test_config("http://127.0.0.1:9", "http://127.0.0.1:9");
+    config.tcp_tunnel_allowed_clients = allowed_clients;
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let tunnel = TcpTunnel {
+        listen: addr,
+        host: "127.0.0.1".to_string(),
+        port: remote_port,
+    };
+    tokio::spawn(tcp_tunnel::serve(
+        listener,
+        Arc::new(tunnel),
+        test_state(config),
+    ));
+    addr.to_string()
+}
+
+#[tokio::test]
+async fn pipes_raw_bytes_to_the_remote() {
+    let upstream = spawn_mock_upstream().await;
+    let tunnel = spawn_tunnel(upstream.addr.port(), vec!["127.0.0.1".parse().unwrap()]).await;
+
+    let mut stream = TcpStream::connect(&tunnel).await.unwrap();
+    stream
+        .write_all(b"GET /raw/socket HTTP/1.1\r\nHost: supplier\r\nConnection: close\r\n\r\n")
+        .await
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+    assert!(response.contains("\"path\":\"/raw/socket\""), "{response}");
+    assert_eq!(upstream.hits(), 1);
+}
+
+#[tokio::test]
+async fn closes_connections_from_unlisted_clients() {
+    let upstream = spawn_mock_upstream().await;
+    let tunnel = spawn_tunnel(upstream.addr.port(), vec!["10.0.0.7".parse().unwrap()]).await;
+
+    let mut stream = TcpStream::connect(&tunnel).await.unwrap();
+    let _ = stream
+        .write_all(b"GET / HTTP/1.1\r\nHost: supplier\r\n\r\n")
+        .await;
+    let mut response = Vec::new();
+    let _ = stream.read_to_end(&mut response).await;
+    assert!(response.is_empty());
+    assert_eq!(upstream.hits(), 0);
+}
@@ -0,0 +1,107 @@
+mod common;
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use axum_example_rev_proxy::rate_limit::RateLimit;
+use axum_example_rev_proxy::tenant::TenantConfig;
+use common::{spawn_mock_upstream, spawn_proxy, test_config, test_state, Echo, TEST_ADMIN_TOKEN};
+use reqwest::StatusCode;
+
+fn tenant(name: &str, upstream: &str, key: &str) -> TenantConfig {
+    TenantConfig {
+        name: name.to_string(),
+        upstreams: BTreeMap::from([("prod".to_string(), upstream.to_string())]),
+        api_keys: vec![key.to_string()],
+        rate_limit: Some(RateLimit {
+            requests: 3,
+            window: Duration::from_secs(60),
+        }),
+    }
+}
+
+async fn get(url: &str, key: Option<&str>) -> reqwest::Response {
+    let mut request = reqwest::Client::new().get(url);
+    if let Some(key) = key {
+        request = request.header("x-api-key", key);
+    }
+    request.send().await.unwrap()
+}
+
+#[tokio::test]
+async fn tenants_are_isolated_by_prefix_key_limit_and_metrics() {
+    let shared = spawn_mock_upstream().await;
+    let booking_upstream = spawn_mock_upstream().await;
+    let payments_upstream = spawn_mock_upstream().await;
+    let mut config = test_config(&shared.base_url, &shared.base_url);
+    config.tenants = vec![
+        tenant("booking", &booking_upstream.base_url, "booking-key"),
+        tenant("payments", &payments_upstream.base_url, "payments-key"),
+    ];
+    let state = test_state(config);
+    let proxy = spawn_proxy(state.clone()).await;
+
+    let response = get(
+        &format!("{proxy}/booking/prod/hotels?city=goa"),
+        Some("booking-key"),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let echo: Echo = response.json().await.unwrap();
+    assert_eq!(echo.path, "/hotels");
+    assert_eq!(echo.query.as_deref(), Some("city=goa"));
+    assert!(!echo.headers.contains_key("x-api-key"));
+
+    // Another tenant's key, no key, and the shared route all stay out.
+    for key in [Some("payments-key"), None] {
+        let response = get(&format!("{proxy}/booking/prod/hotels"), key).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+    let response = get(&format!("{proxy}/booking.prod/hotels"), Some("booking-key")).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(booking_upstream.hits(), 1);
+
+    // The rate limit is the tenant's own: payments still gets through.
+    let mut statuses = Vec::new();
+    for _ in 0..3 {
+        let response = get(&format!("{proxy}/booking/prod/hotels"), Some("booking-key")).await;
+        statuses.push(response.status());
+    }
+    assert_eq!(
+        statuses,
+        [
+            StatusCode::OK,
+            StatusCode::OK,
+            StatusCode::TOO_MANY_REQUESTS
+        ]
+    );
+    let response = get(
+        &format!("{proxy}/payments/prod/charge"),
+        Some("payments-key"),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(payments_upstream.hits(), 1);
+
+    let metrics = get(&format!("{proxy}/booking/metrics"), Some("booking-key"))
+        .await
+        .text()
+        .await
+        .unwrap();
+    assert!(metrics.contains("env=\"booking.prod\""), "{metrics}");
+    assert!(!metrics.contains("payments"), "{metrics}");
+    let response = get(&format!("{proxy}/booking/metrics"), Some("payments-key")).await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    let response = reqwest::Client::new()
+        .get(format!("{proxy}/payments/metrics"))
+        .header("x-proxy-admin-token", TEST_ADMIN_TOKEN)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let shared_metrics = state.metrics.snapshot();
+    assert!(!shared_metrics.requests_by_env.contains_key("booking.prod"));
+    assert!(!shared_metrics.requests_by_env.contains_key("payments.prod"));
+    assert_eq!(shared.hits(), 0);
+}
@@ -0,0 +1,72 @@
+mod common;
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum_example_rev_proxy::udp_relay::{self, UdpRelay};
+use common::{test_config, test_state};
+use tokio::net::UdpSocket;
+
+/// Spawns a UDP server that answers every datagram with it reversed.
+async fn spawn_reverser() -> u16 {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let port = socket.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        let mut buf = [0u8; 1500];
+        loop {
+            let (len, from) = socket.recv_from(&mut buf).await.unwrap();
+            buf[..len].reverse();
+            socket.send_to(&buf[..len], from).await.unwrap();
+        }
+    });
+    port
+}
+
+#[tokio::test]
+async fn relays_datagrams_and_counts_them_per_mapping() {
+    let remote_port = spawn_reverser().await;
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let relay = UdpRelay {
+        listen: socket.local_addr().unwrap(),
+        host: "127.0.0.1".to_string(),
+        port: remote_port,
+    };
+    let mapping = relay.mapping();
+    let state = test_state(test_config("http://127.0.0.1:9", "http://127.0.0.1:9"));
+    tokio::spawn(udp_relay::serve(
+        socket,
+        Arc::new(relay.clone()),
+        state.clone(),
+    ));
+
+    let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    client.connect(relay.listen).await.unwrap();
+    for message in [&b"ping"[..], b"quic"] {
+        client.send(message).await.unwrap();
+        let mut buf = [0u8; 16];
+        let len = tokio::time::timeout(Duration::from_secs(2), client.recv(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let mut expected = message.to_vec();
+        expected.reverse();
+        assert_eq!(&buf[..len], expected);
+    }
+
+    // The last reply is counted just after it's sent.
+    let mut snapshot = state.metrics.snapshot();
+    for _ in 0..50 {
+        if snapshot.udp_relay_traffic[&mapping].packets_in == 2 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        snapshot = state.metrics.snapshot();
+    }
+    let traffic = snapshot.udp_relay_traffic[&mapping];
+    assert_eq!((traffic.packets_out, traffic.bytes_out), (2, 8));
+    assert_eq!((traffic.packets_in, traffic.bytes_in), (2, 8));
+    assert_eq!(snapshot.tunnels_by_protocol["udp"]["opened"], 1);
+    assert!(state.metrics.render().contains(&format!(
+        "proxy_udp_relay_packets_total{{mapping=\"{mapping}\",direction=\"in\"}} 2"
+    )));
+}
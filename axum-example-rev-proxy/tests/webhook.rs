@@ -0,0 +1,185 @@
+mod common;
+
+use axum_example_rev_proxy::canonical::Canonicalization;
+use axum_example_rev_proxy::nowpayments_ipn_webhook::IpnSecret;
+use common::{spawn_proxy, test_config, test_state, TEST_IPN_SECRET};
+use hmac::{Hmac, Mac};
+use reqwest::StatusCode;
+use sha2::Sha512;
+
+const PAYLOAD: &str = r#"{"payment_status":"finished","payment_id":42,"order_id":"b-1"}"#;
+// `PAYLOAD` with its keys sorted, which is what the signature covers.
+const SORTED_PAYLOAD: &str = r#"{"order_id":"b-1","payment_id":42,"payment_status":"finished"}"#;
+
+fn sign(secret: &str, payload: &str) -> String {
+    let mut mac = Hmac::<Sha512>::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(payload.as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+async fn post_webhook(proxy: &str, signature: Option<&str>) -> reqwest::Response {
+    let mut request = reqwest::Client::new()
+        .post(format!("{proxy}/nowpayments-webhook"))
+        .header("content-type", "application/json")
+        .body(PAYLOAD);
+    if let Some(signature) = signature {
+        request = request.header("x-nowpayments-sig", signature);
+    }
+    request.send().await.unwrap()
+}
+
+#[tokio::test]
+async fn accepts_valid_signature_over_sorted_payload() {
+    let proxy = spawn_proxy(test_state(test_config("http://unused", "http://unused"))).await;
+
+    let response = post_webhook(&proxy, Some(&sign(TEST_IPN_SECRET, SORTED_PAYLOAD))).await;
+
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn rejects_signature_from_wrong_secret() {
+    let proxy = spawn_proxy(test_state(test_config("http://unused", "http://unused"))).await;
+
+    let response = post_webhook(&proxy, Some(&sign("other-secret", SORTED_PAYLOAD))).await;
+
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(response.text().await.unwrap(), "Invalid signature");
+}
+
+#[tokio::test]
+async fn rejects_missing_signature() {
+    let proxy = spawn_proxy(test_state(test_config("http://unused", "http://unused"))).await;
+
+    let response = post_webhook(&proxy, None).await;
+
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(response.text().await.unwrap(), "Signature missing");
+}
+
+#[tokio::test]
+async fn rejects_ip_outside_allowlist() {
+    let mut config = test_config("http://unused", "http://unused");
+    config.nowpayments_allowed_ips = vec!["51.89.194.21".parse().unwrap()];
+    let proxy = spawn_proxy(test_state(config)).await;
+
+    let response = post_webhook(&proxy, Some(&sign(TEST_IPN_SECRET, SORTED_PAYLOAD))).await;
+
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn acknowledges_replayed_notification_without_processing_it() {
+    let proxy = spawn_proxy(test_state(test_config("http://unused", "http://unused"))).await;
+    let signature = sign(TEST_IPN_SECRET, SORTED_PAYLOAD);
+
+    let first = post_webhook(&proxy, Some(&signature)).await;
+    let replay = post_webhook(&proxy, Some(&signature)).await;
+
+    assert_eq!(first.status(), StatusCode::OK);
+    assert_eq!(first.text().await.unwrap(), "OK");
+    assert_eq!(replay.status(), StatusCode::OK);
+    assert_eq!(replay.text().await.unwrap(), "Already processed");
+}
+
+#[tokio::test]
+async fn replay_protection_is_shared_by_instances_with_one_store() {
+    let first = test_state(test_config("http://unused", "http://unused"));
+    let mut second = test_state(test_config("http://unused", "http://unused"));
+    second.webhook_dedup = first.webhook_dedup.clone();
+    let first = spawn_proxy(first).await;
+    let second = spawn_proxy(second).await;
+    let signature = sign(TEST_IPN_SECRET, SORTED_PAYLOAD);
+
+    assert_eq!(
+        post_webhook(&first, Some(&signature)).await.status(),
+        StatusCode::OK
+    );
+    assert_eq!(
+        post_webhook(&second, Some(&signature))
+            .await
+            .text()
+            .await
+            .unwrap(),
+        "Already processed"
+    );
+}
+
+#[tokio::test]
+async fn php_canonicalization_keeps_the_senders_number_formatting() {
+    let mut config = test_config("http://unused", "http://unused");
+    config.nowpayments_canonicalization = Canonicalization::Php;
+    let proxy = spawn_proxy(test_state(config)).await;
+    let body = r#"{"pay_amount": 12.50, "payment_id": 43, "invoice_url": "https://np.example/i/1", "fee": {}}"#;
+    // What NOWPayments' PHP signs; serde_json would have written `12.5` and `{}`.
+    let signed =
+        r#"{"fee":[],"invoice_url":"https://np.example/i/1","pay_amount":12.50,"payment_id":43}"#;
+
+    let response = reqwest::Client::new()
+        .post(format!("{proxy}/nowpayments-webhook"))
+        .header("content-type", "application/json")
+        .header("x-nowpayments-sig", sign(TEST_IPN_SECRET, signed))
+        .body(body)
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn raw_body_signatures_need_no_reserialization() {
+    let mut config = test_config("http://unused", "http://unused");
+    config.nowpayments_canonicalization = Canonicalization::Raw;
+    let proxy = spawn_proxy(test_state(config)).await;
+    let body = r#"{"payment_id": 44, "pay_amount": 0.10, "note": "café"}"#;
+
+    let post = |signature: String| {
+        reqwest::Client::new()
+            .post(format!("{proxy}/nowpayments-webhook"))
+            .header("content-type", "application/json")
+            .header("x-nowpayments-sig", signature)
+            .body(body)
+            .send()
+    };
+
+    let sorted = r#"{"note":"café","pay_amount":0.1,"payment_id":44}"#;
+    assert_eq!(
+        post(sign(TEST_IPN_SECRET, sorted)).await.unwrap().status(),
+        StatusCode::BAD_REQUEST
+    );
+    assert_eq!(
+        post(sign(TEST_IPN_SECRET, body)).await.unwrap().status(),
+        StatusCode::OK
+    );
+}
+
+#[tokio::test]
+async fn accepts_any_active_secret_during_rotation() {
+    let mut config = test_config("http://unused", "http://unused");
+    config.ipn_secrets = vec![IpnSecret {
+        id: "2026-09".to_string(),
+        secret: "old-ipn-secret".to_string(),
+        created_at: Some(1),
+    }];
+    let proxy = spawn_proxy(test_state(config)).await;
+
+    let response = post_webhook(&proxy, Some(&sign("old-ipn-secret", SORTED_PAYLOAD))).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        post_webhook(&proxy, Some(&sign("retired-secret", SORTED_PAYLOAD)))
+            .await
+            .status(),
+        StatusCode::BAD_REQUEST
+    );
+
+    let metrics = reqwest::get(format!("{proxy}/metrics"))
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    assert!(metrics.contains("proxy_ipn_signatures_verified_total{key=\"2026-09\"} 1"));
+    assert!(metrics.contains("proxy_ipn_key_age_seconds{key=\"2026-09\"} "));
+    assert!(!metrics.contains("key=\"primary\""));
+}